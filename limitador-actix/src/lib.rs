@@ -0,0 +1,278 @@
+//! An [actix-web](https://actix.rs) middleware rate limiting requests
+//! in-process with a Limitador [`RateLimiter`].
+//!
+//! The requests get checked against the limits of the namespace their route
+//! maps to, by its pattern, e.g. `/users/{id}`, or the default one, if any.
+//! Their values, e.g. their method, path or headers, are bound to
+//! `descriptors[0]`, as with the HTTP API of the server. Requests over the
+//! limits get answered with a `429 Too Many Requests`, along with the
+//! `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers,
+//! without reaching the service:
+//!
+//! ```
+//! use actix_web::{web, App};
+//! use limitador::RateLimiter;
+//! use limitador_actix::RateLimiting;
+//! use std::sync::Arc;
+//!
+//! let limiter = Arc::new(RateLimiter::new(10_000));
+//! let app = App::new()
+//!     .wrap(
+//!         RateLimiting::new(limiter)
+//!             .route("/users/{id}", "users")
+//!             .default_namespace("api")
+//!             .method("method")
+//!             .header("x-user-id", "user"),
+//!     )
+//!     .route("/users/{id}", web::get().to(|| async { "user" }));
+//! ```
+//!
+//! The limiter gets called on the worker serving the request, so it's meant
+//! to be backed by one of the in-memory storages. Requests the limiter fails
+//! to check are let through.
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpResponse};
+use limitador::limit::{Context, Namespace};
+use limitador::RateLimiter;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Rate limits the requests to the services it wraps, against the limits of
+/// the namespaces their routes map to
+#[derive(Clone)]
+pub struct RateLimiting {
+    config: Arc<Config>,
+}
+
+#[derive(Clone)]
+struct Config {
+    limiter: Arc<RateLimiter>,
+    routes: HashMap<String, Namespace>,
+    default_namespace: Option<Namespace>,
+    extractors: Vec<(String, Extractor)>,
+}
+
+#[derive(Clone)]
+enum Extractor {
+    Method,
+    Path,
+    Header(HeaderName),
+}
+
+impl RateLimiting {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            config: Arc::new(Config {
+                limiter,
+                routes: HashMap::new(),
+                default_namespace: None,
+                extractors: Vec::new(),
+            }),
+        }
+    }
+
+    /// Checks the requests to the route of the `pattern`, as registered with
+    /// the app, e.g. `/users/{id}`, against the limits of the `namespace`
+    pub fn route<P: Into<String>, N: Into<Namespace>>(mut self, pattern: P, namespace: N) -> Self {
+        Arc::make_mut(&mut self.config)
+            .routes
+            .insert(pattern.into(), namespace.into());
+        self
+    }
+
+    /// Checks the requests to the routes not mapped to any namespace against
+    /// the limits of the `namespace`, rather than letting them through
+    pub fn default_namespace<N: Into<Namespace>>(mut self, namespace: N) -> Self {
+        Arc::make_mut(&mut self.config).default_namespace = Some(namespace.into());
+        self
+    }
+
+    /// Binds the method of the requests, e.g. `GET`, as `descriptors[0].<key>`
+    pub fn method<K: Into<String>>(self, key: K) -> Self {
+        self.extractor(key.into(), Extractor::Method)
+    }
+
+    /// Binds the path of the requests, without their query, as
+    /// `descriptors[0].<key>`
+    pub fn path<K: Into<String>>(self, key: K) -> Self {
+        self.extractor(key.into(), Extractor::Path)
+    }
+
+    /// Binds the value of the `header` as `descriptors[0].<key>`, when the
+    /// requests have it
+    ///
+    /// # Panics
+    ///
+    /// If `header` isn't a valid header name
+    pub fn header<K: Into<String>>(self, header: &str, key: K) -> Self {
+        let header = HeaderName::try_from(header).expect("invalid header name");
+        self.extractor(key.into(), Extractor::Header(header))
+    }
+
+    fn extractor(mut self, key: String, extractor: Extractor) -> Self {
+        Arc::make_mut(&mut self.config)
+            .extractors
+            .push((key, extractor));
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiting
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitingMiddleware {
+            service,
+            config: Arc::clone(&self.config),
+        }))
+    }
+}
+
+/// The service rate limiting the requests to the wrapped one, as built by
+/// [`RateLimiting`]
+pub struct RateLimitingMiddleware<S> {
+    service: S,
+    config: Arc<Config>,
+}
+
+type ResponseFuture<B> = Pin<Box<dyn Future<Output = Result<ServiceResponse<B>, Error>>>>;
+
+impl<S, B> Service<ServiceRequest> for RateLimitingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = ResponseFuture<EitherBody<B>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Some(response) = self.config.check(&req) {
+            let response = req.into_response(response).map_into_right_body();
+            return Box::pin(ready(Ok(response)));
+        }
+        let response = self.service.call(req);
+        Box::pin(async move { response.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+impl Config {
+    // The response to the request when limited
+    fn check(&self, req: &ServiceRequest) -> Option<HttpResponse> {
+        let namespace = req
+            .match_pattern()
+            .and_then(|pattern| self.routes.get(&pattern))
+            .or(self.default_namespace.as_ref())?;
+
+        let mut ctx = Context::default();
+        ctx.list_binding("descriptors".to_string(), vec![self.values(req)]);
+        let result = self
+            .limiter
+            .check_rate_limited_and_update(namespace, &ctx, 1, true)
+            .ok()
+            .filter(|result| result.limited)?;
+
+        let mut response = HttpResponse::TooManyRequests();
+        for (name, value) in result.ratelimit_headers() {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::try_from(name), HeaderValue::try_from(value))
+            {
+                response.insert_header((name, value));
+            }
+        }
+        Some(response.finish())
+    }
+
+    fn values(&self, req: &ServiceRequest) -> HashMap<String, String> {
+        self.extractors
+            .iter()
+            .filter_map(|(key, extractor)| {
+                let value = match extractor {
+                    Extractor::Method => req.method().as_str(),
+                    Extractor::Path => req.path(),
+                    Extractor::Header(header) => req.headers().get(header)?.to_str().ok()?,
+                };
+                Some((key.clone(), value.to_string()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiting;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use limitador::limit::Limit;
+    use limitador::RateLimiter;
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn answers_the_requests_over_the_limits_of_their_route_with_a_429() {
+        let limiter = Arc::new(RateLimiter::new(100));
+        limiter
+            .add_limit(Limit::new(
+                "users",
+                1,
+                60,
+                vec![],
+                vec!["descriptors[0].user".try_into().expect("failed parsing!")],
+            ))
+            .unwrap();
+        limiter
+            .add_limit(Limit::new("api", 2, 60, vec![], vec![]))
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiting::new(limiter)
+                        .route("/users/{id}", "users")
+                        .default_namespace("api")
+                        .header("x-user-id", "user"),
+                )
+                .route("/users/{id}", web::get().to(|| async { "user" }))
+                .route("/health", web::get().to(|| async { "ok" })),
+        )
+        .await;
+
+        let user = |id: &str, user: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/users/{id}"))
+                .insert_header(("x-user-id", user))
+                .to_request()
+        };
+        let resp = test::call_service(&app, user("1", "alice")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, user("2", "alice")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("ratelimit-limit").unwrap(), "1");
+        let resp = test::call_service(&app, user("1", "bob")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let health = || test::TestRequest::get().uri("/health").to_request();
+        for _ in 0..2 {
+            let resp = test::call_service(&app, health()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let resp = test::call_service(&app, health()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,26 @@
+use std::error::Error;
+
+// `proto` and `vendor` hold copies of the server's, so that the crate builds
+// on its own. CI checks they're the same, for the client to always speak the
+// same protocol
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut config = prost_build::Config::new();
+    // Its examples aren't Rust, yet would be run as doc tests
+    config.disable_comments([".envoy.config.core.v3.HttpUri"]);
+    // The servers only get built for the tests to stand one up
+    tonic_build::configure()
+        .build_server(true)
+        .compile_protos_with_config(
+            config,
+            &["envoy/service/ratelimit/v3/rls.proto"],
+            &[
+                "vendor/protobufs/data-plane-api",
+                "vendor/protobufs/protoc-gen-validate",
+                "vendor/protobufs/xds",
+            ],
+        )?;
+    tonic_build::configure()
+        .build_server(true)
+        .compile_protos(&["limitador/admin/v1/admin.proto"], &["proto"])?;
+    Ok(())
+}
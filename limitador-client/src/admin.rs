@@ -0,0 +1,132 @@
+use crate::error::Error;
+use crate::pb::limitador::admin::v1::limits_admin_client::LimitsAdminClient;
+use crate::pb::limitador::admin::v1::{
+    AddLimitRequest, DeleteLimitRequest, GetCountersRequest, ListLimitsRequest, ListLimitsResponse,
+    UpdateLimitRequest, WatchLimitsRequest,
+};
+use crate::pool::{Builder, Pool};
+use crate::retry::RetryPolicy;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+pub use crate::pb::limitador::admin::v1::{Counter, Limit, MaxValueOverride};
+
+/// A client of Limitador's admin API, managing the limits and reading their
+/// counters
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    client: LimitsAdminClient<Channel>,
+    retry: RetryPolicy,
+}
+
+impl From<Pool> for AdminClient {
+    fn from(pool: Pool) -> Self {
+        Self {
+            client: LimitsAdminClient::new(pool.channel),
+            retry: pool.retry,
+        }
+    }
+}
+
+impl AdminClient {
+    /// Builds a client of the admin API served at each of the `endpoints`,
+    /// e.g. `http://limitador:8082`
+    pub fn builder<I, E>(endpoints: I) -> Builder<Self>
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<String>,
+    {
+        Builder::new(endpoints)
+    }
+
+    pub async fn list_limits(&self, namespace: &str) -> Result<Vec<Limit>, Error> {
+        let request = ListLimitsRequest {
+            namespace: namespace.to_string(),
+        };
+        let response = self
+            .retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.list_limits(request).await }
+            })
+            .await?;
+        Ok(response.into_inner().limits)
+    }
+
+    pub async fn add_limit(&self, limit: Limit) -> Result<(), Error> {
+        let request = AddLimitRequest { limit: Some(limit) };
+        self.retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.add_limit(request).await }
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Updates the limit with the `id` of `limit`, to its definition
+    pub async fn update_limit(&self, limit: Limit) -> Result<(), Error> {
+        let request = UpdateLimitRequest { limit: Some(limit) };
+        self.retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.update_limit(request).await }
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_limit(&self, namespace: &str, id: &str) -> Result<(), Error> {
+        let request = DeleteLimitRequest {
+            namespace: namespace.to_string(),
+            id: id.to_string(),
+        };
+        self.retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.delete_limit(request).await }
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_counters(&self, namespace: &str) -> Result<Vec<Counter>, Error> {
+        let request = GetCountersRequest {
+            namespace: namespace.to_string(),
+        };
+        let response = self
+            .retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.get_counters(request).await }
+            })
+            .await?;
+        Ok(response.into_inner().counters)
+    }
+
+    /// The limits of `namespace`, first as they are, then every time they
+    /// change. Only opening the stream is retried: once it fails, it's up to
+    /// the caller to watch again.
+    pub async fn watch_limits(
+        &self,
+        namespace: &str,
+    ) -> Result<Streaming<ListLimitsResponse>, Error> {
+        let request = WatchLimitsRequest {
+            namespace: namespace.to_string(),
+        };
+        let response = self
+            .retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.watch_limits(request).await }
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+}
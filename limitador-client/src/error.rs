@@ -0,0 +1,18 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no endpoint to connect to")]
+    NoEndpoint,
+    #[error("invalid endpoint '{0}': {1}")]
+    InvalidEndpoint(String, #[source] tonic::transport::Error),
+    /// The status the request failed with, once out of retries
+    #[error("request failed: {0}")]
+    Status(Box<tonic::Status>),
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        Self::Status(Box::new(status))
+    }
+}
@@ -0,0 +1,114 @@
+//! An async client for Limitador's gRPC APIs.
+//!
+//! [`RlsClient`] checks and reports hits through Envoy's rate limit service
+//! (RLS) protocol, while [`AdminClient`] manages the limits, and reads their
+//! counters, through the `limitador.admin.v1.LimitsAdmin` service.
+//!
+//! Both get built from the addresses of one or more Limitador instances,
+//! balancing the requests over a pool of connections to each of them, and
+//! retrying the ones failing with `UNAVAILABLE` as their [`RetryPolicy`] says:
+//!
+//! ```no_run
+//! use limitador_client::{Descriptor, RetryPolicy, RlsClient};
+//! use std::time::Duration;
+//!
+//! # async fn check() -> Result<(), limitador_client::Error> {
+//! let client = RlsClient::builder(["http://limitador-a:8081", "http://limitador-b:8081"])
+//!     .connections_per_endpoint(4)
+//!     .timeout(Duration::from_millis(100))
+//!     .retry(RetryPolicy::default().max_retries(2))
+//!     .build()?;
+//!
+//! let descriptor = Descriptor::new().entry("req.method", "GET");
+//! if client
+//!     .should_rate_limit("my_namespace", &[descriptor], 1)
+//!     .await?
+//!     .over_limit
+//! {
+//!     // ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod admin;
+mod error;
+mod pool;
+mod retry;
+mod rls;
+
+pub use admin::{AdminClient, Counter, Limit, MaxValueOverride};
+pub use error::Error;
+pub use pool::Builder;
+pub use retry::RetryPolicy;
+pub use rls::{Decision, Descriptor, RlsClient};
+
+/// The types generated from the protocols' definitions, for the requests the
+/// typed clients don't cover
+pub mod pb {
+    #[allow(unknown_lints)]
+    pub mod envoy {
+        pub mod config {
+            pub mod core {
+                #[allow(
+                    clippy::enum_variant_names,
+                    clippy::derive_partial_eq_without_eq,
+                    clippy::doc_lazy_continuation,
+                    clippy::doc_overindented_list_items
+                )]
+                pub mod v3 {
+                    tonic::include_proto!("envoy.config.core.v3");
+                }
+            }
+        }
+
+        pub mod extensions {
+            pub mod common {
+                pub mod ratelimit {
+                    #[allow(clippy::derive_partial_eq_without_eq)]
+                    pub mod v3 {
+                        tonic::include_proto!("envoy.extensions.common.ratelimit.v3");
+                    }
+                }
+            }
+        }
+
+        pub mod r#type {
+            #[allow(clippy::derive_partial_eq_without_eq)]
+            pub mod v3 {
+                tonic::include_proto!("envoy.r#type.v3");
+            }
+        }
+
+        pub mod service {
+            pub mod ratelimit {
+                #[allow(
+                    clippy::derive_partial_eq_without_eq,
+                    clippy::doc_lazy_continuation,
+                    clippy::doc_overindented_list_items
+                )]
+                pub mod v3 {
+                    tonic::include_proto!("envoy.service.ratelimit.v3");
+                }
+            }
+        }
+    }
+
+    pub mod xds {
+        pub mod core {
+            #[allow(clippy::derive_partial_eq_without_eq)]
+            pub mod v3 {
+                tonic::include_proto!("xds.core.v3");
+            }
+        }
+    }
+
+    pub mod limitador {
+        pub mod admin {
+            #[allow(clippy::derive_partial_eq_without_eq)]
+            pub mod v1 {
+                tonic::include_proto!("limitador.admin.v1");
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use std::marker::PhantomData;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+
+/// Builds a client of type `C`, e.g. [`RlsClient::builder`](crate::RlsClient::builder)
+#[derive(Debug)]
+pub struct Builder<C> {
+    endpoints: Vec<String>,
+    connections_per_endpoint: usize,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    client: PhantomData<fn() -> C>,
+}
+
+// What the clients get built from: public, for them to be built from it, yet
+// out of reach of the users
+#[derive(Debug, Clone)]
+pub struct Pool {
+    pub channel: Channel,
+    pub retry: RetryPolicy,
+}
+
+impl<C: From<Pool>> Builder<C> {
+    pub(crate) fn new<I, E>(endpoints: I) -> Self
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<String>,
+    {
+        Self {
+            endpoints: endpoints.into_iter().map(Into::into).collect(),
+            connections_per_endpoint: 1,
+            connect_timeout: None,
+            timeout: None,
+            retry: RetryPolicy::default(),
+            client: PhantomData,
+        }
+    }
+
+    /// The connections opened to each endpoint, the requests being balanced
+    /// over all of them. Defaults to one, as a connection multiplexes requests.
+    pub fn connections_per_endpoint(mut self, connections: usize) -> Self {
+        self.connections_per_endpoint = connections.max(1);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// The time each attempt at a request has to complete in, excluding the
+    /// backoff between them
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Builds the client, connecting lazily, as requests get sent. Must be
+    /// called from within a Tokio runtime.
+    pub fn build(self) -> Result<C, Error> {
+        if self.endpoints.is_empty() {
+            return Err(Error::NoEndpoint);
+        }
+        let mut endpoints = Vec::with_capacity(self.endpoints.len());
+        for address in self.endpoints {
+            let mut endpoint = Endpoint::from_shared(address.clone())
+                .map_err(|e| Error::InvalidEndpoint(address, e))?;
+            if let Some(timeout) = self.connect_timeout {
+                endpoint = endpoint.connect_timeout(timeout);
+            }
+            if let Some(timeout) = self.timeout {
+                endpoint = endpoint.timeout(timeout);
+            }
+            endpoints.push(endpoint);
+        }
+        // Each endpoint is listed once per connection, the balancer opening a
+        // connection per entry
+        let connections = endpoints
+            .iter()
+            .flat_map(|endpoint| (0..self.connections_per_endpoint).map(move |_| endpoint.clone()));
+        Ok(C::from(Pool {
+            channel: Channel::balance_list(connections),
+            retry: self.retry,
+        }))
+    }
+}
@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// How requests failing with `UNAVAILABLE`, i.e. that never made it to
+/// Limitador, get retried: up to `max_retries` times, waiting for twice as
+/// long as the previous time before each, from `initial_backoff` up to
+/// `max_backoff`.
+///
+/// Other failures are never retried, as they could have been processed
+/// already, e.g. the hits of a `ShouldRateLimit` request counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retries
+    pub fn none() -> Self {
+        Self::default().max_retries(0)
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Sends the request `call` makes until it succeeds, fails in a way that
+    /// can't be retried, or there are no retries left
+    pub(crate) async fn run<T, F, Fut>(&self, mut call: F) -> Result<T, Status>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut retries = 0;
+        loop {
+            match call().await {
+                Err(status) if status.code() == Code::Unavailable && retries < self.max_retries => {
+                    tokio::time::sleep(self.backoff(retries)).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn backoff(&self, retries: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retries))
+            .min(self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use std::time::Duration;
+    use tonic::Status;
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_max() {
+        let policy = RetryPolicy::default()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(500));
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3), Duration::from_millis(500));
+        assert_eq!(policy.backoff(64), Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_retries_unavailable() {
+        let policy = RetryPolicy::default().max_retries(2);
+
+        let mut calls = 0;
+        let result: Result<(), Status> = policy
+            .run(|| {
+                calls += 1;
+                async { Err(Status::unavailable("down")) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), Status> = policy
+            .run(|| {
+                calls += 1;
+                async { Err(Status::invalid_argument("bad")) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
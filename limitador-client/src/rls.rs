@@ -0,0 +1,238 @@
+use crate::error::Error;
+use crate::pb::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+use crate::pb::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use crate::pb::envoy::service::ratelimit::v3::rate_limit_response::Code;
+use crate::pb::envoy::service::ratelimit::v3::rate_limit_service_client::RateLimitServiceClient;
+use crate::pb::envoy::service::ratelimit::v3::{RateLimitRequest, RateLimitResponse};
+use crate::pool::{Builder, Pool};
+use crate::retry::RetryPolicy;
+use tonic::transport::Channel;
+
+/// The entries of a descriptor, i.e. the values of the variables the limits
+/// of the namespace use
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    entries: Vec<(String, String)>,
+}
+
+impl Descriptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entry(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.entries.push((key.into(), value.into()));
+        self
+    }
+}
+
+impl From<&Descriptor> for RateLimitDescriptor {
+    fn from(descriptor: &Descriptor) -> Self {
+        Self {
+            entries: descriptor
+                .entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// What Limitador answered to a `ShouldRateLimit` request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub over_limit: bool,
+    /// The headers to add to the response to the request, e.g. the
+    /// `X-RateLimit-*` ones, when configured
+    pub response_headers: Vec<(String, String)>,
+    /// The headers to add to the request, e.g. flagging it as soft denied
+    pub request_headers: Vec<(String, String)>,
+}
+
+impl From<RateLimitResponse> for Decision {
+    fn from(response: RateLimitResponse) -> Self {
+        let headers = |headers: Vec<crate::pb::envoy::config::core::v3::HeaderValue>| {
+            headers
+                .into_iter()
+                .map(|header| (header.key, header.value))
+                .collect()
+        };
+        Self {
+            over_limit: response.overall_code() == Code::OverLimit,
+            response_headers: headers(response.response_headers_to_add),
+            request_headers: headers(response.request_headers_to_add),
+        }
+    }
+}
+
+/// A client of Limitador's RLS API
+#[derive(Debug, Clone)]
+pub struct RlsClient {
+    client: RateLimitServiceClient<Channel>,
+    retry: RetryPolicy,
+}
+
+impl From<Pool> for RlsClient {
+    fn from(pool: Pool) -> Self {
+        Self {
+            client: RateLimitServiceClient::new(pool.channel),
+            retry: pool.retry,
+        }
+    }
+}
+
+impl RlsClient {
+    /// Builds a client of the RLS API served at each of the `endpoints`, e.g.
+    /// `http://limitador:8081`
+    pub fn builder<I, E>(endpoints: I) -> Builder<Self>
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<String>,
+    {
+        Builder::new(endpoints)
+    }
+
+    /// Checks whether a request of `namespace`, i.e. the domain, with these
+    /// `descriptors` is over any of its limits, counting `hits` against them
+    /// when it isn't
+    pub async fn should_rate_limit(
+        &self,
+        namespace: &str,
+        descriptors: &[Descriptor],
+        hits: u32,
+    ) -> Result<Decision, Error> {
+        let request = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: descriptors.iter().map(Into::into).collect(),
+            hits_addend: hits,
+        };
+        self.send(request).await.map(Decision::from)
+    }
+
+    /// Sends the `request` as is
+    pub async fn send(&self, request: RateLimitRequest) -> Result<RateLimitResponse, Error> {
+        let response = self
+            .retry
+            .run(|| {
+                let mut client = self.client.clone();
+                let request = request.clone();
+                async move { client.should_rate_limit(request).await }
+            })
+            .await?;
+        Ok(response.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Descriptor, RlsClient};
+    use crate::pb::envoy::config::core::v3::HeaderValue;
+    use crate::pb::envoy::service::ratelimit::v3::rate_limit_response::Code;
+    use crate::pb::envoy::service::ratelimit::v3::rate_limit_service_server::{
+        RateLimitService, RateLimitServiceServer,
+    };
+    use crate::pb::envoy::service::ratelimit::v3::{RateLimitRequest, RateLimitResponse};
+    use crate::RetryPolicy;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    // Unavailable for the first requests, then limiting the ones of the
+    // "limited" namespace
+    struct Flaky {
+        unavailable: AtomicU32,
+    }
+
+    #[tonic::async_trait]
+    impl RateLimitService for Flaky {
+        async fn should_rate_limit(
+            &self,
+            request: Request<RateLimitRequest>,
+        ) -> Result<Response<RateLimitResponse>, Status> {
+            let unavailable = self.unavailable.load(Ordering::SeqCst);
+            if unavailable > 0 {
+                self.unavailable.store(unavailable - 1, Ordering::SeqCst);
+                return Err(Status::unavailable("starting"));
+            }
+            let request = request.into_inner();
+            let code = match request.domain.as_str() {
+                "limited" => Code::OverLimit,
+                _ => Code::Ok,
+            };
+            Ok(Response::new(RateLimitResponse {
+                overall_code: code.into(),
+                response_headers_to_add: vec![HeaderValue {
+                    key: "x-entries".to_string(),
+                    value: request.descriptors[0].entries.len().to_string(),
+                }],
+                ..Default::default()
+            }))
+        }
+    }
+
+    async fn serve(unavailable: u32) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = Flaky {
+            unavailable: AtomicU32::new(unavailable),
+        };
+        tokio::spawn(
+            Server::builder()
+                .add_service(RateLimitServiceServer::new(service))
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        format!("http://{address}")
+    }
+
+    #[tokio::test]
+    async fn retries_until_available() {
+        let endpoint = serve(2).await;
+        let client = RlsClient::builder([endpoint])
+            .connections_per_endpoint(2)
+            .retry(RetryPolicy::default().initial_backoff(Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        let descriptors = [Descriptor::new().entry("a", "1").entry("b", "2")];
+
+        let decision = client
+            .should_rate_limit("limited", &descriptors, 1)
+            .await
+            .unwrap();
+        assert!(decision.over_limit);
+        assert_eq!(
+            decision.response_headers,
+            vec![("x-entries".to_string(), "2".to_string())]
+        );
+
+        let decision = client
+            .should_rate_limit("other", &descriptors, 1)
+            .await
+            .unwrap();
+        assert!(!decision.over_limit);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_out_of_retries() {
+        let endpoint = serve(2).await;
+        let client = RlsClient::builder([endpoint])
+            .retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+
+        let status = match client
+            .should_rate_limit("ns", &[Descriptor::new()], 1)
+            .await
+        {
+            Err(crate::Error::Status(status)) => status,
+            other => panic!("Unexpected: {other:?}"),
+        };
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+    }
+}
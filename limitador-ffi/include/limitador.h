@@ -0,0 +1,53 @@
+/*
+ * C bindings for Limitador's in-memory rate limiter.
+ *
+ * Limits are described in JSON, as in a limits file, and the values of a
+ * request as a JSON object of strings, bound to `descriptors[0]`. The calls
+ * failing return -1, the reason being then available from
+ * `limitador_last_error`.
+ */
+
+#ifndef LIMITADOR_H
+#define LIMITADOR_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Limiter limitador_t;
+
+/* A limiter holding up to `cache_size` counters of limits with variables */
+limitador_t *limitador_new(uint64_t cache_size);
+
+/* Frees the `limiter`, along with its counters. Does nothing when NULL */
+void limitador_free(limitador_t *limiter);
+
+/* Adds the limit, returning 0 when it got added, 1 when it already was. Fails
+ * when its id is the one of another limit */
+int limitador_add_limit(limitador_t *limiter, const char *limit);
+
+/* Returns 1 when the request would be limited, `delta` hits more, 0 otherwise */
+int limitador_check(limitador_t *limiter, const char *ns, const char *values,
+                    uint64_t delta);
+
+/* Adds `delta` hits to the counters of the request, returning 0 */
+int limitador_update(limitador_t *limiter, const char *ns, const char *values,
+                     uint64_t delta);
+
+/* Returns 1 when the request is limited, 0 when its `delta` hits got added */
+int limitador_check_and_update(limitador_t *limiter, const char *ns,
+                               const char *values, uint64_t delta);
+
+/*
+ * Why the last call failing on this thread did, or NULL. Only valid until the
+ * next call failing on this thread
+ */
+const char *limitador_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LIMITADOR_H */
@@ -0,0 +1,268 @@
+//! C bindings for Limitador's rate limiter, for data planes not written in
+//! Rust, e.g. NGINX modules or C++ proxies, to embed it in-process.
+//!
+//! A limiter holds its counters in memory. Its limits are described in JSON,
+//! as in a limits file, and the values of a request are a JSON object of
+//! strings, bound to `descriptors[0]` as with the HTTP API:
+//!
+//! ```c
+//! limitador_t *limiter = limitador_new(10000);
+//! limitador_add_limit(limiter,
+//!     "{\"namespace\": \"my_ns\", \"max_value\": 10, \"seconds\": 60,"
+//!     " \"conditions\": [\"descriptors[0].method == 'GET'\"],"
+//!     " \"variables\": [\"descriptors[0].user_id\"]}");
+//! if (limitador_check_and_update(limiter, "my_ns",
+//!         "{\"method\": \"GET\", \"user_id\": \"alice\"}", 1) == 1) {
+//!     // limited
+//! }
+//! limitador_free(limiter);
+//! ```
+//!
+//! Calls failing return a negative value, the reason being then available
+//! from `limitador_last_error`. See `include/limitador.h` for the declarations.
+
+use limitador::limit::{Context, Limit, Namespace};
+use limitador::RateLimiter;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+const ERROR: c_int = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A rate limiter, opaque to C
+pub struct Limiter(RateLimiter);
+
+/// A limiter holding up to `cache_size` counters of limits with variables
+#[no_mangle]
+pub extern "C" fn limitador_new(cache_size: u64) -> *mut Limiter {
+    Box::into_raw(Box::new(Limiter(RateLimiter::new(cache_size))))
+}
+
+/// Frees the `limiter`, along with its counters.
+///
+/// # Safety
+///
+/// `limiter` must be null, or have been returned by `limitador_new` and not
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn limitador_free(limiter: *mut Limiter) {
+    if !limiter.is_null() {
+        drop(Box::from_raw(limiter));
+    }
+}
+
+/// Adds the limit described by the `limit` JSON. Returns 0 when it got added,
+/// 1 when it already was. Fails when its id is the one of another limit.
+///
+/// # Safety
+///
+/// `limiter` must be a live limiter, and `limit` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn limitador_add_limit(limiter: *mut Limiter, limit: *const c_char) -> c_int {
+    guard(|| {
+        let limiter = limiter_arg(limiter)?;
+        let limit: Limit = serde_json::from_str(str_arg(limit, "limit")?)
+            .map_err(|err| format!("invalid limit: {err}"))?;
+        let added = limiter.0.add_limit(limit).map_err(|err| err.to_string())?;
+        Ok(if added { 0 } else { 1 })
+    })
+}
+
+/// Checks whether the request, with the `values` JSON object, would be
+/// limited, `delta` hits more. Returns 1 when it would, 0 otherwise.
+///
+/// # Safety
+///
+/// `limiter` must be a live limiter, and `namespace` and `values`
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn limitador_check(
+    limiter: *mut Limiter,
+    namespace: *const c_char,
+    values: *const c_char,
+    delta: u64,
+) -> c_int {
+    guard(|| {
+        let limiter = limiter_arg(limiter)?;
+        let namespace = namespace_arg(namespace)?;
+        let ctx = context_arg(values)?;
+        let limited = limiter
+            .0
+            .is_rate_limited(&namespace, &ctx, delta)
+            .map_err(|err| err.to_string())?;
+        Ok(limited.into())
+    })
+}
+
+/// Adds `delta` hits to the counters of the request with the `values` JSON
+/// object, whether they are within their limits or not. Returns 0.
+///
+/// # Safety
+///
+/// `limiter` must be a live limiter, and `namespace` and `values`
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn limitador_update(
+    limiter: *mut Limiter,
+    namespace: *const c_char,
+    values: *const c_char,
+    delta: u64,
+) -> c_int {
+    guard(|| {
+        let limiter = limiter_arg(limiter)?;
+        let namespace = namespace_arg(namespace)?;
+        let ctx = context_arg(values)?;
+        limiter
+            .0
+            .update_counters(&namespace, &ctx, delta)
+            .map_err(|err| err.to_string())?;
+        Ok(0)
+    })
+}
+
+/// Checks the request with the `values` JSON object, only adding its `delta`
+/// hits to the counters when within all their limits. Returns 1 when limited,
+/// 0 otherwise.
+///
+/// # Safety
+///
+/// `limiter` must be a live limiter, and `namespace` and `values`
+/// NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn limitador_check_and_update(
+    limiter: *mut Limiter,
+    namespace: *const c_char,
+    values: *const c_char,
+    delta: u64,
+) -> c_int {
+    guard(|| {
+        let limiter = limiter_arg(limiter)?;
+        let namespace = namespace_arg(namespace)?;
+        let ctx = context_arg(values)?;
+        let result = limiter
+            .0
+            .check_rate_limited_and_update(&namespace, &ctx, delta, false)
+            .map_err(|err| err.to_string())?;
+        Ok(result.limited.into())
+    })
+}
+
+/// The reason the last call that failed on this thread did, or null if none
+/// did. It is only valid until the next call failing on this thread.
+#[no_mangle]
+pub extern "C" fn limitador_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |err| err.as_ptr())
+    })
+}
+
+// Keeps panics from unwinding into C, reporting them as errors, as well as the
+// reason of the ones returned
+fn guard(f: impl FnOnce() -> Result<c_int, String>) -> c_int {
+    let err = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(res)) => return res,
+        Ok(Err(err)) => err,
+        Err(_) => "limitador panicked".to_string(),
+    };
+    let err = CString::new(err.replace('\0', " ")).expect("no NUL left");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(err));
+    ERROR
+}
+
+unsafe fn limiter_arg<'a>(limiter: *mut Limiter) -> Result<&'a Limiter, String> {
+    limiter
+        .as_ref()
+        .ok_or_else(|| "limiter is null".to_string())
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return Err(format!("{name} is null"));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|err| format!("{name} isn't UTF-8: {err}"))
+}
+
+unsafe fn namespace_arg(namespace: *const c_char) -> Result<Namespace, String> {
+    str_arg(namespace, "namespace").map(Namespace::from)
+}
+
+// The values get bound as the first descriptor, as with the HTTP API
+unsafe fn context_arg<'a>(values: *const c_char) -> Result<Context<'a>, String> {
+    let values: HashMap<String, String> = serde_json::from_str(str_arg(values, "values")?)
+        .map_err(|err| format!("invalid values: {err}"))?;
+    let mut ctx = Context::default();
+    ctx.list_binding("descriptors".to_string(), vec![values]);
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_over_the_c_abi() {
+        let cstr = |s: &str| CString::new(s).unwrap();
+        let namespace = cstr("test_namespace");
+        let limit = cstr(
+            r#"{"namespace": "test_namespace", "max_value": 2, "seconds": 60,
+                "conditions": ["descriptors[0].method == 'GET'"],
+                "variables": ["descriptors[0].user_id"]}"#,
+        );
+        let alice = cstr(r#"{"method": "GET", "user_id": "alice"}"#);
+        let bob = cstr(r#"{"method": "GET", "user_id": "bob"}"#);
+
+        unsafe {
+            let limiter = limitador_new(100);
+            assert_eq!(limitador_add_limit(limiter, limit.as_ptr()), 0);
+            assert_eq!(limitador_add_limit(limiter, limit.as_ptr()), 1);
+
+            for _ in 0..2 {
+                assert_eq!(
+                    limitador_check_and_update(limiter, namespace.as_ptr(), alice.as_ptr(), 1),
+                    0
+                );
+            }
+            assert_eq!(
+                limitador_check_and_update(limiter, namespace.as_ptr(), alice.as_ptr(), 1),
+                1
+            );
+            assert_eq!(
+                limitador_check(limiter, namespace.as_ptr(), bob.as_ptr(), 1),
+                0
+            );
+            assert_eq!(
+                limitador_update(limiter, namespace.as_ptr(), bob.as_ptr(), 2),
+                0
+            );
+            assert_eq!(
+                limitador_check(limiter, namespace.as_ptr(), bob.as_ptr(), 1),
+                1
+            );
+
+            assert!(limitador_last_error().is_null());
+            let invalid = cstr(r#"{"method": 1}"#);
+            assert_eq!(
+                limitador_check(limiter, namespace.as_ptr(), invalid.as_ptr(), 1),
+                ERROR
+            );
+            let err = CStr::from_ptr(limitador_last_error()).to_str().unwrap();
+            assert!(err.starts_with("invalid values"), "{err}");
+            assert_eq!(
+                limitador_check(limiter, ptr::null(), bob.as_ptr(), 1),
+                ERROR
+            );
+
+            limitador_free(limiter);
+        }
+    }
+}
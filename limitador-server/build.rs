@@ -23,6 +23,14 @@ fn generate_protobuf() -> Result<(), Box<dyn Error>> {
                 "vendor/protobufs/xds",
             ],
         )?;
+    tonic_build::configure()
+        .build_server(true)
+        .file_descriptor_set_path(original_out_dir.join("admin.bin"))
+        .compile_protos(&["limitador/admin/v1/admin.proto"], &["proto"])?;
+    tonic_build::configure()
+        .build_server(true)
+        .extern_path(".limitador.admin.v1", "crate::admin_grpc::server::admin")
+        .compile_protos(&["limitador/config/v1/config.proto"], &["proto"])?;
     Ok(())
 }
 
@@ -37,6 +45,15 @@ fn set_features(env: &str) {
     if cfg!(feature = "distributed_storage") {
         features.push("+distributed");
     }
+    if cfg!(feature = "sqlite_storage") {
+        features.push("+sqlite");
+    }
+    if cfg!(feature = "etcd_storage") {
+        features.push("+etcd");
+    }
+    if cfg!(feature = "kubernetes") {
+        features.push("+kubernetes");
+    }
     println!("cargo:rustc-env={env}={features:?}");
 }
 
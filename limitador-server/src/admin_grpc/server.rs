@@ -0,0 +1,570 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::admin_grpc::server::admin::limits_admin_server::{LimitsAdmin, LimitsAdminServer};
+use crate::admin_grpc::server::admin::{
+    AddLimitRequest, AddLimitResponse, Counter, DeleteLimitRequest, DeleteLimitResponse,
+    GetCountersRequest, GetCountersResponse, Limit, ListLimitsRequest, ListLimitsResponse,
+    MaxValueOverride, UpdateLimitRequest, UpdateLimitResponse, WatchLimitsRequest,
+};
+use crate::audit;
+use crate::envoy_rls::tls::TlsAcceptor;
+use crate::http_api::auth::{bearer, AuthError, Authenticator, Role};
+use crate::Limiter;
+use limitador::counter::Counter as LimitadorCounter;
+use limitador::errors::LimitadorError;
+use limitador::limit::{InvalidLimit, Limit as LimitadorLimit, LimitBuilder, Namespace};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{transport::Server, Request, Response, Status};
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod admin {
+    tonic::include_proto!("limitador.admin.v1");
+
+    pub(crate) const ADMIN_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("admin");
+}
+
+// How often watchers get the limits checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl From<&LimitadorLimit> for Limit {
+    fn from(limit: &LimitadorLimit) -> Self {
+        let mut conditions: Vec<String> = limit.conditions().into_iter().collect();
+        conditions.sort();
+        let mut variables: Vec<String> = limit.variables().into_iter().collect();
+        variables.sort();
+        Self {
+            id: limit.id().map(String::from),
+            namespace: limit.namespace().as_ref().to_string(),
+            max_value: limit.max_value(),
+            max_value_from: limit.max_value_from().map(String::from),
+            seconds: limit.seconds(),
+            name: limit.name().map(String::from),
+            conditions,
+            variables,
+            cost: limit.cost().map(String::from),
+            ttl: limit.ttl(),
+            overrides: limit
+                .overrides()
+                .iter()
+                .map(|o| MaxValueOverride {
+                    variables: o
+                        .variables()
+                        .iter()
+                        .map(|(var, value)| (var.clone(), value.clone()))
+                        .collect(),
+                    max_value: o.max_value(),
+                })
+                .collect(),
+            metadata: limit
+                .metadata()
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<Limit> for LimitadorLimit {
+    type Error = InvalidLimit;
+
+    fn try_from(limit: Limit) -> Result<Self, Self::Error> {
+        let mut builder = LimitBuilder::new(limit.namespace, limit.max_value, limit.seconds);
+        if let Some(id) = limit.id {
+            builder = builder.id(id);
+        }
+        if let Some(name) = limit.name {
+            builder = builder.name(name);
+        }
+        if let Some(max_value_from) = limit.max_value_from {
+            builder = builder.max_value_from(max_value_from);
+        }
+        if let Some(cost) = limit.cost {
+            builder = builder.cost(cost);
+        }
+        if let Some(ttl) = limit.ttl {
+            builder = builder.ttl(ttl);
+        }
+        for condition in limit.conditions {
+            builder = builder.condition(condition);
+        }
+        for variable in limit.variables {
+            builder = builder.variable(variable);
+        }
+        for (key, value) in limit.metadata {
+            builder = builder.metadata(key, value);
+        }
+
+        let mut limitador_limit = builder.build()?;
+        for o in limit.overrides {
+            limitador_limit.set_override(o.variables.into_iter().collect(), o.max_value)?;
+        }
+        Ok(limitador_limit)
+    }
+}
+
+impl From<&LimitadorCounter> for Counter {
+    fn from(counter: &LimitadorCounter) -> Self {
+        Self {
+            limit: Some(counter.limit().into()),
+            set_variables: counter
+                .set_variables()
+                .iter()
+                .map(|(var, value)| (var.clone(), value.clone()))
+                .collect(),
+            remaining: counter.remaining(),
+            expires_in_seconds: counter.expires_in().map(|duration| duration.as_secs()),
+        }
+    }
+}
+
+pub struct LimitsAdminService {
+    limiter: Arc<Limiter>,
+    watch_interval: Duration,
+    authenticator: Option<Arc<Authenticator>>,
+}
+
+impl LimitsAdminService {
+    pub fn new(limiter: Arc<Limiter>, watch_interval: Duration) -> Self {
+        Self {
+            limiter,
+            watch_interval,
+            authenticator: None,
+        }
+    }
+
+    /// Requires the same bearer tokens as the HTTP API, in the
+    /// `authorization` metadata of the calls
+    pub fn authenticator(mut self, authenticator: Option<Arc<Authenticator>>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    // Who made the call, provided they have the `required` role. This isn't
+    // a tonic interceptor, as these are synchronous, while validating a JWT
+    // might need the keys of the OIDC provider to be fetched again
+    async fn authorize<T>(&self, request: &Request<T>, required: Role) -> Result<String, Status> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(actor(request));
+        };
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(bearer);
+        authenticator
+            .authorize(token, required)
+            .await
+            .map_err(|err| match err {
+                AuthError::Unauthorized => Status::unauthenticated(err.to_string()),
+                AuthError::Forbidden => Status::permission_denied(err.to_string()),
+            })
+    }
+}
+
+#[tonic::async_trait]
+impl LimitsAdmin for LimitsAdminService {
+    type WatchLimitsStream = ReceiverStream<Result<ListLimitsResponse, Status>>;
+
+    #[tracing::instrument(skip_all)]
+    async fn list_limits(
+        &self,
+        request: Request<ListLimitsRequest>,
+    ) -> Result<Response<ListLimitsResponse>, Status> {
+        self.authorize(&request, Role::ReadOnly).await?;
+        let namespace = request.into_inner().namespace.into();
+        Ok(Response::new(ListLimitsResponse {
+            limits: sorted_limits(&self.limiter, &namespace),
+        }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn add_limit(
+        &self,
+        request: Request<AddLimitRequest>,
+    ) -> Result<Response<AddLimitResponse>, Status> {
+        let actor = self.authorize(&request, Role::Admin).await?;
+        let limit = to_limit(request.into_inner().limit)?;
+        let namespace = limit.namespace().clone();
+        let before = self.limiter.limits_of(&namespace);
+        let added = match &*self.limiter {
+            Limiter::Blocking(limiter) => limiter.add_limit(limit),
+            Limiter::Async(limiter) => limiter.add_limit(limit),
+        }
+        .map_err(|conflict| Status::already_exists(conflict.to_string()))?;
+        if !added {
+            return Err(Status::already_exists("the limit already exists"));
+        }
+        audit::limits_changed(&actor, &before, &self.limiter.limits_of(&namespace));
+        Ok(Response::new(AddLimitResponse {}))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_limit(
+        &self,
+        request: Request<UpdateLimitRequest>,
+    ) -> Result<Response<UpdateLimitResponse>, Status> {
+        let actor = self.authorize(&request, Role::Admin).await?;
+        let limit = to_limit(request.into_inner().limit)?;
+        let before = self.limiter.limits_of(limit.namespace());
+        // The limit is the one with its id, or with its definition if it has none
+        let exists = before.iter().any(|current| match limit.id() {
+            Some(id) => current.id() == Some(id),
+            None => current.id().is_none() && *current == limit,
+        });
+        if !exists {
+            return Err(Status::not_found("no such limit"));
+        }
+        let result = match &*self.limiter {
+            Limiter::Blocking(limiter) => limiter.update_limit(&limit),
+            Limiter::Async(limiter) => limiter.update_limit(&limit).await,
+        };
+        result.map_err(storage_status)?;
+        let after = self.limiter.limits_of(limit.namespace());
+        audit::limits_changed(&actor, &before, &after);
+        Ok(Response::new(UpdateLimitResponse {}))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_limit(
+        &self,
+        request: Request<DeleteLimitRequest>,
+    ) -> Result<Response<DeleteLimitResponse>, Status> {
+        let actor = self.authorize(&request, Role::Admin).await?;
+        let DeleteLimitRequest { namespace, id } = request.into_inner();
+        let Some(limit) = get_limits(&self.limiter, &namespace.into())
+            .into_iter()
+            .find(|limit| limit.id() == Some(id.as_str()))
+        else {
+            return Err(Status::not_found(format!("no limit with id `{id}`")));
+        };
+        let result = match &*self.limiter {
+            Limiter::Blocking(limiter) => limiter.delete_limit(&limit),
+            Limiter::Async(limiter) => limiter.delete_limit(&limit).await,
+        };
+        result.map_err(storage_status)?;
+        audit::limits_changed(&actor, &[limit], &[]);
+        Ok(Response::new(DeleteLimitResponse {}))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        request: Request<GetCountersRequest>,
+    ) -> Result<Response<GetCountersResponse>, Status> {
+        self.authorize(&request, Role::ReadOnly).await?;
+        let namespace = request.into_inner().namespace.into();
+        let result = match &*self.limiter {
+            Limiter::Blocking(limiter) => limiter.get_counters(&namespace),
+            Limiter::Async(limiter) => limiter.get_counters(&namespace).await,
+        };
+        let counters = result.map_err(storage_status)?;
+        Ok(Response::new(GetCountersResponse {
+            counters: counters.iter().map(Counter::from).collect(),
+        }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn watch_limits(
+        &self,
+        request: Request<WatchLimitsRequest>,
+    ) -> Result<Response<Self::WatchLimitsStream>, Status> {
+        self.authorize(&request, Role::ReadOnly).await?;
+        let namespace: Namespace = request.into_inner().namespace.into();
+        let limiter = Arc::clone(&self.limiter);
+        let mut interval = tokio::time::interval(self.watch_interval);
+        let (tx, rx) = mpsc::channel(1);
+
+        // The limits can change through this service, but also when the limits
+        // file gets reloaded, so they get compared to what was last sent
+        tokio::spawn(async move {
+            let mut last_sent = None;
+            loop {
+                interval.tick().await;
+                if tx.is_closed() {
+                    break;
+                }
+                let limits = sorted_limits(&limiter, &namespace);
+                if last_sent.as_ref() == Some(&limits) {
+                    continue;
+                }
+                let response = ListLimitsResponse {
+                    limits: limits.clone(),
+                };
+                if tx.send(Ok(response)).await.is_err() {
+                    break;
+                }
+                last_sent = Some(limits);
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn to_limit(limit: Option<Limit>) -> Result<LimitadorLimit, Status> {
+    let limit = limit.ok_or_else(|| Status::invalid_argument("missing limit"))?;
+    limit
+        .try_into()
+        .map_err(|err: InvalidLimit| Status::invalid_argument(err.to_string()))
+}
+
+// Only the storage being unreachable for now is worth retrying
+fn storage_status(err: LimitadorError) -> Status {
+    match &err {
+        LimitadorError::StorageError(e) if e.is_transient() => Status::unavailable(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+// Without authentication, only the address of the client tells who made a
+// change
+fn actor<T>(request: &Request<T>) -> String {
+    match request.remote_addr() {
+        Some(address) => format!("admin-grpc@{}", address.ip()),
+        None => "admin-grpc".to_string(),
+    }
+}
+
+fn get_limits(limiter: &Limiter, namespace: &Namespace) -> HashSet<LimitadorLimit> {
+    match limiter {
+        Limiter::Blocking(limiter) => limiter.get_limits(namespace),
+        Limiter::Async(limiter) => limiter.get_limits(namespace),
+    }
+}
+
+// Sorted, so that two snapshots of the same limits compare equal
+fn sorted_limits(limiter: &Limiter, namespace: &Namespace) -> Vec<Limit> {
+    let mut limits: Vec<Limit> = get_limits(limiter, namespace)
+        .iter()
+        .map(Limit::from)
+        .collect();
+    limits.sort_by(|a, b| {
+        (a.seconds, &a.conditions, &a.variables, &a.id).cmp(&(
+            b.seconds,
+            &b.conditions,
+            &b.variables,
+            &b.id,
+        ))
+    });
+    limits
+}
+
+pub async fn run_admin_grpc_server(
+    address: String,
+    limiter: Arc<Limiter>,
+    grpc_reflection_service: bool,
+    authenticator: Option<Arc<Authenticator>>,
+    tls: Option<Arc<TlsAcceptor>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let svc = LimitsAdminServer::new(
+        LimitsAdminService::new(limiter, WATCH_INTERVAL).authenticator(authenticator),
+    );
+
+    let reflection_service = match grpc_reflection_service {
+        false => None,
+        true => Some(
+            tonic_reflection::server::Builder::configure()
+                .register_encoded_file_descriptor_set(admin::ADMIN_DESCRIPTOR_SET)
+                .build_v1()
+                .unwrap(),
+        ),
+    };
+
+    let router = Server::builder()
+        .add_service(svc)
+        .add_optional_service(reflection_service);
+    // Bound rather than parsed, so that the host can be a name
+    let listener = TcpListener::bind(&address).await?;
+    match tls {
+        None => {
+            router
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+                .await?
+        }
+        Some(tls) => {
+            router
+                .serve_with_incoming_shutdown(tls.incoming(listener), shutdown)
+                .await?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+    use tonic::{Code, IntoRequest};
+
+    use limitador::RateLimiter;
+
+    use super::*;
+
+    fn limit(max_value: u64) -> Limit {
+        Limit {
+            id: Some("per-user".to_string()),
+            namespace: "test_namespace".to_string(),
+            max_value,
+            seconds: 60,
+            conditions: vec!["req_method == 'GET'".to_string()],
+            variables: vec!["user_id".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn service() -> LimitsAdminService {
+        LimitsAdminService::new(
+            Arc::new(Limiter::Blocking(RateLimiter::new(10_000))),
+            Duration::from_millis(10),
+        )
+    }
+
+    async fn list(service: &LimitsAdminService) -> Vec<Limit> {
+        service
+            .list_limits(
+                ListLimitsRequest {
+                    namespace: "test_namespace".to_string(),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap()
+            .into_inner()
+            .limits
+    }
+
+    #[tokio::test]
+    async fn manages_limits() {
+        let service = service();
+
+        let add = AddLimitRequest {
+            limit: Some(limit(10)),
+        };
+        service.add_limit(add.clone().into_request()).await.unwrap();
+        let err = service.add_limit(add.into_request()).await.unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+        assert_eq!(list(&service).await, vec![limit(10)]);
+
+        service
+            .update_limit(
+                UpdateLimitRequest {
+                    limit: Some(limit(20)),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(list(&service).await, vec![limit(20)]);
+
+        let delete = DeleteLimitRequest {
+            namespace: "test_namespace".to_string(),
+            id: "per-user".to_string(),
+        };
+        service
+            .delete_limit(delete.clone().into_request())
+            .await
+            .unwrap();
+        assert!(list(&service).await.is_empty());
+        let err = service
+            .delete_limit(delete.into_request())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn requires_tokens() {
+        let service = service().authenticator(Some(Arc::new(Authenticator::with_tokens(vec![
+            ("admin-token", Role::Admin),
+            ("read-token", Role::ReadOnly),
+        ]))));
+        let add = |token: Option<&str>| {
+            let mut request = AddLimitRequest {
+                limit: Some(limit(10)),
+            }
+            .into_request();
+            if let Some(token) = token {
+                request
+                    .metadata_mut()
+                    .insert("authorization", format!("Bearer {token}").parse().unwrap());
+            }
+            request
+        };
+
+        let err = service.add_limit(add(None)).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let err = service.add_limit(add(Some("wrong"))).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let err = service
+            .add_limit(add(Some("read-token")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        service.add_limit(add(Some("admin-token"))).await.unwrap();
+
+        let err = service
+            .list_limits(
+                ListLimitsRequest {
+                    namespace: "test_namespace".to_string(),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_limits() {
+        let service = service();
+
+        let mut invalid = limit(10);
+        invalid.conditions = vec!["req_method == 'GET".to_string()];
+        let err = service
+            .add_limit(
+                AddLimitRequest {
+                    limit: Some(invalid),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        assert!(list(&service).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_limit_changes() {
+        let service = service();
+
+        let mut stream = service
+            .watch_limits(
+                WatchLimitsRequest {
+                    namespace: "test_namespace".to_string(),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        let snapshot = stream.next().await.unwrap().unwrap();
+        assert!(snapshot.limits.is_empty());
+
+        service
+            .add_limit(
+                AddLimitRequest {
+                    limit: Some(limit(10)),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap();
+        let snapshot = stream.next().await.unwrap().unwrap();
+        assert_eq!(snapshot.limits, vec![limit(10)]);
+    }
+}
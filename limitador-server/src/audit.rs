@@ -0,0 +1,162 @@
+use crate::config::AuditLogSink;
+use limitador::limit::Limit;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// The target of the events recording the changes made to the limits
+pub const AUDIT_TARGET: &str = "limitador::audit";
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+// The `authpriv` facility, at the `info` severity
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+#[derive(Debug, PartialEq)]
+enum Change<'a> {
+    Added(&'a Limit),
+    Updated(&'a Limit, &'a Limit),
+    Deleted(&'a Limit),
+}
+
+// Limits with an id are matched by it, the others by what identifies them,
+// i.e. their namespace, window, conditions and variables
+fn diff<'a>(before: &'a [Limit], after: &'a [Limit]) -> Vec<Change<'a>> {
+    let same = |a: &Limit, b: &Limit| match (a.id(), b.id()) {
+        (Some(a_id), Some(b_id)) => a.namespace() == b.namespace() && a_id == b_id,
+        (None, None) => a == b,
+        _ => false,
+    };
+    let mut changes = Vec::new();
+    for old in before {
+        match after.iter().find(|new| same(old, new)) {
+            None => changes.push(Change::Deleted(old)),
+            Some(new) if to_json(old) != to_json(new) => changes.push(Change::Updated(old, new)),
+            Some(_) => {}
+        }
+    }
+    for new in after {
+        if !before.iter().any(|old| same(old, new)) {
+            changes.push(Change::Added(new));
+        }
+    }
+    changes
+}
+
+fn to_json(limit: &Limit) -> String {
+    serde_json::to_string(limit).unwrap_or_default()
+}
+
+/// Records the changes the `actor` made to the limits, from `before` to
+/// `after`, as events of the `AUDIT_TARGET`
+pub fn limits_changed(actor: &str, before: &[Limit], after: &[Limit]) {
+    for change in diff(before, after) {
+        let (action, limit, old, new) = match change {
+            Change::Added(new) => ("add", new, None, Some(to_json(new))),
+            Change::Updated(old, new) => ("update", new, Some(to_json(old)), Some(to_json(new))),
+            Change::Deleted(old) => ("delete", old, Some(to_json(old)), None),
+        };
+        tracing::info!(
+            target: AUDIT_TARGET,
+            actor,
+            action,
+            namespace = limit.namespace().as_ref(),
+            limit_id = limit.id(),
+            limit_name = limit.name(),
+            before = old,
+            after = new,
+            "Limit changed"
+        );
+    }
+}
+
+/// Writes the audit events, as a JSON object per line, to the `sink`, on top
+/// of the regular logs
+pub fn layer<S>(sink: &AuditLogSink) -> Result<Box<dyn Layer<S> + Send + Sync>, String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + 'static,
+{
+    let filter = Targets::new().with_target(AUDIT_TARGET, tracing::Level::INFO);
+    let layer = tracing_subscriber::fmt::layer().json();
+    Ok(match sink {
+        AuditLogSink::File(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Couldn't open audit log '{path}': {e}"))?;
+            layer
+                .with_writer(Mutex::new(file))
+                .with_filter(filter)
+                .boxed()
+        }
+        AuditLogSink::Syslog => {
+            let socket = UnixDatagram::unbound()
+                .and_then(|socket| socket.connect(SYSLOG_SOCKET).map(|_| socket))
+                .map_err(|e| format!("Couldn't connect to syslog on '{SYSLOG_SOCKET}': {e}"))?;
+            layer
+                .with_ansi(false)
+                .with_writer(Syslog(socket))
+                .with_filter(filter)
+                .boxed()
+        }
+    })
+}
+
+struct Syslog(UnixDatagram);
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogMessage(&self.0)
+    }
+}
+
+// Each event gets formatted, then written at once, as a message of its own
+struct SyslogMessage<'a>(&'a UnixDatagram);
+
+impl Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        let message = format!("<{SYSLOG_PRIORITY}>limitador: {}", line.trim_end());
+        self.0.send(message.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, Change};
+    use limitador::limit::Limit;
+
+    #[test]
+    fn tells_the_limits_added_updated_and_deleted() {
+        let kept = Limit::with_id("kept", "ns", 10, 60, vec![], vec![]);
+        let raised = Limit::with_id("raised", "ns", 10, 60, vec![], vec![]);
+        let mut raised_after = raised.clone();
+        raised_after.set_max_value(20);
+        let deleted = Limit::new("ns", 5, 1, vec![], vec![]);
+        let added = Limit::new("ns", 5, 3600, vec![], vec![]);
+
+        let before = [kept.clone(), raised.clone(), deleted.clone()];
+        let after = [kept, raised_after.clone(), added.clone()];
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                Change::Updated(&raised, &raised_after),
+                Change::Deleted(&deleted),
+                Change::Added(&added),
+            ]
+        );
+    }
+}
@@ -1,19 +1,89 @@
 // LIMITS_FILE: Path
 //
+// LENIENT_CONDITIONS: bool
+//
+// TRACING_ENDPOINT: String
+// └ TRACING_HEADERS: String
+// └ TRACING_SAMPLING_RATIO: f64
+//
+// LOG_FORMAT: text | json
+//
+// AUDIT_LOG: Path | syslog
+//
 // LIMIT_NAME_IN_PROMETHEUS_LABELS: bool
 //
+// LATENCY_HISTOGRAM_BUCKETS: String
+//
+// STATSD_ADDRESS: host:port
+// └ STATSD_FLUSH_INTERVAL_SEC: u64
+// └ STATSD_TAGS: String
+//
+// METRICS_MAX_SERIES: usize
+//
+// HOTTEST_COUNTERS: usize
+//
+// METRICS_EXPORT: pull | push | both
+// └ METRICS_OTLP_ENDPOINT: String
+// └ METRICS_OTLP_INTERVAL_SEC: u64
+//
+// LIMIT_METRICS: bool
+// └ LIMIT_ID_IN_PROMETHEUS_LABELS: bool
+// └ LIMIT_METRICS_VARIABLES: String
+// └ LIMIT_METRICS_METADATA: String
+//
+// IN_MEMORY_SNAPSHOT_PATH: Path
+//
+// DISTRIBUTED_TOKEN: String
+//
+// RAFT_TOKEN: String
+//
 // REDIS_URL: StorageType { String }
 // └ REDIS_LOCAL_CACHE_ENABLED: bool
 //   └ REDIS_LOCAL_CACHE_FLUSHING_PERIOD_MS: i64 ?!
 //   └ REDIS_LOCAL_CACHE_BATCH_SIZE: u64
+//   └ REDIS_LOCAL_CACHE_MAX_CACHED_COUNTERS: u64
+//   └ REDIS_LOCAL_CACHE_MAX_STALENESS_MS: u64
+//   └ REDIS_LOCAL_CACHE_UPDATES_CHANNEL: String
+//   └ REDIS_LOCAL_CACHE_MAX_FLUSHING_PERIOD_MS: u64
+// └ REDIS_USERNAME: String
+// └ REDIS_PASSWORD: String
+// └ REDIS_CA_CERT: Path
+// └ REDIS_CLIENT_CERT: Path
+// └ REDIS_CLIENT_KEY: Path
+// └ REDIS_CONNECTION_TIMEOUT_MS: u64
+// └ REDIS_RESPONSE_TIMEOUT_MS: u64
+// └ REDIS_POOL_SIZE: usize
+// └ REDIS_REPLICAS: String
+// └ REDIS_IN_MEMORY_FALLBACK: bool
+// └ REDIS_STRICT: bool
+// └ REDIS_HASHED_KEYS: bool
+// └ REDIS_MIGRATE_KEYS: bool
 //
 // ENVOY_RLS_HOST: host // just to become ENVOY_RLS_HOST:ENVOY_RLS_PORT as String
 // ENVOY_RLS_PORT: port
 //
 // HTTP_API_HOST: host // just to become HTTP_API_HOST:HTTP_API_PORT as &str
 // HTTP_API_PORT: port
+//
+// ADMIN_GRPC_PORT: port // the admin gRPC API listens on ENVOY_RLS_HOST:ADMIN_GRPC_PORT
+//
+// DRAIN_TIMEOUT_SEC: u64
+//
+// LIMITS_DISCOVERY_SERVER: String
+// └ LIMITS_DISCOVERY_NODE_ID: String
+//
+// OVER_LIMIT_BEHAVIORS_FILE: Path
+//
+// HTTP_OVER_LIMIT_STATUS: u16
+// HTTP_OVER_LIMIT_RETRY_AFTER: bool
+// HTTP_OVER_LIMIT_BODY: String
+//
+// DESCRIPTOR_MAPPING_FILE: Path
+//
+// IDEMPOTENCY_HEADER: String
 
 use crate::envoy_rls::server::RateLimitHeaders;
+use crate::prometheus_metrics::DEFAULT_LATENCY_BUCKETS;
 use limitador::storage;
 use std::fmt;
 use tracing::level_filters::LevelFilter;
@@ -40,6 +110,8 @@ pub fn redacted_url(url: String) -> String {
 #[derive(Debug)]
 pub struct Configuration {
     pub limits_file: String,
+    /// Used instead of the `limits_file`, when set
+    pub limits_dir: Option<String>,
     pub storage: StorageConfiguration,
     rls_host: String,
     rls_port: u16,
@@ -47,9 +119,49 @@ pub struct Configuration {
     http_port: u16,
     pub limit_name_in_labels: bool,
     pub tracing_endpoint: String,
+    /// Sent along the traces exported, e.g. to authenticate with the collector
+    pub tracing_headers: Vec<(String, String)>,
+    /// The ratio of the traces sampled, unless their parent's was
+    pub tracing_sampling_ratio: f64,
     pub log_level: Option<LevelFilter>,
+    pub log_format: LogFormat,
+    /// Where the changes made to the limits are recorded, on top of the logs
+    pub audit_log: Option<AuditLogSink>,
     pub rate_limit_headers: RateLimitHeaders,
     pub grpc_reflection_service: bool,
+    pub admin_grpc_port: Option<u16>,
+    pub rls_tls: Option<RlsTlsConfiguration>,
+    pub http_auth: Option<HttpAuthConfiguration>,
+    #[cfg(feature = "kubernetes")]
+    pub limits_crd: Option<LimitsCrdConfiguration>,
+    pub limits_discovery: Option<LimitsDiscoveryConfiguration>,
+    /// How long to wait, on shutdown, for the in-flight requests to complete
+    /// and the counters to be flushed, in seconds
+    pub drain_timeout: u64,
+    /// How each namespace wants requests over its limits, or hitting storage
+    /// errors, to be answered
+    pub over_limit_behaviors_file: Option<String>,
+    pub http_over_limit: HttpOverLimitConfiguration,
+    /// How to map the descriptors of the RLS requests to namespaces and
+    /// variables
+    pub descriptor_mapping_file: Option<String>,
+    /// The metadata of the RLS requests identifying them across retries
+    pub idempotency_header: Option<String>,
+    pub limit_metrics: Option<LimitMetricsConfiguration>,
+    /// The buckets of the latency histograms, in seconds
+    pub latency_buckets: Vec<f64>,
+    /// Whether the metrics are served on `/metrics`, to be scraped
+    pub metrics_pull: bool,
+    pub metrics_push: Option<MetricsPushConfiguration>,
+    pub statsd: Option<StatsdConfiguration>,
+    /// The series each metric labeled after namespaces, limits or variables
+    /// is capped to
+    pub metrics_max_series: Option<usize>,
+    /// The hottest counters of each namespace to export gauges of
+    pub hottest_counters: Option<usize>,
+    /// Whether the conditions of the limits are only checked by the CEL
+    /// parser, as they used to be. Deprecated
+    pub lenient_conditions: bool,
 }
 
 pub mod env {
@@ -58,15 +170,80 @@ pub mod env {
 
     lazy_static! {
         pub static ref LIMITS_FILE: Option<&'static str> = value_for("LIMITS_FILE");
+        pub static ref LIMITS_DIR: Option<&'static str> = value_for("LIMITS_DIR");
+        pub static ref LENIENT_CONDITIONS: bool = env_option_is_enabled("LENIENT_CONDITIONS");
         pub static ref ENVOY_RLS_HOST: Option<&'static str> = value_for("ENVOY_RLS_HOST");
         pub static ref ENVOY_RLS_PORT: Option<&'static str> = value_for("ENVOY_RLS_PORT");
         pub static ref HTTP_API_HOST: Option<&'static str> = value_for("HTTP_API_HOST");
         pub static ref HTTP_API_PORT: Option<&'static str> = value_for("HTTP_API_PORT");
+        pub static ref ADMIN_GRPC_PORT: Option<&'static str> = value_for("ADMIN_GRPC_PORT");
+        pub static ref DRAIN_TIMEOUT_SEC: Option<&'static str> = value_for("DRAIN_TIMEOUT_SEC");
+        pub static ref LIMITS_DISCOVERY_SERVER: Option<&'static str> =
+            value_for("LIMITS_DISCOVERY_SERVER");
+        pub static ref LIMITS_DISCOVERY_NODE_ID: Option<&'static str> =
+            value_for("LIMITS_DISCOVERY_NODE_ID");
+        pub static ref OVER_LIMIT_BEHAVIORS_FILE: Option<&'static str> =
+            value_for("OVER_LIMIT_BEHAVIORS_FILE");
+        pub static ref HTTP_OVER_LIMIT_STATUS: Option<&'static str> =
+            value_for("HTTP_OVER_LIMIT_STATUS");
+        pub static ref HTTP_OVER_LIMIT_RETRY_AFTER: bool =
+            env_option_is_enabled("HTTP_OVER_LIMIT_RETRY_AFTER");
+        pub static ref HTTP_OVER_LIMIT_BODY: Option<&'static str> =
+            value_for("HTTP_OVER_LIMIT_BODY");
+        pub static ref DESCRIPTOR_MAPPING_FILE: Option<&'static str> =
+            value_for("DESCRIPTOR_MAPPING_FILE");
+        pub static ref IDEMPOTENCY_HEADER: Option<&'static str> = value_for("IDEMPOTENCY_HEADER");
+        pub static ref LATENCY_HISTOGRAM_BUCKETS: Option<&'static str> =
+            value_for("LATENCY_HISTOGRAM_BUCKETS");
+        pub static ref METRICS_MAX_SERIES: Option<&'static str> = value_for("METRICS_MAX_SERIES");
+        pub static ref HOTTEST_COUNTERS: Option<&'static str> = value_for("HOTTEST_COUNTERS");
+        pub static ref AUDIT_LOG: Option<&'static str> = value_for("AUDIT_LOG");
+        pub static ref LOG_FORMAT: Option<&'static str> = value_for("LOG_FORMAT");
+        pub static ref STATSD_ADDRESS: Option<&'static str> = value_for("STATSD_ADDRESS");
+        pub static ref STATSD_FLUSH_INTERVAL_SEC: Option<&'static str> =
+            value_for("STATSD_FLUSH_INTERVAL_SEC");
+        pub static ref STATSD_TAGS: Option<&'static str> = value_for("STATSD_TAGS");
+        pub static ref METRICS_EXPORT: Option<&'static str> = value_for("METRICS_EXPORT");
+        pub static ref METRICS_OTLP_ENDPOINT: Option<&'static str> =
+            value_for("METRICS_OTLP_ENDPOINT");
+        pub static ref METRICS_OTLP_INTERVAL_SEC: Option<&'static str> =
+            value_for("METRICS_OTLP_INTERVAL_SEC");
+        pub static ref LIMIT_METRICS: bool = env_option_is_enabled("LIMIT_METRICS");
+        pub static ref LIMIT_ID_IN_PROMETHEUS_LABELS: bool =
+            env_option_is_enabled("LIMIT_ID_IN_PROMETHEUS_LABELS");
+        pub static ref LIMIT_METRICS_VARIABLES: Option<&'static str> =
+            value_for("LIMIT_METRICS_VARIABLES");
+        pub static ref LIMIT_METRICS_METADATA: Option<&'static str> =
+            value_for("LIMIT_METRICS_METADATA");
+        pub static ref RLS_TLS_CERT: Option<&'static str> = value_for("RLS_TLS_CERT");
+        pub static ref RLS_TLS_KEY: Option<&'static str> = value_for("RLS_TLS_KEY");
+        pub static ref RLS_TLS_CLIENT_CA: Option<&'static str> = value_for("RLS_TLS_CLIENT_CA");
+        pub static ref RLS_TLS_ALLOWED_SANS: Option<&'static str> =
+            value_for("RLS_TLS_ALLOWED_SANS");
+        pub static ref HTTP_AUTH_TOKENS_FILE: Option<&'static str> =
+            value_for("HTTP_AUTH_TOKENS_FILE");
+        pub static ref HTTP_AUTH_OIDC_ISSUER: Option<&'static str> =
+            value_for("HTTP_AUTH_OIDC_ISSUER");
+        pub static ref HTTP_AUTH_OIDC_AUDIENCE: Option<&'static str> =
+            value_for("HTTP_AUTH_OIDC_AUDIENCE");
+        pub static ref HTTP_AUTH_OIDC_ROLES_CLAIM: Option<&'static str> =
+            value_for("HTTP_AUTH_OIDC_ROLES_CLAIM");
+        pub static ref HTTP_AUTH_OIDC_ADMIN_ROLE: Option<&'static str> =
+            value_for("HTTP_AUTH_OIDC_ADMIN_ROLE");
+        pub static ref HTTP_AUTH_OIDC_READ_ONLY_ROLE: Option<&'static str> =
+            value_for("HTTP_AUTH_OIDC_READ_ONLY_ROLE");
         pub static ref TRACING_ENDPOINT: Option<&'static str> = value_for("TRACING_ENDPOINT");
+        pub static ref TRACING_HEADERS: Option<&'static str> = value_for("TRACING_HEADERS");
+        pub static ref TRACING_SAMPLING_RATIO: Option<&'static str> =
+            value_for("TRACING_SAMPLING_RATIO");
         pub static ref LIMIT_NAME_IN_PROMETHEUS_LABELS: bool =
             env_option_is_enabled("LIMIT_NAME_IN_PROMETHEUS_LABELS");
+        pub static ref IN_MEMORY_SNAPSHOT_PATH: Option<&'static str> =
+            value_for("IN_MEMORY_SNAPSHOT_PATH");
         pub static ref DISK_PATH: Option<&'static str> = value_for("DISK_PATH");
         pub static ref DISK_OPTIMIZE: Option<&'static str> = value_for("DISK_OPTIMIZE");
+        pub static ref DISTRIBUTED_TOKEN: Option<&'static str> = value_for("DISTRIBUTED_TOKEN");
+        pub static ref RAFT_TOKEN: Option<&'static str> = value_for("RAFT_TOKEN");
         pub static ref REDIS_URL: Option<&'static str> = value_for("REDIS_URL");
         pub static ref REDIS_LOCAL_CACHE_ENABLED: bool =
             env_option_is_enabled("REDIS_LOCAL_CACHE_ENABLED");
@@ -74,6 +251,30 @@ pub mod env {
             value_for("REDIS_LOCAL_CACHE_FLUSHING_PERIOD_MS");
         pub static ref REDIS_LOCAL_CACHE_BATCH_SIZE: Option<&'static str> =
             value_for("REDIS_LOCAL_CACHE_BATCH_SIZE");
+        pub static ref REDIS_LOCAL_CACHE_MAX_CACHED_COUNTERS: Option<&'static str> =
+            value_for("REDIS_LOCAL_CACHE_MAX_CACHED_COUNTERS");
+        pub static ref REDIS_LOCAL_CACHE_MAX_STALENESS_MS: Option<&'static str> =
+            value_for("REDIS_LOCAL_CACHE_MAX_STALENESS_MS");
+        pub static ref REDIS_LOCAL_CACHE_UPDATES_CHANNEL: Option<&'static str> =
+            value_for("REDIS_LOCAL_CACHE_UPDATES_CHANNEL");
+        pub static ref REDIS_LOCAL_CACHE_MAX_FLUSHING_PERIOD_MS: Option<&'static str> =
+            value_for("REDIS_LOCAL_CACHE_MAX_FLUSHING_PERIOD_MS");
+        pub static ref REDIS_USERNAME: Option<&'static str> = value_for("REDIS_USERNAME");
+        pub static ref REDIS_PASSWORD: Option<&'static str> = value_for("REDIS_PASSWORD");
+        pub static ref REDIS_CA_CERT: Option<&'static str> = value_for("REDIS_CA_CERT");
+        pub static ref REDIS_CLIENT_CERT: Option<&'static str> = value_for("REDIS_CLIENT_CERT");
+        pub static ref REDIS_CLIENT_KEY: Option<&'static str> = value_for("REDIS_CLIENT_KEY");
+        pub static ref REDIS_CONNECTION_TIMEOUT_MS: Option<&'static str> =
+            value_for("REDIS_CONNECTION_TIMEOUT_MS");
+        pub static ref REDIS_RESPONSE_TIMEOUT_MS: Option<&'static str> =
+            value_for("REDIS_RESPONSE_TIMEOUT_MS");
+        pub static ref REDIS_POOL_SIZE: Option<&'static str> = value_for("REDIS_POOL_SIZE");
+        pub static ref REDIS_REPLICAS: Option<&'static str> = value_for("REDIS_REPLICAS");
+        pub static ref REDIS_IN_MEMORY_FALLBACK: bool =
+            env_option_is_enabled("REDIS_IN_MEMORY_FALLBACK");
+        pub static ref REDIS_STRICT: bool = env_option_is_enabled("REDIS_STRICT");
+        pub static ref REDIS_HASHED_KEYS: bool = env_option_is_enabled("REDIS_HASHED_KEYS");
+        pub static ref REDIS_MIGRATE_KEYS: bool = env_option_is_enabled("REDIS_MIGRATE_KEYS");
         pub static ref RATE_LIMIT_HEADERS: Option<&'static str> = value_for("RATE_LIMIT_HEADERS");
     }
 
@@ -96,6 +297,8 @@ impl Configuration {
     pub const DEFAULT_RLS_PORT: &'static str = "8081";
     pub const DEFAULT_HTTP_PORT: &'static str = "8080";
     pub const DEFAULT_IP_BIND: &'static str = "0.0.0.0";
+    pub const DEFAULT_DRAIN_TIMEOUT_SEC: u64 = 30;
+    pub const DEFAULT_TRACING_SAMPLING_RATIO: f64 = 1.0;
 
     #[allow(clippy::too_many_arguments)]
     pub fn with(
@@ -112,6 +315,7 @@ impl Configuration {
     ) -> Self {
         Self {
             limits_file,
+            limits_dir: None,
             storage,
             rls_host,
             rls_port,
@@ -119,9 +323,32 @@ impl Configuration {
             http_port,
             limit_name_in_labels,
             tracing_endpoint,
+            tracing_headers: Vec::new(),
+            tracing_sampling_ratio: Self::DEFAULT_TRACING_SAMPLING_RATIO,
             log_level: None,
+            log_format: LogFormat::default(),
+            audit_log: None,
             rate_limit_headers,
             grpc_reflection_service,
+            admin_grpc_port: None,
+            rls_tls: None,
+            http_auth: None,
+            #[cfg(feature = "kubernetes")]
+            limits_crd: None,
+            limits_discovery: None,
+            drain_timeout: Self::DEFAULT_DRAIN_TIMEOUT_SEC,
+            over_limit_behaviors_file: None,
+            http_over_limit: HttpOverLimitConfiguration::default(),
+            descriptor_mapping_file: None,
+            idempotency_header: None,
+            limit_metrics: None,
+            latency_buckets: DEFAULT_LATENCY_BUCKETS.to_vec(),
+            metrics_pull: true,
+            metrics_push: None,
+            statsd: None,
+            metrics_max_series: None,
+            hottest_counters: None,
+            lenient_conditions: false,
         }
     }
 
@@ -132,6 +359,12 @@ impl Configuration {
     pub fn http_address(&self) -> String {
         format!("{}:{}", self.http_host, self.http_port)
     }
+
+    /// Where the admin gRPC API listens, if enabled. It shares the IP of RLS
+    pub fn admin_grpc_address(&self) -> Option<String> {
+        self.admin_grpc_port
+            .map(|port| format!("{}:{}", self.rls_host, port))
+    }
 }
 
 #[cfg(test)]
@@ -139,8 +372,14 @@ impl Default for Configuration {
     fn default() -> Self {
         Configuration {
             limits_file: "".to_string(),
+            limits_dir: None,
             storage: StorageConfiguration::InMemory(InMemoryStorageConfiguration {
                 cache_size: Some(10_000),
+                snapshot_path: None,
+                concurrent: false,
+                sweep_interval: None,
+                cache_max_bytes: None,
+                idle_timeout: None,
             }),
             rls_host: "".to_string(),
             rls_port: 0,
@@ -148,34 +387,359 @@ impl Default for Configuration {
             http_port: 0,
             limit_name_in_labels: false,
             tracing_endpoint: "".to_string(),
+            tracing_headers: Vec::new(),
+            tracing_sampling_ratio: Self::DEFAULT_TRACING_SAMPLING_RATIO,
             log_level: None,
+            log_format: LogFormat::default(),
+            audit_log: None,
             rate_limit_headers: RateLimitHeaders::None,
             grpc_reflection_service: false,
+            admin_grpc_port: None,
+            rls_tls: None,
+            http_auth: None,
+            #[cfg(feature = "kubernetes")]
+            limits_crd: None,
+            limits_discovery: None,
+            drain_timeout: Self::DEFAULT_DRAIN_TIMEOUT_SEC,
+            over_limit_behaviors_file: None,
+            http_over_limit: HttpOverLimitConfiguration::default(),
+            descriptor_mapping_file: None,
+            idempotency_header: None,
+            limit_metrics: None,
+            latency_buckets: DEFAULT_LATENCY_BUCKETS.to_vec(),
+            metrics_pull: true,
+            metrics_push: None,
+            statsd: None,
+            metrics_max_series: None,
+            hottest_counters: None,
+            lenient_conditions: false,
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// TLS for the RLS server, which then only accepts clients presenting a
+/// certificate signed by `client_ca_file`, when set
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RlsTlsConfiguration {
+    pub cert_file: String,
+    pub key_file: String,
+    pub client_ca_file: Option<String>,
+    /// DNS names, URIs or IP addresses, one of which the client certificate
+    /// must have as a SAN. Any is accepted when empty
+    pub allowed_sans: Vec<String>,
+}
+
+/// The response of the HTTP API's `/check` and `/check_and_report` endpoints
+/// to the requests they deny
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct HttpOverLimitConfiguration {
+    pub status: u16,
+    /// Whether to tell when the limit resets, with a `Retry-After` header
+    pub retry_after: bool,
+    /// JSON template of the body, with `{{namespace}}`, `{{limit_name}}`,
+    /// `{{limit_id}}` and `{{retry_after}}` placeholders
+    pub body: Option<String>,
+}
+
+impl HttpOverLimitConfiguration {
+    pub const DEFAULT_STATUS: u16 = 429;
+}
+
+impl Default for HttpOverLimitConfiguration {
+    fn default() -> Self {
+        Self {
+            status: Self::DEFAULT_STATUS,
+            retry_after: false,
+            body: None,
+        }
+    }
+}
+
+/// Per-limit metrics, i.e. `limitador_limit_hits_total` and
+/// `limitador_limited_total`, labeled by namespace, by limit name when
+/// `limit_name_in_labels`, and as configured here
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct LimitMetricsConfiguration {
+    pub limit_id: bool,
+    /// Variables of the counters to label the metrics with, as `(label,
+    /// variable)`
+    pub variables: Vec<(String, String)>,
+    /// Keys of the metadata of the limits to label the metrics with, as
+    /// `(label, key)`
+    pub metadata: Vec<(String, String)>,
+}
+
+impl LimitMetricsConfiguration {
+    /// Parses a `label=variable` pair, or a lone variable, labeled after it
+    /// with any character not allowed in a label name replaced by `_`
+    pub fn parse_variable(spec: &str) -> Result<(String, String), String> {
+        Self::parse_label(spec, "variable")
+    }
+
+    /// Parses a `label=key` pair, or a lone key of the metadata of the
+    /// limits, labeled after it the same way as variables
+    pub fn parse_metadata(spec: &str) -> Result<(String, String), String> {
+        Self::parse_label(spec, "metadata key")
+    }
+
+    fn parse_label(spec: &str, what: &str) -> Result<(String, String), String> {
+        let (label, variable) = match spec.split_once('=') {
+            Some((label, variable)) => (label.trim().to_string(), variable.trim()),
+            None => {
+                let variable = spec.trim();
+                let mut label: String = variable
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                if label.starts_with(|c: char| c.is_ascii_digit()) {
+                    label.insert(0, '_');
+                }
+                (label, variable)
+            }
+        };
+        let valid = label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !label.starts_with("__");
+        if variable.is_empty() || !valid {
+            return Err(format!(
+                "Invalid {what} to label limit metrics with: '{spec}'"
+            ));
+        }
+        if ["limitador_namespace", "limit_name", "limit_id"].contains(&label.as_str()) {
+            return Err(format!("Label '{label}' is reserved"));
+        }
+        Ok((label, variable.to_string()))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// A JSON object per line, along with the fields of the current spans
+    Json,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum AuditLogSink {
+    /// Appended to the file at that path
+    File(String),
+    /// Sent to the local syslog daemon
+    Syslog,
+}
+
+/// Pushes the metrics, the same ones served on `/metrics`, to an OTLP
+/// collector
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MetricsPushConfiguration {
+    pub endpoint: String,
+    /// How often the metrics are pushed, in seconds
+    pub interval: u64,
+}
+
+impl MetricsPushConfiguration {
+    pub const DEFAULT_INTERVAL_SEC: u64 = 60;
+}
+
+/// Sends the metrics, the same ones served on `/metrics`, to a StatsD server,
+/// e.g. a DogStatsD agent
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StatsdConfiguration {
+    pub address: String,
+    /// How often the metrics are sent, in seconds
+    pub flush_interval: u64,
+    /// Sent along every metric, on top of its labels
+    pub tags: Vec<(String, String)>,
+}
+
+impl StatsdConfiguration {
+    pub const DEFAULT_FLUSH_INTERVAL_SEC: u64 = 10;
+}
+
+/// Protects the HTTP endpoints managing limits and counters, which then
+/// require a bearer token granting either read-only or admin access
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct HttpAuthConfiguration {
+    /// YAML file listing static tokens, along with the role each grants
+    pub tokens_file: Option<String>,
+    pub oidc: Option<OidcConfiguration>,
+}
+
+/// JWTs issued by `issuer` are accepted, granting the role found in their
+/// `roles_claim`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct OidcConfiguration {
+    pub issuer: String,
+    pub audience: Option<String>,
+    /// Dot separated path to the claim, e.g. `realm_access.roles`
+    pub roles_claim: String,
+    pub admin_role: String,
+    pub read_only_role: String,
+}
+
+/// Limits are synced from the `RateLimit` resources, rather than read from the
+/// limits file
+#[cfg(feature = "kubernetes")]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct LimitsCrdConfiguration {
+    /// Only the resources of that namespace are watched, when set
+    pub namespace: Option<String>,
+}
+
+/// Limits are streamed by a management server, rather than read from the
+/// limits file
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct LimitsDiscoveryConfiguration {
+    /// URL of the management server, e.g. `http://limits-manager:18000`
+    pub server: String,
+    /// Identifies this instance to the management server
+    pub node_id: String,
+}
+
+#[derive(PartialEq, Debug)]
 pub enum StorageConfiguration {
     InMemory(InMemoryStorageConfiguration),
     Disk(DiskStorageConfiguration),
     Redis(RedisStorageConfiguration),
     #[cfg(feature = "distributed_storage")]
     Distributed(DistributedStorageConfiguration),
+    #[cfg(feature = "sqlite_storage")]
+    Sqlite(SqliteStorageConfiguration),
+    #[cfg(feature = "etcd_storage")]
+    Etcd(EtcdStorageConfiguration),
+    #[cfg(feature = "raft_storage")]
+    Raft(RaftStorageConfiguration),
+    #[cfg(feature = "partitioned_storage")]
+    Partitioned(PartitionedStorageConfiguration),
 }
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct InMemoryStorageConfiguration {
     pub cache_size: Option<u64>,
+    pub snapshot_path: Option<String>,
+    /// Counters are held without any lock shared by all of them
+    pub concurrent: bool,
+    /// Interval, in milliseconds, to drop the counters whose window is over at
+    pub sweep_interval: Option<u64>,
+    /// Bounds the qualified counters by their size in bytes, rather than count
+    pub cache_max_bytes: Option<u64>,
+    /// Time, in milliseconds, after which qualified counters not hit get evicted
+    pub idle_timeout: Option<u64>,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq)]
 #[cfg(feature = "distributed_storage")]
 pub struct DistributedStorageConfiguration {
     pub name: String,
     pub cache_size: Option<u64>,
     pub listen_address: String,
     pub peer_urls: Vec<String>,
+    pub sync_timeout: u64,
+    pub tls: Option<PeerTlsConfiguration>,
+    pub token: Option<String>,
+    pub discovery: Option<String>,
+    pub discovery_period: u64,
+    pub max_batch_size: usize,
+    pub max_batch_delay: u64,
+    pub compression: Option<String>,
+    pub compression_threshold: usize,
+    pub conflict_resolution: DistributedConflictResolution,
+}
+
+#[derive(PartialEq, Debug)]
+#[cfg(feature = "distributed_storage")]
+pub enum DistributedConflictResolution {
+    Sum,
+    MaxWins,
+    Share(f64),
+    Regional {
+        region: String,
+        shares: Vec<(String, f64)>,
+        // In milliseconds, the shares being static without it
+        rebalance_period: Option<u64>,
+        min_share: f64,
+    },
+}
+
+#[cfg(feature = "distributed_storage")]
+impl fmt::Debug for DistributedStorageConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DistributedStorageConfiguration")
+            .field("name", &self.name)
+            .field("cache_size", &self.cache_size)
+            .field("listen_address", &self.listen_address)
+            .field("peer_urls", &self.peer_urls)
+            .field("sync_timeout", &self.sync_timeout)
+            .field("tls", &self.tls)
+            .field("token", &self.token.as_ref().map(|_| "****"))
+            .field("discovery", &self.discovery)
+            .field("discovery_period", &self.discovery_period)
+            .field("max_batch_size", &self.max_batch_size)
+            .field("max_batch_delay", &self.max_batch_delay)
+            .field("compression", &self.compression)
+            .field("compression_threshold", &self.compression_threshold)
+            .field("conflict_resolution", &self.conflict_resolution)
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+pub struct PeerTlsConfiguration {
+    pub cert: String,
+    pub key: String,
+    pub ca: String,
+    pub domain: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[cfg(feature = "sqlite_storage")]
+pub struct SqliteStorageConfiguration {
+    pub path: String,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[cfg(feature = "etcd_storage")]
+pub struct EtcdStorageConfiguration {
+    pub endpoints: Vec<String>,
+}
+
+#[derive(PartialEq, Eq)]
+#[cfg(feature = "raft_storage")]
+pub struct RaftStorageConfiguration {
+    pub name: String,
+    pub listen_address: String,
+    /// Where the replica persists its log
+    pub path: String,
+    /// The other replicas, by name, along with their url
+    pub peers: Vec<(String, String)>,
+    pub tls: Option<PeerTlsConfiguration>,
+    pub token: Option<String>,
+}
+
+#[cfg(feature = "raft_storage")]
+impl fmt::Debug for RaftStorageConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RaftStorageConfiguration")
+            .field("name", &self.name)
+            .field("listen_address", &self.listen_address)
+            .field("path", &self.path)
+            .field("peers", &self.peers)
+            .field("tls", &self.tls)
+            .field("token", &self.token.as_ref().map(|_| "****"))
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[cfg(feature = "partitioned_storage")]
+pub struct PartitionedStorageConfiguration {
+    pub name: String,
+    pub listen_address: String,
+    /// The other members, by name, along with their url
+    pub peers: Vec<(String, String)>,
+    pub cache_size: Option<u64>,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -188,24 +752,92 @@ pub struct DiskStorageConfiguration {
 pub struct RedisStorageConfiguration {
     pub url: String,
     pub cache: Option<RedisStorageCacheConfiguration>,
+    pub write_behind: Option<RedisWriteBehindConfiguration>,
+    pub broadcast: Option<RedisBroadcastConfiguration>,
+    pub in_memory_fallback: bool,
+    pub strict: bool,
+    pub hashed_keys: bool,
+    pub migrate_keys: bool,
+    pub circuit_breaker: Option<CircuitBreakerConfiguration>,
+    pub connection: RedisConnectionConfiguration,
 }
 
 impl fmt::Debug for RedisStorageConfiguration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Foo")
             .field("cache", &self.cache)
+            .field("write_behind", &self.write_behind)
+            .field("broadcast", &self.broadcast)
+            .field("in_memory_fallback", &self.in_memory_fallback)
+            .field("strict", &self.strict)
+            .field("hashed_keys", &self.hashed_keys)
+            .field("migrate_keys", &self.migrate_keys)
+            .field("circuit_breaker", &self.circuit_breaker)
             .field(
                 "url",
                 &format_args!("{}", redacted_url(self.url.clone()).as_str()),
             )
+            .field("connection", &self.connection)
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Eq, Default)]
+pub struct RedisConnectionConfiguration {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub connection_timeout: Option<u64>,
+    pub response_timeout: Option<u64>,
+    pub pool_size: Option<usize>,
+    pub replicas: Vec<String>,
+}
+
+impl fmt::Debug for RedisConnectionConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisConnectionConfiguration")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "****"))
+            .field("ca_cert", &self.ca_cert)
+            .field("client_cert", &self.client_cert)
+            .field("client_key", &self.client_key)
+            .field("connection_timeout", &self.connection_timeout)
+            .field("response_timeout", &self.response_timeout)
+            .field("pool_size", &self.pool_size)
+            .field("replicas", &self.replicas)
             .finish()
     }
 }
 
+#[derive(PartialEq, Eq, Debug)]
+pub struct CircuitBreakerConfiguration {
+    pub failures: u32,
+    pub reset_timeout: u64,
+    pub allow_when_open: bool,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct RedisWriteBehindConfiguration {
+    pub flushing_period: u64,
+    pub max_counters: usize,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct RedisBroadcastConfiguration {
+    pub channel: String,
+    pub broadcast_period: u64,
+    pub max_counters: usize,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct RedisStorageCacheConfiguration {
     pub batch_size: usize,
     pub flushing_period: i64,
     pub max_counters: usize,
+    pub max_staleness: Option<u64>,
     pub response_timeout: u64,
+    pub updates_channel: Option<String>,
+    pub max_flushing_period: Option<u64>,
 }
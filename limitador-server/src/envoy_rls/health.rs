@@ -0,0 +1,156 @@
+use crate::Limiter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+pub const STORAGE_CHECK_PERIOD: Duration = Duration::from_secs(5);
+pub const STORAGE_CHECK_TTL: Duration = Duration::from_secs(1);
+
+/// Checks the storage of the counters on demand, the outcome being reused for
+/// `ttl`, so that frequent probes don't all hit the storage. Concurrent checks
+/// wait on the one under way.
+pub struct CachedStorageCheck {
+    ttl: Duration,
+    last: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl CachedStorageCheck {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            last: Mutex::default(),
+        }
+    }
+
+    pub async fn check(&self, limiter: &Arc<Limiter>) -> Result<(), String> {
+        let mut last = self.last.lock().await;
+        if let Some((checked_at, alive)) = last.as_ref() {
+            if checked_at.elapsed() < self.ttl {
+                return alive.clone();
+            }
+        }
+        let alive = ping(limiter, STORAGE_CHECK_PERIOD).await;
+        *last = Some((Instant::now(), alive.clone()));
+        alive
+    }
+}
+
+impl Default for CachedStorageCheck {
+    fn default() -> Self {
+        Self::new(STORAGE_CHECK_TTL)
+    }
+}
+
+/// Reports the server, and each of the `services` it serves, as serving for as
+/// long as the storage of the counters can be reached, checking it every
+/// `period`.
+pub async fn track_storage(
+    limiter: Arc<Limiter>,
+    mut reporter: HealthReporter,
+    services: &'static [&'static str],
+    period: Duration,
+) {
+    let mut serving = None;
+    let mut ticks = tokio::time::interval(period);
+    loop {
+        ticks.tick().await;
+        serving = Some(check(&limiter, &mut reporter, services, serving, period).await);
+    }
+}
+
+// Only reports the status when it changed, or was never reported
+async fn check(
+    limiter: &Arc<Limiter>,
+    reporter: &mut HealthReporter,
+    services: &[&str],
+    serving: Option<bool>,
+    timeout: Duration,
+) -> bool {
+    let alive = match ping(limiter, timeout).await {
+        Ok(()) => true,
+        Err(e) => {
+            if serving != Some(false) {
+                warn!("{}, reporting as not serving", e);
+            }
+            false
+        }
+    };
+    if serving == Some(alive) {
+        return alive;
+    }
+    if serving.is_some() && alive {
+        info!("Storage reachable again, reporting as serving");
+    }
+    let status = match alive {
+        true => ServingStatus::Serving,
+        false => ServingStatus::NotServing,
+    };
+    // The empty name stands for the server as a whole
+    for service in [""].iter().chain(services) {
+        reporter.set_service_status(service, status).await;
+    }
+    alive
+}
+
+async fn ping(limiter: &Arc<Limiter>, timeout: Duration) -> Result<(), String> {
+    match tokio::time::timeout(timeout, Arc::clone(limiter).ping()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("Storage unreachable: {e}")),
+        Err(_) => Err("Storage timed out".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::config::Configuration;
+    use crate::Limiter;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic_health::pb::health_check_response::ServingStatus;
+    use tonic_health::pb::health_client::HealthClient;
+    use tonic_health::pb::HealthCheckRequest;
+
+    #[tokio::test]
+    async fn reports_serving_once_storage_checked() {
+        let (mut reporter, service) = tonic_health::server::health_reporter();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let mut client = HealthClient::connect(format!("http://{address}"))
+            .await
+            .unwrap();
+        let status_of = |service: &str| HealthCheckRequest {
+            service: service.to_string(),
+        };
+
+        // Unknown until the storage got checked
+        assert!(client.check(status_of("rls")).await.is_err());
+
+        let limiter = Arc::new(Limiter::new(Configuration::default()).await.unwrap());
+        assert!(
+            check(
+                &limiter,
+                &mut reporter,
+                &["rls"],
+                None,
+                Duration::from_secs(1)
+            )
+            .await
+        );
+
+        for service in ["", "rls"] {
+            let response = client.check(status_of(service)).await.unwrap();
+            assert_eq!(response.into_inner().status(), ServingStatus::Serving);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+/// Where the namespace of a request comes from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NamespaceFrom {
+    /// The `domain` of the request, as without any mapping
+    #[default]
+    Domain,
+    /// The key of the first entry of the first descriptor
+    FirstEntryKey,
+    /// The value of the first entry of the first descriptor
+    FirstEntryValue,
+}
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    /// Only applies to the requests of that domain, when set
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(default)]
+    namespace: NamespaceFrom,
+    /// Entries renamed, from their key in the descriptor to the one the limits
+    /// use
+    #[serde(default)]
+    rename: HashMap<String, String>,
+    /// Entries left out, e.g. the ones Envoy adds that no limit uses
+    #[serde(default)]
+    drop: HashSet<String>,
+}
+
+/// Translates the descriptors of the requests Envoy sends, as its rate limit
+/// actions produce them, to the namespaces and variables the limits use. The
+/// first rule applying to the domain of a request maps it, the namespace
+/// being taken from the original entries, before they get renamed or dropped.
+#[derive(Debug, Default)]
+pub struct DescriptorMapping {
+    rules: Vec<Rule>,
+}
+
+impl DescriptorMapping {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file =
+            std::fs::File::open(path).map_err(|e| format!("Couldn't read file '{path}': {e}"))?;
+        let rules =
+            serde_yaml::from_reader(file).map_err(|e| format!("Couldn't parse '{path}': {e}"))?;
+        Ok(Self { rules })
+    }
+
+    /// The namespace of the request, and the entries of each of its
+    /// descriptors
+    pub fn apply(
+        &self,
+        domain: String,
+        descriptors: &[RateLimitDescriptor],
+    ) -> (String, Vec<HashMap<String, String>>) {
+        let rule = self.rules.iter().find(|rule| match &rule.domain {
+            Some(applies_to) => *applies_to == domain,
+            None => true,
+        });
+        let Some(rule) = rule else {
+            let values = descriptors
+                .iter()
+                .map(|descriptor| {
+                    descriptor
+                        .entries
+                        .iter()
+                        .map(|entry| (entry.key.clone(), entry.value.clone()))
+                        .collect()
+                })
+                .collect();
+            return (domain, values);
+        };
+
+        let first = descriptors
+            .first()
+            .and_then(|descriptor| descriptor.entries.first());
+        let namespace = match rule.namespace {
+            NamespaceFrom::Domain => domain,
+            NamespaceFrom::FirstEntryKey => first.map(|e| e.key.clone()).unwrap_or_default(),
+            NamespaceFrom::FirstEntryValue => first.map(|e| e.value.clone()).unwrap_or_default(),
+        };
+        let values = descriptors
+            .iter()
+            .map(|descriptor| {
+                descriptor
+                    .entries
+                    .iter()
+                    .filter(|entry| !rule.drop.contains(&entry.key))
+                    .map(|entry| {
+                        let key = rule.rename.get(&entry.key).unwrap_or(&entry.key);
+                        (key.clone(), entry.value.clone())
+                    })
+                    .collect()
+            })
+            .collect();
+        (namespace, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DescriptorMapping;
+    use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
+    use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+    use std::collections::HashMap;
+
+    fn descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn maps_descriptors_per_domain() {
+        let mapping = DescriptorMapping {
+            rules: serde_yaml::from_str(
+                "- domain: ingress\n  namespace: first_entry_value\n  \
+                 rename:\n    remote_address: client_ip\n  drop: [generic_key]\n\
+                 - rename:\n    remote_address: ip\n",
+            )
+            .unwrap(),
+        };
+        let descriptors = vec![descriptor(&[
+            ("generic_key", "checkout"),
+            ("remote_address", "10.0.0.1"),
+        ])];
+
+        let (namespace, values) = mapping.apply("ingress".to_string(), &descriptors);
+        assert_eq!(namespace, "checkout");
+        assert_eq!(
+            values,
+            vec![HashMap::from([(
+                "client_ip".to_string(),
+                "10.0.0.1".to_string()
+            )])]
+        );
+
+        // Any other domain falls through to the catch-all rule
+        let (namespace, values) = mapping.apply("other".to_string(), &descriptors);
+        assert_eq!(namespace, "other");
+        assert_eq!(values[0].get("ip").map(String::as_str), Some("10.0.0.1"));
+        assert_eq!(
+            values[0].get("generic_key").map(String::as_str),
+            Some("checkout")
+        );
+
+        // Without rules, the descriptors are left as they are
+        let (namespace, values) =
+            DescriptorMapping::default().apply("ingress".to_string(), &descriptors);
+        assert_eq!(namespace, "ingress");
+        assert_eq!(values[0].len(), 2);
+    }
+}
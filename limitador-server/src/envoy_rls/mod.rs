@@ -1,2 +1,5 @@
 mod envoy_types;
+pub mod health;
+pub mod mapping;
 pub mod server;
+pub mod tls;
@@ -1,8 +1,11 @@
 use opentelemetry::global;
 use opentelemetry::propagation::Extractor;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::sync::Arc;
 
+use crate::envoy_rls::health;
+use crate::envoy_rls::mapping::DescriptorMapping;
 use crate::envoy_rls::server::envoy::config::core::v3::HeaderValue;
 use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_response::Code;
 use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_service_server::{
@@ -11,12 +14,16 @@ use crate::envoy_rls::server::envoy::service::ratelimit::v3::rate_limit_service_
 use crate::envoy_rls::server::envoy::service::ratelimit::v3::{
     RateLimitRequest, RateLimitResponse,
 };
+use crate::envoy_rls::tls::TlsAcceptor;
+use crate::over_limit::{OverLimitBehaviors, Verdict};
 use crate::prometheus_metrics::PrometheusMetrics;
 use crate::Limiter;
 use limitador::limit::Context;
 use limitador::CheckResult;
+use tokio::net::TcpListener;
 use tonic::codegen::http::HeaderMap;
-use tonic::{transport, transport::Server, Request, Response, Status};
+use tonic::server::NamedService;
+use tonic::{transport::Server, Request, Response, Status};
 use tracing::Span;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
@@ -26,6 +33,9 @@ include!("envoy_types.rs");
 pub enum RateLimitHeaders {
     None,
     DraftVersion03,
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`, per the
+    /// IETF draft https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/
+    DraftIetf,
 }
 
 impl RateLimitHeaders {
@@ -37,6 +47,11 @@ impl RateLimitHeaders {
                 .into_iter()
                 .map(|(key, value)| HeaderValue { key, value })
                 .collect(),
+            RateLimitHeaders::DraftIetf => response
+                .ratelimit_headers()
+                .into_iter()
+                .map(|(key, value)| HeaderValue { key, value })
+                .collect(),
         };
         headers.sort_by(|a, b| a.key.cmp(&b.key));
         headers
@@ -47,6 +62,9 @@ pub struct MyRateLimiter {
     limiter: Arc<Limiter>,
     rate_limit_headers: RateLimitHeaders,
     metrics: Arc<PrometheusMetrics>,
+    behaviors: Arc<OverLimitBehaviors>,
+    mapping: Arc<DescriptorMapping>,
+    idempotency_header: Option<String>,
 }
 
 impl MyRateLimiter {
@@ -59,8 +77,32 @@ impl MyRateLimiter {
             limiter,
             rate_limit_headers,
             metrics,
+            behaviors: Arc::default(),
+            mapping: Arc::default(),
+            idempotency_header: None,
         }
     }
+
+    /// Answers the requests exceeding the limits of a namespace, or hitting a
+    /// storage error, as it wants them to be
+    pub fn over_limit_behaviors(mut self, behaviors: Arc<OverLimitBehaviors>) -> Self {
+        self.behaviors = behaviors;
+        self
+    }
+
+    /// Maps the descriptors of the requests to the namespaces and variables
+    /// the limits use
+    pub fn descriptor_mapping(mut self, mapping: Arc<DescriptorMapping>) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    /// Accounts for the requests carrying the same value of that header, in
+    /// their metadata, only once, e.g. when Envoy retries them
+    pub fn idempotency_header(mut self, header: Option<String>) -> Self {
+        self.idempotency_header = header;
+        self
+    }
 }
 
 #[tonic::async_trait]
@@ -72,9 +114,8 @@ impl RateLimitService for MyRateLimiter {
     ) -> Result<Response<RateLimitResponse>, Status> {
         debug!("Request received: {:?}", request);
 
-        let mut values: Vec<HashMap<String, String>> = Vec::default();
         let (metadata, _ext, req) = request.into_parts();
-        let namespace = req.domain;
+        let (namespace, values) = self.mapping.apply(req.domain, &req.descriptors);
         let rl_headers = RateLimitRequestHeaders::new(metadata.into_headers());
         let parent_context =
             global::get_text_map_propagator(|propagator| propagator.extract(&rl_headers));
@@ -95,14 +136,6 @@ impl RateLimitService for MyRateLimiter {
 
         let namespace = namespace.into();
 
-        for descriptor in &req.descriptors {
-            let mut map = HashMap::default();
-            for entry in &descriptor.entries {
-                map.insert(entry.key.clone(), entry.value.clone());
-            }
-            values.push(map);
-        }
-
         // "hits_addend" is optional according to the spec, and should default
         // to 1, However, with the autogenerated structs it defaults to 0.
         let hits_addend = if req.hits_addend == 0 {
@@ -114,56 +147,121 @@ impl RateLimitService for MyRateLimiter {
         let mut ctx = Context::default();
         ctx.list_binding("descriptors".to_string(), values);
 
-        let rate_limited_resp = match &*self.limiter {
-            Limiter::Blocking(limiter) => limiter.check_rate_limited_and_update(
+        let load_counters =
+            self.rate_limit_headers != RateLimitHeaders::None || self.metrics.needs_counters();
+        let token = self
+            .idempotency_header
+            .as_deref()
+            .and_then(|header| rl_headers.get(header));
+        let rate_limited_resp = match (&*self.limiter, token) {
+            (Limiter::Blocking(limiter), None) => limiter.check_rate_limited_and_update(
                 &namespace,
                 &ctx,
                 u64::from(hits_addend),
-                self.rate_limit_headers != RateLimitHeaders::None,
+                load_counters,
             ),
-            Limiter::Async(limiter) => {
+            (Limiter::Blocking(limiter), Some(token)) => limiter
+                .check_rate_limited_and_update_idempotent(
+                    &namespace,
+                    &ctx,
+                    u64::from(hits_addend),
+                    load_counters,
+                    token,
+                ),
+            (Limiter::Async(limiter), None) => {
                 limiter
                     .check_rate_limited_and_update(
                         &namespace,
                         &ctx,
                         u64::from(hits_addend),
-                        self.rate_limit_headers != RateLimitHeaders::None,
+                        load_counters,
+                    )
+                    .await
+            }
+            (Limiter::Async(limiter), Some(token)) => {
+                limiter
+                    .check_rate_limited_and_update_idempotent(
+                        &namespace,
+                        &ctx,
+                        u64::from(hits_addend),
+                        load_counters,
+                        token,
                     )
                     .await
             }
         };
 
-        if let Err(e) = rate_limited_resp {
-            // In this case we could return "Code::Unknown" but that's not
-            // very helpful. When envoy receives "Unknown" it simply lets
-            // the request pass and this cannot be configured using the
-            // "failure_mode_deny" attribute, so it's equivalent to
-            // returning "Code::Ok". That's why we return an "unavailable"
-            // error here. What envoy does after receiving that kind of
-            // error can be configured with "failure_mode_deny". The only
-            // errors that can happen here have to do with connecting to the
-            // limits storage, which should be temporary.
-            error!("Error: {:?}", e);
-            return Err(Status::unavailable("Service unavailable"));
-        }
+        let mut limited_by = None;
+        let mut limit_metadata = None;
+        let (verdict, mut response_headers) = match rate_limited_resp {
+            Ok(mut rate_limited_resp) => {
+                self.metrics
+                    .record_limits(&namespace, &rate_limited_resp, u64::from(hits_addend));
+                let verdict = if rate_limited_resp.limited {
+                    self.metrics
+                        .incr_limited_calls(&namespace, rate_limited_resp.limit_name.as_deref());
+                    limited_by = rate_limited_resp.limit_name.clone();
+                    limit_metadata = rate_limited_resp
+                        .statuses
+                        .iter()
+                        .find(|status| status.limiting)
+                        .filter(|status| !status.limit_metadata.is_empty())
+                        .map(|status| status.limit_metadata.clone());
+                    self.behaviors
+                        .over_limit(&namespace, rate_limited_resp.limit_name.as_deref())
+                } else {
+                    self.metrics.incr_authorized_calls(&namespace);
+                    Verdict::Allow
+                };
+                (
+                    verdict,
+                    self.rate_limit_headers.headers(&mut rate_limited_resp),
+                )
+            }
+            Err(e) => match self.behaviors.on_error(&namespace, &e) {
+                Some(verdict) => (verdict, vec![]),
+                None => {
+                    // In this case we could return "Code::Unknown" but that's not
+                    // very helpful. When envoy receives "Unknown" it simply lets
+                    // the request pass and this cannot be configured using the
+                    // "failure_mode_deny" attribute, so it's equivalent to
+                    // returning "Code::Ok". That's why we return an "unavailable"
+                    // error here. What envoy does after receiving that kind of
+                    // error can be configured with "failure_mode_deny". The only
+                    // errors that can happen here have to do with connecting to the
+                    // limits storage, which should be temporary.
+                    error!("Error: {:?}", e);
+                    return Err(Status::unavailable("Service unavailable"));
+                }
+            },
+        };
 
-        let mut rate_limited_resp = rate_limited_resp.unwrap();
-        let resp_code = if rate_limited_resp.limited {
-            self.metrics
-                .incr_limited_calls(&namespace, rate_limited_resp.limit_name.as_deref());
-            Code::OverLimit
-        } else {
-            self.metrics.incr_authorized_calls(&namespace);
+        verdict.log(
+            &namespace,
+            u64::from(hits_addend),
+            limited_by.as_deref(),
+            limit_metadata.as_ref(),
+        );
+
+        let resp_code = if verdict.allowed() {
             Code::Ok
+        } else {
+            Code::OverLimit
         };
+        if let Some((key, value)) = verdict.header() {
+            response_headers.push(HeaderValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            });
+        }
 
         let reply = RateLimitResponse {
             overall_code: resp_code.into(),
             statuses: vec![],
             request_headers_to_add: vec![],
-            response_headers_to_add: self.rate_limit_headers.headers(&mut rate_limited_resp),
+            response_headers_to_add: response_headers,
             raw_body: vec![],
-            dynamic_metadata: None,
+            dynamic_metadata: limit_metadata.as_ref().map(to_struct),
             quota: None,
         };
 
@@ -171,6 +269,24 @@ impl RateLimitService for MyRateLimiter {
     }
 }
 
+// The metadata of the limit that limited the request, for Envoy to expose it to
+// its filters and access logs
+fn to_struct(metadata: &BTreeMap<String, String>) -> prost_types::Struct {
+    prost_types::Struct {
+        fields: metadata
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.clone(),
+                    prost_types::Value {
+                        kind: Some(prost_types::value::Kind::StringValue(value.clone())),
+                    },
+                )
+            })
+            .collect(),
+    }
+}
+
 struct RateLimitRequestHeaders {
     inner: HeaderMap,
 }
@@ -201,9 +317,17 @@ pub async fn run_envoy_rls_server(
     limiter: Arc<Limiter>,
     rate_limit_headers: RateLimitHeaders,
     metrics: Arc<PrometheusMetrics>,
+    over_limit_behaviors: Arc<OverLimitBehaviors>,
+    descriptor_mapping: Arc<DescriptorMapping>,
+    idempotency_header: Option<String>,
     grpc_reflection_service: bool,
-) -> Result<(), transport::Error> {
-    let rate_limiter = MyRateLimiter::new(limiter, rate_limit_headers, metrics);
+    tls: Option<Arc<TlsAcceptor>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let rate_limiter = MyRateLimiter::new(limiter.clone(), rate_limit_headers, metrics)
+        .over_limit_behaviors(over_limit_behaviors)
+        .descriptor_mapping(descriptor_mapping)
+        .idempotency_header(idempotency_header);
     let svc = RateLimitServiceServer::new(rate_limiter);
 
     let reflection_service = match grpc_reflection_service {
@@ -211,16 +335,39 @@ pub async fn run_envoy_rls_server(
         true => Some(
             tonic_reflection::server::Builder::configure()
                 .register_encoded_file_descriptor_set(rls_proto::RLS_DESCRIPTOR_SET)
+                .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
                 .build_v1()
                 .unwrap(),
         ),
     };
 
-    Server::builder()
+    // `grpc.health.v1.Health`, for gRPC probes and service meshes
+    let (reporter, health_service) = tonic_health::server::health_reporter();
+    let storage_tracking = tokio::spawn(health::track_storage(
+        limiter,
+        reporter,
+        &[RateLimitServiceServer::<MyRateLimiter>::NAME],
+        health::STORAGE_CHECK_PERIOD,
+    ));
+
+    let router = Server::builder()
         .add_service(svc)
-        .add_optional_service(reflection_service)
-        .serve(address.parse().unwrap())
-        .await
+        .add_service(health_service)
+        .add_optional_service(reflection_service);
+    // Once `shutdown` resolves, no new calls are accepted, and the in-flight
+    // ones are completed before returning
+    let served = match tls {
+        None => router.serve_with_shutdown(address.parse()?, shutdown).await,
+        Some(tls) => {
+            let listener = TcpListener::bind(&address).await?;
+            router
+                .serve_with_incoming_shutdown(tls.incoming(listener), shutdown)
+                .await
+        }
+    };
+    storage_tracking.abort();
+    served?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -232,6 +379,7 @@ mod tests {
 
     use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::rate_limit_descriptor::Entry;
     use crate::envoy_rls::server::envoy::extensions::common::ratelimit::v3::RateLimitDescriptor;
+    use crate::over_limit::Behavior;
     use crate::prometheus_metrics::tests::TEST_PROMETHEUS_HANDLE;
     use crate::Configuration;
 
@@ -267,7 +415,7 @@ mod tests {
         );
 
         let limiter = RateLimiter::new(10_000);
-        limiter.add_limit(limit);
+        limiter.add_limit(limit).unwrap();
 
         let rate_limiter = MyRateLimiter::new(
             Arc::new(Limiter::Blocking(limiter)),
@@ -328,6 +476,212 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_returns_the_metadata_of_the_limiting_limit() {
+        let namespace = "test_namespace";
+        let mut limit = Limit::new(namespace, 1, 60, vec![], vec![]);
+        limit.set_metadata(BTreeMap::from([(
+            "team".to_string(),
+            "payments".to_string(),
+        )]));
+        let limiter = RateLimiter::new(10_000);
+        limiter.add_limit(limit).unwrap();
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            Arc::new(PrometheusMetrics::new_with_handle(
+                false,
+                TEST_PROMETHEUS_HANDLE.clone(),
+            )),
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "req.method".to_string(),
+                    value: "GET".to_string(),
+                }],
+                limit: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req.clone().into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(response.dynamic_metadata, None);
+
+        let response = rate_limiter
+            .should_rate_limit(req.into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::OverLimit));
+        let fields = response.dynamic_metadata.unwrap().fields;
+        assert_eq!(fields.len(), 1);
+        assert_eq!(
+            fields["team"].kind,
+            Some(prost_types::value::Kind::StringValue(
+                "payments".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accounts_for_retries_once() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter
+            .add_limit(Limit::new(namespace, 1, 60, vec![], vec![]))
+            .unwrap();
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            Arc::new(PrometheusMetrics::new_with_handle(
+                false,
+                TEST_PROMETHEUS_HANDLE.clone(),
+            )),
+        )
+        .idempotency_header(Some("x-request-id".to_string()));
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "req.method".to_string(),
+                    value: "GET".to_string(),
+                }],
+                limit: None,
+            }],
+            hits_addend: 1,
+        };
+
+        for (request_id, code) in [("a", Code::Ok), ("a", Code::Ok), ("b", Code::OverLimit)] {
+            let mut request = req.clone().into_request();
+            request
+                .metadata_mut()
+                .insert("x-request-id", request_id.parse().unwrap());
+            let response = rate_limiter
+                .should_rate_limit(request)
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(response.overall_code, i32::from(code), "{request_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_soft_denies_over_limit() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter
+            .add_limit(Limit::new(namespace, 0, 60, vec![], vec![]))
+            .unwrap();
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::None,
+            Arc::new(PrometheusMetrics::new_with_handle(
+                false,
+                TEST_PROMETHEUS_HANDLE.clone(),
+            )),
+        )
+        .over_limit_behaviors(Arc::new(OverLimitBehaviors::of(
+            namespace,
+            Behavior::AllowWithHeader,
+            Behavior::Deny,
+        )));
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![],
+                limit: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req.into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        assert_eq!(
+            response.response_headers_to_add,
+            vec![header_value("x-limitador-soft-deny", "over_limit")],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_returns_draft_ietf_headers() {
+        let namespace = "test_namespace";
+        let limiter = RateLimiter::new(10_000);
+        limiter
+            .add_limit(Limit::new(
+                namespace,
+                2,
+                60,
+                vec![],
+                vec!["descriptors[0]['app.id']"
+                    .try_into()
+                    .expect("failed parsing!")],
+            ))
+            .unwrap();
+        limiter
+            .add_limit(Limit::new(
+                namespace,
+                10,
+                3600,
+                vec![],
+                vec!["descriptors[0]['app.id']"
+                    .try_into()
+                    .expect("failed parsing!")],
+            ))
+            .unwrap();
+
+        let rate_limiter = MyRateLimiter::new(
+            Arc::new(Limiter::Blocking(limiter)),
+            RateLimitHeaders::DraftIetf,
+            Arc::new(PrometheusMetrics::new_with_handle(
+                false,
+                TEST_PROMETHEUS_HANDLE.clone(),
+            )),
+        );
+
+        let req = RateLimitRequest {
+            domain: namespace.to_string(),
+            descriptors: vec![RateLimitDescriptor {
+                entries: vec![Entry {
+                    key: "app.id".to_string(),
+                    value: "1".to_string(),
+                }],
+                limit: None,
+            }],
+            hits_addend: 1,
+        };
+
+        let response = rate_limiter
+            .should_rate_limit(req.into_request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.overall_code, i32::from(Code::Ok));
+        // The most constrained is the limit of 2 per minute
+        let headers = response.response_headers_to_add;
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0], header_value("RateLimit-Limit", "2"));
+        assert_eq!(headers[1], header_value("RateLimit-Remaining", "1"));
+        assert_eq!(headers[2].key, "RateLimit-Reset");
+        assert!(headers[2].value.parse::<u64>().unwrap() <= 60);
+    }
+
     #[tokio::test]
     async fn test_returns_ok_when_no_limits_apply() {
         // No limits saved
@@ -429,7 +783,7 @@ mod tests {
         ]
         .into_iter()
         .for_each(|limit| {
-            limiter.add_limit(limit);
+            limiter.add_limit(limit).unwrap();
         });
 
         let rate_limiter = MyRateLimiter::new(
@@ -500,7 +854,7 @@ mod tests {
         );
 
         let limiter = RateLimiter::new(10_000);
-        limiter.add_limit(limit);
+        limiter.add_limit(limit).unwrap();
 
         let rate_limiter = MyRateLimiter::new(
             Arc::new(Limiter::Blocking(limiter)),
@@ -577,7 +931,7 @@ mod tests {
         );
 
         let limiter = RateLimiter::new(10_000);
-        limiter.add_limit(limit);
+        limiter.add_limit(limit).unwrap();
 
         let rate_limiter = MyRateLimiter::new(
             Arc::new(Limiter::Blocking(limiter)),
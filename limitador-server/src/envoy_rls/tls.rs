@@ -0,0 +1,298 @@
+use crate::config::RlsTlsConfiguration;
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    select_next_proto, AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode,
+};
+use openssl::x509::{X509Name, X509Ref};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_openssl::SslStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RELOAD_CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+/// Terminates TLS for the RLS and admin gRPC servers, requiring a client certificate signed by
+/// the client CA, if any, and holding one of the allowed SANs, if any. The
+/// certificates are reloaded whenever their files change, new connections
+/// picking them up while the established ones are left alone.
+pub struct TlsAcceptor {
+    config: RlsTlsConfiguration,
+    acceptor: RwLock<Arc<SslAcceptor>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: RlsTlsConfiguration) -> Result<Self, ErrorStack> {
+        let acceptor = build_acceptor(&config)?;
+        Ok(Self {
+            config,
+            acceptor: RwLock::new(Arc::new(acceptor)),
+        })
+    }
+
+    fn acceptor(&self) -> Arc<SslAcceptor> {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    fn reload(&self) -> Result<(), ErrorStack> {
+        let acceptor = build_acceptor(&self.config)?;
+        *self.acceptor.write().unwrap() = Arc::new(acceptor);
+        Ok(())
+    }
+
+    // Paths are resolved on every check, as a Kubernetes secret gets updated
+    // by swapping a symlink, rather than by writing to the files
+    fn fingerprint(&self) -> Vec<(Option<PathBuf>, Option<SystemTime>)> {
+        [
+            Some(&self.config.cert_file),
+            Some(&self.config.key_file),
+            self.config.client_ca_file.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            (
+                fs::canonicalize(path).ok(),
+                fs::metadata(path).and_then(|m| m.modified()).ok(),
+            )
+        })
+        .collect()
+    }
+
+    /// Periodically checks the certificate files, reloading them on change.
+    /// Should the new ones be invalid, e.g. a key not matching its
+    /// certificate yet, the current ones are kept and the reload retried.
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut last = self.fingerprint();
+            let mut interval = tokio::time::interval(RELOAD_CHECK_PERIOD);
+            loop {
+                interval.tick().await;
+                let current = self.fingerprint();
+                if current == last {
+                    continue;
+                }
+                match self.reload() {
+                    Ok(_) => {
+                        info!("certificates changed; reloaded RLS TLS configuration");
+                        last = current;
+                    }
+                    Err(e) => error!("Failed reloading RLS TLS configuration: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Accepts the connections on `listener`, yielding the ones whose TLS
+    /// handshake succeeded
+    pub fn incoming(
+        self: Arc<Self>,
+        listener: TcpListener,
+    ) -> ReceiverStream<io::Result<TlsStream>> {
+        let (tx, rx) = mpsc::channel(128);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("Failed accepting RLS connection: {}", e);
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = self.acceptor();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&acceptor, stream))
+                        .await
+                    {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(Ok(stream)).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", peer, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", peer),
+                    }
+                });
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+async fn handshake(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+) -> Result<TlsStream, Box<dyn std::error::Error + Send + Sync>> {
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, stream)?;
+    Pin::new(&mut stream).accept().await?;
+    Ok(TlsStream(stream))
+}
+
+fn build_acceptor(config: &RlsTlsConfiguration) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_private_key_file(&config.key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&config.cert_file)?;
+    builder.check_private_key()?;
+    // gRPC is HTTP/2 only
+    builder.set_alpn_select_callback(|_, client| {
+        select_next_proto(b"\x02h2", client).ok_or(AlpnError::NOACK)
+    });
+
+    if let Some(client_ca_file) = &config.client_ca_file {
+        builder.set_ca_file(client_ca_file)?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca_file)?);
+        let allowed_sans = config.allowed_sans.clone();
+        builder.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            move |preverified, ctx| {
+                // Only the client's own certificate is checked for its SANs,
+                // not the ones of the CAs in its chain
+                if !preverified || ctx.error_depth() > 0 || allowed_sans.is_empty() {
+                    return preverified;
+                }
+                ctx.current_cert()
+                    .is_some_and(|cert| has_allowed_san(cert, &allowed_sans))
+            },
+        );
+    }
+    Ok(builder.build())
+}
+
+// Whether any of the DNS names, URIs or IP addresses of the certificate is
+// allowed
+fn has_allowed_san(cert: &X509Ref, allowed_sans: &[String]) -> bool {
+    let Some(names) = cert.subject_alt_names() else {
+        return false;
+    };
+    names.iter().any(|name| {
+        let san = match (name.dnsname(), name.uri(), name.ipaddress()) {
+            (Some(dns), _, _) => dns.to_string(),
+            (_, Some(uri), _) => uri.to_string(),
+            (_, _, Some(ip)) => match ip.len() {
+                4 => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())).to_string(),
+                16 => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())).to_string(),
+                _ => return false,
+            },
+            _ => return false,
+        };
+        allowed_sans.iter().any(|allowed| *allowed == san)
+    })
+}
+
+pub struct TlsStream(SslStream<TcpStream>);
+
+impl Connected for TlsStream {
+    type ConnectInfo = TcpConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.0.get_ref().connect_info()
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_allowed_san, TlsAcceptor};
+    use crate::config::RlsTlsConfiguration;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder, X509};
+
+    fn certificate_with_sans(sans: &SubjectAlternativeName) -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "envoy").unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let extension = sans.build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(extension).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn matches_allowed_sans() {
+        let cert = certificate_with_sans(
+            SubjectAlternativeName::new()
+                .dns("envoy.example.com")
+                .uri("spiffe://cluster.local/ns/default/sa/envoy")
+                .ip("10.0.0.1"),
+        );
+
+        assert!(has_allowed_san(&cert, &["envoy.example.com".into()]));
+        assert!(has_allowed_san(
+            &cert,
+            &["spiffe://cluster.local/ns/default/sa/envoy".into()]
+        ));
+        assert!(has_allowed_san(&cert, &["10.0.0.1".into()]));
+        assert!(!has_allowed_san(
+            &cert,
+            &["other.example.com".into(), "10.0.0.2".into()]
+        ));
+    }
+
+    #[test]
+    fn fails_on_missing_certificates() {
+        let config = RlsTlsConfiguration {
+            cert_file: "/does/not/exist.crt".into(),
+            key_file: "/does/not/exist.key".into(),
+            client_ca_file: None,
+            allowed_sans: vec![],
+        };
+        assert!(TlsAcceptor::new(config).is_err());
+    }
+}
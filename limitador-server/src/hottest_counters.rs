@@ -0,0 +1,110 @@
+use crate::prometheus_metrics::PrometheusMetrics;
+use crate::Limiter;
+use limitador::counter::Counter;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const HOTTEST_COUNTERS_PERIOD: Duration = Duration::from_secs(15);
+
+/// Exports the `top` hottest counters of each namespace, i.e. the ones the
+/// closest to their limit, as gauges of their used and remaining hits,
+/// refreshing them every `period`.
+pub async fn track_hottest(
+    limiter: Arc<Limiter>,
+    metrics: Arc<PrometheusMetrics>,
+    top: usize,
+    period: Duration,
+) {
+    let mut ticks = tokio::time::interval(period);
+    loop {
+        ticks.tick().await;
+        // The counters of limits defined on a pattern are shared by all the
+        // namespaces it matches, so are looked up under the pattern itself
+        let namespaces = match limiter.as_ref() {
+            Limiter::Blocking(limiter) => limiter
+                .get_namespaces()
+                .into_iter()
+                .chain(limiter.get_namespace_patterns()),
+            Limiter::Async(limiter) => limiter
+                .get_namespaces()
+                .into_iter()
+                .chain(limiter.get_namespace_patterns()),
+        };
+        let mut counters = Vec::new();
+        for namespace in namespaces {
+            let of_namespace = match limiter.as_ref() {
+                Limiter::Blocking(limiter) => limiter.get_counters(&namespace),
+                Limiter::Async(limiter) => limiter.get_counters(&namespace).await,
+            };
+            match of_namespace {
+                Ok(of_namespace) => counters.extend(hottest(of_namespace, top)),
+                Err(e) => warn!(
+                    "Failed to load the counters of namespace '{}': {}",
+                    namespace.as_ref(),
+                    e
+                ),
+            }
+        }
+        metrics.set_hottest_counters(&counters);
+    }
+}
+
+fn used(counter: &Counter) -> u64 {
+    let max_value = counter.max_value();
+    max_value - counter.remaining().unwrap_or(max_value).min(max_value)
+}
+
+// The `top` counters the closest to their limit, relatively to it
+fn hottest(counters: impl IntoIterator<Item = Counter>, top: usize) -> Vec<Counter> {
+    let ratio = |counter: &Counter| match counter.max_value() {
+        0 => 1.0,
+        max_value => used(counter) as f64 / max_value as f64,
+    };
+    let mut counters: Vec<_> = counters.into_iter().collect();
+    counters.sort_by(|a, b| {
+        ratio(b)
+            .total_cmp(&ratio(a))
+            .then_with(|| used(b).cmp(&used(a)))
+    });
+    counters.truncate(top);
+    counters
+}
+
+/// The hits counted so far in the current window, and the ones left
+pub fn used_and_remaining(counter: &Counter) -> (u64, u64) {
+    let used = used(counter);
+    (used, counter.max_value() - used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hottest, used_and_remaining};
+    use limitador::counter::Counter;
+    use limitador::limit::Limit;
+    use std::collections::BTreeMap;
+
+    fn counter(id: &str, max_value: u64, remaining: u64) -> Counter {
+        let limit = Limit::with_id(id, "ns", max_value, 60, vec![], vec![]);
+        let mut counter = Counter::with_variables(limit, BTreeMap::default());
+        counter.set_remaining(remaining);
+        counter
+    }
+
+    #[test]
+    fn picks_the_counters_closest_to_their_limit() {
+        let counters = vec![
+            counter("cold", 100, 90),
+            counter("hot", 10, 1),
+            counter("warm", 1000, 500),
+            counter("exhausted", 5, 0),
+        ];
+
+        let hottest: Vec<_> = hottest(counters, 3)
+            .iter()
+            .map(|counter| counter.id().unwrap().to_string())
+            .collect();
+        assert_eq!(hottest, vec!["exhausted", "hot", "warm"]);
+
+        assert_eq!(used_and_remaining(&counter("hot", 10, 1)), (9, 1));
+    }
+}
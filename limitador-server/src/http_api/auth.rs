@@ -0,0 +1,493 @@
+use crate::config::{HttpAuthConfiguration, OidcConfiguration};
+use actix_web::dev::Payload;
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use paperclip::actix::OperationModifier;
+use paperclip::v2::schema::Apiv2Schema;
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Keys are refetched when a token is signed with an unknown one, but no more
+// often than this
+const JWKS_MIN_REFRESH_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+#[derive(Deserialize)]
+struct StaticToken {
+    token: String,
+    role: Role,
+    /// Who the bearer is, as recorded in the audit log
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Tells the role of the bearer of a token, either one of the static tokens or
+/// a JWT issued by the OIDC provider
+pub struct Authenticator {
+    tokens: Vec<StaticToken>,
+    oidc: Option<OidcValidator>,
+}
+
+impl Authenticator {
+    pub async fn new(config: HttpAuthConfiguration) -> Result<Self, String> {
+        let tokens = match &config.tokens_file {
+            None => Vec::new(),
+            Some(path) => {
+                let file = std::fs::File::open(path)
+                    .map_err(|e| format!("Couldn't read file '{path}': {e}"))?;
+                serde_yaml::from_reader(file)
+                    .map_err(|e| format!("Couldn't parse '{path}': {e}"))?
+            }
+        };
+        let oidc = match config.oidc {
+            None => None,
+            Some(oidc) => Some(OidcValidator::discover(oidc).await?),
+        };
+        Ok(Self { tokens, oidc })
+    }
+
+    #[cfg(test)]
+    pub fn with_tokens(tokens: Vec<(&str, Role)>) -> Self {
+        Self {
+            tokens: tokens
+                .into_iter()
+                .map(|(token, role)| StaticToken {
+                    token: token.to_string(),
+                    role,
+                    name: None,
+                })
+                .collect(),
+            oidc: None,
+        }
+    }
+
+    /// Who the bearer of the `token` is, provided they have the `required`
+    /// role
+    pub async fn authorize(
+        &self,
+        token: Option<&str>,
+        required: Role,
+    ) -> Result<String, AuthError> {
+        let token = token.ok_or(AuthError::Unauthorized)?;
+        match self.identify(token).await {
+            Some((role, who)) if role >= required => Ok(who),
+            Some(_) => Err(AuthError::Forbidden),
+            None => Err(AuthError::Unauthorized),
+        }
+    }
+
+    // The role of the bearer, along with who they are
+    async fn identify(&self, token: &str) -> Option<(Role, String)> {
+        let known = self
+            .tokens
+            .iter()
+            .find(|known| {
+                known.token.len() == token.len()
+                    && openssl::memcmp::eq(known.token.as_bytes(), token.as_bytes())
+            })
+            .map(|known| {
+                let name = known.name.as_deref().unwrap_or("static token");
+                (known.role, name.to_string())
+            });
+        match (known, &self.oidc) {
+            (Some(known), _) => Some(known),
+            (None, Some(oidc)) => oidc.identify(token).await,
+            (None, None) => None,
+        }
+    }
+}
+
+struct OidcValidator {
+    config: OidcConfiguration,
+    jwks_uri: String,
+    client: reqwest::Client,
+    keys: RwLock<JwkSet>,
+    last_refresh: Mutex<Instant>,
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    jwks_uri: String,
+}
+
+impl OidcValidator {
+    async fn discover(config: OidcConfiguration) -> Result<Self, String> {
+        let client = reqwest::Client::new();
+        let discovery = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = fetch(&client, &discovery).await?;
+        let keys = fetch(&client, &metadata.jwks_uri).await?;
+        Ok(Self {
+            config,
+            jwks_uri: metadata.jwks_uri,
+            client,
+            keys: RwLock::new(keys),
+            last_refresh: Mutex::new(Instant::now()),
+        })
+    }
+
+    #[cfg(test)]
+    fn with_keys(config: OidcConfiguration, keys: JwkSet) -> Self {
+        Self {
+            config,
+            jwks_uri: String::new(),
+            client: reqwest::Client::new(),
+            keys: RwLock::new(keys),
+            last_refresh: Mutex::new(Instant::now()),
+        }
+    }
+
+    // The key with the `kid`, along with the algorithms tokens signed with it
+    // may use
+    fn key(&self, kid: &str) -> Option<(DecodingKey, Vec<Algorithm>)> {
+        let keys = self.keys.read().unwrap();
+        let jwk = keys.find(kid)?;
+        let algorithms = algorithms(jwk);
+        if algorithms.is_empty() {
+            return None;
+        }
+        Some((DecodingKey::from_jwk(jwk).ok()?, algorithms))
+    }
+
+    // Picks up keys rotated by the provider
+    async fn refresh(&self) {
+        {
+            let mut last_refresh = self.last_refresh.lock().unwrap();
+            if last_refresh.elapsed() < JWKS_MIN_REFRESH_PERIOD {
+                return;
+            }
+            *last_refresh = Instant::now();
+        }
+        match fetch(&self.client, &self.jwks_uri).await {
+            Ok(keys) => *self.keys.write().unwrap() = keys,
+            Err(e) => warn!("Failed refreshing the OIDC provider's keys: {}", e),
+        }
+    }
+
+    async fn identify(&self, token: &str) -> Option<(Role, String)> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let kid = header.kid?;
+        let (key, algorithms) = match self.key(&kid) {
+            Some(key) => key,
+            None => {
+                self.refresh().await;
+                self.key(&kid)?
+            }
+        };
+
+        // The token doesn't get to pick how it's verified: its `alg` must be
+        // one of the key's
+        if !algorithms.contains(&header.alg) {
+            debug!(
+                "Rejecting JWT: {:?} isn't an algorithm of key '{}'",
+                header.alg, kid
+            );
+            return None;
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&self.config.issuer]);
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|e| debug!("Rejecting JWT: {}", e))
+            .ok()?
+            .claims;
+
+        let roles = self
+            .config
+            .roles_claim
+            .split('.')
+            .try_fold(&claims, |claims, name| claims.get(name))?;
+        let has = |role: &str| match roles {
+            serde_json::Value::String(value) => value == role,
+            serde_json::Value::Array(values) => values.iter().any(|value| value == role),
+            _ => false,
+        };
+        let role = if has(&self.config.admin_role) {
+            Role::Admin
+        } else if has(&self.config.read_only_role) {
+            Role::ReadOnly
+        } else {
+            return None;
+        };
+        let subject = claims.get("sub").and_then(|sub| sub.as_str());
+        Some((role, subject.unwrap_or("oidc").to_string()))
+    }
+}
+
+// The algorithms a key is used with: the one it states, or else the asymmetric
+// ones of its type. Shared secrets are never accepted from a provider
+fn algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(algorithm) = jwk.common.key_algorithm {
+        return match algorithm {
+            KeyAlgorithm::ES256 => vec![Algorithm::ES256],
+            KeyAlgorithm::ES384 => vec![Algorithm::ES384],
+            KeyAlgorithm::RS256 => vec![Algorithm::RS256],
+            KeyAlgorithm::RS384 => vec![Algorithm::RS384],
+            KeyAlgorithm::RS512 => vec![Algorithm::RS512],
+            KeyAlgorithm::PS256 => vec![Algorithm::PS256],
+            KeyAlgorithm::PS384 => vec![Algorithm::PS384],
+            KeyAlgorithm::PS512 => vec![Algorithm::PS512],
+            KeyAlgorithm::EdDSA => vec![Algorithm::EdDSA],
+            _ => Vec::new(),
+        };
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => Vec::new(),
+    }
+}
+
+async fn fetch<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<T, String> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Couldn't fetch '{url}': {e}"))?
+        .json()
+        .await
+        .map_err(|e| format!("Couldn't parse '{url}': {e}"))
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Unauthorized,
+    Forbidden,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "Unauthorized"),
+            Self::Forbidden => write!(f, "Forbidden"),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Self::Unauthorized = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response
+            .insert_header(ContentType::plaintext())
+            .body(self.to_string())
+    }
+}
+
+// Every request is let through when no `Authenticator` was configured. It's
+// registered as an `Option`, so that the app is the same either way. Who the
+// bearer is, when authenticated, is returned
+async fn authorize(
+    authenticator: Option<web::Data<Authenticator>>,
+    token: Option<String>,
+    required: Role,
+) -> Result<Option<String>, AuthError> {
+    let Some(authenticator) = authenticator else {
+        return Ok(None);
+    };
+    authenticator
+        .authorize(token.as_deref(), required)
+        .await
+        .map(Some)
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    bearer(value).map(String::from)
+}
+
+/// The token of the value of an `Authorization` header, if a bearer one
+pub fn bearer(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+fn extract<T: 'static>(
+    req: &HttpRequest,
+    required: Role,
+    access: fn(String) -> T,
+) -> Pin<Box<dyn Future<Output = Result<T, AuthError>>>> {
+    let authenticator = req
+        .app_data::<Option<web::Data<Authenticator>>>()
+        .cloned()
+        .flatten();
+    let token = bearer_token(req);
+    let peer = req
+        .peer_addr()
+        .map_or("unknown".to_string(), |peer| peer.ip().to_string());
+    Box::pin(async move {
+        let who = authorize(authenticator, token, required).await?;
+        Ok(access(who.unwrap_or_else(|| format!("anonymous@{peer}"))))
+    })
+}
+
+/// Extractor granting access to the bearer of a read-only or admin token
+#[derive(Debug)]
+pub struct ReadAccess;
+
+impl FromRequest for ReadAccess {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        extract(req, Role::ReadOnly, |_| ReadAccess)
+    }
+}
+
+impl Apiv2Schema for ReadAccess {}
+impl OperationModifier for ReadAccess {}
+
+/// Extractor granting access to the bearer of an admin token, telling who
+/// they are
+#[derive(Debug)]
+pub struct AdminAccess(pub String);
+
+impl FromRequest for AdminAccess {
+    type Error = AuthError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        extract(req, Role::Admin, AdminAccess)
+    }
+}
+
+impl Apiv2Schema for AdminAccess {}
+impl OperationModifier for AdminAccess {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use openssl::rsa::Rsa;
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const ISSUER: &str = "https://issuer.example.com";
+    const AUDIENCE: &str = "limitador";
+
+    fn base64_url(bytes: &[u8]) -> String {
+        openssl::base64::encode_block(bytes)
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_")
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // A validator trusting a single RS256 key, with the key to sign tokens
+    fn validator() -> (OidcValidator, EncodingKey) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let keys: JwkSet = serde_json::from_value(json!({
+            "keys": [{
+                "kty": "RSA",
+                "kid": "test",
+                "alg": "RS256",
+                "n": base64_url(&rsa.n().to_vec()),
+                "e": base64_url(&rsa.e().to_vec()),
+            }]
+        }))
+        .unwrap();
+        let config = OidcConfiguration {
+            issuer: ISSUER.to_string(),
+            audience: Some(AUDIENCE.to_string()),
+            roles_claim: "realm_access.roles".to_string(),
+            admin_role: "admin".to_string(),
+            read_only_role: "viewer".to_string(),
+        };
+        let signing = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+        (OidcValidator::with_keys(config, keys), signing)
+    }
+
+    fn token(key: &EncodingKey, alg: Algorithm, audience: &str, exp: u64) -> String {
+        let mut header = Header::new(alg);
+        header.kid = Some("test".to_string());
+        let claims = json!({
+            "iss": ISSUER,
+            "aud": audience,
+            "sub": "alice",
+            "exp": exp,
+            "realm_access": { "roles": ["admin"] },
+        });
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_tokens() {
+        let (validator, key) = validator();
+        let token = token(&key, Algorithm::RS256, AUDIENCE, now() + 3600);
+        assert_eq!(
+            validator.identify(&token).await,
+            Some((Role::Admin, "alice".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_tokens() {
+        let (validator, key) = validator();
+        let token = token(&key, Algorithm::RS256, AUDIENCE, now() - 3600);
+        assert_eq!(validator.identify(&token).await, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_for_other_audiences() {
+        let (validator, key) = validator();
+        let token = token(&key, Algorithm::RS256, "someone-else", now() + 3600);
+        assert_eq!(validator.identify(&token).await, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_signed_with_another_algorithm_than_the_keys() {
+        let (validator, key) = validator();
+        let token = token(&key, Algorithm::RS384, AUDIENCE, now() + 3600);
+        assert_eq!(validator.identify(&token).await, None);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::config::HttpOverLimitConfiguration;
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use limitador::limit::Namespace;
+use limitador::CheckResult;
+use std::time::Duration;
+
+/// The response to the requests the HTTP API denies, as configured
+pub struct LimitedResponse {
+    status: StatusCode,
+    retry_after: bool,
+    body: Option<String>,
+}
+
+impl Default for LimitedResponse {
+    fn default() -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: false,
+            body: None,
+        }
+    }
+}
+
+impl LimitedResponse {
+    pub fn new(config: HttpOverLimitConfiguration) -> Result<Self, String> {
+        let status = StatusCode::from_u16(config.status)
+            .ok()
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(|| format!("Invalid over-limit status: {}", config.status))?;
+        // Checked once, rather than failing on every request denied
+        if let Some(body) = &config.body {
+            let sample = render(body, "namespace", "name", "id", Some(1));
+            serde_json::from_str::<serde_json::Value>(&sample)
+                .map_err(|e| format!("Invalid over-limit body, once rendered: {e}"))?;
+        }
+        Ok(Self {
+            status,
+            retry_after: config.retry_after,
+            body: config.body,
+        })
+    }
+
+    /// Starts the response to a denied request, `checked` against the limits
+    /// if it got that far, with the status and `Retry-After` header
+    pub fn start(&self, checked: Option<&CheckResult>) -> HttpResponseBuilder {
+        let mut resp = HttpResponse::build(self.status);
+        if self.retry_after {
+            if let Some(retry_after) = checked.and_then(retry_after) {
+                resp.insert_header((header::RETRY_AFTER, retry_after.as_secs()));
+            }
+        }
+        resp
+    }
+
+    /// Finishes the response with the body rendered from the template, or
+    /// with `default` when there's none
+    pub fn finish(
+        &self,
+        mut resp: HttpResponseBuilder,
+        namespace: &Namespace,
+        checked: Option<&CheckResult>,
+        default: impl FnOnce(HttpResponseBuilder) -> HttpResponse,
+    ) -> HttpResponse {
+        let Some(body) = &self.body else {
+            return default(resp);
+        };
+        let limiting =
+            checked.and_then(|checked| checked.statuses.iter().find(|status| status.limiting));
+        let body = render(
+            body,
+            namespace.as_ref(),
+            checked
+                .and_then(|checked| checked.limit_name.as_deref())
+                .unwrap_or_default(),
+            limiting
+                .and_then(|status| status.limit_id.as_deref())
+                .unwrap_or_default(),
+            checked.and_then(retry_after).map(|d| d.as_secs()),
+        );
+        resp.insert_header(ContentType::json()).body(body)
+    }
+}
+
+// Until the counter that limited the request resets, rounded up to the second
+fn retry_after(checked: &CheckResult) -> Option<Duration> {
+    let limiting = checked.statuses.iter().find(|status| status.limiting)?;
+    let resets_in = limiting.resets_in.unwrap_or(limiting.window);
+    let secs = resets_in.as_secs() + u64::from(resets_in.subsec_nanos() > 0);
+    Some(Duration::from_secs(secs.max(1)))
+}
+
+// The strings are JSON escaped, for the template to quote them, while the
+// `retry_after` is either a number or `null`
+fn render(
+    template: &str,
+    namespace: &str,
+    limit_name: &str,
+    limit_id: &str,
+    retry_after: Option<u64>,
+) -> String {
+    let escaped = |value: &str| {
+        let quoted = serde_json::to_string(value).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    };
+    template
+        .replace("{{namespace}}", &escaped(namespace))
+        .replace("{{limit_name}}", &escaped(limit_name))
+        .replace("{{limit_id}}", &escaped(limit_id))
+        .replace(
+            "{{retry_after}}",
+            &retry_after.map_or("null".to_string(), |secs| secs.to_string()),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, LimitedResponse};
+    use crate::config::HttpOverLimitConfiguration;
+
+    #[test]
+    fn renders_json_escaped_values() {
+        let body = render(
+            r#"{"error": "limited by {{limit_name}}", "namespace": "{{namespace}}", "retry_after": {{retry_after}}}"#,
+            "ns",
+            r#"per "user""#,
+            "",
+            None,
+        );
+        assert_eq!(
+            body,
+            r#"{"error": "limited by per \"user\"", "namespace": "ns", "retry_after": null}"#
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configurations() {
+        assert!(LimitedResponse::new(HttpOverLimitConfiguration {
+            status: 200,
+            ..Default::default()
+        })
+        .is_err());
+        assert!(LimitedResponse::new(HttpOverLimitConfiguration {
+            body: Some(r#"{"limit": {{limit_name}}}"#.to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(LimitedResponse::new(HttpOverLimitConfiguration {
+            status: 403,
+            retry_after: true,
+            body: Some(r#"{"limit": "{{limit_name}}"}"#.to_string()),
+        })
+        .is_ok());
+    }
+}
@@ -6,4 +6,6 @@ mod request_types;
 
 pub use request_types::Limit as LimitVO;
 
+pub mod auth;
+pub mod limited;
 pub mod server;
@@ -1,5 +1,7 @@
-use limitador::counter::Counter as LimitadorCounter;
-use limitador::limit::{Expression, Limit as LimitadorLimit, ParseError, Predicate};
+use limitador::counter::{Counter as LimitadorCounter, DeltaExceedsMaxValue};
+use limitador::limit::{
+    InvalidLimit, Limit as LimitadorLimit, LimitBuilder, MaxValueOverride as LimitadorOverride,
+};
 use paperclip::actix::Apiv2Schema;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
@@ -13,6 +15,9 @@ pub struct CheckAndReportInfo {
     pub values: HashMap<String, String>,
     pub delta: u64,
     pub response_headers: Option<String>,
+    /// Identifies the request across its retries, for it to be accounted for
+    /// only once by `/check_and_report`
+    pub idempotency_key: Option<String>,
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
@@ -20,10 +25,17 @@ pub struct Limit {
     id: Option<String>,
     namespace: String,
     max_value: u64,
+    max_value_from: Option<String>,
     seconds: u64,
     name: Option<String>,
     conditions: Vec<String>,
     variables: Vec<String>,
+    cost: Option<String>,
+    ttl: Option<u64>,
+    overrides: Vec<MaxValueOverride>,
+    /// Arbitrary labels of the limit, e.g. the team owning it
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
 }
 
 impl From<&LimitadorLimit> for Limit {
@@ -32,47 +44,130 @@ impl From<&LimitadorLimit> for Limit {
             id: ll.id().map(|id| id.to_string()),
             namespace: ll.namespace().as_ref().to_string(),
             max_value: ll.max_value(),
+            max_value_from: ll.max_value_from().map(|expr| expr.to_string()),
             seconds: ll.seconds(),
             name: ll.name().map(|name| name.to_string()),
             conditions: ll.conditions().into_iter().collect(),
             variables: ll.variables().into_iter().collect(),
+            cost: ll.cost().map(|cost| cost.to_string()),
+            ttl: ll.ttl(),
+            overrides: ll.overrides().iter().map(|o| o.into()).collect(),
+            metadata: ll.metadata().clone(),
         }
     }
 }
 
 impl TryFrom<Limit> for LimitadorLimit {
-    type Error = ParseError;
+    type Error = InvalidLimit;
 
     fn try_from(limit: Limit) -> Result<Self, Self::Error> {
-        let conditions: Result<Vec<Predicate>, ParseError> =
-            limit.conditions.into_iter().map(|p| p.try_into()).collect();
-        let variables: Result<Vec<Expression>, ParseError> =
-            limit.variables.into_iter().map(|v| v.try_into()).collect();
+        let mut builder = LimitBuilder::new(limit.namespace, limit.max_value, limit.seconds);
+        if let Some(id) = limit.id {
+            builder = builder.id(id);
+        }
+        if let Some(name) = limit.name {
+            builder = builder.name(name);
+        }
+        if let Some(max_value_from) = limit.max_value_from {
+            builder = builder.max_value_from(max_value_from);
+        }
+        if let Some(cost) = limit.cost {
+            builder = builder.cost(cost);
+        }
+        if let Some(ttl) = limit.ttl {
+            builder = builder.ttl(ttl);
+        }
+        for condition in limit.conditions {
+            builder = builder.condition(condition);
+        }
+        for variable in limit.variables {
+            builder = builder.variable(variable);
+        }
+        for (key, value) in limit.metadata {
+            builder = builder.metadata(key, value);
+        }
 
-        let mut limitador_limit = if let Some(id) = limit.id {
-            Self::with_id(
-                id,
-                limit.namespace,
-                limit.max_value,
-                limit.seconds,
-                conditions?,
-                variables?,
-            )
-        } else {
-            Self::new(
-                limit.namespace,
-                limit.max_value,
-                limit.seconds,
-                conditions?,
-                variables?,
-            )
+        let mut limitador_limit = builder.build()?;
+        for o in limit.overrides {
+            limitador_limit.set_override(o.variables, o.max_value)?;
+        }
+
+        Ok(limitador_limit)
+    }
+}
+
+/// Why a request got rejected, pointing at the offending part of the limit
+/// when it's an invalid one
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct ValidationError {
+    pub message: String,
+    pub field: Option<String>,
+    pub token: Option<String>,
+    pub position: Option<usize>,
+}
+
+impl ValidationError {
+    pub fn new(message: String) -> Self {
+        Self {
+            message,
+            field: None,
+            token: None,
+            position: None,
+        }
+    }
+}
+
+impl From<InvalidLimit> for ValidationError {
+    fn from(err: InvalidLimit) -> Self {
+        let message = err.to_string();
+        let (field, token, position) = match err {
+            InvalidLimit::InvalidNamespace(_) => ("namespace", None, None),
+            InvalidLimit::InvalidId(_) => ("id", None, None),
+            InvalidLimit::InvalidWindow(_) => ("seconds", None, None),
+            InvalidLimit::InvalidTtl(_) => ("ttl", None, None),
+            InvalidLimit::InvalidCondition { token, position } => {
+                ("conditions", Some(token), Some(position))
+            }
+            InvalidLimit::InvalidExpression {
+                field,
+                token,
+                position,
+            } => match field {
+                "variable" => ("variables", Some(token), Some(position)),
+                field => (field, Some(token), Some(position)),
+            },
+            InvalidLimit::InvalidOverride(_) => ("overrides", None, None),
         };
+        Self {
+            message,
+            field: Some(field.to_string()),
+            token,
+            position,
+        }
+    }
+}
 
-        if let Some(name) = limit.name {
-            limitador_limit.set_name(name)
+impl From<DeltaExceedsMaxValue> for ValidationError {
+    fn from(err: DeltaExceedsMaxValue) -> Self {
+        Self {
+            field: Some("delta".to_string()),
+            ..Self::new(err.to_string())
         }
+    }
+}
 
-        Ok(limitador_limit)
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Apiv2Schema)]
+pub struct MaxValueOverride {
+    pub variables: BTreeMap<String, String>,
+    pub max_value: u64,
+}
+
+impl From<&LimitadorOverride> for MaxValueOverride {
+    fn from(o: &LimitadorOverride) -> Self {
+        Self {
+            variables: o.variables().clone(),
+            max_value: o.max_value(),
+        }
     }
 }
 
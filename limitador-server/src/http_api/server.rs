@@ -1,9 +1,20 @@
-use crate::http_api::request_types::{CheckAndReportInfo, Counter, Limit};
+use crate::audit;
+use crate::envoy_rls::health::CachedStorageCheck;
+use crate::http_api::auth::{AdminAccess, Authenticator, ReadAccess};
+use crate::http_api::limited::LimitedResponse;
+use crate::http_api::request_types::{
+    CheckAndReportInfo, Counter, Limit, MaxValueOverride, ValidationError,
+};
+use crate::over_limit::{OverLimitBehaviors, Verdict};
 use crate::prometheus_metrics::PrometheusMetrics;
-use crate::Limiter;
+use crate::{LimitadorServerError, Limiter};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::ContentType;
 use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, ResponseError};
-use actix_web::{App, HttpServer};
-use limitador::limit::Context;
+use actix_web::{App, HttpRequest, HttpServer};
+use limitador::counter::Counter as LimitadorCounter;
+use limitador::errors::LimitadorError;
+use limitador::limit::{Context, InvalidLimit, Limit as LimitadorLimit};
 use limitador::CheckResult;
 use paperclip::actix::{
     api_v2_errors,
@@ -13,18 +24,41 @@ use paperclip::actix::{
     // extension trait for actix_web::App and proc-macro attributes
     OpenApiExt,
 };
+use std::collections::BTreeMap;
 use std::fmt;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 
 struct RateLimitData {
     limiter: Arc<Limiter>,
     metrics: Arc<PrometheusMetrics>,
+    behaviors: Arc<OverLimitBehaviors>,
+    limited: LimitedResponse,
+    storage_check: CachedStorageCheck,
 }
 
 impl RateLimitData {
     fn new(limiter: Arc<Limiter>, metrics: Arc<PrometheusMetrics>) -> Self {
-        Self { limiter, metrics }
+        Self {
+            limiter,
+            metrics,
+            behaviors: Arc::default(),
+            limited: LimitedResponse::default(),
+            storage_check: CachedStorageCheck::default(),
+        }
+    }
+
+    fn over_limit_behaviors(mut self, behaviors: Arc<OverLimitBehaviors>) -> Self {
+        self.behaviors = behaviors;
+        self
     }
+
+    fn limited_response(mut self, limited: LimitedResponse) -> Self {
+        self.limited = limited;
+        self
+    }
+
     fn limiter(&self) -> &Limiter {
         self.limiter.as_ref()
     }
@@ -32,20 +66,36 @@ impl RateLimitData {
     fn metrics(&self) -> &PrometheusMetrics {
         self.metrics.as_ref()
     }
+
+    fn behaviors(&self) -> &OverLimitBehaviors {
+        self.behaviors.as_ref()
+    }
+
+    fn limited(&self) -> &LimitedResponse {
+        &self.limited
+    }
 }
 
-#[api_v2_errors(429, 500)]
+#[api_v2_errors(400, 404, 409, 429, 500, 503)]
 #[derive(Debug)]
 enum ErrorResponse {
+    BadRequest(ValidationError),
+    NotFound,
+    Conflict,
     TooManyRequests,
     InternalServerError,
+    ServiceUnavailable(String),
 }
 
 impl fmt::Display for ErrorResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::BadRequest(err) => write!(f, "Bad request: {}", err.message),
+            Self::NotFound => write!(f, "Not found"),
+            Self::Conflict => write!(f, "Conflict"),
             Self::TooManyRequests => write!(f, "Too many requests"),
             Self::InternalServerError => write!(f, "Internal server error"),
+            Self::ServiceUnavailable(reason) => write!(f, "Service unavailable: {reason}"),
         }
     }
 }
@@ -53,18 +103,53 @@ impl fmt::Display for ErrorResponse {
 impl ResponseError for ErrorResponse {
     fn status_code(&self) -> StatusCode {
         match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict => StatusCode::CONFLICT,
             Self::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
             Self::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::BadRequest(err) => HttpResponse::BadRequest().json(err),
+            _ => HttpResponse::build(self.status_code())
+                .insert_header(ContentType::plaintext())
+                .body(self.to_string()),
         }
     }
 }
 
+impl From<InvalidLimit> for ErrorResponse {
+    fn from(err: InvalidLimit) -> Self {
+        Self::BadRequest(err.into())
+    }
+}
+
+// Malformed bodies get the same structured errors as invalid limits
+fn json_error(err: JsonPayloadError, _: &HttpRequest) -> actix_web::Error {
+    ErrorResponse::BadRequest(ValidationError::new(err.to_string())).into()
+}
+
 // Used for health checks
 #[api_v2_operation]
 async fn status() -> web::Json<()> {
     Json(())
 }
 
+// Used for readiness checks, failing for as long as the storage can't be reached
+#[api_v2_operation]
+async fn deep_status(data: web::Data<RateLimitData>) -> Result<web::Json<()>, ErrorResponse> {
+    let data = data.get_ref();
+    data.storage_check
+        .check(&data.limiter)
+        .await
+        .map_err(ErrorResponse::ServiceUnavailable)?;
+    Ok(Json(()))
+}
+
 #[tracing::instrument(skip(data))]
 #[api_v2_operation]
 async fn metrics(data: web::Data<RateLimitData>) -> String {
@@ -74,6 +159,7 @@ async fn metrics(data: web::Data<RateLimitData>) -> String {
 #[api_v2_operation]
 #[tracing::instrument(skip(data))]
 async fn get_limits(
+    _: ReadAccess,
     data: web::Data<RateLimitData>,
     namespace: web::Path<String>,
 ) -> Result<web::Json<Vec<Limit>>, ErrorResponse> {
@@ -86,9 +172,244 @@ async fn get_limits(
     Ok(Json(resp_limits))
 }
 
+fn find_limit(limiter: &Limiter, namespace: String, id: &str) -> Option<LimitadorLimit> {
+    let namespace = namespace.into();
+    let limits = match limiter {
+        Limiter::Blocking(limiter) => limiter.get_limits(&namespace),
+        Limiter::Async(limiter) => limiter.get_limits(&namespace),
+    };
+    limits.into_iter().find(|l| l.id() == Some(id))
+}
+
+// Whether the `limit` got updated, see `not_updated` otherwise
+async fn update_limit(limiter: &Limiter, limit: &LimitadorLimit) -> Result<bool, ErrorResponse> {
+    let result = match limiter {
+        Limiter::Blocking(limiter) => limiter.update_limit(limit),
+        Limiter::Async(limiter) => limiter.update_limit(limit).await,
+    };
+    result.map_err(|_| ErrorResponse::InternalServerError)
+}
+
+// Why the `limit` found by its id didn't get updated: it's gone, or got changed
+// in the meantime, unless it already is as requested
+fn not_updated(limiter: &Limiter, limit: &LimitadorLimit) -> Result<(), ErrorResponse> {
+    let namespace = limit.namespace().as_ref().to_string();
+    match find_limit(limiter, namespace, limit.id().unwrap_or_default()) {
+        None => Err(ErrorResponse::NotFound),
+        Some(stored) if Limit::from(&stored) == Limit::from(limit) => Ok(()),
+        Some(_) => Err(ErrorResponse::Conflict),
+    }
+}
+
+// Fails when the limit already exists, or its id is the one of another limit
+fn add_limit(limiter: &Limiter, limit: LimitadorLimit) -> Result<(), ErrorResponse> {
+    let added = match limiter {
+        Limiter::Blocking(limiter) => limiter.add_limit(limit),
+        Limiter::Async(limiter) => limiter.add_limit(limit),
+    };
+    match added {
+        Ok(true) => Ok(()),
+        Ok(false) | Err(_) => Err(ErrorResponse::Conflict),
+    }
+}
+
+async fn delete_limit_and_counters(
+    limiter: &Limiter,
+    limit: &LimitadorLimit,
+) -> Result<(), ErrorResponse> {
+    let result = match limiter {
+        Limiter::Blocking(limiter) => limiter.delete_limit(limit),
+        Limiter::Async(limiter) => limiter.delete_limit(limit).await,
+    };
+    result.map_err(|_| ErrorResponse::InternalServerError)
+}
+
+fn mismatch(message: &str) -> ErrorResponse {
+    ErrorResponse::BadRequest(ValidationError::new(message.to_string()))
+}
+
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn create_limit(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    request: web::Json<Limit>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let limit: LimitadorLimit = request.into_inner().try_into()?;
+    let limiter = data.get_ref().limiter();
+    let namespace = limit.namespace().clone();
+    let before = limiter.limits_of(&namespace);
+    add_limit(limiter, limit)?;
+    audit::limits_changed(&access.0, &before, &limiter.limits_of(&namespace));
+    Ok(Json(()))
+}
+
+// Replaces the limit with the given id. Its counters are kept, unless what
+// defines the limit changed, i.e. its window, conditions or variables, which
+// can't be the ones of another limit
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn replace_limit(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    path: web::Path<(String, String)>,
+    request: web::Json<Limit>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, id) = path.into_inner();
+    let limit: LimitadorLimit = request.into_inner().try_into()?;
+    if limit.namespace().as_ref() != namespace || limit.id() != Some(id.as_str()) {
+        return Err(mismatch(
+            "the namespace and id of the limit must match the path",
+        ));
+    }
+    let limiter = data.get_ref().limiter();
+    let Some(current) = find_limit(limiter, namespace, &id) else {
+        return Err(ErrorResponse::NotFound);
+    };
+    let before = limiter.limits_of(current.namespace());
+    if current != limit && before.contains(&limit) {
+        return Err(ErrorResponse::Conflict);
+    }
+    if !update_limit(limiter, &limit).await? {
+        return not_updated(limiter, &limit).map(Json);
+    }
+    let after = limiter.limits_of(current.namespace());
+    audit::limits_changed(&access.0, &before, &after);
+    Ok(Json(()))
+}
+
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn delete_limit(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    path: web::Path<(String, String)>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, id) = path.into_inner();
+    let limiter = data.get_ref().limiter();
+    let Some(limit) = find_limit(limiter, namespace, &id) else {
+        return Err(ErrorResponse::NotFound);
+    };
+    delete_limit_and_counters(limiter, &limit).await?;
+    audit::limits_changed(&access.0, &[limit], &[]);
+    Ok(Json(()))
+}
+
+// Replaces all the limits of the namespace at once
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn replace_limits(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    namespace: web::Path<String>,
+    request: web::Json<Vec<Limit>>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let namespace = namespace.into_inner().into();
+    let limits = request
+        .into_inner()
+        .into_iter()
+        .map(LimitadorLimit::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    if limits.iter().any(|limit| limit.namespace() != &namespace) {
+        return Err(mismatch("the namespace of the limits must match the path"));
+    }
+    data.get_ref()
+        .limiter()
+        .replace_limits(&namespace, limits, &access.0)
+        .await
+        .map_err(|err| match err {
+            LimitadorServerError::ConfigFile(msg) => mismatch(&msg),
+            LimitadorServerError::Internal(_) => ErrorResponse::InternalServerError,
+        })?;
+    Ok(Json(()))
+}
+
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn delete_limits(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    namespace: web::Path<String>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let namespace = namespace.into_inner().into();
+    let before = data.get_ref().limiter().limits_of(&namespace);
+    let result = match data.get_ref().limiter() {
+        Limiter::Blocking(limiter) => limiter.delete_limits(&namespace),
+        Limiter::Async(limiter) => limiter.delete_limits(&namespace).await,
+    };
+    result.map_err(|_| ErrorResponse::InternalServerError)?;
+    audit::limits_changed(&access.0, &before, &[]);
+    Ok(Json(()))
+}
+
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn get_overrides(
+    _: ReadAccess,
+    data: web::Data<RateLimitData>,
+    path: web::Path<(String, String)>,
+) -> Result<web::Json<Vec<MaxValueOverride>>, ErrorResponse> {
+    let (namespace, id) = path.into_inner();
+    match find_limit(data.get_ref().limiter(), namespace, &id) {
+        Some(limit) => Ok(Json(limit.overrides().iter().map(|o| o.into()).collect())),
+        None => Err(ErrorResponse::NotFound),
+    }
+}
+
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn set_override(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    path: web::Path<(String, String)>,
+    request: web::Json<MaxValueOverride>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, id) = path.into_inner();
+    let limiter = data.get_ref().limiter();
+    let MaxValueOverride {
+        variables,
+        max_value,
+    } = request.into_inner();
+    let Some(mut limit) = find_limit(limiter, namespace, &id) else {
+        return Err(ErrorResponse::NotFound);
+    };
+    let before = limit.clone();
+    limit.set_override(variables, max_value)?;
+    if !update_limit(limiter, &limit).await? {
+        return not_updated(limiter, &limit).map(Json);
+    }
+    audit::limits_changed(&access.0, &[before], &[limit]);
+    Ok(Json(()))
+}
+
+#[api_v2_operation]
+#[tracing::instrument(skip(data))]
+async fn delete_override(
+    access: AdminAccess,
+    data: web::Data<RateLimitData>,
+    path: web::Path<(String, String)>,
+    request: web::Json<BTreeMap<String, String>>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, id) = path.into_inner();
+    let limiter = data.get_ref().limiter();
+    let Some(mut limit) = find_limit(limiter, namespace, &id) else {
+        return Err(ErrorResponse::NotFound);
+    };
+    let before = limit.clone();
+    if !limit.remove_override(&request) {
+        return Err(ErrorResponse::NotFound);
+    }
+    if !update_limit(limiter, &limit).await? {
+        return not_updated(limiter, &limit).map(Json);
+    }
+    audit::limits_changed(&access.0, &[before], &[limit]);
+    Ok(Json(()))
+}
+
 #[tracing::instrument(skip(data))]
 #[api_v2_operation]
 async fn get_counters(
+    _: ReadAccess,
     data: web::Data<RateLimitData>,
     namespace: web::Path<String>,
 ) -> Result<web::Json<Vec<Counter>>, ErrorResponse> {
@@ -110,17 +431,84 @@ async fn get_counters(
     }
 }
 
+// Resets all the counters of the namespace, keeping its limits
+#[tracing::instrument(skip(data))]
+#[api_v2_operation]
+async fn reset_counters(
+    _: AdminAccess,
+    data: web::Data<RateLimitData>,
+    namespace: web::Path<String>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let namespace = namespace.into_inner().into();
+    let reset_result = match data.get_ref().limiter() {
+        Limiter::Blocking(limiter) => limiter.reset_counters(&namespace),
+        Limiter::Async(limiter) => limiter.reset_counters(&namespace).await,
+    };
+
+    match reset_result {
+        Ok(_) => Ok(Json(())),
+        Err(_) => Err(ErrorResponse::InternalServerError),
+    }
+}
+
+// Resets the counter of the limit for the given values of its variables, e.g.
+// a given user's, or all of its counters when none are given
+#[tracing::instrument(skip(data))]
+#[api_v2_operation]
+async fn reset_counters_of_limit(
+    _: AdminAccess,
+    data: web::Data<RateLimitData>,
+    path: web::Path<(String, String)>,
+    request: web::Json<BTreeMap<String, String>>,
+) -> Result<web::Json<()>, ErrorResponse> {
+    let (namespace, id) = path.into_inner();
+    let limiter = data.get_ref().limiter();
+    let Some(limit) = find_limit(limiter, namespace, &id) else {
+        return Err(ErrorResponse::NotFound);
+    };
+    let variables = request.into_inner();
+    let known = limit.variables();
+    if let Some(unknown) = variables.keys().find(|var| !known.contains(*var)) {
+        return Err(mismatch(&format!(
+            "unknown variable '{unknown}' of limit '{id}'"
+        )));
+    }
+    if !variables.is_empty() && variables.len() != known.len() {
+        return Err(mismatch(&format!(
+            "the values of all the variables of limit '{id}' are required"
+        )));
+    }
+    let reset_result = if variables.is_empty() {
+        match limiter {
+            Limiter::Blocking(limiter) => limiter.reset_counters_of_limit(&limit),
+            Limiter::Async(limiter) => limiter.reset_counters_of_limit(&limit).await,
+        }
+    } else {
+        let counter = LimitadorCounter::with_variables(limit, variables);
+        match limiter {
+            Limiter::Blocking(limiter) => limiter.reset_counter(&counter),
+            Limiter::Async(limiter) => limiter.reset_counter(&counter).await,
+        }
+    };
+
+    match reset_result {
+        Ok(_) => Ok(Json(())),
+        Err(_) => Err(ErrorResponse::InternalServerError),
+    }
+}
+
 #[tracing::instrument(skip(state))]
 #[api_v2_operation]
 async fn check(
     state: web::Data<RateLimitData>,
     request: web::Json<CheckAndReportInfo>,
-) -> Result<web::Json<()>, ErrorResponse> {
+) -> HttpResponse {
     let CheckAndReportInfo {
         namespace,
         values,
         delta,
         response_headers: _,
+        idempotency_key: _,
     } = request.into_inner();
     let namespace = namespace.into();
     let mut ctx = Context::default();
@@ -130,15 +518,21 @@ async fn check(
         Limiter::Async(limiter) => limiter.is_rate_limited(&namespace, &ctx, delta).await,
     };
 
-    match is_rate_limited_result {
-        Ok(rate_limited) => {
-            if rate_limited {
-                Err(ErrorResponse::TooManyRequests)
-            } else {
-                Ok(Json(()))
-            }
-        }
-        Err(_) => Err(ErrorResponse::InternalServerError),
+    let verdict = match is_rate_limited_result {
+        Ok(true) => state.behaviors().over_limit(&namespace, None),
+        Ok(false) => Verdict::Allow,
+        Err(e) => match state.behaviors().on_error(&namespace, &e) {
+            Some(verdict) => verdict,
+            None => return ErrorResponse::InternalServerError.error_response(),
+        },
+    };
+    let mut resp = respond(&state, verdict, None);
+    match verdict {
+        Verdict::Deny => state.limited().finish(resp, &namespace, None, |mut resp| {
+            resp.insert_header(ContentType::plaintext())
+                .body(ErrorResponse::TooManyRequests.to_string())
+        }),
+        _ => resp.json(()),
     }
 }
 
@@ -153,6 +547,7 @@ async fn report(
         values,
         delta,
         response_headers: _,
+        idempotency_key: _,
     } = request.into_inner();
     let namespace = namespace.into();
     let mut ctx = Context::default();
@@ -164,6 +559,9 @@ async fn report(
 
     match update_counters_result {
         Ok(_) => Ok(Json(())),
+        Err(LimitadorError::DeltaExceedsMaxValue(err)) => {
+            Err(ErrorResponse::BadRequest(err.into()))
+        }
         Err(_) => Err(ErrorResponse::InternalServerError),
     }
 }
@@ -179,63 +577,111 @@ async fn check_and_report(
         values,
         delta,
         response_headers,
+        idempotency_key,
     } = request.into_inner();
     let namespace = namespace.into();
     let mut ctx = Context::default();
     ctx.list_binding("descriptors".to_string(), vec![values]);
     let rate_limit_data = data.get_ref();
-    let rate_limited_and_update_result = match rate_limit_data.limiter() {
-        Limiter::Blocking(limiter) => limiter.check_rate_limited_and_update(
-            &namespace,
-            &ctx,
-            delta,
-            response_headers.is_some(),
-        ),
-        Limiter::Async(limiter) => {
+    let load_counters = response_headers.is_some() || rate_limit_data.metrics().needs_counters();
+    let rate_limited_and_update_result = match (rate_limit_data.limiter(), idempotency_key) {
+        (Limiter::Blocking(limiter), None) => {
+            limiter.check_rate_limited_and_update(&namespace, &ctx, delta, load_counters)
+        }
+        (Limiter::Blocking(limiter), Some(token)) => limiter
+            .check_rate_limited_and_update_idempotent(
+                &namespace,
+                &ctx,
+                delta,
+                load_counters,
+                &token,
+            ),
+        (Limiter::Async(limiter), None) => {
+            limiter
+                .check_rate_limited_and_update(&namespace, &ctx, delta, load_counters)
+                .await
+        }
+        (Limiter::Async(limiter), Some(token)) => {
             limiter
-                .check_rate_limited_and_update(&namespace, &ctx, delta, response_headers.is_some())
+                .check_rate_limited_and_update_idempotent(
+                    &namespace,
+                    &ctx,
+                    delta,
+                    load_counters,
+                    &token,
+                )
                 .await
         }
     };
 
-    match rate_limited_and_update_result {
-        Ok(mut is_rate_limited) => {
-            if is_rate_limited.limited {
+    let (verdict, mut is_rate_limited) = match rate_limited_and_update_result {
+        Ok(is_rate_limited) => {
+            rate_limit_data
+                .metrics()
+                .record_limits(&namespace, &is_rate_limited, delta);
+            let verdict = if is_rate_limited.limited {
                 rate_limit_data
                     .metrics()
                     .incr_limited_calls(&namespace, is_rate_limited.limit_name.as_deref());
-
-                match response_headers {
-                    None => HttpResponse::TooManyRequests().json(()),
-                    Some(response_headers) => {
-                        let mut resp = HttpResponse::TooManyRequests();
-                        add_response_header(
-                            &mut resp,
-                            response_headers.as_str(),
-                            &mut is_rate_limited,
-                        );
-                        resp.json(())
-                    }
-                }
+                rate_limit_data
+                    .behaviors()
+                    .over_limit(&namespace, is_rate_limited.limit_name.as_deref())
             } else {
                 rate_limit_data.metrics().incr_authorized_calls(&namespace);
-
-                match response_headers {
-                    None => HttpResponse::Ok().json(()),
-                    Some(response_headers) => {
-                        let mut resp = HttpResponse::Ok();
-                        add_response_header(
-                            &mut resp,
-                            response_headers.as_str(),
-                            &mut is_rate_limited,
-                        );
-                        resp.json(())
-                    }
-                }
-            }
+                Verdict::Allow
+            };
+            (verdict, Some(is_rate_limited))
         }
-        Err(_) => HttpResponse::InternalServerError().json(()),
+        Err(e) => match rate_limit_data.behaviors().on_error(&namespace, &e) {
+            Some(verdict) => (verdict, None),
+            None => return HttpResponse::InternalServerError().json(()),
+        },
+    };
+
+    verdict.log(
+        &namespace,
+        delta,
+        is_rate_limited
+            .as_ref()
+            .and_then(|checked| checked.limit_name.as_deref()),
+        is_rate_limited
+            .as_ref()
+            .and_then(|checked| checked.statuses.iter().find(|status| status.limiting))
+            .map(|status| &status.limit_metadata)
+            .filter(|metadata| !metadata.is_empty()),
+    );
+
+    let mut resp = respond(rate_limit_data, verdict, is_rate_limited.as_ref());
+    if let (Some(response_headers), Some(is_rate_limited)) =
+        (response_headers, is_rate_limited.as_mut())
+    {
+        add_response_header(&mut resp, response_headers.as_str(), is_rate_limited);
+    }
+    match verdict {
+        Verdict::Deny => rate_limit_data.limited().finish(
+            resp,
+            &namespace,
+            is_rate_limited.as_ref(),
+            |mut resp| resp.json(()),
+        ),
+        _ => resp.json(()),
+    }
+}
+
+// Allowed, or denied as configured, flagged as the verdict wants it
+fn respond(
+    data: &RateLimitData,
+    verdict: Verdict,
+    checked: Option<&CheckResult>,
+) -> HttpResponseBuilder {
+    let mut resp = match verdict {
+        Verdict::Deny => data.limited().start(checked),
+        Verdict::Allow | Verdict::AllowFlagged(_) => HttpResponse::Ok(),
+    };
+    if let Some(header) = verdict.header() {
+        resp.insert_header(header);
     }
+    resp
 }
 
 pub fn add_response_header(
@@ -255,6 +701,10 @@ pub fn add_response_header(
                 resp.insert_header(("X-RateLimit-Reset", duration.clone()));
             }
         }
+    } else if rate_limit_headers == "DraftIetf" {
+        for header in result.ratelimit_headers() {
+            resp.insert_header(header);
+        }
     }
 }
 
@@ -262,34 +712,82 @@ pub async fn run_http_server(
     address: &str,
     rate_limiter: Arc<Limiter>,
     prometheus_metrics: Arc<PrometheusMetrics>,
+    over_limit_behaviors: Arc<OverLimitBehaviors>,
+    limited_response: LimitedResponse,
+    authenticator: Option<Arc<Authenticator>>,
+    drain_timeout: Duration,
+    shutdown: impl Future<Output = ()> + 'static,
 ) -> std::io::Result<()> {
-    let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+    let data = web::Data::new(
+        RateLimitData::new(rate_limiter, prometheus_metrics)
+            .over_limit_behaviors(over_limit_behaviors)
+            .limited_response(limited_response),
+    );
+    let authenticator = authenticator.map(web::Data::from);
 
     // This uses the paperclip crate to generate an OpenAPI spec.
     // Ref: https://paperclip.waffles.space/actix-plugin.html
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap_api()
             .with_json_spec_at("/api/spec")
+            .with_json_spec_v3_at("/openapi.json")
             .app_data(data.clone())
+            .app_data(authenticator.clone())
+            .app_data(actix_web::web::JsonConfig::default().error_handler(json_error))
             .route("/status", web::get().to(status))
+            .route("/status/deep", web::get().to(deep_status))
             .route("/metrics", web::get().to(metrics))
+            .route("/limits", web::post().to(create_limit))
             .route("/limits/{namespace}", web::get().to(get_limits))
+            .route("/limits/{namespace}", web::put().to(replace_limits))
+            .route("/limits/{namespace}", web::delete().to(delete_limits))
+            .route("/limits/{namespace}/{id}", web::put().to(replace_limit))
+            .route("/limits/{namespace}/{id}", web::delete().to(delete_limit))
+            .route(
+                "/limits/{namespace}/{id}/overrides",
+                web::get().to(get_overrides),
+            )
+            .route(
+                "/limits/{namespace}/{id}/overrides",
+                web::put().to(set_override),
+            )
+            .route(
+                "/limits/{namespace}/{id}/overrides",
+                web::delete().to(delete_override),
+            )
             .route("/counters/{namespace}", web::get().to(get_counters))
+            .route("/counters/{namespace}", web::delete().to(reset_counters))
+            .route(
+                "/counters/{namespace}/{id}",
+                web::delete().to(reset_counters_of_limit),
+            )
             .route("/check_and_report", web::post().to(check_and_report))
             .route("/check", web::post().to(check))
             .route("/report", web::post().to(report))
             .build()
     })
     .bind(address)?
-    .run()
-    .await
+    // Shutting down is up to `shutdown`, so that all servers stop together
+    .disable_signals()
+    .shutdown_timeout(drain_timeout.as_secs())
+    .run();
+
+    let handle = server.handle();
+    actix_rt::spawn(async move {
+        shutdown.await;
+        handle.stop(true).await;
+    });
+    server.await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::HttpOverLimitConfiguration;
+    use crate::http_api::auth::Role;
+    use crate::over_limit::Behavior;
     use crate::prometheus_metrics::tests::TEST_PROMETHEUS_HANDLE;
     use crate::Configuration;
     use actix_web::{test, web};
@@ -313,6 +811,27 @@ mod tests {
         assert!(resp.status().is_success());
     }
 
+    #[actix_rt::test]
+    async fn test_deep_status() {
+        let rate_limiter: Arc<Limiter> =
+            Arc::new(Limiter::new(Configuration::default()).await.unwrap());
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/status/deep", web::get().to(deep_status)),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/status/deep").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert!(resp.status().is_success());
+    }
+
     #[actix_rt::test]
     async fn test_metrics() {
         let rate_limiter: Arc<Limiter> =
@@ -365,6 +884,102 @@ mod tests {
         assert_eq!(*resp_limits.first().unwrap(), Limit::from(&limit));
     }
 
+    #[actix_rt::test]
+    async fn test_overrides() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let limit = LimitadorLimit::with_id(
+            "test_limit",
+            namespace,
+            10,
+            60,
+            vec![],
+            vec!["descriptors[0]['app.id']"
+                .try_into()
+                .expect("failed parsing!")],
+        );
+        match &limiter {
+            Limiter::Blocking(limiter) => limiter.add_limit(limit).unwrap(),
+            Limiter::Async(limiter) => limiter.add_limit(limit).unwrap(),
+        };
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/limits/{namespace}/{id}/overrides",
+                    web::get().to(get_overrides),
+                )
+                .route(
+                    "/limits/{namespace}/{id}/overrides",
+                    web::put().to(set_override),
+                )
+                .route(
+                    "/limits/{namespace}/{id}/overrides",
+                    web::delete().to(delete_override),
+                ),
+        )
+        .await;
+        let uri = format!("/limits/{namespace}/test_limit/overrides");
+        let variables = BTreeMap::from([("descriptors[0]['app.id']".to_string(), "1".to_string())]);
+        let o = MaxValueOverride {
+            variables: variables.clone(),
+            max_value: 100,
+        };
+
+        let req = test::TestRequest::put().uri(&uri).set_json(&o).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // Setting it again changes nothing
+        let req = test::TestRequest::put().uri(&uri).set_json(&o).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        for variables in [
+            BTreeMap::new(),
+            BTreeMap::from([("unknown".to_string(), "1".to_string())]),
+        ] {
+            let invalid = MaxValueOverride {
+                variables,
+                max_value: 1,
+            };
+            let req = test::TestRequest::put()
+                .uri(&uri)
+                .set_json(&invalid)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let err: ValidationError = test::read_body_json(resp).await;
+            assert_eq!(err.field.as_deref(), Some("overrides"));
+        }
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp: Vec<MaxValueOverride> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp, vec![o]);
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .set_json(&variables)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp: Vec<MaxValueOverride> = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.is_empty());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/limits/{namespace}/unknown/overrides"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
     #[actix_rt::test]
     async fn test_check_and_report() {
         let limiter = Limiter::new(Configuration::default()).await.unwrap();
@@ -393,6 +1008,7 @@ mod tests {
             values,
             delta: 1,
             response_headers: None,
+            idempotency_key: None,
         };
 
         // The first request should be OK
@@ -416,6 +1032,175 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 
+    #[actix_rt::test]
+    async fn test_check_and_report_accounts_for_retries_once() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let _limit = create_test_limit(&limiter, namespace, 1).await;
+        let data = web::Data::new(RateLimitData::new(
+            Arc::new(limiter),
+            Arc::new(PrometheusMetrics::new_with_handle(
+                false,
+                TEST_PROMETHEUS_HANDLE.clone(),
+            )),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/check_and_report", web::post().to(check_and_report)),
+        )
+        .await;
+
+        let values = HashMap::from([
+            ("req.method".to_string(), "GET".to_string()),
+            ("req.id".to_string(), "1".to_string()),
+        ]);
+        let info_of = |key: &str| CheckAndReportInfo {
+            namespace: namespace.into(),
+            values: values.clone(),
+            delta: 1,
+            response_headers: None,
+            idempotency_key: Some(key.to_string()),
+        };
+
+        for (key, status) in [
+            ("a", StatusCode::OK),
+            ("a", StatusCode::OK),
+            ("b", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/check_and_report")
+                .set_json(info_of(key))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "request {key}");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_check_and_report_soft_denies_over_limit() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+
+        // Create a limit with max == 1
+        let namespace = "test_namespace";
+        let _limit = create_test_limit(&limiter, namespace, 1).await;
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(
+            RateLimitData::new(rate_limiter, prometheus_metrics).over_limit_behaviors(Arc::new(
+                OverLimitBehaviors::of(namespace, Behavior::AllowWithHeader, Behavior::Deny),
+            )),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/check_and_report", web::post().to(check_and_report)),
+        )
+        .await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".into(), "GET".into());
+        values.insert("req.id".into(), "1".into());
+        let info = CheckAndReportInfo {
+            namespace: namespace.into(),
+            values,
+            delta: 1,
+            response_headers: None,
+            idempotency_key: None,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("x-limitador-soft-deny"), None);
+
+        // Over the limit, yet allowed, and flagged as such
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get("x-limitador-soft-deny").unwrap(),
+            "over_limit"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_check_and_report_with_configured_over_limit_response() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+
+        // Create a limit with max == 1
+        let namespace = "test_namespace";
+        let _limit = create_test_limit(&limiter, namespace, 1).await;
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let limited = LimitedResponse::new(HttpOverLimitConfiguration {
+            status: 403,
+            retry_after: true,
+            body: Some(r#"{"namespace": "{{namespace}}", "retry_after": {{retry_after}}}"#.into()),
+        })
+        .unwrap();
+        let data = web::Data::new(
+            RateLimitData::new(rate_limiter, prometheus_metrics).limited_response(limited),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/check_and_report", web::post().to(check_and_report)),
+        )
+        .await;
+
+        let mut values = HashMap::new();
+        values.insert("req.method".into(), "GET".into());
+        values.insert("req.id".into(), "1".into());
+        let info = CheckAndReportInfo {
+            namespace: namespace.into(),
+            values,
+            delta: 1,
+            response_headers: None,
+            idempotency_key: None,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/check_and_report")
+            .data(data.clone())
+            .set_json(&info)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let retry_after: u64 = resp
+            .headers()
+            .get("Retry-After")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["namespace"], namespace);
+        assert_eq!(body["retry_after"], retry_after);
+    }
+
     #[actix_rt::test]
     async fn test_check_and_report_with_draftversion03_response_headers() {
         let limiter = Limiter::new(Configuration::default()).await.unwrap();
@@ -444,6 +1229,7 @@ mod tests {
             values,
             delta: 1,
             response_headers: Some("DraftVersion03".to_string()),
+            idempotency_key: None,
         };
 
         // The first request should be OK
@@ -519,6 +1305,7 @@ mod tests {
             values,
             delta: 1,
             response_headers: None,
+            idempotency_key: None,
         };
 
         // Without making any requests, check should return OK
@@ -549,6 +1336,250 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
     }
 
+    #[actix_rt::test]
+    async fn test_reset_counters() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let limit = LimitadorLimit::with_id(
+            "test_limit",
+            namespace,
+            1,
+            60,
+            vec![],
+            vec!["descriptors[0]['app.id']"
+                .try_into()
+                .expect("failed parsing!")],
+        );
+        match &limiter {
+            Limiter::Blocking(limiter) => limiter.add_limit(limit).unwrap(),
+            Limiter::Async(limiter) => limiter.add_limit(limit).unwrap(),
+        };
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .route("/check", web::post().to(check))
+                .route("/report", web::post().to(report))
+                .route("/counters/{namespace}", web::delete().to(reset_counters))
+                .route(
+                    "/counters/{namespace}/{id}",
+                    web::delete().to(reset_counters_of_limit),
+                ),
+        )
+        .await;
+
+        let info_of = |app_id: &str| CheckAndReportInfo {
+            namespace: namespace.into(),
+            values: HashMap::from([("app.id".to_string(), app_id.to_string())]),
+            delta: 1,
+            response_headers: None,
+            idempotency_key: None,
+        };
+        for app_id in ["1", "2"] {
+            let req = test::TestRequest::post()
+                .uri("/report")
+                .set_json(info_of(app_id))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+        }
+
+        // Resets app 1's counter only
+        let variables = BTreeMap::from([("descriptors[0]['app.id']".to_string(), "1".to_string())]);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/counters/{namespace}/test_limit"))
+            .set_json(&variables)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/check")
+            .set_json(info_of("1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/check")
+            .set_json(info_of("2"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/counters/{namespace}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/check")
+            .set_json(info_of("2"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/counters/{namespace}/unknown"))
+            .set_json(BTreeMap::<String, String>::new())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let variables = BTreeMap::from([("app.id".to_string(), "1".to_string())]);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/counters/{namespace}/test_limit"))
+            .set_json(&variables)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_limits_crud() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let namespace = "test_namespace";
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(actix_web::web::JsonConfig::default().error_handler(json_error))
+                .route("/limits", web::post().to(create_limit))
+                .route("/limits/{namespace}", web::get().to(get_limits))
+                .route("/limits/{namespace}", web::put().to(replace_limits))
+                .route("/limits/{namespace}", web::delete().to(delete_limits))
+                .route("/limits/{namespace}/{id}", web::put().to(replace_limit))
+                .route("/limits/{namespace}/{id}", web::delete().to(delete_limit)),
+        )
+        .await;
+        let limit = |max_value| {
+            LimitadorLimit::with_id(
+                "test_limit",
+                namespace,
+                max_value,
+                60,
+                vec![],
+                vec!["descriptors[0]['app.id']"
+                    .try_into()
+                    .expect("failed parsing!")],
+            )
+        };
+        let read_limits = || {
+            test::TestRequest::get()
+                .uri(&format!("/limits/{namespace}"))
+                .to_request()
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/limits")
+            .set_json(Limit::from(&limit(10)))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::post()
+            .uri("/limits")
+            .set_json(Limit::from(&limit(10)))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/limits/{namespace}/test_limit"))
+            .set_json(Limit::from(&limit(20)))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let limits: Vec<Limit> = test::call_and_read_body_json(&app, read_limits()).await;
+        assert_eq!(limits, vec![Limit::from(&limit(20))]);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/limits/{namespace}/test_limit"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let limits: Vec<Limit> = test::call_and_read_body_json(&app, read_limits()).await;
+        assert!(limits.is_empty());
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/limits/{namespace}"))
+            .set_json(vec![Limit::from(&limit(30))])
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let limits: Vec<Limit> = test::call_and_read_body_json(&app, read_limits()).await;
+        assert_eq!(limits, vec![Limit::from(&limit(30))]);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/limits/{namespace}"))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let limits: Vec<Limit> = test::call_and_read_body_json(&app, read_limits()).await;
+        assert!(limits.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_invalid_limits_are_rejected() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(actix_web::web::JsonConfig::default().error_handler(json_error))
+                .route("/limits", web::post().to(create_limit))
+                .route("/limits/{namespace}", web::put().to(replace_limits)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/limits")
+            .insert_header(ContentType::json())
+            .set_payload(
+                r#"{"namespace": "test_namespace", "max_value": 10, "seconds": 60,
+                "conditions": ["x == 'a"], "variables": [], "overrides": []}"#,
+            )
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err: ValidationError = test::read_body_json(resp).await;
+        assert_eq!(err.field.as_deref(), Some("conditions"));
+        assert_eq!(err.token.as_deref(), Some("'a"));
+        assert_eq!(err.position, Some(5));
+
+        let req = test::TestRequest::post()
+            .uri("/limits")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"namespace": "test_namespace"}"#)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err: ValidationError = test::read_body_json(resp).await;
+        assert!(err.field.is_none());
+
+        // Two limits with the same id
+        let req = test::TestRequest::put()
+            .uri("/limits/test_namespace")
+            .insert_header(ContentType::json())
+            .set_payload(
+                r#"[{"id": "a", "namespace": "test_namespace", "max_value": 10, "seconds": 60,
+                "conditions": [], "variables": [], "overrides": []},
+                {"id": "a", "namespace": "test_namespace", "max_value": 10, "seconds": 120,
+                "conditions": [], "variables": [], "overrides": []}]"#,
+            )
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
     async fn create_test_limit(limiter: &Limiter, namespace: &str, max: u64) -> LimitadorLimit {
         // Create a limit
         let limit = LimitadorLimit::new(
@@ -564,9 +1595,59 @@ mod tests {
         );
 
         match &limiter {
-            Limiter::Blocking(limiter) => limiter.add_limit(limit.clone()),
-            Limiter::Async(limiter) => limiter.add_limit(limit.clone()),
+            Limiter::Blocking(limiter) => limiter.add_limit(limit.clone()).unwrap(),
+            Limiter::Async(limiter) => limiter.add_limit(limit.clone()).unwrap(),
         };
         limit
     }
+
+    #[actix_rt::test]
+    async fn test_limits_require_authorization() {
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let rate_limiter: Arc<Limiter> = Arc::new(limiter);
+        let prometheus_metrics: Arc<PrometheusMetrics> = Arc::new(
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()),
+        );
+        let data = web::Data::new(RateLimitData::new(rate_limiter, prometheus_metrics));
+        let authenticator = Some(web::Data::new(Authenticator::with_tokens(vec![
+            ("reader", Role::ReadOnly),
+            ("admin", Role::Admin),
+        ])));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(authenticator)
+                .route("/limits", web::post().to(create_limit))
+                .route("/limits/{namespace}", web::get().to(get_limits)),
+        )
+        .await;
+        let limit = LimitadorLimit::with_id("test_limit", "test_namespace", 10, 60, vec![], vec![]);
+        let create = |token: Option<&str>| {
+            let req = test::TestRequest::post()
+                .uri("/limits")
+                .set_json(Limit::from(&limit));
+            let req = match token {
+                Some(token) => req.insert_header(("Authorization", format!("Bearer {token}"))),
+                None => req,
+            };
+            req.to_request()
+        };
+
+        let resp = test::call_service(&app, create(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().contains_key("WWW-Authenticate"));
+        let resp = test::call_service(&app, create(Some("unknown"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, create(Some("reader"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, create(Some("admin"))).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/limits/test_namespace")
+            .insert_header(("Authorization", "Bearer reader"))
+            .to_request();
+        let resp: Vec<Limit> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.len(), 1);
+    }
 }
@@ -0,0 +1,235 @@
+use crate::Limiter;
+use kube::api::{Api, Patch, PatchParams};
+use kube::runtime::watcher::{self, Event};
+use kube::runtime::WatchStreamExt;
+use kube::{Client, CustomResource, ResourceExt};
+use limitador::limit::{InvalidLimit, Limit, LimitBuilder};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::pin::pin;
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+/// A set of limits, as they'd be found in the limits file
+#[derive(CustomResource, Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[kube(
+    group = "limitador.kuadrant.io",
+    version = "v1alpha1",
+    kind = "RateLimit",
+    namespaced,
+    status = "RateLimitStatus"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitSpec {
+    pub limits: Vec<LimitSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LimitSpec {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub namespace: String,
+    pub max_value: u64,
+    pub seconds: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub conditions: Vec<String>,
+    #[serde(default)]
+    pub variables: Vec<String>,
+    #[serde(default)]
+    pub max_value_from: Option<String>,
+    #[serde(default)]
+    pub cost: Option<String>,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+// `message` isn't skipped when empty, so that the merge patch clears it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitStatus {
+    pub accepted: bool,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub observed_generation: Option<i64>,
+}
+
+impl TryFrom<&LimitSpec> for Limit {
+    type Error = InvalidLimit;
+
+    fn try_from(spec: &LimitSpec) -> Result<Self, Self::Error> {
+        let mut builder = LimitBuilder::new(spec.namespace.as_str(), spec.max_value, spec.seconds);
+        if let Some(id) = &spec.id {
+            builder = builder.id(id);
+        }
+        if let Some(name) = &spec.name {
+            builder = builder.name(name);
+        }
+        for condition in &spec.conditions {
+            builder = builder.condition(condition);
+        }
+        for variable in &spec.variables {
+            builder = builder.variable(variable);
+        }
+        if let Some(max_value_from) = &spec.max_value_from {
+            builder = builder.max_value_from(max_value_from);
+        }
+        if let Some(cost) = &spec.cost {
+            builder = builder.cost(cost);
+        }
+        if let Some(ttl) = spec.ttl {
+            builder = builder.ttl(ttl);
+        }
+        for (key, value) in &spec.metadata {
+            builder = builder.metadata(key, value);
+        }
+        builder.build()
+    }
+}
+
+// Keyed by the Kubernetes namespace and name of the resource
+type AcceptedLimits = BTreeMap<(String, String), Vec<Limit>>;
+
+/// Watches the `RateLimit` resources, of all namespaces unless `namespace` is
+/// set, and configures the limiter with the limits of all of them. A resource
+/// with an invalid limit is rejected as a whole, keeping whatever limits it
+/// last had accepted, and its status tells why.
+pub async fn sync_limits(
+    limiter: Arc<Limiter>,
+    namespace: Option<String>,
+) -> Result<(), kube::Error> {
+    let client = Client::try_default().await?;
+    let api: Api<RateLimit> = match &namespace {
+        Some(namespace) => Api::namespaced(client.clone(), namespace),
+        None => Api::all(client.clone()),
+    };
+
+    let mut accepted = AcceptedLimits::new();
+    let mut events = pin!(watcher::watcher(api, watcher::Config::default()).default_backoff());
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(Event::Applied(resource)) => accept(&client, &mut accepted, resource).await,
+            Ok(Event::Deleted(resource)) => {
+                accepted.remove(&key_of(&resource));
+            }
+            Ok(Event::Restarted(resources)) => {
+                accepted.clear();
+                for resource in resources {
+                    accept(&client, &mut accepted, resource).await;
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Something went wrong while watching RateLimit resources: {}",
+                    e
+                );
+                continue;
+            }
+        }
+
+        let limits = accepted.values().flatten().cloned().collect();
+        match limiter.configure_with(limits, "kubernetes").await {
+            Ok(_) => info!("RateLimit resources changed; reloaded limits"),
+            Err(e) => error!("Failed applying the limits of RateLimit resources: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn key_of(resource: &RateLimit) -> (String, String) {
+    (
+        resource.namespace().unwrap_or_default(),
+        resource.name_any(),
+    )
+}
+
+async fn accept(client: &Client, accepted: &mut AcceptedLimits, resource: RateLimit) {
+    let key = key_of(&resource);
+    let limits: Result<Vec<Limit>, _> = resource.spec.limits.iter().map(Limit::try_from).collect();
+    let status = match limits {
+        Ok(limits) => {
+            accepted.insert(key.clone(), limits);
+            RateLimitStatus {
+                accepted: true,
+                message: None,
+                observed_generation: resource.metadata.generation,
+            }
+        }
+        Err(e) => {
+            warn!("Rejecting RateLimit {}/{}: {}", key.0, key.1, e);
+            RateLimitStatus {
+                accepted: false,
+                message: Some(e.to_string()),
+                observed_generation: resource.metadata.generation,
+            }
+        }
+    };
+
+    // Patching the status triggers another event, which this breaks out of
+    if resource.status.as_ref() == Some(&status) {
+        return;
+    }
+    let api: Api<RateLimit> = Api::namespaced(client.clone(), &key.0);
+    let patch = Patch::Merge(serde_json::json!({ "status": status }));
+    if let Err(e) = api
+        .patch_status(&key.1, &PatchParams::default(), &patch)
+        .await
+    {
+        warn!(
+            "Failed updating the status of RateLimit {}/{}: {}",
+            key.0, key.1, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LimitSpec;
+    use limitador::limit::{InvalidLimit, Limit};
+    use std::time::Duration;
+
+    #[test]
+    fn converts_limit_specs() {
+        let spec: LimitSpec = serde_json::from_value(serde_json::json!({
+            "namespace": "test_namespace",
+            "maxValue": 10,
+            "seconds": 60,
+            "conditions": ["req_method == 'GET'"],
+            "variables": ["user_id"],
+        }))
+        .unwrap();
+
+        let limit = Limit::try_from(&spec).unwrap();
+        assert_eq!(
+            limit,
+            Limit::new(
+                "test_namespace",
+                10,
+                60,
+                vec!["req_method == 'GET'".try_into().unwrap()],
+                vec!["user_id".try_into().unwrap()],
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_limit_specs() {
+        let spec: LimitSpec = serde_json::from_value(serde_json::json!({
+            "namespace": "test_namespace",
+            "maxValue": 10,
+            "seconds": 0,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            Limit::try_from(&spec).unwrap_err(),
+            InvalidLimit::InvalidWindow(Duration::ZERO)
+        );
+    }
+}
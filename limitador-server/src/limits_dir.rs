@@ -0,0 +1,330 @@
+use crate::{LimitadorServerError, Limiter};
+use limitador::limit::{Limit, Namespace};
+use notify::{Error, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+
+struct LimitsFile {
+    content: String,
+    limits: Vec<Limit>,
+}
+
+/// The limits of a directory of YAML files, e.g. one per team. The files are
+/// tracked individually, so that a change to one of them only replaces the
+/// limits of the namespaces it defines, or used to.
+pub struct LimitsDir {
+    path: PathBuf,
+    files: BTreeMap<PathBuf, LimitsFile>,
+}
+
+impl LimitsDir {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Reloads the files that changed, were added or removed since the last
+    /// sync. A file that fails to parse, that defines a limit another file
+    /// already does, or whose limits fail to be replaced, is left out, its
+    /// previous limits being kept until the next sync.
+    pub async fn sync(&mut self, limiter: &Limiter) -> Vec<LimitadorServerError> {
+        let mut present = match self.yaml_files() {
+            Ok(files) => files,
+            Err(e) => return vec![e],
+        };
+        present.extend(self.files.keys().cloned());
+
+        let mut errors = Vec::new();
+        for path in present {
+            let content = match fs::read_to_string(&path) {
+                Ok(content) => Some(content),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    errors.push(LimitadorServerError::ConfigFile(format!(
+                        "Couldn't read file '{}': {e}",
+                        path.display()
+                    )));
+                    continue;
+                }
+            };
+            if content.as_ref() == self.files.get(&path).map(|file| &file.content) {
+                continue;
+            }
+            match self.reload(limiter, &path, content).await {
+                Ok(_) => info!("reloaded limit file {}", path.display()),
+                Err(e) => errors.push(e),
+            }
+        }
+        errors
+    }
+
+    fn yaml_files(&self) -> Result<BTreeSet<PathBuf>, LimitadorServerError> {
+        let entries = fs::read_dir(&self.path).map_err(|e| {
+            LimitadorServerError::ConfigFile(format!(
+                "Couldn't read directory '{}': {e}",
+                self.path.display()
+            ))
+        })?;
+        Ok(entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_file()
+                    && matches!(
+                        path.extension().and_then(|ext| ext.to_str()),
+                        Some("yaml" | "yml")
+                    )
+            })
+            .collect())
+    }
+
+    async fn reload(
+        &mut self,
+        limiter: &Limiter,
+        path: &Path,
+        content: Option<String>,
+    ) -> Result<(), LimitadorServerError> {
+        let limits: Vec<Limit> = match &content {
+            None => Vec::new(),
+            Some(content) => serde_yaml::from_str(content).map_err(|e| {
+                LimitadorServerError::ConfigFile(format!(
+                    "Couldn't parse '{}': {e}",
+                    path.display()
+                ))
+            })?,
+        };
+        self.check_conflicts(path, &limits)?;
+
+        let previous = self.files.get(path).map(|file| file.limits.as_slice());
+        let namespaces: BTreeSet<Namespace> = limits
+            .iter()
+            .chain(previous.unwrap_or_default())
+            .map(|limit| limit.namespace().clone())
+            .collect();
+        let others = self
+            .files
+            .iter()
+            .filter(|(other, _)| *other != path)
+            .flat_map(|(_, file)| file.limits.iter());
+
+        // The file is only recorded once the limits of all its namespaces got
+        // replaced, for a failed reload to be retried on the next sync
+        let actor = format!("file:{}", path.display());
+        let mut replaced = Vec::new();
+        for namespace in namespaces {
+            let updated = of_namespace(others.clone().chain(&limits), &namespace);
+            if let Err(e) = limiter.replace_limits(&namespace, updated, &actor).await {
+                for namespace in replaced {
+                    let all = self.files.values().flat_map(|file| file.limits.iter());
+                    let current = of_namespace(all, &namespace);
+                    if let Err(e) = limiter.replace_limits(&namespace, current, &actor).await {
+                        warn!(
+                            "Failed restoring the limits of namespace '{}': {}",
+                            namespace.as_ref(),
+                            e
+                        );
+                    }
+                }
+                return Err(e);
+            }
+            replaced.push(namespace);
+        }
+
+        match content {
+            None => self.files.remove(path),
+            Some(content) => self
+                .files
+                .insert(path.to_path_buf(), LimitsFile { content, limits }),
+        };
+        Ok(())
+    }
+
+    // The same limit, or the same id within a namespace, can't be defined by
+    // two different files
+    fn check_conflicts(&self, path: &Path, limits: &[Limit]) -> Result<(), LimitadorServerError> {
+        for (other, file) in self.files.iter().filter(|(other, _)| *other != path) {
+            for limit in limits {
+                let conflicting = file.limits.iter().find(|defined| {
+                    *defined == limit || (defined.id().is_some() && defined.id() == limit.id())
+                });
+                if let Some(defined) = conflicting {
+                    return Err(LimitadorServerError::ConfigFile(format!(
+                        "'{}' defines limit '{}' of namespace '{}', already defined by '{}'",
+                        path.display(),
+                        defined.id().or(defined.name()).unwrap_or("unnamed"),
+                        limit.namespace().as_ref(),
+                        other.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn of_namespace<'a>(limits: impl Iterator<Item = &'a Limit>, namespace: &Namespace) -> Vec<Limit> {
+    limits
+        .filter(|limit| limit.namespace() == namespace)
+        .cloned()
+        .collect()
+}
+
+/// Loads the limits of the directory, and keeps them in sync with its files.
+/// The returned watcher stops watching the directory once dropped
+pub async fn watch(
+    rate_limiter: Arc<Limiter>,
+    limits_dir: String,
+) -> Result<RecommendedWatcher, Box<dyn std::error::Error>> {
+    info!("limits directory path: {}", limits_dir);
+    let mut dir = LimitsDir::new(&limits_dir);
+    let errors = dir.sync(&rate_limiter).await;
+    if !errors.is_empty() {
+        for e in errors {
+            eprintln!("Failed to load limits: {e}");
+        }
+        std::process::exit(1)
+    }
+    let dir = Arc::new(Mutex::new(dir));
+
+    let limiter = Arc::clone(&rate_limiter);
+    let synced = Arc::clone(&dir);
+    let handle = Handle::current();
+    // Any event is a cue to sync, which only reloads the files that changed,
+    // e.g. when a ConfigMap swaps the symlinks of all its files at once
+    let mut watcher = RecommendedWatcher::new(
+        move |result: Result<Event, Error>| match result {
+            Ok(event) if event.kind.is_access() => (),
+            Ok(_) => {
+                let limiter = Arc::clone(&limiter);
+                let dir = Arc::clone(&synced);
+                handle.spawn(async move {
+                    for e in dir.lock().await.sync(&limiter).await {
+                        error!("Failed reloading limits: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!(
+                "Something went wrong while watching limits directory: {}",
+                e
+            ),
+        },
+        notify::Config::default(),
+    )?;
+    watcher.watch(Path::new(&limits_dir), RecursiveMode::NonRecursive)?;
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                for e in dir.lock().await.sync(&rate_limiter).await {
+                    error!("Failed reloading limits: {}", e);
+                }
+            }
+        });
+    }
+
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LimitsDir;
+    use crate::config::Configuration;
+    use crate::Limiter;
+    use limitador::limit::{Limit, Namespace};
+    use std::fs;
+
+    fn limits_of(limiter: &Limiter, namespace: &str) -> usize {
+        match limiter {
+            Limiter::Blocking(limiter) => limiter.get_limits(&Namespace::from(namespace)).len(),
+            Limiter::Async(limiter) => limiter.get_limits(&Namespace::from(namespace)).len(),
+        }
+    }
+
+    #[tokio::test]
+    async fn syncs_files_individually() {
+        let path = std::env::temp_dir().join(format!("limits_dir_{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        fs::write(
+            path.join("a.yaml"),
+            "- namespace: a\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+        fs::write(
+            path.join("b.yaml"),
+            "- namespace: b\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+
+        let mut dir = LimitsDir::new(&path);
+        assert!(dir.sync(&limiter).await.is_empty());
+        assert_eq!(limits_of(&limiter, "a"), 1);
+        assert_eq!(limits_of(&limiter, "b"), 1);
+
+        // Conflicting with a.yaml, so rejected, and b.yaml's limits are kept
+        fs::write(
+            path.join("b.yaml"),
+            "- namespace: a\n  max_value: 5\n  seconds: 60\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+        assert_eq!(dir.sync(&limiter).await.len(), 1);
+        assert_eq!(limits_of(&limiter, "a"), 1);
+        assert_eq!(limits_of(&limiter, "b"), 1);
+
+        fs::remove_file(path.join("b.yaml")).unwrap();
+        assert!(dir.sync(&limiter).await.is_empty());
+        assert_eq!(limits_of(&limiter, "a"), 1);
+        assert_eq!(limits_of(&limiter, "b"), 0);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_files_whose_limits_were_partially_replaced() {
+        let path = std::env::temp_dir().join(format!("limits_dir_retry_{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        // Defined outside of the directory, conflicting with the id of the
+        // limit of namespace `c`
+        let outside: Vec<Limit> = serde_yaml::from_str(
+            "- id: shared\n  namespace: z\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+        limiter
+            .replace_limits(&Namespace::from("z"), outside, "test")
+            .await
+            .unwrap();
+        fs::write(
+            path.join("a.yaml"),
+            "- namespace: b\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n\
+             - id: shared\n  namespace: c\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+
+        // Namespace `b` gets replaced before `c` fails, and is then restored
+        let mut dir = LimitsDir::new(&path);
+        assert_eq!(dir.sync(&limiter).await.len(), 1);
+        assert_eq!(limits_of(&limiter, "b"), 0);
+        assert_eq!(limits_of(&limiter, "c"), 0);
+
+        // The unchanged file is reloaded once the conflict is gone
+        limiter
+            .replace_limits(&Namespace::from("z"), Vec::new(), "test")
+            .await
+            .unwrap();
+        assert!(dir.sync(&limiter).await.is_empty());
+        assert_eq!(limits_of(&limiter, "b"), 1);
+        assert_eq!(limits_of(&limiter, "c"), 1);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use crate::config::LimitsDiscoveryConfiguration;
+use crate::Limiter;
+use discovery::limits_discovery_service_client::LimitsDiscoveryServiceClient;
+use discovery::{LimitsDiscoveryRequest, LimitsDiscoveryResponse};
+use limitador::limit::{Limit, Namespace};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod discovery {
+    tonic::include_proto!("limitador.config.v1");
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Syncs the limits with the ones the management server streams, forever.
+/// Whenever the stream breaks, it is opened again, announcing the version
+/// last applied, for the server to only send what changed since.
+pub async fn sync_limits(limiter: Arc<Limiter>, config: LimitsDiscoveryConfiguration) {
+    info!(
+        "syncing limits from the management server at {}",
+        config.server
+    );
+    let mut version = String::new();
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let opened = Instant::now();
+        match stream_limits(&limiter, &config, &mut version).await {
+            Ok(()) => warn!("The management server closed the limits stream"),
+            Err(e) => warn!("Limits stream from the management server failed: {}", e),
+        }
+        // A stream that was up for a while isn't part of a series of failures
+        if opened.elapsed() > MAX_RECONNECT_DELAY {
+            delay = MIN_RECONNECT_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn stream_limits(
+    limiter: &Limiter,
+    config: &LimitsDiscoveryConfiguration,
+    version: &mut String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut client = LimitsDiscoveryServiceClient::connect(config.server.clone()).await?;
+    let (requests, outbound) = mpsc::channel(4);
+    requests
+        .send(LimitsDiscoveryRequest {
+            node_id: config.node_id.clone(),
+            version_info: version.clone(),
+            response_nonce: String::new(),
+            error_detail: None,
+        })
+        .await?;
+
+    let mut responses = client
+        .stream_limits(ReceiverStream::new(outbound))
+        .await?
+        .into_inner();
+    while let Some(response) = responses.message().await? {
+        let nonce = response.nonce.clone();
+        let error_detail = match apply(limiter, response).await {
+            Ok(applied) => {
+                info!(
+                    "applied limits version {} from the management server",
+                    applied
+                );
+                *version = applied;
+                None
+            }
+            Err(e) => {
+                warn!("Rejecting limits from the management server: {}", e);
+                Some(e)
+            }
+        };
+        requests
+            .send(LimitsDiscoveryRequest {
+                node_id: config.node_id.clone(),
+                version_info: version.clone(),
+                response_nonce: nonce,
+                error_detail,
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+// Either all the limits of the response are valid and get applied, or none
+// are, returning why
+async fn apply(limiter: &Limiter, response: LimitsDiscoveryResponse) -> Result<String, String> {
+    let mut updates = Vec::new();
+    for namespace in response.namespaces {
+        let limits = namespace
+            .limits
+            .into_iter()
+            .map(|limit| {
+                if limit.namespace != namespace.namespace {
+                    return Err(format!(
+                        "limit of namespace '{}' listed under namespace '{}'",
+                        limit.namespace, namespace.namespace
+                    ));
+                }
+                Limit::try_from(limit).map_err(|e| {
+                    format!("invalid limit in namespace '{}': {e}", namespace.namespace)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        updates.push((Namespace::from(namespace.namespace), limits));
+    }
+    for namespace in response.removed_namespaces {
+        updates.push((Namespace::from(namespace), Vec::new()));
+    }
+
+    for (namespace, limits) in updates {
+        limiter
+            .replace_limits(&namespace, limits, "limits-discovery")
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(response.version_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::discovery::limits_discovery_service_server::{
+        LimitsDiscoveryService, LimitsDiscoveryServiceServer,
+    };
+    use super::discovery::{LimitsDiscoveryRequest, LimitsDiscoveryResponse, NamespaceLimits};
+    use super::stream_limits;
+    use crate::admin_grpc::server::admin::Limit;
+    use crate::config::{Configuration, LimitsDiscoveryConfiguration};
+    use crate::Limiter;
+    use limitador::limit::Namespace;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status, Streaming};
+
+    // Sends its responses one at a time, each once the previous one got
+    // acknowledged or rejected, and reports the requests it gets
+    struct ManagementServer {
+        responses: Mutex<Option<Vec<LimitsDiscoveryResponse>>>,
+        requests: mpsc::UnboundedSender<LimitsDiscoveryRequest>,
+    }
+
+    #[tonic::async_trait]
+    impl LimitsDiscoveryService for ManagementServer {
+        type StreamLimitsStream = ReceiverStream<Result<LimitsDiscoveryResponse, Status>>;
+
+        async fn stream_limits(
+            &self,
+            request: Request<Streaming<LimitsDiscoveryRequest>>,
+        ) -> Result<Response<Self::StreamLimitsStream>, Status> {
+            let mut inbound = request.into_inner();
+            let responses = self.responses.lock().await.take().unwrap_or_default();
+            let requests = self.requests.clone();
+            let (tx, rx) = mpsc::channel(1);
+            tokio::spawn(async move {
+                let mut responses = responses.into_iter();
+                while let Ok(Some(request)) = inbound.message().await {
+                    requests.send(request).unwrap();
+                    match responses.next() {
+                        Some(response) => tx.send(Ok(response)).await.unwrap(),
+                        None => break,
+                    }
+                }
+            });
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+    }
+
+    fn limit(namespace: &str, max_value: u64, seconds: u64) -> Limit {
+        Limit {
+            namespace: namespace.to_string(),
+            max_value,
+            seconds,
+            ..Default::default()
+        }
+    }
+
+    fn limits_of(limiter: &Limiter, namespace: &str) -> usize {
+        match limiter {
+            Limiter::Blocking(limiter) => limiter.get_limits(&Namespace::from(namespace)).len(),
+            Limiter::Async(limiter) => limiter.get_limits(&Namespace::from(namespace)).len(),
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_and_nacks_responses() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+        let server = ManagementServer {
+            responses: Mutex::new(Some(vec![
+                LimitsDiscoveryResponse {
+                    version_info: "1".to_string(),
+                    nonce: "a".to_string(),
+                    namespaces: vec![
+                        NamespaceLimits {
+                            namespace: "a".to_string(),
+                            limits: vec![limit("a", 10, 60)],
+                        },
+                        NamespaceLimits {
+                            namespace: "b".to_string(),
+                            limits: vec![limit("b", 10, 60)],
+                        },
+                    ],
+                    removed_namespaces: vec![],
+                },
+                LimitsDiscoveryResponse {
+                    version_info: "2".to_string(),
+                    nonce: "b".to_string(),
+                    namespaces: vec![NamespaceLimits {
+                        namespace: "a".to_string(),
+                        limits: vec![limit("a", 10, 0)],
+                    }],
+                    removed_namespaces: vec!["b".to_string()],
+                },
+                LimitsDiscoveryResponse {
+                    version_info: "3".to_string(),
+                    nonce: "c".to_string(),
+                    namespaces: vec![],
+                    removed_namespaces: vec!["b".to_string()],
+                },
+            ])),
+            requests,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(LimitsDiscoveryServiceServer::new(server))
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+
+        let limiter = Limiter::new(Configuration::default()).await.unwrap();
+        let config = LimitsDiscoveryConfiguration {
+            server: format!("http://{address}"),
+            node_id: "test".to_string(),
+        };
+        let mut version = String::new();
+        stream_limits(&limiter, &config, &mut version)
+            .await
+            .unwrap();
+
+        let requests: Vec<(String, String, bool)> = std::iter::from_fn(|| received.try_recv().ok())
+            .map(|r| (r.version_info, r.response_nonce, r.error_detail.is_some()))
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                ("".to_string(), "".to_string(), false),
+                ("1".to_string(), "a".to_string(), false),
+                // The invalid window got the whole response rejected
+                ("1".to_string(), "b".to_string(), true),
+                ("3".to_string(), "c".to_string(), false),
+            ]
+        );
+        assert_eq!(version, "3");
+        assert_eq!(limits_of(&limiter, "a"), 1);
+        assert_eq!(limits_of(&limiter, "b"), 0);
+    }
+}
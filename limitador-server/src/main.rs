@@ -5,27 +5,75 @@
 extern crate log;
 extern crate clap;
 
-#[cfg(feature = "distributed_storage")]
-use crate::config::DistributedStorageConfiguration;
+use crate::admin_grpc::server::run_admin_grpc_server;
+#[cfg(feature = "etcd_storage")]
+use crate::config::EtcdStorageConfiguration;
+#[cfg(feature = "kubernetes")]
+use crate::config::LimitsCrdConfiguration;
+#[cfg(feature = "partitioned_storage")]
+use crate::config::PartitionedStorageConfiguration;
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+use crate::config::PeerTlsConfiguration;
+#[cfg(feature = "raft_storage")]
+use crate::config::RaftStorageConfiguration;
+#[cfg(feature = "sqlite_storage")]
+use crate::config::SqliteStorageConfiguration;
 use crate::config::{
-    redacted_url, Configuration, DiskStorageConfiguration, InMemoryStorageConfiguration,
-    RedisStorageCacheConfiguration, RedisStorageConfiguration, StorageConfiguration,
+    redacted_url, AuditLogSink, CircuitBreakerConfiguration, Configuration,
+    DiskStorageConfiguration, HttpAuthConfiguration, HttpOverLimitConfiguration,
+    InMemoryStorageConfiguration, LimitMetricsConfiguration, LimitsDiscoveryConfiguration,
+    LogFormat, MetricsPushConfiguration, OidcConfiguration, RedisBroadcastConfiguration,
+    RedisConnectionConfiguration, RedisStorageCacheConfiguration, RedisStorageConfiguration,
+    RedisWriteBehindConfiguration, RlsTlsConfiguration, StatsdConfiguration, StorageConfiguration,
 };
+#[cfg(feature = "distributed_storage")]
+use crate::config::{DistributedConflictResolution, DistributedStorageConfiguration};
+use crate::envoy_rls::mapping::DescriptorMapping;
 use crate::envoy_rls::server::{run_envoy_rls_server, RateLimitHeaders};
+use crate::envoy_rls::tls::TlsAcceptor;
+use crate::hottest_counters::{track_hottest, HOTTEST_COUNTERS_PERIOD};
+use crate::http_api::auth::Authenticator;
+use crate::http_api::limited::LimitedResponse;
 use crate::http_api::server::run_http_server;
 use crate::metrics::MetricsLayer;
-use clap::{value_parser, Arg, ArgAction, Command};
+use crate::otlp_metrics::OtlpRecorder;
+use crate::over_limit::OverLimitBehaviors;
+use crate::statsd_metrics::StatsdRecorder;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use const_format::formatcp;
 use limitador::counter::Counter;
 use limitador::errors::LimitadorError;
-use limitador::limit::Limit;
+use limitador::limit::{Limit, Namespace};
+use limitador::storage::circuit_breaker::{CircuitBreakerStorage, DEFAULT_RESET_TIMEOUT_SEC};
+use limitador::storage::concurrent::ConcurrentStorage;
 use limitador::storage::disk::DiskStorage;
+#[cfg(feature = "distributed_storage")]
+use limitador::storage::distributed::{
+    Batching, Compression, ConflictResolution, CrInMemoryStorageBuilder, PeerDiscovery,
+    Rebalancing, RegionalQuotas, DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_DISCOVERY_PERIOD_MS,
+    DEFAULT_MAX_BATCH_DELAY_MS, DEFAULT_MAX_BATCH_SIZE, DEFAULT_MIN_REGION_SHARE,
+};
+#[cfg(feature = "etcd_storage")]
+use limitador::storage::etcd::EtcdStorage;
+use limitador::storage::failover::FailoverStorage;
+use limitador::storage::in_memory::{CachePolicy, InMemoryStorage};
+#[cfg(feature = "partitioned_storage")]
+use limitador::storage::partitioned::PartitionedStorage;
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+use limitador::storage::peer_auth::{PeerAuth, PeerTls};
+#[cfg(feature = "raft_storage")]
+use limitador::storage::raft::RaftStorage;
 use limitador::storage::redis::{
-    AsyncRedisStorage, CachedRedisStorage, CachedRedisStorageBuilder, DEFAULT_BATCH_SIZE,
-    DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS, DEFAULT_RESPONSE_TIMEOUT_MS,
+    AsyncRedisStorage, BroadcastRedisStorage, BroadcastRedisStorageBuilder, CachedRedisStorage,
+    CachedRedisStorageBuilder, KeyCodec, RedisConnectionConfig, WriteBehindRedisStorage,
+    WriteBehindRedisStorageBuilder, DEFAULT_BATCH_SIZE, DEFAULT_BROADCAST_CHANNEL,
+    DEFAULT_BROADCAST_PERIOD_MS, DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS,
+    DEFAULT_RESPONSE_TIMEOUT_MS,
 };
+#[cfg(feature = "sqlite_storage")]
+use limitador::storage::sqlite::SqliteStorage;
 #[cfg(feature = "distributed_storage")]
-use limitador::storage::DistributedInMemoryStorage;
+use limitador::storage::DEFAULT_DISTRIBUTED_SYNC_TIMEOUT_MS;
 use limitador::storage::{AsyncCounterStorage, AsyncStorage, Storage};
 use limitador::{
     storage, AsyncRateLimiter, AsyncRateLimiterBuilder, RateLimiter, RateLimiterBuilder,
@@ -36,7 +84,8 @@ use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_sdk::{trace, Resource};
-use prometheus_metrics::PrometheusMetrics;
+use otlp_tonic::metadata::{AsciiMetadataKey, MetadataMap};
+use prometheus_metrics::{Exporters, PrometheusMetrics};
 use std::fmt::Display;
 use std::fs;
 use std::path::Path;
@@ -50,18 +99,31 @@ use clap::parser::ValuesRef;
 use sysinfo::{MemoryRefreshKind, RefreshKind, System};
 use thiserror::Error;
 use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tokio::time::Instant;
 use tracing::level_filters::LevelFilter;
 use tracing::Subscriber;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{layer::SubscriberExt, Layer};
 
+mod admin_grpc;
+mod audit;
 mod envoy_rls;
+mod hottest_counters;
 mod http_api;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod limits_dir;
+mod limits_discovery;
+mod over_limit;
+mod validate;
 
 mod config;
 mod metrics;
+mod otlp_metrics;
 pub mod prometheus_metrics;
+mod statsd_metrics;
 
 const LIMITADOR_VERSION: &str = env!("CARGO_PKG_VERSION");
 const LIMITADOR_PROFILE: &str = env!("LIMITADOR_PROFILE");
@@ -83,7 +145,10 @@ pub enum Limiter {
 
 impl From<LimitadorError> for LimitadorServerError {
     fn from(e: LimitadorError) -> Self {
-        Self::Internal(e)
+        match e {
+            LimitadorError::LimitIdConflict(conflict) => Self::ConfigFile(conflict.to_string()),
+            e => Self::Internal(e),
+        }
     }
 }
 
@@ -93,8 +158,16 @@ impl Limiter {
             StorageConfiguration::Redis(cfg) => Self::redis_limiter(cfg).await,
             StorageConfiguration::InMemory(cfg) => Self::in_memory_limiter(cfg),
             #[cfg(feature = "distributed_storage")]
-            StorageConfiguration::Distributed(cfg) => Self::distributed_limiter(cfg),
+            StorageConfiguration::Distributed(cfg) => Self::distributed_limiter(cfg).await,
             StorageConfiguration::Disk(cfg) => Self::disk_limiter(cfg),
+            #[cfg(feature = "sqlite_storage")]
+            StorageConfiguration::Sqlite(cfg) => Self::sqlite_limiter(cfg),
+            #[cfg(feature = "etcd_storage")]
+            StorageConfiguration::Etcd(cfg) => Self::etcd_limiter(cfg).await,
+            #[cfg(feature = "raft_storage")]
+            StorageConfiguration::Raft(cfg) => Self::raft_limiter(cfg),
+            #[cfg(feature = "partitioned_storage")]
+            StorageConfiguration::Partitioned(cfg) => Self::partitioned_limiter(cfg),
         };
 
         Ok(rate_limiter)
@@ -108,17 +181,76 @@ impl Limiter {
     }
 
     async fn storage_using_redis(cfg: RedisStorageConfiguration) -> AsyncStorage {
-        let counters: Box<dyn AsyncCounterStorage> = if let Some(cache) = &cfg.cache {
-            Box::new(Self::storage_using_redis_and_local_cache(&cfg.url, cache).await)
+        let connection_cfg = Self::redis_connection_config(&cfg.connection);
+        let counters: Box<dyn AsyncCounterStorage> = if let Some(write_behind) = &cfg.write_behind {
+            Box::new(
+                Self::storage_using_redis_write_behind(&cfg.url, write_behind, connection_cfg)
+                    .await,
+            )
+        } else if let Some(broadcast) = &cfg.broadcast {
+            Box::new(Self::storage_using_redis_broadcast(&cfg.url, broadcast, connection_cfg).await)
+        } else if let Some(cache) = &cfg.cache {
+            Box::new(
+                Self::storage_using_redis_and_local_cache(&cfg.url, cache, connection_cfg).await,
+            )
         } else {
             // Let's use the async impl. This could be configurable if needed.
-            Box::new(Self::storage_using_async_redis(&cfg.url).await)
+            let (key_codec, other_key_codec) = if cfg.hashed_keys {
+                (KeyCodec::Hashed, KeyCodec::Full)
+            } else {
+                (KeyCodec::Full, KeyCodec::Hashed)
+            };
+            let storage = Self::storage_using_async_redis(&cfg.url, &connection_cfg)
+                .await
+                .strict(cfg.strict)
+                .key_codec(key_codec);
+            Box::new(if cfg.migrate_keys {
+                storage.migrate_keys_from(other_key_codec)
+            } else {
+                storage
+            })
         };
+        if let Some(breaker) = &cfg.circuit_breaker {
+            let breaker = CircuitBreakerStorage::new(counters)
+                .failure_threshold(breaker.failures)
+                .reset_timeout(Duration::from_millis(breaker.reset_timeout))
+                .allow_when_open(breaker.allow_when_open);
+            return AsyncStorage::with_counter_storage(Box::new(breaker));
+        }
+        if cfg.in_memory_fallback {
+            let fallback = FailoverStorage::new(counters, Box::new(InMemoryStorage::default()));
+            return AsyncStorage::with_counter_storage(Box::new(fallback));
+        }
         AsyncStorage::with_counter_storage(counters)
     }
 
-    async fn storage_using_async_redis(redis_url: &str) -> AsyncRedisStorage {
-        AsyncRedisStorage::new(redis_url)
+    fn redis_connection_config(cfg: &RedisConnectionConfiguration) -> RedisConnectionConfig {
+        let read = |path: &Option<String>| {
+            path.as_ref().map(|path| {
+                fs::read(path).unwrap_or_else(|err| {
+                    eprintln!("Failed to read '{path}': {err}");
+                    process::exit(1)
+                })
+            })
+        };
+        RedisConnectionConfig {
+            username: cfg.username.clone(),
+            password: cfg.password.clone(),
+            ca_cert: read(&cfg.ca_cert),
+            client_cert: read(&cfg.client_cert),
+            client_key: read(&cfg.client_key),
+            connection_timeout: cfg.connection_timeout.map(Duration::from_millis),
+            response_timeout: cfg.response_timeout.map(Duration::from_millis),
+            pool_size: cfg.pool_size,
+            replicas: cfg.replicas.clone(),
+        }
+    }
+
+    async fn storage_using_async_redis(
+        redis_url: &str,
+        connection_cfg: &RedisConnectionConfig,
+    ) -> AsyncRedisStorage {
+        AsyncRedisStorage::new_with_config(redis_url, connection_cfg)
             .await
             .unwrap_or_else(|err| {
                 let redacted_redis_url = redacted_url(String::from(redis_url));
@@ -130,14 +262,25 @@ impl Limiter {
     async fn storage_using_redis_and_local_cache(
         redis_url: &str,
         cache_cfg: &RedisStorageCacheConfiguration,
+        connection_cfg: RedisConnectionConfig,
     ) -> CachedRedisStorage {
-        // TODO: Not all the options are configurable via ENV. Add them as needed.
-
-        let cached_redis_storage = CachedRedisStorageBuilder::new(redis_url)
+        let mut cached_redis_storage = CachedRedisStorageBuilder::new(redis_url)
             .batch_size(cache_cfg.batch_size)
             .flushing_period(Duration::from_millis(cache_cfg.flushing_period as u64))
             .max_cached_counters(cache_cfg.max_counters)
-            .response_timeout(Duration::from_millis(cache_cfg.response_timeout));
+            .response_timeout(Duration::from_millis(cache_cfg.response_timeout))
+            .connection_config(connection_cfg);
+        if let Some(max_staleness) = cache_cfg.max_staleness {
+            cached_redis_storage =
+                cached_redis_storage.max_staleness(Duration::from_millis(max_staleness));
+        }
+        if let Some(channel) = &cache_cfg.updates_channel {
+            cached_redis_storage = cached_redis_storage.updates_channel(channel);
+        }
+        if let Some(max_flushing_period) = cache_cfg.max_flushing_period {
+            cached_redis_storage =
+                cached_redis_storage.adaptive_flushing(Duration::from_millis(max_flushing_period));
+        }
 
         cached_redis_storage.build().await.unwrap_or_else(|err| {
             let redacted_redis_url = redacted_url(String::from(redis_url));
@@ -146,6 +289,43 @@ impl Limiter {
         })
     }
 
+    async fn storage_using_redis_write_behind(
+        redis_url: &str,
+        write_behind_cfg: &RedisWriteBehindConfiguration,
+        connection_cfg: RedisConnectionConfig,
+    ) -> WriteBehindRedisStorage {
+        WriteBehindRedisStorageBuilder::new(redis_url)
+            .flushing_period(Duration::from_millis(write_behind_cfg.flushing_period))
+            .max_cached_counters(write_behind_cfg.max_counters)
+            .connection_config(connection_cfg)
+            .build()
+            .await
+            .unwrap_or_else(|err| {
+                let redacted_redis_url = redacted_url(String::from(redis_url));
+                eprintln!("Failed to connect to Redis at {redacted_redis_url}: {err}");
+                process::exit(1)
+            })
+    }
+
+    async fn storage_using_redis_broadcast(
+        redis_url: &str,
+        broadcast_cfg: &RedisBroadcastConfiguration,
+        connection_cfg: RedisConnectionConfig,
+    ) -> BroadcastRedisStorage {
+        BroadcastRedisStorageBuilder::new(redis_url)
+            .channel(&broadcast_cfg.channel)
+            .broadcast_period(Duration::from_millis(broadcast_cfg.broadcast_period))
+            .max_cached_counters(broadcast_cfg.max_counters)
+            .connection_config(connection_cfg)
+            .build()
+            .await
+            .unwrap_or_else(|err| {
+                let redacted_redis_url = redacted_url(String::from(redis_url));
+                eprintln!("Failed to connect to Redis at {redacted_redis_url}: {err}");
+                process::exit(1)
+            })
+    }
+
     fn disk_limiter(cfg: DiskStorageConfiguration) -> Self {
         let storage = match DiskStorage::open(cfg.path.as_str(), cfg.optimization) {
             Ok(storage) => storage,
@@ -161,26 +341,252 @@ impl Limiter {
     }
 
     fn in_memory_limiter(cfg: InMemoryStorageConfiguration) -> Self {
+        let cache_size = || cfg.cache_size.or_else(guess_cache_size).unwrap();
+        if cfg.concurrent {
+            let storage = ConcurrentStorage::new(cache_size());
+            return Self::Blocking(
+                RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)))
+                    .build(),
+            );
+        }
+        let mut policy = match cfg.cache_max_bytes {
+            Some(max_bytes) => CachePolicy::max_bytes(max_bytes),
+            None => CachePolicy::max_counters(cache_size()),
+        };
+        if let Some(idle_timeout) = cfg.idle_timeout {
+            policy = policy.time_to_idle(Duration::from_millis(idle_timeout));
+        }
+        let mut storage = InMemoryStorage::with_cache_policy(policy);
+        if let Some(interval) = cfg.sweep_interval {
+            storage = storage.sweep_every(Duration::from_millis(interval));
+        }
+        if let Some(snapshot_path) = cfg.snapshot_path {
+            // The counters get saved to the snapshot when flushed, on shutdown
+            storage = storage.snapshot_on_flush(&snapshot_path);
+            match storage.restore_from(&snapshot_path) {
+                Ok(()) => info!("Restored counters from {}", snapshot_path),
+                Err(e) => warn!("Failed to restore counters from {}: {}", snapshot_path, e),
+            }
+        }
+        let rate_limiter_builder =
+            RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)));
+
+        Self::Blocking(rate_limiter_builder.build())
+    }
+
+    #[cfg(feature = "sqlite_storage")]
+    fn sqlite_limiter(cfg: SqliteStorageConfiguration) -> Self {
+        let storage = match SqliteStorage::open(cfg.path.as_str()) {
+            Ok(storage) => storage,
+            Err(err) => {
+                eprintln!("Failed to open DB at {}: {err}", cfg.path);
+                process::exit(1)
+            }
+        };
         let rate_limiter_builder =
-            RateLimiterBuilder::new(cfg.cache_size.or_else(guess_cache_size).unwrap());
+            RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)));
 
         Self::Blocking(rate_limiter_builder.build())
     }
 
-    #[cfg(feature = "distributed_storage")]
-    fn distributed_limiter(cfg: DistributedStorageConfiguration) -> Self {
-        let storage = DistributedInMemoryStorage::new(
+    #[cfg(feature = "etcd_storage")]
+    async fn etcd_limiter(cfg: EtcdStorageConfiguration) -> Self {
+        let storage = EtcdStorage::new(&cfg.endpoints)
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!(
+                    "Failed to connect to etcd at {}: {err}",
+                    cfg.endpoints.join(",")
+                );
+                process::exit(1)
+            });
+        let rate_limiter_builder =
+            AsyncRateLimiterBuilder::new(AsyncStorage::with_counter_storage(Box::new(storage)));
+
+        Self::Async(rate_limiter_builder.build())
+    }
+
+    #[cfg(feature = "raft_storage")]
+    fn raft_limiter(cfg: RaftStorageConfiguration) -> Self {
+        let auth = peer_auth(cfg.tls.as_ref(), cfg.token);
+        let storage =
+            RaftStorage::with_peer_auth(cfg.name, &cfg.listen_address, cfg.peers, &cfg.path, auth)
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to start the raft replica: {err}");
+                    process::exit(1)
+                });
+        let rate_limiter_builder =
+            AsyncRateLimiterBuilder::new(AsyncStorage::with_counter_storage(Box::new(storage)));
+
+        Self::Async(rate_limiter_builder.build())
+    }
+
+    #[cfg(feature = "partitioned_storage")]
+    fn partitioned_limiter(cfg: PartitionedStorageConfiguration) -> Self {
+        let storage = PartitionedStorage::new(
             cfg.name,
+            &cfg.listen_address,
+            cfg.peers,
             cfg.cache_size.or_else(guess_cache_size).unwrap(),
-            cfg.listen_address,
-            cfg.peer_urls,
-        );
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to start the partitioned storage: {err}");
+            process::exit(1)
+        });
+        let rate_limiter_builder =
+            AsyncRateLimiterBuilder::new(AsyncStorage::with_counter_storage(Box::new(storage)));
+
+        Self::Async(rate_limiter_builder.build())
+    }
+
+    #[cfg(feature = "distributed_storage")]
+    async fn distributed_limiter(cfg: DistributedStorageConfiguration) -> Self {
+        let auth = peer_auth(cfg.tls.as_ref(), cfg.token);
+        let mut builder = CrInMemoryStorageBuilder::new(cfg.name, cfg.listen_address)
+            .cache_size(cfg.cache_size.or_else(guess_cache_size).unwrap())
+            .peer_urls(cfg.peer_urls)
+            .peer_auth(auth)
+            .batching(Batching {
+                max_batch_size: cfg.max_batch_size,
+                max_delay: Duration::from_millis(cfg.max_batch_delay),
+                compression: cfg
+                    .compression
+                    .as_deref()
+                    .map(|compression| match compression {
+                        "zstd" => Compression::Zstd,
+                        _ => Compression::Lz4,
+                    }),
+                compression_threshold: cfg.compression_threshold,
+            })
+            .conflict_resolution(match cfg.conflict_resolution {
+                DistributedConflictResolution::Sum => ConflictResolution::Sum,
+                DistributedConflictResolution::MaxWins => ConflictResolution::MaxWins,
+                DistributedConflictResolution::Share(weight) => {
+                    ConflictResolution::WeightedShare(weight)
+                }
+                DistributedConflictResolution::Regional {
+                    region,
+                    shares,
+                    rebalance_period,
+                    min_share,
+                } => ConflictResolution::Regional(RegionalQuotas {
+                    region,
+                    shares: shares.into_iter().collect(),
+                    rebalancing: rebalance_period.map(|period| Rebalancing {
+                        period: Duration::from_millis(period),
+                        min_share,
+                    }),
+                }),
+            });
+        if let Some(address) = cfg.discovery {
+            builder = builder.peer_discovery(PeerDiscovery {
+                address,
+                period: Duration::from_millis(cfg.discovery_period),
+            });
+        }
+        let storage = builder.build().unwrap_or_else(|err| {
+            eprintln!("Failed to start the distributed storage: {err}");
+            process::exit(1)
+        });
+        // Don't serve traffic counting from zero, when peers know better
+        if !storage
+            .synced(Duration::from_millis(cfg.sync_timeout))
+            .await
+        {
+            warn!(
+                "No peer shared its counters within {}ms, starting without them",
+                cfg.sync_timeout
+            );
+        }
         let rate_limiter_builder =
             RateLimiterBuilder::with_storage(Storage::with_counter_storage(Box::new(storage)));
 
         Self::Blocking(rate_limiter_builder.build())
     }
 
+    /// Replaces all the limits with these, on behalf of the `actor`
+    pub async fn configure_with(
+        &self,
+        limits: Vec<Limit>,
+        actor: &str,
+    ) -> Result<(), LimitadorServerError> {
+        let before = self.all_limits();
+        match &self {
+            Self::Blocking(limiter) => limiter.configure_with(limits)?,
+            Self::Async(limiter) => limiter.configure_with(limits).await?,
+        }
+        audit::limits_changed(actor, &before, &self.all_limits());
+        Ok(())
+    }
+
+    /// Replaces the limits of the namespace with these, on behalf of the
+    /// `actor`
+    pub async fn replace_limits(
+        &self,
+        namespace: &Namespace,
+        limits: Vec<Limit>,
+        actor: &str,
+    ) -> Result<(), LimitadorServerError> {
+        let before = self.limits_of(namespace);
+        match &self {
+            Self::Blocking(limiter) => limiter.replace_limits(namespace, limits)?,
+            Self::Async(limiter) => limiter.replace_limits(namespace, limits).await?,
+        }
+        audit::limits_changed(actor, &before, &self.limits_of(namespace));
+        Ok(())
+    }
+
+    pub fn limits_of(&self, namespace: &Namespace) -> Vec<Limit> {
+        let limits = match &self {
+            Self::Blocking(limiter) => limiter.get_limits(namespace),
+            Self::Async(limiter) => limiter.get_limits(namespace),
+        };
+        limits.into_iter().collect()
+    }
+
+    fn all_limits(&self) -> Vec<Limit> {
+        let (namespaces, patterns) = match &self {
+            Self::Blocking(limiter) => (limiter.get_namespaces(), limiter.get_namespace_patterns()),
+            Self::Async(limiter) => (limiter.get_namespaces(), limiter.get_namespace_patterns()),
+        };
+        namespaces
+            .iter()
+            .chain(&patterns)
+            .flat_map(|namespace| self.limits_of(namespace))
+            .collect()
+    }
+
+    /// Writes the counter updates the storage holds back out. Blocking
+    /// storages might wait on other tasks for that, so get their own thread
+    pub async fn flush(self: Arc<Self>) -> Result<(), LimitadorServerError> {
+        if let Self::Async(limiter) = self.as_ref() {
+            limiter.flush().await?;
+            return Ok(());
+        }
+        tokio::task::spawn_blocking(move || match self.as_ref() {
+            Self::Blocking(limiter) => limiter.flush(),
+            Self::Async(_) => unreachable!("not a blocking limiter"),
+        })
+        .await
+        .expect("flushing the limiter panicked")?;
+        Ok(())
+    }
+
+    /// Checks that the storage of the counters can be reached
+    pub async fn ping(self: Arc<Self>) -> Result<(), LimitadorServerError> {
+        if let Self::Async(limiter) = self.as_ref() {
+            limiter.ping().await?;
+            return Ok(());
+        }
+        tokio::task::spawn_blocking(move || match self.as_ref() {
+            Self::Blocking(limiter) => limiter.ping(),
+            Self::Async(_) => unreachable!("not a blocking limiter"),
+        })
+        .await
+        .expect("pinging the storage panicked")?;
+        Ok(())
+    }
+
     pub async fn load_limits_from_file<P: AsRef<Path>>(
         &self,
         path: &P,
@@ -190,11 +596,8 @@ impl Limiter {
                 let parsed_limits: Result<Vec<Limit>, _> = serde_yaml::from_reader(f);
                 match parsed_limits {
                     Ok(limits) => {
-                        match &self {
-                            Self::Blocking(limiter) => limiter.configure_with(limits)?,
-                            Self::Async(limiter) => limiter.configure_with(limits).await?,
-                        }
-                        Ok(())
+                        let actor = format!("file:{}", path.as_ref().display());
+                        self.configure_with(limits, &actor).await
                     }
                     Err(e) => Err(LimitadorServerError::ConfigFile(format!(
                         "Couldn't parse: {e}"
@@ -210,37 +613,23 @@ impl Limiter {
     }
 }
 
-#[actix_rt::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = {
-        let (config, version) = create_config();
-        println!("{LIMITADOR_HEADER} {version}");
-
-        configure_tracing_subscriber(&config);
-
-        info!("Version: {}", version);
-        info!("Using config: {:?}", config);
-        config
-    };
-
-    let prometheus_metrics = Arc::new(PrometheusMetrics::new_with_options(
-        config.limit_name_in_labels,
-    ));
-
-    let limit_file = config.limits_file.clone();
-    let envoy_rls_address = config.rlp_address();
-    let http_api_address = config.http_address();
-    let rate_limit_headers = config.rate_limit_headers.clone();
-    let grpc_reflection_service = config.grpc_reflection_service;
-
-    let rate_limiter: Arc<Limiter> = match Limiter::new(config).await {
-        Ok(limiter) => Arc::new(limiter),
-        Err(e) => {
-            eprintln!("Error: {e}");
-            process::exit(1)
-        }
-    };
+async fn watch_limits(
+    rate_limiter: Arc<Limiter>,
+    limit_file: String,
+    limits_dir: Option<String>,
+) -> Result<RecommendedWatcher, Box<dyn std::error::Error>> {
+    match limits_dir {
+        Some(limits_dir) => limits_dir::watch(rate_limiter, limits_dir).await,
+        None => watch_limits_file(rate_limiter, limit_file).await,
+    }
+}
 
+// Loads the limits from the file, and reloads them whenever it changes. The
+// returned watcher stops watching the file once dropped
+async fn watch_limits_file(
+    rate_limiter: Arc<Limiter>,
+    limit_file: String,
+) -> Result<RecommendedWatcher, Box<dyn std::error::Error>> {
     info!("limits file path: {}", limit_file);
     if let Err(e) = rate_limiter.load_limits_from_file(&limit_file).await {
         eprintln!("Failed to load limit file: {e}");
@@ -315,21 +704,321 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     )?;
     watcher.watch(limits_file_dir, RecursiveMode::Recursive)?;
 
+    // A SIGHUP forces a reload, e.g. when the file watcher can't see the
+    // changes, as on some network file systems
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let limiter = Arc::clone(&rate_limiter);
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match limiter.load_limits_from_file(&limit_file).await {
+                    Ok(_) => info!("SIGHUP received; reloaded limit file"),
+                    Err(e) => error!("Failed reloading limit file: {}", e),
+                }
+            }
+        });
+    }
+
+    Ok(watcher)
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = {
+        let (config, version) = create_config();
+        println!("{LIMITADOR_HEADER} {version}");
+
+        configure_tracing_subscriber(&config);
+
+        info!("Version: {}", version);
+        info!("Using config: {:?}", config);
+        if config.lenient_conditions {
+            warn!("Lenient conditions are deprecated, fix the ones `limitador-server validate` rejects");
+            limitador::limit::set_lenient_conditions(true);
+        }
+        config
+    };
+
+    let mut metrics_provider = None;
+    let mut statsd_flusher = None;
+    let prometheus_metrics = {
+        let otlp = config.metrics_push.as_ref().map(|push| {
+            let recorder = OtlpRecorder::new(
+                &push.endpoint,
+                Duration::from_secs(push.interval),
+                &config.latency_buckets,
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to configure the OTLP metrics exporter: {e}");
+                process::exit(1)
+            });
+            metrics_provider = Some(recorder.provider());
+            recorder
+        });
+        let statsd = config.statsd.as_ref().map(|statsd| {
+            let (recorder, flusher) = StatsdRecorder::new(
+                &statsd.address,
+                Duration::from_secs(statsd.flush_interval),
+                statsd.tags.clone(),
+            )
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to configure the StatsD metrics exporter: {e}");
+                process::exit(1)
+            });
+            statsd_flusher = Some(flusher);
+            recorder
+        });
+        let metrics = PrometheusMetrics::new_with_exporters(
+            config.limit_name_in_labels,
+            &config.latency_buckets,
+            Exporters {
+                pull: config.metrics_pull,
+                otlp,
+                statsd,
+            },
+        );
+        let metrics = match config.limit_metrics.clone() {
+            Some(limit_metrics) => metrics.limit_metrics(limit_metrics),
+            None => metrics,
+        };
+        Arc::new(match config.metrics_max_series {
+            Some(max) => metrics.max_series(max),
+            None => metrics,
+        })
+    };
+
+    let limit_file = config.limits_file.clone();
+    let limits_dir = config.limits_dir.clone();
+    #[cfg(feature = "kubernetes")]
+    let limits_crd = config.limits_crd.clone();
+    let limits_discovery = config.limits_discovery.clone();
+    let envoy_rls_address = config.rlp_address();
+    let http_api_address = config.http_address();
+    let rate_limit_headers = config.rate_limit_headers.clone();
+    let grpc_reflection_service = config.grpc_reflection_service;
+    let admin_grpc_address = config.admin_grpc_address();
+    let rls_tls = config.rls_tls.clone();
+    let http_auth = config.http_auth.clone();
+    let http_over_limit = config.http_over_limit.clone();
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
+    let hottest_counters = config.hottest_counters;
+    let idempotency_header = config.idempotency_header.clone();
+    let descriptor_mapping = match &config.descriptor_mapping_file {
+        None => Arc::new(DescriptorMapping::default()),
+        Some(path) => match DescriptorMapping::load(path) {
+            Ok(mapping) => Arc::new(mapping),
+            Err(e) => {
+                eprintln!("Failed to load the descriptor mapping: {e}");
+                process::exit(1)
+            }
+        },
+    };
+    let over_limit_behaviors = match &config.over_limit_behaviors_file {
+        None => Arc::new(OverLimitBehaviors::default()),
+        Some(path) => match OverLimitBehaviors::load(path) {
+            Ok(behaviors) => Arc::new(behaviors),
+            Err(e) => {
+                eprintln!("Failed to load the over-limit behaviors: {e}");
+                process::exit(1)
+            }
+        },
+    };
+
+    let rate_limiter: Arc<Limiter> = match Limiter::new(config).await {
+        Ok(limiter) => Arc::new(limiter),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            process::exit(1)
+        }
+    };
+
+    #[cfg(feature = "kubernetes")]
+    let _watcher = match (limits_discovery, limits_crd) {
+        (Some(discovery), _) => {
+            tokio::spawn(limits_discovery::sync_limits(
+                Arc::clone(&rate_limiter),
+                discovery,
+            ));
+            None
+        }
+        (None, Some(crd)) => {
+            info!("syncing limits from the RateLimit resources");
+            let limiter = Arc::clone(&rate_limiter);
+            tokio::spawn(async move {
+                if let Err(e) = kubernetes::sync_limits(limiter, crd.namespace).await {
+                    error!("Failed syncing the RateLimit resources: {}", e);
+                }
+            });
+            None
+        }
+        (None, None) => {
+            Some(watch_limits(Arc::clone(&rate_limiter), limit_file, limits_dir).await?)
+        }
+    };
+    #[cfg(not(feature = "kubernetes"))]
+    let _watcher = match limits_discovery {
+        Some(discovery) => {
+            tokio::spawn(limits_discovery::sync_limits(
+                Arc::clone(&rate_limiter),
+                discovery,
+            ));
+            None
+        }
+        None => Some(watch_limits(Arc::clone(&rate_limiter), limit_file, limits_dir).await?),
+    };
+
+    let limiter = Arc::clone(&rate_limiter);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let result = match limiter.as_ref() {
+                Limiter::Blocking(limiter) => limiter.delete_expired_limits(),
+                Limiter::Async(limiter) => limiter.delete_expired_limits().await,
+            };
+            if let Err(e) = result {
+                warn!("Failed to delete expired limits: {}", e);
+            }
+        }
+    });
+
+    if let Some(top) = hottest_counters {
+        tokio::spawn(track_hottest(
+            Arc::clone(&rate_limiter),
+            Arc::clone(&prometheus_metrics),
+            top,
+            HOTTEST_COUNTERS_PERIOD,
+        ));
+    }
+
+    let rls_tls = rls_tls.map(|cfg| match TlsAcceptor::new(cfg) {
+        Ok(acceptor) => {
+            let acceptor = Arc::new(acceptor);
+            Arc::clone(&acceptor).watch();
+            acceptor
+        }
+        Err(e) => {
+            eprintln!("Failed to configure TLS for RLS: {e}");
+            process::exit(1)
+        }
+    });
+
+    // Holds the deadline to drain the servers by, once shutting down
+    let (shutdown, shutting_down) = watch::channel(None);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!(
+            "Shutting down, draining in-flight requests for up to {:?}",
+            drain_timeout
+        );
+        let _ = shutdown.send(Some(Instant::now() + drain_timeout));
+    });
+
     info!("Envoy RLS server starting on {}", envoy_rls_address);
-    tokio::spawn(run_envoy_rls_server(
+    let rls_server = tokio::spawn(run_envoy_rls_server(
         envoy_rls_address.to_string(),
         rate_limiter.clone(),
         rate_limit_headers,
         prometheus_metrics.clone(),
+        over_limit_behaviors.clone(),
+        descriptor_mapping,
+        idempotency_header,
         grpc_reflection_service,
+        rls_tls.clone(),
+        shut_down(shutting_down.clone()),
     ));
 
+    let authenticator = match http_auth {
+        None => None,
+        Some(cfg) => match Authenticator::new(cfg).await {
+            Ok(authenticator) => Some(Arc::new(authenticator)),
+            Err(e) => {
+                eprintln!("Failed to configure HTTP authentication: {e}");
+                process::exit(1)
+            }
+        },
+    };
+
+    // Shares the authentication of the HTTP API, and the TLS of RLS
+    let admin_grpc_server = admin_grpc_address.map(|admin_grpc_address| {
+        info!("Admin gRPC server starting on {}", admin_grpc_address);
+        let served = run_admin_grpc_server(
+            admin_grpc_address,
+            rate_limiter.clone(),
+            grpc_reflection_service,
+            authenticator.clone(),
+            rls_tls.clone(),
+            shut_down(shutting_down.clone()),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = served.await {
+                error!("Admin gRPC server failed: {}", e);
+            }
+        })
+    });
+
+    let limited_response = match LimitedResponse::new(http_over_limit) {
+        Ok(limited_response) => limited_response,
+        Err(e) => {
+            eprintln!("Failed to configure the HTTP over-limit response: {e}");
+            process::exit(1)
+        }
+    };
+
     info!("HTTP server starting on {}", http_api_address);
-    run_http_server(&http_api_address, rate_limiter.clone(), prometheus_metrics).await?;
+    run_http_server(
+        &http_api_address,
+        rate_limiter.clone(),
+        prometheus_metrics,
+        over_limit_behaviors,
+        limited_response,
+        authenticator,
+        drain_timeout,
+        shut_down(shutting_down.clone()),
+    )
+    .await?;
+
+    // The HTTP server only stops once shutting down, and the others drain
+    // within the same deadline
+    let deadline = (*shutting_down.borrow()).unwrap_or_else(|| Instant::now() + drain_timeout);
+    let drained = tokio::time::timeout_at(deadline, async {
+        let _ = rls_server.await;
+        if let Some(admin_grpc_server) = admin_grpc_server {
+            let _ = admin_grpc_server.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!("Timed out draining the in-flight requests");
+    }
+
+    // Whatever is left of the deadline goes to flushing the counters
+    match tokio::time::timeout_at(deadline, rate_limiter.flush()).await {
+        Ok(Ok(())) => info!("Flushed counters"),
+        Ok(Err(e)) => error!("Failed to flush counters: {}", e),
+        Err(_) => error!("Timed out flushing counters, some updates are lost"),
+    }
+
+    // Pushes the metrics one last time
+    if let Some(provider) = metrics_provider {
+        if let Err(e) = provider.shutdown() {
+            error!("Failed to push the metrics: {}", e);
+        }
+    }
+    if let Some(flusher) = statsd_flusher {
+        flusher.flush();
+    }
 
     Ok(())
 }
 
+// Resolves once shutting down
+async fn shut_down(mut shutting_down: watch::Receiver<Option<Instant>>) {
+    let _ = shutting_down.wait_for(Option::is_some).await;
+}
+
 fn create_config() -> (Configuration, &'static str) {
     let full_version: &'static str = formatcp!(
         "v{} ({}) {} {}",
@@ -344,7 +1033,14 @@ fn create_config() -> (Configuration, &'static str) {
         .help("The limit file to use")
         .index(1);
     let limit_arg = match *config::env::LIMITS_FILE {
-        None => limit_arg.required(true),
+        #[cfg(feature = "kubernetes")]
+        None => limit_arg.required_unless_present_any([
+            "limits_dir",
+            "limits_crd",
+            "limits_discovery_server",
+        ]),
+        #[cfg(not(feature = "kubernetes"))]
+        None => limit_arg.required_unless_present_any(["limits_dir", "limits_discovery_server"]),
         Some(file) => limit_arg.default_value(file),
     };
 
@@ -354,6 +1050,89 @@ fn create_config() -> (Configuration, &'static str) {
         Some(url) => redis_url_arg.default_value(url),
     };
 
+    let redis_fallback_arg = Arg::new("fallback")
+        .long("in-memory-fallback")
+        .action(ArgAction::SetTrue)
+        .display_order(9)
+        .help("Falls back to counters held in memory while Redis is unreachable");
+
+    let redis_breaker_args = [
+        Arg::new("breaker_failures")
+            .long("circuit-breaker")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32).range(1..))
+            .conflicts_with("fallback")
+            .display_order(20)
+            .help("Stops calling Redis after that many consecutive failures"),
+        Arg::new("breaker_reset")
+            .long("circuit-breaker-reset")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u64))
+            .default_value(leak(DEFAULT_RESET_TIMEOUT_SEC * 1000))
+            .display_order(21)
+            .help("Time after which Redis is called again, once the circuit breaker opened, in milliseconds"),
+        Arg::new("breaker_decision")
+            .long("circuit-breaker-decision")
+            .action(ArgAction::Set)
+            .default_value("deny")
+            .value_parser(clap::builder::PossibleValuesParser::new(["allow", "deny"]))
+            .display_order(22)
+            .help("Decision made on requests while the circuit breaker is open"),
+    ];
+
+    let redis_connection_args = [
+        with_env_default(
+            Arg::new("username")
+                .long("username")
+                .action(ArgAction::Set)
+                .display_order(10)
+                .help("Username to authenticate to Redis with, using ACLs"),
+            *config::env::REDIS_USERNAME,
+        ),
+        with_env_default(
+            Arg::new("password")
+                .long("password")
+                .action(ArgAction::Set)
+                .hide_default_value(true)
+                .display_order(11)
+                .help("Password to authenticate to Redis with"),
+            *config::env::REDIS_PASSWORD,
+        ),
+        with_env_default(
+            Arg::new("ca_cert")
+                .long("ca-cert")
+                .action(ArgAction::Set)
+                .display_order(12)
+                .help("PEM file of the CA to verify the Redis server with, when using rediss://"),
+            *config::env::REDIS_CA_CERT,
+        ),
+        with_env_default(
+            Arg::new("client_cert")
+                .long("client-cert")
+                .action(ArgAction::Set)
+                .display_order(13)
+                .help("PEM file of the client certificate for mutual TLS"),
+            *config::env::REDIS_CLIENT_CERT,
+        ),
+        with_env_default(
+            Arg::new("client_key")
+                .long("client-key")
+                .action(ArgAction::Set)
+                .display_order(14)
+                .help("PEM file of the key of the client certificate"),
+            *config::env::REDIS_CLIENT_KEY,
+        ),
+        with_env_default(
+            Arg::new("connect_timeout")
+                .long("connect-timeout")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .display_order(15)
+                .help("Timeout for connecting to Redis in milliseconds"),
+            *config::env::REDIS_CONNECTION_TIMEOUT_MS,
+        ),
+    ];
+
     let disk_path_arg = Arg::new("PATH").help("Path to counter DB").index(1);
     let disk_path_arg = match *config::env::DISK_PATH {
         None => disk_path_arg.required(true),
@@ -426,6 +1205,26 @@ fn create_config() -> (Configuration, &'static str) {
                 .display_order(6)
                 .help("The host for the tracing service"),
         )
+        .arg(with_env_default(
+            Arg::new("tracing_headers")
+                .long("tracing-headers")
+                .action(ArgAction::Set)
+                .display_order(37)
+                .help("Comma separated KEY=VALUE headers to send the traces with, e.g. to authenticate"),
+            *config::env::TRACING_HEADERS,
+        ))
+        .arg(
+            Arg::new("tracing_sampling_ratio")
+                .long("tracing-sampling-ratio")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(f64))
+                .default_value(
+                    config::env::TRACING_SAMPLING_RATIO
+                        .unwrap_or(leak(Configuration::DEFAULT_TRACING_SAMPLING_RATIO)),
+                )
+                .display_order(38)
+                .help("Ratio of the traces to sample, from 0 to 1, unless their parent was"),
+        )
         .arg(
             Arg::new("v")
                 .short('v')
@@ -450,6 +1249,7 @@ fn create_config() -> (Configuration, &'static str) {
                 .value_parser(clap::builder::PossibleValuesParser::new([
                     "NONE",
                     "DRAFT_VERSION_03",
+                    "DRAFT_IETF",
                 ]))
                 .help("Enables rate limit response headers"),
         )
@@ -460,6 +1260,322 @@ fn create_config() -> (Configuration, &'static str) {
                 .display_order(10)
                 .help("Enables gRPC server reflection service"),
         )
+        .arg(
+            Arg::new("admin_grpc_port")
+                .long("admin-grpc-port")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u16))
+                .display_order(11)
+                .help("Enables the admin gRPC API, managing the limits, on this port"),
+        )
+        .arg(with_env_default(
+            Arg::new("limits_dir")
+                .long("limits-dir")
+                .action(ArgAction::Set)
+                .display_order(12)
+                .help("Directory of limits files, e.g. one per namespace, to use instead of LIMITS_FILE"),
+            *config::env::LIMITS_DIR,
+        ))
+        .arg(with_env_default(
+            Arg::new("rls_tls_cert")
+                .long("rls-tls-cert")
+                .action(ArgAction::Set)
+                .display_order(13)
+                .help("PEM file of the certificate to serve RLS over TLS with"),
+            *config::env::RLS_TLS_CERT,
+        ))
+        .arg(with_env_default(
+            Arg::new("rls_tls_key")
+                .long("rls-tls-key")
+                .action(ArgAction::Set)
+                .display_order(14)
+                .help("PEM file of the key of the RLS certificate"),
+            *config::env::RLS_TLS_KEY,
+        ))
+        .arg(with_env_default(
+            Arg::new("rls_tls_client_ca")
+                .long("rls-tls-client-ca")
+                .action(ArgAction::Set)
+                .display_order(15)
+                .help("PEM file of the CA RLS clients' certificates must be signed by, for mutual TLS"),
+            *config::env::RLS_TLS_CLIENT_CA,
+        ))
+        .arg(with_env_default(
+            Arg::new("http_auth_tokens")
+                .long("http-auth-tokens")
+                .action(ArgAction::Set)
+                .display_order(17)
+                .help("YAML file of the bearer tokens, and their role, the HTTP limits and counters endpoints require"),
+            *config::env::HTTP_AUTH_TOKENS_FILE,
+        ))
+        .arg(with_env_default(
+            Arg::new("http_auth_oidc_issuer")
+                .long("http-auth-oidc-issuer")
+                .action(ArgAction::Set)
+                .display_order(18)
+                .help("OIDC issuer whose JWTs are accepted as bearer tokens by the HTTP limits and counters endpoints"),
+            *config::env::HTTP_AUTH_OIDC_ISSUER,
+        ))
+        .arg(with_env_default(
+            Arg::new("http_auth_oidc_audience")
+                .long("http-auth-oidc-audience")
+                .action(ArgAction::Set)
+                .display_order(19)
+                .help("Audience the JWTs must be issued for"),
+            *config::env::HTTP_AUTH_OIDC_AUDIENCE,
+        ))
+        .arg(
+            Arg::new("http_auth_oidc_roles_claim")
+                .long("http-auth-oidc-roles-claim")
+                .action(ArgAction::Set)
+                .default_value(config::env::HTTP_AUTH_OIDC_ROLES_CLAIM.unwrap_or("roles"))
+                .display_order(20)
+                .help("Dot separated path to the claim holding the roles of the JWTs"),
+        )
+        .arg(
+            Arg::new("http_auth_oidc_admin_role")
+                .long("http-auth-oidc-admin-role")
+                .action(ArgAction::Set)
+                .default_value(
+                    config::env::HTTP_AUTH_OIDC_ADMIN_ROLE.unwrap_or("limitador-admin"),
+                )
+                .display_order(21)
+                .help("Role granting admin access"),
+        )
+        .arg(
+            Arg::new("http_auth_oidc_read_only_role")
+                .long("http-auth-oidc-read-only-role")
+                .action(ArgAction::Set)
+                .default_value(
+                    config::env::HTTP_AUTH_OIDC_READ_ONLY_ROLE.unwrap_or("limitador-viewer"),
+                )
+                .display_order(22)
+                .help("Role granting read-only access"),
+        )
+        .arg(
+            Arg::new("rls_tls_allowed_san")
+                .long("rls-tls-allowed-san")
+                .action(ArgAction::Append)
+                .display_order(16)
+                .help("A DNS name, URI or IP address RLS clients' certificates may have as SAN, any if not set"),
+        )
+        .arg(
+            Arg::new("drain_timeout")
+                .long("drain-timeout")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64))
+                .default_value(
+                    config::env::DRAIN_TIMEOUT_SEC
+                        .unwrap_or(leak(Configuration::DEFAULT_DRAIN_TIMEOUT_SEC)),
+                )
+                .display_order(25)
+                .help("Time to complete the in-flight requests and flush the counters within, on shutdown, in seconds"),
+        )
+        .arg(with_env_default(
+            Arg::new("limits_discovery_server")
+                .long("limits-discovery-server")
+                .action(ArgAction::Set)
+                .conflicts_with("limits_dir")
+                .display_order(26)
+                .help("URL of the management server to stream the limits from, instead of LIMITS_FILE"),
+            *config::env::LIMITS_DISCOVERY_SERVER,
+        ))
+        .arg(with_env_default(
+            Arg::new("limits_discovery_node_id")
+                .long("limits-discovery-node-id")
+                .action(ArgAction::Set)
+                .display_order(27)
+                .help("Identifies this instance to the management server, defaults to the HOSTNAME"),
+            *config::env::LIMITS_DISCOVERY_NODE_ID,
+        ))
+        .arg(with_env_default(
+            Arg::new("over_limit_behaviors")
+                .long("over-limit-behaviors")
+                .action(ArgAction::Set)
+                .display_order(28)
+                .help("YAML file of how each namespace wants requests over its limits, or hitting storage errors, answered"),
+            *config::env::OVER_LIMIT_BEHAVIORS_FILE,
+        ))
+        .arg(
+            Arg::new("http_over_limit_status")
+                .long("http-over-limit-status")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u16).range(400..600))
+                .default_value(
+                    config::env::HTTP_OVER_LIMIT_STATUS
+                        .unwrap_or(leak(HttpOverLimitConfiguration::DEFAULT_STATUS)),
+                )
+                .display_order(29)
+                .help("Status of the HTTP API's responses to the requests it denies, e.g. 403"),
+        )
+        .arg(
+            Arg::new("http_over_limit_retry_after")
+                .long("http-over-limit-retry-after")
+                .action(ArgAction::SetTrue)
+                .display_order(30)
+                .help("Sets the Retry-After header on the HTTP API's responses to the requests it denies"),
+        )
+        .arg(with_env_default(
+            Arg::new("http_over_limit_body")
+                .long("http-over-limit-body")
+                .action(ArgAction::Set)
+                .display_order(31)
+                .help("JSON template of the body of the HTTP API's responses to the requests it denies"),
+            *config::env::HTTP_OVER_LIMIT_BODY,
+        ))
+        .arg(with_env_default(
+            Arg::new("descriptor_mapping")
+                .long("descriptor-mapping")
+                .action(ArgAction::Set)
+                .display_order(32)
+                .help("YAML file of rules mapping the descriptors of RLS requests to namespaces and variables"),
+            *config::env::DESCRIPTOR_MAPPING_FILE,
+        ))
+        .arg(
+            Arg::new("limit_metrics")
+                .long("limit-metrics")
+                .action(ArgAction::SetTrue)
+                .display_order(33)
+                .help("Exports the hits checked against, and requests limited by, each limit"),
+        )
+        .arg(
+            Arg::new("limit_id_in_labels")
+                .long("limit-id-in-labels")
+                .action(ArgAction::SetTrue)
+                .display_order(34)
+                .help("Include the Limit Id in the labels of the per-limit metrics"),
+        )
+        .arg(
+            Arg::new("limit_metrics_variable")
+                .long("limit-metrics-variable")
+                .action(ArgAction::Append)
+                .display_order(35)
+                .help("A variable of the counters, as [LABEL=]VARIABLE, to label the per-limit metrics with"),
+        )
+        .arg(
+            Arg::new("limit_metrics_metadata")
+                .long("limit-metrics-metadata")
+                .action(ArgAction::Append)
+                .display_order(35)
+                .help("A key of the metadata of the limits, as [LABEL=]KEY, to label the per-limit metrics with"),
+        )
+        .arg(with_env_default(
+            Arg::new("latency_buckets")
+                .long("latency-buckets")
+                .action(ArgAction::Set)
+                .display_order(36)
+                .help("Comma separated buckets of the latency histograms, in seconds"),
+            *config::env::LATENCY_HISTOGRAM_BUCKETS,
+        ))
+        .arg(
+            Arg::new("metrics_export")
+                .long("metrics-export")
+                .action(ArgAction::Set)
+                .value_parser(["pull", "push", "both"])
+                .default_value(config::env::METRICS_EXPORT.unwrap_or("pull"))
+                .display_order(39)
+                .help("Whether the metrics are scraped on /metrics, pushed to an OTLP collector, or both"),
+        )
+        .arg(with_env_default(
+            Arg::new("metrics_otlp_endpoint")
+                .long("metrics-otlp-endpoint")
+                .action(ArgAction::Set)
+                .display_order(40)
+                .help("The OTLP collector to push the metrics to"),
+            *config::env::METRICS_OTLP_ENDPOINT,
+        ))
+        .arg(
+            Arg::new("metrics_otlp_interval")
+                .long("metrics-otlp-interval")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value(
+                    config::env::METRICS_OTLP_INTERVAL_SEC
+                        .unwrap_or(leak(MetricsPushConfiguration::DEFAULT_INTERVAL_SEC)),
+                )
+                .display_order(41)
+                .help("How often to push the metrics, in seconds"),
+        )
+        .arg(with_env_default(
+            Arg::new("metrics_max_series")
+                .long("metrics-max-series")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(usize))
+                .display_order(42)
+                .help("Maximum series of each metric labeled by namespace, limit or variable, the others recorded as 'other'"),
+            *config::env::METRICS_MAX_SERIES,
+        ))
+        .arg(with_env_default(
+            Arg::new("hottest_counters")
+                .long("hottest-counters")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64).range(1..))
+                .display_order(43)
+                .help("Exports gauges of the used and remaining hits of that many of the hottest counters of each namespace"),
+            *config::env::HOTTEST_COUNTERS,
+        ))
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .action(ArgAction::Set)
+                .value_parser(["text", "json"])
+                .default_value(config::env::LOG_FORMAT.unwrap_or("text"))
+                .display_order(44)
+                .help("The format of the logs, either human readable or a JSON object per line"),
+        )
+        .arg(with_env_default(
+            Arg::new("audit_log")
+                .long("audit-log")
+                .action(ArgAction::Set)
+                .value_name("FILE|syslog")
+                .display_order(45)
+                .help("Records the changes made to the limits to that file, or to syslog"),
+            *config::env::AUDIT_LOG,
+        ))
+        .arg(with_env_default(
+            Arg::new("statsd_address")
+                .long("statsd-address")
+                .action(ArgAction::Set)
+                .display_order(46)
+                .help("The StatsD server, as host:port, to send the metrics to"),
+            *config::env::STATSD_ADDRESS,
+        ))
+        .arg(
+            Arg::new("statsd_flush_interval")
+                .long("statsd-flush-interval")
+                .action(ArgAction::Set)
+                .value_parser(value_parser!(u64).range(1..))
+                .default_value(
+                    config::env::STATSD_FLUSH_INTERVAL_SEC
+                        .unwrap_or(leak(StatsdConfiguration::DEFAULT_FLUSH_INTERVAL_SEC)),
+                )
+                .display_order(47)
+                .help("How often to send the metrics to StatsD, in seconds"),
+        )
+        .arg(with_env_default(
+            Arg::new("statsd_tags")
+                .long("statsd-tags")
+                .action(ArgAction::Set)
+                .display_order(48)
+                .help("Comma separated KEY:VALUE tags to send every metric with"),
+            *config::env::STATSD_TAGS,
+        ))
+        .arg(
+            Arg::new("lenient_conditions")
+                .long("lenient-conditions")
+                .action(ArgAction::SetTrue)
+                .display_order(49)
+                .help("Deprecated: only has the CEL parser check the conditions of the limits, as it used to"),
+        )
+        .arg(with_env_default(
+            Arg::new("idempotency_header")
+                .long("idempotency-header")
+                .action(ArgAction::Set)
+                .value_name("HEADER")
+                .display_order(50)
+                .help("Metadata of the RLS requests identifying them across retries, for these to be accounted for once"),
+            *config::env::IDEMPOTENCY_HEADER,
+        ))
         .subcommand(
             Command::new("memory")
                 .display_order(1)
@@ -472,6 +1588,48 @@ fn create_config() -> (Configuration, &'static str) {
                         .value_parser(value_parser!(u64))
                         .display_order(1)
                         .help("Sets the size of the cache for 'qualified counters'"),
+                )
+                .arg(
+                    Arg::new("SNAPSHOT")
+                        .long("snapshot")
+                        .action(ArgAction::Set)
+                        .display_order(2)
+                        .help("File to save the counters to on shutdown, and restore them from on start"),
+                )
+                .arg(
+                    Arg::new("CONCURRENT")
+                        .long("concurrent")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("SNAPSHOT")
+                        .display_order(3)
+                        .help("Holds each counter in its own atomic, for checks to scale with the threads"),
+                )
+                .arg(
+                    Arg::new("SWEEP_INTERVAL")
+                        .long("sweep-interval")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(u64).range(1..))
+                        .conflicts_with("CONCURRENT")
+                        .display_order(4)
+                        .help("Interval, in milliseconds, to drop the counters whose window is over at"),
+                )
+                .arg(
+                    Arg::new("CACHE_MAX_BYTES")
+                        .long("cache-max-bytes")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(u64).range(1..))
+                        .conflicts_with_all(["CACHE_SIZE", "CONCURRENT"])
+                        .display_order(5)
+                        .help("Bounds the cache for 'qualified counters' by their size in bytes, rather than their count"),
+                )
+                .arg(
+                    Arg::new("IDLE_TIMEOUT")
+                        .long("idle-timeout")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(u64).range(1..))
+                        .conflicts_with("CONCURRENT")
+                        .display_order(6)
+                        .help("Time, in milliseconds, after which the 'qualified counters' not hit get evicted"),
                 ),
         )
         .subcommand(
@@ -496,13 +1654,64 @@ fn create_config() -> (Configuration, &'static str) {
             Command::new("redis")
                 .display_order(3)
                 .about("Uses Redis to store counters")
-                .arg(redis_url_arg.clone()),
+                .arg(redis_url_arg.clone())
+                .arg(with_env_default(
+                    Arg::new("timeout")
+                        .long("response-timeout")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64))
+                        .display_order(6)
+                        .help("Timeout for Redis commands in milliseconds"),
+                    *config::env::REDIS_RESPONSE_TIMEOUT_MS,
+                ))
+                .arg(with_env_default(
+                    Arg::new("pool_size")
+                        .long("pool-size")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(usize))
+                        .display_order(7)
+                        .help("Number of connections to Redis to use concurrently"),
+                    *config::env::REDIS_POOL_SIZE,
+                ))
+                .arg(with_env_default(
+                    Arg::new("replicas")
+                        .long("replica")
+                        .action(ArgAction::Append)
+                        .value_delimiter(',')
+                        .display_order(8)
+                        .help("URL of a read replica to read counters from, can be repeated"),
+                    *config::env::REDIS_REPLICAS,
+                ))
+                .arg(
+                    Arg::new("strict")
+                        .long("strict")
+                        .action(ArgAction::SetTrue)
+                        .display_order(8)
+                        .help("Checks and updates counters atomically, for accuracy over throughput"),
+                )
+                .arg(
+                    Arg::new("hashed_keys")
+                        .long("hashed-keys")
+                        .action(ArgAction::SetTrue)
+                        .display_order(8)
+                        .help("Hashes the limits in the keys of the counters, to save memory"),
+                )
+                .arg(
+                    Arg::new("migrate_keys")
+                        .long("migrate-keys")
+                        .action(ArgAction::SetTrue)
+                        .display_order(8)
+                        .help("Migrates the counters keyed the other way, as they get accessed"),
+                )
+                .arg(redis_fallback_arg.clone())
+                .args(redis_breaker_args.clone())
+                .args(redis_connection_args.clone()),
         )
         .subcommand(
             Command::new("redis_cached")
                 .about("Uses Redis to store counters, with an in-memory cache")
                 .display_order(4)
-                .arg(redis_url_arg)
+                .arg(redis_url_arg.clone())
                 .arg(
                     Arg::new("batch")
                         .long("batch-size")
@@ -532,19 +1741,131 @@ fn create_config() -> (Configuration, &'static str) {
                         .long("max-cached")
                         .action(ArgAction::Set)
                         .value_parser(clap::value_parser!(usize))
-                        .default_value(leak(DEFAULT_MAX_CACHED_COUNTERS))
+                        .default_value(
+                            config::env::REDIS_LOCAL_CACHE_MAX_CACHED_COUNTERS
+                                .unwrap_or(leak(DEFAULT_MAX_CACHED_COUNTERS)),
+                        )
                         .display_order(5)
                         .help("Maximum amount of counters cached"),
                 )
+                .arg(with_env_default(
+                    Arg::new("staleness")
+                        .long("max-staleness")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64))
+                        .display_order(6)
+                        .help("Age after which cached counters get refreshed from Redis in milliseconds"),
+                    *config::env::REDIS_LOCAL_CACHE_MAX_STALENESS_MS,
+                ))
+                .arg(with_env_default(
+                    Arg::new("updates_channel")
+                        .long("updates-channel")
+                        .action(ArgAction::Set)
+                        .display_order(6)
+                        .help("Pub/sub channel the instances share the counter values they flush on"),
+                    *config::env::REDIS_LOCAL_CACHE_UPDATES_CHANNEL,
+                ))
+                .arg(with_env_default(
+                    Arg::new("max_flush")
+                        .long("max-flush-period")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64))
+                        .display_order(6)
+                        .help("Flushing period to back off up to, in smaller batches, while Redis is slow or erring, in milliseconds"),
+                    *config::env::REDIS_LOCAL_CACHE_MAX_FLUSHING_PERIOD_MS,
+                ))
                 .arg(
                     Arg::new("timeout")
                         .long("response-timeout")
                         .action(ArgAction::Set)
                         .value_parser(clap::value_parser!(u64))
-                        .default_value(leak(DEFAULT_RESPONSE_TIMEOUT_MS))
+                        .default_value(
+                            config::env::REDIS_RESPONSE_TIMEOUT_MS
+                                .unwrap_or(leak(DEFAULT_RESPONSE_TIMEOUT_MS)),
+                        )
                         .display_order(6)
                         .help("Timeout for Redis commands in milliseconds"),
-                ),
+                )
+                .arg(redis_fallback_arg)
+                .args(redis_breaker_args)
+                .args(redis_connection_args.clone()),
+        )
+        .subcommand(
+            Command::new("redis_write_behind")
+                .about("Counters are held in Limitador, and backed up to Redis in the background")
+                .display_order(5)
+                .arg(redis_url_arg.clone())
+                .arg(
+                    Arg::new("flush")
+                        .long("flush-period")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value(leak(DEFAULT_FLUSHING_PERIOD_SEC * 1000))
+                        .display_order(3)
+                        .help("Period at which counter updates are written to Redis in milliseconds"),
+                )
+                .arg(
+                    Arg::new("max")
+                        .long("max-cached")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value(leak(DEFAULT_MAX_CACHED_COUNTERS))
+                        .display_order(4)
+                        .help("Maximum amount of counters held in memory"),
+                )
+                .args(redis_connection_args.clone()),
+        )
+        .subcommand(
+            Command::new("redis_broadcast")
+                .about("Counters are held in Limitador, the instances sharing their updates through Redis pub/sub")
+                .display_order(5)
+                .arg(redis_url_arg)
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .action(ArgAction::Set)
+                        .default_value(DEFAULT_BROADCAST_CHANNEL)
+                        .display_order(3)
+                        .help("Pub/sub channel the instances publish their counter updates on"),
+                )
+                .arg(
+                    Arg::new("period")
+                        .long("broadcast-period")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value(leak(DEFAULT_BROADCAST_PERIOD_MS))
+                        .display_order(4)
+                        .help("Period at which counter updates are published in milliseconds"),
+                )
+                .arg(
+                    Arg::new("max")
+                        .long("max-cached")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value(leak(DEFAULT_MAX_CACHED_COUNTERS))
+                        .display_order(5)
+                        .help("Maximum amount of counters held in memory"),
+                )
+                .args(redis_connection_args),
+        );
+
+    #[cfg(feature = "kubernetes")]
+    let cmdline = cmdline
+        .arg(
+            Arg::new("limits_crd")
+                .long("limits-crd")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("limits_discovery_server")
+                .display_order(23)
+                .help("Syncs the limits from the RateLimit resources, instead of LIMITS_FILE"),
+        )
+        .arg(
+            Arg::new("limits_crd_namespace")
+                .long("limits-crd-namespace")
+                .action(ArgAction::Set)
+                .requires("limits_crd")
+                .display_order(24)
+                .help("Only watches the RateLimit resources of this Kubernetes namespace"),
         );
 
     #[cfg(feature = "distributed_storage")]
@@ -571,7 +1892,7 @@ fn create_config() -> (Configuration, &'static str) {
                     .action(ArgAction::Append)
                     .required(false)
                     .display_order(3)
-                    .help("A replication peer url that this instance will connect to"),
+                    .help("A replication peer url to join the cluster through, the others being discovered"),
             )
             .arg(
                 Arg::new("CACHE_SIZE")
@@ -581,12 +1902,247 @@ fn create_config() -> (Configuration, &'static str) {
                     .value_parser(value_parser!(u64))
                     .display_order(4)
                     .help("Sets the size of the cache for 'qualified counters'"),
+            )
+            .arg(
+                Arg::new("SYNC_TIMEOUT")
+                    .long("sync-timeout")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(u64))
+                    .default_value(leak(DEFAULT_DISTRIBUTED_SYNC_TIMEOUT_MS))
+                    .display_order(5)
+                    .help("Time to wait for a peer's counters before serving traffic, in milliseconds"),
+            )
+            .args(peer_auth_args(6, *config::env::DISTRIBUTED_TOKEN))
+            .arg(
+                Arg::new("DISCOVER")
+                    .long("discover")
+                    .action(ArgAction::Set)
+                    .display_order(11)
+                    .help("HOST:PORT to periodically resolve the peers at, e.g. of a headless service"),
+            )
+            .arg(
+                Arg::new("DISCOVERY_PERIOD")
+                    .long("discovery-period")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(u64).range(1..))
+                    .default_value(leak(DEFAULT_DISCOVERY_PERIOD_MS))
+                    .display_order(12)
+                    .help("Period at which the peers get resolved, in milliseconds"),
+            )
+            .arg(
+                Arg::new("BATCH_SIZE")
+                    .long("batch-size")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(usize))
+                    .default_value(leak(DEFAULT_MAX_BATCH_SIZE))
+                    .display_order(13)
+                    .help("Maximum number of counter updates sent to a peer in a single frame"),
+            )
+            .arg(
+                Arg::new("BATCH_DELAY")
+                    .long("batch-delay")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(u64))
+                    .default_value(leak(DEFAULT_MAX_BATCH_DELAY_MS))
+                    .display_order(14)
+                    .help("Maximum time a counter update waits for others to batch with, in milliseconds"),
+            )
+            .arg(
+                Arg::new("COMPRESSION")
+                    .long("compression")
+                    .action(ArgAction::Set)
+                    .value_parser(["lz4", "zstd"])
+                    .display_order(15)
+                    .help("Compresses the frames of counter updates sent to the peers"),
+            )
+            .arg(
+                Arg::new("COMPRESSION_THRESHOLD")
+                    .long("compression-threshold")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(usize))
+                    .default_value(leak(DEFAULT_COMPRESSION_THRESHOLD))
+                    .display_order(16)
+                    .help("Size, in bytes, past which frames get compressed"),
+            )
+            .arg(
+                Arg::new("CONFLICT_RESOLUTION")
+                    .long("conflict-resolution")
+                    .action(ArgAction::Set)
+                    .value_parser(["sum", "max-wins", "share", "regional"])
+                    .default_value("sum")
+                    .display_order(17)
+                    .help("How the hits counted by the peers add up, when checking the limits"),
+            )
+            .arg(
+                Arg::new("SHARE")
+                    .long("share")
+                    .action(ArgAction::Set)
+                    .value_parser(parse_share)
+                    .required_if_eq("CONFLICT_RESOLUTION", "share")
+                    .display_order(18)
+                    .help("Share of the limits this instance admits on its own, between 0 and 1"),
+            )
+            .arg(
+                Arg::new("REGION")
+                    .long("region")
+                    .action(ArgAction::Set)
+                    .required_if_eq("CONFLICT_RESOLUTION", "regional")
+                    .display_order(19)
+                    .help("Region this instance admits the share of the limits of"),
+            )
+            .arg(
+                Arg::new("REGION_SHARES")
+                    .long("region-share")
+                    .action(ArgAction::Append)
+                    .value_parser(parse_region_share)
+                    .required_if_eq("CONFLICT_RESOLUTION", "regional")
+                    .display_order(20)
+                    .help("Share of the limits of a region, as REGION=SHARE, e.g. 'eu=0.5'"),
+            )
+            .arg(
+                Arg::new("REBALANCE_PERIOD")
+                    .long("rebalance-period")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(u64).range(1..))
+                    .display_order(21)
+                    .help("Period, in milliseconds, to rebalance the shares of the regions at, as of their traffic"),
+            )
+            .arg(
+                Arg::new("MIN_SHARE")
+                    .long("min-share")
+                    .action(ArgAction::Set)
+                    .value_parser(parse_share)
+                    .default_value(leak(DEFAULT_MIN_REGION_SHARE))
+                    .display_order(22)
+                    .help("Share of the limits regions keep, when rebalancing, however little traffic they get"),
+            ),
+    );
+
+    #[cfg(feature = "sqlite_storage")]
+    let cmdline = cmdline.subcommand(
+        Command::new("sqlite")
+            .about("Counters are held in an embedded SQLite database (persistent)")
+            .display_order(6)
+            .arg(
+                Arg::new("PATH")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .display_order(1)
+                    .help("Path to the SQLite database file"),
+            ),
+    );
+
+    #[cfg(feature = "etcd_storage")]
+    let cmdline = cmdline.subcommand(
+        Command::new("etcd")
+            .about("Counters are held in an etcd cluster (persistent)")
+            .display_order(7)
+            .arg(
+                Arg::new("ENDPOINTS")
+                    .action(ArgAction::Append)
+                    .required(true)
+                    .display_order(1)
+                    .help("The etcd endpoints to connect to, e.g. 'localhost:2379'"),
+            ),
+    );
+
+    #[cfg(feature = "raft_storage")]
+    let cmdline = cmdline.subcommand(
+        Command::new("raft")
+            .about("Counters are replicated with Raft, never over-admitting (persistent)")
+            .display_order(8)
+            .arg(
+                Arg::new("NAME")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .display_order(1)
+                    .help("Unique name to identify this replica"),
+            )
+            .arg(
+                Arg::new("LISTEN_ADDRESS")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .display_order(2)
+                    .help("Local IP:PORT to listen on for the other replicas"),
+            )
+            .arg(
+                Arg::new("PATH")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .display_order(3)
+                    .help("Directory to persist the replica's log to"),
+            )
+            .arg(
+                Arg::new("PEERS")
+                    .action(ArgAction::Append)
+                    .value_parser(parse_named_url)
+                    .display_order(4)
+                    .help("The other replicas, as NAME=URL, e.g. 'b=http://limitador-b:15002'"),
+            )
+            .args(peer_auth_args(5, *config::env::RAFT_TOKEN)),
+    );
+
+    #[cfg(feature = "partitioned_storage")]
+    let cmdline = cmdline.subcommand(
+        Command::new("partitioned")
+            .about("Counters are spread across Limitador servers, each owning a share of them")
+            .display_order(9)
+            .arg(
+                Arg::new("NAME")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .display_order(1)
+                    .help("Unique name to identify this member"),
+            )
+            .arg(
+                Arg::new("LISTEN_ADDRESS")
+                    .action(ArgAction::Set)
+                    .required(true)
+                    .display_order(2)
+                    .help("Local IP:PORT to listen on for the other members"),
+            )
+            .arg(
+                Arg::new("PEERS")
+                    .action(ArgAction::Append)
+                    .value_parser(parse_named_url)
+                    .display_order(3)
+                    .help("The other members, as NAME=URL, e.g. 'b=http://limitador-b:15003'"),
+            )
+            .arg(
+                Arg::new("CACHE_SIZE")
+                    .long("cache")
+                    .short('c')
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(u64))
+                    .display_order(4)
+                    .help("Sets the size of the cache for the counters this member owns"),
             ),
     );
 
+    // Neither a LIMITS_FILE nor a storage are needed to validate limits, so
+    // the subcommand gets parsed on its own
+    let validate_cmd = Command::new("validate")
+        .display_order(0)
+        .about("Validates limits files, or directories of them, and exits")
+        .arg(
+            Arg::new("PATHS")
+                .action(ArgAction::Append)
+                .required(true)
+                .help("The limits files, or directories of them, to validate"),
+        );
+    let cmdline = cmdline.subcommand(validate_cmd.clone());
+    if env::args().nth(1).as_deref() == Some("validate") {
+        let matches = validate_cmd.get_matches_from(env::args().skip(1));
+        let paths: Vec<&String> = matches.get_many::<String>("PATHS").unwrap().collect();
+        process::exit(validate::run(&paths));
+    }
+
     let matches = cmdline.get_matches();
 
-    let limits_file = matches.get_one::<String>("LIMITS_FILE").unwrap();
+    let limits_file = matches
+        .get_one::<String>("LIMITS_FILE")
+        .map(String::as_str)
+        .unwrap_or_default();
 
     if matches.get_flag("validate") {
         let error = match std::fs::File::open(limits_file) {
@@ -621,6 +2177,22 @@ fn create_config() -> (Configuration, &'static str) {
         Some(("redis", sub)) => StorageConfiguration::Redis(RedisStorageConfiguration {
             url: sub.get_one::<String>("URL").unwrap().to_owned(),
             cache: None,
+            write_behind: None,
+            broadcast: None,
+            in_memory_fallback: sub.get_flag("fallback"),
+            strict: sub.get_flag("strict"),
+            hashed_keys: sub.get_flag("hashed_keys"),
+            migrate_keys: sub.get_flag("migrate_keys"),
+            circuit_breaker: circuit_breaker_config_from(sub),
+            connection: RedisConnectionConfiguration {
+                response_timeout: sub.get_one("timeout").copied(),
+                pool_size: sub.get_one("pool_size").copied(),
+                replicas: sub
+                    .get_many::<String>("replicas")
+                    .map(|replicas| replicas.cloned().collect())
+                    .unwrap_or_default(),
+                ..redis_connection_config_from(sub)
+            },
         }),
         Some(("disk", sub)) => StorageConfiguration::Disk(DiskStorageConfiguration {
             path: sub
@@ -639,11 +2211,60 @@ fn create_config() -> (Configuration, &'static str) {
                 batch_size: *sub.get_one("batch").unwrap(),
                 flushing_period: *sub.get_one("flush").unwrap(),
                 max_counters: *sub.get_one("max").unwrap(),
+                max_staleness: sub.get_one("staleness").copied(),
                 response_timeout: *sub.get_one("timeout").unwrap(),
+                updates_channel: sub.get_one::<String>("updates_channel").cloned(),
+                max_flushing_period: sub.get_one("max_flush").copied(),
             }),
+            write_behind: None,
+            broadcast: None,
+            in_memory_fallback: sub.get_flag("fallback"),
+            strict: false,
+            hashed_keys: false,
+            migrate_keys: false,
+            circuit_breaker: circuit_breaker_config_from(sub),
+            connection: redis_connection_config_from(sub),
+        }),
+        Some(("redis_write_behind", sub)) => {
+            StorageConfiguration::Redis(RedisStorageConfiguration {
+                url: sub.get_one::<String>("URL").unwrap().to_owned(),
+                cache: None,
+                write_behind: Some(RedisWriteBehindConfiguration {
+                    flushing_period: *sub.get_one("flush").unwrap(),
+                    max_counters: *sub.get_one("max").unwrap(),
+                }),
+                broadcast: None,
+                in_memory_fallback: false,
+                strict: false,
+                hashed_keys: false,
+                migrate_keys: false,
+                circuit_breaker: None,
+                connection: redis_connection_config_from(sub),
+            })
+        }
+        Some(("redis_broadcast", sub)) => StorageConfiguration::Redis(RedisStorageConfiguration {
+            url: sub.get_one::<String>("URL").unwrap().to_owned(),
+            cache: None,
+            write_behind: None,
+            broadcast: Some(RedisBroadcastConfiguration {
+                channel: sub.get_one::<String>("channel").unwrap().to_owned(),
+                broadcast_period: *sub.get_one("period").unwrap(),
+                max_counters: *sub.get_one("max").unwrap(),
+            }),
+            in_memory_fallback: false,
+            strict: false,
+            hashed_keys: false,
+            migrate_keys: false,
+            circuit_breaker: None,
+            connection: redis_connection_config_from(sub),
         }),
         Some(("memory", sub)) => StorageConfiguration::InMemory(InMemoryStorageConfiguration {
             cache_size: sub.get_one::<u64>("CACHE_SIZE").copied(),
+            snapshot_path: sub.get_one::<String>("SNAPSHOT").cloned(),
+            concurrent: sub.get_flag("CONCURRENT"),
+            sweep_interval: sub.get_one::<u64>("SWEEP_INTERVAL").copied(),
+            cache_max_bytes: sub.get_one::<u64>("CACHE_MAX_BYTES").copied(),
+            idle_timeout: sub.get_one::<u64>("IDLE_TIMEOUT").copied(),
         }),
         #[cfg(feature = "distributed_storage")]
         Some(("distributed", sub)) => {
@@ -656,6 +2277,73 @@ fn create_config() -> (Configuration, &'static str) {
                     .map(|x| x.to_owned())
                     .collect(),
                 cache_size: sub.get_one::<u64>("CACHE_SIZE").copied(),
+                sync_timeout: *sub.get_one::<u64>("SYNC_TIMEOUT").unwrap(),
+                tls: peer_tls_config(sub),
+                token: sub.get_one::<String>("TOKEN").cloned(),
+                discovery: sub.get_one::<String>("DISCOVER").cloned(),
+                discovery_period: *sub.get_one::<u64>("DISCOVERY_PERIOD").unwrap(),
+                max_batch_size: *sub.get_one::<usize>("BATCH_SIZE").unwrap(),
+                max_batch_delay: *sub.get_one::<u64>("BATCH_DELAY").unwrap(),
+                compression: sub.get_one::<String>("COMPRESSION").cloned(),
+                compression_threshold: *sub.get_one::<usize>("COMPRESSION_THRESHOLD").unwrap(),
+                conflict_resolution: match sub
+                    .get_one::<String>("CONFLICT_RESOLUTION")
+                    .map(String::as_str)
+                {
+                    Some("max-wins") => DistributedConflictResolution::MaxWins,
+                    Some("share") => {
+                        DistributedConflictResolution::Share(*sub.get_one::<f64>("SHARE").unwrap())
+                    }
+                    Some("regional") => DistributedConflictResolution::Regional {
+                        region: sub.get_one::<String>("REGION").unwrap().to_owned(),
+                        shares: sub
+                            .get_many::<(String, f64)>("REGION_SHARES")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect(),
+                        rebalance_period: sub.get_one::<u64>("REBALANCE_PERIOD").copied(),
+                        min_share: *sub.get_one::<f64>("MIN_SHARE").unwrap(),
+                    },
+                    _ => DistributedConflictResolution::Sum,
+                },
+            })
+        }
+        #[cfg(feature = "sqlite_storage")]
+        Some(("sqlite", sub)) => StorageConfiguration::Sqlite(SqliteStorageConfiguration {
+            path: sub.get_one::<String>("PATH").unwrap().to_owned(),
+        }),
+        #[cfg(feature = "etcd_storage")]
+        Some(("etcd", sub)) => StorageConfiguration::Etcd(EtcdStorageConfiguration {
+            endpoints: sub
+                .get_many::<String>("ENDPOINTS")
+                .unwrap()
+                .map(|x| x.to_owned())
+                .collect(),
+        }),
+        #[cfg(feature = "raft_storage")]
+        Some(("raft", sub)) => StorageConfiguration::Raft(RaftStorageConfiguration {
+            name: sub.get_one::<String>("NAME").unwrap().to_owned(),
+            listen_address: sub.get_one::<String>("LISTEN_ADDRESS").unwrap().to_owned(),
+            path: sub.get_one::<String>("PATH").unwrap().to_owned(),
+            peers: sub
+                .get_many::<(String, String)>("PEERS")
+                .unwrap_or_default()
+                .cloned()
+                .collect(),
+            tls: peer_tls_config(sub),
+            token: sub.get_one::<String>("TOKEN").cloned(),
+        }),
+        #[cfg(feature = "partitioned_storage")]
+        Some(("partitioned", sub)) => {
+            StorageConfiguration::Partitioned(PartitionedStorageConfiguration {
+                name: sub.get_one::<String>("NAME").unwrap().to_owned(),
+                listen_address: sub.get_one::<String>("LISTEN_ADDRESS").unwrap().to_owned(),
+                peers: sub
+                    .get_many::<(String, String)>("PEERS")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                cache_size: sub.get_one::<u64>("CACHE_SIZE").copied(),
             })
         }
         None => storage_config_from_env(),
@@ -669,6 +2357,7 @@ fn create_config() -> (Configuration, &'static str) {
     {
         "NONE" => RateLimitHeaders::None,
         "DRAFT_VERSION_03" => RateLimitHeaders::DraftVersion03,
+        "DRAFT_IETF" => RateLimitHeaders::DraftIetf,
         _ => unreachable!("invalid --rate-limit-headers value"),
     };
 
@@ -688,6 +2377,98 @@ fn create_config() -> (Configuration, &'static str) {
         matches.get_flag("grpc_reflection_service"),
     );
 
+    config.admin_grpc_port = matches
+        .get_one::<u16>("admin_grpc_port")
+        .copied()
+        .or_else(|| config::env::ADMIN_GRPC_PORT.and_then(|port| port.parse().ok()));
+
+    config.limits_dir = matches.get_one::<String>("limits_dir").cloned();
+    config.drain_timeout = *matches.get_one::<u64>("drain_timeout").unwrap();
+    config.over_limit_behaviors_file = matches.get_one::<String>("over_limit_behaviors").cloned();
+    config.descriptor_mapping_file = matches.get_one::<String>("descriptor_mapping").cloned();
+    config.idempotency_header = matches.get_one::<String>("idempotency_header").cloned();
+    config.http_over_limit = HttpOverLimitConfiguration {
+        status: *matches.get_one::<u16>("http_over_limit_status").unwrap(),
+        retry_after: matches.get_flag("http_over_limit_retry_after")
+            || *config::env::HTTP_OVER_LIMIT_RETRY_AFTER,
+        body: matches.get_one::<String>("http_over_limit_body").cloned(),
+    };
+    config.limits_discovery = matches
+        .get_one::<String>("limits_discovery_server")
+        .map(|server| LimitsDiscoveryConfiguration {
+            server: server.clone(),
+            node_id: matches
+                .get_one::<String>("limits_discovery_node_id")
+                .cloned()
+                .or_else(|| env::var("HOSTNAME").ok())
+                .unwrap_or_else(|| "limitador".to_string()),
+        });
+    config.tracing_headers = matches
+        .get_one::<String>("tracing_headers")
+        .map(|headers| {
+            headers
+                .split(',')
+                .filter(|header| !header.trim().is_empty())
+                .map(|header| match header.split_once('=') {
+                    Some((key, value)) if !key.trim().is_empty() => {
+                        (key.trim().to_string(), value.trim().to_string())
+                    }
+                    _ => {
+                        eprintln!("Error: invalid tracing header '{header}', expected KEY=VALUE");
+                        process::exit(1)
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    config.tracing_sampling_ratio = *matches.get_one::<f64>("tracing_sampling_ratio").unwrap();
+    if !(0.0..=1.0).contains(&config.tracing_sampling_ratio) {
+        eprintln!("Error: the tracing sampling ratio must be between 0 and 1");
+        process::exit(1)
+    }
+    config.limit_metrics = limit_metrics_config_from(&matches);
+    if let Some(buckets) = matches.get_one::<String>("latency_buckets") {
+        config.latency_buckets = parse_latency_buckets(buckets).unwrap_or_else(|e| {
+            eprintln!("Error: {e}");
+            process::exit(1)
+        });
+    }
+    let (metrics_pull, metrics_push) = metrics_export_config_from(&matches).unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1)
+    });
+    config.metrics_pull = metrics_pull;
+    config.metrics_push = metrics_push;
+    config.statsd = statsd_config_from(&matches).unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        process::exit(1)
+    });
+    config.metrics_max_series = matches.get_one::<usize>("metrics_max_series").copied();
+    config.log_format = match matches.get_one::<String>("log_format").unwrap().as_str() {
+        "json" => LogFormat::Json,
+        _ => LogFormat::Text,
+    };
+    config.audit_log = matches
+        .get_one::<String>("audit_log")
+        .map(|sink| match sink.as_str() {
+            "syslog" => AuditLogSink::Syslog,
+            path => AuditLogSink::File(path.to_string()),
+        });
+    config.hottest_counters = matches
+        .get_one::<u64>("hottest_counters")
+        .map(|top| *top as usize);
+    config.lenient_conditions =
+        matches.get_flag("lenient_conditions") || *config::env::LENIENT_CONDITIONS;
+    config.rls_tls = rls_tls_config_from(&matches);
+    config.http_auth = http_auth_config_from(&matches);
+
+    #[cfg(feature = "kubernetes")]
+    if matches.get_flag("limits_crd") {
+        config.limits_crd = Some(LimitsCrdConfiguration {
+            namespace: matches.get_one::<String>("limits_crd_namespace").cloned(),
+        });
+    }
+
     config.log_level = match matches.get_count("v") {
         0 => None,
         1 => Some(LevelFilter::WARN),
@@ -716,15 +2497,95 @@ fn storage_config_from_env() -> StorageConfiguration {
                         .unwrap_or_else(|| (DEFAULT_FLUSHING_PERIOD_SEC * 1000).to_string())
                         .parse()
                         .expect("Expected an i64"),
-                    max_counters: DEFAULT_MAX_CACHED_COUNTERS,
-                    response_timeout: DEFAULT_RESPONSE_TIMEOUT_MS,
+                    max_counters: config::env::REDIS_LOCAL_CACHE_MAX_CACHED_COUNTERS
+                        .map(str::to_owned)
+                        .unwrap_or_else(|| DEFAULT_MAX_CACHED_COUNTERS.to_string())
+                        .parse()
+                        .expect("Expected an usize"),
+                    max_staleness: config::env::REDIS_LOCAL_CACHE_MAX_STALENESS_MS
+                        .map(|staleness| staleness.parse().expect("Expected an u64")),
+                    response_timeout: config::env::REDIS_RESPONSE_TIMEOUT_MS
+                        .map(str::to_owned)
+                        .unwrap_or_else(|| DEFAULT_RESPONSE_TIMEOUT_MS.to_string())
+                        .parse()
+                        .expect("Expected an u64"),
+                    updates_channel: config::env::REDIS_LOCAL_CACHE_UPDATES_CHANNEL
+                        .map(str::to_owned),
+                    max_flushing_period: config::env::REDIS_LOCAL_CACHE_MAX_FLUSHING_PERIOD_MS
+                        .map(|period| period.parse().expect("Expected an u64")),
                 })
             } else {
                 None
             },
+            write_behind: None,
+            broadcast: None,
+            in_memory_fallback: *config::env::REDIS_IN_MEMORY_FALLBACK,
+            strict: *config::env::REDIS_STRICT,
+            hashed_keys: *config::env::REDIS_HASHED_KEYS,
+            migrate_keys: *config::env::REDIS_MIGRATE_KEYS,
+            circuit_breaker: None,
+            connection: RedisConnectionConfiguration {
+                username: config::env::REDIS_USERNAME.map(str::to_owned),
+                password: config::env::REDIS_PASSWORD.map(str::to_owned),
+                ca_cert: config::env::REDIS_CA_CERT.map(str::to_owned),
+                client_cert: config::env::REDIS_CLIENT_CERT.map(str::to_owned),
+                client_key: config::env::REDIS_CLIENT_KEY.map(str::to_owned),
+                connection_timeout: config::env::REDIS_CONNECTION_TIMEOUT_MS
+                    .map(|timeout| timeout.parse().expect("Expected an u64")),
+                response_timeout: config::env::REDIS_RESPONSE_TIMEOUT_MS
+                    .map(|timeout| timeout.parse().expect("Expected an u64")),
+                pool_size: config::env::REDIS_POOL_SIZE
+                    .map(|size| size.parse().expect("Expected an usize")),
+                replicas: config::env::REDIS_REPLICAS
+                    .map(|replicas| replicas.split(',').map(str::to_owned).collect())
+                    .unwrap_or_default(),
+            },
         })
     } else {
-        StorageConfiguration::InMemory(InMemoryStorageConfiguration { cache_size: None })
+        StorageConfiguration::InMemory(InMemoryStorageConfiguration {
+            cache_size: None,
+            snapshot_path: config::env::IN_MEMORY_SNAPSHOT_PATH.map(str::to_owned),
+            concurrent: false,
+            sweep_interval: None,
+            cache_max_bytes: None,
+            idle_timeout: None,
+        })
+    }
+}
+
+// Resolves on SIGTERM, or Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+fn peer_auth(tls: Option<&PeerTlsConfiguration>, token: Option<String>) -> PeerAuth {
+    let read = |path: &str| {
+        fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Failed to read '{path}': {err}");
+            process::exit(1)
+        })
+    };
+    PeerAuth {
+        tls: tls.map(|tls| PeerTls {
+            cert: read(&tls.cert),
+            key: read(&tls.key),
+            ca: read(&tls.ca),
+            domain: tls.domain.clone(),
+        }),
+        token,
     }
 }
 
@@ -744,6 +2605,287 @@ fn guess_cache_size() -> Option<u64> {
     Some(size)
 }
 
+fn circuit_breaker_config_from(sub: &ArgMatches) -> Option<CircuitBreakerConfiguration> {
+    sub.get_one::<u32>("breaker_failures")
+        .map(|failures| CircuitBreakerConfiguration {
+            failures: *failures,
+            reset_timeout: *sub.get_one("breaker_reset").unwrap(),
+            allow_when_open: sub.get_one::<String>("breaker_decision").unwrap() == "allow",
+        })
+}
+
+fn limit_metrics_config_from(matches: &ArgMatches) -> Option<LimitMetricsConfiguration> {
+    if !matches.get_flag("limit_metrics") && !*config::env::LIMIT_METRICS {
+        return None;
+    }
+    let labels = |arg: &str,
+                  env: Option<&'static str>,
+                  parse: fn(&str) -> Result<(String, String), String>| {
+        let specs: Vec<&str> = match matches.get_many::<String>(arg) {
+            Some(specs) => specs.map(String::as_str).collect(),
+            None => env
+                .map(|specs| {
+                    specs
+                        .split(',')
+                        .filter(|spec| !spec.trim().is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        specs
+            .into_iter()
+            .map(parse)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| {
+                eprintln!("Error: {e}");
+                process::exit(1)
+            })
+    };
+    Some(LimitMetricsConfiguration {
+        limit_id: matches.get_flag("limit_id_in_labels")
+            || *config::env::LIMIT_ID_IN_PROMETHEUS_LABELS,
+        variables: labels(
+            "limit_metrics_variable",
+            *config::env::LIMIT_METRICS_VARIABLES,
+            LimitMetricsConfiguration::parse_variable,
+        ),
+        metadata: labels(
+            "limit_metrics_metadata",
+            *config::env::LIMIT_METRICS_METADATA,
+            LimitMetricsConfiguration::parse_metadata,
+        ),
+    })
+}
+
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+fn peer_auth_args(display_order: usize, token: Option<&'static str>) -> [Arg; 5] {
+    [
+        Arg::new("TLS_CERT")
+            .long("tls-cert")
+            .action(ArgAction::Set)
+            .requires_all(["TLS_KEY", "TLS_CA"])
+            .display_order(display_order)
+            .help("PEM file of the certificate to present to the peers, for mutual TLS"),
+        Arg::new("TLS_KEY")
+            .long("tls-key")
+            .action(ArgAction::Set)
+            .requires("TLS_CERT")
+            .display_order(display_order + 1)
+            .help("PEM file of the key of the certificate"),
+        Arg::new("TLS_CA")
+            .long("tls-ca")
+            .action(ArgAction::Set)
+            .requires("TLS_CERT")
+            .display_order(display_order + 2)
+            .help("PEM file of the CA to verify the certificates of the peers with"),
+        Arg::new("TLS_DOMAIN")
+            .long("tls-domain")
+            .action(ArgAction::Set)
+            .requires("TLS_CERT")
+            .display_order(display_order + 3)
+            .help("Name the certificates of the peers are for, when not the host of their urls"),
+        with_env_default(
+            Arg::new("TOKEN")
+                .long("token")
+                .action(ArgAction::Set)
+                .hide_default_value(true)
+                .display_order(display_order + 4)
+                .help("Token shared by the peers, for the ones without a certificate to authenticate with"),
+            token,
+        ),
+    ]
+}
+
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+fn peer_tls_config(sub: &ArgMatches) -> Option<PeerTlsConfiguration> {
+    sub.get_one::<String>("TLS_CERT")
+        .map(|cert| PeerTlsConfiguration {
+            cert: cert.to_owned(),
+            key: sub.get_one::<String>("TLS_KEY").unwrap().to_owned(),
+            ca: sub.get_one::<String>("TLS_CA").unwrap().to_owned(),
+            domain: sub.get_one::<String>("TLS_DOMAIN").cloned(),
+        })
+}
+
+#[cfg(any(feature = "partitioned_storage", feature = "raft_storage"))]
+fn parse_named_url(peer: &str) -> Result<(String, String), String> {
+    match peer.split_once('=') {
+        Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+            Ok((name.to_string(), url.to_string()))
+        }
+        _ => Err(format!("expected NAME=URL, got '{peer}'")),
+    }
+}
+
+#[cfg(feature = "distributed_storage")]
+fn parse_share(share: &str) -> Result<f64, String> {
+    match share.parse::<f64>() {
+        Ok(share) if share > 0.0 && share <= 1.0 => Ok(share),
+        _ => Err(format!("expected a share between 0 and 1, got '{share}'")),
+    }
+}
+
+#[cfg(feature = "distributed_storage")]
+fn parse_region_share(region_share: &str) -> Result<(String, f64), String> {
+    match region_share.split_once('=') {
+        Some((region, share)) if !region.is_empty() => {
+            Ok((region.to_string(), parse_share(share)?))
+        }
+        _ => Err(format!("expected REGION=SHARE, got '{region_share}'")),
+    }
+}
+
+// Whether to serve the metrics to be scraped, and where to push them to
+fn metrics_export_config_from(
+    matches: &ArgMatches,
+) -> Result<(bool, Option<MetricsPushConfiguration>), String> {
+    let export = matches.get_one::<String>("metrics_export").unwrap();
+    let endpoint = matches.get_one::<String>("metrics_otlp_endpoint");
+    let push = match (export.as_str(), endpoint) {
+        ("pull", None) => None,
+        ("pull", Some(_)) => {
+            return Err("an OTLP metrics endpoint requires pushing the metrics".to_string())
+        }
+        (_, None) => {
+            return Err(format!(
+                "exporting the metrics with '{export}' requires an OTLP metrics endpoint"
+            ))
+        }
+        (_, Some(endpoint)) => Some(MetricsPushConfiguration {
+            endpoint: endpoint.clone(),
+            interval: *matches.get_one::<u64>("metrics_otlp_interval").unwrap(),
+        }),
+    };
+    Ok((export != "push", push))
+}
+
+fn statsd_config_from(matches: &ArgMatches) -> Result<Option<StatsdConfiguration>, String> {
+    let Some(address) = matches.get_one::<String>("statsd_address") else {
+        return Ok(None);
+    };
+    let tags = match matches.get_one::<String>("statsd_tags") {
+        Some(tags) => tags
+            .split(',')
+            .map(|tag| match tag.trim().split_once(':') {
+                Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+                _ => Err(format!("Invalid StatsD tag '{tag}', expected KEY:VALUE")),
+            })
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    Ok(Some(StatsdConfiguration {
+        address: address.clone(),
+        flush_interval: *matches.get_one::<u64>("statsd_flush_interval").unwrap(),
+        tags,
+    }))
+}
+
+// The buckets have to be in increasing order, as Prometheus expects them
+fn parse_latency_buckets(buckets: &str) -> Result<Vec<f64>, String> {
+    let buckets = buckets
+        .split(',')
+        .map(|bucket| bucket.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid latency buckets '{buckets}': {e}"))?;
+    let increasing = buckets.windows(2).all(|pair| pair[0] < pair[1]);
+    if buckets
+        .iter()
+        .any(|bucket| !bucket.is_finite() || *bucket <= 0.0)
+        || !increasing
+    {
+        return Err(format!(
+            "Latency buckets must be positive and in increasing order: {buckets:?}"
+        ));
+    }
+    Ok(buckets)
+}
+
+fn rls_tls_config_from(matches: &ArgMatches) -> Option<RlsTlsConfiguration> {
+    let client_ca_file = matches.get_one::<String>("rls_tls_client_ca").cloned();
+    let allowed_sans: Vec<String> = match matches.get_many::<String>("rls_tls_allowed_san") {
+        Some(sans) => sans.cloned().collect(),
+        None => config::env::RLS_TLS_ALLOWED_SANS
+            .map(|sans| {
+                sans.split(',')
+                    .map(str::trim)
+                    .filter(|san| !san.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    if !allowed_sans.is_empty() && client_ca_file.is_none() {
+        eprintln!("Error: allowed SANs for RLS require a client CA");
+        process::exit(1)
+    }
+
+    match (
+        matches.get_one::<String>("rls_tls_cert"),
+        matches.get_one::<String>("rls_tls_key"),
+    ) {
+        (Some(cert_file), Some(key_file)) => Some(RlsTlsConfiguration {
+            cert_file: cert_file.to_owned(),
+            key_file: key_file.to_owned(),
+            client_ca_file,
+            allowed_sans,
+        }),
+        (None, None) if client_ca_file.is_none() => None,
+        _ => {
+            eprintln!("Error: TLS for RLS requires both a certificate and its key");
+            process::exit(1)
+        }
+    }
+}
+
+fn http_auth_config_from(matches: &ArgMatches) -> Option<HttpAuthConfiguration> {
+    let tokens_file = matches.get_one::<String>("http_auth_tokens").cloned();
+    let oidc = matches
+        .get_one::<String>("http_auth_oidc_issuer")
+        .map(|issuer| OidcConfiguration {
+            issuer: issuer.to_owned(),
+            audience: matches
+                .get_one::<String>("http_auth_oidc_audience")
+                .cloned(),
+            roles_claim: matches
+                .get_one::<String>("http_auth_oidc_roles_claim")
+                .unwrap()
+                .to_owned(),
+            admin_role: matches
+                .get_one::<String>("http_auth_oidc_admin_role")
+                .unwrap()
+                .to_owned(),
+            read_only_role: matches
+                .get_one::<String>("http_auth_oidc_read_only_role")
+                .unwrap()
+                .to_owned(),
+        });
+    if tokens_file.is_none() && oidc.is_none() {
+        return None;
+    }
+    Some(HttpAuthConfiguration { tokens_file, oidc })
+}
+
+fn redis_connection_config_from(sub: &ArgMatches) -> RedisConnectionConfiguration {
+    RedisConnectionConfiguration {
+        username: sub.get_one::<String>("username").cloned(),
+        password: sub.get_one::<String>("password").cloned(),
+        ca_cert: sub.get_one::<String>("ca_cert").cloned(),
+        client_cert: sub.get_one::<String>("client_cert").cloned(),
+        client_key: sub.get_one::<String>("client_key").cloned(),
+        connection_timeout: sub.get_one("connect_timeout").copied(),
+        response_timeout: None,
+        pool_size: None,
+        replicas: Vec::new(),
+    }
+}
+
+fn with_env_default(arg: Arg, value: Option<&'static str>) -> Arg {
+    match value {
+        None => arg,
+        Some(value) => arg.default_value(value),
+    }
+}
+
 fn leak<D: Display>(s: D) -> &'static str {
     Box::leak(format!("{}", s).into_boxed_str())
 }
@@ -755,77 +2897,115 @@ fn configure_tracing_subscriber(config: &Configuration) {
             .unwrap_or(LevelFilter::ERROR)
     });
 
-    let metrics_layer = MetricsLayer::default()
-        .gather(
-            "should_rate_limit",
-            PrometheusMetrics::record_datastore_latency,
-            vec!["datastore"],
-        )
-        .gather(
-            "flush_batcher_and_update_counters",
-            PrometheusMetrics::record_datastore_latency,
-            vec!["datastore"],
-        );
+    let metrics_layer = MetricsLayer::default().time(
+        "should_rate_limit",
+        PrometheusMetrics::record_should_rate_limit_latency,
+    );
+    // Running in memory, there's no datastore latency to speak of
+    let metrics_layer = match config.storage {
+        StorageConfiguration::InMemory(_) => metrics_layer,
+        _ => metrics_layer
+            .gather(
+                "should_rate_limit",
+                PrometheusMetrics::record_datastore_latency,
+                vec!["datastore"],
+            )
+            .gather(
+                "flush_batcher_and_update_counters",
+                PrometheusMetrics::record_datastore_latency,
+                vec!["datastore"],
+            ),
+    };
+
+    let audit_layer = config.audit_log.as_ref().map(|sink| {
+        audit::layer(sink).unwrap_or_else(|e| {
+            eprintln!("Error: {e}");
+            process::exit(1)
+        })
+    });
 
     if !config.tracing_endpoint.is_empty() {
         // Init tracing subscriber with telemetry
-        // If running in memory initialize without metrics
-        match config.storage {
-            StorageConfiguration::InMemory(_) => tracing_subscriber::registry()
-                .with(fmt_layer(level))
-                .with(telemetry_layer(&config.tracing_endpoint, level))
-                .init(),
-            _ => tracing_subscriber::registry()
-                .with(metrics_layer)
-                .with(fmt_layer(level))
-                .with(telemetry_layer(&config.tracing_endpoint, level))
-                .init(),
-        }
+        tracing_subscriber::registry()
+            .with(audit_layer)
+            .with(metrics_layer)
+            .with(fmt_layer(level, config.log_format))
+            .with(telemetry_layer(config, level))
+            .init()
     } else {
-        // If running in memory initialize without metrics
-        match config.storage {
-            StorageConfiguration::InMemory(_) => {
-                tracing_subscriber::registry().with(fmt_layer(level)).init()
-            }
-            _ => tracing_subscriber::registry()
-                .with(metrics_layer)
-                .with(fmt_layer(level))
-                .init(),
-        }
+        tracing_subscriber::registry()
+            .with(audit_layer)
+            .with(metrics_layer)
+            .with(fmt_layer(level, config.log_format))
+            .init()
     }
 }
 
-fn fmt_layer<S>(level: LevelFilter) -> impl Layer<S>
+fn fmt_layer<S>(level: LevelFilter, format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
 where
-    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a> + 'static,
 {
-    tracing_subscriber::fmt::layer()
-        .with_span_events(if level >= LevelFilter::DEBUG {
-            FmtSpan::CLOSE
-        } else {
-            FmtSpan::NONE
-        })
-        .with_filter(level)
+    let span_events = if level >= LevelFilter::DEBUG {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_span_events(span_events)
+            .with_filter(level)
+            .boxed(),
+        // The fields of the spans, e.g. the namespace of the request, are
+        // logged along, for the pipelines to filter on
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_span_events(span_events)
+            .with_filter(level)
+            .boxed(),
+    }
 }
 
-fn telemetry_layer<S>(tracing_endpoint: &String, level: LevelFilter) -> impl Layer<S>
+fn telemetry_layer<S>(config: &Configuration, level: LevelFilter) -> impl Layer<S>
 where
     S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
     global::set_text_map_propagator(TraceContextPropagator::new());
 
+    let mut metadata = MetadataMap::new();
+    for (key, value) in &config.tracing_headers {
+        match (AsciiMetadataKey::from_bytes(key.as_bytes()), value.parse()) {
+            (Ok(key), Ok(value)) => {
+                metadata.insert(key, value);
+            }
+            _ => {
+                eprintln!("Error: invalid tracing header '{key}'");
+                process::exit(1)
+            }
+        }
+    }
+
+    // Traces started upstream, e.g. by Envoy, are sampled as they were there
+    let sampler = trace::Sampler::ParentBased(Box::new(trace::Sampler::TraceIdRatioBased(
+        config.tracing_sampling_ratio,
+    )));
+
     let tracer = opentelemetry_otlp::new_pipeline()
         .tracing()
         .with_exporter(
             opentelemetry_otlp::new_exporter()
                 .tonic()
-                .with_endpoint(tracing_endpoint),
+                .with_endpoint(&config.tracing_endpoint)
+                .with_metadata(metadata),
         )
         .with_trace_config(
-            trace::config().with_resource(Resource::new(vec![KeyValue::new(
-                "service.name",
-                "limitador",
-            )])),
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    "limitador",
+                )])),
         )
         .install_batch(opentelemetry_sdk::runtime::Tokio)
         .expect("error installing tokio tracing exporter");
@@ -81,9 +81,13 @@ impl MetricsGroup {
     }
 }
 
+// When a span timed end to end got created
+struct Started(Instant);
+
 #[derive(Default)]
 pub struct MetricsLayer {
     groups: HashMap<String, MetricsGroup>,
+    timed: HashMap<String, fn(Duration)>,
 }
 
 impl MetricsLayer {
@@ -95,6 +99,13 @@ impl MetricsLayer {
             .or_insert_with(|| MetricsGroup::new(Box::new(consumer), rec));
         self
     }
+
+    /// Passes the time from the creation of every `span` to its closing, i.e.
+    /// including the time it spent idle, to the `consumer`
+    pub fn time(mut self, span: &str, consumer: fn(Duration)) -> Self {
+        self.timed.insert(span.to_string(), consumer);
+        self
+    }
 }
 
 impl<S> Layer<S> for MetricsLayer
@@ -107,6 +118,10 @@ where
         let mut extensions = span.extensions_mut();
         let name = span.name().to_string();
 
+        if self.timed.contains_key(&name) {
+            extensions.insert(Started(Instant::now()));
+        }
+
         // if there's a parent
         if let Some(parent) = span.parent() {
             // if the parent has SpanState propagate to this span
@@ -176,6 +191,12 @@ where
         let mut extensions = span.extensions_mut();
         let name = span.name().to_string();
 
+        if let (Some(consumer), Some(Started(start))) =
+            (self.timed.get(&name), extensions.get::<Started>())
+        {
+            consumer(start.elapsed());
+        }
+
         let timing = extensions.get_mut::<Timings>().map(|t| {
             let now = Instant::now();
             t.idle += (now - t.last).as_nanos() as u64;
@@ -213,7 +234,9 @@ where
 #[cfg(test)]
 mod tests {
     use super::{MetricsLayer, SpanState, Timings};
-    use std::time::Instant;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+    use tracing_subscriber::layer::SubscriberExt;
 
     #[test]
     fn timings_add() {
@@ -290,4 +313,21 @@ mod tests {
         let ml = MetricsLayer::default().gather("group", consumer, vec!["record"]);
         assert_eq!(ml.groups.get("group").unwrap().records, vec!["record"]);
     }
+
+    #[test]
+    fn times_spans_end_to_end() {
+        static TIMED: AtomicU64 = AtomicU64::new(0);
+        let consumer = |duration: Duration| {
+            TIMED.fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+        };
+        let subscriber =
+            tracing_subscriber::registry().with(MetricsLayer::default().time("timed", consumer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("timed");
+            std::thread::sleep(Duration::from_millis(5));
+            span.in_scope(|| tracing::info_span!("not_timed").in_scope(|| {}));
+        });
+        assert!(TIMED.load(Ordering::SeqCst) >= Duration::from_millis(5).as_nanos() as u64);
+    }
 }
@@ -0,0 +1,198 @@
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use opentelemetry::metrics::{self as otel, Meter, MeterProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::reader::{AggregationSelector, DefaultAggregationSelector};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, SdkMeterProvider};
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Mirrors the metrics recorded, i.e. the ones served on `/metrics`, to an
+/// OTLP collector, exporting them every period
+pub struct OtlpRecorder {
+    meter: Meter,
+    provider: SdkMeterProvider,
+    descriptions: Mutex<HashMap<String, SharedString>>,
+}
+
+impl OtlpRecorder {
+    pub fn new(endpoint: &str, period: Duration, latency_buckets: &[f64]) -> Result<Self, String> {
+        let provider = opentelemetry_otlp::new_pipeline()
+            .metrics(opentelemetry_sdk::runtime::Tokio)
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_period(period)
+            .with_aggregation_selector(LatencyBuckets(latency_buckets.to_vec()))
+            .with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "limitador",
+            )]))
+            .build()
+            .map_err(|e| format!("Couldn't export metrics to '{endpoint}': {e}"))?;
+        Ok(Self {
+            meter: provider.meter("limitador"),
+            provider,
+            descriptions: Mutex::default(),
+        })
+    }
+
+    /// To export what's left to, on shutdown
+    pub fn provider(&self) -> SdkMeterProvider {
+        self.provider.clone()
+    }
+
+    fn describe(&self, key: KeyName, description: SharedString) {
+        self.descriptions
+            .lock()
+            .unwrap()
+            .insert(key.as_str().to_string(), description);
+    }
+
+    fn description(&self, key: &Key) -> String {
+        self.descriptions
+            .lock()
+            .unwrap()
+            .get(key.name())
+            .map(|description| String::from(&**description))
+            .unwrap_or_default()
+    }
+}
+
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels()
+        .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+impl Recorder for OtlpRecorder {
+    fn describe_counter(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description)
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let counter = self
+            .meter
+            .u64_counter(key.name().to_string())
+            .with_description(self.description(key))
+            .init();
+        Counter::from_arc(Arc::new(OtlpCounter {
+            counter,
+            attributes: attributes(key),
+            total: AtomicU64::new(0),
+        }))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let gauge = self
+            .meter
+            .f64_up_down_counter(key.name().to_string())
+            .with_description(self.description(key))
+            .init();
+        Gauge::from_arc(Arc::new(OtlpGauge {
+            gauge,
+            attributes: attributes(key),
+            value: Mutex::new(0.0),
+        }))
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let histogram = self
+            .meter
+            .f64_histogram(key.name().to_string())
+            .with_description(self.description(key))
+            .init();
+        Histogram::from_arc(Arc::new(OtlpHistogram {
+            histogram,
+            attributes: attributes(key),
+        }))
+    }
+}
+
+// The latency histograms use the same buckets as on `/metrics`, rather than
+// OpenTelemetry's default ones, meant for milliseconds
+struct LatencyBuckets(Vec<f64>);
+
+impl AggregationSelector for LatencyBuckets {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        match kind {
+            InstrumentKind::Histogram => Aggregation::ExplicitBucketHistogram {
+                boundaries: self.0.clone(),
+                record_min_max: true,
+            },
+            _ => DefaultAggregationSelector::new().aggregation(kind),
+        }
+    }
+}
+
+struct OtlpCounter {
+    counter: otel::Counter<u64>,
+    attributes: Vec<KeyValue>,
+    // OpenTelemetry counters only ever get added to
+    total: AtomicU64,
+}
+
+impl CounterFn for OtlpCounter {
+    fn increment(&self, value: u64) {
+        self.total.fetch_add(value, Ordering::Relaxed);
+        self.counter.add(value, &self.attributes);
+    }
+
+    fn absolute(&self, value: u64) {
+        let previous = self.total.fetch_max(value, Ordering::Relaxed);
+        if value > previous {
+            self.counter.add(value - previous, &self.attributes);
+        }
+    }
+}
+
+struct OtlpGauge {
+    gauge: otel::UpDownCounter<f64>,
+    attributes: Vec<KeyValue>,
+    // Gauges get set, while up-down counters only get added to
+    value: Mutex<f64>,
+}
+
+impl GaugeFn for OtlpGauge {
+    fn increment(&self, value: f64) {
+        *self.value.lock().unwrap() += value;
+        self.gauge.add(value, &self.attributes);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.increment(-value)
+    }
+
+    fn set(&self, value: f64) {
+        let mut current = self.value.lock().unwrap();
+        self.gauge.add(value - *current, &self.attributes);
+        *current = value;
+    }
+}
+
+struct OtlpHistogram {
+    histogram: otel::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtlpHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
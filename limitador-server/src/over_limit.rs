@@ -0,0 +1,214 @@
+use limitador::errors::LimitadorError;
+use limitador::limit::Namespace;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Set on the requests let through while they'd have been denied, to the
+/// reason they would have been
+pub const SOFT_DENY_HEADER: &str = "x-limitador-soft-deny";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Behavior {
+    /// The request is denied
+    Deny,
+    /// The request is allowed, flagged with the `SOFT_DENY_HEADER`
+    AllowWithHeader,
+    /// The request is allowed, and a warning logged
+    AllowAndLog,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamespaceBehaviors {
+    namespace: String,
+    #[serde(default)]
+    over_limit: Option<Behavior>,
+    #[serde(default)]
+    on_storage_error: Option<Behavior>,
+}
+
+/// What to answer to a request once its namespace's behavior applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Allowed, but flagged with the reason it would have been denied
+    AllowFlagged(&'static str),
+    Deny,
+}
+
+impl Verdict {
+    pub fn allowed(&self) -> bool {
+        !matches!(self, Self::Deny)
+    }
+
+    /// The header to flag the request with, if any
+    pub fn header(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Self::AllowFlagged(reason) => Some((SOFT_DENY_HEADER, *reason)),
+            Self::Allow | Self::Deny => None,
+        }
+    }
+
+    /// Logs the decision made on the `hits` of a request of `namespace`, with
+    /// the `limit` that limited it, if any, as fields of the event
+    pub fn log(
+        &self,
+        namespace: &Namespace,
+        hits: u64,
+        limit: Option<&str>,
+        metadata: Option<&BTreeMap<String, String>>,
+    ) {
+        let verdict = match self {
+            Self::Allow => "allow",
+            Self::AllowFlagged(_) => "allow_flagged",
+            Self::Deny => "deny",
+        };
+        tracing::debug!(
+            namespace = namespace.as_ref(),
+            hits,
+            limit,
+            metadata = ?metadata,
+            verdict,
+            "Rate limiting decision"
+        );
+    }
+}
+
+/// How each namespace wants requests exceeding its limits, or hitting a storage
+/// error, to be answered. Namespaces not listed deny requests exceeding their
+/// limits, and fail on storage errors, leaving it up to the client, e.g.
+/// Envoy's `failure_mode_deny`.
+#[derive(Debug, Default)]
+pub struct OverLimitBehaviors {
+    namespaces: HashMap<String, NamespaceBehaviors>,
+}
+
+impl OverLimitBehaviors {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file =
+            std::fs::File::open(path).map_err(|e| format!("Couldn't read file '{path}': {e}"))?;
+        let behaviors: Vec<NamespaceBehaviors> =
+            serde_yaml::from_reader(file).map_err(|e| format!("Couldn't parse '{path}': {e}"))?;
+        Ok(Self::new(behaviors))
+    }
+
+    fn new(behaviors: Vec<NamespaceBehaviors>) -> Self {
+        Self {
+            namespaces: behaviors
+                .into_iter()
+                .map(|behaviors| (behaviors.namespace.clone(), behaviors))
+                .collect(),
+        }
+    }
+
+    /// The verdict for a request of `namespace` exceeding its `limit`
+    pub fn over_limit(&self, namespace: &Namespace, limit: Option<&str>) -> Verdict {
+        let behavior = self
+            .namespaces
+            .get(namespace.as_ref())
+            .and_then(|behaviors| behaviors.over_limit)
+            .unwrap_or(Behavior::Deny);
+        match behavior {
+            Behavior::Deny => Verdict::Deny,
+            Behavior::AllowWithHeader => Verdict::AllowFlagged("over_limit"),
+            Behavior::AllowAndLog => {
+                warn!(
+                    "Allowing request over limit '{}' of namespace '{}'",
+                    limit.unwrap_or("unnamed"),
+                    namespace.as_ref()
+                );
+                Verdict::Allow
+            }
+        }
+    }
+
+    /// The verdict for a request of `namespace` that failed with `err`, if
+    /// it's a storage error and the namespace has one rather than failing, or
+    /// the one of a request over the limit its delta exceeds the max value of
+    pub fn on_error(&self, namespace: &Namespace, err: &LimitadorError) -> Option<Verdict> {
+        match err {
+            LimitadorError::StorageError(err) => self.on_storage_error(namespace, err),
+            LimitadorError::DeltaExceedsMaxValue(err) => {
+                Some(self.over_limit(namespace, err.limit().name()))
+            }
+            LimitadorError::InterpreterError(_) | LimitadorError::LimitIdConflict(_) => None,
+        }
+    }
+
+    fn on_storage_error(&self, namespace: &Namespace, err: &dyn fmt::Display) -> Option<Verdict> {
+        let behavior = self
+            .namespaces
+            .get(namespace.as_ref())
+            .and_then(|behaviors| behaviors.on_storage_error)?;
+        Some(match behavior {
+            Behavior::Deny => Verdict::Deny,
+            Behavior::AllowWithHeader => Verdict::AllowFlagged("storage_error"),
+            Behavior::AllowAndLog => {
+                warn!(
+                    "Allowing request of namespace '{}' despite storage error: {}",
+                    namespace.as_ref(),
+                    err
+                );
+                Verdict::Allow
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+impl OverLimitBehaviors {
+    pub fn of(namespace: &str, over_limit: Behavior, on_storage_error: Behavior) -> Self {
+        Self::new(vec![NamespaceBehaviors {
+            namespace: namespace.to_string(),
+            over_limit: Some(over_limit),
+            on_storage_error: Some(on_storage_error),
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Behavior, OverLimitBehaviors, Verdict};
+    use limitador::limit::Namespace;
+
+    #[test]
+    fn applies_the_namespace_behaviors() {
+        let behaviors: OverLimitBehaviors = serde_yaml::from_str::<Vec<_>>(
+            "- namespace: soft\n  over_limit: allow_with_header\n  on_storage_error: allow_and_log\n\
+             - namespace: strict\n  on_storage_error: deny\n",
+        )
+        .map(OverLimitBehaviors::new)
+        .unwrap();
+        let error = "connection refused";
+
+        let soft = Namespace::from("soft");
+        assert_eq!(
+            behaviors.over_limit(&soft, None),
+            Verdict::AllowFlagged("over_limit")
+        );
+        assert_eq!(
+            behaviors.on_storage_error(&soft, &error),
+            Some(Verdict::Allow)
+        );
+
+        let strict = Namespace::from("strict");
+        assert_eq!(behaviors.over_limit(&strict, None), Verdict::Deny);
+        assert_eq!(
+            behaviors.on_storage_error(&strict, &error),
+            Some(Verdict::Deny)
+        );
+
+        let other = Namespace::from("other");
+        assert_eq!(behaviors.over_limit(&other, None), Verdict::Deny);
+        assert_eq!(behaviors.on_storage_error(&other, &error), None);
+
+        let flagged = OverLimitBehaviors::of("a", Behavior::Deny, Behavior::AllowWithHeader);
+        assert_eq!(
+            flagged
+                .on_storage_error(&Namespace::from("a"), &error)
+                .and_then(|verdict| verdict.header()),
+            Some(("x-limitador-soft-deny", "storage_error"))
+        );
+    }
+}
@@ -1,17 +1,102 @@
-use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use std::sync::Arc;
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Label,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::FanoutBuilder;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::config::LimitMetricsConfiguration;
+use crate::hottest_counters::used_and_remaining;
 use crate::metrics::Timings;
+use crate::otlp_metrics::OtlpRecorder;
+use crate::statsd_metrics::StatsdRecorder;
+use limitador::counter::Counter;
 use limitador::limit::Namespace;
+use limitador::CheckResult;
 
 const NAMESPACE_LABEL: &str = "limitador_namespace";
 const LIMIT_NAME_LABEL: &str = "limit_name";
+const LIMIT_ID_LABEL: &str = "limit_id";
+/// The value of every label of the series past the maximum of a metric
+const OVERFLOW_LABEL_VALUE: &str = "other";
+
+/// The buckets, in seconds, of the latency histograms, i.e.
+/// `datastore_latency` and `should_rate_limit_latency`
+pub const DEFAULT_LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+const LATENCY_HISTOGRAMS: [&str; 2] = ["datastore_latency", "should_rate_limit_latency"];
+
+/// Where the metrics are exported to, on top of `/metrics` when `pull`
+pub struct Exporters {
+    pub pull: bool,
+    pub otlp: Option<OtlpRecorder>,
+    pub statsd: Option<StatsdRecorder>,
+}
+
+impl Default for Exporters {
+    fn default() -> Self {
+        Self {
+            pull: true,
+            otlp: None,
+            statsd: None,
+        }
+    }
+}
 
 pub struct PrometheusMetrics {
     prometheus_handle: Arc<PrometheusHandle>,
     use_limit_name_label: bool,
+    limit_metrics: Option<LimitMetricsConfiguration>,
+    series_limit: Option<SeriesLimit>,
+    // Rendered apart, for the counters no longer the hottest not to linger
+    hottest_counters: Mutex<String>,
+}
+
+/// Caps the label sets of the metrics labeled after namespaces, limits or
+/// variables, for their cardinality not to grow unbounded
+struct SeriesLimit {
+    max: usize,
+    series: Mutex<HashMap<&'static str, Series>>,
+}
+
+#[derive(Default)]
+struct Series {
+    kept: HashSet<Vec<Label>>,
+    // Only their hashes, for the dropped ones not to take as much memory
+    dropped: HashSet<u64>,
+}
+
+impl SeriesLimit {
+    // The labels to record the `metric` with, the overflowing ones being all
+    // set to `OVERFLOW_LABEL_VALUE`
+    fn admit(&self, metric: &'static str, labels: Vec<Label>) -> Vec<Label> {
+        let newly_dropped = {
+            let mut series = self.series.lock().unwrap();
+            let series = series.entry(metric).or_default();
+            if series.kept.contains(&labels) {
+                return labels;
+            }
+            if series.kept.len() < self.max {
+                series.kept.insert(labels.clone());
+                return labels;
+            }
+            let mut hasher = DefaultHasher::new();
+            labels.hash(&mut hasher);
+            series.dropped.insert(hasher.finish())
+        };
+        if newly_dropped {
+            counter!("limitador_metric_series_dropped_total", "metric" => metric).increment(1);
+        }
+        labels
+            .into_iter()
+            .map(|label| Label::new(label.key().to_string(), OVERFLOW_LABEL_VALUE))
+            .collect()
+    }
 }
 
 impl Default for PrometheusMetrics {
@@ -22,7 +107,7 @@ impl Default for PrometheusMetrics {
 
 impl PrometheusMetrics {
     pub fn new() -> Self {
-        Self::new_with_options(false)
+        Self::new_with_options(false, &DEFAULT_LATENCY_BUCKETS)
     }
 
     // Note: This is optional because for a small number of limits it should be
@@ -30,11 +115,26 @@ impl PrometheusMetrics {
     // caution note in the Prometheus docs:
     // https://prometheus.io/docs/practices/naming/#labels
     pub fn new_with_counters_by_limit_name() -> Self {
-        Self::new_with_options(true)
+        Self::new_with_options(true, &DEFAULT_LATENCY_BUCKETS)
     }
 
-    pub fn new_with_options(use_limit_name_label: bool) -> Self {
-        Self::new_with_handle(use_limit_name_label, Arc::new(Self::init_handle()))
+    pub fn new_with_options(use_limit_name_label: bool, latency_buckets: &[f64]) -> Self {
+        Self::new_with_handle(
+            use_limit_name_label,
+            Arc::new(Self::init_handle(latency_buckets, Exporters::default())),
+        )
+    }
+
+    /// Also mirrors the metrics to the `exporters`
+    pub fn new_with_exporters(
+        use_limit_name_label: bool,
+        latency_buckets: &[f64],
+        exporters: Exporters,
+    ) -> Self {
+        Self::new_with_handle(
+            use_limit_name_label,
+            Arc::new(Self::init_handle(latency_buckets, exporters)),
+        )
     }
 
     pub(crate) fn new_with_handle(
@@ -45,6 +145,10 @@ impl PrometheusMetrics {
             "datastore_latency",
             "Latency to the underlying counter datastore"
         );
+        describe_histogram!(
+            "should_rate_limit_latency",
+            "Latency of the RLS requests, end to end"
+        );
         describe_counter!("authorized_calls", "Authorized calls");
         describe_counter!("limited_calls", "Limited calls");
         describe_gauge!("limitador_up", "Limitador is running");
@@ -54,61 +158,284 @@ impl PrometheusMetrics {
             "Limitador is partitioned from backing datastore"
         );
         gauge!("datastore_partitioned").set(0);
+        describe_gauge!(
+            "datastore_pool_connections_in_use",
+            "Connections of the pool to the datastore in use"
+        );
+        describe_histogram!(
+            "datastore_pool_wait_time",
+            "Time spent waiting for a connection of the pool to the datastore"
+        );
+        describe_gauge!(
+            "datastore_failed_over",
+            "Limitador is serving from the secondary datastore"
+        );
+        describe_gauge!(
+            "datastore_circuit_open",
+            "The circuit breaker around the datastore is open"
+        );
+        describe_counter!(
+            "datastore_decisions",
+            "Rate limiting decisions, by datastore tier that made them"
+        );
+        describe_counter!(
+            "evicted_counters",
+            "Counters evicted from the in-memory cache, by cause: its size or their idling"
+        );
+        describe_gauge!(
+            "batcher_flushing_period",
+            "Period at which the cached counters get flushed to Redis, in seconds"
+        );
+        describe_gauge!(
+            "batcher_flushing_batch_size",
+            "Maximum amount of cached counters flushed to Redis at once"
+        );
+        describe_counter!(
+            "reclaimed_counters",
+            "Counters dropped by the sweeper of the in-memory storage, their window being over"
+        );
         Self {
             use_limit_name_label,
             prometheus_handle,
+            limit_metrics: None,
+            series_limit: None,
+            hottest_counters: Mutex::default(),
         }
     }
 
+    /// Caps the series of the metrics labeled after namespaces, limits or
+    /// variables to `max` each, recording the ones past it as a single series
+    /// with all its labels set to `other`
+    pub fn max_series(mut self, max: usize) -> Self {
+        describe_counter!(
+            "limitador_metric_series_dropped_total",
+            "Series not exported, as past the maximum of the metric, and recorded as `other`"
+        );
+        self.series_limit = Some(SeriesLimit {
+            max,
+            series: Mutex::default(),
+        });
+        self
+    }
+
+    /// Also exports the hits checked against each limit, and the requests it
+    /// limited. As these require the counters of every limit a request is
+    /// checked against, they get loaded along the way.
+    pub fn limit_metrics(mut self, config: LimitMetricsConfiguration) -> Self {
+        describe_counter!(
+            "limitador_limit_hits_total",
+            "Hits checked against the limit"
+        );
+        describe_counter!("limitador_limited_total", "Requests limited by the limit");
+        self.limit_metrics = Some(config);
+        self
+    }
+
+    /// Whether the counters need loading when checking requests, for the
+    /// per-limit metrics
+    pub fn needs_counters(&self) -> bool {
+        self.limit_metrics.is_some()
+    }
+
     // Creates and installs the prometheus exporter as global recorder
     // Only one recorder can be registered for the lifetime of the application
-    fn init_handle() -> PrometheusHandle {
-        let prom_builder = PrometheusBuilder::new();
-        prom_builder
-            .install_recorder()
-            .expect("failed to create prometheus metrics exporter")
+    fn init_handle(latency_buckets: &[f64], exporters: Exporters) -> PrometheusHandle {
+        let mut prom_builder = PrometheusBuilder::new();
+        for histogram in LATENCY_HISTOGRAMS {
+            prom_builder = prom_builder
+                .set_buckets_for_metric(Matcher::Full(histogram.to_string()), latency_buckets)
+                .expect("invalid latency buckets");
+        }
+        let Exporters { pull, otlp, statsd } = exporters;
+        if otlp.is_none() && statsd.is_none() {
+            return prom_builder
+                .install_recorder()
+                .expect("failed to create prometheus metrics exporter");
+        }
+        // Not pulling, the recorder is left out and `/metrics` stays empty
+        let prometheus = prom_builder.build_recorder();
+        let handle = prometheus.handle();
+        let mut fanout = FanoutBuilder::default();
+        if pull {
+            fanout = fanout.add_recorder(prometheus);
+        }
+        if let Some(otlp) = otlp {
+            fanout = fanout.add_recorder(otlp);
+        }
+        if let Some(statsd) = statsd {
+            fanout = fanout.add_recorder(statsd);
+        }
+        metrics::set_global_recorder(fanout.build())
+            .expect("failed to install the metrics recorders");
+        handle
     }
 
     pub fn incr_authorized_calls(&self, namespace: &Namespace) {
-        counter!("authorized_calls", NAMESPACE_LABEL => namespace.as_ref().to_string()).increment(1)
+        let labels = vec![Label::new(NAMESPACE_LABEL, namespace.as_ref().to_string())];
+        counter!("authorized_calls", self.admit("authorized_calls", labels)).increment(1)
     }
 
     pub fn incr_limited_calls<'a, LN>(&self, namespace: &Namespace, limit_name: LN)
     where
         LN: Into<Option<&'a str>>,
     {
-        let mut labels = vec![(NAMESPACE_LABEL, namespace.as_ref().to_string())];
+        let mut labels = vec![Label::new(NAMESPACE_LABEL, namespace.as_ref().to_string())];
 
         if self.use_limit_name_label {
             // If we have configured the metric to accept 2 labels we need to
             // set values for them.
-            labels.push((
+            labels.push(Label::new(
                 LIMIT_NAME_LABEL,
                 limit_name.into().unwrap_or("").to_string(),
             ));
         }
-        counter!("limited_calls", &labels).increment(1)
+        counter!("limited_calls", self.admit("limited_calls", labels)).increment(1)
+    }
+
+    fn admit(&self, metric: &'static str, labels: Vec<Label>) -> Vec<Label> {
+        match &self.series_limit {
+            Some(series_limit) => series_limit.admit(metric, labels),
+            None => labels,
+        }
+    }
+
+    /// Records the `hits` of a request of the `namespace` against each of the
+    /// limits it got `checked` against, and the one that limited it, if any
+    pub fn record_limits(&self, namespace: &Namespace, checked: &CheckResult, hits: u64) {
+        let Some(config) = &self.limit_metrics else {
+            return;
+        };
+        // The statuses are the ones of the counters, in the same order, as
+        // they got loaded
+        for (i, limit_counter) in checked.counters.iter().enumerate() {
+            let labels = self.limit_labels(namespace, limit_counter, config);
+            let hits = limit_counter.delta(hits);
+            let hits_labels = self.admit("limitador_limit_hits_total", labels.clone());
+            counter!("limitador_limit_hits_total", hits_labels).increment(hits);
+            if checked
+                .statuses
+                .get(i)
+                .is_some_and(|status| status.limiting)
+            {
+                let labels = self.admit("limitador_limited_total", labels);
+                counter!("limitador_limited_total", labels).increment(1);
+            }
+        }
+    }
+
+    fn limit_labels(
+        &self,
+        namespace: &Namespace,
+        limit_counter: &Counter,
+        config: &LimitMetricsConfiguration,
+    ) -> Vec<Label> {
+        let mut labels = vec![Label::new(NAMESPACE_LABEL, namespace.as_ref().to_string())];
+        if self.use_limit_name_label {
+            let name = limit_counter.limit().name().unwrap_or("");
+            labels.push(Label::new(LIMIT_NAME_LABEL, name.to_string()));
+        }
+        if config.limit_id {
+            let id = limit_counter.id().unwrap_or("");
+            labels.push(Label::new(LIMIT_ID_LABEL, id.to_string()));
+        }
+        for (label, variable) in &config.variables {
+            let value = limit_counter.set_variables().get(variable);
+            labels.push(Label::new(
+                label.clone(),
+                value.cloned().unwrap_or_default(),
+            ));
+        }
+        for (label, key) in &config.metadata {
+            let value = limit_counter.limit().metadata().get(key);
+            labels.push(Label::new(
+                label.clone(),
+                value.cloned().unwrap_or_default(),
+            ));
+        }
+        labels
+    }
+
+    /// Replaces the gauges of the used and remaining hits of the hottest
+    /// counters with the ones of `counters`
+    pub fn set_hottest_counters(&self, counters: &[Counter]) {
+        let mut used = String::from(
+            "# HELP limitador_counter_used Hits counted in the current window of the hottest counters\n\
+             # TYPE limitador_counter_used gauge\n",
+        );
+        let mut remaining = String::from(
+            "# HELP limitador_counter_remaining Hits left in the current window of the hottest counters\n\
+             # TYPE limitador_counter_remaining gauge\n",
+        );
+        for counter in counters {
+            let labels = counter_labels(counter);
+            let (counter_used, counter_remaining) = used_and_remaining(counter);
+            used.push_str(&format!(
+                "limitador_counter_used{{{labels}}} {counter_used}\n"
+            ));
+            remaining.push_str(&format!(
+                "limitador_counter_remaining{{{labels}}} {counter_remaining}\n"
+            ));
+        }
+        *self.hottest_counters.lock().unwrap() = format!("{used}\n{remaining}\n");
     }
 
     pub fn gather_metrics(&self) -> String {
-        self.prometheus_handle.render()
+        let mut metrics = self.prometheus_handle.render();
+        let hottest_counters = self.hottest_counters.lock().unwrap();
+        if !hottest_counters.is_empty() {
+            if !metrics.is_empty() && !metrics.ends_with("\n\n") {
+                metrics.push('\n');
+            }
+            metrics.push_str(&hottest_counters);
+        }
+        metrics
     }
 
     pub fn record_datastore_latency(timings: Timings) {
         histogram!("datastore_latency").record(Duration::from(timings).as_secs_f64())
     }
+
+    pub fn record_should_rate_limit_latency(latency: Duration) {
+        histogram!("should_rate_limit_latency").record(latency.as_secs_f64())
+    }
+}
+
+// The limit, and variables as `name=value` pairs, identifying the counter
+fn counter_labels(counter: &Counter) -> String {
+    let escaped = |value: &str| {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    };
+    let variables: Vec<_> = counter
+        .set_variables()
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    format!(
+        "{NAMESPACE_LABEL}=\"{}\",{LIMIT_NAME_LABEL}=\"{}\",{LIMIT_ID_LABEL}=\"{}\",variables=\"{}\"",
+        escaped(counter.namespace().as_ref()),
+        escaped(counter.limit().name().unwrap_or("")),
+        escaped(counter.id().unwrap_or("")),
+        escaped(&variables.join(",")),
+    )
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use lazy_static::lazy_static;
+    use limitador::limit::Limit;
+    use limitador::CounterStatus;
     use metrics_exporter_prometheus::PrometheusHandle;
+    use std::collections::BTreeMap;
 
     // Setting recorder once for all test cases
     lazy_static! {
-        pub static ref TEST_PROMETHEUS_HANDLE: Arc<PrometheusHandle> =
-            Arc::new(PrometheusMetrics::init_handle());
+        pub static ref TEST_PROMETHEUS_HANDLE: Arc<PrometheusHandle> = Arc::new(
+            PrometheusMetrics::init_handle(&DEFAULT_LATENCY_BUCKETS, Exporters::default())
+        );
     }
 
     #[test]
@@ -226,6 +553,143 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn shows_hits_and_limited_by_limit() {
+        let prometheus_metrics =
+            PrometheusMetrics::new_with_handle(true, TEST_PROMETHEUS_HANDLE.clone()).limit_metrics(
+                LimitMetricsConfiguration {
+                    limit_id: true,
+                    variables: vec![("user".to_string(), "descriptors[0].user_id".to_string())],
+                    metadata: vec![("team".to_string(), "team".to_string())],
+                },
+            );
+        assert!(prometheus_metrics.needs_counters());
+        let namespace: Namespace = "limit_metrics_by_limit".into();
+
+        let mut per_user = Limit::with_id(
+            "per_user",
+            namespace.clone(),
+            10,
+            60,
+            vec![],
+            vec!["descriptors[0].user_id"
+                .try_into()
+                .expect("failed parsing!")],
+        );
+        per_user.set_name("Per user".to_string());
+        per_user.set_metadata(BTreeMap::from([(
+            "team".to_string(),
+            "payments".to_string(),
+        )]));
+        let global = Limit::with_id("global", namespace.clone(), 100, 60, vec![], vec![]);
+        let counters = vec![
+            Counter::with_variables(
+                per_user,
+                BTreeMap::from([("descriptors[0].user_id".to_string(), "alice".to_string())]),
+            ),
+            Counter::with_variables(global, BTreeMap::default()),
+        ];
+        let statuses = counters
+            .iter()
+            .enumerate()
+            .map(|(i, counter)| CounterStatus {
+                limit_id: counter.id().map(str::to_owned),
+                limit_name: counter.limit().name().map(str::to_owned),
+                limit_metadata: counter.limit().metadata().clone(),
+                max_value: counter.max_value(),
+                window: counter.window(),
+                remaining: None,
+                resets_in: None,
+                limiting: i == 0,
+            })
+            .collect();
+        let checked = CheckResult {
+            limited: true,
+            counters,
+            limit_name: Some("Per user".to_string()),
+            statuses,
+        };
+        prometheus_metrics.record_limits(&namespace, &checked, 2);
+
+        let metrics_output = prometheus_metrics.gather_metrics();
+        for expected in [
+            "limitador_limit_hits_total{limitador_namespace=\"limit_metrics_by_limit\",limit_name=\"Per user\",limit_id=\"per_user\",user=\"alice\",team=\"payments\"} 2",
+            "limitador_limit_hits_total{limitador_namespace=\"limit_metrics_by_limit\",limit_name=\"\",limit_id=\"global\",user=\"\",team=\"\"} 2",
+            "limitador_limited_total{limitador_namespace=\"limit_metrics_by_limit\",limit_name=\"Per user\",limit_id=\"per_user\",user=\"alice\",team=\"payments\"} 1",
+        ] {
+            assert!(metrics_output.contains(expected), "missing: {expected}");
+        }
+        assert!(!metrics_output.contains("limitador_limited_total{limitador_namespace=\"limit_metrics_by_limit\",limit_name=\"\""));
+    }
+
+    #[test]
+    fn records_series_past_the_maximum_as_other() {
+        let prometheus_metrics =
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone()).max_series(1);
+
+        prometheus_metrics.incr_authorized_calls(&"max_series_kept".into());
+        prometheus_metrics.incr_authorized_calls(&"max_series_dropped".into());
+        prometheus_metrics.incr_authorized_calls(&"max_series_dropped".into());
+        prometheus_metrics.incr_authorized_calls(&"max_series_kept".into());
+
+        let metrics_output = prometheus_metrics.gather_metrics();
+        assert!(metrics_output.contains(&formatted_counter_with_namespace(
+            "authorized_calls",
+            2,
+            &"max_series_kept".into()
+        )));
+        assert!(!metrics_output.contains("max_series_dropped"));
+        assert!(metrics_output.contains(&formatted_counter_with_namespace(
+            "authorized_calls",
+            2,
+            &"other".into()
+        )));
+        assert!(metrics_output
+            .contains("limitador_metric_series_dropped_total{metric=\"authorized_calls\"} 1"));
+    }
+
+    #[test]
+    fn shows_only_the_latest_hottest_counters() {
+        let prometheus_metrics =
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone());
+        let limit = Limit::with_id(
+            "per_user",
+            "hottest_counters",
+            10,
+            60,
+            vec![],
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        let mut counter = Counter::with_variables(
+            limit,
+            BTreeMap::from([("user".to_string(), "a \"quoted\" user".to_string())]),
+        );
+        counter.set_remaining(3);
+
+        prometheus_metrics.set_hottest_counters(&[counter]);
+        let metrics_output = prometheus_metrics.gather_metrics();
+        let labels = "limitador_namespace=\"hottest_counters\",limit_name=\"\",limit_id=\"per_user\",variables=\"user=a \\\"quoted\\\" user\"";
+        assert!(metrics_output.contains(&format!("limitador_counter_used{{{labels}}} 7")));
+        assert!(metrics_output.contains(&format!("limitador_counter_remaining{{{labels}}} 3")));
+
+        prometheus_metrics.set_hottest_counters(&[]);
+        let metrics_output = prometheus_metrics.gather_metrics();
+        assert!(metrics_output.contains("# TYPE limitador_counter_used gauge"));
+        assert!(!metrics_output.contains("hottest_counters"));
+    }
+
+    #[test]
+    fn shows_latencies_as_histograms() {
+        let prometheus_metrics =
+            PrometheusMetrics::new_with_handle(false, TEST_PROMETHEUS_HANDLE.clone());
+        PrometheusMetrics::record_should_rate_limit_latency(Duration::from_millis(3));
+
+        let metrics_output = prometheus_metrics.gather_metrics();
+        assert!(metrics_output.contains("should_rate_limit_latency_bucket{le=\"0.0025\"} 0"));
+        assert!(metrics_output.contains("should_rate_limit_latency_bucket{le=\"0.005\"} 1"));
+        assert!(metrics_output.contains("should_rate_limit_latency_count 1"));
+    }
+
     #[test]
     fn shows_limitador_up_set_to_1() {
         let metrics_output =
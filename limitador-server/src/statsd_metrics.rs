@@ -0,0 +1,229 @@
+use metrics::{
+    Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Keeps the datagrams within the payload of an Ethernet frame
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+#[derive(Default)]
+struct Registry {
+    // The totals, along with the ones already sent
+    counters: Mutex<HashMap<Key, (Arc<AtomicU64>, u64)>>,
+    gauges: Mutex<HashMap<Key, Arc<AtomicU64>>>,
+    histograms: Mutex<HashMap<Key, Arc<Samples>>>,
+}
+
+#[derive(Default)]
+struct Samples(Mutex<Vec<f64>>);
+
+impl HistogramFn for Samples {
+    fn record(&self, value: f64) {
+        self.0.lock().unwrap().push(value);
+    }
+}
+
+/// Mirrors the metrics recorded, i.e. the ones served on `/metrics`, to a
+/// StatsD server, e.g. a DogStatsD agent, their labels sent as tags
+pub struct StatsdRecorder {
+    registry: Arc<Registry>,
+}
+
+impl StatsdRecorder {
+    /// Sends the metrics aggregated to `address` every `interval`, tagged
+    /// with `tags` on top of their own labels
+    pub fn new(
+        address: &str,
+        interval: Duration,
+        tags: Vec<(String, String)>,
+    ) -> Result<(Self, StatsdFlusher), String> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(address).map(|_| socket))
+            .map_err(|e| format!("Couldn't send metrics to '{address}': {e}"))?;
+        let flusher = StatsdFlusher {
+            registry: Arc::default(),
+            socket: Arc::new(socket),
+            tags: Arc::new(tags),
+        };
+        let periodic = flusher.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                periodic.flush();
+            }
+        });
+        Ok((
+            Self {
+                registry: Arc::clone(&flusher.registry),
+            },
+            flusher,
+        ))
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    // StatsD has no notion of descriptions, nor units
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut counters = self.registry.counters.lock().unwrap();
+        let (total, _) = counters.entry(key.clone()).or_default();
+        Counter::from_arc(Arc::clone(total))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.registry.gauges.lock().unwrap();
+        Gauge::from_arc(Arc::clone(gauges.entry(key.clone()).or_default()))
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let mut histograms = self.registry.histograms.lock().unwrap();
+        Histogram::from_arc(Arc::clone(histograms.entry(key.clone()).or_default()))
+    }
+}
+
+/// Sends what got recorded since the last flush
+#[derive(Clone)]
+pub struct StatsdFlusher {
+    registry: Arc<Registry>,
+    socket: Arc<UdpSocket>,
+    tags: Arc<Vec<(String, String)>>,
+}
+
+impl StatsdFlusher {
+    pub fn flush(&self) {
+        let mut lines = Vec::new();
+        for (key, (total, sent)) in self.registry.counters.lock().unwrap().iter_mut() {
+            let total = total.load(Ordering::Relaxed);
+            if total > *sent {
+                lines.push(self.line(key, &(total - *sent).to_string(), "c"));
+                *sent = total;
+            }
+        }
+        for (key, value) in self.registry.gauges.lock().unwrap().iter() {
+            let value = f64::from_bits(value.load(Ordering::Relaxed));
+            lines.push(self.line(key, &value.to_string(), "g"));
+        }
+        // The histograms are all of durations in seconds, sent as timings
+        for (key, samples) in self.registry.histograms.lock().unwrap().iter() {
+            let samples = std::mem::take(&mut *samples.0.lock().unwrap());
+            lines.extend(
+                samples
+                    .iter()
+                    .map(|seconds| self.line(key, &(seconds * 1000.0).to_string(), "ms")),
+            );
+        }
+
+        for datagram in pack(lines) {
+            if let Err(e) = self.socket.send(datagram.as_bytes()) {
+                debug!("Failed to send metrics to StatsD: {}", e);
+            }
+        }
+    }
+
+    // As `name:value|type|#tag:value,...`, the tags being DogStatsD's
+    // extension to the protocol
+    fn line(&self, key: &Key, value: &str, kind: &str) -> String {
+        let mut line = format!("{}:{value}|{kind}", key.name());
+        let labels = key.labels().map(|label| (label.key(), label.value()));
+        let tags = self.tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        for (i, (name, value)) in labels.chain(tags).enumerate() {
+            let separator = if i == 0 { "|#" } else { "," };
+            let _ = write!(line, "{separator}{}:{}", sanitize(name), sanitize(value));
+        }
+        line
+    }
+}
+
+// The characters delimiting the parts of a line can't be part of a tag
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | ',' | '#' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// Packs as many lines as fit in each datagram
+fn pack(lines: Vec<String>) -> Vec<String> {
+    let mut datagrams: Vec<String> = Vec::new();
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= MAX_DATAGRAM_SIZE => {
+                datagram.push('\n');
+                datagram.push_str(&line);
+            }
+            _ => datagrams.push(line),
+        }
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatsdRecorder;
+    use metrics::{Key, Label, Metadata, Recorder};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn sends_the_metrics_tagged_with_their_labels() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (recorder, flusher) = StatsdRecorder::new(
+            &server.local_addr().unwrap().to_string(),
+            Duration::from_secs(3600),
+            vec![("env".to_string(), "test".to_string())],
+        )
+        .unwrap();
+        let metadata = Metadata::new("test", metrics::Level::INFO, None);
+
+        let key = Key::from_parts(
+            "authorized_calls",
+            vec![Label::new("limitador_namespace", "ns")],
+        );
+        let counter = recorder.register_counter(&key, &metadata);
+        counter.increment(2);
+        counter.increment(1);
+        recorder
+            .register_histogram(&Key::from_name("should_rate_limit_latency"), &metadata)
+            .record(0.5);
+        flusher.flush();
+
+        let mut buf = [0; 1500];
+        let len = server.recv(&mut buf).unwrap();
+        let datagram = String::from_utf8_lossy(&buf[..len]).to_string();
+        let lines: Vec<_> = datagram.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "authorized_calls:3|c|#limitador_namespace:ns,env:test",
+                "should_rate_limit_latency:500|ms|#env:test",
+            ]
+        );
+
+        // Only what got counted since is sent on the next flush
+        counter.increment(1);
+        flusher.flush();
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..len]),
+            "authorized_calls:1|c|#limitador_namespace:ns,env:test"
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use limitador::limit::{InvalidLimit, Limit, LimitBuilder};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An issue with a limits file, located as precisely as possible
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    file: PathBuf,
+    // 1-based
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+            if let Some(column) = self.column {
+                write!(f, ":{column}")?;
+            }
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+struct Located {
+    file: PathBuf,
+    line: Option<usize>,
+    limit: Limit,
+}
+
+/// Validates the limits, printing the problems found, if any, and returns the
+/// exit code
+pub fn run<P: AsRef<Path>>(paths: &[P]) -> i32 {
+    let (valid, problems) = validate(paths);
+    if problems.is_empty() {
+        println!("{valid} limits are valid");
+        return 0;
+    }
+    for problem in &problems {
+        eprintln!("{problem}");
+    }
+    eprintln!("{} problem(s) found", problems.len());
+    1
+}
+
+/// Validates the limits files, or directories of them, as a whole: each limit
+/// must be valid, and defined only once across all of them
+pub fn validate<P: AsRef<Path>>(paths: &[P]) -> (usize, Vec<Problem>) {
+    let mut problems = Vec::new();
+    let mut limits: Vec<Located> = Vec::new();
+
+    for file in files(paths, &mut problems) {
+        let content = match fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) => {
+                problems.push(Problem {
+                    file,
+                    line: None,
+                    column: None,
+                    message: format!("couldn't read file: {e}"),
+                });
+                continue;
+            }
+        };
+        let parsed: Vec<Limit> = match serde_yaml::from_str(&content) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let location = e.location();
+                let mut message = e.to_string();
+                // The location is reported separately
+                if let Some(location) = &location {
+                    let suffix =
+                        format!(" at line {} column {}", location.line(), location.column());
+                    if message.ends_with(&suffix) {
+                        message.truncate(message.len() - suffix.len());
+                    }
+                }
+                problems.push(Problem {
+                    file,
+                    line: location.as_ref().map(|l| l.line()),
+                    column: location.as_ref().map(|l| l.column()),
+                    message,
+                });
+                continue;
+            }
+        };
+
+        let lines = item_lines(&content);
+        for (index, limit) in parsed.into_iter().enumerate() {
+            let line = lines.get(index).copied();
+            if let Err(e) = rebuild(&limit) {
+                problems.push(Problem {
+                    file: file.clone(),
+                    line,
+                    column: None,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+            if let Some(message) = clash(&limits, &limit) {
+                problems.push(Problem {
+                    file: file.clone(),
+                    line,
+                    column: None,
+                    message,
+                });
+                continue;
+            }
+            limits.push(Located {
+                file: file.clone(),
+                line,
+                limit,
+            });
+        }
+    }
+    (limits.len(), problems)
+}
+
+fn files<P: AsRef<Path>>(paths: &[P], problems: &mut Vec<Problem>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths.iter().map(AsRef::as_ref) {
+        if !path.is_dir() {
+            files.push(path.to_path_buf());
+            continue;
+        }
+        match fs::read_dir(path) {
+            Ok(entries) => {
+                let mut yaml_files: Vec<PathBuf> = entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|path| {
+                        path.is_file()
+                            && matches!(
+                                path.extension().and_then(|ext| ext.to_str()),
+                                Some("yaml" | "yml")
+                            )
+                    })
+                    .collect();
+                yaml_files.sort();
+                files.extend(yaml_files);
+            }
+            Err(e) => problems.push(Problem {
+                file: path.to_path_buf(),
+                line: None,
+                column: None,
+                message: format!("couldn't read directory: {e}"),
+            }),
+        }
+    }
+    files
+}
+
+// The checks that parsing alone doesn't do, e.g. a window of 0 seconds
+fn rebuild(limit: &Limit) -> Result<Limit, InvalidLimit> {
+    let mut builder = LimitBuilder::new(
+        limit.namespace().clone(),
+        limit.max_value(),
+        limit.seconds(),
+    );
+    if let Some(id) = limit.id() {
+        builder = builder.id(id);
+    }
+    for condition in limit.conditions() {
+        builder = builder.condition(condition);
+    }
+    for variable in limit.variables() {
+        builder = builder.variable(variable);
+    }
+    if let Some(max_value_from) = limit.max_value_from() {
+        builder = builder.max_value_from(max_value_from);
+    }
+    if let Some(cost) = limit.cost() {
+        builder = builder.cost(cost);
+    }
+    if let Some(ttl) = limit.ttl() {
+        builder = builder.ttl(ttl);
+    }
+    let mut rebuilt = builder.build()?;
+    for o in limit.overrides() {
+        rebuilt.set_override(o.variables().clone(), o.max_value())?;
+    }
+    Ok(rebuilt)
+}
+
+fn clash(limits: &[Located], limit: &Limit) -> Option<String> {
+    // Ids are unique across namespaces, as the counters are keyed by id alone
+    let same_id = |other: &Limit| other.id().is_some() && other.id() == limit.id();
+    let other = limits
+        .iter()
+        .find(|other| other.limit == *limit || same_id(&other.limit))?;
+    let location = match other.line {
+        Some(line) => format!("{}:{line}", other.file.display()),
+        None => other.file.display().to_string(),
+    };
+    let identical = other.limit == *limit
+        && other.limit.id() == limit.id()
+        && other.limit.max_value() == limit.max_value()
+        && other.limit.name() == limit.name();
+    Some(if identical {
+        format!("duplicate of the limit at {location}")
+    } else if other.limit != *limit {
+        format!(
+            "reuses the id `{}` of the limit at {location}",
+            limit.id().unwrap_or_default()
+        )
+    } else {
+        format!("conflicts with the limit at {location}")
+    })
+}
+
+// Where each item of the top level sequence starts, as serde_yaml doesn't
+// tell once parsed
+fn item_lines(content: &str) -> Vec<usize> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| *line == "-" || line.starts_with("- "))
+        .map(|(index, _)| index + 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate;
+    use std::fs;
+
+    #[test]
+    fn reports_problems_with_their_location() {
+        let path = std::env::temp_dir().join(format!("validate_{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        fs::write(
+            path.join("a.yaml"),
+            "- namespace: a\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n\
+             - namespace: a\n  max_value: 10\n  seconds: 0\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+        fs::write(
+            path.join("b.yaml"),
+            "- namespace: a\n  max_value: 20\n  seconds: 60\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+        fs::write(
+            path.join("c.yaml"),
+            "- namespace: c\n  max_value: ten\n  seconds: 60\n",
+        )
+        .unwrap();
+
+        let (valid, problems) = validate(&[&path]);
+        let problems: Vec<String> = problems
+            .iter()
+            .map(|problem| {
+                problem
+                    .to_string()
+                    .trim_start_matches(&format!("{}/", path.display()))
+                    .to_string()
+            })
+            .collect();
+
+        assert_eq!(valid, 1);
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], "a.yaml:6: invalid window of 0 seconds");
+        assert_eq!(
+            problems[1],
+            format!(
+                "b.yaml:1: conflicts with the limit at {}/a.yaml:1",
+                path.display()
+            )
+        );
+        assert!(problems[2].starts_with("c.yaml:2:"), "{}", problems[2]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_ids_reused_across_namespaces() {
+        let path = std::env::temp_dir().join(format!("validate_ids_{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        fs::write(
+            path.join("a.yaml"),
+            "- id: per-user\n  namespace: a\n  max_value: 10\n  seconds: 60\n  conditions: []\n  variables: []\n\
+             - id: per-user\n  namespace: b\n  max_value: 10\n  seconds: 3600\n  conditions: []\n  variables: []\n",
+        )
+        .unwrap();
+
+        let (valid, problems) = validate(&[&path]);
+
+        assert_eq!(valid, 1);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].to_string(),
+            format!(
+                "{0}/a.yaml:7: reuses the id `per-user` of the limit at {0}/a.yaml:1",
+                path.display()
+            )
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+//! Rate limits the requests to [tonic](https://docs.rs/tonic) gRPC services
+//! in-process with a Limitador [`RateLimiter`].
+//!
+//! The values of a request, i.e. its gRPC method, e.g.
+//! `/helloworld.Greeter/SayHello`, and metadata, are bound to
+//! `descriptors[0]`, as with the HTTP API of the server. Requests over the
+//! limits of the namespace are answered with a `RESOURCE_EXHAUSTED` status,
+//! detailing when to retry with a `google.rpc.RetryInfo`, as well as the
+//! `ratelimit-limit`, `ratelimit-remaining` and `ratelimit-reset` metadata:
+//!
+//! ```
+//! use limitador::RateLimiter;
+//! use limitador_tonic::RateLimitLayer;
+//! use std::sync::Arc;
+//!
+//! let limiter = Arc::new(RateLimiter::new(10_000));
+//! let layer = RateLimitLayer::new(limiter, "my_grpc_api")
+//!     .method("method")
+//!     .metadata("x-user-id", "user");
+//! // Server::builder().layer(layer).add_service(...)
+//! ```
+//!
+//! As tonic's interceptors only get to see the metadata of the requests, the
+//! [`RateLimitInterceptor`] they convert into doesn't bind the method.
+//!
+//! The limiter gets called on the task serving the request, so it's meant to
+//! be backed by one of the in-memory storages. Requests the limiter fails to
+//! check are let through.
+
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{HeaderMap, Request, Response};
+use limitador::limit::{Context, Namespace};
+use limitador::RateLimiter;
+use pin_project_lite::pin_project;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Rate limits the requests to the gRPC services it wraps, e.g. as a layer of
+/// a tonic `Server`, against the limits of a namespace
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    config: Arc<Config>,
+}
+
+#[derive(Clone)]
+struct Config {
+    limiter: Arc<RateLimiter>,
+    namespace: Namespace,
+    method: Option<String>,
+    // The names of the metadata, along with the keys their values get bound to
+    metadata: Vec<(String, String)>,
+}
+
+impl RateLimitLayer {
+    pub fn new<N: Into<Namespace>>(limiter: Arc<RateLimiter>, namespace: N) -> Self {
+        Self {
+            config: Arc::new(Config {
+                limiter,
+                namespace: namespace.into(),
+                method: None,
+                metadata: Vec::new(),
+            }),
+        }
+    }
+
+    /// Binds the gRPC method of the requests, e.g.
+    /// `/helloworld.Greeter/SayHello`, as `descriptors[0].<key>`
+    pub fn method<K: Into<String>>(mut self, key: K) -> Self {
+        Arc::make_mut(&mut self.config).method = Some(key.into());
+        self
+    }
+
+    /// Binds the value of the ASCII metadata `name` as `descriptors[0].<key>`,
+    /// when the requests have it
+    pub fn metadata<K: Into<String>>(mut self, name: &str, key: K) -> Self {
+        Arc::make_mut(&mut self.config)
+            .metadata
+            .push((name.to_ascii_lowercase(), key.into()));
+        self
+    }
+
+    /// The interceptor checking the requests the same way, but for their
+    /// method, which interceptors don't get to see
+    pub fn into_interceptor(self) -> RateLimitInterceptor {
+        RateLimitInterceptor {
+            config: self.config,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            config: Arc::clone(&self.config),
+        }
+    }
+}
+
+/// The service rate limiting the requests to the `inner` one, as built by the
+/// [`RateLimitLayer`]
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    config: Arc<Config>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let headers = req.headers();
+        let values = self.config.values(Some(req.uri().path()), |name| {
+            headers.get(name).and_then(|value| value.to_str().ok())
+        });
+        match self.config.check(values) {
+            Some(status) => ResponseFuture {
+                kind: Kind::Limited {
+                    response: Some(grpc_response(&status)),
+                },
+            },
+            None => ResponseFuture {
+                kind: Kind::Inner {
+                    future: self.inner.call(req),
+                },
+            },
+        }
+    }
+}
+
+/// A tonic interceptor rate limiting the requests by their metadata, as
+/// converted from a [`RateLimitLayer`]
+#[derive(Clone)]
+pub struct RateLimitInterceptor {
+    config: Arc<Config>,
+}
+
+impl Interceptor for RateLimitInterceptor {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        let metadata: &MetadataMap = request.metadata();
+        let values = self.config.values(None, |name| {
+            metadata.get(name).and_then(|value| value.to_str().ok())
+        });
+        match self.config.check(values) {
+            Some(status) => Err(status),
+            None => Ok(request),
+        }
+    }
+}
+
+impl Config {
+    fn values<'a>(
+        &self,
+        method: Option<&str>,
+        metadata: impl Fn(&str) -> Option<&'a str>,
+    ) -> HashMap<String, String> {
+        let mut values: HashMap<String, String> = self
+            .metadata
+            .iter()
+            .filter_map(|(name, key)| Some((key.clone(), metadata(name)?.to_string())))
+            .collect();
+        if let (Some(key), Some(method)) = (&self.method, method) {
+            values.insert(key.clone(), method.to_string());
+        }
+        values
+    }
+
+    // The status to answer the request with when limited
+    fn check(&self, values: HashMap<String, String>) -> Option<Status> {
+        let mut ctx = Context::default();
+        ctx.list_binding("descriptors".to_string(), vec![values]);
+        let result = self
+            .limiter
+            .check_rate_limited_and_update(&self.namespace, &ctx, 1, true)
+            .ok()
+            .filter(|result| result.limited)?;
+
+        let retry_delay = result
+            .statuses
+            .iter()
+            .find(|status| status.limiting)
+            .and_then(|status| status.resets_in);
+        let mut status = Status::with_error_details(
+            Code::ResourceExhausted,
+            "rate limited",
+            ErrorDetails::with_retry_info(retry_delay),
+        );
+        for (name, value) in result.ratelimit_headers() {
+            if let (Ok(name), Ok(value)) = (
+                MetadataKey::from_bytes(name.to_ascii_lowercase().as_bytes()),
+                MetadataValue::try_from(value.as_str()),
+            ) {
+                status.metadata_mut().insert(name, value);
+            }
+        }
+        Some(status)
+    }
+}
+
+// A gRPC response carries its status in its headers, as it has no message
+fn grpc_response<B: Default>(status: &Status) -> Response<B> {
+    let mut response = Response::new(B::default());
+    let headers: &mut HeaderMap = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    let _ = status.add_header(headers);
+    response
+}
+
+pin_project! {
+    /// The response of the inner service, or the one of a limited request
+    pub struct ResponseFuture<F, B> {
+        #[pin]
+        kind: Kind<F, B>,
+    }
+}
+
+pin_project! {
+    #[project = KindProj]
+    enum Kind<F, B> {
+        Inner { #[pin] future: F },
+        Limited { response: Option<Response<B>> },
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        match self.project().kind.project() {
+            KindProj::Inner { future } => future.poll(cx),
+            KindProj::Limited { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimitLayer;
+    use http::{Request, Response};
+    use limitador::limit::Limit;
+    use limitador::RateLimiter;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tonic::service::Interceptor;
+    use tonic::{Code, Status};
+    use tonic_types::StatusExt;
+    use tower::{service_fn, ServiceExt};
+    use tower_layer::Layer;
+
+    fn limiter() -> Arc<RateLimiter> {
+        let limiter = Arc::new(RateLimiter::new(100));
+        limiter
+            .add_limit(Limit::new(
+                "test_namespace",
+                1,
+                60,
+                vec!["descriptors[0].method == '/test.Greeter/SayHello'"
+                    .try_into()
+                    .expect("failed parsing!")],
+                vec!["descriptors[0].user".try_into().expect("failed parsing!")],
+            ))
+            .unwrap();
+        limiter
+    }
+
+    #[tokio::test]
+    async fn answers_the_requests_over_the_limits_with_resource_exhausted() {
+        let service = RateLimitLayer::new(limiter(), "test_namespace")
+            .method("method")
+            .metadata("x-user-id", "user")
+            .layer(service_fn(|_req: Request<String>| async {
+                Ok::<_, Infallible>(Response::new("hello".to_string()))
+            }));
+        let request = |method: &str| {
+            Request::builder()
+                .uri(format!("http://localhost{method}"))
+                .header("x-user-id", "alice")
+                .body(String::new())
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(request("/test.Greeter/SayHello"))
+            .await
+            .unwrap();
+        assert_eq!(response.body(), "hello");
+
+        let response = service
+            .clone()
+            .oneshot(request("/test.Greeter/SayHello"))
+            .await
+            .unwrap();
+        assert!(response.body().is_empty());
+        let status = Status::from_header_map(response.headers()).expect("must have a status");
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        let retry_info = status
+            .get_details_retry_info()
+            .expect("must have a retry info");
+        assert!(retry_info
+            .retry_delay
+            .is_some_and(|delay| delay.as_secs() <= 60));
+        assert_eq!(status.metadata().get("ratelimit-limit").unwrap(), "1");
+
+        let response = service
+            .clone()
+            .oneshot(request("/test.Greeter/SayGoodbye"))
+            .await
+            .unwrap();
+        assert_eq!(response.body(), "hello");
+    }
+
+    #[test]
+    fn intercepts_the_requests_by_their_metadata() {
+        let limiter = Arc::new(RateLimiter::new(100));
+        limiter
+            .add_limit(Limit::new(
+                "test_namespace",
+                1,
+                60,
+                vec![],
+                vec!["descriptors[0].user".try_into().expect("failed parsing!")],
+            ))
+            .unwrap();
+        let mut interceptor = RateLimitLayer::new(limiter, "test_namespace")
+            .metadata("x-user-id", "user")
+            .into_interceptor();
+        let request = || {
+            let mut request = tonic::Request::new(());
+            request
+                .metadata_mut()
+                .insert("x-user-id", "alice".parse().unwrap());
+            request
+        };
+
+        assert!(interceptor.call(request()).is_ok());
+        let status = interceptor.call(request()).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert!(status.get_details_retry_info().is_some());
+    }
+}
@@ -0,0 +1,299 @@
+//! A [tower](https://docs.rs/tower) middleware rate limiting requests
+//! in-process with a Limitador [`RateLimiter`], for axum or hyper services to
+//! limit themselves without a Limitador server.
+//!
+//! The values of a request are extracted from it, e.g. its method, path or
+//! headers, and bound to `descriptors[0]`, as with the HTTP API of the server.
+//! Requests over the limits of the namespace get answered with a
+//! `429 Too Many Requests`, along with the `RateLimit-Limit`,
+//! `RateLimit-Remaining` and `RateLimit-Reset` headers, without reaching the
+//! service:
+//!
+//! ```
+//! use limitador::limit::Limit;
+//! use limitador::RateLimiter;
+//! use limitador_tower::RateLimitLayer;
+//! use std::sync::Arc;
+//!
+//! let limiter = Arc::new(RateLimiter::new(10_000));
+//! limiter.add_limit(Limit::new(
+//!     "my_api",
+//!     10,
+//!     60,
+//!     vec!["descriptors[0].method == 'GET'".try_into().expect("failed parsing!")],
+//!     vec!["descriptors[0].user".try_into().expect("failed parsing!")],
+//! )).unwrap();
+//!
+//! let layer = RateLimitLayer::new(limiter, "my_api")
+//!     .method("method")
+//!     .path("path")
+//!     .header("x-user-id", "user");
+//! ```
+//!
+//! The limiter gets called on the task serving the request, so it's meant to
+//! be backed by one of the in-memory storages. Requests the limiter fails to
+//! check are let through.
+
+use http::header::{HeaderName, HeaderValue};
+use http::{Request, Response, StatusCode};
+use limitador::limit::{Context, Namespace};
+use limitador::{CheckResult, RateLimiter};
+use pin_project_lite::pin_project;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Rate limits the requests to the services it wraps, against the limits of a
+/// namespace
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    config: Arc<Config>,
+}
+
+#[derive(Clone)]
+struct Config {
+    limiter: Arc<RateLimiter>,
+    namespace: Namespace,
+    extractors: Vec<(String, Extractor)>,
+}
+
+#[derive(Clone)]
+enum Extractor {
+    Method,
+    Path,
+    Header(HeaderName),
+}
+
+impl RateLimitLayer {
+    pub fn new<N: Into<Namespace>>(limiter: Arc<RateLimiter>, namespace: N) -> Self {
+        Self {
+            config: Arc::new(Config {
+                limiter,
+                namespace: namespace.into(),
+                extractors: Vec::new(),
+            }),
+        }
+    }
+
+    /// Binds the method of the requests, e.g. `GET`, as `descriptors[0].<key>`
+    pub fn method<K: Into<String>>(self, key: K) -> Self {
+        self.extractor(key.into(), Extractor::Method)
+    }
+
+    /// Binds the path of the requests, without their query, as
+    /// `descriptors[0].<key>`
+    pub fn path<K: Into<String>>(self, key: K) -> Self {
+        self.extractor(key.into(), Extractor::Path)
+    }
+
+    /// Binds the value of the `header` as `descriptors[0].<key>`, when the
+    /// requests have it
+    ///
+    /// # Panics
+    ///
+    /// If `header` isn't a valid header name
+    pub fn header<K: Into<String>>(self, header: &str, key: K) -> Self {
+        let header = HeaderName::try_from(header).expect("invalid header name");
+        self.extractor(key.into(), Extractor::Header(header))
+    }
+
+    fn extractor(mut self, key: String, extractor: Extractor) -> Self {
+        Arc::make_mut(&mut self.config)
+            .extractors
+            .push((key, extractor));
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            config: Arc::clone(&self.config),
+        }
+    }
+}
+
+/// The service rate limiting the requests to the `inner` one, as built by the
+/// [`RateLimitLayer`]
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    config: Arc<Config>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimit<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future, ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let mut ctx = Context::default();
+        ctx.list_binding("descriptors".to_string(), vec![self.config.values(&req)]);
+        match self.config.limiter.check_rate_limited_and_update(
+            &self.config.namespace,
+            &ctx,
+            1,
+            true,
+        ) {
+            Ok(result) if result.limited => ResponseFuture {
+                kind: Kind::Limited {
+                    response: Some(too_many_requests(&result)),
+                },
+            },
+            _ => ResponseFuture {
+                kind: Kind::Inner {
+                    future: self.inner.call(req),
+                },
+            },
+        }
+    }
+}
+
+impl Config {
+    fn values<B>(&self, req: &Request<B>) -> HashMap<String, String> {
+        self.extractors
+            .iter()
+            .filter_map(|(key, extractor)| {
+                let value = match extractor {
+                    Extractor::Method => req.method().as_str(),
+                    Extractor::Path => req.uri().path(),
+                    Extractor::Header(header) => req.headers().get(header)?.to_str().ok()?,
+                };
+                Some((key.clone(), value.to_string()))
+            })
+            .collect()
+    }
+}
+
+fn too_many_requests<B: Default>(result: &CheckResult) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    for (name, value) in result.ratelimit_headers() {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    response
+}
+
+pin_project! {
+    /// The response of the inner service, or the one of a limited request
+    pub struct ResponseFuture<F, B> {
+        #[pin]
+        kind: Kind<F, B>,
+    }
+}
+
+pin_project! {
+    #[project = KindProj]
+    enum Kind<F, B> {
+        Inner { #[pin] future: F },
+        Limited { response: Option<Response<B>> },
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F, B>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<B>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        match self.project().kind.project() {
+            KindProj::Inner { future } => future.poll(cx),
+            KindProj::Limited { response } => {
+                Poll::Ready(Ok(response.take().expect("polled after completion")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimitLayer;
+    use http::{Request, Response, StatusCode};
+    use limitador::limit::Limit;
+    use limitador::RateLimiter;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tower::{service_fn, ServiceExt};
+    use tower_layer::Layer;
+
+    #[tokio::test]
+    async fn answers_the_requests_over_the_limits_with_a_429() {
+        let limiter = Arc::new(RateLimiter::new(100));
+        limiter
+            .add_limit(Limit::new(
+                "test_namespace",
+                2,
+                60,
+                vec!["descriptors[0].method == 'GET'"
+                    .try_into()
+                    .expect("failed parsing!")],
+                vec!["descriptors[0].user".try_into().expect("failed parsing!")],
+            ))
+            .unwrap();
+        let service = RateLimitLayer::new(limiter, "test_namespace")
+            .method("method")
+            .header("x-user-id", "user")
+            .layer(service_fn(|_req: Request<String>| async {
+                Ok::<_, Infallible>(Response::new("hello".to_string()))
+            }));
+
+        let request = |method: &str, user: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/")
+                .header("x-user-id", user)
+                .body(String::new())
+                .unwrap()
+        };
+
+        for _ in 0..2 {
+            let response = service
+                .clone()
+                .oneshot(request("GET", "alice"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.body(), "hello");
+        }
+        let response = service
+            .clone()
+            .oneshot(request("GET", "alice"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert!(response.body().is_empty());
+
+        let response = service
+            .clone()
+            .oneshot(request("POST", "alice"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = service
+            .clone()
+            .oneshot(request("GET", "bob"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::future::Future;
+use std::thread;
 use std::time::Instant;
 
 use criterion::{black_box, criterion_group, criterion_main, Bencher, BenchmarkId, Criterion};
@@ -8,6 +9,7 @@ use rand::seq::SliceRandom;
 use rand::SeedableRng;
 
 use limitador::limit::{Context, Limit};
+use limitador::storage::concurrent::ConcurrentStorage;
 #[cfg(feature = "disk_storage")]
 use limitador::storage::disk::{DiskStorage, OptimizeFor};
 #[cfg(feature = "distributed_storage")]
@@ -19,13 +21,28 @@ use limitador::storage::{AsyncCounterStorage, CounterStorage};
 use limitador::{AsyncRateLimiter, RateLimiter};
 
 const SEED: u64 = 42;
+// As many as the workers of a busy server, checking the limits all at once
+const CONTENDING_THREADS: usize = 64;
 
 #[cfg(all(not(feature = "disk_storage"), not(feature = "redis_storage")))]
-criterion_group!(benches, bench_in_mem);
+criterion_group!(benches, bench_in_mem, bench_concurrent, bench_contention);
 #[cfg(all(feature = "disk_storage", not(feature = "redis_storage")))]
-criterion_group!(benches, bench_in_mem, bench_disk);
+criterion_group!(
+    benches,
+    bench_in_mem,
+    bench_concurrent,
+    bench_contention,
+    bench_disk
+);
 #[cfg(all(not(feature = "disk_storage"), feature = "redis_storage"))]
-criterion_group!(benches, bench_in_mem, bench_redis, bench_cached_redis);
+criterion_group!(
+    benches,
+    bench_in_mem,
+    bench_concurrent,
+    bench_contention,
+    bench_redis,
+    bench_cached_redis
+);
 #[cfg(all(
     feature = "disk_storage",
     feature = "redis_storage",
@@ -34,6 +51,8 @@ criterion_group!(benches, bench_in_mem, bench_redis, bench_cached_redis);
 criterion_group!(
     benches,
     bench_in_mem,
+    bench_concurrent,
+    bench_contention,
     bench_disk,
     bench_redis,
     bench_cached_redis
@@ -46,6 +65,8 @@ criterion_group!(
 criterion_group!(
     benches,
     bench_in_mem,
+    bench_concurrent,
+    bench_contention,
     bench_disk,
     bench_redis,
     bench_cached_redis,
@@ -60,6 +81,12 @@ struct TestScenario {
     n_limits_per_ns: u32,
     n_conds_per_limit: u32,
     n_vars_per_limit: u32,
+    // The limits of a namespace are spread across that many routes, each
+    // request only meeting the conditions of the ones of its route
+    n_routes: u32,
+    // The distinct values each variable takes across the requests, i.e. the
+    // counters of each limit
+    n_values_per_var: u32,
 }
 
 const TEST_SCENARIOS: &[&TestScenario] = &[
@@ -68,24 +95,75 @@ const TEST_SCENARIOS: &[&TestScenario] = &[
         n_limits_per_ns: 50,
         n_conds_per_limit: 10,
         n_vars_per_limit: 0,
+        n_routes: 1,
+        n_values_per_var: 1,
     },
     &TestScenario {
         n_namespaces: 1,
         n_limits_per_ns: 1,
         n_conds_per_limit: 1,
         n_vars_per_limit: 1,
+        n_routes: 1,
+        n_values_per_var: 1,
     },
     &TestScenario {
         n_namespaces: 10,
         n_limits_per_ns: 10,
         n_conds_per_limit: 10,
         n_vars_per_limit: 10,
+        n_routes: 1,
+        n_values_per_var: 1,
     },
     &TestScenario {
         n_namespaces: 10,
         n_limits_per_ns: 50,
         n_conds_per_limit: 10,
         n_vars_per_limit: 10,
+        n_routes: 1,
+        n_values_per_var: 1,
+    },
+    // Many limits per namespace, only a few of which apply to each request
+    &TestScenario {
+        n_namespaces: 1,
+        n_limits_per_ns: 1_000,
+        n_conds_per_limit: 1,
+        n_vars_per_limit: 1,
+        n_routes: 250,
+        n_values_per_var: 1,
+    },
+    // High cardinality qualified counters
+    &TestScenario {
+        n_namespaces: 1,
+        n_limits_per_ns: 1,
+        n_conds_per_limit: 1,
+        n_vars_per_limit: 1,
+        n_routes: 1,
+        n_values_per_var: 10_000,
+    },
+    // The cost of evaluating ever more conditions
+    &TestScenario {
+        n_namespaces: 1,
+        n_limits_per_ns: 10,
+        n_conds_per_limit: 1,
+        n_vars_per_limit: 1,
+        n_routes: 1,
+        n_values_per_var: 1,
+    },
+    &TestScenario {
+        n_namespaces: 1,
+        n_limits_per_ns: 10,
+        n_conds_per_limit: 5,
+        n_vars_per_limit: 1,
+        n_routes: 1,
+        n_values_per_var: 1,
+    },
+    &TestScenario {
+        n_namespaces: 1,
+        n_limits_per_ns: 10,
+        n_conds_per_limit: 20,
+        n_vars_per_limit: 1,
+        n_routes: 1,
+        n_values_per_var: 1,
     },
 ];
 
@@ -101,7 +179,14 @@ impl Display for TestScenario {
             f,
             "{} namespaces with {} limits each with {} conditions and {} variables",
             self.n_namespaces, self.n_limits_per_ns, self.n_conds_per_limit, self.n_vars_per_limit
-        )
+        )?;
+        if self.n_routes > 1 {
+            write!(f, " across {} routes", self.n_routes)?;
+        }
+        if self.n_values_per_var > 1 {
+            write!(f, " of {} values", self.n_values_per_var)?;
+        }
+        Ok(())
     }
 }
 
@@ -136,6 +221,61 @@ fn bench_in_mem(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_concurrent(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Concurrent");
+    for scenario in TEST_SCENARIOS {
+        group.bench_with_input(
+            BenchmarkId::new("is_rate_limited", scenario),
+            scenario,
+            |b: &mut Bencher, test_scenario: &&TestScenario| {
+                let storage = Box::<ConcurrentStorage>::default();
+                bench_is_rate_limited(b, test_scenario, storage);
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("update_counters", scenario),
+            scenario,
+            |b: &mut Bencher, test_scenario: &&TestScenario| {
+                let storage = Box::<ConcurrentStorage>::default();
+                bench_update_counters(b, test_scenario, storage);
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("check_rate_limited_and_update", scenario),
+            scenario,
+            |b: &mut Bencher, test_scenario: &&TestScenario| {
+                let storage = Box::<ConcurrentStorage>::default();
+                bench_check_rate_limited_and_update(b, test_scenario, storage);
+            },
+        );
+    }
+    group.finish();
+}
+
+// Both in memory storages, under the contention of many threads
+fn bench_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Contention");
+    for scenario in TEST_SCENARIOS {
+        group.bench_with_input(
+            BenchmarkId::new("memory", scenario),
+            scenario,
+            |b: &mut Bencher, test_scenario: &&TestScenario| {
+                let storage = Box::<InMemoryStorage>::default();
+                bench_contended_check_rate_limited_and_update(b, test_scenario, storage);
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("concurrent", scenario),
+            scenario,
+            |b: &mut Bencher, test_scenario: &&TestScenario| {
+                let storage = Box::<ConcurrentStorage>::default();
+                bench_contended_check_rate_limited_and_update(b, test_scenario, storage);
+            },
+        );
+    }
+    group.finish();
+}
+
 #[cfg(feature = "distributed_storage")]
 fn bench_distributed(c: &mut Criterion) {
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -446,6 +586,45 @@ fn bench_check_rate_limited_and_update(
     })
 }
 
+// The iterations get split across the contending threads, each picking its
+// own requests
+fn bench_contended_check_rate_limited_and_update(
+    b: &mut Bencher,
+    test_scenario: &TestScenario,
+    storage: Box<dyn CounterStorage>,
+) {
+    storage.clear().unwrap();
+    let (rate_limiter, call_params) = generate_test_data(test_scenario, storage);
+
+    b.iter_custom(|iters| {
+        let per_thread = iters.div_ceil(CONTENDING_THREADS as u64);
+        let start = Instant::now();
+        thread::scope(|scope| {
+            for i in 0..CONTENDING_THREADS {
+                let rate_limiter = &rate_limiter;
+                let call_params = &call_params;
+                scope.spawn(move || {
+                    let rng = &mut rand::rngs::StdRng::seed_from_u64(SEED + i as u64);
+                    for _ in 0..per_thread {
+                        let params = call_params.choose(rng).unwrap();
+                        black_box(
+                            rate_limiter
+                                .check_rate_limited_and_update(
+                                    &params.namespace.to_owned().into(),
+                                    &params.ctx,
+                                    params.delta,
+                                    false,
+                                )
+                                .unwrap(),
+                        );
+                    }
+                });
+            }
+        });
+        start.elapsed()
+    })
+}
+
 fn async_bench_check_rate_limited_and_update<F>(
     runtime: &tokio::runtime::Runtime,
     b: &mut Bencher,
@@ -485,8 +664,8 @@ fn async_bench_check_rate_limited_and_update<F>(
 // greatest impact on performance.
 // The limits generated are big enough to avoid being rate-limited during the
 // benchmark.
-// Note that with this test data each request only increases one counter, we can
-// that as another variable in the future.
+// Note that with this test data each request only increases the counters of the
+// limits of a single route.
 fn generate_test_data(
     scenario: &TestScenario,
     storage: Box<dyn CounterStorage>,
@@ -495,7 +674,7 @@ fn generate_test_data(
 
     let (test_limits, call_params) = generate_test_limits(scenario);
     for limit in test_limits {
-        rate_limiter.add_limit(limit);
+        rate_limiter.add_limit(limit).unwrap();
     }
 
     (rate_limiter, call_params)
@@ -507,8 +686,8 @@ fn generate_test_data(
 // greatest impact on performance.
 // The limits generated are big enough to avoid being rate-limited during the
 // benchmark.
-// Note that with this test data each request only increases one counter, we can
-// that as another variable in the future.
+// Note that with this test data each request only increases the counters of the
+// limits of a single route.
 fn generate_async_test_data(
     scenario: &TestScenario,
     storage: Box<dyn AsyncCounterStorage>,
@@ -517,7 +696,7 @@ fn generate_async_test_data(
 
     let (test_limits, call_params) = generate_test_limits(scenario);
     for limit in test_limits {
-        rate_limiter.add_limit(limit);
+        rate_limiter.add_limit(limit).unwrap();
     }
 
     (rate_limiter, call_params)
@@ -551,20 +730,40 @@ fn generate_test_limits(scenario: &TestScenario) -> (Vec<Limit>, Vec<TestCallPar
         let namespace = idx_namespace.to_string();
 
         for limit_idx in 0..scenario.n_limits_per_ns {
+            let mut conditions = conditions.clone();
+            if scenario.n_routes > 1 {
+                let route = limit_idx % scenario.n_routes;
+                conditions.push(
+                    format!("route == 'route_{route}'")
+                        .try_into()
+                        .expect("failed parsing!"),
+                );
+            }
             test_limits.push(Limit::new(
                 namespace.clone(),
                 u64::MAX,
                 ((limit_idx * 60) + 10) as u64,
-                conditions.clone(),
+                conditions,
                 variables.clone(),
             ))
         }
 
-        call_params.push(TestCallParams {
-            namespace,
-            ctx: test_values.clone().into(),
-            delta: 1,
-        });
+        for route in 0..scenario.n_routes {
+            for value in 0..scenario.n_values_per_var {
+                let mut values = test_values.clone();
+                if scenario.n_routes > 1 {
+                    values.insert("route".to_string(), format!("route_{route}"));
+                }
+                for idx_var in 0..scenario.n_vars_per_limit {
+                    values.insert(format!("var_{idx_var}"), value.to_string());
+                }
+                call_params.push(TestCallParams {
+                    namespace: namespace.clone(),
+                    ctx: values.into(),
+                    delta: 1,
+                });
+            }
+        }
     }
     (test_limits, call_params)
 }
@@ -18,5 +18,25 @@ fn generate_protobuf() -> Result<(), Box<dyn Error>> {
             .compile_protos(&[proto_path], &[proto_dir])?;
     }
 
+    if cfg!(feature = "partitioned_storage") {
+        let proto_path: &Path = "proto/partitioned.proto".as_ref();
+
+        let proto_dir = proto_path
+            .parent()
+            .expect("proto file should reside in a directory");
+
+        tonic_build::configure().compile_protos(&[proto_path], &[proto_dir])?;
+    }
+
+    if cfg!(feature = "raft_storage") {
+        let proto_path: &Path = "proto/raft.proto".as_ref();
+
+        let proto_dir = proto_path
+            .parent()
+            .expect("proto file should reside in a directory");
+
+        tonic_build::configure().compile_protos(&[proto_path], &[proto_dir])?;
+    }
+
     Ok(())
 }
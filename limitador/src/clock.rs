@@ -0,0 +1,142 @@
+//! The time the windows of the counters are measured against: the one of the
+//! system, unless a clock got set, e.g. on `wasm32-unknown-unknown`, where
+//! there is none and the host has to provide it. Storages can also be given a
+//! [`Clock`] of their own, e.g. to replay traffic or test expiries without
+//! waiting. The in-memory ones default to a [`MonotonicClock`], which the
+//! system's being stepped doesn't affect.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+static CLOCK: OnceLock<fn() -> SystemTime> = OnceLock::new();
+static ANCHOR: OnceLock<(Instant, SystemTime)> = OnceLock::new();
+
+/// Has the time read from `clock`, e.g. the one of the host of a proxy-wasm
+/// filter, rather than from the system. It can only be set once, ahead of any
+/// counter being checked: `false` when a clock was already set.
+pub fn set_clock(clock: fn() -> SystemTime) -> bool {
+    CLOCK.set(clock).is_ok()
+}
+
+pub(crate) fn now() -> SystemTime {
+    match CLOCK.get() {
+        Some(clock) => clock(),
+        None => SystemTime::now(),
+    }
+}
+
+/// Tells the time the windows of the counters are measured against, for the
+/// storages it gets injected into, e.g. a [`ManualClock`] for tests to move
+/// time forward, rather than to wait for it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// The time of the system `at` the time of this clock stands for, e.g. to
+    /// report when a counter resets, or persist its expiry
+    fn to_system_time(&self, at: SystemTime) -> SystemTime {
+        at
+    }
+
+    /// The time of this clock the time of the system `at` stands for
+    fn from_system_time(&self, at: SystemTime) -> SystemTime {
+        at
+    }
+}
+
+/// The clock of the system, or the one set with [`set_clock`], which the
+/// storages default to
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        now()
+    }
+}
+
+/// The time of the system as of when it first got read, moved forward by a
+/// monotonic clock from then on: stepping the system's, e.g. by NTP, neither
+/// expires nor extends the counters measured against it. When a clock got set
+/// with [`set_clock`], it is that one instead.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> SystemTime {
+        if CLOCK.get().is_some() {
+            return now();
+        }
+        let (instant, time) = ANCHOR.get_or_init(|| (Instant::now(), SystemTime::now()));
+        *time + instant.elapsed()
+    }
+
+    fn to_system_time(&self, at: SystemTime) -> SystemTime {
+        shift(at, self.now(), now())
+    }
+
+    fn from_system_time(&self, at: SystemTime) -> SystemTime {
+        shift(at, now(), self.now())
+    }
+}
+
+// Moves `at` from the timeline `from` is the present of to the one of `to`
+fn shift(at: SystemTime, from: SystemTime, to: SystemTime) -> SystemTime {
+    match at.duration_since(from) {
+        Ok(ahead) => to + ahead,
+        Err(behind) => to - behind.duration(),
+    }
+}
+
+/// A clock that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, MonotonicClock};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn monotonic_clock_maps_to_the_system_time() {
+        let clock = MonotonicClock;
+        let before = clock.now();
+        assert!(clock.now() >= before);
+
+        let reset = clock.now() + Duration::from_secs(60);
+        let ttl = clock
+            .to_system_time(reset)
+            .duration_since(SystemTime::now())
+            .unwrap();
+        assert!(ttl <= Duration::from_secs(60) && ttl > Duration::from_secs(59));
+        let roundtrip = clock.from_system_time(clock.to_system_time(reset));
+        let drift = match roundtrip.duration_since(reset) {
+            Ok(ahead) => ahead,
+            Err(behind) => behind.duration(),
+        };
+        assert!(drift < Duration::from_secs(1));
+    }
+}
@@ -2,6 +2,8 @@ use crate::limit::{Context, Limit, Namespace};
 use crate::LimitadorResult;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::time::Duration;
@@ -10,24 +12,68 @@ use std::time::Duration;
 pub struct Counter {
     limit: Arc<Limit>,
 
-    set_variables: BTreeMap<String, String>,
+    // Shared by the clones of the counter, as well as with the other counters
+    // of the same request whose limits have the same variables
+    set_variables: Arc<BTreeMap<String, String>>,
 
     remaining: Option<u64>,
     expires_in: Option<Duration>,
+    #[serde(skip)]
+    cost: Option<u64>,
+    #[serde(skip)]
+    max_value: Option<u64>,
 }
 
 impl Counter {
     pub fn new<L: Into<Arc<Limit>>>(limit: L, ctx: &Context) -> LimitadorResult<Option<Self>> {
         let limit = limit.into();
-        let variables = limit.resolve_variables(ctx)?;
-        match variables {
+        match limit.resolve_variables(ctx)? {
             None => Ok(None),
-            Some(variables) => Ok(Some(Self {
-                limit,
-                set_variables: variables,
-                remaining: None,
-                expires_in: None,
-            })),
+            Some(variables) => {
+                Self::with_resolved_variables(limit, ctx, Arc::new(variables)).map(Some)
+            }
+        }
+    }
+
+    /// The counter of the `limit` for the `ctx`, whose variables resolved to
+    /// `variables`, e.g. for another limit with the same variables
+    pub(crate) fn with_resolved_variables(
+        limit: Arc<Limit>,
+        ctx: &Context,
+        variables: Arc<BTreeMap<String, String>>,
+    ) -> LimitadorResult<Self> {
+        let cost = limit.resolve_cost(ctx)?;
+        let max_value = limit.resolve_max_value_of(ctx, &variables)?;
+        Ok(Self {
+            limit,
+            set_variables: variables,
+            remaining: None,
+            expires_in: None,
+            cost,
+            max_value,
+        })
+    }
+
+    /// The counter of the `limit` for the given values of its variables, keyed
+    /// by their expression, e.g. to reset it. Values of variables the limit
+    /// doesn't have are ignored.
+    pub fn with_variables<L: Into<Arc<Limit>>>(
+        limit: L,
+        set_variables: BTreeMap<String, String>,
+    ) -> Self {
+        let limit = limit.into();
+        let variables = limit.variables();
+        let mut set_variables = set_variables;
+        set_variables.retain(|var, _| variables.contains(var));
+        let max_value = limit.max_value_override(&set_variables);
+
+        Self {
+            limit,
+            set_variables: Arc::new(set_variables),
+            remaining: None,
+            expires_in: None,
+            cost: None,
+            max_value,
         }
     }
 
@@ -38,12 +84,16 @@ impl Counter {
         let limit = limit.into();
         let mut vars = set_variables;
         vars.retain(|var, _| limit.has_variable(var));
+        let set_variables: BTreeMap<String, String> = vars.into_iter().collect();
+        let max_value = limit.max_value_override(&set_variables);
 
         Ok(Self {
             limit,
-            set_variables: vars.into_iter().collect(),
+            set_variables: Arc::new(set_variables),
             remaining: None,
             expires_in: None,
+            cost: None,
+            max_value,
         })
     }
 
@@ -51,9 +101,24 @@ impl Counter {
     pub(crate) fn key(&self) -> Self {
         Self {
             limit: Arc::clone(&self.limit),
-            set_variables: self.set_variables.clone(),
+            set_variables: Arc::clone(&self.set_variables),
             remaining: None,
             expires_in: None,
+            cost: self.cost,
+            max_value: self.max_value,
+        }
+    }
+
+    /// This counter, increased by exactly the hits it's given, e.g. to write
+    /// back deltas its cost got applied to already
+    pub(crate) fn without_cost(&self) -> Self {
+        Self {
+            limit: Arc::clone(&self.limit),
+            set_variables: Arc::clone(&self.set_variables),
+            remaining: None,
+            expires_in: None,
+            cost: None,
+            max_value: self.max_value,
         }
     }
 
@@ -61,18 +126,49 @@ impl Counter {
         &self.limit
     }
 
+    pub(crate) fn shared_limit(&self) -> Arc<Limit> {
+        Arc::clone(&self.limit)
+    }
+
     pub fn max_value(&self) -> u64 {
-        self.limit.max_value()
+        self.max_value.unwrap_or(self.limit.max_value())
     }
 
+    /// Makes this counter one of the `limit`, e.g. as decoded from a key that
+    /// only tells its definition, with the max value it overrides it with, if
+    /// any
     pub fn update_to_limit(&mut self, limit: Arc<Limit>) -> bool {
         if limit == self.limit {
+            self.max_value = limit.max_value_override(&self.set_variables);
             self.limit = limit;
             return true;
         }
         false
     }
 
+    /// The amount this counter is to be increased by for `hits` hits, i.e.
+    /// `hits` times the resolved cost of its limit, if any
+    pub fn delta(&self, hits: u64) -> u64 {
+        match self.cost {
+            Some(cost) => hits.saturating_mul(cost),
+            None => hits,
+        }
+    }
+
+    /// The delta of this counter for `hits` hits, unless it exceeds its max
+    /// value, in which case no request of as many hits could ever be admitted
+    pub fn checked_delta(&self, hits: u64) -> Result<u64, DeltaExceedsMaxValue> {
+        let delta = self.delta(hits);
+        if delta > self.max_value() {
+            return Err(DeltaExceedsMaxValue {
+                limit: self.shared_limit(),
+                delta,
+                max_value: self.max_value(),
+            });
+        }
+        Ok(delta)
+    }
+
     pub fn window(&self) -> Duration {
         Duration::from_secs(self.limit.seconds())
     }
@@ -109,10 +205,14 @@ impl Counter {
         !self.set_variables.is_empty()
     }
 
-    #[cfg(feature = "disk_storage")]
+    #[cfg(any(
+        feature = "disk_storage",
+        feature = "etcd_storage",
+        feature = "sqlite_storage"
+    ))]
     pub(crate) fn variables_for_key(&self) -> Vec<(&str, &str)> {
         let mut variables = Vec::with_capacity(self.set_variables.len());
-        for (var, value) in &self.set_variables {
+        for (var, value) in self.set_variables.iter() {
             variables.push((var.as_str(), value.as_str()));
         }
         variables.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
@@ -137,6 +237,44 @@ impl PartialEq for Counter {
     }
 }
 
+/// The delta of a request exceeds the max value of a limit that applies to
+/// it, which it could never be within
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaExceedsMaxValue {
+    limit: Arc<Limit>,
+    delta: u64,
+    max_value: u64,
+}
+
+impl DeltaExceedsMaxValue {
+    pub fn limit(&self) -> &Limit {
+        &self.limit
+    }
+
+    pub fn delta(&self) -> u64 {
+        self.delta
+    }
+
+    pub fn max_value(&self) -> u64 {
+        self.max_value
+    }
+}
+
+impl Display for DeltaExceedsMaxValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "delta {} exceeds the max value {} of limit `{}` of namespace `{}`",
+            self.delta,
+            self.max_value,
+            self.limit.name().unwrap_or("unnamed"),
+            self.limit.namespace().as_ref()
+        )
+    }
+}
+
+impl Error for DeltaExceedsMaxValue {}
+
 #[cfg(test)]
 mod tests {
     use crate::counter::Counter;
@@ -161,4 +299,17 @@ mod tests {
             Some("13".to_string()).as_ref()
         );
     }
+    #[test]
+    fn rejects_deltas_exceeding_the_max_value() {
+        let limit = Limit::new("ns", 10, 60, Vec::default(), Vec::default());
+        let ctx = HashMap::<String, String>::default().into();
+        let counter = Counter::new(limit, &ctx)
+            .expect("failed creating counter")
+            .unwrap();
+
+        assert_eq!(counter.checked_delta(10).unwrap(), 10);
+        let err = counter.checked_delta(u64::MAX).unwrap_err();
+        assert_eq!(err.delta(), u64::MAX);
+        assert_eq!(err.max_value(), 10);
+    }
 }
@@ -1,6 +1,7 @@
+use crate::counter::DeltaExceedsMaxValue;
 use crate::limit::EvaluationError;
 use crate::limit::ParseError;
-use crate::storage::StorageErr;
+use crate::storage::{LimitIdConflict, StorageErr};
 use std::convert::Infallible;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
@@ -9,6 +10,8 @@ use std::fmt::{Display, Formatter};
 pub enum LimitadorError {
     StorageError(StorageErr),
     InterpreterError(EvaluationError),
+    LimitIdConflict(LimitIdConflict),
+    DeltaExceedsMaxValue(DeltaExceedsMaxValue),
 }
 
 impl Display for LimitadorError {
@@ -20,6 +23,8 @@ impl Display for LimitadorError {
             LimitadorError::InterpreterError(err) => {
                 write!(f, "error parsing condition: {err:?}")
             }
+            LimitadorError::LimitIdConflict(err) => err.fmt(f),
+            LimitadorError::DeltaExceedsMaxValue(err) => err.fmt(f),
         }
     }
 }
@@ -29,6 +34,8 @@ impl Error for LimitadorError {
         match self {
             LimitadorError::StorageError(err) => Some(err),
             LimitadorError::InterpreterError(err) => Some(err),
+            LimitadorError::LimitIdConflict(err) => Some(err),
+            LimitadorError::DeltaExceedsMaxValue(err) => Some(err),
         }
     }
 }
@@ -45,6 +52,18 @@ impl From<EvaluationError> for LimitadorError {
     }
 }
 
+impl From<LimitIdConflict> for LimitadorError {
+    fn from(err: LimitIdConflict) -> Self {
+        LimitadorError::LimitIdConflict(err)
+    }
+}
+
+impl From<DeltaExceedsMaxValue> for LimitadorError {
+    fn from(err: DeltaExceedsMaxValue) -> Self {
+        LimitadorError::DeltaExceedsMaxValue(err)
+    }
+}
+
 impl From<Infallible> for ParseError {
     fn from(value: Infallible) -> Self {
         unreachable!("unexpected infallible value: {:?}", value)
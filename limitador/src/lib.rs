@@ -62,6 +62,19 @@
 //! Notice that the keys and variables are generic, so they do not necessarily
 //! have to refer to an HTTP request.
 //!
+//! Namespaces can be nested using `/` as a separator, e.g.
+//! `tenant-a/service-x`, once the storage is configured with
+//! [`Storage::hierarchical_namespaces`]. Checking a request against a child
+//! namespace then also evaluates the limits defined on all of its parents, so
+//! `tenant-a` can hold quotas shared by all of its services.
+//!
+//! A limit can also be defined on a namespace pattern, e.g. `api.*`, where `*`
+//! matches any sequence of characters. Such a limit applies to all the
+//! namespaces matching the pattern, see [`Namespace::is_pattern`]. Its counters
+//! are the ones of the pattern, i.e. shared by all these namespaces: requests
+//! to `api.foo` and `api.bar` count against the same ones, unless they are
+//! told apart by the limit's variables.
+//!
 //! # Manage limits
 //!
 //! ```
@@ -193,43 +206,145 @@
 // TODO this needs review to reduce the bloat pulled in by dependencies
 #![allow(clippy::multiple_crate_versions)]
 
-use crate::counter::Counter;
+use crate::counter::{Counter, DeltaExceedsMaxValue};
 use crate::errors::LimitadorError;
-use crate::limit::{Context, Limit, Namespace};
+use crate::limit::{Context, Expression, Limit, Namespace};
+use crate::storage::blocking::resolve;
 use crate::storage::in_memory::InMemoryStorage;
-use crate::storage::{AsyncCounterStorage, AsyncStorage, Authorization, CounterStorage, Storage};
-use std::collections::{HashMap, HashSet};
+use crate::storage::{
+    check_limit_ids, AsyncCounterStorage, AsyncStorage, Authorization, CounterFilter,
+    CounterStorage, CountersPage, LimitIdConflict, Storage,
+};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[macro_use]
 extern crate core;
 
+pub mod clock;
 pub mod counter;
 pub mod errors;
 pub mod limit;
+mod observe;
 pub mod storage;
+pub mod wire;
 
 pub struct RateLimiter {
-    storage: Storage,
+    limiter: AsyncRateLimiter,
 }
 
 pub struct AsyncRateLimiter {
     storage: AsyncStorage,
+    observers: Vec<Arc<dyn DecisionObserver>>,
+    idempotency_horizon: Duration,
 }
 
 pub struct RateLimiterBuilder {
     storage: Storage,
+    observers: Vec<Arc<dyn DecisionObserver>>,
+    idempotency_horizon: Duration,
 }
 
+/// How long the idempotency tokens of the requests are remembered for, by
+/// default
+pub const DEFAULT_IDEMPOTENCY_HORIZON_SEC: u64 = 60;
+
 type LimitadorResult<T> = Result<T, LimitadorError>;
 
+/// One of the requests checked together, e.g. the descriptors of an Envoy
+/// request, by `check_rate_limited_and_update_batch`
+pub struct CheckRequest<'a> {
+    pub namespace: Namespace,
+    pub ctx: Context<'a>,
+    pub delta: u64,
+}
+
 pub struct CheckResult {
     pub limited: bool,
     pub counters: Vec<Counter>,
     pub limit_name: Option<String>,
+    /// The status of every counter the request got checked against, when
+    /// loading the counters, in the order of the `counters`. Otherwise, only
+    /// the one of the counter that limited the request, if any
+    pub statuses: Vec<CounterStatus>,
+}
+
+/// Where a request stands against one of the limits that apply to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterStatus {
+    pub limit_id: Option<String>,
+    pub limit_name: Option<String>,
+    /// The metadata of the limit, e.g. the team owning it
+    pub limit_metadata: BTreeMap<String, String>,
+    pub max_value: u64,
+    pub window: Duration,
+    /// What's left of the limit, once the request is accounted for
+    pub remaining: Option<u64>,
+    /// How long until the counter resets
+    pub resets_in: Option<Duration>,
+    /// Whether this is the counter that limited the request
+    pub limiting: bool,
+}
+
+impl CounterStatus {
+    fn new(counter: &Counter, limiting: bool) -> Self {
+        Self {
+            limit_id: counter.id().map(|id| id.to_owned()),
+            limit_name: counter.limit().name().map(|name| name.to_owned()),
+            limit_metadata: counter.limit().metadata().clone(),
+            max_value: counter.max_value(),
+            window: counter.window(),
+            remaining: counter.remaining(),
+            resets_in: counter.expires_in(),
+            limiting,
+        }
+    }
 }
 
 impl CheckResult {
+    fn new(counters: Vec<Counter>, authorization: Authorization, load_counters: bool) -> Self {
+        let limiting = match &authorization {
+            Authorization::Ok => None,
+            Authorization::Limited(limit) => limit
+                .as_ref()
+                .and_then(|limit| counters.iter().position(|c| c.limit() == &**limit)),
+        };
+        let statuses = if load_counters {
+            counters
+                .iter()
+                .enumerate()
+                .map(|(i, counter)| CounterStatus::new(counter, Some(i) == limiting))
+                .collect()
+        } else {
+            limiting
+                .map(|i| vec![CounterStatus::new(&counters[i], true)])
+                .unwrap_or_default()
+        };
+
+        let counters = if load_counters {
+            counters
+        } else {
+            Vec::default()
+        };
+
+        match authorization {
+            Authorization::Ok => Self {
+                limited: false,
+                counters,
+                limit_name: None,
+                statuses,
+            },
+            Authorization::Limited(limit) => Self {
+                limited: true,
+                counters,
+                limit_name: limit.and_then(|limit| limit.name().map(|name| name.to_owned())),
+                statuses,
+            },
+        }
+    }
+
     pub fn response_header(&mut self) -> HashMap<String, String> {
         let mut headers = HashMap::new();
         // sort by the limit remaining..
@@ -270,6 +385,63 @@ impl CheckResult {
         }
         headers
     }
+
+    /// The `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+    /// headers of the IETF draft, for the most constrained of the counters
+    pub fn ratelimit_headers(&self) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        let Some(counter) = self
+            .counters
+            .iter()
+            .min_by_key(|counter| counter.remaining().unwrap_or(counter.max_value()))
+        else {
+            return headers;
+        };
+
+        headers.insert(
+            "RateLimit-Limit".to_string(),
+            counter.max_value().to_string(),
+        );
+        headers.insert(
+            "RateLimit-Remaining".to_string(),
+            counter
+                .remaining()
+                .unwrap_or(counter.max_value())
+                .to_string(),
+        );
+        if let Some(duration) = counter.expires_in() {
+            headers.insert(
+                "RateLimit-Reset".to_string(),
+                duration.as_secs().to_string(),
+            );
+        }
+        headers
+    }
+}
+
+/// A request having been checked against the limits of a namespace
+#[derive(Debug)]
+pub struct DecisionEvent<'a> {
+    pub namespace: &'a Namespace,
+    /// The limits that applied to the request
+    pub limits: Vec<&'a Limit>,
+    pub delta: u64,
+    pub limited: bool,
+    /// The limit that limited the request, when known
+    pub limited_by: Option<&'a Limit>,
+}
+
+/// Gets notified of every decision taken by a rate limiter, e.g. for logging
+/// or billing purposes. It gets called on the request path, so it must not
+/// block.
+pub trait DecisionObserver: Send + Sync {
+    fn on_decision(&self, event: &DecisionEvent);
+}
+
+impl<F: Fn(&DecisionEvent) + Send + Sync> DecisionObserver for F {
+    fn on_decision(&self, event: &DecisionEvent) {
+        self(event)
+    }
 }
 
 impl From<CheckResult> for bool {
@@ -280,13 +452,15 @@ impl From<CheckResult> for bool {
 
 impl RateLimiterBuilder {
     pub fn with_storage(storage: Storage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            observers: Vec::new(),
+            idempotency_horizon: Duration::from_secs(DEFAULT_IDEMPOTENCY_HORIZON_SEC),
+        }
     }
 
     pub fn new(cache_size: u64) -> Self {
-        Self {
-            storage: Storage::new(cache_size),
-        }
+        Self::with_storage(Storage::new(cache_size))
     }
 
     pub fn storage(mut self, storage: Storage) -> Self {
@@ -294,88 +468,131 @@ impl RateLimiterBuilder {
         self
     }
 
+    /// Registers an observer notified of every check, in registration order
+    pub fn observer(mut self, observer: Arc<dyn DecisionObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// How long the idempotency tokens of the requests are remembered for,
+    /// i.e. within which their retries aren't accounted for again
+    pub fn idempotency_horizon(mut self, horizon: Duration) -> Self {
+        self.idempotency_horizon = horizon;
+        self
+    }
+
     pub fn build(self) -> RateLimiter {
+        let mut builder = AsyncRateLimiterBuilder::new(self.storage.into_async())
+            .idempotency_horizon(self.idempotency_horizon);
+        builder.observers = self.observers;
         RateLimiter {
-            storage: self.storage,
+            limiter: builder.build(),
         }
     }
 }
 
 pub struct AsyncRateLimiterBuilder {
     storage: AsyncStorage,
+    observers: Vec<Arc<dyn DecisionObserver>>,
+    idempotency_horizon: Duration,
 }
 
 impl AsyncRateLimiterBuilder {
     pub fn new(storage: AsyncStorage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            observers: Vec::new(),
+            idempotency_horizon: Duration::from_secs(DEFAULT_IDEMPOTENCY_HORIZON_SEC),
+        }
+    }
+
+    /// Registers an observer notified of every check, in registration order
+    pub fn observer(mut self, observer: Arc<dyn DecisionObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// How long the idempotency tokens of the requests are remembered for,
+    /// i.e. within which their retries aren't accounted for again
+    pub fn idempotency_horizon(mut self, horizon: Duration) -> Self {
+        self.idempotency_horizon = horizon;
+        self
     }
 
     pub fn build(self) -> AsyncRateLimiter {
         AsyncRateLimiter {
             storage: self.storage,
+            observers: self.observers,
+            idempotency_horizon: self.idempotency_horizon,
         }
     }
 }
 
+// The blocking limiter is the async one, over a storage whose calls resolve
+// right away
 impl RateLimiter {
     pub fn new(cache_size: u64) -> Self {
-        Self {
-            storage: Storage::new(cache_size),
-        }
+        RateLimiterBuilder::new(cache_size).build()
     }
 
     pub fn new_with_storage(counters: Box<dyn CounterStorage>) -> Self {
-        Self {
-            storage: Storage::with_counter_storage(counters),
-        }
+        RateLimiterBuilder::with_storage(Storage::with_counter_storage(counters)).build()
     }
 
+    /// The concrete namespaces limits are defined on, i.e. not the patterns
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
-        self.storage.get_namespaces()
+        self.limiter.get_namespaces()
     }
 
-    pub fn add_limit(&self, limit: Limit) -> bool {
-        self.storage.add_limit(limit)
+    /// The namespace patterns limits are defined on
+    pub fn get_namespace_patterns(&self) -> HashSet<Namespace> {
+        self.limiter.get_namespace_patterns()
+    }
+
+    pub fn add_limit(&self, limit: Limit) -> Result<bool, LimitIdConflict> {
+        self.limiter.add_limit(limit)
     }
 
     pub fn delete_limit(&self, limit: &Limit) -> LimitadorResult<()> {
-        self.storage.delete_limit(limit)?;
-        Ok(())
+        resolve(self.limiter.delete_limit(limit))
+    }
+
+    /// Updates the limit with the id of the `limit`, or with its definition
+    /// when it has none, see [`AsyncStorage::update_limit`]
+    pub fn update_limit(&self, limit: &Limit) -> LimitadorResult<bool> {
+        resolve(self.limiter.update_limit(limit))
     }
 
     pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit> {
-        self.storage
-            .get_limits(namespace)
-            .iter()
-            .map(|l| (**l).clone())
-            .collect()
+        self.limiter.get_limits(namespace)
     }
 
     pub fn delete_limits(&self, namespace: &Namespace) -> LimitadorResult<()> {
-        self.storage.delete_limits(namespace)?;
-        Ok(())
+        resolve(self.limiter.delete_limits(namespace))
+    }
+
+    pub fn delete_expired_limits(&self) -> LimitadorResult<()> {
+        resolve(self.limiter.delete_expired_limits())
+    }
+
+    /// Writes the counter updates the storage holds back out, e.g. before
+    /// shutting down
+    pub fn flush(&self) -> LimitadorResult<()> {
+        resolve(self.limiter.flush())
+    }
+
+    /// Checks that the storage of the counters can be reached
+    pub fn ping(&self) -> LimitadorResult<()> {
+        resolve(self.limiter.ping())
     }
 
     pub fn is_rate_limited(
         &self,
         namespace: &Namespace,
-        values: &Context,
+        ctx: &Context,
         delta: u64,
     ) -> LimitadorResult<bool> {
-        let counters = self.counters_that_apply(namespace, values)?;
-
-        for counter in counters {
-            match self.storage.is_within_limits(&counter, delta) {
-                Ok(within_limits) => {
-                    if !within_limits {
-                        return Ok(true);
-                    }
-                }
-                Err(e) => return Err(e.into()),
-            }
-        }
-
-        Ok(false)
+        resolve(self.limiter.is_rate_limited(namespace, ctx, delta))
     }
 
     pub fn update_counters(
@@ -384,12 +601,13 @@ impl RateLimiter {
         ctx: &Context,
         delta: u64,
     ) -> LimitadorResult<()> {
-        let counters = self.counters_that_apply(namespace, ctx)?;
+        resolve(self.limiter.update_counters(namespace, ctx, delta))
+    }
 
-        counters
-            .iter()
-            .try_for_each(|counter| self.storage.update_counter(counter, delta))
-            .map_err(|err| err.into())
+    /// Gives `delta` back to the counters of the limits that apply, e.g. when
+    /// the request it got taken for failed upstream. Counters never go below 0
+    pub fn refund(&self, namespace: &Namespace, ctx: &Context, delta: u64) -> LimitadorResult<()> {
+        resolve(self.limiter.refund(namespace, ctx, delta))
     }
 
     pub fn check_rate_limited_and_update(
@@ -399,116 +617,126 @@ impl RateLimiter {
         delta: u64,
         load_counters: bool,
     ) -> LimitadorResult<CheckResult> {
-        let mut counters = self.counters_that_apply(namespace, ctx)?;
-
-        if counters.is_empty() {
-            return Ok(CheckResult {
-                limited: false,
-                counters,
-                limit_name: None,
-            });
-        }
-
-        let check_result = self
-            .storage
-            .check_and_update(&mut counters, delta, load_counters)?;
+        resolve(
+            self.limiter
+                .check_rate_limited_and_update(namespace, ctx, delta, load_counters),
+        )
+    }
 
-        let counters = if load_counters {
-            counters
-        } else {
-            Vec::default()
-        };
+    /// Checks and updates the counters as `check_rate_limited_and_update`
+    /// does, unless the request is a retry of one carrying the same
+    /// idempotency `token`, see [`AsyncRateLimiter::check_rate_limited_and_update_idempotent`]
+    pub fn check_rate_limited_and_update_idempotent(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context,
+        delta: u64,
+        load_counters: bool,
+        token: &str,
+    ) -> LimitadorResult<CheckResult> {
+        resolve(self.limiter.check_rate_limited_and_update_idempotent(
+            namespace,
+            ctx,
+            delta,
+            load_counters,
+            token,
+        ))
+    }
 
-        match check_result {
-            Authorization::Ok => Ok(CheckResult {
-                limited: false,
-                counters,
-                limit_name: None,
-            }),
-            Authorization::Limited(name) => Ok(CheckResult {
-                limited: true,
-                counters,
-                limit_name: name,
-            }),
-        }
+    pub fn check_rate_limited_and_update_batch(
+        &self,
+        requests: Vec<CheckRequest>,
+        load_counters: bool,
+    ) -> LimitadorResult<Vec<CheckResult>> {
+        resolve(
+            self.limiter
+                .check_rate_limited_and_update_batch(requests, load_counters),
+        )
     }
 
     pub fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>> {
-        self.storage
-            .get_counters(namespace)
-            .map_err(|err| err.into())
+        resolve(self.limiter.get_counters(namespace))
+    }
+
+    /// Checks whether the request would be limited, and what's left of each
+    /// of the limits that apply to it, without updating any counter
+    pub fn peek(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        resolve(self.limiter.peek(namespace, ctx, delta))
     }
 
     // Deletes all the limits stored except the ones received in the params. For
     // every limit received, if it does not exist, it is created. If it already
     // exists, its associated counters are not reset.
-    pub fn configure_with(&self, limits: impl IntoIterator<Item = Limit>) -> LimitadorResult<()> {
-        let limits_to_keep_or_create = classify_limits_by_namespace(limits);
-
-        let namespaces_limits_to_keep_or_create: HashSet<Namespace> =
-            limits_to_keep_or_create.keys().cloned().collect();
-
-        for namespace in self
-            .get_namespaces()
-            .union(&namespaces_limits_to_keep_or_create)
-        {
-            let limits_in_namespace = self.get_limits(namespace);
-            let limits_to_keep_in_ns: HashSet<Limit> = limits_to_keep_or_create
-                .get(namespace)
-                .cloned()
-                .unwrap_or_default();
+    /// Up to `page_size` of the counters of the `namespace` matching the
+    /// `filter`. The next page is the one the returned cursor points to.
+    pub fn get_counters_page(
+        &self,
+        namespace: &Namespace,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> LimitadorResult<CountersPage> {
+        resolve(
+            self.limiter
+                .get_counters_page(namespace, filter, cursor, page_size),
+        )
+    }
 
-            for limit in limits_in_namespace.difference(&limits_to_keep_in_ns) {
-                self.delete_limit(limit)?;
-            }
+    /// Resets the `counter`, e.g. the one of a given user, as if it was never
+    /// hit
+    pub fn reset_counter(&self, counter: &Counter) -> LimitadorResult<()> {
+        resolve(self.limiter.reset_counter(counter))
+    }
 
-            for limit in limits_to_keep_in_ns.difference(&limits_in_namespace) {
-                self.add_limit(limit.clone());
-            }
+    /// Resets all the counters of the `limit`, which is kept
+    pub fn reset_counters_of_limit(&self, limit: &Limit) -> LimitadorResult<()> {
+        resolve(self.limiter.reset_counters_of_limit(limit))
+    }
 
-            for limit in limits_to_keep_in_ns.union(&limits_in_namespace) {
-                self.storage.update_limit(limit);
-            }
-        }
+    /// Resets all the counters of the limits in the `namespace`, which are
+    /// kept
+    pub fn reset_counters(&self, namespace: &Namespace) -> LimitadorResult<()> {
+        resolve(self.limiter.reset_counters(namespace))
+    }
 
-        Ok(())
+    pub fn configure_with(&self, limits: impl IntoIterator<Item = Limit>) -> LimitadorResult<()> {
+        resolve(self.limiter.configure_with(limits))
     }
 
-    fn counters_that_apply(
+    /// Replaces the limits of the `namespace` with the given ones, all at
+    /// once. Only the counters of the limits whose definition, i.e. window,
+    /// conditions or variables, changed are reset. Updating the max value of a
+    /// limit keeps its counters, rescaled if the storage is set to.
+    pub fn replace_limits(
         &self,
         namespace: &Namespace,
-        ctx: &Context,
-    ) -> LimitadorResult<Vec<Counter>> {
-        let limits = self.storage.get_limits(namespace);
-        limits
-            .iter()
-            .filter(|lim| lim.applies(ctx))
-            .filter_map(|lim| match Counter::new(Arc::clone(lim), ctx) {
-                Ok(None) => None,
-                Ok(Some(c)) => Some(Ok(c)),
-                Err(e) => Some(Err(e)),
-            })
-            .collect()
+        limits: impl IntoIterator<Item = Limit>,
+    ) -> LimitadorResult<()> {
+        resolve(self.limiter.replace_limits(namespace, limits))
     }
 }
 
-// TODO: the code of this implementation is almost identical to the blocking
-// one. The only exception is that the functions defined are "async" and all the
-// calls to the storage need to include ".await". We'll need to think about how
-// to remove this duplication.
-
 impl AsyncRateLimiter {
     pub fn new_with_storage(storage: Box<dyn AsyncCounterStorage>) -> Self {
-        Self {
-            storage: AsyncStorage::with_counter_storage(storage),
-        }
+        AsyncRateLimiterBuilder::new(AsyncStorage::with_counter_storage(storage)).build()
     }
 
+    /// The concrete namespaces limits are defined on, i.e. not the patterns
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
         self.storage.get_namespaces()
     }
 
-    pub fn add_limit(&self, limit: Limit) -> bool {
+    /// The namespace patterns limits are defined on
+    pub fn get_namespace_patterns(&self) -> HashSet<Namespace> {
+        self.storage.get_namespace_patterns()
+    }
+
+    pub fn add_limit(&self, limit: Limit) -> Result<bool, LimitIdConflict> {
         self.storage.add_limit(limit)
     }
 
@@ -517,6 +745,12 @@ impl AsyncRateLimiter {
         Ok(())
     }
 
+    /// Updates the limit with the id of the `limit`, or with its definition
+    /// when it has none, see [`AsyncStorage::update_limit`]
+    pub async fn update_limit(&self, limit: &Limit) -> LimitadorResult<bool> {
+        Ok(self.storage.update_limit(limit).await?)
+    }
+
     pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Limit> {
         self.storage
             .get_limits(namespace)
@@ -530,25 +764,53 @@ impl AsyncRateLimiter {
         Ok(())
     }
 
+    pub async fn delete_expired_limits(&self) -> LimitadorResult<()> {
+        self.storage.delete_expired_limits().await?;
+        Ok(())
+    }
+
+    /// Writes the counter updates the storage holds back out, e.g. before
+    /// shutting down
+    pub async fn flush(&self) -> LimitadorResult<()> {
+        self.storage.flush().await?;
+        Ok(())
+    }
+
+    /// Checks that the storage of the counters can be reached
+    pub async fn ping(&self) -> LimitadorResult<()> {
+        self.storage.ping().await?;
+        Ok(())
+    }
+
     pub async fn is_rate_limited(
         &self,
         namespace: &Namespace,
         ctx: &Context<'_>,
         delta: u64,
     ) -> LimitadorResult<bool> {
-        let counters = self.counters_that_apply(namespace, ctx).await?;
+        if self.observers.is_empty() {
+            if let Some(counters) = self.storage.blocking_counters() {
+                return self.is_rate_limited_in_place(counters, namespace, ctx, delta);
+            }
+        }
 
-        for counter in counters {
-            match self.storage.is_within_limits(&counter, delta).await {
+        let counters = self.counters_that_apply(namespace, ctx)?;
+
+        let mut limited_by = None;
+        for counter in &counters {
+            match self.storage.is_within_limits(counter, delta).await {
                 Ok(within_limits) => {
                     if !within_limits {
-                        return Ok(true);
+                        limited_by = Some(counter.limit());
+                        break;
                     }
                 }
                 Err(e) => return Err(e.into()),
             }
         }
-        Ok(false)
+        let limited = limited_by.is_some();
+        self.notify(namespace, &counters, delta, limited, limited_by);
+        Ok(limited)
     }
 
     pub async fn update_counters(
@@ -557,7 +819,8 @@ impl AsyncRateLimiter {
         ctx: &Context<'_>,
         delta: u64,
     ) -> LimitadorResult<()> {
-        let counters = self.counters_that_apply(namespace, ctx).await?;
+        let counters = self.counters_that_apply(namespace, ctx)?;
+        check_deltas(&counters, delta)?;
 
         for counter in counters {
             self.storage.update_counter(&counter, delta).await?
@@ -566,6 +829,27 @@ impl AsyncRateLimiter {
         Ok(())
     }
 
+    /// Gives `delta` back to the counters of the limits that apply, e.g. when
+    /// the request it got taken for failed upstream. Counters never go below 0
+    pub async fn refund(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<()> {
+        let counters = self.counters_that_apply(namespace, ctx)?;
+
+        for counter in counters {
+            self.storage.refund_counter(&counter, delta).await?
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether the request is within the limits that apply to it, and
+    /// if so, updates their counters by `delta`. Fails with a
+    /// [`LimitadorError::DeltaExceedsMaxValue`] when `delta` exceeds the max
+    /// value of any of them, as the request could never be within it.
     pub async fn check_rate_limited_and_update(
         &self,
         namespace: &Namespace,
@@ -573,40 +857,126 @@ impl AsyncRateLimiter {
         delta: u64,
         load_counters: bool,
     ) -> LimitadorResult<CheckResult> {
-        // the above where-clause is needed in order to call unwrap().
-        let mut counters = self.counters_that_apply(namespace, ctx).await?;
+        let mut counters = self.counters_that_apply(namespace, ctx)?;
 
         if counters.is_empty() {
+            self.notify(namespace, &counters, delta, false, None);
             return Ok(CheckResult {
                 limited: false,
                 counters,
                 limit_name: None,
+                statuses: Vec::default(),
             });
         }
+        check_deltas(&counters, delta)?;
 
         let check_result = self
             .storage
             .check_and_update(&mut counters, delta, load_counters)
             .await?;
 
-        let counters = if load_counters {
-            counters
+        match &check_result {
+            Authorization::Ok => self.notify(namespace, &counters, delta, false, None),
+            Authorization::Limited(limit) => {
+                self.notify(namespace, &counters, delta, true, limit.as_deref())
+            }
+        }
+        Ok(CheckResult::new(counters, check_result, load_counters))
+    }
+
+    /// Checks and updates the counters as `check_rate_limited_and_update`
+    /// does, unless a request carrying the same idempotency `token` got
+    /// accounted for within the idempotency horizon, e.g. one a proxy retries
+    /// after timing out. The retry is let through, as the original request
+    /// was, without its hits being counted again, nor it being reported to the
+    /// observers. The tokens of the requests that got limited are forgotten,
+    /// for their retries to be checked as any other request.
+    pub async fn check_rate_limited_and_update_idempotent(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+        load_counters: bool,
+        token: &str,
+    ) -> LimitadorResult<CheckResult> {
+        if !self
+            .storage
+            .claim_token(namespace, token, self.idempotency_horizon)
+            .await?
+        {
+            let mut counters = self.counters_that_apply(namespace, ctx)?;
+            if load_counters && !counters.is_empty() {
+                self.storage.peek(&mut counters, 0).await?;
+            }
+            return Ok(CheckResult::new(counters, Authorization::Ok, load_counters));
+        }
+
+        match self
+            .check_rate_limited_and_update(namespace, ctx, delta, load_counters)
+            .await
+        {
+            Ok(result) if !result.limited => Ok(result),
+            result => {
+                self.storage.release_token(namespace, token).await?;
+                result
+            }
+        }
+    }
+
+    /// Checks and updates the counters of each of the `requests` on its own,
+    /// as `check_rate_limited_and_update` would, but all in a single call to
+    /// the storage, e.g. one round trip to Redis. The results are in the order
+    /// of the `requests`. Fails as a whole when the delta of any of them
+    /// exceeds the max value of a limit that applies to it.
+    pub async fn check_rate_limited_and_update_batch(
+        &self,
+        requests: Vec<CheckRequest<'_>>,
+        load_counters: bool,
+    ) -> LimitadorResult<Vec<CheckResult>> {
+        let mut batches = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let counters = self.counters_that_apply(&request.namespace, &request.ctx)?;
+            check_deltas(&counters, request.delta)?;
+            batches.push((counters, request.delta));
+        }
+
+        let authorizations = self
+            .storage
+            .check_and_update_batch(&mut batches, load_counters)
+            .await?;
+
+        let mut results = Vec::with_capacity(requests.len());
+        for ((request, (counters, delta)), authorization) in
+            requests.iter().zip(batches).zip(authorizations)
+        {
+            match &authorization {
+                Authorization::Ok => self.notify(&request.namespace, &counters, delta, false, None),
+                Authorization::Limited(limit) => {
+                    self.notify(&request.namespace, &counters, delta, true, limit.as_deref())
+                }
+            }
+            results.push(CheckResult::new(counters, authorization, load_counters));
+        }
+        Ok(results)
+    }
+
+    /// Checks whether the request would be limited, and what's left of each
+    /// of the limits that apply to it, without updating any counter
+    pub async fn peek(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<CheckResult> {
+        let mut counters = self.counters_that_apply(namespace, ctx)?;
+
+        let authorization = if counters.is_empty() {
+            Authorization::Ok
         } else {
-            Vec::default()
+            self.storage.peek(&mut counters, delta).await?
         };
 
-        match check_result {
-            Authorization::Ok => Ok(CheckResult {
-                limited: false,
-                counters,
-                limit_name: None,
-            }),
-            Authorization::Limited(name) => Ok(CheckResult {
-                limited: true,
-                counters,
-                limit_name: name,
-            }),
-        }
+        Ok(CheckResult::new(counters, authorization, true))
     }
 
     pub async fn get_counters(&self, namespace: &Namespace) -> LimitadorResult<HashSet<Counter>> {
@@ -616,6 +986,41 @@ impl AsyncRateLimiter {
             .map_err(|err| err.into())
     }
 
+    /// Up to `page_size` of the counters of the `namespace` matching the
+    /// `filter`. The next page is the one the returned cursor points to.
+    pub async fn get_counters_page(
+        &self,
+        namespace: &Namespace,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> LimitadorResult<CountersPage> {
+        self.storage
+            .get_counters_page(namespace, filter, cursor, page_size)
+            .await
+            .map_err(|err| err.into())
+    }
+
+    /// Resets the `counter`, e.g. the one of a given user, as if it was never
+    /// hit
+    pub async fn reset_counter(&self, counter: &Counter) -> LimitadorResult<()> {
+        self.storage.reset_counter(counter).await?;
+        Ok(())
+    }
+
+    /// Resets all the counters of the `limit`, which is kept
+    pub async fn reset_counters_of_limit(&self, limit: &Limit) -> LimitadorResult<()> {
+        self.storage.reset_counters_of_limit(limit).await?;
+        Ok(())
+    }
+
+    /// Resets all the counters of the limits in the `namespace`, which are
+    /// kept
+    pub async fn reset_counters(&self, namespace: &Namespace) -> LimitadorResult<()> {
+        self.storage.reset_counters(namespace).await?;
+        Ok(())
+    }
+
     // Deletes all the limits stored except the ones received in the params. For
     // every limit received, if it does not exist, it is created. If it already
     // exists, its associated counters are not reset.
@@ -623,55 +1028,161 @@ impl AsyncRateLimiter {
         &self,
         limits: impl IntoIterator<Item = Limit>,
     ) -> LimitadorResult<()> {
-        let limits_to_keep_or_create = classify_limits_by_namespace(limits);
+        let limits: Vec<Limit> = limits.into_iter().collect();
+        check_limit_ids(&limits)?;
+        let mut limits_to_keep_or_create = classify_limits_by_namespace(limits);
 
-        let namespaces_limits_to_keep_or_create: HashSet<Namespace> =
-            limits_to_keep_or_create.keys().cloned().collect();
+        let mut namespaces = self.get_namespaces();
+        namespaces.extend(self.get_namespace_patterns());
+        namespaces.extend(limits_to_keep_or_create.keys().cloned());
 
-        for namespace in self
-            .get_namespaces()
-            .union(&namespaces_limits_to_keep_or_create)
-        {
-            let limits_in_namespace = self.get_limits(namespace);
-            let limits_to_keep_in_ns: HashSet<Limit> = limits_to_keep_or_create
-                .get(namespace)
-                .cloned()
+        for namespace in namespaces {
+            let limits_in_ns = limits_to_keep_or_create
+                .remove(&namespace)
                 .unwrap_or_default();
+            self.storage
+                .replace_limits(&namespace, limits_in_ns)
+                .await?;
+        }
 
-            for limit in limits_in_namespace.difference(&limits_to_keep_in_ns) {
-                self.delete_limit(limit).await?;
-            }
+        Ok(())
+    }
 
-            for limit in limits_to_keep_in_ns.difference(&limits_in_namespace) {
-                self.add_limit(limit.clone());
-            }
+    /// Replaces the limits of the `namespace` with the given ones, all at
+    /// once. Only the counters of the limits whose definition, i.e. window,
+    /// conditions or variables, changed are reset. Updating the max value of a
+    /// limit keeps its counters, rescaled if the storage is set to.
+    pub async fn replace_limits(
+        &self,
+        namespace: &Namespace,
+        limits: impl IntoIterator<Item = Limit>,
+    ) -> LimitadorResult<()> {
+        let limits: Vec<Limit> = limits
+            .into_iter()
+            .filter(|limit| limit.namespace() == namespace)
+            .collect();
+        if limits.iter().any(|limit| limit.id().is_some()) {
+            let others: Vec<Arc<Limit>> = self
+                .storage
+                .get_namespaces()
+                .iter()
+                .chain(&self.storage.get_namespace_patterns())
+                .filter(|other| *other != namespace)
+                .flat_map(|other| self.storage.get_limits(other))
+                .collect();
+            check_limit_ids(others.iter().map(Arc::as_ref).chain(&limits))?;
+        }
+        self.storage.replace_limits(namespace, limits).await?;
+        Ok(())
+    }
 
-            for limit in limits_to_keep_in_ns.union(&limits_in_namespace) {
-                self.storage.update_limit(limit);
-            }
+    fn notify(
+        &self,
+        namespace: &Namespace,
+        counters: &[Counter],
+        delta: u64,
+        limited: bool,
+        limited_by: Option<&Limit>,
+    ) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let event = DecisionEvent {
+            namespace,
+            limits: counters.iter().map(Counter::limit).collect(),
+            delta,
+            limited,
+            limited_by,
+        };
+        for observer in &self.observers {
+            observer.on_decision(&event);
         }
+    }
 
-        Ok(())
+    // Checks the limits where they are held, from storages that can be read
+    // right away. Only the limits with variables get a counter built, to look
+    // their value up with.
+    fn is_rate_limited_in_place(
+        &self,
+        counters: &dyn CounterStorage,
+        namespace: &Namespace,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> LimitadorResult<bool> {
+        self.storage
+            .with_candidate_limits(namespace, ctx, |limits| -> LimitadorResult<bool> {
+                for limit in limits.filter(|lim| lim.applies(ctx)) {
+                    let Some(variables) = limit.resolve_variables(ctx)? else {
+                        continue;
+                    };
+                    let hits = match limit.resolve_cost(ctx)? {
+                        Some(cost) => delta.saturating_mul(cost),
+                        None => delta,
+                    };
+                    let max_value = limit
+                        .resolve_max_value_of(ctx, &variables)?
+                        .unwrap_or(limit.max_value());
+                    let borrowed = if variables.is_empty() {
+                        counters.is_limit_within(limit, max_value, hits)
+                    } else {
+                        None
+                    };
+                    let within_limits = match borrowed {
+                        Some(within_limits) => within_limits?,
+                        None => {
+                            let counter = Counter::with_resolved_variables(
+                                Arc::clone(limit),
+                                ctx,
+                                Arc::new(variables),
+                            )?;
+                            counters.is_within_limits(&counter, delta)?
+                        }
+                    };
+                    if !within_limits {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            })
     }
 
-    async fn counters_that_apply(
+    fn counters_that_apply(
         &self,
         namespace: &Namespace,
         ctx: &Context<'_>,
     ) -> LimitadorResult<Vec<Counter>> {
-        let limits = self.storage.get_limits(namespace);
-        limits
-            .iter()
-            .filter(|lim| lim.applies(ctx))
-            .filter_map(|lim| match Counter::new(Arc::clone(lim), ctx) {
-                Ok(None) => None,
-                Ok(Some(c)) => Some(Ok(c)),
-                Err(e) => Some(Err(e)),
-            })
-            .collect()
+        let limits = self.storage.get_candidate_limits(namespace, ctx);
+
+        // The limits with the same variables share the values they resolve to
+        let mut resolved: HashMap<&BTreeSet<Expression>, Option<Arc<BTreeMap<String, String>>>> =
+            HashMap::new();
+        let mut counters = Vec::new();
+        for limit in limits.iter().filter(|lim| lim.applies(ctx)) {
+            let variables = match resolved.entry(limit.variable_expressions()) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => entry
+                    .insert(limit.resolve_variables(ctx)?.map(Arc::new))
+                    .clone(),
+            };
+            if let Some(variables) = variables {
+                counters.push(Counter::with_resolved_variables(
+                    Arc::clone(limit),
+                    ctx,
+                    variables,
+                )?);
+            }
+        }
+        Ok(counters)
     }
 }
 
+// Fails on the first of the `counters` whose max value `delta` hits exceed
+fn check_deltas(counters: &[Counter], delta: u64) -> Result<(), DeltaExceedsMaxValue> {
+    counters
+        .iter()
+        .try_for_each(|counter| counter.checked_delta(delta).map(|_| ()))
+}
+
 fn classify_limits_by_namespace(
     limits: impl IntoIterator<Item = Limit>,
 ) -> HashMap<Namespace, HashSet<Limit>> {
@@ -695,8 +1206,11 @@ fn classify_limits_by_namespace(
 
 #[cfg(test)]
 mod test {
-    use crate::limit::{Context, Expression, Limit};
-    use crate::RateLimiter;
+    use crate::limit::{Context, Expression, Limit, Namespace};
+    use crate::storage::{MaxValueUpdate, Storage};
+    use crate::{DecisionEvent, RateLimiter, RateLimiterBuilder};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn properly_updates_existing_limits() {
@@ -704,7 +1218,7 @@ mod test {
         let namespace = "foo";
 
         let l = Limit::new(namespace, 42, 100, vec![], Vec::<Expression>::default());
-        rl.add_limit(l.clone());
+        rl.add_limit(l.clone()).unwrap();
         let limits = rl.get_limits(&namespace.into());
         assert_eq!(limits.len(), 1);
         assert!(limits.contains(&l));
@@ -729,4 +1243,391 @@ mod test {
             .unwrap();
         assert_eq!(r.counters.first().unwrap().max_value(), 50);
     }
+
+    #[test]
+    fn resets_counters_only_when_limits_get_redefined() {
+        let rl = RateLimiter::new(100);
+        let namespace = "foo".into();
+        let limit = |seconds| {
+            Limit::with_id(
+                "per-ns",
+                "foo",
+                2,
+                seconds,
+                vec![],
+                Vec::<Expression>::default(),
+            )
+        };
+        rl.add_limit(limit(60)).unwrap();
+        let check = || {
+            rl.check_rate_limited_and_update(&namespace, &Context::default(), 1, false)
+                .unwrap()
+                .limited
+        };
+        assert!(!check());
+
+        let mut renamed = limit(60);
+        renamed.set_name("renamed".to_string());
+        assert!(rl.update_limit(&renamed).unwrap());
+        assert!(!rl.update_limit(&renamed).unwrap());
+        assert!(!check());
+        assert!(check());
+
+        assert!(rl.update_limit(&limit(3600)).unwrap());
+        let limits = rl.get_limits(&namespace);
+        assert_eq!(limits.len(), 1);
+        assert!(limits.iter().all(|limit| limit.seconds() == 3600));
+        assert!(!check());
+
+        let unknown = Limit::with_id("other", "foo", 2, 60, vec![], Vec::<Expression>::default());
+        assert!(!rl.update_limit(&unknown).unwrap());
+    }
+
+    #[test]
+    fn rescales_counters_to_updated_max_values() {
+        let rl = RateLimiterBuilder::with_storage(
+            Storage::new(100).max_value_update(MaxValueUpdate::Rescale),
+        )
+        .build();
+        let namespace = "foo".into();
+        let mut limit = Limit::new("foo", 10, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(limit.clone()).unwrap();
+        let check = |hits| {
+            rl.check_rate_limited_and_update(&namespace, &Context::default(), hits, true)
+                .unwrap()
+        };
+        assert_eq!(check(5).statuses[0].remaining, Some(5));
+
+        limit.set_max_value(20);
+        assert!(rl.update_limit(&limit).unwrap());
+        assert_eq!(check(1).statuses[0].remaining, Some(9));
+
+        limit.set_max_value(4);
+        rl.replace_limits(&namespace, [limit]).unwrap();
+        assert_eq!(check(0).statuses[0].remaining, Some(2));
+    }
+
+    // Unlike the in memory storage, which keeps the max value of the limit its
+    // counters got created with, SQLite tells what remains of a counter
+    // against the max value of the limit it's given
+    #[cfg(feature = "sqlite_storage")]
+    #[test]
+    fn rescales_counters_below_their_value() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let storage =
+            crate::storage::sqlite::SqliteStorage::open(dir.path().join("counters.db")).unwrap();
+        let rl = RateLimiterBuilder::with_storage(
+            Storage::with_counter_storage(Box::new(storage))
+                .max_value_update(MaxValueUpdate::Rescale),
+        )
+        .build();
+        let namespace = "foo".into();
+        let mut limit = Limit::new("foo", 10, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(limit.clone()).unwrap();
+        let check = |hits| {
+            rl.check_rate_limited_and_update(&namespace, &Context::default(), hits, true)
+                .unwrap()
+        };
+        assert_eq!(check(8).statuses[0].remaining, Some(2));
+
+        // 8 out of 10 is 3 out of 4, rounding down
+        limit.set_max_value(4);
+        assert!(rl.update_limit(&limit).unwrap());
+        assert_eq!(check(0).statuses[0].remaining, Some(1));
+
+        limit.set_max_value(8);
+        rl.replace_limits(&namespace, [limit]).unwrap();
+        assert_eq!(check(0).statuses[0].remaining, Some(2));
+    }
+
+    #[test]
+    fn rejects_limit_ids_already_in_use() {
+        let rl = RateLimiter::new(100);
+        let per_user = |namespace: &str, seconds| {
+            Limit::with_id("per-user", namespace, 10, seconds, vec![], Vec::default())
+        };
+
+        assert_eq!(rl.add_limit(per_user("a", 60)), Ok(true));
+        assert_eq!(rl.add_limit(per_user("a", 60)), Ok(false));
+        let conflict = rl.add_limit(per_user("b", 60)).unwrap_err();
+        assert_eq!(conflict.id(), "per-user");
+        assert_eq!(conflict.namespace(), &"a".into());
+        assert!(rl.add_limit(per_user("a", 3600)).is_err());
+        assert!(rl.replace_limits(&"b".into(), [per_user("b", 60)]).is_err());
+        assert!(rl.get_limits(&"b".into()).is_empty());
+
+        assert!(rl
+            .configure_with([per_user("a", 60), per_user("b", 60)])
+            .is_err());
+        rl.configure_with([per_user("b", 60)]).unwrap();
+        assert!(rl.get_limits(&"a".into()).is_empty());
+        assert_eq!(rl.get_limits(&"b".into()).len(), 1);
+    }
+
+    #[test]
+    fn accounts_for_retries_of_a_request_once() {
+        let rl = RateLimiter::new(100);
+        let namespace = "foo".into();
+        let limit = Limit::new("foo", 2, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(limit).unwrap();
+
+        let check = |token: &str| {
+            rl.check_rate_limited_and_update_idempotent(
+                &namespace,
+                &Context::default(),
+                1,
+                true,
+                token,
+            )
+            .unwrap()
+        };
+
+        let first = check("a");
+        assert!(!first.limited);
+        assert_eq!(first.statuses[0].remaining, Some(1));
+        let retry = check("a");
+        assert!(!retry.limited);
+        assert_eq!(retry.statuses[0].remaining, Some(1));
+
+        assert!(!check("b").limited);
+        assert!(check("c").limited);
+        // Its retry is checked again, as it wasn't accounted for
+        assert!(check("c").limited);
+        assert!(!check("b").limited);
+    }
+
+    #[test]
+    fn applies_limits_of_matching_namespace_patterns() {
+        let rl = RateLimiter::new(100);
+
+        let limit = Limit::new("api.*", 1, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(limit.clone()).unwrap();
+        assert!(rl.get_namespaces().is_empty());
+        assert_eq!(
+            rl.get_namespace_patterns(),
+            HashSet::from([limit.namespace().clone()])
+        );
+
+        let ctx = Context::default();
+        assert!(
+            !rl.check_rate_limited_and_update(&"api.foo".into(), &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+        assert!(
+            rl.check_rate_limited_and_update(&"api.bar".into(), &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+        assert!(
+            !rl.check_rate_limited_and_update(&"web.foo".into(), &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+
+        rl.delete_limit(&limit).unwrap();
+        assert!(
+            !rl.check_rate_limited_and_update(&"api.bar".into(), &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+    }
+
+    #[test]
+    fn checks_the_limits_in_place_as_it_does_the_counters() {
+        let in_place = RateLimiter::new(100);
+        let observed = RateLimiterBuilder::new(100)
+            .observer(Arc::new(|_: &DecisionEvent| {}))
+            .build();
+        let namespace = "foo".into();
+
+        let mut simple = Limit::new("foo", 10, 60, vec![], Vec::<Expression>::default());
+        simple.set_cost("2".try_into().unwrap());
+        let qualified = Limit::new(
+            "foo",
+            2,
+            60,
+            vec!["method == 'GET'".try_into().unwrap()],
+            vec!["user".try_into().unwrap()],
+        );
+        for rl in [&in_place, &observed] {
+            rl.add_limit(simple.clone()).unwrap();
+            rl.add_limit(qualified.clone()).unwrap();
+        }
+
+        let get = |user: &str| -> Context {
+            HashMap::from([
+                ("method".to_string(), "GET".to_string()),
+                ("user".to_string(), user.to_string()),
+            ])
+            .into()
+        };
+        let post =
+            || -> Context { HashMap::from([("method".to_string(), "POST".to_string())]).into() };
+        for rl in [&in_place, &observed] {
+            for ctx in [get("alice"), get("bob"), post()] {
+                assert!(!rl.is_rate_limited(&namespace, &ctx, 1).unwrap());
+                rl.update_counters(&namespace, &ctx, 1).unwrap();
+            }
+            assert!(rl.is_rate_limited(&namespace, &get("alice"), 2).unwrap());
+            assert!(!rl.is_rate_limited(&namespace, &get("carol"), 2).unwrap());
+            assert!(!rl.is_rate_limited(&namespace, &post(), 2).unwrap());
+            assert!(rl.is_rate_limited(&namespace, &post(), 3).unwrap());
+        }
+    }
+
+    #[test]
+    fn deletes_expired_limits() {
+        let rl = RateLimiter::new(100);
+        let namespace = "foo".into();
+
+        let mut temporary = Limit::new("foo", 1, 60, vec![], vec!["x".try_into().unwrap()]);
+        temporary.set_ttl(0);
+        let permanent = Limit::new("foo", 1, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(temporary.clone()).unwrap();
+        rl.add_limit(permanent.clone()).unwrap();
+
+        rl.delete_expired_limits().unwrap();
+        let limits = rl.get_limits(&namespace);
+        assert_eq!(limits.len(), 1);
+        assert!(limits.contains(&permanent));
+    }
+
+    #[test]
+    fn applies_limits_of_parent_namespaces() {
+        let rl = RateLimiterBuilder::with_storage(Storage::new(100).hierarchical_namespaces(true))
+            .build();
+
+        let parent = Limit::new("tenant", 2, 60, vec![], Vec::<Expression>::default());
+        let child = Limit::new("tenant/svc", 5, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(parent).unwrap();
+        rl.add_limit(child).unwrap();
+
+        assert_eq!(rl.get_limits(&"tenant/svc".into()).len(), 1);
+
+        let ctx = Context::default();
+        for _ in 0..2 {
+            let r = rl
+                .check_rate_limited_and_update(&"tenant/svc".into(), &ctx, 1, true)
+                .unwrap();
+            assert!(!r.limited);
+            assert_eq!(r.counters.len(), 2);
+        }
+        assert!(
+            rl.check_rate_limited_and_update(&"tenant/other".into(), &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+        assert!(
+            !rl.check_rate_limited_and_update(&"other/svc".into(), &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+    }
+
+    #[test]
+    fn only_applies_limits_of_parent_namespaces_when_hierarchical() {
+        let rl = RateLimiter::new(100);
+
+        let parent = Limit::new("tenant", 1, 60, vec![], Vec::<Expression>::default());
+        rl.add_limit(parent).unwrap();
+
+        let ctx = Context::default();
+        for _ in 0..2 {
+            let r = rl
+                .check_rate_limited_and_update(&"tenant/svc".into(), &ctx, 1, true)
+                .unwrap();
+            assert!(!r.limited);
+            assert!(r.counters.is_empty());
+        }
+    }
+
+    #[test]
+    fn refunds_unused_quota() {
+        let rl = RateLimiter::new(100);
+        let namespace = "foo".into();
+        rl.add_limit(Limit::new(
+            "foo",
+            1,
+            60,
+            vec![],
+            Vec::<Expression>::default(),
+        ))
+        .unwrap();
+
+        let ctx = Context::default();
+        assert!(
+            !rl.check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                .unwrap()
+                .limited
+        );
+        assert!(rl.is_rate_limited(&namespace, &ctx, 1).unwrap());
+
+        rl.refund(&namespace, &ctx, 2).unwrap();
+        assert!(!rl.is_rate_limited(&namespace, &ctx, 1).unwrap());
+        assert!(rl.is_rate_limited(&namespace, &ctx, 2).unwrap());
+    }
+
+    #[test]
+    fn notifies_observers_of_decisions() {
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&decisions);
+        let rl = RateLimiterBuilder::new(100)
+            .observer(Arc::new(move |event: &DecisionEvent| {
+                observed.lock().unwrap().push((
+                    event.namespace.clone(),
+                    event.limits.len(),
+                    event.delta,
+                    event.limited,
+                    event.limited_by.and_then(Limit::name).map(String::from),
+                ))
+            }))
+            .build();
+        let mut limit = Limit::new("foo", 1, 60, vec![], Vec::<Expression>::default());
+        limit.set_name("one per minute".into());
+        rl.add_limit(limit).unwrap();
+
+        let ctx = Context::default();
+        rl.check_rate_limited_and_update(&"foo".into(), &ctx, 1, false)
+            .unwrap();
+        rl.check_rate_limited_and_update(&"foo".into(), &ctx, 1, false)
+            .unwrap();
+        rl.is_rate_limited(&"bar".into(), &ctx, 2).unwrap();
+
+        let namespace: Namespace = "foo".into();
+        assert_eq!(
+            *decisions.lock().unwrap(),
+            vec![
+                (namespace.clone(), 1, 1, false, None),
+                (namespace, 1, 1, true, Some("one per minute".to_string())),
+                ("bar".into(), 0, 2, false, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn counters_of_limits_with_the_same_variables_share_their_values() {
+        let rl = RateLimiter::new(100);
+        for seconds in [60, 3600] {
+            rl.add_limit(Limit::new(
+                "foo",
+                10,
+                seconds,
+                vec![],
+                vec!["app_id".try_into().expect("failed parsing!")],
+            ))
+            .unwrap();
+        }
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let ctx: Context = values.into();
+
+        let result = rl
+            .check_rate_limited_and_update(&"foo".into(), &ctx, 1, true)
+            .unwrap();
+        assert_eq!(result.counters.len(), 2);
+        assert!(std::ptr::eq(
+            result.counters[0].set_variables(),
+            result.counters[1].set_variables()
+        ));
+    }
 }
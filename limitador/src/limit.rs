@@ -1,17 +1,72 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::time::Duration;
 
+mod builder;
 mod cel;
+mod schedule;
+mod window;
 
-pub use cel::{Context, Expression, Predicate};
+pub use builder::{InvalidLimit, LimitBuilder, MAX_TTL_SECS};
+pub use cel::{set_lenient_conditions, Context, Expression, Predicate};
 pub use cel::{EvaluationError, ParseError};
+pub use schedule::{InvalidSchedule, Schedule, TimeOfDay, UtcOffset, Weekday};
+pub use window::{parse_window, ParseWindowError};
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Namespace(String);
 
+impl Namespace {
+    pub const SEPARATOR: char = '/';
+
+    /// The enclosing namespace, e.g. `tenant-a` for `tenant-a/service-x`
+    pub fn parent(&self) -> Option<Namespace> {
+        self.0
+            .rsplit_once(Self::SEPARATOR)
+            .map(|(parent, _)| parent.into())
+    }
+
+    /// This namespace followed by all of its ancestors, closest first
+    pub fn hierarchy(&self) -> impl Iterator<Item = Namespace> {
+        std::iter::successors(Some(self.clone()), Namespace::parent)
+    }
+
+    pub const WILDCARD: char = '*';
+
+    /// Whether this is a namespace pattern, e.g. `api.*`, rather than a
+    /// concrete namespace. The counters of the limits defined on a pattern are
+    /// shared by all the namespaces it matches.
+    pub fn is_pattern(&self) -> bool {
+        self.0.contains(Self::WILDCARD)
+    }
+
+    /// Whether `namespace` matches this pattern, where `*` matches any
+    /// sequence of characters
+    pub fn matches(&self, namespace: &Namespace) -> bool {
+        let mut parts = self.0.split(Self::WILDCARD);
+        let Some(mut rest) = parts
+            .next()
+            .and_then(|first| namespace.0.strip_prefix(first))
+        else {
+            return false;
+        };
+        let mut parts: Vec<&str> = parts.collect();
+        let Some(last) = parts.pop() else {
+            return rest.is_empty();
+        };
+        for part in parts {
+            match rest.find(part) {
+                Some(idx) => rest = &rest[idx + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+}
+
 impl From<&str> for Namespace {
     fn from(s: &str) -> Namespace {
         Self(s.into())
@@ -30,6 +85,37 @@ impl From<String> for Namespace {
     }
 }
 
+/// A max value taking precedence over the one of its limit, for the counters
+/// whose variables resolve to the given values, e.g. a specific user
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct MaxValueOverride {
+    variables: BTreeMap<String, String>,
+    max_value: u64,
+}
+
+impl MaxValueOverride {
+    pub fn new(variables: BTreeMap<String, String>, max_value: u64) -> Self {
+        Self {
+            variables,
+            max_value,
+        }
+    }
+
+    pub fn variables(&self) -> &BTreeMap<String, String> {
+        &self.variables
+    }
+
+    pub fn max_value(&self) -> u64 {
+        self.max_value
+    }
+
+    fn applies_to(&self, set_variables: &BTreeMap<String, String>) -> bool {
+        self.variables
+            .iter()
+            .all(|(var, value)| set_variables.get(var) == Some(value))
+    }
+}
+
 #[derive(Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct Limit {
     #[serde(skip_serializing, default)]
@@ -37,9 +123,22 @@ pub struct Limit {
     namespace: Namespace,
     #[serde(skip_serializing, default)]
     max_value: u64,
+    #[serde(skip_serializing, default)]
+    max_value_from: Option<Expression>,
+    #[serde(deserialize_with = "window::deserialize_seconds")]
     seconds: u64,
     #[serde(skip_serializing, default)]
     name: Option<String>,
+    #[serde(skip_serializing, default)]
+    cost: Option<Expression>,
+    #[serde(skip_serializing, default)]
+    schedule: Option<Schedule>,
+    #[serde(skip_serializing, default, deserialize_with = "deserialize_ttl")]
+    ttl: Option<u64>,
+    #[serde(skip_serializing, default)]
+    overrides: Vec<MaxValueOverride>,
+    #[serde(skip_serializing, default)]
+    metadata: BTreeMap<String, String>,
 
     // Need to sort to generate the same object when using the JSON as a key or
     // value in Redis.
@@ -62,8 +161,14 @@ impl Limit {
             id: None,
             namespace: namespace.into(),
             max_value,
+            max_value_from: None,
             seconds,
             name: None,
+            cost: None,
+            schedule: None,
+            ttl: None,
+            overrides: Vec::new(),
+            metadata: BTreeMap::new(),
             conditions: conditions.into_iter().collect(),
             variables: variables.into_iter().collect(),
         }
@@ -81,8 +186,14 @@ impl Limit {
             id: Some(id.into()),
             namespace: namespace.into(),
             max_value,
+            max_value_from: None,
             seconds,
             name: None,
+            cost: None,
+            schedule: None,
+            ttl: None,
+            overrides: Vec::new(),
+            metadata: BTreeMap::new(),
             conditions: conditions.into_iter().collect(),
             variables: variables.into_iter().collect(),
         }
@@ -104,6 +215,10 @@ impl Limit {
         self.seconds
     }
 
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.seconds)
+    }
+
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
@@ -116,6 +231,56 @@ impl Limit {
         self.max_value = value;
     }
 
+    pub fn max_value_from(&self) -> Option<&str> {
+        self.max_value_from.as_ref().map(Expression::source)
+    }
+
+    /// Sets the expression the max value of this limit is taken from, e.g.
+    /// `int(descriptors[0].quota)`, instead of the static `max_value`
+    pub fn set_max_value_from(&mut self, max_value_from: Expression) {
+        self.max_value_from = Some(max_value_from)
+    }
+
+    pub fn cost(&self) -> Option<&str> {
+        self.cost.as_ref().map(Expression::source)
+    }
+
+    /// Sets the expression used to compute the cost of a single hit against
+    /// this limit, e.g. `int(descriptors[0].tokens)`
+    pub fn set_cost(&mut self, cost: Expression) {
+        self.cost = Some(cost)
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    /// Restricts this limit to only apply within the recurring time window of
+    /// the `schedule`
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = Some(schedule)
+    }
+
+    pub fn ttl(&self) -> Option<u64> {
+        self.ttl
+    }
+
+    /// Makes this limit temporary: it gets deleted, along with its counters,
+    /// `seconds` after being added to the storage
+    pub fn set_ttl(&mut self, seconds: u64) {
+        self.ttl = Some(seconds)
+    }
+
+    /// Arbitrary labels of this limit, e.g. the team owning it, or the URL of
+    /// its runbook, which don't affect what it limits
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: BTreeMap<String, String>) {
+        self.metadata = metadata
+    }
+
     pub fn conditions(&self) -> HashSet<String> {
         self.conditions
             .iter()
@@ -123,6 +288,10 @@ impl Limit {
             .collect()
     }
 
+    pub(crate) fn predicates(&self) -> impl Iterator<Item = &Predicate> {
+        self.conditions.iter()
+    }
+
     pub fn variables(&self) -> HashSet<String> {
         self.variables
             .iter()
@@ -130,6 +299,10 @@ impl Limit {
             .collect()
     }
 
+    pub(crate) fn variable_expressions(&self) -> &BTreeSet<Expression> {
+        &self.variables
+    }
+
     pub fn resolve_variables(
         &self,
         ctx: &Context,
@@ -147,7 +320,76 @@ impl Limit {
         Ok(Some(map))
     }
 
-    #[cfg(feature = "disk_storage")]
+    pub fn resolve_cost(&self, ctx: &Context) -> Result<Option<u64>, EvaluationError> {
+        eval_u64("cost", self.cost.as_ref(), ctx)
+    }
+
+    pub fn resolve_max_value(&self, ctx: &Context) -> Result<Option<u64>, EvaluationError> {
+        eval_u64("max_value_from", self.max_value_from.as_ref(), ctx)
+    }
+
+    pub fn overrides(&self) -> &[MaxValueOverride] {
+        &self.overrides
+    }
+
+    /// Sets the max value of the counters whose variables resolve to
+    /// `variables`, replacing any override previously set for these. Fails
+    /// unless `variables` has a value for each of the limit's, and only these
+    pub fn set_override(
+        &mut self,
+        variables: BTreeMap<String, String>,
+        max_value: u64,
+    ) -> Result<(), InvalidLimit> {
+        let matches = variables.len() == self.variables.len()
+            && self
+                .variables
+                .iter()
+                .all(|var| variables.contains_key(var.source()));
+        if !matches {
+            return Err(InvalidLimit::InvalidOverride(
+                variables.into_keys().collect(),
+            ));
+        }
+        self.remove_override(&variables);
+        self.overrides
+            .push(MaxValueOverride::new(variables, max_value));
+        Ok(())
+    }
+
+    pub fn remove_override(&mut self, variables: &BTreeMap<String, String>) -> bool {
+        let len = self.overrides.len();
+        self.overrides.retain(|o| o.variables() != variables);
+        self.overrides.len() != len
+    }
+
+    /// The max value of the counter whose variables resolved to `variables`,
+    /// when either overridden for these or resolved from the `ctx`
+    pub(crate) fn resolve_max_value_of(
+        &self,
+        ctx: &Context,
+        variables: &BTreeMap<String, String>,
+    ) -> Result<Option<u64>, EvaluationError> {
+        match self.max_value_override(variables) {
+            Some(max_value) => Ok(Some(max_value)),
+            None => self.resolve_max_value(ctx),
+        }
+    }
+
+    pub(crate) fn max_value_override(
+        &self,
+        set_variables: &BTreeMap<String, String>,
+    ) -> Option<u64> {
+        self.overrides
+            .iter()
+            .find(|o| o.applies_to(set_variables))
+            .map(MaxValueOverride::max_value)
+    }
+
+    #[cfg(any(
+        feature = "disk_storage",
+        feature = "etcd_storage",
+        feature = "sqlite_storage"
+    ))]
     pub(crate) fn variables_for_key(&self) -> Vec<&str> {
         let mut variables = Vec::with_capacity(self.variables.len());
         for var in &self.variables {
@@ -165,25 +407,63 @@ impl Limit {
     }
 
     pub fn applies(&self, ctx: &Context) -> bool {
+        if let Some(schedule) = &self.schedule {
+            if !schedule.is_active() {
+                return false;
+            }
+        }
+
         let ctx = ctx.for_limit(self);
         let all_conditions_apply = self
             .conditions
             .iter()
             .all(|predicate| predicate.test(&ctx.for_limit(self)).unwrap());
 
-        let all_vars_are_set = self.variables.iter().all(|var| {
-            ctx.has_variables(
-                &var.variables()
-                    .iter()
-                    .map(String::as_str)
-                    .collect::<Vec<&str>>(),
-            )
-        });
+        let all_vars_are_set = self
+            .variables
+            .iter()
+            .chain(&self.cost)
+            .chain(&self.max_value_from)
+            .all(|var| {
+                ctx.has_variables(
+                    &var.variables()
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<&str>>(),
+                )
+            });
 
         all_conditions_apply && all_vars_are_set
     }
 }
 
+// Rejects the same ttls `LimitBuilder` does
+fn deserialize_ttl<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(ttl) if !(1..=MAX_TTL_SECS).contains(&ttl) => {
+            Err(serde::de::Error::custom(InvalidLimit::InvalidTtl(ttl)))
+        }
+        ttl => Ok(ttl),
+    }
+}
+
+fn eval_u64(
+    what: &str,
+    expression: Option<&Expression>,
+    ctx: &Context,
+) -> Result<Option<u64>, EvaluationError> {
+    let Some(expression) = expression else {
+        return Ok(None);
+    };
+    match expression.eval(ctx)? {
+        None => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| EvaluationError::UnexpectedValueType(format!("{what}: `{value}`"))),
+    }
+}
+
 impl Hash for Limit {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.namespace.hash(state);
@@ -230,6 +510,43 @@ mod tests {
     use std::cmp::Ordering::Equal;
     use std::collections::HashMap;
 
+    #[test]
+    fn namespace_hierarchy() {
+        let ns: Namespace = "tenant-a/service-x/v1".into();
+        assert_eq!(ns.parent(), Some("tenant-a/service-x".into()));
+        assert_eq!(
+            ns.hierarchy().collect::<Vec<_>>(),
+            vec![
+                Namespace::from("tenant-a/service-x/v1"),
+                Namespace::from("tenant-a/service-x"),
+                Namespace::from("tenant-a"),
+            ]
+        );
+
+        let ns: Namespace = "tenant-a".into();
+        assert_eq!(ns.parent(), None);
+        assert_eq!(ns.hierarchy().collect::<Vec<_>>(), vec![ns]);
+    }
+
+    #[test]
+    fn namespace_patterns() {
+        let pattern: Namespace = "api.*".into();
+        assert!(pattern.is_pattern());
+        assert!(pattern.matches(&"api.foo".into()));
+        assert!(pattern.matches(&"api.".into()));
+        assert!(!pattern.matches(&"web.api.foo".into()));
+
+        let pattern: Namespace = "a*bc*c".into();
+        assert!(pattern.matches(&"abcc".into()));
+        assert!(pattern.matches(&"a-bc-c".into()));
+        assert!(!pattern.matches(&"abc".into()));
+
+        let ns: Namespace = "api.foo".into();
+        assert!(!ns.is_pattern());
+        assert!(ns.matches(&"api.foo".into()));
+        assert!(!ns.matches(&"api.foo.bar".into()));
+    }
+
     #[test]
     fn limit_can_have_an_optional_name() {
         let mut limit = Limit::new(
@@ -407,6 +724,143 @@ mod tests {
         assert!(limit.has_variable("x"));
     }
 
+    #[test]
+    fn rejects_out_of_range_ttls() {
+        let limit = |ttl: u64| {
+            serde_json::from_value::<Limit>(serde_json::json!({
+                "namespace": "ns",
+                "max_value": 10,
+                "seconds": 60,
+                "conditions": [],
+                "variables": [],
+                "ttl": ttl,
+            }))
+        };
+        assert_eq!(limit(60).unwrap().ttl(), Some(60));
+        assert!(limit(0).is_err());
+        assert!(limit(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn resolves_cost() {
+        let mut limit = Limit::new(
+            "ns",
+            100,
+            60,
+            Vec::default(),
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([
+            ("user".to_string(), "alice".to_string()),
+            ("tokens".to_string(), "42".to_string()),
+        ])
+        .into();
+        assert_eq!(limit.resolve_cost(&ctx), Ok(None));
+
+        limit.set_cost("int(tokens) * 2".try_into().expect("failed parsing!"));
+        assert!(limit.applies(&ctx));
+        assert_eq!(limit.resolve_cost(&ctx), Ok(Some(84)));
+
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("failed")
+            .expect("Should have a counter");
+        assert_eq!(counter.delta(1), 84);
+        assert_eq!(counter.delta(2), 168);
+
+        let ctx = HashMap::from([("user".to_string(), "alice".to_string())]).into();
+        assert!(!limit.applies(&ctx));
+    }
+
+    #[test]
+    fn resolves_max_value() {
+        let mut limit = Limit::new(
+            "ns",
+            100,
+            60,
+            Vec::default(),
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        let ctx = HashMap::from([
+            ("user".to_string(), "alice".to_string()),
+            ("quota".to_string(), "1000".to_string()),
+        ])
+        .into();
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("failed")
+            .expect("Should have a counter");
+        assert_eq!(counter.max_value(), 100);
+
+        limit.set_max_value_from("int(quota)".try_into().expect("failed parsing!"));
+        assert!(limit.applies(&ctx));
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("failed")
+            .expect("Should have a counter");
+        assert_eq!(counter.max_value(), 1000);
+
+        let ctx = HashMap::from([("user".to_string(), "alice".to_string())]).into();
+        assert!(!limit.applies(&ctx));
+    }
+
+    #[test]
+    fn overrides_max_value() {
+        let mut limit = Limit::new(
+            "ns",
+            100,
+            60,
+            Vec::default(),
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        let alice = BTreeMap::from([("user".to_string(), "alice".to_string())]);
+        limit
+            .set_override(alice.clone(), 10)
+            .expect("valid override");
+        limit
+            .set_override(alice.clone(), 1000)
+            .expect("valid override");
+        assert_eq!(limit.overrides().len(), 1);
+
+        let ctx = HashMap::from([("user".to_string(), "alice".to_string())]).into();
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("failed")
+            .expect("Should have a counter");
+        assert_eq!(counter.max_value(), 1000);
+
+        let ctx = HashMap::from([("user".to_string(), "bob".to_string())]).into();
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("failed")
+            .expect("Should have a counter");
+        assert_eq!(counter.max_value(), 100);
+
+        assert!(limit.remove_override(&alice));
+        assert!(limit.overrides().is_empty());
+    }
+
+    #[test]
+    fn overrides_must_set_the_variables_of_the_limit() {
+        let mut limit = Limit::new(
+            "ns",
+            100,
+            60,
+            Vec::default(),
+            vec!["user".try_into().expect("failed parsing!")],
+        );
+        let invalid = [
+            BTreeMap::new(),
+            BTreeMap::from([("team".to_string(), "a".to_string())]),
+            BTreeMap::from([
+                ("user".to_string(), "alice".to_string()),
+                ("team".to_string(), "a".to_string()),
+            ]),
+        ];
+        for variables in invalid {
+            assert!(matches!(
+                limit.set_override(variables, 10),
+                Err(InvalidLimit::InvalidOverride(_))
+            ));
+        }
+        assert!(limit.overrides().is_empty());
+    }
+
     #[test]
     fn conditions_have_limit_info() {
         let mut limit = Limit::new(
@@ -0,0 +1,398 @@
+use crate::limit::{Expression, Limit, Namespace, Predicate, Schedule};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// The longest `ttl` of a limit, in seconds, about 136 years, so that its
+/// expiry can always be told
+pub const MAX_TTL_SECS: u64 = u32::MAX as u64;
+
+/// Builds a [`Limit`] out of raw strings, validating all of them upfront, so
+/// that an invalid condition or variable is reported before the limit ever
+/// reaches a storage, rather than when a request gets evaluated against it.
+#[derive(Debug, Clone)]
+pub struct LimitBuilder {
+    id: Option<String>,
+    namespace: Namespace,
+    max_value: u64,
+    window: Duration,
+    name: Option<String>,
+    conditions: Vec<String>,
+    variables: Vec<String>,
+    max_value_from: Option<String>,
+    cost: Option<String>,
+    schedule: Option<Schedule>,
+    ttl: Option<u64>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl LimitBuilder {
+    pub fn new<N: Into<Namespace>>(namespace: N, max_value: u64, seconds: u64) -> Self {
+        Self {
+            id: None,
+            namespace: namespace.into(),
+            max_value,
+            window: Duration::from_secs(seconds),
+            name: None,
+            conditions: Vec::new(),
+            variables: Vec::new(),
+            max_value_from: None,
+            cost: None,
+            schedule: None,
+            ttl: None,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Adds a CEL predicate, e.g. `req.method == 'GET'`
+    pub fn condition<S: Into<String>>(mut self, condition: S) -> Self {
+        self.conditions.push(condition.into());
+        self
+    }
+
+    /// Adds a CEL expression whose value qualifies the counters, e.g. `user_id`
+    pub fn variable<S: Into<String>>(mut self, variable: S) -> Self {
+        self.variables.push(variable.into());
+        self
+    }
+
+    pub fn max_value_from<S: Into<String>>(mut self, max_value_from: S) -> Self {
+        self.max_value_from = Some(max_value_from.into());
+        self
+    }
+
+    pub fn cost<S: Into<String>>(mut self, cost: S) -> Self {
+        self.cost = Some(cost.into());
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Overrides the window given to [`LimitBuilder::new`], which must be a
+    /// whole number of seconds
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn ttl(mut self, seconds: u64) -> Self {
+        self.ttl = Some(seconds);
+        self
+    }
+
+    /// Adds a label to the limit, e.g. `team` or `runbook`
+    pub fn metadata<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Limit, InvalidLimit> {
+        if self.namespace.as_ref().trim().is_empty() {
+            return Err(InvalidLimit::InvalidNamespace(
+                self.namespace.as_ref().into(),
+            ));
+        }
+        if let Some(id) = &self.id {
+            if id.is_empty() || id.chars().any(char::is_whitespace) {
+                return Err(InvalidLimit::InvalidId(id.clone()));
+            }
+        }
+        if self.window.is_zero() || self.window.subsec_nanos() != 0 {
+            return Err(InvalidLimit::InvalidWindow(self.window));
+        }
+        if let Some(ttl) = self.ttl.filter(|ttl| !(1..=MAX_TTL_SECS).contains(ttl)) {
+            return Err(InvalidLimit::InvalidTtl(ttl));
+        }
+
+        let conditions = self
+            .conditions
+            .into_iter()
+            .map(|condition| {
+                Predicate::parse(&condition).map_err(|err| InvalidLimit::InvalidCondition {
+                    token: err.token().into(),
+                    position: err.position(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let variables = self
+            .variables
+            .iter()
+            .map(|variable| parse_expression(variable, "variable"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let max_value_from = self
+            .max_value_from
+            .map(|source| parse_expression(&source, "max_value_from"))
+            .transpose()?;
+        let cost = self
+            .cost
+            .map(|source| parse_expression(&source, "cost"))
+            .transpose()?;
+
+        let mut limit = match self.id {
+            Some(id) => Limit::with_id(
+                id,
+                self.namespace,
+                self.max_value,
+                self.window.as_secs(),
+                conditions,
+                variables,
+            ),
+            None => Limit::new(
+                self.namespace,
+                self.max_value,
+                self.window.as_secs(),
+                conditions,
+                variables,
+            ),
+        };
+        if let Some(name) = self.name {
+            limit.set_name(name);
+        }
+        if let Some(max_value_from) = max_value_from {
+            limit.set_max_value_from(max_value_from);
+        }
+        if let Some(cost) = cost {
+            limit.set_cost(cost);
+        }
+        if let Some(schedule) = self.schedule {
+            limit.set_schedule(schedule);
+        }
+        if let Some(ttl) = self.ttl {
+            limit.set_ttl(ttl);
+        }
+        limit.set_metadata(self.metadata);
+        Ok(limit)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InvalidLimit {
+    InvalidNamespace(String),
+    InvalidId(String),
+    InvalidWindow(Duration),
+    InvalidTtl(u64),
+    /// `position` is the byte offset of `token` within the condition
+    InvalidCondition {
+        token: String,
+        position: usize,
+    },
+    /// An invalid variable, `max_value_from` or `cost` expression, `field`
+    /// telling which one
+    InvalidExpression {
+        field: &'static str,
+        token: String,
+        position: usize,
+    },
+    /// An override whose variables aren't exactly the ones of its limit
+    InvalidOverride(Vec<String>),
+}
+
+impl Display for InvalidLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidLimit::InvalidNamespace(namespace) => {
+                write!(f, "invalid namespace `{namespace}`")
+            }
+            InvalidLimit::InvalidId(id) => write!(f, "invalid limit id `{id}`"),
+            InvalidLimit::InvalidWindow(window) if window.subsec_nanos() == 0 => {
+                write!(f, "invalid window of {} seconds", window.as_secs())
+            }
+            InvalidLimit::InvalidWindow(window) => {
+                write!(
+                    f,
+                    "invalid window of {window:?}, not a whole number of seconds"
+                )
+            }
+            InvalidLimit::InvalidTtl(seconds) => write!(f, "invalid ttl of {seconds} seconds"),
+            InvalidLimit::InvalidCondition { token, position } => {
+                write!(f, "invalid condition: unexpected `{token}` at {position}")
+            }
+            InvalidLimit::InvalidExpression {
+                field,
+                token,
+                position,
+            } => write!(f, "invalid {field}: unexpected `{token}` at {position}"),
+            InvalidLimit::InvalidOverride(variables) => write!(
+                f,
+                "invalid override of [{}], not the variables of the limit",
+                variables.join(", ")
+            ),
+        }
+    }
+}
+
+impl Error for InvalidLimit {}
+
+fn parse_expression(source: &str, field: &'static str) -> Result<Expression, InvalidLimit> {
+    Expression::parse(source).map_err(|err| InvalidLimit::InvalidExpression {
+        field,
+        token: err.token().into(),
+        position: err.position(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidLimit, LimitBuilder, MAX_TTL_SECS};
+    use crate::limit::{Context, Limit};
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn builds_a_valid_limit() {
+        let limit = LimitBuilder::new("ns", 10, 60)
+            .id("per-user")
+            .name("per user")
+            .condition("req_method == 'GET'")
+            .variable("user_id")
+            .cost("1")
+            .build()
+            .unwrap();
+
+        assert_eq!(limit.id(), Some("per-user"));
+        assert_eq!(limit.name(), Some("per user"));
+        assert_eq!(limit.cost(), Some("1"));
+        assert_eq!(
+            limit,
+            Limit::new(
+                "ns",
+                10,
+                60,
+                vec!["req_method == 'GET'".try_into().unwrap()],
+                vec!["user_id".try_into().unwrap()],
+            )
+        );
+
+        let values: HashMap<String, String> = HashMap::from([
+            ("req_method".into(), "GET".into()),
+            ("user_id".into(), "alice".into()),
+        ]);
+        assert!(limit.applies(&Context::from(values)));
+    }
+
+    #[test]
+    fn rejects_invalid_conditions_with_the_offending_token() {
+        let err = LimitBuilder::new("ns", 10, 60)
+            .condition("req_method == 'GET")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InvalidLimit::InvalidCondition {
+                token: "'GET".into(),
+                position: 14,
+            }
+        );
+
+        let err = LimitBuilder::new("ns", 10, 60)
+            .condition("size(x)) > 1")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InvalidLimit::InvalidCondition {
+                token: ")".into(),
+                position: 7,
+            }
+        );
+
+        let err = LimitBuilder::new("ns", 10, 60)
+            .condition("'GET'")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InvalidLimit::InvalidCondition {
+                token: "'GET'".into(),
+                position: 0,
+            }
+        );
+
+        let err = LimitBuilder::new("ns", 10, 60)
+            .condition("a == #b")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InvalidLimit::InvalidCondition {
+                token: "#".into(),
+                position: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        let err = LimitBuilder::new("ns", 10, 60)
+            .variable("user_id[0")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            InvalidLimit::InvalidExpression {
+                field: "variable",
+                token: "[".into(),
+                position: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_window_and_id() {
+        assert_eq!(
+            LimitBuilder::new("ns", 10, 0).build().unwrap_err(),
+            InvalidLimit::InvalidWindow(Duration::ZERO)
+        );
+        assert_eq!(
+            LimitBuilder::new("ns", 10, 60)
+                .window(Duration::from_millis(1500))
+                .build()
+                .unwrap_err(),
+            InvalidLimit::InvalidWindow(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            LimitBuilder::new("ns", 10, 60)
+                .window(Duration::from_secs(300))
+                .build()
+                .unwrap()
+                .seconds(),
+            300
+        );
+        assert_eq!(
+            LimitBuilder::new("ns", 10, 60).id("").build().unwrap_err(),
+            InvalidLimit::InvalidId("".into())
+        );
+        assert_eq!(
+            LimitBuilder::new("ns", 10, 60)
+                .id("per user")
+                .build()
+                .unwrap_err(),
+            InvalidLimit::InvalidId("per user".into())
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_ttls() {
+        let ttl = |seconds| LimitBuilder::new("ns", 10, 60).ttl(seconds).build();
+        assert_eq!(ttl(0).unwrap_err(), InvalidLimit::InvalidTtl(0));
+        assert_eq!(ttl(MAX_TTL_SECS).unwrap().ttl(), Some(MAX_TTL_SECS));
+        assert_eq!(
+            ttl(u64::MAX).unwrap_err(),
+            InvalidLimit::InvalidTtl(u64::MAX)
+        );
+    }
+}
@@ -1,12 +1,28 @@
 use crate::limit::Limit;
 use cel_interpreter::{ExecutionError, Value};
+use cel_parser::{Atom, RelationOp};
 pub use errors::{EvaluationError, ParseError};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{self, AtomicBool};
 use std::sync::Arc;
 
+static LENIENT_CONDITIONS: AtomicBool = AtomicBool::new(false);
+
+/// Has conditions and expressions checked by the CEL parser alone, as they
+/// used to be, rather than also for the first token that can't be part of
+/// them and for conditions that can't be a boolean, e.g. `'GET'`. Deprecated:
+/// only meant for limits relying on it to keep loading, while getting fixed.
+pub fn set_lenient_conditions(lenient: bool) {
+    LENIENT_CONDITIONS.store(lenient, atomic::Ordering::Relaxed);
+}
+
+fn lenient() -> bool {
+    LENIENT_CONDITIONS.load(atomic::Ordering::Relaxed)
+}
+
 pub(super) mod errors {
     use cel_interpreter::ExecutionError;
     use std::error::Error;
@@ -41,27 +57,56 @@ pub(super) mod errors {
     #[derive(Debug)]
     pub struct ParseError {
         input: String,
-        source: Box<dyn Error + 'static>,
+        token: String,
+        position: usize,
+        source: Option<Box<dyn Error + 'static>>,
     }
 
     impl ParseError {
         pub fn from(source: cel_parser::ParseError, input: String) -> Self {
+            let (token, position) = super::whole(&input);
             Self {
                 input,
-                source: Box::new(source),
+                token,
+                position,
+                source: Some(Box::new(source)),
             }
         }
+
+        pub(crate) fn unexpected(input: String, token: String, position: usize) -> Self {
+            Self {
+                input,
+                token,
+                position,
+                source: None,
+            }
+        }
+
+        /// The offending token, or the whole input when the CEL parser
+        /// rejected it, as it doesn't tell where it gave up
+        pub fn token(&self) -> &str {
+            &self.token
+        }
+
+        /// The byte offset of the [`token`](Self::token) within the input
+        pub fn position(&self) -> usize {
+            self.position
+        }
     }
 
     impl Display for ParseError {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            write!(f, "couldn't parse {}: {}", self.input, self.source)
+            write!(
+                f,
+                "couldn't parse `{}`: unexpected `{}` at {}",
+                self.input, self.token, self.position
+            )
         }
     }
 
     impl Error for ParseError {
         fn source(&self) -> Option<&(dyn Error + 'static)> {
-            Some(self.source.as_ref())
+            self.source.as_deref()
         }
     }
 
@@ -108,6 +153,12 @@ impl<'a> Context<'a> {
             .add_variable_from_value(name, Value::List(v.into()));
     }
 
+    pub fn map_binding(&mut self, name: String, value: HashMap<String, String>) {
+        let map = cel_interpreter::objects::Map::from(value);
+        self.variables.insert(name.clone());
+        self.ctx.add_variable_from_value(name, Value::Map(map));
+    }
+
     pub(crate) fn for_limit<'b>(&'b self, limit: &Limit) -> Self
     where
         'b: 'a,
@@ -165,6 +216,11 @@ pub struct Expression {
 impl Expression {
     pub fn parse<T: ToString>(source: T) -> Result<Self, ParseError> {
         let source = source.to_string();
+        if !lenient() {
+            if let Err((token, position)) = check(&source) {
+                return Err(ParseError::unexpected(source, token, position));
+            }
+        }
         match cel_parser::parse(&source) {
             Ok(expression) => Ok(Self { source, expression }),
             Err(err) => Err(ParseError::from(err, source)),
@@ -226,6 +282,93 @@ fn err_on_value(val: Value) -> EvaluationError {
     }
 }
 
+// Literals other than booleans, arithmetic, lists and maps never are one,
+// whatever the request
+fn may_be_boolean(expression: &cel_parser::Expression) -> bool {
+    match expression {
+        cel_parser::Expression::Atom(atom) => matches!(atom, Atom::Bool(_)),
+        cel_parser::Expression::Arithmetic(..)
+        | cel_parser::Expression::List(..)
+        | cel_parser::Expression::Map(..) => false,
+        _ => true,
+    }
+}
+
+// The parser doesn't tell where it gave up, so when the lexical checks below
+// pass, the whole expression is reported as the offending token
+fn whole(source: &str) -> (String, usize) {
+    let trimmed = source.trim_start();
+    (trimmed.trim_end().into(), source.len() - trimmed.len())
+}
+
+// Spots the first token that can't be part of a valid CEL expression: an
+// unknown character, an unterminated string or an unbalanced delimiter
+fn check(source: &str) -> Result<(), (String, usize)> {
+    if source.trim().is_empty() {
+        return Err((String::new(), 0));
+    }
+
+    let mut open: Vec<(char, usize)> = Vec::new();
+    let mut chars = source.char_indices();
+    while let Some((position, c)) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                let tripled: String = [c, c, c].iter().collect();
+                let quote = if source[position..].starts_with(&tripled) {
+                    tripled
+                } else {
+                    c.to_string()
+                };
+                let prefix = source[..position]
+                    .rsplit(|ch: char| !ch.is_alphanumeric() && ch != '_')
+                    .next()
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                let raw = matches!(prefix.as_str(), "r" | "br" | "rb");
+
+                for _ in 1..quote.len() {
+                    chars.next();
+                }
+                let mut terminated = false;
+                while let Some((at, next)) = chars.next() {
+                    if next == '\\' && !raw {
+                        chars.next();
+                    } else if source[at..].starts_with(&quote) {
+                        for _ in 1..quote.len() {
+                            chars.next();
+                        }
+                        terminated = true;
+                        break;
+                    }
+                }
+                if !terminated {
+                    return Err((source[position..].into(), position));
+                }
+            }
+            '(' | '[' | '{' => open.push((c, position)),
+            ')' | ']' | '}' => {
+                let expected = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                match open.pop() {
+                    Some((opening, _)) if opening == expected => {}
+                    _ => return Err((c.into(), position)),
+                }
+            }
+            c if c.is_alphanumeric() || c.is_whitespace() => {}
+            '_' | '.' | ',' | '+' | '-' | '*' | '/' | '%' | '!' | '=' | '<' | '>' | '&' | '|'
+            | '?' | ':' => {}
+            c => return Err((c.into(), position)),
+        }
+    }
+    match open.pop() {
+        Some((opening, position)) => Err((opening.into(), position)),
+        None => Ok(()),
+    }
+}
+
 impl TryFrom<String> for Expression {
     type Error = ParseError;
 
@@ -284,7 +427,12 @@ pub struct Predicate {
 
 impl Predicate {
     pub fn parse<T: ToString>(source: T) -> Result<Self, ParseError> {
-        Expression::parse(source).map(|e| Self {
+        let e = Expression::parse(source)?;
+        if !lenient() && !may_be_boolean(&e.expression) {
+            let (token, position) = whole(&e.source);
+            return Err(ParseError::unexpected(e.source, token, position));
+        }
+        Ok(Self {
             variables: e
                 .expression
                 .references()
@@ -315,6 +463,28 @@ impl Predicate {
             Err(err) => Err(err.into()),
         }
     }
+
+    /// The operand and the string this predicate compares for equality, when
+    /// it is nothing but such a comparison, e.g. `descriptors[0].method == 'GET'`
+    pub(crate) fn equality(&self) -> Option<(Expression, String)> {
+        let cel_parser::Expression::Relation(left, RelationOp::Equals, right) =
+            &self.expression.expression
+        else {
+            return None;
+        };
+        let (operand, value) = match (left.as_ref(), right.as_ref()) {
+            (operand, cel_parser::Expression::Atom(Atom::String(value)))
+            | (cel_parser::Expression::Atom(Atom::String(value)), operand) => (operand, value),
+            _ => return None,
+        };
+        // Operands don't have a source of their own, but only need one that
+        // tells them apart
+        let operand = Expression {
+            source: format!("{operand:?}"),
+            expression: operand.clone(),
+        };
+        Some((operand, value.to_string()))
+    }
 }
 
 impl Eq for Predicate {}
@@ -417,13 +587,43 @@ mod tests {
 
     #[test]
     fn unexpected_value_predicate() {
-        let pred = Predicate::parse("42").expect("failed to parse");
+        let pred = Predicate::parse("uint('42')").expect("failed to parse");
         assert_eq!(
             pred.test(&ctx()).map_err(|e| format!("{e}")),
-            Err("unexpected value of type integer: `42`".to_string())
+            Err("unexpected value of type unsigned integer: `42`".to_string())
         );
     }
 
+    #[test]
+    fn rejects_malformed_sources_with_the_offending_token() {
+        let err = Expression::parse("x == 'GET").unwrap_err();
+        assert_eq!((err.token(), err.position()), ("'GET", 5));
+        assert_eq!(
+            err.to_string(),
+            "couldn't parse `x == 'GET`: unexpected `'GET` at 5"
+        );
+
+        let err = Predicate::parse("size(x)) > 1").unwrap_err();
+        assert_eq!((err.token(), err.position()), (")", 7));
+
+        let err = Predicate::parse(" x == == 1 ").unwrap_err();
+        assert_eq!((err.token(), err.position()), ("x == == 1", 1));
+
+        assert!(Predicate::parse("x == '''it's'''").is_ok());
+        assert!(Predicate::parse(r"x == 'a\'b'").is_ok());
+    }
+
+    #[test]
+    fn rejects_conditions_that_cannot_be_booleans() {
+        for source in ["42", "'GET'", "x + 1", "[true]", "{'a': true}"] {
+            let err = Predicate::parse(source).unwrap_err();
+            assert_eq!((err.token(), err.position()), (source, 0));
+            assert!(Expression::parse(source).is_ok());
+        }
+        assert!(Predicate::parse("true").is_ok());
+        assert!(Predicate::parse("x").is_ok());
+    }
+
     #[test]
     fn supports_list_bindings() {
         let pred = Predicate::parse("root[0].key == '1' && root[1]['key'] == '2'")
@@ -439,6 +639,107 @@ mod tests {
         assert_eq!(pred.test(&ctx).map_err(|e| format!("{e}")), Ok(true));
     }
 
+    #[test]
+    fn supports_regex_matching() {
+        let pred =
+            Predicate::parse("path.matches('^/api/v[0-9]+/users')").expect("failed to parse");
+        assert_eq!(
+            pred.test(
+                &HashMap::from([("path".to_string(), "/api/v2/users/42".to_string())]).into()
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            pred.test(&HashMap::from([("path".to_string(), "/api/vX/users".to_string())]).into()),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn supports_set_membership() {
+        let pred =
+            Predicate::parse("method in ['POST', 'PUT', 'DELETE']").expect("failed to parse");
+        assert_eq!(
+            pred.test(&HashMap::from([("method".to_string(), "PUT".to_string())]).into()),
+            Ok(true)
+        );
+        assert_eq!(
+            pred.test(&HashMap::from([("method".to_string(), "GET".to_string())]).into()),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn supports_logical_or_and_grouping() {
+        let pred = Predicate::parse("(method == 'GET' || method == 'HEAD') && path != '/health'")
+            .expect("failed to parse");
+        fn ctx<'a>(method: &str, path: &str) -> Context<'a> {
+            HashMap::from([
+                ("method".to_string(), method.to_string()),
+                ("path".to_string(), path.to_string()),
+            ])
+            .into()
+        }
+        assert_eq!(pred.test(&ctx("GET", "/")), Ok(true));
+        assert_eq!(pred.test(&ctx("HEAD", "/")), Ok(true));
+        assert_eq!(pred.test(&ctx("POST", "/")), Ok(false));
+        assert_eq!(pred.test(&ctx("GET", "/health")), Ok(false));
+    }
+
+    #[test]
+    fn supports_map_bindings() {
+        let pred = Predicate::parse("request.path.startsWith('/admin') && auth.tier != 'gold'")
+            .expect("failed to parse");
+        let mut ctx = Context::default();
+        ctx.map_binding(
+            "request".to_string(),
+            HashMap::from([("path".to_string(), "/admin/users".to_string())]),
+        );
+        assert_eq!(pred.test(&ctx), Ok(false));
+
+        ctx.map_binding(
+            "auth".to_string(),
+            HashMap::from([("tier".to_string(), "silver".to_string())]),
+        );
+        assert_eq!(pred.test(&ctx), Ok(true));
+
+        ctx.map_binding(
+            "auth".to_string(),
+            HashMap::from([("tier".to_string(), "gold".to_string())]),
+        );
+        assert_eq!(pred.test(&ctx), Ok(false));
+    }
+
+    #[test]
+    fn tells_equality_predicates_apart() {
+        let (operand, value) = Predicate::parse("request.method == 'GET'")
+            .unwrap()
+            .equality()
+            .expect("must be an equality");
+        assert_eq!(value, "GET");
+        let mut ctx = Context::default();
+        ctx.map_binding(
+            "request".to_string(),
+            HashMap::from([("method".to_string(), "POST".to_string())]),
+        );
+        assert_eq!(operand.eval(&ctx), Ok(Some("POST".to_string())));
+
+        let (reversed, _) = Predicate::parse("'GET' == request.method")
+            .unwrap()
+            .equality()
+            .expect("must be an equality");
+        assert_eq!(operand, reversed);
+
+        assert!(Predicate::parse("request.method != 'GET'")
+            .unwrap()
+            .equality()
+            .is_none());
+        assert!(Predicate::parse("request.method == 'GET' && x == '1'")
+            .unwrap()
+            .equality()
+            .is_none());
+    }
+
     fn ctx<'a>() -> Context<'a> {
         Context {
             variables: HashSet::default(),
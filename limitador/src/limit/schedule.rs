@@ -0,0 +1,321 @@
+use crate::clock;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// A recurring window of time during which a limit is active, e.g. business
+/// hours. Windows where `to` is before `from` span midnight and belong to the
+/// day they start on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawSchedule")]
+pub struct Schedule {
+    from: TimeOfDay,
+    to: TimeOfDay,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    days: Vec<Weekday>,
+    #[serde(skip_serializing_if = "UtcOffset::is_utc")]
+    utc_offset: UtcOffset,
+}
+
+// What a schedule gets parsed from, before its window gets checked
+#[derive(Deserialize)]
+struct RawSchedule {
+    from: TimeOfDay,
+    to: TimeOfDay,
+    #[serde(default)]
+    days: Vec<Weekday>,
+    #[serde(default)]
+    utc_offset: UtcOffset,
+}
+
+impl TryFrom<RawSchedule> for Schedule {
+    type Error = InvalidSchedule;
+
+    fn try_from(raw: RawSchedule) -> Result<Self, Self::Error> {
+        if raw.from == raw.to {
+            return Err(InvalidSchedule(format!(
+                "empty window from {} to {}",
+                String::from(raw.from),
+                String::from(raw.to)
+            )));
+        }
+        Ok(Self::new(raw.from, raw.to)
+            .on_days(raw.days)
+            .with_utc_offset(raw.utc_offset))
+    }
+}
+
+impl Schedule {
+    /// The window is never active when `from` and `to` are the same, which
+    /// parsing rejects
+    pub fn new(from: TimeOfDay, to: TimeOfDay) -> Self {
+        Self {
+            from,
+            to,
+            days: Vec::default(),
+            utc_offset: UtcOffset::default(),
+        }
+    }
+
+    pub fn on_days(mut self, days: impl IntoIterator<Item = Weekday>) -> Self {
+        self.days = days.into_iter().collect();
+        self
+    }
+
+    pub fn with_utc_offset(mut self, utc_offset: UtcOffset) -> Self {
+        self.utc_offset = utc_offset;
+        self
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(clock::now())
+    }
+
+    pub fn is_active_at(&self, when: SystemTime) -> bool {
+        let local = when
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+            + i64::from(self.utc_offset.0);
+        let day = local.div_euclid(SECONDS_PER_DAY);
+        let time = local.rem_euclid(SECONDS_PER_DAY) as u32;
+
+        let (in_window, day) = if self.from <= self.to {
+            (self.from.0 <= time && time < self.to.0, day)
+        } else if time >= self.from.0 {
+            (true, day)
+        } else {
+            (time < self.to.0, day - 1)
+        };
+
+        in_window
+            && (self.days.is_empty() || self.days.contains(&Weekday::from_days_since_epoch(day)))
+    }
+}
+
+/// A time of the day, from `00:00` to `24:00`, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    pub fn new(hours: u32, minutes: u32) -> Result<Self, InvalidSchedule> {
+        if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+            return Err(InvalidSchedule(format!(
+                "invalid time of day: {hours:02}:{minutes:02}"
+            )));
+        }
+        Ok(Self((hours * 60 + minutes) * 60))
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = InvalidSchedule;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || InvalidSchedule(format!("invalid time of day `{value}`, expected HH:MM"));
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours = digits(hours).ok_or_else(invalid)?;
+        let minutes = digits(minutes).ok_or_else(invalid)?;
+        Self::new(hours, minutes)
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(value: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", value.0 / 3600, value.0 % 3600 / 60)
+    }
+}
+
+/// An offset from UTC, e.g. `+02:00`, in seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UtcOffset(i32);
+
+impl UtcOffset {
+    pub fn new(hours: i32, minutes: i32) -> Result<Self, InvalidSchedule> {
+        if !(-14..=14).contains(&hours) || !(0..60).contains(&minutes) {
+            return Err(InvalidSchedule(format!(
+                "invalid UTC offset: {hours:+03}:{minutes:02}"
+            )));
+        }
+        let seconds = (hours.abs() * 60 + minutes) * 60;
+        Ok(Self(if hours < 0 { -seconds } else { seconds }))
+    }
+
+    pub fn is_utc(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl TryFrom<String> for UtcOffset {
+    type Error = InvalidSchedule;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || InvalidSchedule(format!("invalid UTC offset `{value}`, expected ±HH:MM"));
+        let (sign, offset) = match value.chars().next() {
+            Some('+') => (1, &value[1..]),
+            Some('-') => (-1, &value[1..]),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = offset.split_once(':').ok_or_else(invalid)?;
+        let hours = digits(hours).ok_or_else(invalid)?;
+        let minutes = digits(minutes).ok_or_else(invalid)?;
+        let offset = Self::new(hours as i32, minutes as i32)?;
+        Ok(Self(sign * offset.0))
+    }
+}
+
+impl From<UtcOffset> for String {
+    fn from(value: UtcOffset) -> Self {
+        let sign = if value.0 < 0 { '-' } else { '+' };
+        let seconds = value.0.abs();
+        format!("{sign}{:02}:{:02}", seconds / 3600, seconds % 3600 / 60)
+    }
+}
+
+// Parses the hours or minutes of `HH:MM`, which `u32::from_str` would accept
+// with a sign
+fn digits(value: &str) -> Option<u32> {
+    if value.is_empty() || value.len() > 2 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    fn from_days_since_epoch(days: i64) -> Self {
+        // 1970-01-01 was a Thursday
+        Self::ALL[(days + 3).rem_euclid(7) as usize]
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidSchedule(String);
+
+impl Display for InvalidSchedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for InvalidSchedule {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Schedule, TimeOfDay, UtcOffset, Weekday};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    // 2024-01-01 was a Monday
+    const MONDAY: u64 = 1_704_067_200;
+
+    fn at(day: u64, hours: u64, minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(MONDAY + day * 86_400 + hours * 3600 + minutes * 60)
+    }
+
+    #[test]
+    fn business_hours() {
+        let schedule = Schedule::new(
+            TimeOfDay::new(9, 0).unwrap(),
+            TimeOfDay::new(17, 0).unwrap(),
+        )
+        .on_days([
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+        ]);
+
+        assert!(!schedule.is_active_at(at(0, 8, 59)));
+        assert!(schedule.is_active_at(at(0, 9, 0)));
+        assert!(schedule.is_active_at(at(4, 16, 59)));
+        assert!(!schedule.is_active_at(at(4, 17, 0)));
+        assert!(!schedule.is_active_at(at(5, 12, 0)));
+    }
+
+    #[test]
+    fn spans_midnight() {
+        let schedule = Schedule::new(
+            TimeOfDay::new(22, 0).unwrap(),
+            TimeOfDay::new(6, 0).unwrap(),
+        )
+        .on_days([Weekday::Fri]);
+
+        assert!(schedule.is_active_at(at(4, 23, 0)));
+        assert!(schedule.is_active_at(at(5, 5, 59)));
+        assert!(!schedule.is_active_at(at(5, 6, 0)));
+        assert!(!schedule.is_active_at(at(5, 23, 0)));
+    }
+
+    #[test]
+    fn applies_utc_offset() {
+        let schedule = Schedule::new(
+            TimeOfDay::new(9, 0).unwrap(),
+            TimeOfDay::new(17, 0).unwrap(),
+        )
+        .with_utc_offset(UtcOffset::new(2, 0).unwrap());
+
+        assert!(schedule.is_active_at(at(0, 7, 0)));
+        assert!(!schedule.is_active_at(at(0, 15, 0)));
+    }
+
+    #[test]
+    fn deserializes() {
+        let schedule: Schedule = serde_json::from_str(
+            r#"{"from": "09:00", "to": "17:30", "days": ["mon", "fri"], "utc_offset": "-05:00"}"#,
+        )
+        .expect("failed to deserialize");
+        assert_eq!(
+            schedule,
+            Schedule::new(
+                TimeOfDay::new(9, 0).unwrap(),
+                TimeOfDay::new(17, 30).unwrap()
+            )
+            .on_days([Weekday::Mon, Weekday::Fri])
+            .with_utc_offset(UtcOffset::new(-5, 0).unwrap())
+        );
+
+        assert!(serde_json::from_str::<Schedule>(r#"{"from": "09:00", "to": "25:00"}"#).is_err());
+        assert!(serde_json::from_str::<Schedule>(r#"{"from": "09:00", "to": "09:00"}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_signs_within() {
+        for offset in ["+-05:00", "--05:00", "-+05:00", "+05:-00", "05:00", "+5:0a"] {
+            assert!(UtcOffset::try_from(offset.to_string()).is_err(), "{offset}");
+        }
+        for time in ["+09:00", "09:-0", "-9:00"] {
+            assert!(TimeOfDay::try_from(time.to_string()).is_err(), "{time}");
+        }
+        assert_eq!(
+            UtcOffset::try_from("-05:30".to_string()),
+            UtcOffset::new(-5, 30)
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use serde::de::{self, Deserializer, Unexpected, Visitor};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+const EXPECTED: &str = "a number of seconds, or a duration like `5m`, `1h30m` or `30d`";
+
+/// Parses the window of a limit, either a number of seconds, e.g. `60`, or a
+/// sequence of whole days, hours, minutes and seconds, e.g. `1h30m`. Both
+/// normalize to seconds, so that `60`, `1m` and `60s` are the same window, and
+/// share their counters.
+pub fn parse_window(value: &str) -> Result<Duration, ParseWindowError> {
+    let invalid = || ParseWindowError(format!("invalid window `{value}`, expected {EXPECTED}"));
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    if value.bytes().all(|b| b.is_ascii_digit()) {
+        return value
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| invalid());
+    }
+
+    let mut seconds: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return Err(invalid());
+        }
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        seconds = amount
+            .checked_mul(unit)
+            .and_then(|amount| seconds.checked_add(amount))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Deserializes the `seconds` of a [`Limit`](super::Limit) from either an
+/// integer or a string [`parse_window`] accepts
+pub(super) fn deserialize_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u64, D::Error> {
+    struct Seconds;
+
+    impl<'de> Visitor<'de> for Seconds {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            f.write_str(EXPECTED)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+            Ok(value)
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+            u64::try_from(value).map_err(|_| E::invalid_value(Unexpected::Signed(value), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+            parse_window(value)
+                .map(|window| window.as_secs())
+                .map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(Seconds)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseWindowError(String);
+
+impl Display for ParseWindowError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ParseWindowError {}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_window;
+    use crate::limit::Limit;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_seconds_and_units() {
+        assert_eq!(parse_window("60"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_window("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_window("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_window("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_window("30d"), Ok(Duration::from_secs(2_592_000)));
+        assert_eq!(parse_window("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_window("1d2h3m4s"), Ok(Duration::from_secs(93_784)));
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for window in ["", "m", "1h30", "5 m", "-5m", "1.5h", "5ms", "2w"] {
+            assert!(parse_window(window).is_err(), "`{window}` got parsed");
+        }
+        assert!(parse_window(&format!("{}d", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn normalizes_windows_to_seconds() {
+        let limit = |seconds: serde_json::Value| {
+            serde_json::from_value::<Limit>(serde_json::json!({
+                "namespace": "ns",
+                "max_value": 10,
+                "seconds": seconds,
+                "conditions": [],
+                "variables": [],
+            }))
+        };
+
+        let one_minute = limit("1m".into()).unwrap();
+        assert_eq!(one_minute.seconds(), 60);
+        assert_eq!(one_minute, limit(60.into()).unwrap());
+        assert_eq!(one_minute, limit("60s".into()).unwrap());
+        assert_eq!(
+            serde_json::to_string(&one_minute).unwrap(),
+            serde_json::to_string(&limit(60.into()).unwrap()).unwrap()
+        );
+        assert!(limit("1y".into()).is_err());
+        assert!(limit((-60).into()).is_err());
+    }
+}
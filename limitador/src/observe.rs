@@ -0,0 +1,58 @@
+//! The logs and metrics of the parts of the crate always built, which go
+//! nowhere without the `tracing` and `metrics` features, e.g. in a minimal
+//! build.
+
+#[cfg(feature = "metrics")]
+pub(crate) use metrics::{counter, gauge};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{error, warn};
+
+/// Stands in for the handles of the `metrics` crate
+#[cfg(not(feature = "metrics"))]
+pub(crate) struct Noop;
+
+#[cfg(not(feature = "metrics"))]
+impl Noop {
+    pub fn increment(&self, _value: u64) {}
+
+    pub fn set<T>(&self, _value: T) {}
+}
+
+#[cfg(not(feature = "metrics"))]
+macro_rules! counter {
+    ($name:expr $(, $label:expr => $value:expr)* $(,)?) => {{
+        let _ = ($name, $(($label, $value)),*);
+        $crate::observe::Noop
+    }};
+}
+
+#[cfg(not(feature = "metrics"))]
+macro_rules! gauge {
+    ($name:expr $(, $label:expr => $value:expr)* $(,)?) => {{
+        let _ = ($name, $(($label, $value)),*);
+        $crate::observe::Noop
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) use {counter, gauge};
+#[cfg(not(feature = "tracing"))]
+pub(crate) use {error, warn};
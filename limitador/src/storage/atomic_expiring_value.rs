@@ -1,3 +1,4 @@
+use crate::clock;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -23,14 +24,15 @@ impl AtomicExpiringValue {
         self.value.load(Ordering::SeqCst)
     }
 
+    #[cfg(feature = "redis_storage")]
     pub fn value(&self) -> u64 {
-        self.value_at(SystemTime::now())
+        self.value_at(clock::now())
     }
 
     #[cfg(feature = "redis_storage")]
     pub fn add_and_set_expiry(&self, delta: u64, expiry: SystemTime) -> u64 {
         self.expiry.update(expiry);
-        self.value.fetch_add(delta, Ordering::SeqCst) + delta
+        self.saturating_add(delta)
     }
 
     pub fn update(&self, delta: u64, ttl: Duration, when: SystemTime) -> u64 {
@@ -38,12 +40,46 @@ impl AtomicExpiringValue {
             self.value.store(delta, Ordering::SeqCst);
             return delta;
         }
-        self.value.fetch_add(delta, Ordering::SeqCst) + delta
+        self.saturating_add(delta)
+    }
+
+    // Adds `delta` to the value, without going past `u64::MAX`, returning
+    // the new value
+    fn saturating_add(&self, delta: u64) -> u64 {
+        let previous = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_add(delta))
+            })
+            .unwrap_or_else(|value| value);
+        previous.saturating_add(delta)
     }
 
+    /// Takes `delta` back off the value, without going below 0, unless it
+    /// expired already
+    pub fn refund(&self, delta: u64, when: SystemTime) {
+        if self.expiry.expired_at(when) {
+            return;
+        }
+        let _ = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_sub(delta))
+            });
+    }
+
+    #[cfg(any(feature = "redis_storage", feature = "partitioned_storage"))]
     pub fn ttl(&self) -> Duration {
         self.expiry.ttl()
     }
+
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expiry.ttl_at(when)
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        self.expiry.expires_at()
+    }
 }
 
 #[derive(Debug)]
@@ -65,12 +101,15 @@ impl AtomicExpiryTime {
             .as_micros() as u64
     }
 
+    #[allow(dead_code)]
     pub fn ttl(&self) -> Duration {
+        self.ttl_at(clock::now())
+    }
+
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
         let expiry =
             SystemTime::UNIX_EPOCH + Duration::from_micros(self.expiry.load(Ordering::SeqCst));
-        expiry
-            .duration_since(SystemTime::now())
-            .unwrap_or(Duration::ZERO)
+        expiry.duration_since(when).unwrap_or(Duration::ZERO)
     }
 
     pub fn expired_at(&self, when: SystemTime) -> bool {
@@ -102,7 +141,7 @@ impl AtomicExpiryTime {
     pub fn merge(&self, other: Self) {
         let mut other = other;
         loop {
-            let now = SystemTime::now();
+            let now = clock::now();
             other = match self.merge_at(other, now) {
                 Ok(_) => return,
                 Err(other) => other,
@@ -134,7 +173,6 @@ impl AtomicExpiryTime {
         self.expires_at()
     }
 
-    #[allow(dead_code)]
     pub fn expires_at(&self) -> SystemTime {
         SystemTime::UNIX_EPOCH + Duration::from_micros(self.expiry.load(Ordering::SeqCst))
     }
@@ -211,11 +249,19 @@ mod tests {
     fn updates_when_expired() {
         let now = SystemTime::now();
         let val = AtomicExpiringValue::new(42, now);
-        assert_eq!(val.ttl(), Duration::ZERO);
+        assert_eq!(val.ttl_at(now), Duration::ZERO);
         val.update(3, Duration::from_secs(10), now);
         assert_eq!(val.value_at(now - Duration::from_secs(1)), 3);
     }
 
+    #[test]
+    fn saturates_on_overflow() {
+        let now = SystemTime::now();
+        let val = AtomicExpiringValue::new(u64::MAX - 1, now + Duration::from_secs(1));
+        assert_eq!(val.update(3, Duration::from_secs(10), now), u64::MAX);
+        assert_eq!(val.value_at(now), u64::MAX);
+    }
+
     #[test]
     fn test_overlapping_updates() {
         let now = SystemTime::now();
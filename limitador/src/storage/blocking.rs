@@ -0,0 +1,144 @@
+use crate::counter::Counter;
+use crate::limit::{Limit, Namespace};
+use crate::storage::{
+    AsyncCounterStorage, Authorization, CounterFilter, CounterStorage, CountersPage, StorageErr,
+};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+
+// Exposes a blocking storage as an async one, so that the blocking limiter is
+// just the async one, driven by `resolve`. None of these futures ever wait on
+// anything: they are ready as soon as they get polled.
+pub(crate) struct BlockingCounterStorage(pub(crate) Box<dyn CounterStorage>);
+
+#[async_trait]
+impl AsyncCounterStorage for BlockingCounterStorage {
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        self.0.is_within_limits(counter, delta)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.0.add_counter(limit)
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.0.update_counter(counter, delta)
+    }
+
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.0.refund_counter(counter, delta)
+    }
+
+    async fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        self.0.claim_token(namespace, token, horizon)
+    }
+
+    async fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        self.0.release_token(namespace, token)
+    }
+
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.0.check_and_update(counters, delta, load_counters)
+    }
+
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        self.0.peek(counters, delta)
+    }
+
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        self.0.get_counters(limits)
+    }
+
+    async fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        self.0.get_counters_page(limits, filter, cursor, page_size)
+    }
+
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.0.delete_counter(counter)
+    }
+
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        self.0.delete_counters(limits)
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.0.clear()
+    }
+
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.0.flush()
+    }
+
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.0.ping()
+    }
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Runs `future` to completion on the calling thread. It must only await
+/// storages that never wait, e.g. a `BlockingCounterStorage`.
+pub(crate) fn resolve<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    match pin!(future).poll(&mut cx) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking storages are always ready"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, BlockingCounterStorage};
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::{AsyncCounterStorage, Authorization};
+
+    #[test]
+    fn resolves_blocking_storage_calls() {
+        let limit = Limit::new("test_namespace", 1, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+        let storage = BlockingCounterStorage(Box::new(InMemoryStorage::default()));
+        storage.add_counter(counter.limit()).unwrap();
+
+        let result = resolve(storage.check_and_update(&mut vec![counter.clone()], 1, false));
+        assert!(matches!(result, Ok(Authorization::Ok)));
+        let result = resolve(storage.check_and_update(&mut vec![counter.clone()], 1, false));
+        assert!(matches!(result, Ok(Authorization::Limited(Some(_)))));
+    }
+}
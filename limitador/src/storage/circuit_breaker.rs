@@ -0,0 +1,412 @@
+use crate::counter::Counter;
+use crate::limit::{Limit, Namespace};
+use crate::observe::{gauge, warn};
+use crate::storage::{AsyncCounterStorage, Authorization, CounterFilter, CountersPage, StorageErr};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_RESET_TIMEOUT_SEC: u64 = 5;
+
+enum State {
+    Closed { failures: u32 },
+    Open { since: Instant },
+    // A single request is let through to probe the storage. Another one is,
+    // if that probe isn't answered within a `reset_timeout`, e.g. as its
+    // future got dropped
+    HalfOpen { since: Instant },
+}
+
+// Stops calling the `inner` storage after `failure_threshold` consecutive
+// transient errors, e.g. while Redis is unreachable, so that requests don't
+// each wait for a timeout. While open, requests are either all allowed or all
+// denied, depending on `allow_when_open`. Every `reset_timeout`, a request is
+// let through to probe the storage, closing the breaker again if it answers.
+pub struct CircuitBreakerStorage {
+    inner: Box<dyn AsyncCounterStorage>,
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    allow_when_open: bool,
+    state: Mutex<State>,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for CircuitBreakerStorage {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        if !self.acquire() {
+            return Ok(self.allow_when_open);
+        }
+        let result = self.inner.is_within_limits(counter, delta).await;
+        self.record(result)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.inner.add_counter(limit)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        if !self.acquire() {
+            // The update is lost, as it would have been on a failure
+            return Ok(());
+        }
+        let result = self.inner.update_counter(counter, delta).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        if !self.acquire() {
+            return Ok(());
+        }
+        let result = self.inner.refund_counter(counter, delta).await;
+        self.record(result)
+    }
+
+    // While open, requests are all taken as new ones
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        if !self.acquire() {
+            return Ok(true);
+        }
+        let result = self.inner.claim_token(namespace, token, horizon).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        if !self.acquire() {
+            return Ok(());
+        }
+        let result = self.inner.release_token(namespace, token).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        if !self.acquire() {
+            return Ok(if self.allow_when_open {
+                Authorization::Ok
+            } else {
+                Authorization::Limited(None)
+            });
+        }
+        let result = self
+            .inner
+            .check_and_update(counters, delta, load_counters)
+            .await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        if !self.acquire() {
+            return Ok(if self.allow_when_open {
+                Authorization::Ok
+            } else {
+                Authorization::Limited(None)
+            });
+        }
+        let result = self.inner.peek(counters, delta).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        if !self.acquire() {
+            return Err(open_err());
+        }
+        let result = self.inner.get_counters(limits).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        if !self.acquire() {
+            return Err(open_err());
+        }
+        let result = self
+            .inner
+            .get_counters_page(limits, filter, cursor, page_size)
+            .await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        if !self.acquire() {
+            return Err(open_err());
+        }
+        let result = self.inner.delete_counter(counter).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        if !self.acquire() {
+            return Err(open_err());
+        }
+        let result = self.inner.delete_counters(limits).await;
+        self.record(result)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        if !self.acquire() {
+            return Err(open_err());
+        }
+        let result = self.inner.clear().await;
+        self.record(result)
+    }
+
+    // Attempted even while open, as it's the last chance to
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.inner.flush().await
+    }
+
+    // Not recorded, so that health checks don't open or close the breaker
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn ping(&self) -> Result<(), StorageErr> {
+        self.inner.ping().await
+    }
+}
+
+impl CircuitBreakerStorage {
+    pub fn new(inner: Box<dyn AsyncCounterStorage>) -> Self {
+        Self {
+            inner,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            reset_timeout: Duration::from_secs(DEFAULT_RESET_TIMEOUT_SEC),
+            allow_when_open: false,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Consecutive transient errors after which the breaker opens
+    pub fn failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    /// How long the breaker stays open before letting a request probe the
+    /// storage again
+    pub fn reset_timeout(mut self, reset_timeout: Duration) -> Self {
+        self.reset_timeout = reset_timeout;
+        self
+    }
+
+    /// Whether requests are allowed, rather than denied, while the breaker is
+    /// open
+    pub fn allow_when_open(mut self, allow_when_open: bool) -> Self {
+        self.allow_when_open = allow_when_open;
+        self
+    }
+
+    // Whether the call can go to the inner storage
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { since } | State::HalfOpen { since }
+                if since.elapsed() >= self.reset_timeout =>
+            {
+                *state = State::HalfOpen {
+                    since: Instant::now(),
+                };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record<T>(&self, result: Result<T, StorageErr>) -> Result<T, StorageErr> {
+        let mut state = self.state.lock().unwrap();
+        match &result {
+            Ok(_) => {
+                if !matches!(*state, State::Closed { .. }) {
+                    warn!("Storage recovered, closing the circuit breaker");
+                    gauge!("datastore_circuit_open").set(0);
+                }
+                *state = State::Closed { failures: 0 };
+            }
+            Err(err) if err.is_transient() => match *state {
+                State::Closed { failures } if failures + 1 < self.failure_threshold => {
+                    *state = State::Closed {
+                        failures: failures + 1,
+                    };
+                }
+                State::Closed { .. } => {
+                    warn!("Opening the circuit breaker on storage errors: {}", err);
+                    gauge!("datastore_circuit_open").set(1);
+                    *state = State::Open {
+                        since: Instant::now(),
+                    };
+                }
+                // Still down, or another call failed while the probe was
+                // in flight
+                State::HalfOpen { .. } | State::Open { .. } => {
+                    *state = State::Open {
+                        since: Instant::now(),
+                    };
+                }
+            },
+            // The storage answered, it just didn't like the request
+            Err(_) => {
+                if !matches!(*state, State::Closed { .. }) {
+                    warn!("Storage recovered, closing the circuit breaker");
+                    gauge!("datastore_circuit_open").set(0);
+                }
+                *state = State::Closed { failures: 0 };
+            }
+        }
+        result
+    }
+}
+
+fn open_err() -> StorageErr {
+    StorageErr {
+        msg: "circuit breaker is open, the storage is unavailable".to_string(),
+        source: None,
+        transient: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CircuitBreakerStorage;
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::testing::Flaky;
+    use crate::storage::{AsyncCounterStorage, Authorization};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn opens_and_half_opens() {
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+
+        let inner = Arc::new(Flaky::default());
+        let storage = CircuitBreakerStorage::new(Box::new(Arc::clone(&inner)))
+            .failure_threshold(2)
+            .reset_timeout(Duration::from_millis(50));
+
+        inner.down.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            assert!(storage
+                .check_and_update(&mut vec![counter.clone()], 1, false)
+                .await
+                .is_err());
+        }
+        // Open: denied without hitting the storage
+        let result = storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .unwrap();
+        assert!(matches!(result, Authorization::Limited(None)));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+
+        inner.down.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // Half open: the probe goes through, and closes the breaker
+        let result = storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .unwrap();
+        assert!(matches!(result, Authorization::Ok));
+        assert!(storage.is_within_limits(&counter, 1).await.unwrap());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 4);
+    }
+
+    async fn open(storage: &CircuitBreakerStorage, inner: &Flaky, counter: &Counter) {
+        inner.down.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            assert!(storage
+                .check_and_update(&mut vec![counter.clone()], 1, false)
+                .await
+                .is_err());
+        }
+        inner.down.store(false, Ordering::SeqCst);
+    }
+
+    #[tokio::test]
+    async fn closes_on_rejected_probes() {
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+
+        let inner = Arc::new(Flaky::default());
+        let storage = CircuitBreakerStorage::new(Box::new(Arc::clone(&inner)))
+            .failure_threshold(2)
+            .reset_timeout(Duration::from_millis(50));
+        open(&storage, &inner, &counter).await;
+
+        inner.rejecting.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .is_err());
+        inner.rejecting.store(false, Ordering::SeqCst);
+        // The storage answered the probe, so the breaker is closed
+        assert!(storage.is_within_limits(&counter, 1).await.unwrap());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn releases_abandoned_probes() {
+        let limit = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+
+        let inner = Arc::new(Flaky::default());
+        let storage = CircuitBreakerStorage::new(Box::new(Arc::clone(&inner)))
+            .failure_threshold(2)
+            .reset_timeout(Duration::from_millis(50));
+        open(&storage, &inner, &counter).await;
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // A probe that never gets recorded, e.g. as its future got dropped
+        assert!(storage.acquire());
+        assert!(!storage.acquire());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .is_ok());
+        assert!(storage.is_within_limits(&counter, 1).await.unwrap());
+    }
+}
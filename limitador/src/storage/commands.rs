@@ -0,0 +1,88 @@
+// The commands the members of a cluster run on the counters they hold, be it
+// the owner of the counters of a partition, or all the replicas of the
+// counters alike, along with their outcome.
+
+use crate::storage::StorageErr;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Command {
+    // Appended by the raft leaders as they get elected, to commit the entries
+    // of the previous terms
+    Noop,
+    Update {
+        updates: Vec<Update>,
+        check: bool,
+        apply: bool,
+    },
+    Refund {
+        refunds: Vec<(Vec<u8>, u64)>,
+    },
+    Scan {
+        prefixes: Vec<Vec<u8>>,
+    },
+    Delete {
+        keys: Vec<Vec<u8>>,
+    },
+    Clear,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Update {
+    pub key: Vec<u8>,
+    pub max_value: u64,
+    // In milliseconds
+    pub window: u64,
+    pub delta: u64,
+}
+
+// The expiries are when the windows end, in milliseconds since the epoch, for
+// the raft replicas agreeing on the time commands got proposed at, or their
+// TTLs, in milliseconds, for the owners of the partitions, as the clocks of the
+// members can't be relied upon to agree on when the counters expire
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum Outcome {
+    Done,
+    // The first of the updates that would go over its limit, if any, and the
+    // values and expiries of all of them, before being updated
+    Updated {
+        limited: Option<usize>,
+        values: Vec<(u64, u64)>,
+    },
+    Counters(Vec<(Vec<u8>, u64, u64)>),
+}
+
+pub(super) fn unavailable(msg: String) -> StorageErr {
+    StorageErr {
+        msg,
+        source: None,
+        transient: true,
+    }
+}
+
+pub(super) fn unexpected(outcome: Outcome) -> StorageErr {
+    StorageErr {
+        msg: format!("unexpected outcome: {outcome:?}"),
+        source: None,
+        transient: false,
+    }
+}
+
+#[cfg(test)]
+pub(super) fn update(key: &str, max_value: u64, delta: u64) -> Update {
+    Update {
+        key: key.as_bytes().to_vec(),
+        max_value,
+        window: 60_000,
+        delta,
+    }
+}
+
+#[cfg(test)]
+pub(super) fn check_and_update(updates: Vec<Update>) -> Command {
+    Command::Update {
+        updates,
+        check: true,
+        apply: true,
+    }
+}
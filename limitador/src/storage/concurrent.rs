@@ -0,0 +1,412 @@
+use crate::clock::{Clock, MonotonicClock};
+use crate::counter::Counter;
+use crate::limit::{Context, Limit, Namespace};
+use crate::storage::atomic_expiring_value::AtomicExpiryTime;
+use crate::storage::tokens::{SeenTokens, MAX_SEEN_TOKENS};
+use crate::storage::{peek_counters, Authorization, CounterStorage, StorageErr};
+use dashmap::DashMap;
+use moka::sync::Cache;
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// Counters held in memory, as with the
+/// [`InMemoryStorage`](crate::storage::in_memory::InMemoryStorage), but without
+/// any lock shared by all of them: each counter is its own atomic, held in
+/// concurrent maps, so that requests hitting different limits never contend.
+/// Checking and updating a counter is a single compare-and-swap, which never
+/// admits more than the limit, however many threads hit it.
+pub struct ConcurrentStorage {
+    simple_limits: DashMap<Limit, Arc<ConcurrentCounter>>,
+    qualified_counters: Cache<Counter, Arc<ConcurrentCounter>>,
+    seen_tokens: SeenTokens,
+    clock: Arc<dyn Clock>,
+}
+
+impl CounterStorage for ConcurrentStorage {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let value = self
+            .get(counter)
+            .map(|value| value.value_at(self.clock.now()))
+            .unwrap_or_default();
+        Ok(counter.max_value() >= value.saturating_add(counter.delta(delta)))
+    }
+
+    fn is_limit_within(
+        &self,
+        limit: &Limit,
+        max_value: u64,
+        hits: u64,
+    ) -> Option<Result<bool, StorageErr>> {
+        let value = self
+            .simple_limits
+            .get(limit)
+            .map(|value| value.value_at(self.clock.now()))
+            .unwrap_or_default();
+        Some(Ok(max_value >= value.saturating_add(hits)))
+    }
+
+    // The counters get created on their first hit, their window starting then
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let now = self.clock.now();
+        self.get_or_create(counter, now)
+            .add(counter.delta(delta), counter.window(), now);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        if let Some(value) = self.get(counter) {
+            value.refund(counter.delta(delta), self.clock.now());
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        Ok(self
+            .seen_tokens
+            .claim(namespace, token, horizon, self.clock.now()))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        self.seen_tokens.release(namespace, token);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let now = self.clock.now();
+        let values: Vec<Arc<ConcurrentCounter>> = counters
+            .iter()
+            .map(|counter| self.get_or_create(counter, now))
+            .collect();
+
+        if load_counters {
+            let mut first_limited = None;
+            for (counter, value) in counters.iter_mut().zip(&values) {
+                let remaining = counter
+                    .max_value()
+                    .checked_sub(value.value_at(now).saturating_add(counter.delta(delta)));
+                counter.set_remaining(remaining.unwrap_or_default());
+                if first_limited.is_none() && remaining.is_none() {
+                    first_limited = Some(Authorization::limited_by(counter));
+                }
+            }
+            if let Some(limited) = first_limited {
+                return Ok(limited);
+            }
+        }
+
+        // Each counter only gets the hits when within its limit, the ones
+        // before it taking them back otherwise
+        for (i, (counter, value)) in counters.iter().zip(&values).enumerate() {
+            let hits = counter.delta(delta);
+            if value
+                .try_add(hits, counter.max_value(), counter.window(), now)
+                .is_err()
+            {
+                for (counter, value) in counters.iter().zip(&values).take(i) {
+                    value.refund(counter.delta(delta), now);
+                }
+                return Ok(Authorization::limited_by(counter));
+            }
+        }
+        Ok(Authorization::Ok)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn peek(&self, counters: &mut [Counter], delta: u64) -> Result<Authorization, StorageErr> {
+        let now = self.clock.now();
+        let values_and_ttls: Vec<(u64, Duration)> = counters
+            .iter()
+            .map(|counter| {
+                self.get(counter)
+                    .map(|value| (value.value_at(now), value.ttl_at(now)))
+                    .filter(|(_, ttl)| !ttl.is_zero())
+                    .unwrap_or((0, counter.window()))
+            })
+            .collect();
+        Ok(peek_counters(counters, values_and_ttls, delta))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
+        let now = self.clock.now();
+        let mut res = HashSet::new();
+
+        let simple = self
+            .simple_limits
+            .iter()
+            .filter(|entry| limits.contains(entry.key()))
+            .filter_map(|entry| {
+                let counter = Counter::new(entry.key().clone(), &Context::default()).ok()??;
+                Some((counter, Arc::clone(entry.value())))
+            })
+            .collect::<Vec<_>>();
+        let qualified = self
+            .qualified_counters
+            .iter()
+            .filter(|(counter, _)| limits.contains(counter.limit()))
+            .map(|(counter, value)| (counter.deref().clone(), value));
+
+        for (mut counter, value) in simple.into_iter().chain(qualified) {
+            let ttl = value.ttl_at(now);
+            if !ttl.is_zero() {
+                counter.set_remaining(counter.max_value().saturating_sub(value.value_at(now)));
+                counter.set_expires_in(ttl);
+                res.insert(counter);
+            }
+        }
+        Ok(res)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        if counter.is_qualified() {
+            self.qualified_counters.invalidate(counter);
+        } else {
+            self.simple_limits.remove(counter.limit());
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        for limit in limits {
+            self.simple_limits.remove(limit.deref());
+        }
+        for (counter, _) in self.qualified_counters.iter() {
+            if limits.contains(counter.limit()) {
+                self.qualified_counters.invalidate(counter.deref());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn clear(&self) -> Result<(), StorageErr> {
+        self.simple_limits.clear();
+        self.qualified_counters.invalidate_all();
+        self.seen_tokens.clear();
+        Ok(())
+    }
+}
+
+impl ConcurrentStorage {
+    pub fn new(cache_size: u64) -> Self {
+        Self {
+            simple_limits: DashMap::new(),
+            qualified_counters: Cache::new(cache_size),
+            seen_tokens: SeenTokens::new(MAX_SEEN_TOKENS),
+            clock: Arc::new(MonotonicClock),
+        }
+    }
+
+    /// Measures the windows of the counters against the `clock`, rather than
+    /// a [`MonotonicClock`]
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn get(&self, counter: &Counter) -> Option<Arc<ConcurrentCounter>> {
+        if counter.is_qualified() {
+            self.qualified_counters.get(counter)
+        } else {
+            self.simple_limits
+                .get(counter.limit())
+                .map(|value| Arc::clone(value.value()))
+        }
+    }
+
+    // The value is cloned out of the maps, for their shards not to stay locked
+    // while it gets updated. Only one of the threads racing to create it does,
+    // for none of their hits to get lost.
+    fn get_or_create(&self, counter: &Counter, now: SystemTime) -> Arc<ConcurrentCounter> {
+        let create = || Arc::new(ConcurrentCounter::new(now + counter.window()));
+        if counter.is_qualified() {
+            self.qualified_counters.get_with_by_ref(counter, create)
+        } else if let Some(value) = self.simple_limits.get(counter.limit()) {
+            Arc::clone(value.value())
+        } else {
+            Arc::clone(
+                self.simple_limits
+                    .entry(counter.limit().clone())
+                    .or_insert_with(create)
+                    .value(),
+            )
+        }
+    }
+}
+
+impl Default for ConcurrentStorage {
+    fn default() -> Self {
+        Self::new(10_000)
+    }
+}
+
+/// The hits of a counter within its current window. Whoever first sees the
+/// window elapsed starts the next one, which hits racing it can get lost to,
+/// as with the `AtomicExpiringValue`.
+#[derive(Debug)]
+struct ConcurrentCounter {
+    value: AtomicU64,
+    expiry: AtomicExpiryTime,
+}
+
+impl ConcurrentCounter {
+    fn new(expiry: SystemTime) -> Self {
+        Self {
+            value: AtomicU64::new(0),
+            expiry: AtomicExpiryTime::new(expiry),
+        }
+    }
+
+    fn value_at(&self, when: SystemTime) -> u64 {
+        if self.expiry.expired_at(when) {
+            return 0;
+        }
+        self.value.load(Ordering::SeqCst)
+    }
+
+    fn add(&self, delta: u64, window: Duration, when: SystemTime) {
+        if self.expiry.update_if_expired(window, when) {
+            self.value.store(delta, Ordering::SeqCst);
+        } else {
+            let _ = self
+                .value
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                    Some(value.saturating_add(delta))
+                });
+        }
+    }
+
+    /// Adds `delta`, unless that would get the counter over `max_value`, its
+    /// value then being the error
+    fn try_add(
+        &self,
+        delta: u64,
+        max_value: u64,
+        window: Duration,
+        when: SystemTime,
+    ) -> Result<u64, u64> {
+        if self.expiry.update_if_expired(window, when) {
+            self.value.store(0, Ordering::SeqCst);
+        }
+        self.value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                value.checked_add(delta).filter(|value| *value <= max_value)
+            })
+            .map(|value| value + delta)
+    }
+
+    fn refund(&self, delta: u64, when: SystemTime) {
+        if self.expiry.expired_at(when) {
+            return;
+        }
+        let _ = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_sub(delta))
+            });
+    }
+
+    fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expiry.ttl_at(when)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConcurrentStorage;
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::{Authorization, CounterStorage};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+
+    #[test]
+    fn never_admits_more_than_the_limits_across_threads() {
+        let storage = ConcurrentStorage::default();
+        let limit = Limit::new(
+            "test_namespace",
+            1_000,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let ctx: Context = values.into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let admitted = AtomicU64::new(0);
+        thread::scope(|scope| {
+            for _ in 0..64 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let mut counters = vec![counter.clone()];
+                        if let Authorization::Ok =
+                            storage.check_and_update(&mut counters, 1, false).unwrap()
+                        {
+                            admitted.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(admitted.load(Ordering::Relaxed), 1_000);
+        assert!(!storage.is_within_limits(&counter, 1).unwrap());
+    }
+
+    #[test]
+    fn takes_the_hits_back_when_another_counter_is_limited() {
+        let storage = ConcurrentStorage::default();
+        let per_minute = Limit::new("test_namespace", 10, 60, vec![], vec![]);
+        let per_hour = Limit::new("test_namespace", 1, 3600, vec![], vec![]);
+        let counter = |limit: &Limit| {
+            Counter::new(limit.clone(), &Context::default())
+                .unwrap()
+                .expect("must have a counter")
+        };
+        let mut counters = vec![counter(&per_minute), counter(&per_hour)];
+
+        assert!(matches!(
+            storage.check_and_update(&mut counters, 1, false).unwrap(),
+            Authorization::Ok
+        ));
+        assert!(matches!(
+            storage.check_and_update(&mut counters, 1, true).unwrap(),
+            Authorization::Limited(_)
+        ));
+        assert!(matches!(
+            storage.check_and_update(&mut counters, 1, false).unwrap(),
+            Authorization::Limited(_)
+        ));
+        assert_eq!(counters[0].remaining(), Some(8));
+        assert!(storage.is_within_limits(&counters[0], 9).unwrap());
+    }
+}
@@ -32,7 +32,7 @@ impl ExpiringValue {
             self.expiry
         };
 
-        let value = self.value_at(now) + delta;
+        let value = self.value_at(now).saturating_add(delta);
         Self { value, expiry }
     }
 
@@ -40,7 +40,7 @@ impl ExpiringValue {
     pub fn merge(self, other: ExpiringValue, now: SystemTime) -> Self {
         if self.expiry > now {
             ExpiringValue {
-                value: self.value + other.value,
+                value: self.value.saturating_add(other.value),
                 expiry: self.expiry,
             }
         } else {
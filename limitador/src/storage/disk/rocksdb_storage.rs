@@ -3,21 +3,29 @@ use crate::limit::Limit;
 use crate::storage::disk::expiring_value::ExpiringValue;
 use crate::storage::disk::OptimizeFor;
 use crate::storage::keys::bin::{
-    key_for_counter, partial_counter_from_counter_key, prefix_for_namespace,
+    key_for_counter, namespace_from_counter_key, partial_counter_from_counter_key,
+    prefix_for_namespace,
+};
+use crate::storage::{
+    from_hex, invalid_cursor, parse_cursor, to_hex, Authorization, CounterFilter, CounterStorage,
+    CountersPage, StorageErr,
 };
-use crate::storage::{Authorization, CounterStorage, StorageErr};
 use rocksdb::{
-    CompactionDecision, DBCompressionType, DBWithThreadMode, IteratorMode, MultiThreaded, Options,
-    DB,
+    CompactionDecision, DBCompressionType, DBWithThreadMode, Direction, IteratorMode,
+    MultiThreaded, Options, DB,
 };
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ops::Deref;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tracing::debug_span;
 
+// The files holding data older than this get compacted by RocksDB itself, in
+// the background, the compaction filter dropping the expired counters then
+const PERIODIC_COMPACTION: Duration = Duration::from_secs(60 * 60);
+
 pub struct RocksDbStorage {
-    db: DBWithThreadMode<MultiThreaded>,
+    db: Arc<DBWithThreadMode<MultiThreaded>>,
 }
 
 impl CounterStorage for RocksDbStorage {
@@ -25,7 +33,7 @@ impl CounterStorage for RocksDbStorage {
     fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
         let key = key_for_counter(counter);
         let value = self.insert_or_update(&key, counter, 0)?;
-        Ok(counter.max_value() >= value.value() + delta)
+        Ok(counter.max_value() >= value.value().saturating_add(counter.delta(delta)))
     }
 
     #[tracing::instrument(skip_all)]
@@ -51,6 +59,7 @@ impl CounterStorage for RocksDbStorage {
 
         for counter in &mut *counters {
             let key = key_for_counter(counter);
+            let delta = counter.delta(delta);
             let slice: &[u8] = key.as_ref();
             let entry = {
                 let span = debug_span!("datastore");
@@ -71,15 +80,13 @@ impl CounterStorage for RocksDbStorage {
                 counter.set_remaining(
                     counter
                         .max_value()
-                        .checked_sub(val + delta)
+                        .checked_sub(val.saturating_add(delta))
                         .unwrap_or_default(),
                 );
             }
 
-            if counter.max_value() < val + delta {
-                return Ok(Authorization::Limited(
-                    counter.limit().name().map(|n| n.to_string()),
-                ));
+            if counter.max_value() < val.saturating_add(delta) {
+                return Ok(Authorization::limited_by(counter));
             }
 
             keys.push(key);
@@ -119,7 +126,9 @@ impl CounterStorage for RocksDbStorage {
                                 counter.update_to_limit(Arc::clone(limit));
                                 let ttl = value.ttl();
                                 counter.set_expires_in(ttl);
-                                counter.set_remaining(limit.max_value() - value.value());
+                                counter.set_remaining(
+                                    counter.max_value().saturating_sub(value.value()),
+                                );
                                 break;
                             }
                         }
@@ -134,6 +143,87 @@ impl CounterStorage for RocksDbStorage {
         Ok(counters)
     }
 
+    // Pages through the counters of each namespace in turn, in key order,
+    // seeking past the last key of the previous page rather than loading them
+    // all
+    #[tracing::instrument(skip_all)]
+    fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let namespaces: Vec<&str> = limits
+            .iter()
+            .map(|l| l.namespace().as_ref())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let (mut index, position) = parse_cursor(cursor)?;
+        let mut after =
+            from_hex(position).ok_or_else(|| invalid_cursor(cursor.unwrap_or_default()))?;
+
+        let mut page = CountersPage::default();
+        'namespaces: while index < namespaces.len() {
+            let prefix = prefix_for_namespace(namespaces[index]);
+            let start = if after.is_empty() { &prefix } else { &after };
+            let mut iterator = self
+                .db
+                .iterator(IteratorMode::From(start, Direction::Forward));
+            loop {
+                let next = {
+                    let span = debug_span!("datastore");
+                    let _entered = span.enter();
+                    iterator.next()
+                };
+                let Some(entry) = next else {
+                    break;
+                };
+                let (key, value) = entry?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                if *key == *after {
+                    continue;
+                }
+                if page.counters.len() == page_size {
+                    break 'namespaces;
+                }
+                after = key.to_vec();
+                let mut counter = partial_counter_from_counter_key(key.as_ref());
+                let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) else {
+                    continue;
+                };
+                let value: ExpiringValue = value.as_ref().try_into()?;
+                let ttl = value.ttl();
+                if ttl == Duration::ZERO {
+                    continue;
+                }
+                counter.update_to_limit(Arc::clone(limit));
+                counter.set_expires_in(ttl);
+                counter.set_remaining(counter.max_value().saturating_sub(value.value()));
+                if filter.matches(&counter) {
+                    page.counters.push(counter);
+                }
+            }
+            index += 1;
+            after = Vec::new();
+        }
+        if index < namespaces.len() {
+            page.next_cursor = Some(format!("{index}:{}", to_hex(&after)));
+        }
+        Ok(page)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        self.db.delete(key_for_counter(counter))?;
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
         let counters = self.get_counters(limits)?;
@@ -164,19 +254,19 @@ impl RocksDbStorage {
         match mode {
             OptimizeFor::Space => {
                 opts.set_compression_type(DBCompressionType::Bz2);
-                opts.set_compaction_filter("ExpiredValueFilter", |_level, _key, value| {
-                    if let Ok(value) = ExpiringValue::try_from(value) {
-                        if value.value_at(SystemTime::now()) != 0 {
-                            return CompactionDecision::Keep;
-                        }
-                    }
-                    CompactionDecision::Remove
-                });
             }
             OptimizeFor::Throughput => {
                 opts.set_compression_type(DBCompressionType::None);
             }
         }
+        opts.set_compaction_filter("ExpiredValueFilter", |_level, _key, value| {
+            if let Ok(value) = ExpiringValue::try_from(value) {
+                if value.value_at(SystemTime::now()) != 0 {
+                    return CompactionDecision::Keep;
+                }
+            }
+            CompactionDecision::Remove
+        });
         opts.set_merge_operator_associative("ExpiringValueMerge", |_key, start, operands| {
             let now = SystemTime::now();
             let mut value: ExpiringValue = start
@@ -190,11 +280,41 @@ impl RocksDbStorage {
             }
             Some(Vec::from(value))
         });
+        opts.set_periodic_compaction_seconds(PERIODIC_COMPACTION.as_secs());
         opts.create_if_missing(true);
-        let db = DB::open(&opts, path).unwrap();
+        let db = Arc::new(DB::open(&opts, path).unwrap());
         Ok(Self { db })
     }
 
+    /// Physically removes the expired counters from disk, by flushing all
+    /// pending writes and compacting the whole database. RocksDB does so
+    /// incrementally in the background anyway, as it compacts its files.
+    pub fn purge_expired(&self) -> Result<(), StorageErr> {
+        Self::purge(&self.db)
+    }
+
+    /// Reports the approximate size, uncompressed, of the counters stored for
+    /// each namespace.
+    pub fn size_by_namespace(&self) -> Result<HashMap<String, u64>, StorageErr> {
+        let mut sizes = HashMap::new();
+        for entry in self.db.iterator(IteratorMode::Start) {
+            let (key, value) = entry?;
+            if let Some(namespace) = namespace_from_counter_key(key.as_ref()) {
+                *sizes.entry(namespace.to_string()).or_default() +=
+                    (key.len() + value.len()) as u64;
+            }
+        }
+        Ok(sizes)
+    }
+
+    fn purge(db: &DBWithThreadMode<MultiThreaded>) -> Result<(), StorageErr> {
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        db.flush()?;
+        db.compact_range::<&[u8], &[u8]>(None, None);
+        Ok(())
+    }
+
     fn insert_or_update(
         &self,
         key: &[u8],
@@ -202,6 +322,7 @@ impl RocksDbStorage {
         delta: u64,
     ) -> Result<ExpiringValue, StorageErr> {
         let now = SystemTime::now();
+        let delta = counter.delta(delta);
         let entry = {
             let span = debug_span!("datastore");
             let _entered = span.enter();
@@ -214,7 +335,7 @@ impl RocksDbStorage {
                 slice.try_into()?
             }
         };
-        if value.value_at(now) + delta <= counter.max_value() {
+        if value.value_at(now).saturating_add(delta) <= counter.max_value() {
             let expiring_value =
                 ExpiringValue::new(delta, now + Duration::from_secs(counter.limit().seconds()));
             let span = debug_span!("datastore");
@@ -233,12 +354,77 @@ mod tests {
     use crate::counter::Counter;
     use crate::limit::Limit;
     use crate::storage::disk::OptimizeFor;
-    use crate::storage::CounterStorage;
-    use std::collections::HashMap;
+    use crate::storage::{CounterFilter, CounterStorage};
+    use std::collections::{HashMap, HashSet};
     use std::fs;
+    use std::sync::Arc;
     use std::time::Duration;
     use tempfile::TempDir;
 
+    #[test]
+    fn purges_expired_counters() {
+        let limit = Limit::new("test_namespace", 10, 1, vec![], vec![]);
+        let ctx = HashMap::<String, String>::default().into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage = RocksDbStorage::open(tmp.path(), OptimizeFor::Throughput)
+            .expect("We should have a storage");
+        storage.update_counter(&counter, 1).unwrap();
+        let sizes = storage.size_by_namespace().unwrap();
+        assert!(sizes["test_namespace"] > 0);
+
+        std::thread::sleep(Duration::from_secs(2));
+        storage.purge_expired().unwrap();
+        storage.purge_expired().unwrap();
+        assert!(storage.size_by_namespace().unwrap().is_empty());
+    }
+
+    #[test]
+    fn pages_through_counters() {
+        let limit = Arc::new(Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        ));
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage = RocksDbStorage::open(tmp.path(), OptimizeFor::Throughput)
+            .expect("We should have a storage");
+        for app_id in ["a1", "a2", "a3", "a4", "b1"] {
+            let ctx = HashMap::from([("app_id".to_string(), app_id.to_string())]).into();
+            let counter = Counter::new(Arc::clone(&limit), &ctx)
+                .unwrap()
+                .expect("must have a counter");
+            storage.update_counter(&counter, 1).unwrap();
+        }
+
+        let limits = HashSet::from([limit]);
+        let filter = CounterFilter::default().variable_prefix("app_id", "a");
+        let mut seen = HashSet::new();
+        let mut cursor = None;
+        loop {
+            let page = storage
+                .get_counters_page(&limits, &filter, cursor.as_deref(), 3)
+                .unwrap();
+            assert!(page.counters.len() <= 3);
+            for counter in page.counters {
+                assert!(seen.insert(counter.set_variables()["app_id"].clone()));
+            }
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            seen,
+            HashSet::from(["a1", "a2", "a3", "a4"].map(String::from))
+        );
+    }
+
     #[test]
     fn opens_db_on_disk() {
         let namespace = "test_namespace";
@@ -12,18 +12,27 @@ pub struct CrCounterValue<A: Ord> {
     max_value: u64,
     value: AtomicU64,
     others: RwLock<BTreeMap<A, u64>>,
+    // Refunds grow on their own, and are subtracted from the increments
+    refunded: AtomicU64,
+    others_refunded: RwLock<BTreeMap<A, u64>>,
     expiry: AtomicExpiryTime,
 }
 
 #[allow(dead_code)]
-impl<A: Ord> CrCounterValue<A> {
+impl<A: Clone + Ord> CrCounterValue<A> {
     pub fn new(actor: A, max_value: u64, time_window: Duration) -> Self {
+        Self::new_at(actor, max_value, time_window, SystemTime::now())
+    }
+
+    pub fn new_at(actor: A, max_value: u64, time_window: Duration, when: SystemTime) -> Self {
         Self {
             ourselves: actor,
             max_value,
             value: Default::default(),
             others: RwLock::default(),
-            expiry: AtomicExpiryTime::new(SystemTime::now() + time_window),
+            refunded: Default::default(),
+            others_refunded: RwLock::default(),
+            expiry: AtomicExpiryTime::new(when + time_window),
         }
     }
 
@@ -40,8 +49,61 @@ impl<A: Ord> CrCounterValue<A> {
             0
         } else {
             let guard = self.others.read().unwrap();
-            let others: u64 = guard.values().sum();
-            others + self.value.load(Ordering::Relaxed)
+            let others = guard.values().copied().fold(0, u64::saturating_add);
+            let refunded = self
+                .others_refunded
+                .read()
+                .unwrap()
+                .values()
+                .copied()
+                .fold(0, u64::saturating_add);
+            others
+                .saturating_add(self.value.load(Ordering::Relaxed))
+                .saturating_sub(refunded.saturating_add(self.refunded.load(Ordering::Relaxed)))
+        }
+    }
+
+    /// The most any single actor counted, its refunds aside
+    pub fn read_max_at(&self, when: SystemTime) -> u64 {
+        if self.expiry.expired_at(when) {
+            0
+        } else {
+            let others = self.others.read().unwrap();
+            let refunded = self.others_refunded.read().unwrap();
+            others
+                .iter()
+                .map(|(actor, value)| value.saturating_sub(*refunded.get(actor).unwrap_or(&0)))
+                .fold(self.read_local_at(when), u64::max)
+        }
+    }
+
+    /// What we counted ourselves, our refunds aside
+    pub fn read_local_at(&self, when: SystemTime) -> u64 {
+        if self.expiry.expired_at(when) {
+            0
+        } else {
+            self.value
+                .load(Ordering::Relaxed)
+                .saturating_sub(self.refunded.load(Ordering::Relaxed))
+        }
+    }
+
+    /// What the `actors` counted, ourselves included when among them, their
+    /// refunds aside
+    pub fn read_of_at(&self, when: SystemTime, actors: impl Fn(&A) -> bool) -> u64 {
+        if self.expiry.expired_at(when) {
+            return 0;
+        }
+        let others = self.others.read().unwrap();
+        let refunded = self.others_refunded.read().unwrap();
+        let value = others
+            .iter()
+            .filter(|(actor, _)| actors(actor))
+            .map(|(actor, value)| value.saturating_sub(*refunded.get(actor).unwrap_or(&0)))
+            .fold(0, u64::saturating_add);
+        match actors(&self.ourselves) {
+            true => self.read_local_at(when).saturating_add(value),
+            false => value,
         }
     }
 
@@ -52,9 +114,27 @@ impl<A: Ord> CrCounterValue<A> {
     pub fn inc_at(&self, increment: u64, time_window: Duration, when: SystemTime) {
         if self.expiry.update_if_expired(time_window, when) {
             self.value.store(increment, Ordering::SeqCst);
+            self.refunded.store(0, Ordering::SeqCst);
         } else {
-            self.value.fetch_add(increment, Ordering::SeqCst);
+            let _ = self
+                .value
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                    Some(value.saturating_add(increment))
+                });
+        }
+    }
+
+    pub fn refund(&self, amount: u64) {
+        self.refund_at(amount, SystemTime::now())
+    }
+
+    /// Gives back up to `amount`, never taking the value below 0
+    pub fn refund_at(&self, amount: u64, when: SystemTime) {
+        if self.expiry.expired_at(when) {
+            return;
         }
+        let amount = amount.min(self.read_at(when));
+        self.refunded.fetch_add(amount, Ordering::SeqCst);
     }
 
     pub fn inc_actor(&self, actor: A, increment: u64, time_window: Duration) {
@@ -69,7 +149,8 @@ impl<A: Ord> CrCounterValue<A> {
             if self.expiry.update_if_expired(time_window, when) {
                 guard.insert(actor, increment);
             } else {
-                *guard.entry(actor).or_insert(0) += increment;
+                let value = guard.entry(actor).or_insert(0);
+                *value = value.saturating_add(increment);
             }
         }
     }
@@ -79,7 +160,7 @@ impl<A: Ord> CrCounterValue<A> {
     }
 
     pub fn merge_at(&self, other: Self, when: SystemTime) {
-        let (expiry, other_values) = other.into_inner();
+        let (expiry, other_values, other_refunds) = other.into_inner();
         if expiry > when {
             let _ = self.expiry.merge_at(expiry.into(), when);
             if self.expiry.expired_at(when) {
@@ -109,6 +190,21 @@ impl<A: Ord> CrCounterValue<A> {
                     }
                 }
             }
+            let refunded = self.refunded.load(Ordering::SeqCst);
+            let mut others_refunded = self.others_refunded.write().unwrap();
+            for (actor, other_refund) in other_refunds {
+                if actor == self.ourselves {
+                    if other_refund > refunded {
+                        self.refunded
+                            .fetch_add(other_refund - refunded, Ordering::SeqCst);
+                    }
+                } else if other_refund > 0 {
+                    let local = others_refunded.entry(actor).or_insert(0);
+                    if other_refund > *local {
+                        *local = other_refund;
+                    }
+                }
+            }
         }
     }
 
@@ -116,36 +212,55 @@ impl<A: Ord> CrCounterValue<A> {
         self.expiry.ttl()
     }
 
+    pub fn ttl_at(&self, when: SystemTime) -> Duration {
+        self.expiry.ttl_at(when)
+    }
+
     pub fn expiry(&self) -> SystemTime {
         self.expiry.expires_at()
     }
 
-    pub fn into_inner(self) -> (SystemTime, BTreeMap<A, u64>) {
+    /// The expiry, the increments and the refunds, by actor
+    pub fn into_inner(self) -> (SystemTime, BTreeMap<A, u64>, BTreeMap<A, u64>) {
         let Self {
             ourselves,
             max_value: _,
             value,
             others,
+            refunded,
+            others_refunded,
             expiry,
         } = self;
         let mut map = others.into_inner().unwrap();
-        map.insert(ourselves, value.into_inner());
-        (expiry.into_inner(), map)
+        map.insert(ourselves.clone(), value.into_inner());
+        let mut refunds = others_refunded.into_inner().unwrap();
+        refunds.insert(ourselves, refunded.into_inner());
+        (expiry.into_inner(), map, refunds)
     }
 
-    pub fn local_values(&self) -> (SystemTime, &A, u64) {
+    /// The expiry, our own increments and refunds
+    pub fn local_values(&self) -> (SystemTime, &A, u64, u64) {
         (
             self.expiry.clone().into_inner(),
             &self.ourselves,
             self.value.load(Ordering::Relaxed),
+            self.refunded.load(Ordering::Relaxed),
         )
     }
 
+    pub fn with_refunds(self, refunds: BTreeMap<A, u64>) -> Self {
+        *self.others_refunded.write().unwrap() = refunds;
+        self
+    }
+
     fn reset(&self, expiry: SystemTime) {
         let mut guard = self.others.write().unwrap();
+        let mut refunds = self.others_refunded.write().unwrap();
         self.expiry.update(expiry);
         self.value.store(0, Ordering::SeqCst);
-        guard.clear()
+        self.refunded.store(0, Ordering::SeqCst);
+        guard.clear();
+        refunds.clear()
     }
 }
 
@@ -156,6 +271,8 @@ impl<A: Clone + Ord> Clone for CrCounterValue<A> {
             max_value: self.max_value,
             value: AtomicU64::new(self.value.load(Ordering::SeqCst)),
             others: RwLock::new(self.others.read().unwrap().clone()),
+            refunded: AtomicU64::new(self.refunded.load(Ordering::SeqCst)),
+            others_refunded: RwLock::new(self.others_refunded.read().unwrap().clone()),
             expiry: self.expiry.clone(),
         }
     }
@@ -168,6 +285,8 @@ impl<A: Clone + Ord + Default> From<(SystemTime, BTreeMap<A, u64>)> for CrCounte
             max_value: 0,
             value: Default::default(),
             others: RwLock::new(value.1),
+            refunded: Default::default(),
+            others_refunded: RwLock::default(),
             expiry: value.0.into(),
         }
     }
@@ -175,6 +294,7 @@ impl<A: Clone + Ord + Default> From<(SystemTime, BTreeMap<A, u64>)> for CrCounte
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
     use std::time::{Duration, SystemTime};
 
     use crate::storage::distributed::cr_counter_value::CrCounterValue;
@@ -289,6 +409,33 @@ mod tests {
         assert_eq!(a.read(), 2);
     }
 
+    #[test]
+    fn refunds_never_go_below_zero() {
+        let window = Duration::from_secs(1);
+        let a = CrCounterValue::new('A', u64::MAX, window);
+        a.inc(3, window);
+        a.refund(2);
+        assert_eq!(a.read(), 1);
+        a.refund(5);
+        assert_eq!(a.read(), 0);
+        a.inc(2, window);
+        assert_eq!(a.read(), 2);
+    }
+
+    #[test]
+    fn merges_refunds() {
+        let window = Duration::from_secs(1);
+        let a = CrCounterValue::new('A', u64::MAX, window);
+        let b = CrCounterValue::new('B', u64::MAX, window);
+        a.inc(3, window);
+        b.inc(2, window);
+        b.refund(1);
+        a.merge(b.clone());
+        assert_eq!(a.read(), 4);
+        b.merge(a);
+        assert_eq!(b.read(), 4);
+    }
+
     #[test]
     fn merge_uses_earliest_expiry() {
         let later = Duration::from_secs(1);
@@ -300,4 +447,39 @@ mod tests {
         a.merge(b);
         assert!(a.expiry.ttl() < sooner);
     }
+
+    #[test]
+    fn reads_the_most_any_actor_counted_or_our_own_count() {
+        let window = Duration::from_secs(1);
+        let now = SystemTime::now();
+        let a = CrCounterValue::new('A', u64::MAX, window);
+        a.inc_at(2, window, now);
+        a.inc_actor_at('B', 5, window, now);
+        a.inc_actor_at('C', 3, window, now);
+        assert_eq!(a.read_at(now), 10);
+        assert_eq!(a.read_max_at(now), 5);
+        assert_eq!(a.read_local_at(now), 2);
+
+        a.refund_at(2, now);
+        assert_eq!(a.read_local_at(now), 0);
+        assert_eq!(a.read_max_at(now + window), 0);
+    }
+
+    #[test]
+    fn reads_what_some_actors_counted() {
+        let window = Duration::from_secs(1);
+        let now = SystemTime::now();
+        let a = CrCounterValue::new('A', u64::MAX, window);
+        a.inc_at(2, window, now);
+        a.inc_actor_at('B', 5, window, now);
+        a.inc_actor_at('C', 3, window, now);
+        a.merge_at(
+            CrCounterValue::from((now + window, BTreeMap::from([('C', 3)])))
+                .with_refunds(BTreeMap::from([('C', 1)])),
+            now,
+        );
+        assert_eq!(a.read_of_at(now, |actor| *actor != 'B'), 4);
+        assert_eq!(a.read_of_at(now, |actor| *actor == 'B'), 5);
+        assert_eq!(a.read_of_at(now + window, |_| true), 0);
+    }
 }
@@ -1,16 +1,19 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::ops::Add;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{error::Error, io::ErrorKind, pin::Pin};
 
+use crate::clock::Clock;
 use crate::counter::Counter;
 use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Permit, Sender};
-use tokio::sync::{broadcast, mpsc, Notify, RwLock};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
 use tokio::time::sleep;
 use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::transport::{Endpoint, Server};
 use tonic::{Code, Request, Response, Status, Streaming};
 use tracing::debug;
 
@@ -19,8 +22,11 @@ use crate::storage::distributed::grpc::v1::packet::Message;
 use crate::storage::distributed::grpc::v1::replication_client::ReplicationClient;
 use crate::storage::distributed::grpc::v1::replication_server::{Replication, ReplicationServer};
 use crate::storage::distributed::grpc::v1::{
-    CounterUpdate, Empty, Hello, MembershipUpdate, Packet, Peer, Pong,
+    Compressed, CounterUpdate, CounterUpdates, Empty, Hello, MembershipUpdate, Packet, Peer,
+    PeerState, Pong,
 };
+use crate::storage::distributed::{Batching, Compression, PeerAuth, PeerDiscovery};
+use prost::Message as _;
 
 // clippy will barf on protobuff generated code for enum variants in
 // v3::socket_option::SocketState, so allow this lint
@@ -29,6 +35,17 @@ pub mod v1 {
     tonic::include_proto!("limitador.service.distributed.v1");
 }
 
+// Sessions ping their peer every PROBE_INTERVAL, and end when nothing was heard
+// from it for PROBE_TIMEOUT
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// A peer suspected for SUSPECT_TIMEOUT is declared dead, and then remembered as
+// such for DEAD_RETENTION, so that stale gossip doesn't bring it back
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEAD_RETENTION: Duration = Duration::from_secs(60);
+// How long to wait for the peer to catch up, when it can't take more updates
+const SEND_RETRY_DELAY: Duration = Duration::from_millis(10);
+
 #[derive(Copy, Clone, Debug)]
 enum ClockSkew {
     None(),
@@ -88,22 +105,41 @@ impl Session {
     async fn close(&mut self) {
         let mut replication_state = self.replication_state.write().await;
         if let Some(peer) = replication_state.peer_trackers.get_mut(&self.peer_id) {
-            peer.session = None;
+            // unless this session got replaced by another one with the same peer
+            let current = peer
+                .session
+                .as_ref()
+                .is_some_and(|session| session.out_stream.same_channel(&self.out_stream));
+            if current {
+                peer.session = None;
+                // we don't hear from the peer anymore, but others might still
+                if peer.state == PeerState::Alive {
+                    peer.set_state(PeerState::Suspect);
+                    self.broker_state.membership_changed();
+                }
+            }
         }
     }
 
+    async fn peers(&self) -> Vec<Peer> {
+        let state = self.replication_state.read().await;
+        state.peers(
+            &self.broker_state.id,
+            &self.broker_state.region,
+            self.broker_state.incarnation.load(Ordering::Acquire),
+        )
+    }
+
     async fn send(&self, message: Message) -> Result<(), Status> {
         self.out_stream.clone().send(Ok(message)).await
     }
 
     async fn process(&mut self, in_stream: &mut Streaming<Packet>) -> Result<(), Status> {
         // Send a MembershipUpdate to inform the peer about all the members
-        // We should resend it again if we learn of new members.
+        // It gets sent again whenever the membership changes.
+        let mut membership = self.broker_state.membership.subscribe();
         self.send(Message::MembershipUpdate(MembershipUpdate {
-            peers: {
-                let state = self.replication_state.read().await;
-                state.peers().clone()
-            },
+            peers: self.peers().await,
         }))
         .await?;
 
@@ -149,42 +185,77 @@ impl Session {
 
         let mut udpates_to_send = self.broker_state.publisher.subscribe();
         let mut tx_updates_by_key = HashMap::new();
-        let mut tx_updates_order = vec![];
-        let notifier = Notify::default();
+        let mut tx_updates_order = VecDeque::new();
+        let unsent_total = Arc::clone(&self.broker_state.unsent);
+        let mut unsent = UnsentUpdates::new(&unsent_total);
+        let batching = self.broker_state.batching;
+        let max_batch_size = batching.max_batch_size.max(1);
+        // When the pending updates are due to be sent, if any
+        let mut flush_at: Option<tokio::time::Instant> = None;
+        let mut probes = tokio::time::interval(PROBE_INTERVAL);
+        let mut last_heard = Instant::now();
 
         loop {
             tokio::select! {
+                _ = membership.changed() => {
+                    self.send(Message::MembershipUpdate(MembershipUpdate {
+                        peers: self.peers().await,
+                    }))
+                    .await?;
+                }
+                _ = probes.tick() => {
+                    if last_heard.elapsed() >= PROBE_TIMEOUT {
+                        return Err(Status::deadline_exceeded("peer stopped answering pings"));
+                    }
+                    self.send(Message::Ping(Empty::default())).await?;
+                }
                 update = udpates_to_send.recv() => {
                     let update = update.map_err(|_| Status::unknown("broadcast error"))?;
                     // Multiple updates collapse into a single update for the same key
                     let key = &update.key.clone();
                     if !tx_updates_by_key.contains_key(key) {
                         tx_updates_by_key.insert(key.clone(), update);
-                        tx_updates_order.push(key.clone());
-                        notifier.notify_one();
+                        tx_updates_order.push_back(key.clone());
+                        unsent.add();
+                        // a full batch doesn't wait for more
+                        if tx_updates_order.len() >= max_batch_size {
+                            flush_at = Some(tokio::time::Instant::now());
+                        } else if flush_at.is_none() {
+                            flush_at = Some(tokio::time::Instant::now() + batching.max_delay);
+                        }
                     }
                 }
-                _ = notifier.notified() => {
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(tokio::time::Instant::now)), if flush_at.is_some() => {
+                    flush_at = None;
                     // while we have pending updates to send...
                     while !tx_updates_order.is_empty() {
-                        // and we have space on the transmission channel to send the update...
+                        // and we have space on the transmission channel to send them...
                         match self.out_stream.clone().try_reserve() {
                             Err(_) => {
+                                // the peer is lagging, keep on coalescing meanwhile
+                                flush_at = Some(tokio::time::Instant::now() + SEND_RETRY_DELAY);
                                 break
                             },
                             Ok(permit) => {
-
-                                let key = tx_updates_order.remove(0);
-                                let cr_counter_value = tx_updates_by_key.remove(&key).unwrap().clone();
-                                let (expiry, values) = cr_counter_value.value.clone().into_inner();
-
-                                // only send the update if it has not expired.
-                                if expiry > SystemTime::now() {
-                                    permit.send(Ok(Message::CounterUpdate(CounterUpdate {
-                                        key,
-                                        values: values.into_iter().collect(),
-                                        expires_at: expiry.duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                                    })))?;
+                                let count = tx_updates_order.len().min(max_batch_size);
+                                let now = self.broker_state.clock.now();
+                                let updates = tx_updates_order
+                                    .drain(..count)
+                                    .filter_map(|key| {
+                                        let cr_counter_value = tx_updates_by_key.remove(&key).unwrap();
+                                        unsent.remove();
+                                        let (expiry, values, refunds) = cr_counter_value.value.clone().into_inner();
+                                        // only send the update if it has not expired.
+                                        (expiry > now).then(|| CounterUpdate {
+                                            key,
+                                            values: values.into_iter().collect(),
+                                            refunds: refunds.into_iter().collect(),
+                                            expires_at: expiry.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                                        })
+                                    })
+                                    .collect();
+                                if let Some(message) = frame(updates, &batching) {
+                                    permit.send(Ok(message))?;
                                 }
                             }
                         }
@@ -197,6 +268,7 @@ impl Session {
                             return Ok(())
                         },
                         Some(Ok(packet)) => {
+                            last_heard = Instant::now();
                             self.process_packet(packet).await?;
                         },
                         Some(Err(err)) => {
@@ -227,41 +299,49 @@ impl Session {
                     })))
                     .await?;
             }
+            Some(Message::Pong(_)) => {
+                // answering our pings is all it takes to be known alive
+                debug!("peer: '{}': Pong", self.peer_id);
+            }
             Some(Message::MembershipUpdate(update)) => {
                 debug!("peer: '{}': MembershipUpdate", self.peer_id);
-                // add any new peers to peer_trackers
+                let mut changed = false;
                 let mut state = self.replication_state.write().await;
                 for peer in update.peers {
-                    let peer_id = peer.peer_id.clone();
-                    match state.peer_trackers.get(&peer_id) {
-                        None => {
-                            // we are discovering a peer from neighbor, adding a tracker will
-                            // trigger a connection attempt to it.
-                            state.peer_trackers.insert(
-                                peer_id.clone(),
-                                PeerTracker {
-                                    peer_id,
-                                    url: None,
-                                    discovered_urls: peer.urls.iter().cloned().collect(),
-                                    latency: 0, // todo maybe set this to peer.latency + session.latency
-                                    clock_skew: ClockSkew::None(),
-                                    session: None,
-                                },
-                            );
-                        }
-                        Some(_peer_tracker) => {
-                            // // TODO: add discovered urls to the existing tracker.
-                            // peer.urls.clone().iter().for_each(|url| {
-                            //     peer_tracker.discovered_urls.insert(url.clone());
-                            // });
-                        }
+                    if peer.peer_id == self.broker_state.id {
+                        changed |= self.broker_state.refute(&peer);
+                    } else {
+                        changed |= state.merge(peer);
                     }
                 }
+                drop(state);
+                if changed {
+                    self.broker_state.membership_changed();
+                }
             }
             Some(Message::CounterUpdate(update)) => {
                 debug!("peer: '{}': CounterUpdate", self.peer_id);
                 (self.broker_state.on_counter_update)(update);
             }
+            Some(Message::CounterUpdates(updates)) => {
+                debug!("peer: '{}': CounterUpdates", self.peer_id);
+                updates
+                    .updates
+                    .into_iter()
+                    .for_each(|update| (self.broker_state.on_counter_update)(update));
+            }
+            Some(Message::Compressed(compressed)) => {
+                debug!("peer: '{}': Compressed", self.peer_id);
+                decompress(&compressed)?
+                    .updates
+                    .into_iter()
+                    .for_each(|update| (self.broker_state.on_counter_update)(update));
+            }
+            Some(Message::ReSyncEnd(_)) => {
+                // all the counters the peer had got applied ahead of this
+                debug!("peer: '{}': ReSyncEnd", self.peer_id);
+                self.broker_state.synced.send_replace(true);
+            }
             _ => {
                 debug!("peer: '{}': unsupported packet: {:?}", self.peer_id, packet);
                 return Err(Status::invalid_argument(format!(
@@ -284,6 +364,18 @@ struct PeerTracker {
     clock_skew: ClockSkew,
     // The communication session we have with the peer, may be None if not connected
     session: Option<Session>,
+    // What we know of the peer, as of its incarnation, and since when
+    incarnation: u64,
+    state: PeerState,
+    state_since: Instant,
+    region: String,
+}
+
+impl PeerTracker {
+    fn set_state(&mut self, state: PeerState) {
+        self.state = state;
+        self.state_since = Instant::now();
+    }
 }
 
 // Track the replication session with all peers.
@@ -294,8 +386,16 @@ struct ReplicationState {
 }
 
 impl ReplicationState {
-    fn peers(&self) -> Vec<Peer> {
-        let mut peers = Vec::new();
+    // All the members, ourselves included, so that peers learn of our incarnation
+    fn peers(&self, id: &str, region: &str, incarnation: u64) -> Vec<Peer> {
+        let mut peers = vec![Peer {
+            peer_id: id.to_string(),
+            latency: 0,
+            urls: self.discovered_urls.iter().map(String::to_owned).collect(),
+            incarnation,
+            state: PeerState::Alive.into(),
+            region: region.to_string(),
+        }];
         self.peer_trackers.iter().for_each(|(_, peer_tracker)| {
             peers.push(Peer {
                 peer_id: peer_tracker.peer_id.clone(),
@@ -305,11 +405,67 @@ impl ReplicationState {
                     .iter()
                     .map(String::to_owned)
                     .collect(), // peer_tracker.urls.clone().into_iter().collect()
+                incarnation: peer_tracker.incarnation,
+                state: peer_tracker.state.into(),
+                region: peer_tracker.region.clone(),
             });
         });
         peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
         peers
     }
+
+    // Applies what a peer gossiped about another one, SWIM style: news of a
+    // later incarnation always wins, while for the same one a suspicion
+    // overrides being alive, and a death overrides both. Returns whether
+    // anything changed.
+    fn merge(&mut self, peer: Peer) -> bool {
+        let state = peer.state();
+        match self.peer_trackers.get_mut(&peer.peer_id) {
+            None => {
+                if state == PeerState::Dead {
+                    return false;
+                }
+                // we are discovering a peer from neighbor, adding a tracker will
+                // trigger a connection attempt to it.
+                self.peer_trackers.insert(
+                    peer.peer_id.clone(),
+                    PeerTracker {
+                        peer_id: peer.peer_id,
+                        url: None,
+                        discovered_urls: peer.urls.into_iter().collect(),
+                        latency: 0, // todo maybe set this to peer.latency + session.latency
+                        clock_skew: ClockSkew::None(),
+                        session: None,
+                        incarnation: peer.incarnation,
+                        state,
+                        state_since: Instant::now(),
+                        region: peer.region,
+                    },
+                );
+                true
+            }
+            Some(tracker) => {
+                tracker.discovered_urls.extend(peer.urls);
+                if tracker.region.is_empty() {
+                    tracker.region = peer.region;
+                }
+                // we know better about the ones we are in session with, which
+                // refute the rumours about them on their own
+                if tracker.session.is_some() && state != PeerState::Alive {
+                    return false;
+                }
+                let newer = peer.incarnation > tracker.incarnation
+                    || (peer.incarnation == tracker.incarnation && state > tracker.state);
+                if newer {
+                    tracker.incarnation = peer.incarnation;
+                    if state != tracker.state {
+                        tracker.set_state(state);
+                    }
+                }
+                newer
+            }
+        }
+    }
 }
 
 fn match_for_io_error(err_status: &Status) -> Option<&std::io::Error> {
@@ -392,6 +548,18 @@ impl MessageSender {
             },
         }
     }
+    fn same_channel(&self, other: &MessageSender) -> bool {
+        match (self, other) {
+            (MessageSender::Server(sender), MessageSender::Server(other)) => {
+                sender.same_channel(other)
+            }
+            (MessageSender::Client(sender), MessageSender::Client(other)) => {
+                sender.same_channel(other)
+            }
+            _ => false,
+        }
+    }
+
     fn try_reserve(&self) -> Result<MessagePermit<'_>, Status> {
         match self {
             MessageSender::Client(sender) => {
@@ -435,6 +603,49 @@ impl MessagePermit<'_> {
     }
 }
 
+// A single update goes as is, more get batched in a single frame, and either
+// compressed when large enough
+fn frame(mut updates: Vec<CounterUpdate>, batching: &Batching) -> Option<Message> {
+    if let Some(compression) = batching.compression {
+        let batch = CounterUpdates { updates };
+        if batch.encoded_len() > batching.compression_threshold {
+            return Some(Message::Compressed(compress(compression, &batch)));
+        }
+        updates = batch.updates;
+    }
+    if updates.len() > 1 {
+        Some(Message::CounterUpdates(CounterUpdates { updates }))
+    } else {
+        updates.pop().map(Message::CounterUpdate)
+    }
+}
+
+fn compress(compression: Compression, updates: &CounterUpdates) -> Compressed {
+    let bytes = updates.encode_to_vec();
+    match compression {
+        Compression::Lz4 => Compressed {
+            compression: v1::Compression::Lz4.into(),
+            updates: lz4_flex::compress_prepend_size(&bytes),
+        },
+        Compression::Zstd => Compressed {
+            compression: v1::Compression::Zstd.into(),
+            updates: zstd::stream::encode_all(bytes.as_slice(), 0)
+                .expect("compressing in memory can't fail"),
+        },
+    }
+}
+
+fn decompress(compressed: &Compressed) -> Result<CounterUpdates, Status> {
+    let bytes = match compressed.compression() {
+        v1::Compression::Lz4 => lz4_flex::decompress_size_prepended(&compressed.updates)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?,
+        v1::Compression::Zstd => zstd::stream::decode_all(compressed.updates.as_slice())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?,
+    };
+    CounterUpdates::decode(bytes.as_slice())
+        .map_err(|err| Status::invalid_argument(err.to_string()))
+}
+
 type CounterUpdateFn = Pin<Box<dyn Fn(CounterUpdate) + Sync + Send>>;
 #[derive(Clone, Debug)]
 pub struct CounterEntry {
@@ -453,41 +664,125 @@ impl CounterEntry {
     }
 }
 
+// Counts the updates a session has yet to send, in the count shared by all of
+// them, until it ends
+struct UnsentUpdates<'a> {
+    total: &'a AtomicUsize,
+    count: usize,
+}
+
+impl<'a> UnsentUpdates<'a> {
+    fn new(total: &'a AtomicUsize) -> Self {
+        Self { total, count: 0 }
+    }
+
+    fn add(&mut self) {
+        self.count += 1;
+        self.total.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn remove(&mut self) {
+        self.count -= 1;
+        self.total.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Drop for UnsentUpdates<'_> {
+    fn drop(&mut self) {
+        self.total.fetch_sub(self.count, Ordering::AcqRel);
+    }
+}
+
 #[derive(Clone)]
 struct BrokerState {
     id: String,
     publisher: broadcast::Sender<Arc<CounterEntry>>,
     on_counter_update: Arc<CounterUpdateFn>,
     on_re_sync: Arc<Sender<Sender<Option<CounterUpdate>>>>,
+    unsent: Arc<AtomicUsize>,
+    incarnation: Arc<AtomicU64>,
+    membership: Arc<watch::Sender<()>>,
+    // Whether we got the counters of at least one peer
+    synced: Arc<watch::Sender<bool>>,
+    batching: Batching,
+    region: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl BrokerState {
+    // Has all the sessions gossip the membership to their peer
+    fn membership_changed(&self) {
+        self.membership.send_replace(());
+    }
+
+    // Peers suspecting us, or even declaring us dead, get proven wrong by a
+    // later incarnation of ours
+    fn refute(&self, peer: &Peer) -> bool {
+        let incarnation = self.incarnation.load(Ordering::Acquire);
+        if peer.state() == PeerState::Alive || peer.incarnation < incarnation {
+            return false;
+        }
+        debug!(
+            "peer '{}' refuting being {:?} as of incarnation {}",
+            self.id,
+            peer.state(),
+            peer.incarnation
+        );
+        self.incarnation
+            .store(peer.incarnation + 1, Ordering::Release);
+        true
+    }
 }
 
 #[derive(Clone)]
 pub struct Broker {
     listen_address: SocketAddr,
     peer_urls: Vec<String>,
+    auth: PeerAuth,
+    discovery: Option<PeerDiscovery>,
+    // The urls the discovery last resolved, retried as the peer_urls are
+    discovered_peer_urls: Arc<std::sync::RwLock<HashSet<String>>>,
     broker_state: BrokerState,
     replication_state: Arc<RwLock<ReplicationState>>,
 }
 
 impl Broker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
+        region: String,
         listen_address: SocketAddr,
         peer_urls: Vec<String>,
         on_counter_update: CounterUpdateFn,
         on_re_sync: Sender<Sender<Option<CounterUpdate>>>,
+        auth: PeerAuth,
+        discovery: Option<PeerDiscovery>,
+        batching: Batching,
+        clock: Arc<dyn Clock>,
     ) -> Broker {
         let (tx, _) = broadcast::channel(16);
         let publisher: broadcast::Sender<Arc<CounterEntry>> = tx;
+        // without any peer to join, there is nothing to sync with
+        let (synced, _) = watch::channel(peer_urls.is_empty() && discovery.is_none());
 
         Broker {
             listen_address,
             peer_urls,
+            auth,
+            discovery,
+            discovered_peer_urls: Arc::default(),
             broker_state: BrokerState {
                 id,
                 publisher,
                 on_counter_update: Arc::new(on_counter_update),
                 on_re_sync: Arc::new(on_re_sync),
+                unsent: Arc::new(AtomicUsize::new(0)),
+                incarnation: Arc::new(AtomicU64::new(0)),
+                membership: Arc::new(watch::channel(()).0),
+                synced: Arc::new(synced),
+                batching,
+                region,
+                clock,
             },
             replication_state: Arc::new(RwLock::new(ReplicationState {
                 discovered_urls: HashSet::new(),
@@ -501,6 +796,32 @@ impl Broker {
         _ = self.broker_state.publisher.send(counter_update);
     }
 
+    /// The number of updates that were published, but not sent to all the
+    /// peers yet
+    pub fn unsent(&self) -> usize {
+        self.broker_state.publisher.len() + self.broker_state.unsent.load(Ordering::Acquire)
+    }
+
+    /// Waits, up to `timeout`, for a peer to have sent us all of its counters,
+    /// returning whether one did
+    pub async fn synced(&self, timeout: Duration) -> bool {
+        let mut synced = self.broker_state.synced.subscribe();
+        tokio::time::timeout(timeout, synced.wait_for(|synced| *synced))
+            .await
+            .is_ok_and(|result| result.is_ok())
+    }
+
+    /// The regions of the peers we know of, by their id, those not set aside
+    pub async fn regions(&self) -> HashMap<String, String> {
+        let state = self.replication_state.read().await;
+        state
+            .peer_trackers
+            .values()
+            .filter(|tracker| !tracker.region.is_empty())
+            .map(|tracker| (tracker.peer_id.clone(), tracker.region.clone()))
+            .collect()
+    }
+
     pub async fn start(&self) {
         self.clone().peer_urls.into_iter().for_each(|peer_url| {
             let broker = self.clone();
@@ -520,12 +841,23 @@ impl Broker {
             });
         });
 
-        // Periodically reconnect to failed peers
+        if let Some(discovery) = self.discovery.clone() {
+            let broker = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    broker.discover_peers(&discovery).await;
+                    sleep(discovery.period).await;
+                }
+            });
+        }
+
+        // Periodically reconnect to failed peers, and give up on the dead ones
         {
             let broker = self.clone();
             tokio::spawn(async move {
                 loop {
                     sleep(Duration::from_secs(1)).await;
+                    broker.detect_failures().await;
                     broker.reconnect_to_failed_peers().await;
                 }
             });
@@ -536,7 +868,13 @@ impl Broker {
             self.broker_state.id, self.listen_address
         );
 
-        tonic::transport::Server::builder()
+        let mut server = Server::builder();
+        if let Some(tls) = self.auth.server_tls() {
+            server = server
+                .tls_config(tls)
+                .expect("validated when creating the storage");
+        }
+        server
             .add_service(ReplicationServer::new(self.clone()))
             .serve(self.listen_address)
             .await
@@ -546,8 +884,15 @@ impl Broker {
     // Connect to a peer and start a replication session.  This returns once the session handshake
     // completes.
     async fn connect_to_peer(&self, peer_url: String) -> Result<(), Status> {
-        let mut client = match ReplicationClient::connect(peer_url.clone()).await {
-            Ok(client) => client,
+        let mut endpoint = Endpoint::from_shared(peer_url.clone())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        if let Some(tls) = self.auth.client_tls() {
+            endpoint = endpoint
+                .tls_config(tls)
+                .map_err(|err| Status::new(Code::Unknown, err.to_string()))?;
+        }
+        let mut client = match endpoint.connect().await {
+            Ok(channel) => ReplicationClient::new(channel),
             Err(err) => {
                 return Err(Status::new(Code::Unknown, err.to_string()));
             }
@@ -555,7 +900,9 @@ impl Broker {
 
         let (tx, rx) = mpsc::channel(1);
 
-        let mut in_stream = client.stream(ReceiverStream::new(rx)).await?.into_inner();
+        let mut request = Request::new(ReceiverStream::new(rx));
+        self.auth.authenticate(&mut request)?;
+        let mut in_stream = client.stream(request).await?.into_inner();
         let mut sender = MessageSender::Client(tx);
         let session = self
             .handshake(&mut in_stream, &mut sender, Some(peer_url))
@@ -591,7 +938,8 @@ impl Broker {
                 .peer_trackers
                 .iter()
                 .filter_map(|(_, peer_tracker)| {
-                    if peer_tracker.session.is_none() {
+                    let alive = peer_tracker.state != PeerState::Dead || self.is_seed(peer_tracker);
+                    if peer_tracker.session.is_none() && alive {
                         // first try to connect to the configured URL
                         let mut urls: Vec<_> = peer_tracker.url.iter().cloned().collect();
                         // Then try to connect to discovered urls.
@@ -622,6 +970,79 @@ impl Broker {
         }
     }
 
+    // Peers suspected for too long get declared dead, and the ones dead for
+    // long enough forgotten, but for the ones configured to join the cluster
+    // through, which keep on being retried
+    async fn detect_failures(&self) {
+        let mut changed = false;
+        let mut state = self.replication_state.write().await;
+        state
+            .peer_trackers
+            .retain(|_, tracker| match tracker.state {
+                PeerState::Suspect if tracker.state_since.elapsed() >= SUSPECT_TIMEOUT => {
+                    debug!("peer '{}' declared dead", tracker.peer_id);
+                    tracker.set_state(PeerState::Dead);
+                    changed = true;
+                    true
+                }
+                PeerState::Dead if tracker.state_since.elapsed() >= DEAD_RETENTION => {
+                    self.is_seed(tracker)
+                }
+                _ => true,
+            });
+        drop(state);
+        if changed {
+            self.broker_state.membership_changed();
+        }
+    }
+
+    fn is_seed(&self, tracker: &PeerTracker) -> bool {
+        tracker.url.as_ref().is_some_and(|url| {
+            self.peer_urls.contains(url) || self.discovered_peer_urls.read().unwrap().contains(url)
+        })
+    }
+
+    // Connects to the peers the DNS name resolves to that we aren't in session
+    // with yet, ourselves aside
+    async fn discover_peers(&self, discovery: &PeerDiscovery) {
+        let addresses = match tokio::net::lookup_host(&discovery.address).await {
+            Ok(addresses) => addresses,
+            Err(err) => {
+                debug!("failed to resolve peers '{}': {:?}", discovery.address, err);
+                return;
+            }
+        };
+        let scheme = if self.auth.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let urls: HashSet<String> = addresses
+            .map(|address| format!("{scheme}://{address}"))
+            .collect();
+        *self.discovered_peer_urls.write().unwrap() = urls.clone();
+
+        let known: HashSet<String> = {
+            let state = self.replication_state.read().await;
+            let in_session = state
+                .peer_trackers
+                .values()
+                .filter(|tracker| tracker.session.is_some())
+                .flat_map(|tracker| tracker.url.iter().chain(tracker.discovered_urls.iter()));
+            state
+                .discovered_urls
+                .iter()
+                .chain(in_session)
+                .cloned()
+                .collect()
+        };
+        for url in urls.difference(&known) {
+            if let Err(err) = self.connect_to_peer(url.clone()).await {
+                debug!("failed to connect with peer '{}': {:?}", url, err);
+            }
+        }
+    }
+
     // handshake is called when a new stream is created, it will handle the initial handshake
     // and updating the session state in the state.peer_trackers map.  Result is None if an
     // existing session is already established with the peer.
@@ -641,6 +1062,8 @@ impl Broker {
                     sender_peer_id: self.broker_state.id.clone(),
                     sender_urls: state.discovered_urls.clone().into_iter().collect(),
                     receiver_url: peer_url.clone(),
+                    sender_incarnation: self.broker_state.incarnation.load(Ordering::Acquire),
+                    sender_region: self.broker_state.region.clone(),
                 })))
                 .await?;
         }
@@ -648,6 +1071,15 @@ impl Broker {
         // Wait for the peer to tell us who he is...
         let peer_hello = read_hello(in_stream).await?;
 
+        // Discovered, or listed, peers can be ourselves, the url then being ours
+        if peer_hello.sender_peer_id == self.broker_state.id {
+            if let Some(url) = peer_url.or(peer_hello.receiver_url) {
+                let mut state = self.replication_state.write().await;
+                state.discovered_urls.insert(url);
+            }
+            return Ok(None);
+        }
+
         // respond with a Pong so the peer can calculate the round trip latency
         out_stream
             .clone()
@@ -707,6 +1139,12 @@ impl Broker {
                     }
                     None => {
                         tracker.session = Some(session.clone());
+                        tracker.incarnation =
+                            tracker.incarnation.max(peer_hello.sender_incarnation);
+                        tracker.region = peer_hello.sender_region.clone();
+                        if tracker.state != PeerState::Alive {
+                            tracker.set_state(PeerState::Alive);
+                        }
                         (tracker, Some(session))
                     }
                 }
@@ -727,6 +1165,10 @@ impl Broker {
                     latency: latency.as_millis() as u32,
                     clock_skew: ClockSkew::new(end, peer_time_adj),
                     session: Some(session.clone()),
+                    incarnation: peer_hello.sender_incarnation,
+                    state: PeerState::Alive,
+                    state_since: Instant::now(),
+                    region: peer_hello.sender_region.clone(),
                 };
 
                 debug!(
@@ -744,6 +1186,9 @@ impl Broker {
         if peer_url.is_some() {
             tracker.url.clone_from(&peer_url)
         }
+        if option.is_some() {
+            self.broker_state.membership_changed();
+        }
 
         Ok(option)
     }
@@ -759,6 +1204,7 @@ impl Replication for Broker {
         req: Request<Streaming<Packet>>,
     ) -> Result<Response<Self::StreamStream>, Status> {
         debug!("ReplicationServer::stream");
+        self.auth.authorize(&req)?;
 
         let mut in_stream = req.into_inner();
         let (tx, rx) = mpsc::channel(1);
@@ -792,3 +1238,97 @@ impl Replication for Broker {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::v1::packet::Message;
+    use super::v1::{CounterUpdate, Peer, PeerState};
+    use super::{decompress, frame, ReplicationState};
+    use crate::storage::distributed::{Batching, Compression};
+    use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
+
+    fn gossip(peer_id: &str, incarnation: u64, state: PeerState) -> Peer {
+        Peer {
+            peer_id: peer_id.to_string(),
+            latency: 0,
+            urls: vec![format!("http://{peer_id}:15001")],
+            incarnation,
+            state: state.into(),
+            region: String::new(),
+        }
+    }
+
+    #[test]
+    fn merges_gossip_by_incarnation_then_state() {
+        let mut state = ReplicationState {
+            discovered_urls: HashSet::new(),
+            peer_trackers: HashMap::new(),
+        };
+
+        // Peers never heard of are only tracked when not dead
+        assert!(!state.merge(gossip("a", 0, PeerState::Dead)));
+        assert!(state.merge(gossip("a", 0, PeerState::Alive)));
+        assert!(state.merge(gossip("a", 0, PeerState::Suspect)));
+        // Being alive, as of the same incarnation, doesn't clear the suspicion
+        assert!(!state.merge(gossip("a", 0, PeerState::Alive)));
+        // The peer refuting it with a later incarnation does
+        assert!(state.merge(gossip("a", 1, PeerState::Alive)));
+        assert!(state.merge(gossip("a", 1, PeerState::Dead)));
+        assert!(!state.merge(gossip("a", 1, PeerState::Suspect)));
+
+        let peers: Vec<_> = state
+            .peers("b", "", 3)
+            .iter()
+            .map(|peer| (peer.peer_id.clone(), peer.incarnation, peer.state()))
+            .collect();
+        assert_eq!(
+            peers,
+            vec![
+                ("a".to_string(), 1, PeerState::Dead),
+                ("b".to_string(), 3, PeerState::Alive),
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_batches_compressed_past_the_threshold() {
+        let updates: Vec<CounterUpdate> = (0..10u8)
+            .map(|i| CounterUpdate {
+                key: vec![i; 32],
+                values: HashMap::from([("a".to_string(), u64::from(i))]),
+                expires_at: 1,
+                refunds: HashMap::new(),
+            })
+            .collect();
+        let batching = |compression| Batching {
+            max_batch_size: 10,
+            max_delay: Duration::ZERO,
+            compression,
+            compression_threshold: 64,
+        };
+
+        assert!(matches!(
+            frame(updates[..1].to_vec(), &batching(None)),
+            Some(Message::CounterUpdate(_))
+        ));
+        match frame(updates.clone(), &batching(None)) {
+            Some(Message::CounterUpdates(batch)) => assert_eq!(batch.updates, updates),
+            other => panic!("expected a batch, got {other:?}"),
+        }
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            match frame(updates.clone(), &batching(Some(compression))) {
+                Some(Message::Compressed(compressed)) => {
+                    assert_eq!(decompress(&compressed).unwrap().updates, updates)
+                }
+                other => panic!("expected compressed updates, got {other:?}"),
+            }
+        }
+        // below the threshold, it's not worth it
+        assert!(matches!(
+            frame(updates[..1].to_vec(), &batching(Some(Compression::Lz4))),
+            Some(Message::CounterUpdate(_))
+        ));
+        assert!(frame(vec![], &batching(None)).is_none());
+    }
+}
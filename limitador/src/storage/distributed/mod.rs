@@ -8,23 +8,153 @@ use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
 use tracing::debug;
 
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
 use crate::limit::{Context, Limit};
 use crate::storage::distributed::cr_counter_value::CrCounterValue;
 use crate::storage::distributed::grpc::v1::CounterUpdate;
 use crate::storage::distributed::grpc::{Broker, CounterEntry};
-use crate::storage::keys::bin::key_for_counter_v2;
+use crate::storage::distributed::regions::RegionView;
+use crate::storage::keys::bin::{key_for_counter_v2, partial_counter_from_counter_key_v2};
+pub use crate::storage::peer_auth::{PeerAuth, PeerTls};
 use crate::storage::{Authorization, CounterStorage, StorageErr};
 
 mod cr_counter_value;
 mod grpc;
+mod regions;
+
+const FLUSH_POLLING_PERIOD: Duration = Duration::from_millis(10);
+const DEFAULT_CACHE_SIZE: u64 = 10_000;
+pub const DEFAULT_SYNC_TIMEOUT_MS: u64 = 5000;
+pub const DEFAULT_DISCOVERY_PERIOD_MS: u64 = 10_000;
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+pub const DEFAULT_MAX_BATCH_DELAY_MS: u64 = 0;
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+pub const DEFAULT_MIN_REGION_SHARE: f64 = 0.1;
 
 pub type LimitsMap = HashMap<Vec<u8>, Arc<CounterEntry>>;
 
+/// How the hits the peers counted add up, when deciding whether a request is
+/// within the limits, trading consistency for availability, or the other way
+/// around, during partitions
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ConflictResolution {
+    /// The hits of all the peers add up, which is as strict as it gets, as long
+    /// as they can replicate: peers cut off from one another can each admit up
+    /// to the limit
+    #[default]
+    Sum,
+    /// The counter is as high as the most any peer counted: the limits then
+    /// apply to the traffic of each peer, e.g. when all get the same traffic,
+    /// and only catch up on the ones lagging behind
+    MaxWins,
+    /// Each peer only admits its own share of the limits, the weight, e.g.
+    /// `0.25` for each of the 2 peers of a region getting half the traffic. As
+    /// long as the shares add up to `1` at most, the limits hold even during
+    /// partitions, at the cost of some peers limiting while others still have
+    /// room left.
+    WeightedShare(f64),
+    /// Each region only admits its share of the limits, which its peers
+    /// enforce without waiting on the other regions, so that the checks only
+    /// ever take the latency of the region
+    Regional(RegionalQuotas),
+}
+
+/// The limits split into the `shares` of the regions, e.g. `0.5` for both
+/// `eu` and `us`, that should add up to `1` at most, the peers of `region`
+/// only admitting theirs. When rebalancing, the shares then follow the
+/// traffic each region gets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegionalQuotas {
+    pub region: String,
+    pub shares: HashMap<String, f64>,
+    pub rebalancing: Option<Rebalancing>,
+}
+
+/// Every `period`, the shares of the regions move halfway towards the share of
+/// the hits they counted, regions without any traffic keeping `min_share` of
+/// the limits, so that they can still admit some.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rebalancing {
+    pub period: Duration,
+    pub min_share: f64,
+}
+
+impl RegionalQuotas {
+    fn validate(&self) -> Result<(), String> {
+        if !self.shares.contains_key(&self.region) {
+            return Err(format!("no share for the region '{}'", self.region));
+        }
+        if let Some((region, share)) = self
+            .shares
+            .iter()
+            .find(|(_, share)| !(0.0..=1.0).contains(*share))
+        {
+            return Err(format!(
+                "the share of '{region}', {share}, isn't within [0, 1]"
+            ));
+        }
+        // allowing for the rounding of shares like thirds
+        if self.shares.values().sum::<f64>() > 1.0 + 1e-9 {
+            return Err("the shares add up to more than 1".to_string());
+        }
+        match self.rebalancing {
+            Some(rebalancing) if rebalancing.period.is_zero() => {
+                Err("the rebalancing period can't be zero".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How the updates get sent to each peer: the ones to the same counter
+/// coalesce, until sent along with up to `max_batch_size` others in a single
+/// frame. The first update of a batch waits up to `max_delay` for others to
+/// join it, and frames larger than `compression_threshold` bytes get
+/// compressed, when a `compression` is set.
+#[derive(Clone, Copy, Debug)]
+pub struct Batching {
+    pub max_batch_size: usize,
+    pub max_delay: Duration,
+    pub compression: Option<Compression>,
+    pub compression_threshold: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_delay: Duration::from_millis(DEFAULT_MAX_BATCH_DELAY_MS),
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+/// lz4 is the cheaper one, zstd the one compressing best, e.g. for WAN links
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Lz4,
+    Zstd,
+}
+
+/// Peers looked up, every `period`, by resolving the DNS name of `address`, a
+/// `HOST:PORT`, e.g. of a Kubernetes headless service. Only the addresses
+/// still resolved keep on being retried once they fail, as any other peer
+/// joining the cluster through them.
+#[derive(Clone)]
+pub struct PeerDiscovery {
+    pub address: String,
+    pub period: Duration,
+}
+
 pub struct CrInMemoryStorage {
     identifier: String,
     limits: Arc<RwLock<LimitsMap>>,
     broker: Broker,
+    resolution: ConflictResolution,
+    regions: Arc<RwLock<RegionView>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl CounterStorage for CrInMemoryStorage {
@@ -35,9 +165,9 @@ impl CounterStorage for CrInMemoryStorage {
         let mut value = 0;
         let key = encode_counter_to_key(counter);
         if let Some(counter_value) = limits.get(&key) {
-            value = counter_value.value.read()
+            value = self.read(&counter_value.value, counter.max_value())
         }
-        Ok(counter.max_value() >= value + delta)
+        Ok(counter.max_value() >= value.saturating_add(counter.delta(delta)))
     }
 
     #[tracing::instrument(skip_all)]
@@ -50,10 +180,11 @@ impl CounterStorage for CrInMemoryStorage {
                 counter: Counter::new(limit.clone(), &Context::default())
                     .expect("counter creation can't fail! no vars to resolve!")
                     .expect("must have a counter"),
-                value: CrCounterValue::new(
+                value: CrCounterValue::new_at(
                     self.identifier.clone(),
                     limit.max_value(),
                     Duration::from_secs(limit.seconds()),
+                    self.clock.now(),
                 ),
             }));
         }
@@ -63,7 +194,8 @@ impl CounterStorage for CrInMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
         let mut limits = self.limits.write().unwrap();
-        let now = SystemTime::now();
+        let now = self.clock.now();
+        let delta = counter.delta(delta);
 
         let key = encode_counter_to_key(counter);
         match limits.entry(key.clone()) {
@@ -72,22 +204,35 @@ impl CounterStorage for CrInMemoryStorage {
                 let value = Arc::new(CounterEntry {
                     key: key.clone(),
                     counter: counter.clone(),
-                    value: CrCounterValue::new(
+                    value: CrCounterValue::new_at(
                         self.identifier.clone(),
                         counter.max_value(),
                         duration,
+                        now,
                     ),
                 });
-                self.increment_counter(value.clone(), delta, now);
+                self.increment_counter(value.clone(), counter.window(), delta, now);
                 entry.insert(value);
             }
             Entry::Occupied(entry) => {
-                self.increment_counter(entry.get().clone(), delta, now);
+                self.increment_counter(entry.get().clone(), counter.window(), delta, now);
             }
         };
         Ok(())
     }
 
+    #[tracing::instrument(skip_all)]
+    fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let limits = self.limits.read().unwrap();
+        if let Some(counter_entry) = limits.get(&encode_counter_to_key(counter)) {
+            counter_entry
+                .value
+                .refund_at(counter.delta(delta), self.clock.now());
+            self.broker.publish(Arc::clone(counter_entry));
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     fn check_and_update(
         &self,
@@ -96,24 +241,20 @@ impl CounterStorage for CrInMemoryStorage {
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
         let mut first_limited = None;
-        let mut counter_values_to_update: Vec<Vec<u8>> = Vec::new();
-        let now = SystemTime::now();
+        let mut counter_values_to_update: Vec<(Vec<u8>, Duration, u64)> = Vec::new();
+        let now = self.clock.now();
 
         let mut process_counter =
             |counter: &mut Counter, value: u64, delta: u64| -> Option<Authorization> {
                 if load_counters {
-                    let remaining = counter.max_value().checked_sub(value + delta);
+                    let remaining = counter.max_value().checked_sub(value.saturating_add(delta));
                     counter.set_remaining(remaining.unwrap_or(0));
                     if first_limited.is_none() && remaining.is_none() {
-                        first_limited = Some(Authorization::Limited(
-                            counter.limit().name().map(|n| n.to_owned()),
-                        ));
+                        first_limited = Some(Authorization::limited_by(counter));
                     }
                 }
                 if !Self::counter_is_within_limits(counter, Some(&value), delta) {
-                    return Some(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
+                    return Some(Authorization::limited_by(counter));
                 }
                 None
             };
@@ -121,6 +262,7 @@ impl CounterStorage for CrInMemoryStorage {
         // Process simple counters
         for counter in counters.iter_mut() {
             let key = encode_counter_to_key(counter);
+            let delta = counter.delta(delta);
 
             // most of the time the counter should exist, so first try with a read only lock
             // since that will allow us to have higher concurrency
@@ -130,14 +272,13 @@ impl CounterStorage for CrInMemoryStorage {
                 match limits.get(&key) {
                     None => false,
                     Some(store_value) => {
-                        if let Some(limited) =
-                            process_counter(counter, store_value.value.read(), delta)
-                        {
+                        let value = self.read(&store_value.value, counter.max_value());
+                        if let Some(limited) = process_counter(counter, value, delta) {
                             if !load_counters {
                                 return Ok(limited);
                             }
                         }
-                        counter_values_to_update.push(key);
+                        counter_values_to_update.push((key, counter.window(), delta));
                         true
                     }
                 }
@@ -150,19 +291,21 @@ impl CounterStorage for CrInMemoryStorage {
                 let store_value = limits.entry(key.clone()).or_insert(Arc::new(CounterEntry {
                     key: key.clone(),
                     counter: counter.clone(),
-                    value: CrCounterValue::new(
+                    value: CrCounterValue::new_at(
                         self.identifier.clone(),
                         counter.max_value(),
                         counter.window(),
+                        now,
                     ),
                 }));
 
-                if let Some(limited) = process_counter(counter, store_value.value.read(), delta) {
+                let value = self.read(&store_value.value, counter.max_value());
+                if let Some(limited) = process_counter(counter, value, delta) {
                     if !load_counters {
                         return Ok(limited);
                     }
                 }
-                counter_values_to_update.push(key);
+                counter_values_to_update.push((key, counter.window(), delta));
             }
         }
 
@@ -172,10 +315,12 @@ impl CounterStorage for CrInMemoryStorage {
 
         // Update counters
         let limits = self.limits.read().unwrap();
-        counter_values_to_update.into_iter().for_each(|key| {
-            let store_value = limits.get(&key).unwrap();
-            self.increment_counter(store_value.clone(), delta, now);
-        });
+        counter_values_to_update
+            .into_iter()
+            .for_each(|(key, window, delta)| {
+                let store_value = limits.get(&key).unwrap();
+                self.increment_counter(store_value.clone(), window, delta, now);
+            });
 
         Ok(Authorization::Ok)
     }
@@ -183,12 +328,13 @@ impl CounterStorage for CrInMemoryStorage {
     #[tracing::instrument(skip_all)]
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
+        let now = self.clock.now();
         let limits_map = self.limits.read().unwrap();
         for (_, counter_entry) in limits_map.iter() {
-            if limits.contains(counter_entry.counter.limit()) {
-                let mut counter: Counter = counter_entry.counter.clone();
-                counter.set_remaining(counter.max_value() - counter_entry.value.read());
-                counter.set_expires_in(counter_entry.value.ttl());
+            if let Some(mut counter) = counter_of_limits(&counter_entry.counter, limits) {
+                let value = self.read(&counter_entry.value, counter.max_value());
+                counter.set_remaining(counter.max_value().saturating_sub(value));
+                counter.set_expires_in(counter_entry.value.ttl_at(now));
                 if counter.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter);
                 }
@@ -197,6 +343,15 @@ impl CounterStorage for CrInMemoryStorage {
         Ok(res)
     }
 
+    #[tracing::instrument(skip_all)]
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.limits
+            .write()
+            .unwrap()
+            .remove(&encode_counter_to_key(counter));
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
         for limit in limits {
@@ -210,24 +365,72 @@ impl CounterStorage for CrInMemoryStorage {
         self.limits.write().unwrap().clear();
         Ok(())
     }
+
+    // Waits for the sessions to have sent the updates to the peers they're
+    // connected to, which they might never do, so callers should bound this
+    // with a timeout
+    #[tracing::instrument(skip_all)]
+    fn flush(&self) -> Result<(), StorageErr> {
+        while self.broker.unsent() > 0 {
+            std::thread::sleep(FLUSH_POLLING_PERIOD);
+        }
+        Ok(())
+    }
 }
 
 impl CrInMemoryStorage {
     pub fn new(
         identifier: String,
-        _cache_size: u64,
+        cache_size: u64,
         listen_address: String,
         peer_urls: Vec<String>,
     ) -> Self {
+        CrInMemoryStorageBuilder::new(identifier, listen_address)
+            .cache_size(cache_size)
+            .peer_urls(peer_urls)
+            .build()
+            .expect("no peer authentication to get wrong")
+    }
+
+    fn start(builder: CrInMemoryStorageBuilder) -> Result<Self, StorageErr> {
+        let CrInMemoryStorageBuilder {
+            identifier,
+            cache_size: _,
+            listen_address,
+            peer_urls,
+            auth,
+            discovery,
+            batching,
+            resolution,
+            clock,
+        } = builder;
+        auth.validate().map_err(|e| StorageErr {
+            msg: format!("invalid replication TLS configuration: {e}"),
+            source: None,
+            transient: false,
+        })?;
+        let region = match &resolution {
+            ConflictResolution::Regional(quotas) => {
+                quotas.validate().map_err(|e| StorageErr {
+                    msg: format!("invalid regional quotas: {e}"),
+                    source: None,
+                    transient: false,
+                })?;
+                quotas.region.clone()
+            }
+            _ => String::new(),
+        };
         let listen_address = listen_address.to_socket_addrs().unwrap().next().unwrap();
-        let peer_urls = peer_urls.clone();
         let limits = Arc::new(RwLock::new(LimitsMap::new()));
 
         let limits_clone = limits.clone();
+        let identifier_clone = identifier.clone();
+        let clock_clone = clock.clone();
 
         let (re_sync_queue_tx, mut re_sync_queue_rx) = mpsc::channel(100);
         let broker = grpc::Broker::new(
             identifier.clone(),
+            region,
             listen_address,
             peer_urls,
             Box::pin(move |update: CounterUpdate| {
@@ -237,13 +440,50 @@ impl CrInMemoryStorage {
                         .iter()
                         .map(|(k, v)| (k.to_owned(), v.to_owned())),
                 );
-                let limits = limits_clone.read().unwrap();
-                let value = limits.get(&update.key).unwrap();
-                value
-                    .value
-                    .merge((UNIX_EPOCH + Duration::from_secs(update.expires_at), values).into());
+                let refunds = BTreeMap::from_iter(
+                    update
+                        .refunds
+                        .iter()
+                        .map(|(k, v)| (k.to_owned(), v.to_owned())),
+                );
+                let value = {
+                    let limits = limits_clone.read().unwrap();
+                    limits.get(&update.key).cloned()
+                };
+                // peers share counters we never got hit for, e.g. when joining
+                let value = value.unwrap_or_else(|| {
+                    let mut limits = limits_clone.write().unwrap();
+                    limits
+                        .entry(update.key.clone())
+                        .or_insert_with(|| {
+                            Arc::new(CounterEntry::new(
+                                update.key.clone(),
+                                partial_counter_from_counter_key_v2(&update.key),
+                                // expired, for the update to set the expiry
+                                CrCounterValue::new_at(
+                                    identifier_clone.clone(),
+                                    0,
+                                    Duration::ZERO,
+                                    clock_clone.now(),
+                                ),
+                            ))
+                        })
+                        .clone()
+                });
+                value.value.merge_at(
+                    CrCounterValue::from((
+                        UNIX_EPOCH + Duration::from_secs(update.expires_at),
+                        values,
+                    ))
+                    .with_refunds(refunds),
+                    clock_clone.now(),
+                );
             }),
             re_sync_queue_tx,
+            auth,
+            discovery,
+            batching,
+            clock.clone(),
         );
 
         {
@@ -256,17 +496,59 @@ impl CrInMemoryStorage {
         // process the re-sync requests...
         {
             let limits = limits.clone();
+            let clock = clock.clone();
             tokio::spawn(async move {
                 while let Some(sender) = re_sync_queue_rx.recv().await {
-                    process_re_sync(&limits, sender).await;
+                    process_re_sync(&limits, sender, clock.as_ref()).await;
                 }
             });
         }
 
-        Self {
+        let regions = Arc::new(RwLock::new(RegionView::default()));
+        if let ConflictResolution::Regional(quotas) = &resolution {
+            *regions.write().unwrap() = RegionView::new(&identifier, quotas);
+            tokio::spawn(regions::reconcile(
+                identifier.clone(),
+                quotas.clone(),
+                broker.clone(),
+                limits.clone(),
+                regions.clone(),
+                clock.clone(),
+            ));
+        }
+
+        Ok(Self {
             identifier,
             limits,
             broker,
+            resolution,
+            regions,
+            clock,
+        })
+    }
+
+    /// Waits, up to `timeout`, for the counters of a peer to be applied, so
+    /// that a joining node doesn't start counting from zero. Returns whether
+    /// they were, which is always the case without any peer to join.
+    pub async fn synced(&self, timeout: Duration) -> bool {
+        self.broker.synced(timeout).await
+    }
+
+    // The value of the counter, as of the conflict resolution. A share of the
+    // limit reads as what's left of it being all that's left of the limit.
+    fn read(&self, value: &CrCounterValue<String>, max_value: u64) -> u64 {
+        let now = self.clock.now();
+        match &self.resolution {
+            ConflictResolution::Sum => value.read_at(now),
+            ConflictResolution::MaxWins => value.read_max_at(now),
+            ConflictResolution::WeightedShare(weight) => {
+                read_share(max_value, *weight, value.read_local_at(now))
+            }
+            ConflictResolution::Regional(quotas) => {
+                let regions = self.regions.read().unwrap();
+                let counted = value.read_of_at(now, |actor| regions.is_in(actor, &quotas.region));
+                read_share(max_value, regions.share(&quotas.region), counted)
+            }
         }
     }
 
@@ -277,20 +559,104 @@ impl CrInMemoryStorage {
 
     fn counter_is_within_limits(counter: &Counter, current_val: Option<&u64>, delta: u64) -> bool {
         match current_val {
-            Some(current_val) => current_val + delta <= counter.max_value(),
+            Some(current_val) => current_val.saturating_add(delta) <= counter.max_value(),
             None => counter.max_value() >= delta,
         }
     }
 
-    fn increment_counter(&self, counter_entry: Arc<CounterEntry>, delta: u64, when: SystemTime) {
-        counter_entry
-            .value
-            .inc_at(delta, counter_entry.counter.window(), when);
+    // The window comes from the counter being hit, as the entry's one might
+    // only be partial, when we learnt about it from a peer
+    fn increment_counter(
+        &self,
+        counter_entry: Arc<CounterEntry>,
+        window: Duration,
+        delta: u64,
+        when: SystemTime,
+    ) {
+        counter_entry.value.inc_at(delta, window, when);
         self.broker.publish(counter_entry)
     }
 }
 
-async fn process_re_sync(limits: &Arc<RwLock<LimitsMap>>, sender: Sender<Option<CounterUpdate>>) {
+pub struct CrInMemoryStorageBuilder {
+    identifier: String,
+    cache_size: u64,
+    listen_address: String,
+    peer_urls: Vec<String>,
+    auth: PeerAuth,
+    discovery: Option<PeerDiscovery>,
+    batching: Batching,
+    resolution: ConflictResolution,
+    clock: Arc<dyn Clock>,
+}
+
+impl CrInMemoryStorageBuilder {
+    pub fn new(identifier: String, listen_address: String) -> Self {
+        Self {
+            identifier,
+            cache_size: DEFAULT_CACHE_SIZE,
+            listen_address,
+            peer_urls: Vec::new(),
+            auth: PeerAuth::default(),
+            discovery: None,
+            batching: Batching::default(),
+            resolution: ConflictResolution::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn cache_size(mut self, cache_size: u64) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// The peers to join the cluster through, the others being discovered
+    pub fn peer_urls(mut self, peer_urls: Vec<String>) -> Self {
+        self.peer_urls = peer_urls;
+        self
+    }
+
+    /// How the replication traffic gets authenticated
+    pub fn peer_auth(mut self, auth: PeerAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Where to periodically look up the peers at, on top of the `peer_urls`
+    pub fn peer_discovery(mut self, discovery: PeerDiscovery) -> Self {
+        self.discovery = Some(discovery);
+        self
+    }
+
+    pub fn batching(mut self, batching: Batching) -> Self {
+        self.batching = batching;
+        self
+    }
+
+    pub fn conflict_resolution(mut self, resolution: ConflictResolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// The clock the windows of the counters are measured against, rather
+    /// than the one of the system
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Starts replicating with the peers, failing when the certificates of the
+    /// `peer_auth`, or the regional quotas, are invalid
+    pub fn build(self) -> Result<CrInMemoryStorage, StorageErr> {
+        CrInMemoryStorage::start(self)
+    }
+}
+
+async fn process_re_sync(
+    limits: &Arc<RwLock<LimitsMap>>,
+    sender: Sender<Option<CounterUpdate>>,
+    clock: &dyn Clock,
+) {
     // sending all the counters to the peer might take a while, so we don't want to lock
     // the limits map for too long, lets figure first get the list of keys that needs to be sent.
     let keys: Vec<_> = {
@@ -302,14 +668,16 @@ async fn process_re_sync(limits: &Arc<RwLock<LimitsMap>>, sender: Sender<Option<
         let update = {
             let limits = limits.read().unwrap();
             limits.get(&key).and_then(|store_value| {
-                let (expiry, ourself, value) = store_value.value.local_values();
-                if value == 0 || expiry <= SystemTime::now() {
+                // all that we know of, so that a joining peer doesn't need to
+                // hear from everyone to catch up
+                let (expiry, values, refunds) = store_value.value.clone().into_inner();
+                if values.values().all(|value| *value == 0) || expiry <= clock.now() {
                     None // no point in sending a counter that is empty
                 } else {
-                    let values = HashMap::from([(ourself.clone(), value)]);
                     Some(CounterUpdate {
                         key: key.clone(),
-                        values,
+                        values: values.into_iter().collect(),
+                        refunds: refunds.into_iter().collect(),
                         expires_at: expiry.duration_since(UNIX_EPOCH).unwrap().as_secs(),
                     })
                 }
@@ -330,6 +698,29 @@ async fn process_re_sync(limits: &Arc<RwLock<LimitsMap>>, sender: Sender<Option<
     _ = sender.send(None).await;
 }
 
+// What's left of the `weight` of the limit, after what was `counted` against it,
+// being all that's left of the limit
+fn read_share(max_value: u64, weight: f64, counted: u64) -> u64 {
+    let share = (max_value as f64 * weight.clamp(0.0, 1.0)) as u64;
+    max_value.saturating_sub(share.saturating_sub(counted))
+}
+
+// The counter of an entry, as of the limit it counts for, if among these. The
+// counters we learnt about from peers lack most of their limit, and those with
+// an id even the namespace.
+fn counter_of_limits(counter: &Counter, limits: &HashSet<Arc<Limit>>) -> Option<Counter> {
+    let limit = match counter.id() {
+        Some(id) => limits.iter().find(|limit| limit.id() == Some(id)),
+        None => limits.get(counter.limit()),
+    }?;
+    let variables = counter
+        .set_variables()
+        .iter()
+        .map(|(var, value)| (var.clone(), value.clone()))
+        .collect();
+    Counter::resolved_vars(Arc::clone(limit), variables).ok()
+}
+
 fn encode_counter_to_key(counter: &Counter) -> Vec<u8> {
     key_for_counter_v2(counter)
 }
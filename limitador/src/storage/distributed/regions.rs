@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::Clock;
+
+use crate::storage::distributed::grpc::Broker;
+use crate::storage::distributed::{LimitsMap, RegionalQuotas};
+
+// How often the regions of the peers get refreshed, as they get to know one
+// another, regardless of the shares getting rebalanced
+const REFRESH_PERIOD: Duration = Duration::from_secs(1);
+
+/// What the regional quotas are enforced as of
+#[derive(Default)]
+pub(super) struct RegionView {
+    // The region of each peer, ourselves included, by their id
+    regions: HashMap<String, String>,
+    shares: HashMap<String, f64>,
+}
+
+impl RegionView {
+    pub fn new(identifier: &str, quotas: &RegionalQuotas) -> Self {
+        Self {
+            regions: HashMap::from([(identifier.to_string(), quotas.region.clone())]),
+            shares: quotas.shares.clone(),
+        }
+    }
+
+    pub fn share(&self, region: &str) -> f64 {
+        self.shares.get(region).copied().unwrap_or_default()
+    }
+
+    pub fn is_in(&self, actor: &str, region: &str) -> bool {
+        self.regions.get(actor).is_some_and(|r| r == region)
+    }
+}
+
+// Keeps the view up to date with the regions of the peers, and, when
+// rebalancing, moves the quota a region leaves unused to the busier ones. All
+// the peers rebalance from the same replicated counters, so that they mostly
+// agree on the shares.
+pub(super) async fn reconcile(
+    identifier: String,
+    quotas: RegionalQuotas,
+    broker: Broker,
+    limits: Arc<RwLock<LimitsMap>>,
+    view: Arc<RwLock<RegionView>>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut interval = tokio::time::interval(REFRESH_PERIOD);
+    let mut rebalanced = Instant::now();
+    loop {
+        interval.tick().await;
+        let mut regions = broker.regions().await;
+        regions.insert(identifier.clone(), quotas.region.clone());
+
+        let shares = match quotas.rebalancing {
+            Some(rebalancing) if rebalanced.elapsed() >= rebalancing.period => {
+                rebalanced = Instant::now();
+                let traffic = traffic(&limits, &regions, quotas.shares.keys(), clock.now());
+                let previous = view.read().unwrap().shares.clone();
+                Some(rebalance(&previous, &traffic, rebalancing.min_share))
+            }
+            _ => None,
+        };
+
+        let mut current = view.write().unwrap();
+        current.regions = regions;
+        if let Some(shares) = shares {
+            current.shares = shares;
+        }
+    }
+}
+
+// The hits each region counted, across all the counters
+fn traffic<'a>(
+    limits: &RwLock<LimitsMap>,
+    regions_of_peers: &HashMap<String, String>,
+    regions: impl Iterator<Item = &'a String>,
+    now: SystemTime,
+) -> HashMap<String, u64> {
+    let entries: Vec<_> = limits.read().unwrap().values().cloned().collect();
+    regions
+        .map(|region| {
+            let hits = entries
+                .iter()
+                .map(|entry| {
+                    entry.value.read_of_at(now, |actor| {
+                        regions_of_peers.get(actor).is_some_and(|r| r == region)
+                    })
+                })
+                .sum();
+            (region.clone(), hits)
+        })
+        .collect()
+}
+
+// Moves the shares halfway towards the traffic the regions got, even ones
+// without any, none getting less than the `min_share`, before they all get
+// scaled back to adding up to the whole limits
+fn rebalance(
+    previous: &HashMap<String, f64>,
+    traffic: &HashMap<String, u64>,
+    min_share: f64,
+) -> HashMap<String, f64> {
+    let total: u64 = traffic.values().sum();
+    let even = 1.0 / traffic.len() as f64;
+    let shares: HashMap<String, f64> = traffic
+        .iter()
+        .map(|(region, hits)| {
+            let observed = match total {
+                0 => even,
+                total => *hits as f64 / total as f64,
+            };
+            let previous = previous.get(region).copied().unwrap_or(even);
+            (region.clone(), ((previous + observed) / 2.0).max(min_share))
+        })
+        .collect();
+    let sum: f64 = shares.values().sum();
+    shares
+        .into_iter()
+        .map(|(region, share)| (region, share / sum))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rebalance;
+    use std::collections::HashMap;
+
+    #[test]
+    fn rebalances_the_unused_quota_towards_the_busier_regions() {
+        let shares = HashMap::from([("eu".to_string(), 0.5), ("us".to_string(), 0.5)]);
+        let traffic = HashMap::from([("eu".to_string(), 50), ("us".to_string(), 10)]);
+
+        let shares = rebalance(&shares, &traffic, 0.1);
+        assert!((shares["eu"] - 0.6667).abs() < 0.001, "{shares:?}");
+        assert!((shares["us"] - 0.3333).abs() < 0.001, "{shares:?}");
+
+        // Idle regions keep their minimal share
+        let traffic = HashMap::from([("eu".to_string(), 50), ("us".to_string(), 0)]);
+        let mut rebalanced = shares;
+        for _ in 0..10 {
+            rebalanced = rebalance(&rebalanced, &traffic, 0.1);
+        }
+        assert!((rebalanced["us"] - 0.095).abs() < 0.001, "{rebalanced:?}");
+        assert!((rebalanced.values().sum::<f64>() - 1.0).abs() < 1e-9);
+
+        // Without any traffic, the shares go back to being even
+        let traffic = HashMap::from([("eu".to_string(), 0), ("us".to_string(), 0)]);
+        for _ in 0..20 {
+            rebalanced = rebalance(&rebalanced, &traffic, 0.1);
+        }
+        assert!((rebalanced["eu"] - 0.5).abs() < 0.001, "{rebalanced:?}");
+    }
+}
@@ -0,0 +1,460 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::keys::bin::{
+    key_for_counter, partial_counter_from_counter_key, prefix_for_namespace,
+};
+use crate::storage::{
+    from_hex, invalid_cursor, parse_cursor, to_hex, AsyncCounterStorage, Authorization,
+    CounterFilter, CountersPage, StorageErr,
+};
+use async_trait::async_trait;
+use etcd_client::{
+    Client, Compare, CompareOp, DeleteOptions, GetOptions, KeyValue, PutOptions, Txn, TxnOp,
+    TxnOpResponse,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info_span, Instrument};
+
+const KEY_PREFIX: &[u8] = b"limitador/";
+const MAX_ATTEMPTS: usize = 10;
+
+// Counters are stored as plain decimal values, attached to a lease that
+// expires with their window. Updates are optimistic: the values are read, then
+// written in a transaction that only succeeds if none of them got modified in
+// the meantime, retrying otherwise.
+pub struct EtcdStorage {
+    client: Client,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for EtcdStorage {
+    #[tracing::instrument(skip_all)]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let mut client = self.client.clone();
+        let response = client
+            .get(etcd_key(counter), None)
+            .instrument(info_span!("datastore"))
+            .await?;
+        let value = response.kvs().first().map(value_of).unwrap_or(Ok(0))?;
+        Ok(value.saturating_add(counter.delta(delta)) <= counter.max_value())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let mut counters = vec![counter.clone()];
+        self.update(&mut counters, delta, false, false).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.update(counters, delta, true, load_counters).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        let mut client = self.client.clone();
+        let mut counters = HashSet::default();
+        let namespaces: BTreeSet<&str> = limits.iter().map(|l| l.namespace().as_ref()).collect();
+        for ns in namespaces {
+            let mut prefix = KEY_PREFIX.to_vec();
+            prefix.extend(prefix_for_namespace(ns));
+            let response = client
+                .get(prefix, Some(GetOptions::new().with_prefix()))
+                .instrument(info_span!("datastore"))
+                .await?;
+            for kv in response.kvs() {
+                let mut counter = partial_counter_from_counter_key(&kv.key()[KEY_PREFIX.len()..]);
+                if counter.namespace().as_ref() != ns {
+                    continue;
+                }
+                let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) else {
+                    continue;
+                };
+                counter.update_to_limit(Arc::clone(limit));
+                counter.set_remaining(counter.max_value().saturating_sub(value_of(kv)?));
+                counter.set_expires_in(self.ttl_of(kv).await?);
+                counters.insert(counter);
+            }
+        }
+        Ok(counters)
+    }
+
+    // Pages through the counters of each namespace in turn, in key order,
+    // ranging over the keys past the last one of the previous page rather than
+    // loading them all
+    #[tracing::instrument(skip_all)]
+    async fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let namespaces: Vec<&str> = limits
+            .iter()
+            .map(|l| l.namespace().as_ref())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let (mut index, position) = parse_cursor(cursor)?;
+        let mut after =
+            from_hex(position).ok_or_else(|| invalid_cursor(cursor.unwrap_or_default()))?;
+
+        let mut client = self.client.clone();
+        let mut page = CountersPage::default();
+        while index < namespaces.len() && page.counters.len() < page_size {
+            let mut prefix = KEY_PREFIX.to_vec();
+            prefix.extend(prefix_for_namespace(namespaces[index]));
+            // The first key past the last one of the previous page
+            let start = if after.is_empty() {
+                prefix.clone()
+            } else {
+                [after.as_slice(), &[0]].concat()
+            };
+            let response = client
+                .get(
+                    start,
+                    Some(
+                        GetOptions::new()
+                            .with_range(prefix_end(&prefix))
+                            .with_limit(page_size as i64),
+                    ),
+                )
+                .instrument(info_span!("datastore"))
+                .await?;
+            let mut exhausted = response.kvs().len() < page_size;
+            for kv in response.kvs() {
+                if page.counters.len() == page_size {
+                    exhausted = false;
+                    break;
+                }
+                after = kv.key().to_vec();
+                let mut counter = partial_counter_from_counter_key(&kv.key()[KEY_PREFIX.len()..]);
+                let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) else {
+                    continue;
+                };
+                counter.update_to_limit(Arc::clone(limit));
+                counter.set_remaining(counter.max_value().saturating_sub(value_of(kv)?));
+                counter.set_expires_in(self.ttl_of(kv).await?);
+                if filter.matches(&counter) {
+                    page.counters.push(counter);
+                }
+            }
+            if exhausted {
+                index += 1;
+                after = Vec::new();
+            }
+        }
+        if index < namespaces.len() {
+            page.next_cursor = Some(format!("{index}:{}", to_hex(&after)));
+        }
+        Ok(page)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        let mut client = self.client.clone();
+        client
+            .delete(etcd_key(counter), None)
+            .instrument(info_span!("datastore"))
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let mut client = self.client.clone();
+        for counter in self.get_counters(limits).await? {
+            client
+                .delete(etcd_key(&counter), None)
+                .instrument(info_span!("datastore"))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        let mut client = self.client.clone();
+        client
+            .delete(KEY_PREFIX, Some(DeleteOptions::new().with_prefix()))
+            .instrument(info_span!("datastore"))
+            .await?;
+        Ok(())
+    }
+}
+
+impl EtcdStorage {
+    /// Connects to the etcd cluster at `endpoints`, e.g. `["localhost:2379"]`
+    pub async fn new<E: AsRef<str>>(endpoints: &[E]) -> Result<Self, StorageErr> {
+        let client = Client::connect(endpoints, None).await?;
+        Ok(Self { client })
+    }
+
+    async fn update(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+        check: bool,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        // New counters get a lease for their window, shared by the ones with
+        // the same window. They are granted once, reused across the attempts,
+        // and the ones the successful transaction didn't attach revoked
+        let mut leases: HashMap<u64, i64> = HashMap::new();
+        let mut attached = HashSet::new();
+        let result = self
+            .try_update(
+                counters,
+                delta,
+                check,
+                load_counters,
+                &mut leases,
+                &mut attached,
+            )
+            .await;
+        let mut client = self.client.clone();
+        for lease in leases.into_values().filter(|l| !attached.contains(l)) {
+            // Best effort, the lease expires with its window anyway
+            let _ = client
+                .lease_revoke(lease)
+                .instrument(info_span!("datastore"))
+                .await;
+        }
+        result
+    }
+
+    async fn try_update(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+        check: bool,
+        load_counters: bool,
+        leases: &mut HashMap<u64, i64>,
+        attached: &mut HashSet<i64>,
+    ) -> Result<Authorization, StorageErr> {
+        let mut client = self.client.clone();
+        let keys: Vec<Vec<u8>> = counters.iter().map(etcd_key).collect();
+
+        for _ in 0..MAX_ATTEMPTS {
+            let read = Txn::new().and_then(
+                keys.iter()
+                    .map(|key| TxnOp::get(key.clone(), None))
+                    .collect::<Vec<_>>(),
+            );
+            let response = client.txn(read).instrument(info_span!("datastore")).await?;
+            let current: Vec<Option<KeyValue>> = response
+                .op_responses()
+                .into_iter()
+                .map(|op| match op {
+                    TxnOpResponse::Get(get) => get.kvs().first().cloned(),
+                    _ => None,
+                })
+                .collect();
+
+            let mut first_limited = None;
+            for (counter, kv) in counters.iter_mut().zip(&current) {
+                let value = kv.as_ref().map(value_of).unwrap_or(Ok(0))?;
+                let delta = counter.delta(delta);
+                if load_counters {
+                    counter.set_remaining(
+                        counter
+                            .max_value()
+                            .checked_sub(value.saturating_add(delta))
+                            .unwrap_or_default(),
+                    );
+                    counter.set_expires_in(match kv {
+                        Some(kv) => self.ttl_of(kv).await?,
+                        None => counter.window(),
+                    });
+                }
+                if check
+                    && first_limited.is_none()
+                    && value.saturating_add(delta) > counter.max_value()
+                {
+                    first_limited = Some(Authorization::limited_by(counter));
+                    if !load_counters {
+                        break;
+                    }
+                }
+            }
+            if let Some(limited) = first_limited {
+                return Ok(limited);
+            }
+
+            let mut used = HashSet::new();
+            let mut compares = Vec::with_capacity(keys.len());
+            let mut puts = Vec::with_capacity(keys.len());
+            for ((key, counter), kv) in keys.iter().zip(counters.iter()).zip(&current) {
+                let delta = counter.delta(delta);
+                match kv {
+                    Some(kv) => {
+                        compares.push(Compare::mod_revision(
+                            key.clone(),
+                            CompareOp::Equal,
+                            kv.mod_revision(),
+                        ));
+                        puts.push(TxnOp::put(
+                            key.clone(),
+                            value_of(kv)?.saturating_add(delta).to_string(),
+                            Some(PutOptions::new().with_ignore_lease()),
+                        ));
+                    }
+                    None => {
+                        let window = counter.window().as_secs().max(1);
+                        let lease = match leases.get(&window) {
+                            Some(lease) => *lease,
+                            None => {
+                                let lease = client
+                                    .lease_grant(window as i64, None)
+                                    .instrument(info_span!("datastore"))
+                                    .await?
+                                    .id();
+                                leases.insert(window, lease);
+                                lease
+                            }
+                        };
+                        used.insert(lease);
+                        compares.push(Compare::mod_revision(key.clone(), CompareOp::Equal, 0));
+                        puts.push(TxnOp::put(
+                            key.clone(),
+                            delta.to_string(),
+                            Some(PutOptions::new().with_lease(lease)),
+                        ));
+                    }
+                }
+            }
+
+            let write = Txn::new().when(compares).and_then(puts);
+            let response = client
+                .txn(write)
+                .instrument(info_span!("datastore"))
+                .await?;
+            if response.succeeded() {
+                *attached = used;
+                return Ok(Authorization::Ok);
+            }
+        }
+
+        Err(StorageErr {
+            msg: format!(
+                "counters kept being modified concurrently, gave up after {MAX_ATTEMPTS} attempts"
+            ),
+            source: None,
+            transient: true,
+        })
+    }
+
+    async fn ttl_of(&self, kv: &KeyValue) -> Result<Duration, StorageErr> {
+        if kv.lease() == 0 {
+            return Ok(Duration::ZERO);
+        }
+        let mut client = self.client.clone();
+        let response = client
+            .lease_time_to_live(kv.lease(), None)
+            .instrument(info_span!("datastore"))
+            .await?;
+        Ok(Duration::from_secs(
+            u64::try_from(response.ttl()).unwrap_or(0),
+        ))
+    }
+}
+
+// The end of the range of the keys starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+fn etcd_key(counter: &Counter) -> Vec<u8> {
+    let mut key = KEY_PREFIX.to_vec();
+    key.extend(key_for_counter(counter));
+    key
+}
+
+fn value_of(kv: &KeyValue) -> Result<u64, StorageErr> {
+    std::str::from_utf8(kv.value())
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| StorageErr {
+            msg: "corrupted counter value".to_string(),
+            source: None,
+            transient: false,
+        })
+}
+
+impl From<etcd_client::Error> for StorageErr {
+    fn from(error: etcd_client::Error) -> Self {
+        let transient = match &error {
+            etcd_client::Error::TransportError(_) | etcd_client::Error::IoError(_) => true,
+            etcd_client::Error::GRpcStatus(status) => matches!(
+                status.code(),
+                tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+            ),
+            _ => false,
+        };
+        Self {
+            msg: format!("Underlying storage error: {error}"),
+            source: Some(Box::new(error)),
+            transient,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{etcd_key, prefix_end, KEY_PREFIX};
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use crate::storage::keys::bin::{partial_counter_from_counter_key, prefix_for_namespace};
+    use std::collections::HashMap;
+
+    #[test]
+    fn keys_are_prefixed_and_scannable_by_namespace() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let key = etcd_key(&counter);
+        let mut prefix = KEY_PREFIX.to_vec();
+        prefix.extend(prefix_for_namespace("test_namespace"));
+        assert!(key.starts_with(&prefix));
+
+        let partial = partial_counter_from_counter_key(&key[KEY_PREFIX.len()..]);
+        assert_eq!(partial.namespace().as_ref(), "test_namespace");
+        assert_eq!(partial.limit(), counter.limit());
+    }
+
+    #[test]
+    fn ranges_over_the_keys_with_a_prefix() {
+        assert_eq!(prefix_end(b"ns"), b"nt".to_vec());
+        assert_eq!(prefix_end(&[b'n', u8::MAX]), b"o".to_vec());
+    }
+}
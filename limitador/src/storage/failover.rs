@@ -0,0 +1,481 @@
+use crate::counter::Counter;
+use crate::limit::{Limit, Namespace};
+use crate::observe::{counter, error, gauge, warn};
+use crate::storage::{
+    AsyncCounterStorage, Authorization, CounterFilter, CounterStorage, CountersPage, StorageErr,
+};
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RETRY_INTERVAL_SEC: u64 = 1;
+
+// Serves from the `primary` storage, e.g. Redis, and falls back to the
+// `secondary` one, e.g. in memory, as soon as the primary returns a transient
+// error. The primary is then retried every `retry_interval`. Once it recovers,
+// the updates the secondary served in windows that aren't over yet are written
+// to it in the background, and the secondary is cleared, to be ready for the
+// next outage.
+pub struct FailoverStorage {
+    primary: Arc<dyn AsyncCounterStorage>,
+    secondary: Arc<dyn CounterStorage>,
+    retry_interval: Duration,
+    failed_at: Arc<Mutex<Option<Instant>>>,
+    // What the secondary served, by counters without a cost, so that they're
+    // resynced as is
+    unsynced: Arc<Mutex<HashMap<Counter, Unsynced>>>,
+}
+
+// The delta, costs applied, the secondary served within the current window of
+// a counter, and when that window is over
+#[derive(Clone, Copy)]
+struct Unsynced {
+    delta: u64,
+    expires_at: Instant,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for FailoverStorage {
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        if self.use_primary() {
+            match self.primary.is_within_limits(counter, delta).await {
+                Ok(within) => {
+                    self.recovered();
+                    return Ok(within);
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.add_limits_to_secondary([counter])?;
+        self.secondary.is_within_limits(counter, delta)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.primary.add_counter(limit)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        if self.use_primary() {
+            match self.primary.update_counter(counter, delta).await {
+                Ok(()) => {
+                    self.recovered();
+                    return Ok(());
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.add_limits_to_secondary([counter])?;
+        self.secondary.update_counter(counter, delta)?;
+        self.track_unsynced([counter], delta);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        if self.use_primary() {
+            match self.primary.refund_counter(counter, delta).await {
+                Ok(()) => {
+                    self.recovered();
+                    return Ok(());
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        // What the secondary served is given back, rather than resynced
+        self.secondary.refund_counter(counter, delta)?;
+        if let Some(unsynced) = self.unsynced.lock().unwrap().get_mut(counter) {
+            unsynced.delta = unsynced.delta.saturating_sub(counter.delta(delta));
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        if self.use_primary() {
+            match self.primary.claim_token(namespace, token, horizon).await {
+                Ok(claimed) => {
+                    self.recovered();
+                    return Ok(claimed);
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.secondary.claim_token(namespace, token, horizon)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        if self.use_primary() {
+            match self.primary.release_token(namespace, token).await {
+                Ok(()) => {
+                    self.recovered();
+                    return Ok(());
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.secondary.release_token(namespace, token)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        if self.use_primary() {
+            match self
+                .primary
+                .check_and_update(counters, delta, load_counters)
+                .await
+            {
+                Ok(authorization) => {
+                    self.recovered();
+                    counter!("datastore_decisions", "tier" => "primary").increment(1);
+                    return Ok(authorization);
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+
+        self.add_limits_to_secondary(counters.iter())?;
+        let authorization = self
+            .secondary
+            .check_and_update(counters, delta, load_counters)?;
+        if matches!(authorization, Authorization::Ok) {
+            self.track_unsynced(counters.iter(), delta);
+        }
+        counter!("datastore_decisions", "tier" => "secondary").increment(1);
+        Ok(authorization)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        if self.use_primary() {
+            match self.primary.peek(counters, delta).await {
+                Ok(authorization) => {
+                    self.recovered();
+                    return Ok(authorization);
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.add_limits_to_secondary(counters.iter())?;
+        self.secondary.peek(counters, delta)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        if self.use_primary() {
+            match self.primary.get_counters(limits).await {
+                Ok(counters) => {
+                    self.recovered();
+                    return Ok(counters);
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.secondary.get_counters(limits)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        if self.use_primary() {
+            match self
+                .primary
+                .get_counters_page(limits, filter, cursor, page_size)
+                .await
+            {
+                Ok(page) => {
+                    self.recovered();
+                    return Ok(page);
+                }
+                Err(err) => self.failed(err)?,
+            }
+        }
+        self.secondary
+            .get_counters_page(limits, filter, cursor, page_size)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.secondary.delete_counter(counter)?;
+        self.unsynced.lock().unwrap().remove(counter);
+        self.primary.delete_counter(counter).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        self.secondary.delete_counters(limits)?;
+        self.unsynced
+            .lock()
+            .unwrap()
+            .retain(|counter, _| !limits.contains(counter.limit()));
+        self.primary.delete_counters(limits).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.secondary.clear()?;
+        self.unsynced.lock().unwrap().clear();
+        self.primary.clear().await
+    }
+
+    // What the secondary served during an ongoing outage can't be written
+    // anywhere, so only the primary gets flushed
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn flush(&self) -> Result<(), StorageErr> {
+        self.primary.flush().await
+    }
+
+    // Requests keep being served by the secondary while the primary is down
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    async fn ping(&self) -> Result<(), StorageErr> {
+        match self.primary.ping().await {
+            Ok(()) => Ok(()),
+            Err(_) => self.secondary.ping(),
+        }
+    }
+}
+
+impl FailoverStorage {
+    pub fn new(primary: Box<dyn AsyncCounterStorage>, secondary: Box<dyn CounterStorage>) -> Self {
+        Self {
+            primary: Arc::from(primary),
+            secondary: Arc::from(secondary),
+            retry_interval: Duration::from_secs(DEFAULT_RETRY_INTERVAL_SEC),
+            failed_at: Arc::new(Mutex::new(None)),
+            unsynced: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How long to wait, after the primary storage failed, before trying it
+    /// again
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    fn use_primary(&self) -> bool {
+        match *self.failed_at.lock().unwrap() {
+            None => true,
+            Some(failed_at) => failed_at.elapsed() >= self.retry_interval,
+        }
+    }
+
+    // Falls back to the secondary on transient errors, these are returned as is
+    // otherwise
+    fn failed(&self, err: StorageErr) -> Result<(), StorageErr> {
+        if !err.is_transient() {
+            return Err(err);
+        }
+        let mut failed_at = self.failed_at.lock().unwrap();
+        if failed_at.is_none() {
+            warn!(
+                "Primary storage failed, falling back to the secondary: {}",
+                err
+            );
+            gauge!("datastore_failed_over").set(1);
+        }
+        *failed_at = Some(Instant::now());
+        Ok(())
+    }
+
+    // Resyncs in the background, for the request that found the primary back
+    // not to wait on it
+    fn recovered(&self) {
+        if self.failed_at.lock().unwrap().take().is_none() {
+            return;
+        }
+        warn!("Primary storage recovered");
+        gauge!("datastore_failed_over").set(0);
+
+        // What got served in windows that are over no longer counts against
+        // the limits
+        let now = Instant::now();
+        let mut unsynced = mem::take(&mut *self.unsynced.lock().unwrap());
+        unsynced.retain(|_, unsynced| unsynced.expires_at > now);
+
+        let primary = Arc::clone(&self.primary);
+        let secondary = Arc::clone(&self.secondary);
+        let failed_at = Arc::clone(&self.failed_at);
+        let pending = Arc::clone(&self.unsynced);
+        tokio::spawn(async move {
+            let mut failed = Vec::new();
+            for (counter, unsynced) in unsynced {
+                if let Err(err) = primary.update_counter(&counter, unsynced.delta).await {
+                    error!(
+                        "Couldn't resync {:?} to the primary storage: {}",
+                        counter, err
+                    );
+                    failed.push((counter, unsynced));
+                }
+            }
+            if !failed.is_empty() {
+                // Give it another go on the next recovery
+                let mut pending = pending.lock().unwrap();
+                for (counter, failed) in failed {
+                    pending
+                        .entry(counter)
+                        .and_modify(|pending| {
+                            pending.delta = pending.delta.saturating_add(failed.delta)
+                        })
+                        .or_insert(failed);
+                }
+            } else if failed_at.lock().unwrap().is_none() {
+                // Unless the primary failed again meanwhile, and the secondary
+                // is serving anew
+                if let Err(err) = secondary.clear() {
+                    error!("Couldn't clear the secondary storage: {}", err);
+                }
+            }
+        });
+    }
+
+    // The secondary might not know of the limits yet, e.g. the in memory
+    // storage that needs them added first
+    fn add_limits_to_secondary<'a>(
+        &self,
+        counters: impl IntoIterator<Item = &'a Counter>,
+    ) -> Result<(), StorageErr> {
+        for counter in counters {
+            if !counter.is_qualified() {
+                self.secondary.add_counter(counter.limit())?;
+            }
+        }
+        Ok(())
+    }
+
+    // A counter whose window is over starts a new one, as it does in the
+    // secondary
+    fn track_unsynced<'a>(&self, counters: impl IntoIterator<Item = &'a Counter>, delta: u64) {
+        let now = Instant::now();
+        let mut unsynced = self.unsynced.lock().unwrap();
+        for counter in counters {
+            let window = Unsynced {
+                delta: 0,
+                expires_at: now + counter.window(),
+            };
+            let unsynced = unsynced.entry(counter.without_cost()).or_insert(window);
+            if unsynced.expires_at <= now {
+                *unsynced = window;
+            }
+            unsynced.delta = unsynced.delta.saturating_add(counter.delta(delta));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FailoverStorage;
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::in_memory::InMemoryStorage;
+    use crate::storage::testing::Flaky;
+    use crate::storage::{AsyncCounterStorage, Authorization};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn falls_back_and_resyncs() {
+        let mut limit = Limit::new("test_namespace", 6, 60, vec![], vec![]);
+        limit.set_cost("3".try_into().expect("failed parsing!"));
+        let counter = Counter::new(limit, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+
+        let primary = Arc::new(Flaky::default());
+        let storage = FailoverStorage::new(
+            Box::new(Arc::clone(&primary)),
+            Box::new(InMemoryStorage::default()),
+        )
+        .retry_interval(Duration::ZERO);
+
+        primary.down.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            let result = storage
+                .check_and_update(&mut vec![counter.clone()], 1, false)
+                .await
+                .unwrap();
+            assert!(matches!(result, Authorization::Ok));
+        }
+        // The secondary enforces the limit while the primary is down
+        let result = storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .unwrap();
+        assert!(matches!(result, Authorization::Limited(Some(_))));
+        assert_eq!(primary.hits.load(Ordering::SeqCst), 0);
+
+        primary.down.store(false, Ordering::SeqCst);
+        storage
+            .check_and_update(&mut vec![counter.clone()], 1, false)
+            .await
+            .unwrap();
+        // The 2 hits the secondary served, and the one just checked, all
+        // costing 3
+        resynced(&primary, 9).await;
+    }
+
+    #[tokio::test]
+    async fn only_resyncs_the_windows_that_are_not_over() {
+        let limit = Limit::new("test_namespace", 10, 1, vec![], vec![]);
+        let counter = Counter::new(limit, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+
+        let primary = Arc::new(Flaky::default());
+        let storage = FailoverStorage::new(
+            Box::new(Arc::clone(&primary)),
+            Box::new(InMemoryStorage::default()),
+        )
+        .retry_interval(Duration::ZERO);
+
+        primary.down.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            storage.update_counter(&counter, 1).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        storage.update_counter(&counter, 1).await.unwrap();
+
+        primary.down.store(false, Ordering::SeqCst);
+        storage.update_counter(&counter, 1).await.unwrap();
+        // The hit of the window not over yet, and the one just updated
+        resynced(&primary, 2).await;
+    }
+
+    // The resync happens in the background
+    async fn resynced(primary: &Flaky, hits: u64) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while primary.hits.load(Ordering::SeqCst) != hits {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("not resynced");
+    }
+}
@@ -1,51 +1,220 @@
+use crate::clock::{Clock, MonotonicClock};
 use crate::counter::Counter;
 use crate::limit::{Context, Limit, Namespace};
+use crate::observe::counter;
 use crate::storage::atomic_expiring_value::AtomicExpiringValue;
-use crate::storage::{Authorization, CounterStorage, StorageErr};
-use moka::sync::Cache;
-use std::collections::btree_map::Entry;
+#[cfg(not(feature = "cache"))]
+use crate::storage::maps::{Cache, DashMap, Entry, Expiring};
+use crate::storage::tokens::{SeenTokens, MAX_SEEN_TOKENS};
+use crate::storage::{peek_counters, Authorization, CounterStorage, StorageErr};
+#[cfg(feature = "cache")]
+use dashmap::{mapref::entry::Entry, DashMap};
+#[cfg(feature = "cache")]
+use moka::{notification::RemovalCause, sync::Cache};
+use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, SystemTime};
 
 pub struct InMemoryStorage {
-    simple_limits: RwLock<BTreeMap<Limit, AtomicExpiringValue>>,
+    // Sharded, for updates to the counters of different limits not to contend
+    // on a single lock, the values getting cloned out of it before being used
+    simple_limits: Arc<DashMap<Limit, Arc<AtomicExpiringValue>>>,
     qualified_counters: Cache<Counter, Arc<AtomicExpiringValue>>,
+    // Counters restored from a snapshot, waiting for their limit to be added
+    restored: Arc<Mutex<HashMap<Limit, Vec<SnapshotEntry>>>>,
+    // Where to snapshot the counters to when flushed
+    snapshot_path: Option<PathBuf>,
+    // The idempotency tokens of the requests accounted for
+    seen_tokens: SeenTokens,
+    clock: Arc<dyn Clock>,
+}
+
+// What a cached counter weighs, besides the values of its variables
+const COUNTER_OVERHEAD: usize = 128;
+
+/// How the counters of the limits with variables get evicted from the cache
+/// holding them
+#[derive(Clone, Copy, Debug)]
+pub struct CachePolicy {
+    max_capacity: u64,
+    weighted: bool,
+    time_to_idle: Option<Duration>,
+}
+
+impl CachePolicy {
+    /// Holds up to `max_counters` counters, whatever their size
+    pub fn max_counters(max_counters: u64) -> Self {
+        Self {
+            max_capacity: max_counters,
+            weighted: false,
+            time_to_idle: None,
+        }
+    }
+
+    /// Holds counters up to about `max_bytes`, each weighing the size of the
+    /// names and values of its variables, plus some fixed overhead
+    pub fn max_bytes(max_bytes: u64) -> Self {
+        Self {
+            max_capacity: max_bytes,
+            weighted: true,
+            time_to_idle: None,
+        }
+    }
+
+    /// Evicts the counters that didn't get hit for `time_to_idle`, even though
+    /// there would still be room for them. Ignored without the `cache` feature
+    pub fn time_to_idle(mut self, time_to_idle: Duration) -> Self {
+        self.time_to_idle = Some(time_to_idle);
+        self
+    }
+
+    #[cfg(feature = "cache")]
+    fn build(&self) -> Cache<Counter, Arc<AtomicExpiringValue>> {
+        let mut builder = Cache::builder()
+            .max_capacity(self.max_capacity)
+            .eviction_listener(Self::eviction_listener);
+        if self.weighted {
+            builder = builder.weigher(|counter: &Counter, _: &Arc<AtomicExpiringValue>| {
+                let size: usize = counter
+                    .set_variables()
+                    .iter()
+                    .map(|(var, value)| var.len() + value.len())
+                    .sum();
+                u32::try_from(COUNTER_OVERHEAD + size).unwrap_or(u32::MAX)
+            });
+        }
+        if let Some(time_to_idle) = self.time_to_idle {
+            builder = builder.time_to_idle(time_to_idle);
+        }
+        builder.build()
+    }
+
+    // Without the `cache` feature, the counters are weighed by their overhead
+    // only, and never evicted for being idle
+    #[cfg(not(feature = "cache"))]
+    fn build(&self) -> Cache<Counter, Arc<AtomicExpiringValue>> {
+        if self.weighted {
+            Cache::new(self.max_capacity / COUNTER_OVERHEAD as u64)
+        } else {
+            Cache::new(self.max_capacity)
+        }
+    }
+
+    #[cfg(feature = "cache")]
+    fn eviction_listener(
+        _counter: Arc<Counter>,
+        _value: Arc<AtomicExpiringValue>,
+        cause: RemovalCause,
+    ) {
+        let cause = match cause {
+            RemovalCause::Size => "size",
+            RemovalCause::Expired => "idle",
+            _ => return,
+        };
+        counter!("evicted_counters", "cause" => cause).increment(1);
+    }
+}
+
+#[cfg(not(feature = "cache"))]
+impl Expiring for Arc<AtomicExpiringValue> {
+    fn expired(&self) -> bool {
+        self.expires_at() <= MonotonicClock.now()
+    }
+}
+
+// A snapshot doesn't carry the full limits (e.g. their max value), so its
+// entries only get matched back to the actual limit once it is added again
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    limit: Limit,
+    variables: BTreeMap<String, String>,
+    value: u64,
+    expires_at: SystemTime,
+}
+
+impl SnapshotEntry {
+    fn new(
+        limit: &Limit,
+        variables: &BTreeMap<String, String>,
+        value: &AtomicExpiringValue,
+        now: SystemTime,
+    ) -> Option<Self> {
+        let expires_at = value.expires_at();
+        if expires_at <= now {
+            return None;
+        }
+        Some(Self {
+            limit: limit.clone(),
+            variables: variables.clone(),
+            value: value.value_at(now),
+            expires_at,
+        })
+    }
 }
 
 impl CounterStorage for InMemoryStorage {
-    #[tracing::instrument(skip_all)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let now = self.clock.now();
         let value = if counter.is_qualified() {
             self.qualified_counters
                 .get(counter)
-                .map(|c| c.value())
+                .map(|c| c.value_at(now))
                 .unwrap_or_default()
         } else {
-            let limits_by_namespace = self.simple_limits.read().unwrap();
-            limits_by_namespace
+            self.simple_limits
                 .get(counter.limit())
-                .map(|c| c.value())
+                .map(|c| c.value().value_at(now))
                 .unwrap_or_default()
         };
 
-        Ok(counter.max_value() >= value + delta)
+        Ok(counter.max_value() >= value.saturating_add(counter.delta(delta)))
     }
 
-    #[tracing::instrument(skip_all)]
+    fn is_limit_within(
+        &self,
+        limit: &Limit,
+        max_value: u64,
+        hits: u64,
+    ) -> Option<Result<bool, StorageErr>> {
+        let value = self
+            .simple_limits
+            .get(limit)
+            .map(|c| c.value().value_at(self.clock.now()))
+            .unwrap_or_default();
+        Some(Ok(max_value >= value.saturating_add(hits)))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        let restored = self.restored.lock().unwrap().remove(limit);
+        for entry in restored.into_iter().flatten() {
+            let value = AtomicExpiringValue::new(entry.value, entry.expires_at);
+            if entry.variables.is_empty() {
+                self.simple_limits.insert(limit.clone(), Arc::new(value));
+            } else {
+                let counter =
+                    Counter::resolved_vars(limit.clone(), entry.variables.into_iter().collect())
+                        .expect("counter creation failed!");
+                self.qualified_counters.insert(counter, Arc::new(value));
+            }
+        }
         if limit.variables().is_empty() {
-            let mut limits_by_namespace = self.simple_limits.write().unwrap();
-            limits_by_namespace.entry(limit.clone()).or_default();
+            self.simple_limits.entry(limit.clone()).or_default();
         }
         Ok(())
     }
 
-    #[tracing::instrument(skip_all)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
-        let mut counters = self.simple_limits.write().unwrap();
-        let now = SystemTime::now();
+        let now = self.clock.now();
+        let delta = counter.delta(delta);
         if counter.is_qualified() {
             let value = match self.qualified_counters.get(counter) {
                 None => self.qualified_counters.get_with(counter.clone(), || {
@@ -54,10 +223,15 @@ impl CounterStorage for InMemoryStorage {
                 Some(counter) => counter,
             };
             value.update(delta, counter.window(), now);
+        } else if let Some(value) = self.simple_limits.get(counter.limit()) {
+            value.update(delta, counter.window(), now);
         } else {
-            match counters.entry(counter.limit().clone()) {
+            match self.simple_limits.entry(counter.limit().clone()) {
                 Entry::Vacant(v) => {
-                    v.insert(AtomicExpiringValue::new(delta, now + counter.window()));
+                    v.insert(Arc::new(AtomicExpiringValue::new(
+                        delta,
+                        now + counter.window(),
+                    )));
                 }
                 Entry::Occupied(o) => {
                     o.get().update(delta, counter.window(), now);
@@ -67,50 +241,91 @@ impl CounterStorage for InMemoryStorage {
         Ok(())
     }
 
-    #[tracing::instrument(skip_all)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let now = self.clock.now();
+        let delta = counter.delta(delta);
+        if counter.is_qualified() {
+            if let Some(value) = self.qualified_counters.get(counter) {
+                value.refund(delta, now);
+            }
+        } else if let Some(value) = self.simple_limits.get(counter.limit()) {
+            value.refund(delta, now);
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        Ok(self
+            .seen_tokens
+            .claim(namespace, token, horizon, self.clock.now()))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        self.seen_tokens.release(namespace, token);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn check_and_update(
         &self,
         counters: &mut Vec<Counter>,
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        let limits_by_namespace = self.simple_limits.read().unwrap();
         let mut first_limited = None;
-        let mut counter_values_to_update: Vec<(&AtomicExpiringValue, Duration)> = Vec::new();
-        let mut qualified_counter_values_to_updated: Vec<(Arc<AtomicExpiringValue>, Duration)> =
+        let mut counter_values_to_update: Vec<(Arc<AtomicExpiringValue>, Duration, u64)> =
             Vec::new();
-        let now = SystemTime::now();
+        let mut qualified_counter_values_to_updated: Vec<(
+            Arc<AtomicExpiringValue>,
+            Duration,
+            u64,
+        )> = Vec::new();
+        let now = self.clock.now();
 
         let mut process_counter =
             |counter: &mut Counter, value: u64, delta: u64| -> Option<Authorization> {
                 if load_counters {
-                    let remaining = counter.max_value().checked_sub(value + delta);
+                    let remaining = counter.max_value().checked_sub(value.saturating_add(delta));
                     counter.set_remaining(remaining.unwrap_or_default());
                     if first_limited.is_none() && remaining.is_none() {
-                        first_limited = Some(Authorization::Limited(
-                            counter.limit().name().map(|n| n.to_owned()),
-                        ));
+                        first_limited = Some(Authorization::limited_by(counter));
                     }
                 }
                 if !Self::counter_is_within_limits(counter, Some(&value), delta) {
-                    return Some(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
+                    return Some(Authorization::limited_by(counter));
                 }
                 None
             };
 
         // Process simple counters
         for counter in counters.iter_mut().filter(|c| !c.is_qualified()) {
-            let atomic_expiring_value: &AtomicExpiringValue =
-                limits_by_namespace.get(counter.limit()).unwrap();
+            let atomic_expiring_value = match self.simple_limits.get(counter.limit()) {
+                Some(value) => Arc::clone(value.value()),
+                None => Arc::clone(
+                    self.simple_limits
+                        .entry(counter.limit().clone())
+                        .or_default()
+                        .value(),
+                ),
+            };
+            let delta = counter.delta(delta);
 
-            if let Some(limited) = process_counter(counter, atomic_expiring_value.value(), delta) {
+            if let Some(limited) =
+                process_counter(counter, atomic_expiring_value.value_at(now), delta)
+            {
                 if !load_counters {
                     return Ok(limited);
                 }
             }
-            counter_values_to_update.push((atomic_expiring_value, counter.window()));
+            counter_values_to_update.push((atomic_expiring_value, counter.window(), delta));
         }
 
         // Process qualified counters
@@ -121,14 +336,15 @@ impl CounterStorage for InMemoryStorage {
                 }),
                 Some(counter) => counter,
             };
+            let delta = counter.delta(delta);
 
-            if let Some(limited) = process_counter(counter, value.value(), delta) {
+            if let Some(limited) = process_counter(counter, value.value_at(now), delta) {
                 if !load_counters {
                     return Ok(limited);
                 }
             }
 
-            qualified_counter_values_to_updated.push((value, counter.window()));
+            qualified_counter_values_to_updated.push((value, counter.window(), delta));
         }
 
         if let Some(limited) = first_limited {
@@ -136,28 +352,55 @@ impl CounterStorage for InMemoryStorage {
         }
 
         // Update counters
-        counter_values_to_update.iter().for_each(|(v, ttl)| {
-            v.update(delta, *ttl, now);
+        counter_values_to_update.iter().for_each(|(v, ttl, delta)| {
+            v.update(*delta, *ttl, now);
         });
         qualified_counter_values_to_updated
             .iter()
-            .for_each(|(v, ttl)| {
-                v.update(delta, *ttl, now);
+            .for_each(|(v, ttl, delta)| {
+                v.update(*delta, *ttl, now);
             });
 
         Ok(Authorization::Ok)
     }
 
-    #[tracing::instrument(skip_all)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn peek(&self, counters: &mut [Counter], delta: u64) -> Result<Authorization, StorageErr> {
+        let now = self.clock.now();
+        let values_and_ttls: Vec<(u64, Duration)> = counters
+            .iter()
+            .map(|counter| {
+                let loaded = if counter.is_qualified() {
+                    self.qualified_counters
+                        .get(counter)
+                        .map(|value| (value.value_at(now), value.ttl_at(now)))
+                } else {
+                    self.simple_limits
+                        .get(counter.limit())
+                        .map(|value| (value.value_at(now), value.ttl_at(now)))
+                };
+                loaded
+                    .filter(|(_, ttl)| !ttl.is_zero())
+                    .unwrap_or((0, counter.window()))
+            })
+            .collect();
+        Ok(peek_counters(counters, values_and_ttls, delta))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
         let mut res = HashSet::new();
+        let now = self.clock.now();
 
         for limit in limits {
             for (counter, expiring_value) in self.counters_in_namespace(limit.namespace()) {
                 let mut counter_with_val = counter.clone();
-                counter_with_val
-                    .set_remaining(counter_with_val.max_value() - expiring_value.value());
-                counter_with_val.set_expires_in(expiring_value.ttl());
+                counter_with_val.set_remaining(
+                    counter_with_val
+                        .max_value()
+                        .saturating_sub(expiring_value.value_at(now)),
+                );
+                counter_with_val.set_expires_in(expiring_value.ttl_at(now));
                 if counter_with_val.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter_with_val);
                 }
@@ -167,9 +410,12 @@ impl CounterStorage for InMemoryStorage {
         for (counter, expiring_value) in self.qualified_counters.iter() {
             if limits.contains(counter.limit()) {
                 let mut counter_with_val = counter.deref().clone();
-                counter_with_val
-                    .set_remaining(counter_with_val.max_value() - expiring_value.value());
-                counter_with_val.set_expires_in(expiring_value.ttl());
+                counter_with_val.set_remaining(
+                    counter_with_val
+                        .max_value()
+                        .saturating_sub(expiring_value.value_at(now)),
+                );
+                counter_with_val.set_expires_in(expiring_value.ttl_at(now));
                 if counter_with_val.expires_in().unwrap() > Duration::ZERO {
                     res.insert(counter_with_val);
                 }
@@ -179,7 +425,17 @@ impl CounterStorage for InMemoryStorage {
         Ok(res)
     }
 
-    #[tracing::instrument(skip_all)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        if counter.is_qualified() {
+            self.qualified_counters.invalidate(counter);
+        } else {
+            self.simple_limits.remove(counter.limit());
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
         for limit in limits {
             self.delete_counters_of_limit(limit);
@@ -187,19 +443,161 @@ impl CounterStorage for InMemoryStorage {
         Ok(())
     }
 
-    #[tracing::instrument(skip_all)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
     fn clear(&self) -> Result<(), StorageErr> {
-        self.simple_limits.write().unwrap().clear();
+        self.simple_limits.clear();
+        self.restored.lock().unwrap().clear();
+        self.seen_tokens.clear();
         Ok(())
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+    fn flush(&self) -> Result<(), StorageErr> {
+        match &self.snapshot_path {
+            Some(path) => self.snapshot_to(path),
+            None => Ok(()),
+        }
+    }
 }
 
 impl InMemoryStorage {
     pub fn new(cache_size: u64) -> Self {
+        Self::with_cache_policy(CachePolicy::max_counters(cache_size))
+    }
+
+    /// Holds the counters of the limits with variables in a cache evicting
+    /// them as per the `policy`
+    pub fn with_cache_policy(policy: CachePolicy) -> Self {
         Self {
-            simple_limits: RwLock::new(BTreeMap::new()),
-            qualified_counters: Cache::new(cache_size),
+            simple_limits: Arc::new(DashMap::new()),
+            qualified_counters: policy.build(),
+            restored: Arc::new(Mutex::new(HashMap::new())),
+            snapshot_path: None,
+            seen_tokens: SeenTokens::new(MAX_SEEN_TOKENS),
+            clock: Arc::new(MonotonicClock),
+        }
+    }
+
+    /// Measures the windows of the counters against the `clock`, rather than
+    /// a [`MonotonicClock`]. The sweeper only uses it when set ahead of
+    /// [`sweep_every`](InMemoryStorage::sweep_every).
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Has the counters snapshot to `path` whenever the storage gets flushed,
+    /// i.e. when shutting down
+    pub fn snapshot_on_flush<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
+
+    /// Has a background thread [`sweep`](InMemoryStorage::sweep) the counters
+    /// every `period`, for as long as the storage is around
+    pub fn sweep_every(self, period: Duration) -> Self {
+        let simple_limits = Arc::downgrade(&self.simple_limits);
+        let qualified_counters = self.qualified_counters.clone();
+        let restored = Arc::downgrade(&self.restored);
+        let clock = Arc::clone(&self.clock);
+        thread::Builder::new()
+            .name("counters-sweeper".to_string())
+            .spawn(move || loop {
+                thread::sleep(period);
+                let (Some(simple_limits), Some(restored)) =
+                    (simple_limits.upgrade(), restored.upgrade())
+                else {
+                    break;
+                };
+                sweep(&simple_limits, &qualified_counters, &restored, clock.now());
+            })
+            .expect("failed to spawn the counters sweeper");
+        self
+    }
+
+    /// Drops the counters whose window is over, rather than leaving them for
+    /// the cache to evict, or around for good when not qualified, as well as
+    /// the expired ones restored from a snapshot. Returns how many got
+    /// reclaimed, which the `reclaimed_counters` metric adds up.
+    pub fn sweep(&self) -> usize {
+        sweep(
+            &self.simple_limits,
+            &self.qualified_counters,
+            &self.restored,
+            self.clock.now(),
+        )
+    }
+
+    /// Writes all the live counters, with their remaining TTLs, to `path`, e.g.
+    /// when shutting down. The file is replaced atomically.
+    pub fn snapshot_to<P: AsRef<Path>>(&self, path: P) -> Result<(), StorageErr> {
+        let now = self.clock.now();
+        let mut entries = Vec::new();
+        for entry in self.simple_limits.iter() {
+            entries.extend(SnapshotEntry::new(
+                entry.key(),
+                &BTreeMap::new(),
+                entry.value(),
+                now,
+            ));
+        }
+        for (counter, value) in self.qualified_counters.iter() {
+            entries.extend(SnapshotEntry::new(
+                counter.limit(),
+                counter.set_variables(),
+                &value,
+                now,
+            ));
+        }
+        // Whatever got restored but whose limit never got added back, yet
+        for entry in self.restored.lock().unwrap().values().flatten() {
+            if entry.expires_at > now {
+                entries.push(SnapshotEntry {
+                    limit: entry.limit.clone(),
+                    variables: entry.variables.clone(),
+                    value: entry.value,
+                    expires_at: entry.expires_at,
+                });
+            }
+        }
+
+        // Persisted as times of the system, for them to outlive this process'
+        for entry in entries.iter_mut() {
+            entry.expires_at = self.clock.to_system_time(entry.expires_at);
+        }
+
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_vec(&entries)
+            .map_err(|err| snapshot_err("Couldn't serialize snapshot", err))?;
+        fs::write(&tmp, json).map_err(|err| snapshot_err("Couldn't write snapshot", err))?;
+        fs::rename(&tmp, path).map_err(|err| snapshot_err("Couldn't write snapshot", err))?;
+        Ok(())
+    }
+
+    /// Restores the counters from a snapshot taken with
+    /// [`InMemoryStorage::snapshot_to`], skipping the ones that expired since.
+    /// They only start counting again once their limit gets added. A missing
+    /// snapshot is not an error, there is just nothing to restore.
+    pub fn restore_from<P: AsRef<Path>>(&self, path: P) -> Result<(), StorageErr> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(snapshot_err("Couldn't read snapshot", err)),
+        };
+        let entries: Vec<SnapshotEntry> = serde_json::from_slice(&json)
+            .map_err(|err| snapshot_err("Couldn't parse snapshot", err))?;
+
+        let now = self.clock.now();
+        let mut restored = self.restored.lock().unwrap();
+        for mut entry in entries {
+            entry.expires_at = self.clock.from_system_time(entry.expires_at);
+            if entry.expires_at <= now {
+                continue;
+            }
+            restored.entry(entry.limit.clone()).or_default().push(entry);
         }
+        Ok(())
     }
 
     fn counters_in_namespace(
@@ -208,14 +606,15 @@ impl InMemoryStorage {
     ) -> HashMap<Counter, AtomicExpiringValue> {
         let mut res: HashMap<Counter, AtomicExpiringValue> = HashMap::new();
 
-        for (limit, counter) in self.simple_limits.read().unwrap().iter() {
+        for entry in self.simple_limits.iter() {
+            let (limit, value) = entry.pair();
             if limit.namespace() == namespace {
                 res.insert(
                     // todo fixme
                     Counter::new(limit.clone(), &Context::default())
                         .unwrap()
                         .unwrap(),
-                    counter.clone(),
+                    value.deref().clone(),
                 );
             }
         }
@@ -230,17 +629,68 @@ impl InMemoryStorage {
     }
 
     fn delete_counters_of_limit(&self, limit: &Limit) {
-        self.simple_limits.write().unwrap().remove(limit);
+        self.simple_limits.remove(limit);
     }
 
     fn counter_is_within_limits(counter: &Counter, current_val: Option<&u64>, delta: u64) -> bool {
         match current_val {
-            Some(current_val) => current_val + delta <= counter.max_value(),
+            Some(current_val) => current_val.saturating_add(delta) <= counter.max_value(),
             None => counter.max_value() >= delta,
         }
     }
 }
 
+// A hit racing the sweep of a qualified counter whose window is over starts
+// the next window on the value being dropped, and gets lost with it. The ones
+// of limits without variables only get dropped when no hit holds them.
+fn sweep(
+    simple_limits: &DashMap<Limit, Arc<AtomicExpiringValue>>,
+    qualified_counters: &Cache<Counter, Arc<AtomicExpiringValue>>,
+    restored: &Mutex<HashMap<Limit, Vec<SnapshotEntry>>>,
+    now: SystemTime,
+) -> usize {
+    let mut reclaimed = 0;
+
+    let expired: Vec<Limit> = simple_limits
+        .iter()
+        .filter(|entry| entry.value().expires_at() <= now)
+        .map(|entry| entry.key().clone())
+        .collect();
+    for limit in expired {
+        let removed = simple_limits.remove_if(&limit, |_, value| {
+            value.expires_at() <= now && Arc::strong_count(value) == 1
+        });
+        if removed.is_some() {
+            reclaimed += 1;
+        }
+    }
+
+    for (counter, value) in qualified_counters.iter() {
+        if value.expires_at() <= now {
+            qualified_counters.invalidate(counter.deref());
+            reclaimed += 1;
+        }
+    }
+
+    restored.lock().unwrap().retain(|_, entries| {
+        let before = entries.len();
+        entries.retain(|entry| entry.expires_at > now);
+        reclaimed += before - entries.len();
+        !entries.is_empty()
+    });
+
+    counter!("reclaimed_counters").increment(reclaimed as u64);
+    reclaimed
+}
+
+fn snapshot_err<E: std::error::Error + 'static>(msg: &str, err: E) -> StorageErr {
+    StorageErr {
+        msg: format!("{msg}: {err}"),
+        source: Some(Box::new(err)),
+        transient: false,
+    }
+}
+
 impl Default for InMemoryStorage {
     fn default() -> Self {
         Self::new(10_000)
@@ -250,6 +700,98 @@ impl Default for InMemoryStorage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn sweeps_the_counters_whose_window_is_over() {
+        let storage = InMemoryStorage::default();
+        let simple = Limit::new("test_namespace", 10, 1, vec![], vec![]);
+        let qualified = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let simple = Counter::new(simple, &Context::default())
+            .unwrap()
+            .expect("must have a counter");
+        let qualified = Counter::new(qualified, &values.into())
+            .unwrap()
+            .expect("must have a counter");
+
+        let now = SystemTime::now();
+        storage.update_counter(&simple, 1).unwrap();
+        storage.update_counter(&qualified, 1).unwrap();
+        let sweep_at = |when: SystemTime| {
+            sweep(
+                &storage.simple_limits,
+                &storage.qualified_counters,
+                &storage.restored,
+                when,
+            )
+        };
+
+        assert_eq!(sweep_at(now), 0);
+        assert_eq!(sweep_at(now + Duration::from_secs(2)), 1);
+        assert!(storage.simple_limits.is_empty());
+        assert!(storage.qualified_counters.contains_key(&qualified));
+        assert_eq!(sweep_at(now + Duration::from_secs(61)), 1);
+        assert!(!storage.qualified_counters.contains_key(&qualified));
+    }
+
+    #[test]
+    fn expires_the_counters_as_of_its_clock() {
+        let clock = Arc::new(ManualClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        let storage = InMemoryStorage::default().clock(clock.clone());
+        let limit = Limit::new(
+            "test_namespace",
+            1,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let counter = Counter::new(limit, &values.into())
+            .unwrap()
+            .expect("must have a counter");
+
+        storage.update_counter(&counter, 1).unwrap();
+        assert!(!storage.is_within_limits(&counter, 1).unwrap());
+        clock.advance(Duration::from_secs(59));
+        assert!(!storage.is_within_limits(&counter, 1).unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert!(storage.is_within_limits(&counter, 1).unwrap());
+        assert_eq!(storage.sweep(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "cache")]
+    fn bounds_the_qualified_counters_by_their_size() {
+        let max_bytes = 10 * 1024;
+        let storage = InMemoryStorage::with_cache_policy(CachePolicy::max_bytes(max_bytes));
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["user_id".try_into().expect("failed parsing!")],
+        );
+        for i in 0..1_000 {
+            let values = HashMap::from([("user_id".to_string(), format!("{i:0>100}"))]);
+            let counter = Counter::new(limit.clone(), &values.into())
+                .unwrap()
+                .expect("must have a counter");
+            storage.update_counter(&counter, 1).unwrap();
+        }
+
+        storage.qualified_counters.run_pending_tasks();
+        assert!(storage.qualified_counters.weighted_size() <= max_bytes);
+        assert!(storage.qualified_counters.entry_count() <= max_bytes / 100);
+    }
 
     #[test]
     fn counters_for_multiple_limit_per_ns() {
@@ -285,4 +827,146 @@ mod tests {
             2
         );
     }
+
+    #[test]
+    fn refunds_without_going_below_zero() {
+        let storage = InMemoryStorage::default();
+        let limit = Limit::new(
+            "test_namespace",
+            2,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .expect("counter creation failed!")
+            .expect("Should have a counter");
+
+        storage.update_counter(&counter, 2).unwrap();
+        assert!(!storage.is_within_limits(&counter, 1).unwrap());
+
+        storage.refund_counter(&counter, 1).unwrap();
+        assert!(storage.is_within_limits(&counter, 1).unwrap());
+        storage.refund_counter(&counter, 5).unwrap();
+        assert!(storage.is_within_limits(&counter, 2).unwrap());
+        assert!(!storage.is_within_limits(&counter, 3).unwrap());
+    }
+
+    #[test]
+    fn updates_the_counters_of_different_limits_in_parallel() {
+        let storage = InMemoryStorage::default();
+        let counters: Vec<Counter> = (0..8)
+            .map(|i| {
+                let limit = Limit::new(format!("namespace_{i}"), 1_000, 60, vec![], vec![]);
+                storage.add_counter(&limit).unwrap();
+                let counter = Counter::new(limit, &Context::default())
+                    .expect("counter creation failed!")
+                    .expect("Should have a counter");
+                // starts the window, for none of the hits to race it
+                storage.update_counter(&counter, 200).unwrap();
+                counter
+            })
+            .collect();
+
+        let storage = &storage;
+        std::thread::scope(|scope| {
+            for counter in &counters {
+                for _ in 0..4 {
+                    scope.spawn(move || {
+                        for _ in 0..100 {
+                            let mut counters = vec![counter.clone()];
+                            storage.check_and_update(&mut counters, 1, false).unwrap();
+                        }
+                    });
+                }
+            }
+        });
+        for counter in &counters {
+            assert!(storage.is_within_limits(counter, 400).unwrap());
+            assert!(!storage.is_within_limits(counter, 401).unwrap());
+        }
+    }
+
+    #[test]
+    fn restores_counters_from_snapshot() {
+        let namespace = "test_namespace";
+        let simple = Limit::new(namespace, 5, 60, vec![], vec![]);
+        let qualified = Limit::new(
+            namespace,
+            5,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([
+            ("app_id".to_string(), "foo".to_string()),
+            ("req_method".to_string(), "GET".to_string()),
+        ]);
+        let ctx = map.into();
+        let simple_counter = Counter::new(simple.clone(), &ctx)
+            .expect("counter creation failed!")
+            .expect("Should have a counter");
+        let qualified_counter = Counter::new(qualified.clone(), &ctx)
+            .expect("counter creation failed!")
+            .expect("Should have a counter");
+
+        let tmp = tempfile::TempDir::new().expect("We should have a dir!");
+        let path = tmp.path().join("counters.json");
+
+        let storage = InMemoryStorage::default();
+        storage.add_counter(&simple).unwrap();
+        storage.add_counter(&qualified).unwrap();
+        storage.update_counter(&simple_counter, 2).unwrap();
+        storage.update_counter(&qualified_counter, 3).unwrap();
+        storage.snapshot_to(&path).unwrap();
+
+        let restored = InMemoryStorage::default();
+        restored.restore_from(&path).unwrap();
+        restored.add_counter(&simple).unwrap();
+        restored.add_counter(&qualified).unwrap();
+
+        let limits = HashSet::from([Arc::new(simple), Arc::new(qualified)]);
+        let counters = restored.get_counters(&limits).unwrap();
+        assert_eq!(counters.len(), 2);
+        for counter in counters {
+            let expected = if counter.is_qualified() { 2 } else { 3 };
+            assert_eq!(counter.remaining(), Some(expected));
+            assert!(counter.expires_in().unwrap() <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn restoring_without_snapshot_is_a_noop() {
+        let tmp = tempfile::TempDir::new().expect("We should have a dir!");
+        let storage = InMemoryStorage::default();
+        storage
+            .restore_from(tmp.path().join("missing.json"))
+            .unwrap();
+        assert!(storage.restored.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshots_on_flush() {
+        let limit = Limit::new("test_namespace", 5, 60, vec![], vec![]);
+        let counter = Counter::new(limit.clone(), &Context::default())
+            .expect("counter creation failed!")
+            .expect("Should have a counter");
+
+        let tmp = tempfile::TempDir::new().expect("We should have a dir!");
+        let path = tmp.path().join("counters.json");
+
+        let storage = InMemoryStorage::default().snapshot_on_flush(&path);
+        storage.add_counter(&limit).unwrap();
+        storage.update_counter(&counter, 2).unwrap();
+        assert!(!path.exists());
+        storage.flush().unwrap();
+
+        let restored = InMemoryStorage::default();
+        restored.restore_from(&path).unwrap();
+        restored.add_counter(&limit).unwrap();
+        assert!(restored.is_within_limits(&counter, 3).unwrap());
+        assert!(!restored.is_within_limits(&counter, 4).unwrap());
+    }
 }
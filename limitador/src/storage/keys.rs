@@ -15,8 +15,97 @@
 use crate::counter::Counter;
 use crate::limit::Limit;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 
+// Prefix of the keys encoded with `KeyCodec::Hashed`. Previous versions of
+// Limitador don't know of it, and never look these keys up.
+const HASHED_PREFIX: &[u8] = b"v3:";
+
+/// How the keys of the counters, and of the sets of counters of a limit, get
+/// encoded. Either way, the keys of the counters are of a [`KeyVersion`] that
+/// can always be decoded, so that counters can be migrated from one codec to
+/// another.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyCodec {
+    /// The namespace, conditions and variables of the limit are all part of
+    /// the key
+    #[default]
+    Full,
+    /// The limit is replaced with a stable hash of it in the key, keeping the
+    /// namespace and the values of the variables readable. Switching to it
+    /// starts all counters afresh, as does going back to a version of
+    /// Limitador that doesn't support it.
+    Hashed,
+}
+
+impl KeyCodec {
+    pub fn key_for_counter(&self, counter: &Counter) -> Vec<u8> {
+        match self {
+            Self::Full => key_for_counter(counter),
+            Self::Hashed => {
+                let mut key = hashed_key_for_limit(counter.limit());
+                key.push(b':');
+                key.extend(serde_json::to_vec(counter.set_variables()).unwrap());
+                key
+            }
+        }
+    }
+
+    pub fn key_for_counters_of_limit(&self, limit: &Limit) -> Vec<u8> {
+        match self {
+            Self::Full => key_for_counters_of_limit(limit),
+            Self::Hashed => hashed_key_for_limit(limit),
+        }
+    }
+}
+
+/// The versions of the encoding of the keys of the counters, each keeping its
+/// own prefix, so that the keys of older versions are told apart, and still
+/// decoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyVersion {
+    /// `namespace:{ns},counter:` followed by the counter as JSON, for the
+    /// limits without an id with [`KeyCodec::Full`]
+    Text = 1,
+    /// The counter encoded with postcard, following a tag byte, for the
+    /// limits with an id with [`KeyCodec::Full`]
+    Binary = 2,
+    /// `v3:` followed by the hash of the limit, with [`KeyCodec::Hashed`]
+    Hashed = 3,
+}
+
+impl KeyVersion {
+    pub fn of(key: &[u8]) -> Self {
+        if key.starts_with(HASHED_PREFIX) {
+            Self::Hashed
+        } else if key.starts_with(b"namespace:") {
+            Self::Text
+        } else {
+            Self::Binary
+        }
+    }
+}
+
+fn hashed_key_for_limit(limit: &Limit) -> Vec<u8> {
+    let namespace = limit.namespace().as_ref();
+    let hash = stable_hash(serde_json::to_string(limit).unwrap().as_bytes());
+    let mut key = HASHED_PREFIX.to_vec();
+    key.extend(format!("{{{namespace}}}:{hash:016x}").into_bytes());
+    key
+}
+
+// FNV-1a, which unlike the std hashers is guaranteed not to change across
+// versions
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
 pub fn key_for_counter(counter: &Counter) -> Vec<u8> {
     if counter.id().is_none() {
         // continue to use the legacy text encoding...
@@ -62,7 +151,30 @@ pub fn key_for_counters_of_limit(limit: &Limit) -> Vec<u8> {
     }
 }
 
+/// The key recording the idempotency `token` of a request to the `namespace`
+#[cfg(feature = "redis_storage")]
+pub fn key_for_token(namespace: &crate::limit::Namespace, token: &str) -> Vec<u8> {
+    let namespace = namespace.as_ref();
+    format!("namespace:{{{namespace}}},token:{token}").into_bytes()
+}
+
 pub fn counter_from_counter_key(key: &Vec<u8>, limit: Arc<Limit>) -> Counter {
+    if KeyVersion::of(key) == KeyVersion::Hashed {
+        // The limit can't be rebuilt from its hash, only checked against it
+        let mut prefix = hashed_key_for_limit(&limit);
+        prefix.push(b':');
+        let variables: HashMap<String, String> = key
+            .strip_prefix(prefix.as_slice())
+            .and_then(|variables| serde_json::from_slice(variables).ok())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Failed to rebuild Counter from the provided Limit: {:?}",
+                    limit
+                )
+            });
+        return Counter::resolved_vars(limit, variables).expect("counter creation failed!");
+    }
+
     let mut counter = partial_counter_from_counter_key(key);
     if !counter.update_to_limit(Arc::clone(&limit)) {
         // this means some kind of data corruption _or_ most probably
@@ -77,40 +189,46 @@ pub fn counter_from_counter_key(key: &Vec<u8>, limit: Arc<Limit>) -> Counter {
 }
 
 pub fn partial_counter_from_counter_key(key: &Vec<u8>) -> Counter {
-    if key.starts_with(b"namespace:") {
-        let key = String::from_utf8_lossy(key.as_ref());
-
-        // It's using to the legacy text encoding...
-        let namespace_prefix = "namespace:";
-        let counter_prefix = ",counter:";
-
-        // Find the start position of the counter portion
-        let start_pos_namespace = key
-            .find(namespace_prefix)
-            .expect("Namespace not found in the key");
-        let start_pos_counter = key[start_pos_namespace..]
-            .find(counter_prefix)
-            .expect("Counter not found in the key")
-            + start_pos_namespace
-            + counter_prefix.len();
-
-        // Extract counter JSON substring and deserialize it
-        let counter_str = &key[start_pos_counter..];
-        let counter: Counter =
-            serde_json::from_str(counter_str).expect("Failed to deserialize counter JSON");
-        counter
-    } else {
-        // It's using to the new binary encoding...
-        bin::partial_counter_from_counter_key_v2(key)
+    match KeyVersion::of(key) {
+        KeyVersion::Text => partial_counter_from_text_key(key),
+        KeyVersion::Binary => bin::partial_counter_from_counter_key_v2(key),
+        KeyVersion::Hashed => {
+            panic!("Counters can't be rebuilt from hashed keys without their Limit")
+        }
     }
 }
 
+fn partial_counter_from_text_key(key: &[u8]) -> Counter {
+    let key = String::from_utf8_lossy(key);
+
+    let namespace_prefix = "namespace:";
+    let counter_prefix = ",counter:";
+
+    // Find the start position of the counter portion
+    let start_pos_namespace = key
+        .find(namespace_prefix)
+        .expect("Namespace not found in the key");
+    let start_pos_counter = key[start_pos_namespace..]
+        .find(counter_prefix)
+        .expect("Counter not found in the key")
+        + start_pos_namespace
+        + counter_prefix.len();
+
+    // Extract counter JSON substring and deserialize it
+    let counter_str = &key[start_pos_counter..];
+    serde_json::from_str(counter_str).expect("Failed to deserialize counter JSON")
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{key_for_counter, key_for_counters_of_limit, partial_counter_from_counter_key};
+    use super::{
+        counter_from_counter_key, key_for_counter, key_for_counters_of_limit,
+        partial_counter_from_counter_key, KeyCodec, KeyVersion,
+    };
     use crate::counter::Counter;
     use crate::Limit;
     use std::collections::HashMap;
+    use std::sync::Arc;
     use std::time::Duration;
 
     #[test]
@@ -162,6 +280,71 @@ mod tests {
         assert_eq!(counter, partial_counter_from_counter_key(&raw));
     }
 
+    #[test]
+    fn hashed_key_format() {
+        let limit = Limit::new(
+            "example.com",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit.clone(), &ctx)
+            .expect("counter creation failed!")
+            .expect("must have a counter");
+
+        assert_eq!(
+            "v3:{example.com}:b11a30b16cc792f4".as_bytes(),
+            KeyCodec::Hashed.key_for_counters_of_limit(&limit)
+        );
+        let raw = KeyCodec::Hashed.key_for_counter(&counter);
+        assert_eq!(
+            "v3:{example.com}:b11a30b16cc792f4:{\"app_id\":\"foo\"}".as_bytes(),
+            raw
+        );
+        assert_eq!(counter, counter_from_counter_key(&raw, Arc::new(limit)));
+    }
+
+    #[test]
+    fn decodes_the_keys_of_all_versions() {
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let limit = Limit::new(
+            "example.com",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let limit_with_id = Limit::with_id(
+            "test_id",
+            "example.com",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+
+        for (limit, codec, version) in [
+            (&limit, KeyCodec::Full, KeyVersion::Text),
+            (&limit_with_id, KeyCodec::Full, KeyVersion::Binary),
+            (&limit, KeyCodec::Hashed, KeyVersion::Hashed),
+            (&limit_with_id, KeyCodec::Hashed, KeyVersion::Hashed),
+        ] {
+            let counter = Counter::new(limit.clone(), &ctx)
+                .expect("counter creation failed!")
+                .expect("must have a counter");
+            let raw = codec.key_for_counter(&counter);
+            assert_eq!(KeyVersion::of(&raw), version);
+            assert_eq!(
+                counter,
+                counter_from_counter_key(&raw, Arc::new(limit.clone()))
+            );
+        }
+    }
+
     #[test]
     fn counter_key_does_not_include_transient_state() {
         let namespace = "ns_counter:";
@@ -184,7 +367,15 @@ mod tests {
     }
 }
 
-#[cfg(feature = "disk_storage")]
+#[cfg(any(
+    feature = "disk_storage",
+    feature = "distributed_storage",
+    feature = "etcd_storage",
+    feature = "partitioned_storage",
+    feature = "raft_storage",
+    feature = "redis_storage",
+    feature = "sqlite_storage"
+))]
 pub mod bin {
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
@@ -332,6 +523,12 @@ pub mod bin {
         postcard::to_stdvec(namespace).unwrap()
     }
 
+    pub fn namespace_from_counter_key(key: &[u8]) -> Option<&str> {
+        postcard::take_from_bytes::<&str>(key)
+            .ok()
+            .map(|(namespace, _)| namespace)
+    }
+
     pub fn partial_counter_from_counter_key(key: &[u8]) -> Counter {
         let key: CounterKey = postcard::from_bytes(key).unwrap();
         let CounterKey {
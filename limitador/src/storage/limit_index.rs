@@ -0,0 +1,231 @@
+use crate::limit::{Context, Expression, Limit, Namespace};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// The limits of a namespace, indexed by one of their conditions comparing an
+/// operand to a string, e.g. `descriptors[0].method == 'GET'`. A request then
+/// only gets tested against the limits whose indexed condition it meets, and
+/// the ones that have none, rather than against all of them.
+#[derive(Default)]
+pub(super) struct LimitIndex {
+    equalities: Vec<Equality>,
+    unindexed: Vec<Arc<Limit>>,
+}
+
+// The limits indexed by the value their condition compares the operand to
+struct Equality {
+    operand: Expression,
+    variables: Vec<String>,
+    limits: HashMap<String, Vec<Arc<Limit>>>,
+}
+
+impl LimitIndex {
+    pub fn new<'a>(limits: impl IntoIterator<Item = &'a Arc<Limit>>) -> Self {
+        let mut index = Self::default();
+        for limit in limits {
+            let Some((operand, value)) = indexed_condition(limit) else {
+                index.unindexed.push(Arc::clone(limit));
+                continue;
+            };
+            let position = match index.equalities.iter().position(|e| e.operand == operand) {
+                Some(position) => position,
+                None => {
+                    index.equalities.push(Equality {
+                        variables: operand.variables(),
+                        operand,
+                        limits: HashMap::new(),
+                    });
+                    index.equalities.len() - 1
+                }
+            };
+            index.equalities[position]
+                .limits
+                .entry(value)
+                .or_default()
+                .push(Arc::clone(limit));
+        }
+        index
+    }
+
+    /// Adds the limits that may apply to the `ctx` to `candidates`: all the
+    /// ones that do, along with some that don't
+    pub fn add_candidates(&self, ctx: &Context, candidates: &mut HashSet<Arc<Limit>>) {
+        candidates.extend(self.candidates(ctx).cloned());
+    }
+
+    /// The limits that may apply to the `ctx`, borrowed from the index. None
+    /// of them is yielded twice.
+    pub fn candidates<'a, 'c>(
+        &'a self,
+        ctx: &'a Context<'c>,
+    ) -> impl Iterator<Item = &'a Arc<Limit>> + Captures<'c> {
+        let indexed = self
+            .equalities
+            .iter()
+            .flat_map(move |equality| equality.candidates(ctx));
+        self.unindexed.iter().chain(indexed)
+    }
+}
+
+impl Equality {
+    fn candidates(&self, ctx: &Context) -> impl Iterator<Item = &Arc<Limit>> {
+        let (matched, all) = if !self
+            .variables
+            .iter()
+            .all(|var| ctx.has_variables(&[var.as_str()]))
+        {
+            (None, None)
+        } else {
+            match self.operand.eval(ctx) {
+                Ok(Some(value)) => (self.limits.get(&value), None),
+                Ok(None) => (None, None),
+                // Left for testing the conditions to surface
+                Err(_) => (None, Some(self.limits.values())),
+            }
+        };
+        matched
+            .into_iter()
+            .flatten()
+            .chain(all.into_iter().flatten().flatten())
+    }
+}
+
+/// The namespace patterns limits are defined on, indexed by their literal
+/// prefix, i.e. what comes before their first wildcard. A namespace then only
+/// gets matched against the patterns whose prefix it starts with, rather than
+/// against all of them.
+#[derive(Default)]
+pub(super) struct PatternIndex {
+    patterns: HashMap<String, HashSet<Namespace>>,
+}
+
+impl PatternIndex {
+    pub fn insert(&mut self, pattern: &Namespace) {
+        self.patterns
+            .entry(literal_prefix(pattern).to_string())
+            .or_default()
+            .insert(pattern.clone());
+    }
+
+    pub fn remove(&mut self, pattern: &Namespace) {
+        let prefix = literal_prefix(pattern);
+        if let Some(patterns) = self.patterns.get_mut(prefix) {
+            patterns.remove(pattern);
+            if patterns.is_empty() {
+                self.patterns.remove(prefix);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.patterns.clear();
+    }
+
+    /// The patterns matching the `namespace`
+    pub fn matching<'a>(&'a self, namespace: &'a Namespace) -> impl Iterator<Item = &'a Namespace> {
+        let ns = namespace.as_ref();
+        ns.char_indices()
+            .map(|(idx, _)| idx)
+            .chain([ns.len()])
+            .filter_map(move |idx| self.patterns.get(&ns[..idx]))
+            .flatten()
+            .filter(move |pattern| pattern.matches(namespace))
+    }
+}
+
+fn literal_prefix(pattern: &Namespace) -> &str {
+    let pattern = pattern.as_ref();
+    match pattern.find(Namespace::WILDCARD) {
+        Some(idx) => &pattern[..idx],
+        None => pattern,
+    }
+}
+
+// Lets the iterators borrowing the context name its lifetime
+pub(super) trait Captures<'a> {}
+
+impl<T: ?Sized> Captures<'_> for T {}
+
+// The operand and value of the first of the conditions of the `limit` that
+// compares one to a string, unless it depends on the limit itself
+fn indexed_condition(limit: &Limit) -> Option<(Expression, String)> {
+    limit
+        .predicates()
+        .filter_map(|predicate| predicate.equality())
+        .find(|(operand, _)| !operand.variables().iter().any(|var| var == "limit"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LimitIndex, PatternIndex};
+    use crate::limit::Namespace;
+    use crate::limit::{Context, Limit};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    #[test]
+    fn only_yields_the_limits_whose_indexed_condition_is_met() {
+        let limit = |condition: &str| {
+            Arc::new(Limit::new(
+                "test_namespace",
+                10,
+                60,
+                vec![condition.try_into().expect("failed parsing!")],
+                vec!["app_id".try_into().expect("failed parsing!")],
+            ))
+        };
+        let get = limit("req_method == 'GET'");
+        let post = limit("req_method == 'POST'");
+        let reversed = limit("'GET' == req_method");
+        let other = limit("size(app_id) > 3");
+        let index = LimitIndex::new(&[
+            Arc::clone(&get),
+            Arc::clone(&post),
+            Arc::clone(&reversed),
+            Arc::clone(&other),
+        ]);
+
+        let values = HashMap::from([
+            ("req_method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "test_app_id".to_string()),
+        ]);
+        let mut candidates = HashSet::new();
+        index.add_candidates(&values.into(), &mut candidates);
+        assert_eq!(
+            candidates,
+            HashSet::from([get, reversed, Arc::clone(&other)])
+        );
+
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let mut candidates = HashSet::new();
+        index.add_candidates(&Context::from(values), &mut candidates);
+        assert_eq!(candidates, HashSet::from([other]));
+    }
+
+    #[test]
+    fn only_matches_the_patterns_whose_prefix_the_namespace_starts_with() {
+        let mut index = PatternIndex::default();
+        for pattern in ["api.*", "api.*.v1", "*.v1", "web.*"] {
+            index.insert(&pattern.into());
+        }
+        index.remove(&"web.*".into());
+
+        let matching = |namespace: &str| {
+            let namespace: Namespace = namespace.into();
+            index
+                .matching(&namespace)
+                .map(|pattern| pattern.as_ref().to_string())
+                .collect::<HashSet<_>>()
+        };
+        assert_eq!(
+            matching("api.foo.v1"),
+            HashSet::from([
+                "api.*".to_string(),
+                "api.*.v1".to_string(),
+                "*.v1".to_string()
+            ])
+        );
+        assert_eq!(matching("api.foo"), HashSet::from(["api.*".to_string()]));
+        assert!(matching("web.foo").is_empty());
+    }
+}
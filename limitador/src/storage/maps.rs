@@ -0,0 +1,247 @@
+//! Std-only stand-ins for the `dashmap` and `moka` maps the in-memory storage
+//! holds its counters in, when built without the `cache` feature. Only what
+//! the storage uses of their APIs is there: every map is behind a single lock,
+//! and the cache makes room for a new counter by evicting an expired one, or
+//! an arbitrary one when none is.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+
+pub(crate) struct DashMap<K, V> {
+    map: RwLock<HashMap<K, V>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> DashMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            map: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Ref<V>> {
+        self.map.read().unwrap().get(key).cloned().map(Ref)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.map.write().unwrap().insert(key, value)
+    }
+
+    pub fn entry(&self, key: K) -> Entry<'_, K, V> {
+        let map = self.map.write().unwrap();
+        if let Some(value) = map.get(&key).cloned() {
+            return Entry::Occupied(OccupiedEntry(value));
+        }
+        Entry::Vacant(VacantEntry { map, key })
+    }
+
+    pub fn remove(&self, key: &K) -> Option<(K, V)> {
+        self.map.write().unwrap().remove_entry(key)
+    }
+
+    pub fn remove_if(&self, key: &K, f: impl FnOnce(&K, &V) -> bool) -> Option<(K, V)> {
+        let mut map = self.map.write().unwrap();
+        if map.get_key_value(key).is_some_and(|(k, v)| f(k, v)) {
+            map.remove_entry(key)
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&self) {
+        self.map.write().unwrap().clear()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.map.read().unwrap().is_empty()
+    }
+
+    /// A snapshot of the entries, the map not staying locked while iterated
+    pub fn iter(&self) -> impl Iterator<Item = RefMulti<K, V>> {
+        let entries: Vec<_> = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| RefMulti(key.clone(), value.clone()))
+            .collect();
+        entries.into_iter()
+    }
+}
+
+/// A value cloned out of the map
+pub(crate) struct Ref<V>(V);
+
+impl<V> Ref<V> {
+    pub fn value(&self) -> &V {
+        &self.0
+    }
+}
+
+impl<V> Deref for Ref<V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.0
+    }
+}
+
+/// An entry cloned out of the map
+pub(crate) struct RefMulti<K, V>(K, V);
+
+impl<K, V> RefMulti<K, V> {
+    pub fn key(&self) -> &K {
+        &self.0
+    }
+
+    pub fn value(&self) -> &V {
+        &self.1
+    }
+
+    pub fn pair(&self) -> (&K, &V) {
+        (&self.0, &self.1)
+    }
+}
+
+pub(crate) enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+impl<K: Eq + Hash, V: Clone> Entry<'_, K, V> {
+    pub fn or_default(self) -> Ref<V>
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn or_insert_with(self, f: impl FnOnce() -> V) -> Ref<V> {
+        match self {
+            Entry::Occupied(entry) => Ref(entry.0),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+}
+
+pub(crate) struct OccupiedEntry<V>(V);
+
+impl<V> OccupiedEntry<V> {
+    pub fn get(&self) -> &V {
+        &self.0
+    }
+}
+
+/// Keeps the map locked, until the value gets inserted
+pub(crate) struct VacantEntry<'a, K, V> {
+    map: RwLockWriteGuard<'a, HashMap<K, V>>,
+    key: K,
+}
+
+impl<K: Eq + Hash, V: Clone> VacantEntry<'_, K, V> {
+    pub fn insert(mut self, value: V) -> Ref<V> {
+        self.map.insert(self.key, value.clone());
+        Ref(value)
+    }
+}
+
+/// Holds up to `max_capacity` entries, shared by its clones
+pub(crate) struct Cache<K, V> {
+    map: Arc<RwLock<HashMap<Arc<K>, V>>>,
+    max_capacity: u64,
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            map: Arc::clone(&self.map),
+            max_capacity: self.max_capacity,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone + Expiring> Cache<K, V> {
+    pub fn new(max_capacity: u64) -> Self {
+        Self {
+            map: Arc::new(RwLock::new(HashMap::new())),
+            max_capacity,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map.read().unwrap().get(key).cloned()
+    }
+
+    pub fn get_with(&self, key: K, init: impl FnOnce() -> V) -> V {
+        let mut map = self.map.write().unwrap();
+        if let Some(value) = map.get(&key) {
+            return value.clone();
+        }
+        let value = init();
+        self.make_room(&mut map);
+        map.insert(Arc::new(key), value.clone());
+        value
+    }
+
+    pub fn get_with_by_ref(&self, key: &K, init: impl FnOnce() -> V) -> V {
+        if let Some(value) = self.get(key) {
+            return value;
+        }
+        self.get_with(key.clone(), init)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut map = self.map.write().unwrap();
+        let key = Arc::new(key);
+        if !map.contains_key(&key) {
+            self.make_room(&mut map);
+        }
+        map.insert(key, value);
+    }
+
+    #[cfg(test)]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.read().unwrap().contains_key(key)
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        self.map.write().unwrap().remove(key);
+    }
+
+    pub fn invalidate_all(&self) {
+        self.map.write().unwrap().clear()
+    }
+
+    /// A snapshot of the entries, the cache not staying locked while iterated
+    pub fn iter(&self) -> impl Iterator<Item = (Arc<K>, V)> {
+        let entries: Vec<_> = self
+            .map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| (Arc::clone(key), value.clone()))
+            .collect();
+        entries.into_iter()
+    }
+
+    fn make_room(&self, map: &mut HashMap<Arc<K>, V>) {
+        if (map.len() as u64) < self.max_capacity {
+            return;
+        }
+        let evicted = map
+            .iter()
+            .find(|(_, value)| value.expired())
+            .or_else(|| map.iter().next())
+            .map(|(key, _)| Arc::clone(key));
+        if let Some(key) = evicted {
+            map.remove(&key);
+        }
+    }
+}
+
+/// What the cache evicts first
+pub(crate) trait Expiring {
+    fn expired(&self) -> bool;
+}
@@ -1,133 +1,286 @@
+use crate::clock;
 use crate::counter::Counter;
-use crate::limit::{Limit, Namespace};
+use crate::limit::{Context, Limit, Namespace};
+use crate::storage::blocking::{resolve, BlockingCounterStorage};
+use crate::storage::limit_index::{LimitIndex, PatternIndex};
 use crate::InMemoryStorage;
 use async_trait::async_trait;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::ops::Deref;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
+pub mod circuit_breaker;
+#[cfg(feature = "cache")]
+pub mod concurrent;
 #[cfg(feature = "disk_storage")]
 pub mod disk;
 #[cfg(feature = "distributed_storage")]
 pub mod distributed;
+#[cfg(feature = "etcd_storage")]
+pub mod etcd;
+#[cfg(feature = "tokio")]
+pub mod failover;
 pub mod in_memory;
+#[cfg(feature = "partitioned_storage")]
+pub mod partitioned;
+#[cfg(any(feature = "distributed_storage", feature = "raft_storage"))]
+pub mod peer_auth;
+#[cfg(feature = "raft_storage")]
+pub mod raft;
 
 #[cfg(feature = "distributed_storage")]
-pub use crate::storage::distributed::CrInMemoryStorage as DistributedInMemoryStorage;
+pub use crate::storage::distributed::{
+    CrInMemoryStorage as DistributedInMemoryStorage,
+    DEFAULT_SYNC_TIMEOUT_MS as DEFAULT_DISTRIBUTED_SYNC_TIMEOUT_MS,
+};
 
 #[cfg(feature = "redis_storage")]
 pub mod redis;
+#[cfg(feature = "sqlite_storage")]
+pub mod sqlite;
 
 mod atomic_expiring_value;
-#[cfg(any(feature = "disk_storage", feature = "redis_storage"))]
+pub(crate) mod blocking;
+#[cfg(any(feature = "partitioned_storage", feature = "raft_storage"))]
+mod commands;
+#[cfg(any(
+    feature = "disk_storage",
+    feature = "distributed_storage",
+    feature = "etcd_storage",
+    feature = "partitioned_storage",
+    feature = "raft_storage",
+    feature = "redis_storage",
+    feature = "sqlite_storage"
+))]
 mod keys;
+mod limit_index;
+#[cfg(not(feature = "cache"))]
+mod maps;
+#[cfg(test)]
+mod testing;
+mod tokens;
 
 pub enum Authorization {
     Ok,
-    Limited(Option<String>), // First counter found over the limits
+    Limited(Option<Arc<Limit>>), // Limit of the first counter found over the limits
+}
+
+impl Authorization {
+    pub fn limited_by(counter: &Counter) -> Self {
+        Authorization::Limited(Some(counter.shared_limit()))
+    }
+}
+
+/// Narrows down the counters returned by `get_counters_page`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CounterFilter {
+    limit_id: Option<String>,
+    variable_prefix: Option<(String, String)>,
+    min_usage_percent: Option<u64>,
+}
+
+impl CounterFilter {
+    /// Only the counters of the limit with this `id`
+    pub fn limit_id(mut self, id: impl Into<String>) -> Self {
+        self.limit_id = Some(id.into());
+        self
+    }
+
+    /// Only the counters whose value of the `variable` starts with `prefix`
+    pub fn variable_prefix(
+        mut self,
+        variable: impl Into<String>,
+        prefix: impl Into<String>,
+    ) -> Self {
+        self.variable_prefix = Some((variable.into(), prefix.into()));
+        self
+    }
+
+    /// Only the counters that used at least `percent` of their max value
+    pub fn min_usage_percent(mut self, percent: u64) -> Self {
+        self.min_usage_percent = Some(percent);
+        self
+    }
+
+    pub(crate) fn applies_to_limit(&self, limit: &Limit) -> bool {
+        match &self.limit_id {
+            Some(id) => limit.id() == Some(id.as_str()),
+            None => true,
+        }
+    }
+
+    // Whether the counter could match, from its variables alone, i.e. before
+    // having to load its value
+    fn matches_variables(&self, counter: &Counter) -> bool {
+        match &self.variable_prefix {
+            Some((variable, prefix)) => counter
+                .set_variables()
+                .get(variable)
+                .is_some_and(|value| value.starts_with(prefix.as_str())),
+            None => true,
+        }
+    }
+
+    /// Whether the `counter`, with its remaining loaded, matches
+    pub fn matches(&self, counter: &Counter) -> bool {
+        if !self.applies_to_limit(counter.limit()) || !self.matches_variables(counter) {
+            return false;
+        }
+        match self.min_usage_percent {
+            Some(percent) => {
+                let max_value = counter.max_value();
+                let used = max_value - counter.remaining().unwrap_or(max_value).min(max_value);
+                u128::from(used) * 100 >= u128::from(percent) * u128::from(max_value)
+            }
+            None => true,
+        }
+    }
+}
+
+/// What becomes of the counters of a limit whose max value gets updated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MaxValueUpdate {
+    /// The counters keep their values, e.g. with 5 hits out of 10, raising the
+    /// max value to 20 lets 15 more through
+    #[default]
+    Keep,
+    /// The counters get rescaled to the new max value, rounding down, e.g.
+    /// with 5 hits out of 10, raising the max value to 20 lets 10 more through
+    Rescale,
+}
+
+/// A page of counters, along with the cursor to get the next one with, if any
+#[derive(Debug, Default)]
+pub struct CountersPage {
+    pub counters: Vec<Counter>,
+    pub next_cursor: Option<String>,
 }
 
 pub struct Storage {
-    limits: RwLock<HashMap<Namespace, HashSet<Arc<Limit>>>>,
-    counters: Box<dyn CounterStorage>,
+    inner: AsyncStorage,
 }
 
 pub struct AsyncStorage {
     limits: RwLock<HashMap<Namespace, HashSet<Arc<Limit>>>>,
+    patterns: RwLock<PatternIndex>,
+    indexes: RwLock<HashMap<Namespace, Arc<LimitIndex>>>,
+    expirations: RwLock<HashMap<Arc<Limit>, SystemTime>>,
     counters: Box<dyn AsyncCounterStorage>,
+    // The same counters, when they are a blocking storage
+    blocking: Option<Arc<dyn CounterStorage>>,
+    max_value_update: MaxValueUpdate,
+    hierarchical_namespaces: bool,
 }
 
+// The blocking front of an `AsyncStorage`, over a `CounterStorage` whose
+// calls it resolves right away
 impl Storage {
     pub fn new(cache_size: u64) -> Self {
-        Self {
-            limits: RwLock::new(HashMap::new()),
-            counters: Box::new(InMemoryStorage::new(cache_size)),
-        }
+        Self::with_counter_storage(Box::new(InMemoryStorage::new(cache_size)))
     }
 
     pub fn with_counter_storage(counters: Box<dyn CounterStorage>) -> Self {
-        Self {
-            limits: RwLock::new(HashMap::new()),
-            counters,
-        }
+        let counters: Arc<dyn CounterStorage> = Arc::from(counters);
+        let mut inner = AsyncStorage::with_counter_storage(Box::new(BlockingCounterStorage(
+            Box::new(Arc::clone(&counters)),
+        )));
+        inner.blocking = Some(counters);
+        Self { inner }
+    }
+
+    /// What becomes of the counters of the limits whose max value gets
+    /// updated, kept as they are by default
+    pub fn max_value_update(mut self, max_value_update: MaxValueUpdate) -> Self {
+        self.inner = self.inner.max_value_update(max_value_update);
+        self
+    }
+
+    /// Whether namespaces nest, using `/` as a separator, the limits of a
+    /// namespace then applying to all of its descendants. Off by default
+    pub fn hierarchical_namespaces(mut self, hierarchical: bool) -> Self {
+        self.inner = self.inner.hierarchical_namespaces(hierarchical);
+        self
     }
 
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
-        self.limits.read().unwrap().keys().cloned().collect()
+        self.inner.get_namespaces()
     }
 
-    pub fn add_limit(&self, limit: Limit) -> bool {
-        let namespace = limit.namespace().clone();
-        let mut limits = self.limits.write().unwrap();
-        self.counters.add_counter(&limit).unwrap();
-        limits.entry(namespace).or_default().insert(Arc::new(limit))
+    pub fn get_namespace_patterns(&self) -> HashSet<Namespace> {
+        self.inner.get_namespace_patterns()
     }
 
-    pub fn update_limit(&self, update: &Limit) -> bool {
-        let mut namespaces = self.limits.write().unwrap();
-        let limits = namespaces.get_mut(update.namespace());
-        if let Some(limits) = limits {
-            let req_update = if let Some(limit) = limits.get(update) {
-                limit.max_value() != update.max_value() || limit.name() != update.name()
-            } else {
-                false
-            };
-            if req_update {
-                limits.remove(update);
-                limits.insert(Arc::new(update.clone()));
-                return true;
-            }
-        }
-        false
+    pub fn add_limit(&self, limit: Limit) -> Result<bool, LimitIdConflict> {
+        self.inner.add_limit(limit)
     }
 
-    pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Arc<Limit>> {
-        match self.limits.read().unwrap().get(namespace) {
-            // todo revise typing here?
-            Some(limits) => limits.iter().map(Arc::clone).collect(),
-            None => HashSet::new(),
-        }
+    pub fn update_limit(&self, update: &Limit) -> Result<bool, StorageErr> {
+        resolve(self.inner.update_limit(update))
     }
 
-    pub fn delete_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
-        let arc = match self.limits.read().unwrap().get(limit.namespace()) {
-            None => Arc::new(limit.clone()),
-            Some(limits) => limits
-                .iter()
-                .find(|l| ***l == *limit)
-                .cloned()
-                .unwrap_or_else(|| Arc::new(limit.clone())),
-        };
-        let mut limits = HashSet::new();
-        limits.insert(arc);
-        self.counters.delete_counters(&limits)?;
+    pub fn replace_limits(
+        &self,
+        namespace: &Namespace,
+        limits: impl IntoIterator<Item = Limit>,
+    ) -> Result<(), StorageErr> {
+        resolve(self.inner.replace_limits(namespace, limits))
+    }
 
-        let mut limits = self.limits.write().unwrap();
+    pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Arc<Limit>> {
+        self.inner.get_limits(namespace)
+    }
 
-        if let Some(limits_for_ns) = limits.get_mut(limit.namespace()) {
-            limits_for_ns.remove(limit);
+    pub fn get_applicable_limits(&self, namespace: &Namespace) -> HashSet<Arc<Limit>> {
+        self.inner.get_applicable_limits(namespace)
+    }
 
-            if limits_for_ns.is_empty() {
-                limits.remove(limit.namespace());
-            }
-        }
-        Ok(())
+    pub fn get_candidate_limits(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context,
+    ) -> HashSet<Arc<Limit>> {
+        self.inner.get_candidate_limits(namespace, ctx)
+    }
+
+    pub fn delete_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
+        resolve(self.inner.delete_limit(limit))
     }
 
     pub fn delete_limits(&self, namespace: &Namespace) -> Result<(), StorageErr> {
-        if let Some(data) = self.limits.write().unwrap().remove(namespace) {
-            self.counters.delete_counters(&data)?;
-        }
-        Ok(())
+        resolve(self.inner.delete_limits(namespace))
+    }
+
+    /// Deletes the limits whose `ttl` has elapsed, along with their counters
+    pub fn delete_expired_limits(&self) -> Result<(), StorageErr> {
+        resolve(self.inner.delete_expired_limits())
     }
 
     pub fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
-        self.counters.is_within_limits(counter, delta)
+        resolve(self.inner.is_within_limits(counter, delta))
     }
 
     pub fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
-        self.counters.update_counter(counter, delta)
+        resolve(self.inner.update_counter(counter, delta))
+    }
+
+    pub fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        resolve(self.inner.refund_counter(counter, delta))
+    }
+
+    pub fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        resolve(self.inner.claim_token(namespace, token, horizon))
+    }
+
+    pub fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        resolve(self.inner.release_token(namespace, token))
     }
 
     pub fn check_and_update(
@@ -136,20 +289,68 @@ impl Storage {
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr> {
-        self.counters
-            .check_and_update(counters, delta, load_counters)
+        resolve(self.inner.check_and_update(counters, delta, load_counters))
+    }
+
+    pub fn check_and_update_batch(
+        &self,
+        batches: &mut [(Vec<Counter>, u64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        resolve(self.inner.check_and_update_batch(batches, load_counters))
+    }
+
+    pub fn peek(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        resolve(self.inner.peek(counters, delta))
     }
 
     pub fn get_counters(&self, namespace: &Namespace) -> Result<HashSet<Counter>, StorageErr> {
-        match self.limits.read().unwrap().get(namespace) {
-            Some(limits) => self.counters.get_counters(limits),
-            None => Ok(HashSet::new()),
-        }
+        resolve(self.inner.get_counters(namespace))
+    }
+
+    pub fn get_counters_page(
+        &self,
+        namespace: &Namespace,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        resolve(
+            self.inner
+                .get_counters_page(namespace, filter, cursor, page_size),
+        )
+    }
+
+    pub fn reset_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        resolve(self.inner.reset_counter(counter))
+    }
+
+    pub fn reset_counters_of_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
+        resolve(self.inner.reset_counters_of_limit(limit))
+    }
+
+    pub fn reset_counters(&self, namespace: &Namespace) -> Result<(), StorageErr> {
+        resolve(self.inner.reset_counters(namespace))
     }
 
     pub fn clear(&self) -> Result<(), StorageErr> {
-        self.limits.write().unwrap().clear();
-        self.counters.clear()
+        resolve(self.inner.clear())
+    }
+
+    pub fn flush(&self) -> Result<(), StorageErr> {
+        resolve(self.inner.flush())
+    }
+
+    pub fn ping(&self) -> Result<(), StorageErr> {
+        resolve(self.inner.ping())
+    }
+
+    pub(crate) fn into_async(self) -> AsyncStorage {
+        self.inner
     }
 }
 
@@ -157,46 +358,201 @@ impl AsyncStorage {
     pub fn with_counter_storage(counters: Box<dyn AsyncCounterStorage>) -> Self {
         Self {
             limits: RwLock::new(HashMap::new()),
+            patterns: RwLock::new(PatternIndex::default()),
+            indexes: RwLock::new(HashMap::new()),
+            expirations: RwLock::new(HashMap::new()),
             counters,
+            blocking: None,
+            max_value_update: MaxValueUpdate::default(),
+            hierarchical_namespaces: false,
         }
     }
 
+    /// What becomes of the counters of the limits whose max value gets
+    /// updated, kept as they are by default
+    pub fn max_value_update(mut self, max_value_update: MaxValueUpdate) -> Self {
+        self.max_value_update = max_value_update;
+        self
+    }
+
+    /// Whether namespaces nest, using `/` as a separator, the limits of a
+    /// namespace then applying to all of its descendants. Off by default
+    pub fn hierarchical_namespaces(mut self, hierarchical: bool) -> Self {
+        self.hierarchical_namespaces = hierarchical;
+        self
+    }
+
+    /// The concrete namespaces limits are defined on, i.e. not the patterns
     pub fn get_namespaces(&self) -> HashSet<Namespace> {
-        self.limits.read().unwrap().keys().cloned().collect()
+        self.limits
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|namespace| !namespace.is_pattern())
+            .cloned()
+            .collect()
     }
 
-    pub fn add_limit(&self, limit: Limit) -> bool {
-        let namespace = limit.namespace().clone();
+    /// The namespace patterns limits are defined on
+    pub fn get_namespace_patterns(&self) -> HashSet<Namespace> {
+        self.limits
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|namespace| namespace.is_pattern())
+            .cloned()
+            .collect()
+    }
 
+    /// Adds the limit, unless it already is: `false` then. Fails when its id
+    /// is the one of another limit, of any namespace
+    pub fn add_limit(&self, limit: Limit) -> Result<bool, LimitIdConflict> {
         let mut limits_for_namespace = self.limits.write().unwrap();
+        check_limit_ids(
+            limits_for_namespace
+                .values()
+                .flatten()
+                .map(Arc::as_ref)
+                .chain([&limit]),
+        )?;
+        self.counters.add_counter(&limit).unwrap();
+        Ok(self.insert_limit(&mut limits_for_namespace, Arc::new(limit)))
+    }
 
-        match limits_for_namespace.get_mut(&namespace) {
-            Some(limits) => limits.insert(Arc::new(limit)),
-            None => {
-                let mut limits = HashSet::new();
-                limits.insert(Arc::new(limit));
-                limits_for_namespace.insert(namespace, limits);
-                true
+    /// Updates the limit with the id of the `update`, or the one with its
+    /// definition, i.e. namespace, window, conditions and variables, when it
+    /// has no id: `false` when there's no such limit, nothing to update, or
+    /// when its new definition is the one of another limit. Its counters are
+    /// kept when only its name, metadata, cost, schedule, ttl or overrides
+    /// change, rescaled when its max value does, as set with
+    /// `max_value_update`, and reset when its definition does.
+    pub async fn update_limit(&self, update: &Limit) -> Result<bool, StorageErr> {
+        let current = {
+            let namespaces = self.limits.read().unwrap();
+            match limit_to_update(&namespaces, update) {
+                Some(current) if *current != *update => {
+                    let taken = namespaces
+                        .get(update.namespace())
+                        .is_some_and(|limits| limits.contains(update));
+                    if taken {
+                        return Ok(false);
+                    }
+                    current
+                }
+                Some(current) if requires_update(&current, update) => current,
+                _ => return Ok(false),
             }
+        };
+
+        if *current != *update {
+            self.reset_counters_of_limit(&current).await?;
+            self.counters.add_counter(update)?;
         }
+        // Read while still of the previous limit, whose max value the storages
+        // tell what remains of them against
+        let to_rescale = if self.rescales(&current, update) {
+            let limits = HashSet::from([Arc::clone(&current)]);
+            self.counters.get_counters(&limits).await?
+        } else {
+            HashSet::new()
+        };
+        let limit = Arc::new(update.clone());
+        {
+            let mut namespaces = self.limits.write().unwrap();
+            self.remove_limit(&mut namespaces, &current);
+            self.insert_limit(&mut namespaces, Arc::clone(&limit));
+        }
+        self.rescale_counters(to_rescale, &limit).await?;
+        Ok(true)
     }
 
-    pub fn update_limit(&self, update: &Limit) -> bool {
-        let mut namespaces = self.limits.write().unwrap();
-        let limits = namespaces.get_mut(update.namespace());
-        if let Some(limits) = limits {
-            let req_update = if let Some(limit) = limits.get(update) {
-                limit.max_value() != update.max_value() || limit.name() != update.name()
+    /// Replaces the limits of the `namespace` with the given ones, all at
+    /// once. The limits whose definition, i.e. window, conditions or
+    /// variables, isn't in `limits` anymore are deleted along with their
+    /// counters, while the others keep theirs, rescaled when their max value
+    /// changed, as set with `max_value_update`. Limits of other namespaces
+    /// are ignored.
+    pub async fn replace_limits(
+        &self,
+        namespace: &Namespace,
+        limits: impl IntoIterator<Item = Limit>,
+    ) -> Result<(), StorageErr> {
+        let limits: Vec<Limit> = limits
+            .into_iter()
+            .filter(|limit| limit.namespace() == namespace)
+            .collect();
+        // Read while still of their previous limits, whose max value the
+        // storages tell what remains of them against
+        let rescaling: Vec<Arc<Limit>> = self
+            .get_limits(namespace)
+            .into_iter()
+            .filter(|current| {
+                limits
+                    .iter()
+                    .any(|limit| limit == current.as_ref() && self.rescales(current, limit))
+            })
+            .collect();
+        let mut to_rescale = HashMap::new();
+        for current in rescaling {
+            let limits = HashSet::from([Arc::clone(&current)]);
+            to_rescale.insert(current, self.counters.get_counters(&limits).await?);
+        }
+
+        let mut rescaled = Vec::new();
+        let replaced = {
+            let mut namespaces = self.limits.write().unwrap();
+            let previous = namespaces.get(namespace).cloned().unwrap_or_default();
+
+            let mut replacements = HashSet::new();
+            let mut changed = Vec::new();
+            for limit in limits {
+                match previous.get(&limit) {
+                    Some(current) if !requires_update(current, &limit) => {
+                        replacements.insert(Arc::clone(current));
+                    }
+                    current => {
+                        self.counters.add_counter(&limit)?;
+                        let limit = Arc::new(limit);
+                        if current.is_some_and(|c| self.rescales(c, &limit)) {
+                            rescaled.push(Arc::clone(&limit));
+                        }
+                        changed.push(Arc::clone(&limit));
+                        replacements.replace(limit);
+                    }
+                }
+            }
+
+            let replaced: HashSet<Arc<Limit>> =
+                previous.difference(&replacements).cloned().collect();
+            let mut expirations = self.expirations.write().unwrap();
+            for limit in &replaced {
+                expirations.remove(limit);
+            }
+            for limit in &changed {
+                track_expiry(&mut expirations, limit);
+            }
+            self.indexes.write().unwrap().remove(namespace);
+            if replacements.is_empty() {
+                namespaces.remove(namespace);
+                self.patterns.write().unwrap().remove(namespace);
             } else {
-                false
-            };
-            if req_update {
-                limits.remove(update);
-                limits.insert(Arc::new(update.clone()));
-                return true;
+                if namespace.is_pattern() {
+                    self.patterns.write().unwrap().insert(namespace);
+                }
+                namespaces.insert(namespace.clone(), replacements);
+            }
+            replaced
+        };
+
+        if !replaced.is_empty() {
+            self.counters.delete_counters(&replaced).await?;
+        }
+        for limit in rescaled {
+            if let Some(counters) = to_rescale.remove(&limit) {
+                self.rescale_counters(counters, &limit).await?;
             }
         }
-        false
+        Ok(())
     }
 
     pub fn get_limits(&self, namespace: &Namespace) -> HashSet<Arc<Limit>> {
@@ -206,39 +562,221 @@ impl AsyncStorage {
         }
     }
 
-    pub async fn delete_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
-        let arc = match self.limits.read().unwrap().get(limit.namespace()) {
-            None => Arc::new(limit.clone()),
-            Some(limits) => limits
-                .iter()
-                .find(|l| ***l == *limit)
-                .cloned()
-                .unwrap_or_else(|| Arc::new(limit.clone())),
+    /// All the limits that apply to `namespace`: the ones defined on it, and
+    /// its ancestors when namespaces are hierarchical, as well as the ones
+    /// defined on namespace patterns matching any of these
+    pub fn get_applicable_limits(&self, namespace: &Namespace) -> HashSet<Arc<Limit>> {
+        let limits = self.limits.read().unwrap();
+        let mut res = HashSet::new();
+        for ns in self.applicable_namespaces(&limits, namespace) {
+            if let Some(limits) = limits.get(&ns) {
+                res.extend(limits.iter().map(Arc::clone));
+            }
+        }
+        res
+    }
+
+    /// The limits that apply to `namespace` which the `ctx` may meet the
+    /// conditions of, looked up in an index of their conditions rather than
+    /// all tested against it
+    pub fn get_candidate_limits(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context,
+    ) -> HashSet<Arc<Limit>> {
+        let limits = self.limits.read().unwrap();
+        let mut res = HashSet::new();
+        for ns in self.applicable_namespaces(&limits, namespace) {
+            self.index_of(&limits, &ns).add_candidates(ctx, &mut res);
+        }
+        res
+    }
+
+    /// Has `f` go through the same limits as `get_candidate_limits`, borrowed
+    /// where they are held rather than cloned out. The limits can't change
+    /// until it returns.
+    pub(crate) fn with_candidate_limits<R>(
+        &self,
+        namespace: &Namespace,
+        ctx: &Context,
+        f: impl FnOnce(&mut dyn Iterator<Item = &Arc<Limit>>) -> R,
+    ) -> R {
+        let limits = self.limits.read().unwrap();
+        let namespaces = self.applicable_namespaces(&limits, namespace);
+        match namespaces.as_slice() {
+            [namespace] => f(&mut self.index_of(&limits, namespace).candidates(ctx)),
+            namespaces => {
+                let indexes: Vec<Arc<LimitIndex>> = namespaces
+                    .iter()
+                    .map(|ns| self.index_of(&limits, ns))
+                    .collect();
+                f(&mut indexes.iter().flat_map(|index| index.candidates(ctx)))
+            }
+        }
+    }
+
+    /// The counters, when they are a blocking storage, e.g. held in memory,
+    /// for them to be checked without going through futures
+    pub(crate) fn blocking_counters(&self) -> Option<&dyn CounterStorage> {
+        self.blocking.as_deref()
+    }
+
+    // The namespaces with limits that apply to `namespace`: itself, its
+    // ancestors when namespaces are hierarchical, and the patterns matching any
+    // of these
+    fn applicable_namespaces(
+        &self,
+        limits: &HashMap<Namespace, HashSet<Arc<Limit>>>,
+        namespace: &Namespace,
+    ) -> Vec<Namespace> {
+        let patterns = self.patterns.read().unwrap();
+        let hierarchy: Box<dyn Iterator<Item = Namespace>> = if self.hierarchical_namespaces {
+            Box::new(namespace.hierarchy())
+        } else {
+            Box::new(std::iter::once(namespace.clone()))
         };
-        let mut limits = HashSet::new();
-        limits.insert(arc);
-        self.counters.delete_counters(&limits).await?;
+        let mut res = Vec::new();
+        for ns in hierarchy {
+            for ns in std::iter::once(&ns).chain(patterns.matching(&ns)) {
+                if limits.contains_key(ns) && !res.contains(ns) {
+                    res.push(ns.clone());
+                }
+            }
+        }
+        res
+    }
 
-        let mut limits_for_namespace = self.limits.write().unwrap();
+    // Inserts the `limit`, unless it's there already: `false` then
+    fn insert_limit(
+        &self,
+        namespaces: &mut HashMap<Namespace, HashSet<Arc<Limit>>>,
+        limit: Arc<Limit>,
+    ) -> bool {
+        let namespace = limit.namespace().clone();
+        if namespace.is_pattern() {
+            self.patterns.write().unwrap().insert(namespace);
+        }
+        let added = namespaces
+            .entry(namespace)
+            .or_default()
+            .insert(Arc::clone(&limit));
+        if added {
+            self.indexes.write().unwrap().remove(limit.namespace());
+            track_expiry(&mut self.expirations.write().unwrap(), &limit);
+        }
+        added
+    }
+
+    // Removes the `limit`, along with its namespace when it was the last of
+    // its limits
+    fn remove_limit(
+        &self,
+        namespaces: &mut HashMap<Namespace, HashSet<Arc<Limit>>>,
+        limit: &Limit,
+    ) {
+        if let Some(limits) = namespaces.get_mut(limit.namespace()) {
+            limits.remove(limit);
+            self.indexes.write().unwrap().remove(limit.namespace());
+            self.expirations.write().unwrap().remove(limit);
+
+            if limits.is_empty() {
+                namespaces.remove(limit.namespace());
+                self.patterns.write().unwrap().remove(limit.namespace());
+            }
+        }
+    }
 
-        if let Some(counters_by_limit) = limits_for_namespace.get_mut(limit.namespace()) {
-            counters_by_limit.remove(limit);
+    // Whether the counters of the `previous` limit get rescaled, now that it
+    // got updated to `limit`
+    fn rescales(&self, previous: &Limit, limit: &Limit) -> bool {
+        self.max_value_update == MaxValueUpdate::Rescale
+            && previous == limit
+            && previous.max_value() != limit.max_value()
+    }
 
-            if counters_by_limit.is_empty() {
-                limits_for_namespace.remove(limit.namespace());
+    // Rescales the `counters`, loaded while of the previous definition of the
+    // `limit`, from their max value then to the one they have now, rounding
+    // down
+    async fn rescale_counters(
+        &self,
+        counters: HashSet<Counter>,
+        limit: &Arc<Limit>,
+    ) -> Result<(), StorageErr> {
+        for previous in counters {
+            let from = previous.max_value();
+            if from == 0 {
+                continue;
+            }
+            let value = from.saturating_sub(previous.remaining().unwrap_or(from));
+            // Without the cost of the request it got loaded from, if any
+            let counter =
+                Counter::with_variables(Arc::clone(limit), previous.set_variables().clone());
+            let to = counter.max_value();
+            let rescaled = u64::try_from(u128::from(value) * u128::from(to) / u128::from(from))
+                .unwrap_or(u64::MAX);
+            if rescaled > value {
+                self.counters
+                    .update_counter(&counter, rescaled - value)
+                    .await?;
+            } else if rescaled < value {
+                self.counters
+                    .refund_counter(&counter, value - rescaled)
+                    .await?;
             }
         }
         Ok(())
     }
 
+    // Indexes get built on their first lookup after the limits of their
+    // namespace changed
+    fn index_of(
+        &self,
+        limits: &HashMap<Namespace, HashSet<Arc<Limit>>>,
+        namespace: &Namespace,
+    ) -> Arc<LimitIndex> {
+        if let Some(index) = self.indexes.read().unwrap().get(namespace) {
+            return Arc::clone(index);
+        }
+        let index = Arc::new(LimitIndex::new(limits.get(namespace).into_iter().flatten()));
+        self.indexes
+            .write()
+            .unwrap()
+            .insert(namespace.clone(), Arc::clone(&index));
+        index
+    }
+
+    pub async fn delete_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.reset_counters_of_limit(limit).await?;
+        self.remove_limit(&mut self.limits.write().unwrap(), limit);
+        Ok(())
+    }
+
     pub async fn delete_limits(&self, namespace: &Namespace) -> Result<(), StorageErr> {
-        let option = { self.limits.write().unwrap().remove(namespace) };
+        self.patterns.write().unwrap().remove(namespace);
+        self.expirations
+            .write()
+            .unwrap()
+            .retain(|limit, _| limit.namespace() != namespace);
+        let option = {
+            let mut limits = self.limits.write().unwrap();
+            self.indexes.write().unwrap().remove(namespace);
+            limits.remove(namespace)
+        };
         if let Some(data) = option {
             self.counters.delete_counters(&data).await?;
         }
         Ok(())
     }
 
+    /// Deletes the limits whose `ttl` has elapsed, along with their counters
+    pub async fn delete_expired_limits(&self) -> Result<(), StorageErr> {
+        let expired = expired_limits(&self.expirations.read().unwrap());
+        for limit in expired {
+            self.delete_limit(&limit).await?;
+        }
+        Ok(())
+    }
+
     pub async fn is_within_limits(
         &self,
         counter: &Counter,
@@ -251,6 +789,30 @@ impl AsyncStorage {
         self.counters.update_counter(counter, delta).await
     }
 
+    pub async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.counters.refund_counter(counter, delta).await
+    }
+
+    /// Records the idempotency `token` of a request to the `namespace` for
+    /// the next `horizon`: `false` when it's a retry of a request already
+    /// accounted for
+    pub async fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        self.counters.claim_token(namespace, token, horizon).await
+    }
+
+    pub async fn release_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+    ) -> Result<(), StorageErr> {
+        self.counters.release_token(namespace, token).await
+    }
+
     pub async fn check_and_update<'a>(
         &self,
         counters: &mut Vec<Counter>,
@@ -262,6 +824,24 @@ impl AsyncStorage {
             .await
     }
 
+    pub async fn check_and_update_batch(
+        &self,
+        batches: &mut [(Vec<Counter>, u64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        self.counters
+            .check_and_update_batch(batches, load_counters)
+            .await
+    }
+
+    pub async fn peek(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        self.counters.peek(counters, delta).await
+    }
+
     pub async fn get_counters(
         &self,
         namespace: &Namespace,
@@ -270,43 +850,514 @@ impl AsyncStorage {
         self.counters.get_counters(&limits).await
     }
 
+    /// Up to `page_size` of the counters of the `namespace` that match the
+    /// `filter`, resuming from the `cursor` the previous page returned
+    pub async fn get_counters_page(
+        &self,
+        namespace: &Namespace,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let limits: HashSet<Arc<Limit>> = self
+            .get_limits(namespace)
+            .into_iter()
+            .filter(|limit| filter.applies_to_limit(limit))
+            .collect();
+        if limits.is_empty() {
+            return Ok(CountersPage::default());
+        }
+        self.counters
+            .get_counters_page(&limits, filter, cursor, page_size.max(1))
+            .await
+    }
+
+    /// Resets the `counter`, e.g. for a given user, as if it was never hit
+    pub async fn reset_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.counters.delete_counter(counter).await
+    }
+
+    /// Resets all the counters of the `limit`, keeping the limit itself
+    pub async fn reset_counters_of_limit(&self, limit: &Limit) -> Result<(), StorageErr> {
+        let arc = match self.limits.read().unwrap().get(limit.namespace()) {
+            None => Arc::new(limit.clone()),
+            Some(limits) => limits
+                .iter()
+                .find(|l| ***l == *limit)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(limit.clone())),
+        };
+        let mut limits = HashSet::new();
+        limits.insert(arc);
+        self.counters.delete_counters(&limits).await
+    }
+
+    /// Resets all the counters of the limits in the `namespace`, keeping the
+    /// limits themselves
+    pub async fn reset_counters(&self, namespace: &Namespace) -> Result<(), StorageErr> {
+        let limits = self.get_limits(namespace);
+        self.counters.delete_counters(&limits).await
+    }
+
     pub async fn clear(&self) -> Result<(), StorageErr> {
         self.limits.write().unwrap().clear();
+        self.patterns.write().unwrap().clear();
+        self.indexes.write().unwrap().clear();
+        self.expirations.write().unwrap().clear();
         self.counters.clear().await
     }
+
+    /// Writes the counter updates the storage holds back out
+    pub async fn flush(&self) -> Result<(), StorageErr> {
+        self.counters.flush().await
+    }
+
+    /// Checks that the counters can be reached
+    pub async fn ping(&self) -> Result<(), StorageErr> {
+        self.counters.ping().await
+    }
+}
+
+/// Loads each of the `counters` from its current value and TTL, as the request
+/// would leave it, returning the first one it would take over its limit
+fn peek_counters(
+    counters: &mut [Counter],
+    values_and_ttls: impl IntoIterator<Item = (u64, Duration)>,
+    delta: u64,
+) -> Authorization {
+    let mut first_limited = None;
+    for (counter, (value, ttl)) in counters.iter_mut().zip(values_and_ttls) {
+        let remaining = counter
+            .max_value()
+            .checked_sub(value.saturating_add(counter.delta(delta)));
+        counter.set_remaining(remaining.unwrap_or_default());
+        counter.set_expires_in(ttl);
+        if first_limited.is_none() && remaining.is_none() {
+            first_limited = Some(Authorization::limited_by(counter));
+        }
+    }
+    first_limited.unwrap_or(Authorization::Ok)
+}
+
+// The limit the `update` is of: the one with its id, if it has one, or else the
+// one without an id with its definition
+fn limit_to_update(
+    limits: &HashMap<Namespace, HashSet<Arc<Limit>>>,
+    update: &Limit,
+) -> Option<Arc<Limit>> {
+    match update.id() {
+        Some(id) => limits
+            .values()
+            .flatten()
+            .find(|limit| limit.id() == Some(id))
+            .cloned(),
+        None => limits
+            .get(update.namespace())?
+            .get(update)
+            .filter(|limit| limit.id().is_none())
+            .cloned(),
+    }
+}
+
+// Whether the `update` changes anything else than what identifies the `limit`
+fn requires_update(limit: &Limit, update: &Limit) -> bool {
+    limit.max_value() != update.max_value()
+        || limit.max_value_from() != update.max_value_from()
+        || limit.name() != update.name()
+        || limit.cost() != update.cost()
+        || limit.schedule() != update.schedule()
+        || limit.ttl() != update.ttl()
+        || limit.overrides() != update.overrides()
+        || limit.metadata() != update.metadata()
+}
+
+// Pages through the `counters` in a stable order, the cursor being the sort key
+// of the last counter of the previous page. Only the counters of the page get
+// sorted, which is what storages holding their counters in memory use
+fn paginate(
+    counters: HashSet<Counter>,
+    filter: &CounterFilter,
+    cursor: Option<&str>,
+    page_size: usize,
+) -> CountersPage {
+    let mut matching: Vec<(String, Counter)> = counters
+        .into_iter()
+        .filter(|counter| filter.matches(counter))
+        .map(|counter| {
+            let key = serde_json::to_string(&(counter.limit(), counter.set_variables()))
+                .expect("counters serialize");
+            (key, counter)
+        })
+        .filter(|(key, _)| match cursor {
+            Some(cursor) => key.as_str() > cursor,
+            None => true,
+        })
+        .collect();
+    let more = matching.len() > page_size;
+    if more {
+        matching.select_nth_unstable_by(page_size, |(a, _), (b, _)| a.cmp(b));
+        matching.truncate(page_size);
+    }
+    matching.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let next_cursor = if more {
+        matching.last().map(|(key, _)| key.clone())
+    } else {
+        None
+    };
+    CountersPage {
+        counters: matching.into_iter().map(|(_, counter)| counter).collect(),
+        next_cursor,
+    }
+}
+
+// The storages paging through their counters one limit, or namespace, at a
+// time use cursors made of the index of the one to resume from, and the
+// position within it
+#[cfg(any(
+    feature = "redis_storage",
+    feature = "sqlite_storage",
+    feature = "disk_storage",
+    feature = "etcd_storage"
+))]
+fn parse_cursor(cursor: Option<&str>) -> Result<(usize, &str), StorageErr> {
+    let Some(cursor) = cursor else {
+        return Ok((0, ""));
+    };
+    cursor
+        .split_once(':')
+        .and_then(|(index, position)| Some((index.parse().ok()?, position)))
+        .ok_or_else(|| invalid_cursor(cursor))
+}
+
+#[cfg(any(
+    feature = "redis_storage",
+    feature = "sqlite_storage",
+    feature = "disk_storage",
+    feature = "etcd_storage"
+))]
+fn invalid_cursor(cursor: &str) -> StorageErr {
+    StorageErr {
+        msg: format!("invalid cursor: {cursor}"),
+        source: None,
+        transient: false,
+    }
+}
+
+// The positions of the cursors of the storages scanning their counters in key
+// order are the last key of the previous page
+#[cfg(any(
+    feature = "sqlite_storage",
+    feature = "disk_storage",
+    feature = "etcd_storage"
+))]
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(any(
+    feature = "sqlite_storage",
+    feature = "disk_storage",
+    feature = "etcd_storage"
+))]
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn track_expiry(expirations: &mut HashMap<Arc<Limit>, SystemTime>, limit: &Arc<Limit>) {
+    expirations.remove(limit);
+    // A ttl past what can be told is as good as none
+    let expires_at = limit
+        .ttl()
+        .and_then(|ttl| clock::now().checked_add(Duration::from_secs(ttl)));
+    if let Some(expires_at) = expires_at {
+        expirations.insert(Arc::clone(limit), expires_at);
+    }
+}
+
+fn expired_limits(expirations: &HashMap<Arc<Limit>, SystemTime>) -> Vec<Arc<Limit>> {
+    let now = clock::now();
+    expirations
+        .iter()
+        .filter(|(_, expires_at)| **expires_at <= now)
+        .map(|(limit, _)| Arc::clone(limit))
+        .collect()
 }
 
 pub trait CounterStorage: Sync + Send {
     fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr>;
+    /// Whether `hits` more keep the counter of the `limit`, which has no
+    /// variables, within `max_value`, as `is_within_limits` does, but looked
+    /// up from the borrowed limit. `None` when the storage needs the counter.
+    fn is_limit_within(
+        &self,
+        _limit: &Limit,
+        _max_value: u64,
+        _hits: u64,
+    ) -> Option<Result<bool, StorageErr>> {
+        None
+    }
     fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr>;
     fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr>;
+    /// Gives `delta` back to the counter, which never goes below 0
+    fn refund_counter(&self, _counter: &Counter, _delta: u64) -> Result<(), StorageErr> {
+        Err(refunds_unsupported())
+    }
+    /// Records the idempotency `token` of a request to the `namespace` for
+    /// the next `horizon`, unless it is already: `false` then, as the request
+    /// is a retry. Storages that can't tell take every request as a new one.
+    fn claim_token(
+        &self,
+        _namespace: &Namespace,
+        _token: &str,
+        _horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+    /// Forgets about the `token`, e.g. of a request that got limited, for its
+    /// retries to be checked again
+    fn release_token(&self, _namespace: &Namespace, _token: &str) -> Result<(), StorageErr> {
+        Ok(())
+    }
     fn check_and_update(
         &self,
         counters: &mut Vec<Counter>,
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr>;
+    /// Checks the `counters` as `check_and_update` would, without updating
+    /// them. Storages that can tell load the counters' remaining and expiry.
+    fn peek(&self, counters: &mut [Counter], delta: u64) -> Result<Authorization, StorageErr> {
+        for counter in counters.iter() {
+            if !self.is_within_limits(counter, delta)? {
+                return Ok(Authorization::limited_by(counter));
+            }
+        }
+        Ok(Authorization::Ok)
+    }
     fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr>; // todo revise typing here?
+    /// Up to `page_size` of the counters of the `limits` that match the
+    /// `filter`, resuming from the `cursor` the previous page returned.
+    /// Storages scanning their counters in batches might return pages of
+    /// about `page_size` counters instead.
+    fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let counters = self.get_counters(limits)?;
+        Ok(paginate(counters, filter, cursor, page_size))
+    }
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr>;
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr>; // todo revise typing here?
     fn clear(&self) -> Result<(), StorageErr>;
+    /// Sends out the updates held back, e.g. the ones not replicated to the
+    /// other nodes yet, waiting for them to be. Meant for shutting down.
+    fn flush(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
+    /// Checks that the storage can be reached, e.g. for health checks. Only
+    /// storages relying on something remote can fail it.
+    fn ping(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
+}
+
+// Lets a storage be shared, e.g. to also hold onto it to snapshot it
+impl<S: CounterStorage + ?Sized> CounterStorage for Arc<S> {
+    fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        self.deref().is_within_limits(counter, delta)
+    }
+
+    fn is_limit_within(
+        &self,
+        limit: &Limit,
+        max_value: u64,
+        hits: u64,
+    ) -> Option<Result<bool, StorageErr>> {
+        self.deref().is_limit_within(limit, max_value, hits)
+    }
+
+    fn add_counter(&self, limit: &Limit) -> Result<(), StorageErr> {
+        self.deref().add_counter(limit)
+    }
+
+    fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.deref().update_counter(counter, delta)
+    }
+
+    fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.deref().refund_counter(counter, delta)
+    }
+
+    fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        self.deref().claim_token(namespace, token, horizon)
+    }
+
+    fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        self.deref().release_token(namespace, token)
+    }
+
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.deref()
+            .check_and_update(counters, delta, load_counters)
+    }
+
+    fn peek(&self, counters: &mut [Counter], delta: u64) -> Result<Authorization, StorageErr> {
+        self.deref().peek(counters, delta)
+    }
+
+    fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
+        self.deref().get_counters(limits)
+    }
+
+    fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        self.deref()
+            .get_counters_page(limits, filter, cursor, page_size)
+    }
+
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.deref().delete_counter(counter)
+    }
+
+    fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        self.deref().delete_counters(limits)
+    }
+
+    fn clear(&self) -> Result<(), StorageErr> {
+        self.deref().clear()
+    }
+
+    fn flush(&self) -> Result<(), StorageErr> {
+        self.deref().flush()
+    }
+
+    fn ping(&self) -> Result<(), StorageErr> {
+        self.deref().ping()
+    }
 }
 
 #[async_trait]
 pub trait AsyncCounterStorage: Sync + Send {
     async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr>;
+    /// Lets the storage know of a newly added limit, ahead of its counters
+    /// being used
+    fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
+        Ok(())
+    }
     async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr>;
+    /// Gives `delta` back to the counter, which never goes below 0
+    async fn refund_counter(&self, _counter: &Counter, _delta: u64) -> Result<(), StorageErr> {
+        Err(refunds_unsupported())
+    }
+    /// Records the idempotency `token` of a request to the `namespace` for
+    /// the next `horizon`, unless it is already: `false` then, as the request
+    /// is a retry. Storages that can't tell take every request as a new one.
+    async fn claim_token(
+        &self,
+        _namespace: &Namespace,
+        _token: &str,
+        _horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        Ok(true)
+    }
+    /// Forgets about the `token`, e.g. of a request that got limited, for its
+    /// retries to be checked again
+    async fn release_token(&self, _namespace: &Namespace, _token: &str) -> Result<(), StorageErr> {
+        Ok(())
+    }
     async fn check_and_update<'a>(
         &self,
         counters: &mut Vec<Counter>,
         delta: u64,
         load_counters: bool,
     ) -> Result<Authorization, StorageErr>;
+    /// Checks and updates each of the `batches` of counters by its delta, on
+    /// its own, as `check_and_update` would. Storages that can do it for all
+    /// of them in a single call, e.g. one round trip to Redis, override it.
+    async fn check_and_update_batch(
+        &self,
+        batches: &mut [(Vec<Counter>, u64)],
+        load_counters: bool,
+    ) -> Result<Vec<Authorization>, StorageErr> {
+        let mut res = Vec::with_capacity(batches.len());
+        for (counters, delta) in batches.iter_mut() {
+            res.push(
+                self.check_and_update(counters, *delta, load_counters)
+                    .await?,
+            );
+        }
+        Ok(res)
+    }
+    /// Checks the `counters` as `check_and_update` would, without updating
+    /// them. Storages that can tell load the counters' remaining and expiry.
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        for counter in counters.iter() {
+            if !self.is_within_limits(counter, delta).await? {
+                return Ok(Authorization::limited_by(counter));
+            }
+        }
+        Ok(Authorization::Ok)
+    }
     async fn get_counters(
         &self,
         limits: &HashSet<Arc<Limit>>,
     ) -> Result<HashSet<Counter>, StorageErr>;
+    /// Up to `page_size` of the counters of the `limits` that match the
+    /// `filter`, resuming from the `cursor` the previous page returned.
+    /// Storages scanning their counters in batches might return pages of
+    /// about `page_size` counters instead.
+    async fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let counters = self.get_counters(limits).await?;
+        Ok(paginate(counters, filter, cursor, page_size))
+    }
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr>;
     async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr>;
     async fn clear(&self) -> Result<(), StorageErr>;
+    /// Writes the updates held back, e.g. batched for Redis, out right away.
+    /// Meant for shutting down.
+    async fn flush(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
+    /// Checks that the storage can be reached, e.g. for health checks. Only
+    /// storages relying on something remote can fail it.
+    async fn ping(&self) -> Result<(), StorageErr> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -328,6 +1379,72 @@ impl Error for StorageErr {
     }
 }
 
+/// The id of a limit being added is already the one of another limit, whose
+/// counters it would share, as they are keyed by id alone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitIdConflict {
+    id: String,
+    namespace: Namespace,
+}
+
+impl LimitIdConflict {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The namespace of the limit already using the id
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+}
+
+impl Display for LimitIdConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "limit id `{}` is already used by another limit, of namespace `{}`",
+            self.id,
+            self.namespace.as_ref()
+        )
+    }
+}
+
+impl Error for LimitIdConflict {}
+
+/// Fails on the first limit whose id is the one of a previous, different,
+/// limit
+pub(crate) fn check_limit_ids<'a>(
+    limits: impl IntoIterator<Item = &'a Limit>,
+) -> Result<(), LimitIdConflict> {
+    let mut ids: HashMap<&str, &Limit> = HashMap::new();
+    for limit in limits {
+        let Some(id) = limit.id() else {
+            continue;
+        };
+        match ids.get(id) {
+            Some(other) if *other != limit => {
+                return Err(LimitIdConflict {
+                    id: id.to_string(),
+                    namespace: other.namespace().clone(),
+                })
+            }
+            Some(_) => {}
+            None => {
+                ids.insert(id, limit);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn refunds_unsupported() -> StorageErr {
+    StorageErr {
+        msg: "refunds are not supported by this storage".to_string(),
+        source: None,
+        transient: false,
+    }
+}
+
 impl StorageErr {
     pub fn msg(&self) -> &str {
         &self.msg
@@ -0,0 +1,548 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::atomic_expiring_value::AtomicExpiringValue;
+use crate::storage::commands::{unavailable, unexpected, Command, Outcome, Update};
+use crate::storage::keys::bin::{
+    key_for_counter, partial_counter_from_counter_key, prefix_for_namespace,
+};
+use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use async_trait::async_trait;
+use moka::sync::Cache;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::timeout;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status};
+use tracing::{error, warn};
+
+use ring::Ring;
+use v1::partition_client::PartitionClient;
+use v1::partition_server::{Partition, PartitionServer};
+use v1::{ExecuteRequest, ExecuteResponse};
+
+mod ring;
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+mod v1 {
+    tonic::include_proto!("limitador.service.partitioned.v1");
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Each counter is owned by a single member of the cluster, picked by
+// consistent hashing of its key, and only ever held by it. The others forward
+// the operations on the counter to its owner, so that it is as accurate as
+// if a single instance enforced the limit, while each member only holds its
+// share of the counters. A request hitting counters owned by different
+// members gets the hits taken back from the ones that admitted them, when
+// another didn't.
+//
+// The members are static: all of them must be configured with the same ones,
+// for them to agree on the owners. While a member is unreachable, the
+// operations on the counters it owns fail.
+pub struct PartitionedStorage {
+    ring: Ring,
+    owned: Arc<Owned>,
+    peers: HashMap<String, PartitionClient<Channel>>,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for PartitionedStorage {
+    #[tracing::instrument(skip_all)]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let mut counters = [counter.clone()];
+        let authorization = self
+            .update(&mut counters, delta, true, false, false)
+            .await?;
+        Ok(matches!(authorization, Authorization::Ok))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let mut counters = [counter.clone()];
+        self.update(&mut counters, delta, false, true, false)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let key = key_for_counter(counter);
+        let owner = self.ring.owner(&key);
+        self.execute(
+            owner,
+            Command::Refund {
+                refunds: vec![(key.clone(), counter.delta(delta))],
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.update(counters, delta, true, true, load_counters)
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        self.update(counters, delta, true, false, true).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        let namespaces: BTreeSet<&str> = limits.iter().map(|l| l.namespace().as_ref()).collect();
+        let prefixes: Vec<Vec<u8>> = namespaces.into_iter().map(prefix_for_namespace).collect();
+
+        let mut counters = HashSet::default();
+        for member in self.ring.members() {
+            let outcome = self
+                .execute(
+                    member,
+                    Command::Scan {
+                        prefixes: prefixes.clone(),
+                    },
+                )
+                .await?;
+            let Outcome::Counters(values) = outcome else {
+                return Err(unexpected(outcome));
+            };
+            for (key, value, ttl) in values {
+                let mut counter = partial_counter_from_counter_key(&key);
+                let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) else {
+                    continue;
+                };
+                counter.update_to_limit(Arc::clone(limit));
+                counter.set_remaining(counter.max_value().saturating_sub(value));
+                counter.set_expires_in(Duration::from_millis(ttl));
+                counters.insert(counter);
+            }
+        }
+        Ok(counters)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        let key = key_for_counter(counter);
+        let owner = self.ring.owner(&key);
+        self.execute(
+            owner,
+            Command::Delete {
+                keys: vec![key.clone()],
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let mut keys_by_owner: BTreeMap<&str, Vec<Vec<u8>>> = BTreeMap::new();
+        for counter in self.get_counters(limits).await? {
+            let key = key_for_counter(&counter);
+            keys_by_owner
+                .entry(self.ring.owner(&key))
+                .or_default()
+                .push(key);
+        }
+        for (owner, keys) in keys_by_owner {
+            self.execute(owner, Command::Delete { keys }).await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        for member in self.ring.members() {
+            self.execute(member, Command::Clear).await?;
+        }
+        Ok(())
+    }
+}
+
+impl PartitionedStorage {
+    /// Joins the `peers`, as pairs of their name and url, e.g.
+    /// `("b", "http://limitador-b:15003")`, listening on `listen_address` for
+    /// them, and holding up to `cache_size` of the counters it owns
+    pub fn new(
+        name: String,
+        listen_address: &str,
+        peers: Vec<(String, String)>,
+        cache_size: u64,
+    ) -> Result<Self, StorageErr> {
+        let listen_address = listen_address.parse().map_err(|e| StorageErr {
+            msg: format!("invalid listen address '{listen_address}': {e}"),
+            source: None,
+            transient: false,
+        })?;
+
+        let mut clients = HashMap::new();
+        for (peer, url) in peers {
+            let channel = Endpoint::from_shared(url.clone())
+                .map_err(|e| StorageErr {
+                    msg: format!("invalid url for '{peer}', '{url}': {e}"),
+                    source: None,
+                    transient: false,
+                })?
+                .connect_timeout(CONNECT_TIMEOUT)
+                .connect_lazy();
+            clients.insert(peer, PartitionClient::new(channel));
+        }
+        let ring = Ring::new(clients.keys().cloned().chain([name]).collect());
+
+        let owned = Arc::new(Owned {
+            counters: Cache::new(cache_size),
+            updates: Mutex::new(()),
+        });
+        let service = PartitionServer::new(Service(Arc::clone(&owned)));
+        tokio::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(listen_address)
+                .await
+            {
+                error!(
+                    "partitioned storage failed to listen on {}: {}",
+                    listen_address, e
+                );
+            }
+        });
+
+        Ok(Self {
+            ring,
+            owned,
+            peers: clients,
+        })
+    }
+
+    async fn update(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+        check: bool,
+        apply: bool,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let updates: Vec<Update> = counters
+            .iter()
+            .map(|counter| Update {
+                key: key_for_counter(counter),
+                max_value: counter.max_value(),
+                window: counter.window().as_millis() as u64,
+                delta: counter.delta(delta),
+            })
+            .collect();
+        let mut by_owner: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, update) in updates.iter().enumerate() {
+            by_owner
+                .entry(self.ring.owner(&update.key))
+                .or_default()
+                .push(i);
+        }
+
+        let mut limited = None;
+        let mut applied = Vec::new();
+        for (owner, indices) in by_owner {
+            if limited.is_some() && !load_counters {
+                break;
+            }
+            // Once limited, the other counters are only loaded
+            let apply = apply && limited.is_none();
+            let command = Command::Update {
+                updates: indices.iter().map(|&i| updates[i].clone()).collect(),
+                check,
+                apply,
+            };
+            let outcome = match self.execute(owner, command).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    self.take_back(applied, &updates).await;
+                    return Err(e);
+                }
+            };
+            let Outcome::Updated {
+                limited: limited_in_group,
+                values,
+            } = outcome
+            else {
+                self.take_back(applied, &updates).await;
+                return Err(unexpected(outcome));
+            };
+
+            if load_counters {
+                for (&i, (value, ttl)) in indices.iter().zip(values) {
+                    let counter = &mut counters[i];
+                    counter.set_remaining(
+                        counter
+                            .max_value()
+                            .checked_sub(value.saturating_add(updates[i].delta))
+                            .unwrap_or_default(),
+                    );
+                    counter.set_expires_in(Duration::from_millis(ttl));
+                }
+            }
+            match limited_in_group {
+                Some(i) => limited = limited.or(Some(indices[i])),
+                None if apply => applied.push((owner, indices)),
+                None => {}
+            }
+        }
+
+        match limited {
+            Some(i) => {
+                self.take_back(applied, &updates).await;
+                Ok(Authorization::limited_by(&counters[i]))
+            }
+            None => Ok(Authorization::Ok),
+        }
+    }
+
+    // Refunds the hits the owners of `applied` admitted, when another owner
+    // didn't, or couldn't be reached
+    async fn take_back(&self, applied: Vec<(&str, Vec<usize>)>, updates: &[Update]) {
+        for (owner, indices) in applied {
+            let refunds = indices
+                .iter()
+                .map(|&i| (updates[i].key.clone(), updates[i].delta))
+                .collect();
+            if let Err(e) = self.execute(owner, Command::Refund { refunds }).await {
+                warn!("couldn't take the hits back from '{}': {}", owner, e);
+            }
+        }
+    }
+
+    async fn execute(&self, owner: &str, command: Command) -> Result<Outcome, StorageErr> {
+        let Some(client) = self.peers.get(owner) else {
+            return Ok(self.owned.execute(command));
+        };
+        let command = postcard::to_stdvec(&command).expect("commands can be serialized");
+        let response = timeout(
+            REQUEST_TIMEOUT,
+            client.clone().execute(ExecuteRequest { command }),
+        )
+        .await
+        .map_err(|_| unavailable(format!("timed out waiting for '{owner}'")))?
+        .map_err(|status| unavailable(format!("'{owner}' failed: {}", status.message())))?;
+        postcard::from_bytes(&response.into_inner().outcome).map_err(|e| StorageErr {
+            msg: format!("couldn't decode the outcome from '{owner}': {e}"),
+            source: None,
+            transient: false,
+        })
+    }
+}
+
+/// The counters owned by this member
+struct Owned {
+    counters: Cache<Vec<u8>, Arc<AtomicExpiringValue>>,
+    // Serializes the checks and updates, for concurrent ones not to admit more
+    // than the limits allow
+    updates: Mutex<()>,
+}
+
+impl Owned {
+    fn execute(&self, command: Command) -> Outcome {
+        let now = SystemTime::now();
+        match command {
+            Command::Noop => Outcome::Done,
+            Command::Update {
+                updates,
+                check,
+                apply,
+            } => {
+                let _serialized = self.updates.lock().unwrap();
+                let values: Vec<Arc<AtomicExpiringValue>> = updates
+                    .iter()
+                    .map(|update| {
+                        self.counters.get_with_by_ref(&update.key, || {
+                            let window = Duration::from_millis(update.window);
+                            Arc::new(AtomicExpiringValue::new(0, now + window))
+                        })
+                    })
+                    .collect();
+                let loaded: Vec<(u64, u64)> = updates
+                    .iter()
+                    .zip(&values)
+                    .map(|(update, value)| match value.ttl() {
+                        ttl if ttl.is_zero() => (0, update.window),
+                        ttl => (value.value_at(now), ttl.as_millis() as u64),
+                    })
+                    .collect();
+                let limited = match check {
+                    true => updates
+                        .iter()
+                        .zip(&loaded)
+                        .position(|(update, (value, _))| {
+                            value.saturating_add(update.delta) > update.max_value
+                        }),
+                    false => None,
+                };
+                if apply && limited.is_none() {
+                    for (update, value) in updates.iter().zip(&values) {
+                        value.update(update.delta, Duration::from_millis(update.window), now);
+                    }
+                }
+                Outcome::Updated {
+                    limited,
+                    values: loaded,
+                }
+            }
+            Command::Refund { refunds } => {
+                for (key, delta) in refunds {
+                    if let Some(value) = self.counters.get(&key) {
+                        value.refund(delta, now);
+                    }
+                }
+                Outcome::Done
+            }
+            Command::Scan { prefixes } => Outcome::Counters(
+                self.counters
+                    .iter()
+                    .filter(|(key, _)| prefixes.iter().any(|prefix| key.starts_with(prefix)))
+                    .filter_map(|(key, value)| {
+                        let ttl = value.ttl();
+                        (!ttl.is_zero())
+                            .then(|| (key.to_vec(), value.value_at(now), ttl.as_millis() as u64))
+                    })
+                    .collect(),
+            ),
+            Command::Delete { keys } => {
+                for key in keys {
+                    self.counters.invalidate(&key);
+                }
+                Outcome::Done
+            }
+            Command::Clear => {
+                self.counters.invalidate_all();
+                Outcome::Done
+            }
+        }
+    }
+}
+
+struct Service(Arc<Owned>);
+
+#[tonic::async_trait]
+impl Partition for Service {
+    async fn execute(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        let command = postcard::from_bytes(&request.into_inner().command)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let outcome = postcard::to_stdvec(&self.0.execute(command))
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(ExecuteResponse { outcome }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartitionedStorage;
+    use crate::counter::Counter;
+    use crate::limit::{Context, Limit};
+    use crate::storage::commands::Command;
+    use crate::storage::keys::bin::key_for_counter;
+    use crate::storage::{AsyncCounterStorage, Authorization};
+    use std::collections::{HashMap, HashSet};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn free_address() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn counter(limit: &Arc<Limit>, user: &str) -> Counter {
+        let ctx: Context = HashMap::from([("user".to_string(), user.to_string())]).into();
+        Counter::new(Arc::clone(limit), &ctx).unwrap().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn gives_the_hits_back_when_the_owner_of_another_counter_limits() {
+        let addresses = [("a", free_address()), ("b", free_address())];
+        let storages: Vec<PartitionedStorage> = addresses
+            .iter()
+            .map(|(name, address)| {
+                let peers = addresses
+                    .iter()
+                    .filter(|(peer, _)| peer != name)
+                    .map(|(peer, address)| (peer.to_string(), format!("http://{address}")))
+                    .collect();
+                PartitionedStorage::new(name.to_string(), &address.to_string(), peers, 100).unwrap()
+            })
+            .collect();
+        let storage = &storages[0];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while storage.execute("b", Command::Noop).await.is_err() {
+            assert!(Instant::now() < deadline, "'b' never came up");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let loose = Arc::new(Limit::new(
+            "ns",
+            10,
+            60,
+            vec![],
+            vec!["user".try_into().unwrap()],
+        ));
+        let tight = Arc::new(Limit::new(
+            "ns",
+            1,
+            120,
+            vec![],
+            vec!["user".try_into().unwrap()],
+        ));
+        // The counters "a" admits first, before "b" limits
+        let owner = |counter: &Counter| storage.ring.owner(&key_for_counter(counter)).to_string();
+        let user = (0..)
+            .map(|i| format!("user-{i}"))
+            .find(|user| {
+                owner(&counter(&loose, user)) == "a" && owner(&counter(&tight, user)) == "b"
+            })
+            .unwrap();
+        let mut counters = vec![counter(&loose, &user), counter(&tight, &user)];
+
+        let authorization = storage
+            .check_and_update(&mut counters, 1, false)
+            .await
+            .unwrap();
+        assert!(matches!(authorization, Authorization::Ok));
+        let authorization = storage
+            .check_and_update(&mut counters, 1, false)
+            .await
+            .unwrap();
+        assert!(matches!(authorization, Authorization::Limited(_)));
+
+        let remaining: HashMap<u64, Option<u64>> = storage
+            .get_counters(&HashSet::from([Arc::clone(&loose), Arc::clone(&tight)]))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|counter| (counter.max_value(), counter.remaining()))
+            .collect();
+        assert_eq!(
+            remaining,
+            HashMap::from([(10, Some(9)), (1, Some(0))]),
+            "'a' should have given the second hit back"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::storage::keys::stable_hash;
+
+// The points each member gets on the ring, for the counters to spread evenly
+// across them
+const VIRTUAL_NODES: usize = 128;
+
+/// Consistent hashing of the counters' keys to the members owning them: each
+/// member owns the keys hashed past one of its points, up to the next point.
+/// Members joining or leaving only move the keys of the points they add or
+/// remove.
+pub(super) struct Ring {
+    members: Vec<String>,
+    // Sorted by position, along with the index of the member they belong to
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    pub fn new(mut members: Vec<String>) -> Self {
+        // All members must agree on the ring, whatever the order they got
+        // configured in
+        members.sort();
+        members.dedup();
+        let mut points: Vec<(u64, usize)> = members
+            .iter()
+            .enumerate()
+            .flat_map(|(i, member)| {
+                (0..VIRTUAL_NODES).map(move |point| (position(format!("{member}#{point}")), i))
+            })
+            .collect();
+        points.sort();
+        Self { members, points }
+    }
+
+    pub fn owner(&self, key: &[u8]) -> &str {
+        let position = position(key);
+        let point = self.points.partition_point(|(p, _)| *p < position);
+        let (_, member) = self.points[point % self.points.len()];
+        &self.members[member]
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+}
+
+// FNV-1a is stable across versions and instances, but similar keys, e.g. only
+// differing in the value of a variable, hash close to one another. Mixing the
+// bits spreads them across the whole ring.
+fn position(bytes: impl AsRef<[u8]>) -> u64 {
+    let mut hash = stable_hash(bytes.as_ref());
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+    use std::collections::HashMap;
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn spreads_keys_evenly_and_only_moves_the_ones_of_leaving_members() {
+        let keys: Vec<Vec<u8>> = (0..10_000)
+            .map(|i| format!("user-{i}").into_bytes())
+            .collect();
+        let ring = Ring::new(members(&["c", "a", "b"]));
+
+        let mut owned: HashMap<&str, usize> = HashMap::new();
+        for key in &keys {
+            *owned.entry(ring.owner(key)).or_default() += 1;
+        }
+        assert_eq!(owned.len(), 3);
+        for count in owned.values() {
+            assert!(*count > 2_000 && *count < 4_700, "unbalanced: {owned:?}");
+        }
+
+        let smaller = Ring::new(members(&["a", "b"]));
+        for key in &keys {
+            if ring.owner(key) != "c" {
+                assert_eq!(ring.owner(key), smaller.owner(key));
+            }
+        }
+        // The order the members are configured in doesn't matter
+        let reordered = Ring::new(members(&["b", "c", "a"]));
+        assert!(keys
+            .iter()
+            .all(|key| ring.owner(key) == reordered.owner(key)));
+    }
+
+    #[test]
+    fn joining_members_only_take_their_share_of_the_keys_over() {
+        let keys: Vec<Vec<u8>> = (0..10_000)
+            .map(|i| format!("user-{i}").into_bytes())
+            .collect();
+        let ring = Ring::new(members(&["a", "b"]));
+        let larger = Ring::new(members(&["a", "b", "c"]));
+
+        let moved = keys
+            .iter()
+            .filter(|key| ring.owner(key) != larger.owner(key))
+            .inspect(|key| assert_eq!(larger.owner(key), "c"))
+            .count();
+        assert!(moved > 2_000 && moved < 4_700, "unbalanced: {moved} moved");
+        assert_eq!(larger.members(), members(&["a", "b", "c"]));
+    }
+}
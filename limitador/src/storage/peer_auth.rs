@@ -0,0 +1,129 @@
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Status};
+
+// The metadata peers pass their token in
+const AUTHORIZATION: &str = "authorization";
+
+/// How peers authenticate one another: with the certificates of `tls`, with a
+/// shared `token`, or with either when both are set, the token then being the
+/// fallback for peers not presenting a certificate. Peers accept anyone
+/// otherwise.
+#[derive(Clone, Default)]
+pub struct PeerAuth {
+    pub tls: Option<PeerTls>,
+    pub token: Option<String>,
+}
+
+/// The PEM encoded certificate, and key, a peer presents to the others, which
+/// it verifies theirs against the certificate authority `ca` of. Peers then
+/// need to be reached over `https` urls.
+#[derive(Clone)]
+pub struct PeerTls {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    pub ca: Vec<u8>,
+    /// The name the certificates of the peers are for, when not the host of
+    /// their urls
+    pub domain: Option<String>,
+}
+
+impl PeerAuth {
+    // Invalid certificates only surface when used, so better try them out of
+    // the gate
+    pub(crate) fn validate(&self) -> Result<(), tonic::transport::Error> {
+        if let Some(tls) = self.server_tls() {
+            Server::builder().tls_config(tls)?;
+        }
+        if let Some(tls) = self.client_tls() {
+            Endpoint::from_static("https://localhost").tls_config(tls)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn server_tls(&self) -> Option<ServerTlsConfig> {
+        self.tls.as_ref().map(|tls| {
+            ServerTlsConfig::new()
+                .identity(Identity::from_pem(&tls.cert, &tls.key))
+                .client_ca_root(Certificate::from_pem(&tls.ca))
+                // the ones without a certificate then have to pass the token
+                .client_auth_optional(self.token.is_some())
+        })
+    }
+
+    pub(crate) fn client_tls(&self) -> Option<ClientTlsConfig> {
+        self.tls.as_ref().map(|tls| {
+            let config = ClientTlsConfig::new()
+                .identity(Identity::from_pem(&tls.cert, &tls.key))
+                .ca_certificate(Certificate::from_pem(&tls.ca));
+            match &tls.domain {
+                Some(domain) => config.domain_name(domain),
+                None => config,
+            }
+        })
+    }
+
+    pub(crate) fn authenticate<T>(&self, request: &mut Request<T>) -> Result<(), Status> {
+        if let Some(token) = &self.token {
+            let value = MetadataValue::try_from(format!("Bearer {token}"))
+                .map_err(|_| Status::invalid_argument("invalid token"))?;
+            request.metadata_mut().insert(AUTHORIZATION, value);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        if self.tls.is_none() && self.token.is_none() {
+            return Ok(());
+        }
+        // the TLS handshake already verified the certificate against our CA
+        let certified = request.peer_certs().is_some_and(|certs| !certs.is_empty());
+        let passed_token = self.token.as_ref().is_some_and(|token| {
+            request
+                .metadata()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes()))
+        });
+        if certified || passed_token {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("peer failed to authenticate"))
+        }
+    }
+}
+
+// Doesn't give away how much of the token got guessed right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerAuth;
+    use tonic::Request;
+
+    #[test]
+    fn authorizes_the_peers_passing_the_token() {
+        let auth = |token: Option<&str>| PeerAuth {
+            tls: None,
+            token: token.map(str::to_string),
+        };
+        let request = |token: Option<&str>| {
+            let mut request = Request::new(());
+            auth(token).authenticate(&mut request).unwrap();
+            request
+        };
+
+        assert!(auth(Some("s3cr3t"))
+            .authorize(&request(Some("s3cr3t")))
+            .is_ok());
+        assert!(auth(Some("s3cr3t"))
+            .authorize(&request(Some("s3cr3")))
+            .is_err());
+        assert!(auth(Some("s3cr3t")).authorize(&request(None)).is_err());
+        // without any authentication, anyone gets in
+        assert!(auth(None).authorize(&request(None)).is_ok());
+    }
+}
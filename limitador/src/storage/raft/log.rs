@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write as _};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tokio::sync::{mpsc, watch};
+use tracing::error;
+
+const STATE_FILE: &str = "state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+
+/// What a replica must never forget of the elections, lest it votes twice in
+/// the same term
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Entry {
+    pub term: u64,
+    pub command: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Snapshot {
+    index: u64,
+    term: u64,
+    counters: Vec<u8>,
+}
+
+// Each entry is written along with its index, so that entries already
+// compacted into the snapshot get skipped when the log couldn't be rewritten
+#[derive(Serialize, Deserialize)]
+struct Record {
+    index: u64,
+    entry: Entry,
+}
+
+// What has to be persisted, in the order it got written to the log in memory
+enum Write {
+    State(HardState),
+    Append {
+        index: u64,
+        entries: Vec<Entry>,
+    },
+    // The log file replaced with the entries past the snapshot, along with the
+    // snapshot itself when it changed
+    Rewrite {
+        snapshot: Option<Snapshot>,
+        snapshot_index: u64,
+        entries: Vec<Entry>,
+    },
+}
+
+/// The sequence number of the last write persisted, or why they no longer
+/// get persisted
+pub(super) type Persisted = watch::Receiver<Result<u64, String>>;
+
+/// The entries of the replicated log, held in memory, and persisted to `dir`
+/// by a thread of its own, so that the disk is never waited on while holding
+/// the state of the replica. Each write returns its sequence number, to wait
+/// on with [`persisted`] before acknowledging it. The entries applied long
+/// enough ago get compacted into a snapshot of the counters they resulted in.
+pub(super) struct Log {
+    // The index and term of the last entry compacted into the snapshot
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot: Vec<u8>,
+    // `entries[i]` is the one at `snapshot_index + 1 + i`
+    entries: Vec<Entry>,
+    writes: mpsc::UnboundedSender<(u64, Write)>,
+    written: u64,
+    persisted: Persisted,
+}
+
+impl Log {
+    /// Loads what got persisted to `dir`, if anything, along with the last
+    /// snapshot of the counters
+    pub fn open(dir: &Path) -> io::Result<(Self, HardState)> {
+        fs::create_dir_all(dir)?;
+        let hard_state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(bytes) => postcard::from_bytes(&bytes).map_err(corrupted)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e),
+        };
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => postcard::from_bytes(&bytes).map_err(corrupted)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Snapshot {
+                index: 0,
+                term: 0,
+                counters: Vec::new(),
+            },
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        match File::open(dir.join(LOG_FILE)) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                while let Some(record) = read_record(&mut reader)? {
+                    if record.index <= snapshot.index {
+                        continue;
+                    }
+                    // A later record overrides the ones it got truncated from
+                    let position = (record.index - snapshot.index - 1) as usize;
+                    if position > entries.len() {
+                        return Err(corrupted("missing entries"));
+                    }
+                    entries.truncate(position);
+                    entries.push(record.entry);
+                }
+            }
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+
+        // Drops whatever got written past the last complete record
+        let file = write_log(dir, snapshot.index, &entries)?;
+        let (writes, pending) = mpsc::unbounded_channel();
+        let (done, persisted) = watch::channel(Ok(0));
+        let dir = dir.to_path_buf();
+        thread::Builder::new()
+            .name("raft-log".to_string())
+            .spawn(move || persist(&dir, file, pending, done))?;
+
+        let log = Self {
+            snapshot_index: snapshot.index,
+            snapshot_term: snapshot.term,
+            snapshot: snapshot.counters,
+            entries,
+            writes,
+            written: 0,
+            persisted,
+        };
+        Ok((log, hard_state))
+    }
+
+    pub fn save(&mut self, hard_state: &HardState) -> u64 {
+        self.write(Write::State(hard_state.clone()))
+    }
+
+    /// The sequence number of the last write
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The sequence number of the last write persisted, none when they no
+    /// longer get persisted
+    pub fn persisted_seq(&self) -> Option<u64> {
+        self.persisted.borrow().as_ref().ok().copied()
+    }
+
+    pub fn persisted(&self) -> Persisted {
+        self.persisted.clone()
+    }
+
+    pub fn snapshot(&self) -> (u64, u64, &[u8]) {
+        (self.snapshot_index, self.snapshot_term, &self.snapshot)
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map(|entry| entry.term)
+            .unwrap_or(self.snapshot_term)
+    }
+
+    /// The term of the entry at `index`, unless it got compacted, or isn't
+    /// there yet
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    /// Up to `max` of the entries from `index` on, that didn't get compacted
+    pub fn entries_from(&self, index: u64, max: usize) -> &[Entry] {
+        let start = index.saturating_sub(self.snapshot_index + 1) as usize;
+        let start = start.min(self.entries.len());
+        let end = (start + max).min(self.entries.len());
+        &self.entries[start..end]
+    }
+
+    pub fn append(&mut self, entries: Vec<Entry>) -> u64 {
+        let index = self.last_index() + 1;
+        self.entries.extend_from_slice(&entries);
+        self.write(Write::Append { index, entries })
+    }
+
+    /// Drops the entries from `index` on, conflicting with the leader's
+    pub fn truncate(&mut self, index: u64) -> u64 {
+        self.entries
+            .truncate(index.saturating_sub(self.snapshot_index + 1) as usize);
+        self.rewrite(None)
+    }
+
+    /// Replaces the entries up to `index` with the `counters` they resulted in
+    pub fn compact(&mut self, index: u64, counters: Vec<u8>) -> u64 {
+        let term = self.term_at(index).unwrap_or(self.snapshot_term);
+        let compacted = (index - self.snapshot_index) as usize;
+        self.entries.drain(..compacted.min(self.entries.len()));
+        let snapshot = self.set_snapshot(index, term, counters);
+        self.rewrite(Some(snapshot))
+    }
+
+    /// Takes the snapshot the leader sent over, keeping the entries past it
+    /// only if they agree with it
+    pub fn install(&mut self, index: u64, term: u64, counters: Vec<u8>) -> u64 {
+        self.entries = if self.term_at(index) == Some(term) {
+            self.entries
+                .split_off((index - self.snapshot_index) as usize)
+        } else {
+            Vec::new()
+        };
+        let snapshot = self.set_snapshot(index, term, counters);
+        self.rewrite(Some(snapshot))
+    }
+
+    fn set_snapshot(&mut self, index: u64, term: u64, counters: Vec<u8>) -> Snapshot {
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot = counters;
+        Snapshot {
+            index,
+            term,
+            counters: self.snapshot.clone(),
+        }
+    }
+
+    fn rewrite(&mut self, snapshot: Option<Snapshot>) -> u64 {
+        self.write(Write::Rewrite {
+            snapshot,
+            snapshot_index: self.snapshot_index,
+            entries: self.entries.clone(),
+        })
+    }
+
+    // Once the writer stopped, on failing to persist, what's written never
+    // gets persisted, which `persisted` tells
+    fn write(&mut self, write: Write) -> u64 {
+        self.written += 1;
+        let _ = self.writes.send((self.written, write));
+        self.written
+    }
+}
+
+/// Waits for the write of sequence number `seq` to be persisted
+pub(super) async fn persisted(mut persisted: Persisted, seq: u64) -> io::Result<()> {
+    let persisted = persisted
+        .wait_for(|persisted| match persisted {
+            Ok(done) => *done >= seq,
+            Err(_) => true,
+        })
+        .await
+        .map_err(|_| io::Error::other("the raft log got closed"))?;
+    match &*persisted {
+        Ok(_) => Ok(()),
+        Err(e) => Err(io::Error::other(e.clone())),
+    }
+}
+
+// Persists the writes in order, stopping at the first that fails, as none of
+// the ones after it could be relied on
+fn persist(
+    dir: &Path,
+    mut file: File,
+    mut writes: mpsc::UnboundedReceiver<(u64, Write)>,
+    done: watch::Sender<Result<u64, String>>,
+) {
+    while let Some((seq, write)) = writes.blocking_recv() {
+        if let Err(e) = persist_one(dir, &mut file, write) {
+            error!("couldn't persist the raft log: {}", e);
+            done.send_replace(Err(e.to_string()));
+            return;
+        }
+        done.send_replace(Ok(seq));
+    }
+}
+
+fn persist_one(dir: &Path, file: &mut File, write: Write) -> io::Result<()> {
+    match write {
+        Write::State(hard_state) => {
+            let bytes = postcard::to_stdvec(&hard_state).map_err(corrupted)?;
+            write_atomically(&dir.join(STATE_FILE), &bytes)
+        }
+        Write::Append { index, entries } => {
+            let mut writer = BufWriter::new(&*file);
+            for (i, entry) in entries.iter().enumerate() {
+                write_record(&mut writer, index + i as u64, entry)?;
+            }
+            writer.flush()?;
+            drop(writer);
+            file.sync_data()
+        }
+        Write::Rewrite {
+            snapshot,
+            snapshot_index,
+            entries,
+        } => {
+            if let Some(snapshot) = snapshot {
+                let bytes = postcard::to_stdvec(&snapshot).map_err(corrupted)?;
+                write_atomically(&dir.join(SNAPSHOT_FILE), &bytes)?;
+            }
+            *file = write_log(dir, snapshot_index, &entries)?;
+            Ok(())
+        }
+    }
+}
+
+// Replaces the log file with one holding the `entries` past `snapshot_index`,
+// opened to append the next ones to
+fn write_log(dir: &Path, snapshot_index: u64, entries: &[Entry]) -> io::Result<File> {
+    let path = dir.join(LOG_FILE);
+    let tmp = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for (i, entry) in entries.iter().enumerate() {
+            write_record(&mut writer, snapshot_index + 1 + i as u64, entry)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+    }
+    fs::rename(&tmp, &path)?;
+    OpenOptions::new().append(true).open(&path)
+}
+
+fn write_record(writer: &mut impl io::Write, index: u64, entry: &Entry) -> io::Result<()> {
+    let bytes = postcard::to_stdvec(&Record {
+        index,
+        entry: entry.clone(),
+    })
+    .map_err(corrupted)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)
+}
+
+// None once past the last complete record
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Record>> {
+    let mut len = [0; 4];
+    if let Err(e) = reader.read_exact(&mut len) {
+        return match e.kind() {
+            ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        };
+    }
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    if let Err(e) = reader.read_exact(&mut bytes) {
+        return match e.kind() {
+            ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        };
+    }
+    postcard::from_bytes(&bytes).map(Some).map_err(corrupted)
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+fn corrupted(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{persisted, Entry, HardState, Log};
+
+    fn entry(term: u64) -> Entry {
+        Entry {
+            term,
+            command: vec![term as u8],
+        }
+    }
+
+    #[tokio::test]
+    async fn reloads_what_got_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let (mut log, hard_state) = Log::open(dir.path()).unwrap();
+            assert_eq!(hard_state, HardState::default());
+            log.save(&HardState {
+                term: 2,
+                voted_for: Some("a".to_string()),
+            });
+            log.append(vec![entry(1), entry(1), entry(2)]);
+            log.truncate(3);
+            log.append(vec![entry(2), entry(2)]);
+            let written = log.compact(2, vec![42]);
+            persisted(log.persisted(), written).await.unwrap();
+        }
+
+        let (log, hard_state) = Log::open(dir.path()).unwrap();
+        assert_eq!(hard_state.term, 2);
+        assert_eq!(hard_state.voted_for.as_deref(), Some("a"));
+        assert_eq!(log.snapshot(), (2, 1, &[42][..]));
+        assert_eq!(log.last_index(), 4);
+        assert_eq!(log.term_at(2), Some(1));
+        assert_eq!(log.entry(2), None);
+        assert_eq!(log.entries_from(3, 10), &[entry(2), entry(2)]);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::commands::{unexpected, Command, Outcome, Update};
+use crate::storage::keys::bin::{
+    key_for_counter, partial_counter_from_counter_key, prefix_for_namespace,
+};
+use crate::storage::peer_auth::PeerAuth;
+use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod log;
+mod node;
+
+use node::Node;
+
+// Counters are replicated with Raft: every operation, reads included, is a
+// command appended to the log the replicas agree on, and only answered once a
+// majority of them persisted it, and the leader applied it. Updates are then
+// linearizable, never admitting more hits than the limits allow, even when
+// replicas fail, at the cost of a round trip to a majority of them each.
+pub struct RaftStorage {
+    node: Arc<Node>,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for RaftStorage {
+    #[tracing::instrument(skip_all)]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let mut counters = [counter.clone()];
+        let authorization = self
+            .update(&mut counters, delta, true, false, false)
+            .await?;
+        Ok(matches!(authorization, Authorization::Ok))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let mut counters = [counter.clone()];
+        self.update(&mut counters, delta, false, true, false)
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.node
+            .propose(Command::Refund {
+                refunds: vec![(key_for_counter(counter), counter.delta(delta))],
+            })
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.update(counters, delta, true, true, load_counters)
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        self.update(counters, delta, true, false, true).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        let namespaces: BTreeSet<&str> = limits.iter().map(|l| l.namespace().as_ref()).collect();
+        let outcome = self
+            .node
+            .propose(Command::Scan {
+                prefixes: namespaces.into_iter().map(prefix_for_namespace).collect(),
+            })
+            .await?;
+        let Outcome::Counters(values) = outcome else {
+            return Err(unexpected(outcome));
+        };
+
+        let mut counters = HashSet::default();
+        for (key, value, expires_at) in values {
+            let mut counter = partial_counter_from_counter_key(&key);
+            let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) else {
+                continue;
+            };
+            counter.update_to_limit(Arc::clone(limit));
+            counter.set_remaining(counter.max_value().saturating_sub(value));
+            counter.set_expires_in(expires_in(expires_at));
+            counters.insert(counter);
+        }
+        Ok(counters)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.node
+            .propose(Command::Delete {
+                keys: vec![key_for_counter(counter)],
+            })
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let keys = self
+            .get_counters(limits)
+            .await?
+            .iter()
+            .map(key_for_counter)
+            .collect();
+        self.node.propose(Command::Delete { keys }).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.node.propose(Command::Clear).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn ping(&self) -> Result<(), StorageErr> {
+        // Only answered once a majority of the replicas got it
+        self.node.propose(Command::Noop).await?;
+        Ok(())
+    }
+}
+
+impl RaftStorage {
+    /// Joins the replicas of the `peers`, as pairs of their id and url, e.g.
+    /// `("b", "http://limitador-b:15002")`, listening on `listen_address` for
+    /// them, its log persisted to `dir`
+    pub fn new<P: AsRef<Path>>(
+        id: String,
+        listen_address: &str,
+        peers: Vec<(String, String)>,
+        dir: P,
+    ) -> Result<Self, StorageErr> {
+        Self::with_peer_auth(id, listen_address, peers, dir, PeerAuth::default())
+    }
+
+    /// Same as [`RaftStorage::new`], the replicas authenticating one another
+    /// as per `auth`, which any of them reaching the others has to pass
+    pub fn with_peer_auth<P: AsRef<Path>>(
+        id: String,
+        listen_address: &str,
+        peers: Vec<(String, String)>,
+        dir: P,
+        auth: PeerAuth,
+    ) -> Result<Self, StorageErr> {
+        auth.validate().map_err(|e| StorageErr {
+            msg: format!("invalid replication TLS configuration: {e}"),
+            source: None,
+            transient: false,
+        })?;
+        let listen_address = listen_address.parse().map_err(|e| StorageErr {
+            msg: format!("invalid listen address '{listen_address}': {e}"),
+            source: None,
+            transient: false,
+        })?;
+        let node = Node::new(id, peers, dir.as_ref(), auth).map_err(|e| StorageErr {
+            msg: format!("couldn't load the raft log: {e}"),
+            source: Some(Box::new(e)),
+            transient: false,
+        })?;
+        Arc::clone(&node).start(listen_address);
+        Ok(Self { node })
+    }
+
+    async fn update(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+        check: bool,
+        apply: bool,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let updates = counters
+            .iter()
+            .map(|counter| Update {
+                key: key_for_counter(counter),
+                max_value: counter.max_value(),
+                window: counter.window().as_millis() as u64,
+                delta: counter.delta(delta),
+            })
+            .collect();
+        let outcome = self
+            .node
+            .propose(Command::Update {
+                updates,
+                check,
+                apply,
+            })
+            .await?;
+        let Outcome::Updated { limited, values } = outcome else {
+            return Err(unexpected(outcome));
+        };
+
+        if load_counters {
+            for (counter, (value, expires_at)) in counters.iter_mut().zip(values) {
+                let delta = counter.delta(delta);
+                counter.set_remaining(
+                    counter
+                        .max_value()
+                        .checked_sub(value.saturating_add(delta))
+                        .unwrap_or_default(),
+                );
+                counter.set_expires_in(expires_in(expires_at));
+            }
+        }
+        Ok(match limited {
+            Some(i) => Authorization::limited_by(&counters[i]),
+            None => Authorization::Ok,
+        })
+    }
+}
+
+/// The state the log entries get applied to
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counters {
+    // The value of each counter, and when its window ends, in milliseconds
+    // since the epoch
+    values: BTreeMap<Vec<u8>, (u64, u64)>,
+    // The latest time commands got applied at, for all replicas to purge the
+    // same expired counters
+    now: u64,
+}
+
+impl Counters {
+    // The entries of the log are the commands along with the leader's time, as
+    // of them getting proposed, for all replicas to apply them the same way
+    fn apply(&mut self, at: u64, command: Command) -> Outcome {
+        self.now = self.now.max(at);
+        match command {
+            Command::Noop => Outcome::Done,
+            Command::Update {
+                updates,
+                check,
+                apply,
+            } => {
+                let values: Vec<(u64, u64)> = updates
+                    .iter()
+                    .map(|update| match self.values.get(&update.key) {
+                        Some((value, expires_at)) if *expires_at > at => (*value, *expires_at),
+                        _ => (0, at + update.window),
+                    })
+                    .collect();
+                let limited = match check {
+                    true => updates
+                        .iter()
+                        .zip(&values)
+                        .position(|(update, (value, _))| {
+                            value.saturating_add(update.delta) > update.max_value
+                        }),
+                    false => None,
+                };
+                if apply && limited.is_none() {
+                    for (update, (value, expires_at)) in updates.into_iter().zip(&values) {
+                        self.values.insert(
+                            update.key,
+                            (value.saturating_add(update.delta), *expires_at),
+                        );
+                    }
+                }
+                Outcome::Updated { limited, values }
+            }
+            Command::Refund { refunds } => {
+                for (key, delta) in refunds {
+                    if let Some((value, _)) = self.values.get_mut(&key) {
+                        *value = value.saturating_sub(delta);
+                    }
+                }
+                Outcome::Done
+            }
+            Command::Scan { prefixes } => Outcome::Counters(
+                self.values
+                    .iter()
+                    .filter(|(key, (_, expires_at))| {
+                        *expires_at > at && prefixes.iter().any(|prefix| key.starts_with(prefix))
+                    })
+                    .map(|(key, (value, expires_at))| (key.clone(), *value, *expires_at))
+                    .collect(),
+            ),
+            Command::Delete { keys } => {
+                for key in keys {
+                    self.values.remove(&key);
+                }
+                Outcome::Done
+            }
+            Command::Clear => {
+                self.values.clear();
+                Outcome::Done
+            }
+        }
+    }
+
+    /// Drops the counters whose window ended, ahead of being snapshotted
+    fn purge(&mut self) {
+        let now = self.now;
+        self.values.retain(|_, (_, expires_at)| *expires_at > now);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn expires_in(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Counters;
+    use crate::storage::commands::{check_and_update, update, Command, Outcome};
+
+    #[test]
+    fn applies_updates_within_the_limits_only() {
+        let mut counters = Counters::default();
+
+        assert_eq!(
+            counters.apply(1_000, check_and_update(vec![update("a", 2, 2)])),
+            Outcome::Updated {
+                limited: None,
+                values: vec![(0, 61_000)],
+            }
+        );
+        // None of the counters get updated when any is over its limit
+        assert_eq!(
+            counters.apply(
+                2_000,
+                check_and_update(vec![update("b", 10, 1), update("a", 2, 1)])
+            ),
+            Outcome::Updated {
+                limited: Some(1),
+                values: vec![(0, 62_000), (2, 61_000)],
+            }
+        );
+        counters.apply(
+            2_500,
+            Command::Refund {
+                refunds: vec![(b"a".to_vec(), 1)],
+            },
+        );
+        assert_eq!(
+            counters.apply(3_000, check_and_update(vec![update("a", 2, 1)])),
+            Outcome::Updated {
+                limited: None,
+                values: vec![(1, 61_000)],
+            }
+        );
+
+        // Past its window, a counter starts over
+        assert_eq!(
+            counters.apply(61_000, check_and_update(vec![update("a", 2, 1)])),
+            Outcome::Updated {
+                limited: None,
+                values: vec![(0, 121_000)],
+            }
+        );
+        assert_eq!(
+            counters.apply(
+                61_000,
+                Command::Scan {
+                    prefixes: vec![vec![]],
+                }
+            ),
+            Outcome::Counters(vec![(b"a".to_vec(), 1, 121_000)])
+        );
+    }
+}
@@ -0,0 +1,946 @@
+use super::log::{self, Entry, HardState, Log, Persisted};
+use super::{now, Counters};
+use crate::storage::commands::{unavailable, Command, Outcome};
+use crate::storage::peer_auth::PeerAuth;
+use crate::storage::StorageErr;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info};
+
+use v1::raft_client::RaftClient;
+use v1::raft_server::{Raft, RaftServer};
+use v1::{
+    AppendRequest, AppendResponse, ProposeRequest, ProposeResponse, SnapshotRequest, VoteRequest,
+    VoteResponse,
+};
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod v1 {
+    tonic::include_proto!("limitador.service.raft.v1");
+}
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+// Randomized up to twice as long, so that replicas rarely run for leader at
+// the same time
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ENTRIES_PER_APPEND: usize = 512;
+// The entries applied past which the log gets compacted
+const SNAPSHOT_THRESHOLD: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+type Proposal = oneshot::Sender<Result<Outcome, String>>;
+
+struct State {
+    role: Role,
+    hard: HardState,
+    leader: Option<String>,
+    log: Log,
+    commit_index: u64,
+    last_applied: u64,
+    counters: Counters,
+    election_deadline: Instant,
+    // For the leader, the next entry to send to each peer, and the last one
+    // known to be replicated on it
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    // The proposals awaiting for their entry, of that term, to be applied
+    pending: HashMap<u64, (u64, Proposal)>,
+    // For the leader, the entries it appended by the write persisting them,
+    // and the last one of them persisted, the leader counting itself in the
+    // majority of the replicas of an entry only then
+    unpersisted: VecDeque<(u64, u64)>,
+    persisted_index: u64,
+}
+
+enum Replication {
+    Append(AppendRequest),
+    Snapshot(SnapshotRequest),
+}
+
+/// A replica, in the Raft sense, of the counters
+pub(super) struct Node {
+    id: String,
+    peers: HashMap<String, RaftClient<Channel>>,
+    // Wakes the replication to each peer up, as new entries get appended
+    replicate: HashMap<String, Notify>,
+    auth: PeerAuth,
+    persisted: Persisted,
+    snapshot_threshold: u64,
+    state: Mutex<State>,
+}
+
+impl Node {
+    pub fn new(
+        id: String,
+        peers: Vec<(String, String)>,
+        dir: &Path,
+        auth: PeerAuth,
+    ) -> io::Result<Arc<Self>> {
+        let (log, hard) = Log::open(dir)?;
+        let (snapshot_index, _, snapshot) = log.snapshot();
+        let counters = match snapshot.is_empty() {
+            true => Counters::default(),
+            false => postcard::from_bytes(snapshot)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+
+        auth.authenticate(&mut Request::new(()))
+            .map_err(|status| io::Error::new(io::ErrorKind::InvalidInput, status.message()))?;
+        let mut clients = HashMap::new();
+        for (peer, url) in peers {
+            let invalid =
+                |e: String| io::Error::new(io::ErrorKind::InvalidInput, format!("'{url}': {e}"));
+            let mut endpoint = Endpoint::from_shared(url.clone())
+                .map_err(|e| invalid(e.to_string()))?
+                .connect_timeout(ELECTION_TIMEOUT);
+            if let Some(tls) = auth.client_tls() {
+                endpoint = endpoint
+                    .tls_config(tls)
+                    .map_err(|e| invalid(e.to_string()))?;
+            }
+            let channel = endpoint.connect_lazy();
+            clients.insert(peer, RaftClient::new(channel));
+        }
+        let replicate = clients
+            .keys()
+            .map(|peer| (peer.clone(), Notify::new()))
+            .collect();
+
+        Ok(Arc::new(Self {
+            id,
+            peers: clients,
+            replicate,
+            auth,
+            persisted: log.persisted(),
+            snapshot_threshold: SNAPSHOT_THRESHOLD,
+            state: Mutex::new(State {
+                role: Role::Follower,
+                hard,
+                leader: None,
+                log,
+                commit_index: snapshot_index,
+                last_applied: snapshot_index,
+                counters,
+                election_deadline: election_deadline(),
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                pending: HashMap::new(),
+                unpersisted: VecDeque::new(),
+                persisted_index: 0,
+            }),
+        }))
+    }
+
+    /// Serves the other replicas on `listen_address`, and takes part in the
+    /// elections, replicating the log to the others once elected
+    pub fn start(self: Arc<Self>, listen_address: SocketAddr) {
+        let service = RaftServer::new(Service(Arc::clone(&self)));
+        let mut server = tonic::transport::Server::builder();
+        if let Some(tls) = self.auth.server_tls() {
+            server = server
+                .tls_config(tls)
+                .expect("the TLS configuration got validated");
+        }
+        tokio::spawn(async move {
+            if let Err(e) = server.add_service(service).serve(listen_address).await {
+                error!("raft replica failed to listen on {}: {}", listen_address, e);
+            }
+        });
+
+        for peer in self.peers.keys() {
+            tokio::spawn(Arc::clone(&self).replicate_to(peer.clone()));
+        }
+
+        let node = Arc::clone(&self);
+        let mut persisted = self.persisted.clone();
+        tokio::spawn(async move {
+            while persisted.changed().await.is_ok() {
+                let mut state = node.state.lock().unwrap();
+                node.advance_commit(&mut state);
+            }
+        });
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                let election = {
+                    let mut state = self.state.lock().unwrap();
+                    match state.role != Role::Leader && Instant::now() >= state.election_deadline {
+                        true => self.run_for_leader(&mut state),
+                        false => None,
+                    }
+                };
+                // Its vote for itself has to be persisted before asking for
+                // the others'
+                if let Some((request, written)) = election {
+                    if self.persisted(written).await.is_ok() {
+                        self.gather_votes(request).await;
+                    }
+                }
+            }
+        });
+    }
+
+    // A request to a peer, carrying the token to authenticate with, if any
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        self.auth
+            .authenticate(&mut request)
+            .expect("the token got validated");
+        request
+    }
+
+    async fn persisted(&self, written: u64) -> Result<(), Status> {
+        log::persisted(self.persisted.clone(), written)
+            .await
+            .map_err(internal)
+    }
+
+    /// Has the leader append the `command` to the log, and answers with its
+    /// outcome once applied
+    pub async fn propose(&self, command: Command) -> Result<Outcome, StorageErr> {
+        let command = postcard::to_stdvec(&(now(), command)).expect("commands can be serialized");
+        let leader = {
+            let state = self.state.lock().unwrap();
+            match state.role {
+                Role::Leader => None,
+                _ => Some(state.leader.clone()),
+            }
+        };
+        match leader {
+            None => self.propose_as_leader(command).await.map_err(unavailable),
+            Some(leader) => self.forward(leader, command).await,
+        }
+    }
+
+    async fn propose_as_leader(&self, command: Vec<u8>) -> Result<Outcome, String> {
+        let proposed = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err("not the leader anymore".to_string());
+            }
+            let term = state.hard.term;
+            let index = state.log.last_index() + 1;
+            let written = state.log.append(vec![Entry { term, command }]);
+            state.unpersisted.push_back((written, index));
+            let (sender, receiver) = oneshot::channel();
+            state.pending.insert(index, (term, sender));
+            receiver
+        };
+        self.replicate_now();
+
+        match timeout(PROPOSAL_TIMEOUT, proposed).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(_)) => Err("the proposal got dropped".to_string()),
+            Err(_) => Err("timed out waiting for a majority of the replicas".to_string()),
+        }
+    }
+
+    async fn forward(
+        &self,
+        leader: Option<String>,
+        command: Vec<u8>,
+    ) -> Result<Outcome, StorageErr> {
+        let Some(mut client) = leader.and_then(|leader| self.peers.get(&leader).cloned()) else {
+            return Err(unavailable("no leader elected".to_string()));
+        };
+        let request = self.request(ProposeRequest { command });
+        let response = timeout(PROPOSAL_TIMEOUT, client.propose(request))
+            .await
+            .map_err(|_| unavailable("timed out waiting for the leader".to_string()))?
+            .map_err(|status| unavailable(status.message().to_string()))?;
+        postcard::from_bytes(&response.into_inner().outcome).map_err(|e| StorageErr {
+            msg: format!("couldn't decode the outcome from the leader: {e}"),
+            source: None,
+            transient: false,
+        })
+    }
+
+    fn replicate_now(&self) {
+        for notify in self.replicate.values() {
+            notify.notify_one();
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        (self.peers.len() + 1) / 2 + 1
+    }
+
+    // Becomes a candidate for the next term, voting for itself, and returns
+    // the request for the others' votes, along with the write of its vote
+    fn run_for_leader(&self, state: &mut State) -> Option<(VoteRequest, u64)> {
+        let hard = HardState {
+            term: state.hard.term + 1,
+            voted_for: Some(self.id.clone()),
+        };
+        let written = state.log.save(&hard);
+        debug!(
+            "replica '{}' running for leader of term {}",
+            self.id, hard.term
+        );
+        state.hard = hard;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.election_deadline = election_deadline();
+        if self.quorum() == 1 {
+            self.become_leader(state);
+            return None;
+        }
+        let request = VoteRequest {
+            term: state.hard.term,
+            candidate_id: self.id.clone(),
+            last_log_index: state.log.last_index(),
+            last_log_term: state.log.last_term(),
+        };
+        Some((request, written))
+    }
+
+    async fn gather_votes(&self, request: VoteRequest) {
+        let mut requests = JoinSet::new();
+        for client in self.peers.values() {
+            let mut client = client.clone();
+            let request = self.request(request.clone());
+            requests.spawn(
+                async move { timeout(ELECTION_TIMEOUT, client.request_vote(request)).await },
+            );
+        }
+
+        let mut votes = 1;
+        while let Some(response) = requests.join_next().await {
+            let Ok(Ok(Ok(response))) = response else {
+                continue;
+            };
+            let response = response.into_inner();
+            let mut state = self.state.lock().unwrap();
+            if response.term > state.hard.term {
+                self.step_down(&mut state, response.term);
+                return;
+            }
+            if state.role != Role::Candidate || state.hard.term != request.term {
+                return;
+            }
+            if response.vote_granted {
+                votes += 1;
+                if votes >= self.quorum() {
+                    self.become_leader(&mut state);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn become_leader(&self, state: &mut State) {
+        info!(
+            "replica '{}' elected leader of term {}",
+            self.id, state.hard.term
+        );
+        state.role = Role::Leader;
+        state.leader = Some(self.id.clone());
+        let next = state.log.last_index() + 1;
+        for peer in self.peers.keys() {
+            state.next_index.insert(peer.clone(), next);
+            state.match_index.insert(peer.clone(), 0);
+        }
+        // Committing an entry of its own term commits the previous ones
+        let term = state.hard.term;
+        let index = state.log.last_index() + 1;
+        let noop =
+            postcard::to_stdvec(&(now(), Command::Noop)).expect("commands can be serialized");
+        let written = state.log.append(vec![Entry {
+            term,
+            command: noop,
+        }]);
+        state.unpersisted = VecDeque::from([(written, index)]);
+        state.persisted_index = 0;
+        self.advance_commit(state);
+        self.replicate_now();
+    }
+
+    // Follows whoever is of a later term, or the leader of the current one
+    fn step_down(&self, state: &mut State, term: u64) {
+        if term > state.hard.term {
+            let hard = HardState {
+                term,
+                voted_for: None,
+            };
+            state.log.save(&hard);
+            state.hard = hard;
+            state.leader = None;
+        }
+        if state.role == Role::Leader {
+            debug!("replica '{}' stepping down as leader", self.id);
+            for (_, (_, proposal)) in state.pending.drain() {
+                let _ = proposal.send(Err("not the leader anymore".to_string()));
+            }
+        }
+        state.role = Role::Follower;
+        state.election_deadline = election_deadline();
+    }
+
+    // The leader commits the entries of its term a majority persisted
+    fn advance_commit(&self, state: &mut State) {
+        if state.role == Role::Leader {
+            let persisted = state.log.persisted_seq().unwrap_or_default();
+            while let Some((written, index)) = state.unpersisted.front().copied() {
+                if written > persisted {
+                    break;
+                }
+                state.persisted_index = index;
+                state.unpersisted.pop_front();
+            }
+
+            let term = state.hard.term;
+            for index in (state.commit_index + 1..=state.log.last_index()).rev() {
+                if state.log.term_at(index) != Some(term) {
+                    break;
+                }
+                let replicas = usize::from(state.persisted_index >= index)
+                    + state
+                        .match_index
+                        .values()
+                        .filter(|matched| **matched >= index)
+                        .count();
+                if replicas >= self.quorum() {
+                    state.commit_index = index;
+                    break;
+                }
+            }
+        }
+        self.apply(state);
+    }
+
+    fn apply(&self, state: &mut State) {
+        while state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+            let Some(entry) = state.log.entry(index) else {
+                break;
+            };
+            let term = entry.term;
+            let outcome = match postcard::from_bytes(&entry.command) {
+                Ok((at, command)) => Ok(state.counters.apply(at, command)),
+                Err(e) => Err(format!("couldn't decode the command: {e}")),
+            };
+            state.last_applied = index;
+            if let Some((proposed_in, proposal)) = state.pending.remove(&index) {
+                let _ = proposal.send(match proposed_in == term {
+                    true => outcome,
+                    false => Err("the proposal got overwritten by a new leader".to_string()),
+                });
+            }
+        }
+
+        let (snapshot_index, _, _) = state.log.snapshot();
+        if state.last_applied - snapshot_index >= self.snapshot_threshold {
+            state.counters.purge();
+            let counters =
+                postcard::to_stdvec(&state.counters).expect("counters can be serialized");
+            let index = state.last_applied;
+            state.log.compact(index, counters);
+        }
+    }
+
+    async fn replicate_to(self: Arc<Self>, peer: String) {
+        let notify = &self.replicate[&peer];
+        let mut client = self.peers[&peer].clone();
+        loop {
+            let _ = timeout(HEARTBEAT_INTERVAL, notify.notified()).await;
+            let Some(replication) = self.replication_for(&peer) else {
+                continue;
+            };
+            let (term, replicated, response) = match replication {
+                Replication::Append(request) => {
+                    let term = request.term;
+                    let replicated = request.prev_log_index + request.entries.len() as u64;
+                    let request = self.request(request);
+                    let response = timeout(PROPOSAL_TIMEOUT, client.append_entries(request)).await;
+                    (term, replicated, response)
+                }
+                Replication::Snapshot(request) => {
+                    let term = request.term;
+                    let replicated = request.last_included_index;
+                    let request = self.request(request);
+                    let response =
+                        timeout(PROPOSAL_TIMEOUT, client.install_snapshot(request)).await;
+                    (term, replicated, response)
+                }
+            };
+            let response = match response {
+                Ok(Ok(response)) => response.into_inner(),
+                Ok(Err(status)) => {
+                    debug!("couldn't replicate to '{}': {}", peer, status.message());
+                    continue;
+                }
+                Err(_) => {
+                    debug!("couldn't replicate to '{}': timed out", peer);
+                    continue;
+                }
+            };
+
+            let mut state = self.state.lock().unwrap();
+            if response.term > state.hard.term {
+                self.step_down(&mut state, response.term);
+                continue;
+            }
+            if state.role != Role::Leader || state.hard.term != term {
+                continue;
+            }
+            let next = if response.success {
+                state.match_index.insert(peer.clone(), replicated);
+                self.advance_commit(&mut state);
+                replicated + 1
+            } else {
+                // Backs off to where the peer's log might agree with ours
+                let next = state.next_index.get(&peer).copied().unwrap_or(1);
+                (next - 1).min(response.last_log_index + 1).max(1)
+            };
+            state.next_index.insert(peer.clone(), next);
+            if next <= state.log.last_index() {
+                notify.notify_one();
+            }
+        }
+    }
+
+    // What the leader has to send to the `peer` next, from the entries it
+    // misses, or the snapshot when they got compacted
+    fn replication_for(&self, peer: &str) -> Option<Replication> {
+        let state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return None;
+        }
+        let next = state.next_index.get(peer).copied().unwrap_or(1);
+        let (snapshot_index, snapshot_term, snapshot) = state.log.snapshot();
+        if next <= snapshot_index {
+            return Some(Replication::Snapshot(SnapshotRequest {
+                term: state.hard.term,
+                leader_id: self.id.clone(),
+                last_included_index: snapshot_index,
+                last_included_term: snapshot_term,
+                counters: snapshot.to_vec(),
+            }));
+        }
+        let prev_log_index = next - 1;
+        Some(Replication::Append(AppendRequest {
+            term: state.hard.term,
+            leader_id: self.id.clone(),
+            prev_log_index,
+            prev_log_term: state.log.term_at(prev_log_index).unwrap_or_default(),
+            entries: state
+                .log
+                .entries_from(next, MAX_ENTRIES_PER_APPEND)
+                .iter()
+                .map(|entry| v1::Entry {
+                    term: entry.term,
+                    command: entry.command.clone(),
+                })
+                .collect(),
+            leader_commit: state.commit_index,
+        }))
+    }
+
+    // The response, along with the write to persist before sending it
+    fn on_vote_request(&self, request: VoteRequest) -> (VoteResponse, u64) {
+        let mut state = self.state.lock().unwrap();
+        if request.term > state.hard.term {
+            self.step_down(&mut state, request.term);
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (state.log.last_term(), state.log.last_index());
+        let available = match &state.hard.voted_for {
+            None => true,
+            Some(candidate) => *candidate == request.candidate_id,
+        };
+        let vote_granted = request.term == state.hard.term && available && up_to_date;
+        if vote_granted {
+            let hard = HardState {
+                term: state.hard.term,
+                voted_for: Some(request.candidate_id),
+            };
+            state.log.save(&hard);
+            state.hard = hard;
+            state.election_deadline = election_deadline();
+        }
+        let response = VoteResponse {
+            term: state.hard.term,
+            vote_granted,
+        };
+        (response, state.log.written())
+    }
+
+    // The response, along with the write to persist before sending it
+    fn on_append_request(&self, request: AppendRequest) -> Result<(AppendResponse, u64), Status> {
+        let mut state = self.state.lock().unwrap();
+        if request.term < state.hard.term {
+            return Ok(rejected(&state, state.log.last_index()));
+        }
+        self.follow(&mut state, request.term, request.leader_id);
+
+        // Whatever got compacted was committed, and so is in the leader's log
+        let (snapshot_index, _, _) = state.log.snapshot();
+        let agrees = request.prev_log_index <= snapshot_index
+            || state.log.term_at(request.prev_log_index) == Some(request.prev_log_term);
+        if !agrees {
+            let last_log_index = state
+                .log
+                .last_index()
+                .min(request.prev_log_index.saturating_sub(1));
+            return Ok(rejected(&state, last_log_index));
+        }
+
+        let last_new_index = request.prev_log_index + request.entries.len() as u64;
+        let mut entries = request.entries.into_iter();
+        let mut index = request.prev_log_index;
+        while let Some(entry) = entries.next() {
+            index += 1;
+            if index <= snapshot_index {
+                continue;
+            }
+            match state.log.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    state.log.truncate(index);
+                }
+                None => {}
+            }
+            let new = std::iter::once(entry)
+                .chain(entries.by_ref())
+                .map(|entry| Entry {
+                    term: entry.term,
+                    command: entry.command,
+                })
+                .collect();
+            state.log.append(new);
+            break;
+        }
+
+        if request.leader_commit > state.commit_index {
+            state.commit_index = request.leader_commit.min(last_new_index);
+            self.apply(&mut state);
+        }
+        let response = AppendResponse {
+            term: state.hard.term,
+            success: true,
+            last_log_index: state.log.last_index(),
+        };
+        Ok((response, state.log.written()))
+    }
+
+    // The response, along with the write to persist before sending it
+    fn on_snapshot(&self, request: SnapshotRequest) -> Result<(AppendResponse, u64), Status> {
+        let mut state = self.state.lock().unwrap();
+        if request.term < state.hard.term {
+            let response = AppendResponse {
+                term: state.hard.term,
+                success: false,
+                last_log_index: state.log.last_index(),
+            };
+            return Ok((response, state.log.written()));
+        }
+        self.follow(&mut state, request.term, request.leader_id);
+
+        let index = request.last_included_index;
+        if index > state.commit_index {
+            let counters = postcard::from_bytes(&request.counters).map_err(internal)?;
+            state
+                .log
+                .install(index, request.last_included_term, request.counters);
+            state.counters = counters;
+            state.commit_index = index;
+            state.last_applied = index;
+        }
+        let response = AppendResponse {
+            term: state.hard.term,
+            success: true,
+            last_log_index: index,
+        };
+        Ok((response, state.log.written()))
+    }
+
+    fn follow(&self, state: &mut State, term: u64, leader: String) {
+        if term > state.hard.term || state.role != Role::Follower {
+            self.step_down(state, term);
+        }
+        state.leader = Some(leader);
+        state.election_deadline = election_deadline();
+    }
+}
+
+struct Service(Arc<Node>);
+
+#[tonic::async_trait]
+impl Raft for Service {
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> Result<Response<VoteResponse>, Status> {
+        self.0.auth.authorize(&request)?;
+        let (response, written) = self.0.on_vote_request(request.into_inner());
+        self.0.persisted(written).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        self.0.auth.authorize(&request)?;
+        let (response, written) = self.0.on_append_request(request.into_inner())?;
+        self.0.persisted(written).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<AppendResponse>, Status> {
+        self.0.auth.authorize(&request)?;
+        let (response, written) = self.0.on_snapshot(request.into_inner())?;
+        self.0.persisted(written).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn propose(
+        &self,
+        request: Request<ProposeRequest>,
+    ) -> Result<Response<ProposeResponse>, Status> {
+        self.0.auth.authorize(&request)?;
+        let outcome = self
+            .0
+            .propose_as_leader(request.into_inner().command)
+            .await
+            .map_err(Status::unavailable)?;
+        let outcome = postcard::to_stdvec(&outcome).map_err(internal)?;
+        Ok(Response::new(ProposeResponse { outcome }))
+    }
+}
+
+fn rejected(state: &State, last_log_index: u64) -> (AppendResponse, u64) {
+    let response = AppendResponse {
+        term: state.hard.term,
+        success: false,
+        last_log_index,
+    };
+    (response, state.log.written())
+}
+
+fn election_deadline() -> Instant {
+    let jitter = RandomState::new().hash_one(Instant::now()) % ELECTION_TIMEOUT.as_millis() as u64;
+    Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter)
+}
+
+fn internal(e: impl ToString) -> Status {
+    Status::internal(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::v1::{AppendRequest, Entry};
+    use super::{Node, Role};
+    use crate::storage::commands::{Command, Outcome, Update};
+    use crate::storage::peer_auth::PeerAuth;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    fn free_address() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn replica(
+        id: &str,
+        addresses: &[(&str, SocketAddr)],
+        dir: &TempDir,
+        snapshot_threshold: u64,
+    ) -> Arc<Node> {
+        let peers = addresses
+            .iter()
+            .filter(|(peer, _)| *peer != id)
+            .map(|(peer, address)| (peer.to_string(), format!("http://{address}")))
+            .collect();
+        let mut node = Node::new(id.to_string(), peers, dir.path(), PeerAuth::default()).unwrap();
+        Arc::get_mut(&mut node).unwrap().snapshot_threshold = snapshot_threshold;
+        let (_, address) = addresses.iter().find(|(peer, _)| *peer == id).unwrap();
+        Arc::clone(&node).start(*address);
+        node
+    }
+
+    // The one leader all the others follow, if any
+    fn elected(nodes: &[Arc<Node>]) -> Option<Arc<Node>> {
+        let leader = nodes
+            .iter()
+            .find(|node| node.state.lock().unwrap().role == Role::Leader)?;
+        let term = leader.state.lock().unwrap().hard.term;
+        nodes
+            .iter()
+            .all(|node| {
+                let state = node.state.lock().unwrap();
+                state.hard.term == term && state.leader.as_ref() == Some(&leader.id)
+            })
+            .then(|| Arc::clone(leader))
+    }
+
+    async fn eventually<T>(mut what: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(value) = what() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "timed out");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn hit(node: &Node, key: &str) {
+        let outcome = node
+            .propose(Command::Update {
+                updates: vec![Update {
+                    key: key.as_bytes().to_vec(),
+                    max_value: 10,
+                    window: 60_000,
+                    delta: 1,
+                }],
+                check: true,
+                apply: true,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Updated { limited: None, .. }));
+    }
+
+    fn value(node: &Node, key: &str) -> Option<u64> {
+        let state = node.state.lock().unwrap();
+        state
+            .counters
+            .values
+            .get(key.as_bytes())
+            .map(|(value, _)| *value)
+    }
+
+    fn noop(term: u64) -> Entry {
+        Entry {
+            term,
+            command: postcard::to_stdvec(&(0u64, Command::Noop)).unwrap(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn elects_a_leader_and_replicates_its_log() {
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let addresses = [
+            ("a", free_address()),
+            ("b", free_address()),
+            ("c", free_address()),
+        ];
+        let nodes: Vec<_> = addresses
+            .iter()
+            .zip(&dirs)
+            .map(|((id, _), dir)| replica(id, &addresses, dir, 100))
+            .collect();
+
+        let leader = eventually(|| elected(&nodes)).await;
+        // Followers forward to the leader
+        let follower = nodes.iter().find(|node| node.id != leader.id).unwrap();
+        hit(follower, "key").await;
+        hit(&leader, "key").await;
+
+        for node in &nodes {
+            eventually(|| (value(node, "key") == Some(2)).then_some(())).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn installs_the_leaders_snapshot_on_the_replicas_lagging_behind() {
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let addresses = [
+            ("a", free_address()),
+            ("b", free_address()),
+            ("c", free_address()),
+        ];
+        // A majority, without "c"
+        let nodes = vec![
+            replica("a", &addresses, &dirs[0], 2),
+            replica("b", &addresses, &dirs[1], 2),
+        ];
+        let leader = eventually(|| {
+            nodes
+                .iter()
+                .find(|node| node.state.lock().unwrap().role == Role::Leader)
+                .cloned()
+        })
+        .await;
+        for _ in 0..5 {
+            hit(&leader, "key").await;
+        }
+        let (compacted, _, _) = leader.state.lock().unwrap().log.snapshot();
+        assert!(compacted > 0);
+
+        let late = replica("c", &addresses, &dirs[2], 2);
+        eventually(|| (value(&late, "key") == Some(5)).then_some(())).await;
+        let (snapshot_index, _, _) = late.state.lock().unwrap().log.snapshot();
+        assert!(snapshot_index >= compacted);
+    }
+
+    #[test]
+    fn truncates_the_entries_conflicting_with_the_leader() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = Node::new("c".to_string(), vec![], dir.path(), PeerAuth::default()).unwrap();
+        let append = |leader: &str, term, prev_log_index, prev_log_term, entries| {
+            let (response, _) = node
+                .on_append_request(AppendRequest {
+                    term,
+                    leader_id: leader.to_string(),
+                    prev_log_index,
+                    prev_log_term,
+                    entries,
+                    leader_commit: 0,
+                })
+                .unwrap();
+            response
+        };
+
+        let response = append("a", 1, 0, 0, vec![noop(1), noop(1), noop(1)]);
+        assert!(response.success);
+        assert_eq!(response.last_log_index, 3);
+
+        // The leader of the next term only got the first of them replicated
+        let response = append("b", 2, 1, 1, vec![noop(2)]);
+        assert!(response.success);
+        assert_eq!(response.last_log_index, 2);
+        {
+            let state = node.state.lock().unwrap();
+            assert_eq!(state.log.term_at(2), Some(2));
+            assert_eq!(state.log.term_at(3), None);
+            assert_eq!(state.leader.as_deref(), Some("b"));
+        }
+
+        // Entries only get appended where the logs agree
+        let response = append("b", 2, 2, 1, vec![noop(2)]);
+        assert!(!response.success);
+        assert!(response.last_log_index <= 1);
+        // Nor from the leaders of the terms past
+        let response = append("a", 1, 2, 2, vec![noop(1)]);
+        assert!(!response.success);
+        assert_eq!(node.state.lock().unwrap().log.last_index(), 2);
+    }
+}
@@ -0,0 +1,500 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::atomic_expiring_value::AtomicExpiringValue;
+use crate::storage::keys::bin::{key_for_counter, partial_counter_from_counter_key};
+use crate::storage::redis::{
+    RedisConnectionConfig, DEFAULT_BROADCAST_CHANNEL, DEFAULT_BROADCAST_PERIOD_MS,
+    DEFAULT_MAX_CACHED_COUNTERS,
+};
+use crate::storage::{peek_counters, AsyncCounterStorage, Authorization, StorageErr};
+use async_trait::async_trait;
+use moka::sync::Cache;
+use redis::aio::ConnectionManager;
+use redis::{Client, RedisError};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_stream::StreamExt;
+use tracing::{info_span, warn, Instrument};
+
+// Decisions are made from the counters held in memory alone, as the in memory
+// storage does, and Redis is never waited on. What gets added to, or refunded
+// from, the counters is published on a Redis pub/sub channel every
+// `broadcast_period`, and the deltas the other instances publish on it get
+// applied to ours. Redis doesn't hold any counter, it only relays the deltas.
+//
+// The enforcement is approximate: each instance admits hits the others don't
+// know of yet, for up to a `broadcast_period` plus the time it takes the deltas
+// to get relayed, and for as long as Redis is unreachable, the deltas being
+// kept until they can be published. A counter that isn't known locally starts
+// with the window the delta was published for, while the ones already known
+// keep their own. The deltas for a window that already ended are dropped.
+pub struct BroadcastRedisStorage {
+    local: Arc<Cache<Vec<u8>, Arc<AtomicExpiringValue>>>,
+    pending: Arc<Mutex<HashMap<Vec<u8>, CounterDelta>>>,
+    publisher: Publisher,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for BroadcastRedisStorage {
+    #[tracing::instrument(skip_all)]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let value = self
+            .local
+            .get(&key_for_counter(counter))
+            .map(|value| value.value())
+            .unwrap_or_default();
+        Ok(counter.max_value() >= value.saturating_add(counter.delta(delta)))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let now = SystemTime::now();
+        let (key, value) = self.value_of(counter, now);
+        let delta = counter.delta(delta);
+        value.update(delta, counter.window(), now);
+        let mut pending = self.pending.lock().unwrap();
+        let pending = pending_delta(&mut pending, key, counter, &value);
+        pending.added = pending.added.saturating_add(delta);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let key = key_for_counter(counter);
+        if let Some(value) = self.local.get(&key) {
+            let delta = counter.delta(delta);
+            value.refund(delta, SystemTime::now());
+            let mut pending = self.pending.lock().unwrap();
+            let pending = pending_delta(&mut pending, key, counter, &value);
+            pending.refunded = pending.refunded.saturating_add(delta);
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let now = SystemTime::now();
+        let values: Vec<(Vec<u8>, Arc<AtomicExpiringValue>)> = counters
+            .iter()
+            .map(|counter| self.value_of(counter, now))
+            .collect();
+
+        let mut first_limited = None;
+        for (counter, (_, value)) in counters.iter_mut().zip(&values) {
+            let delta = counter.delta(delta);
+            let remaining = counter
+                .max_value()
+                .checked_sub(value.value_at(now).saturating_add(delta));
+            if load_counters {
+                counter.set_remaining(remaining.unwrap_or_default());
+                counter.set_expires_in(value.ttl());
+            }
+            if first_limited.is_none() && remaining.is_none() {
+                first_limited = Some(Authorization::limited_by(counter));
+                if !load_counters {
+                    break;
+                }
+            }
+        }
+        if let Some(limited) = first_limited {
+            return Ok(limited);
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        for (counter, (key, value)) in counters.iter().zip(values) {
+            let delta = counter.delta(delta);
+            value.update(delta, counter.window(), now);
+            let pending = pending_delta(&mut pending, key, counter, &value);
+            pending.added = pending.added.saturating_add(delta);
+        }
+
+        Ok(Authorization::Ok)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        let now = SystemTime::now();
+        let values_and_ttls: Vec<(u64, Duration)> = counters
+            .iter()
+            .map(|counter| {
+                self.local
+                    .get(&key_for_counter(counter))
+                    .map(|value| (value.value_at(now), value.ttl()))
+                    .filter(|(_, ttl)| !ttl.is_zero())
+                    .unwrap_or((0, counter.window()))
+            })
+            .collect();
+        Ok(peek_counters(counters, values_and_ttls, delta))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        let mut res = HashSet::new();
+        let now = SystemTime::now();
+        for (key, value) in self.local.iter() {
+            let ttl = value.ttl();
+            if ttl.is_zero() {
+                continue;
+            }
+            let mut counter = partial_counter_from_counter_key(&key);
+            let Some(limit) = limits.get(counter.limit()) else {
+                continue;
+            };
+            counter.update_to_limit(Arc::clone(limit));
+            counter.set_remaining(counter.max_value().saturating_sub(value.value_at(now)));
+            counter.set_expires_in(ttl);
+            res.insert(counter);
+        }
+        Ok(res)
+    }
+
+    // Deleting, or clearing, counters only affects this instance's copy
+    #[tracing::instrument(skip_all)]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        let key = key_for_counter(counter);
+        self.local.invalidate(&key);
+        self.pending.lock().unwrap().remove(&key);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let of_limits = |key: &[u8]| limits.contains(partial_counter_from_counter_key(key).limit());
+        for (key, _) in self.local.iter() {
+            if of_limits(&key) {
+                self.local.invalidate(key.as_slice());
+            }
+        }
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|key, _| !of_limits(key));
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.local.invalidate_all();
+        self.pending.lock().unwrap().clear();
+        Ok(())
+    }
+
+    // Publishes the deltas not yet broadcast right away
+    #[tracing::instrument(skip_all)]
+    async fn flush(&self) -> Result<(), StorageErr> {
+        broadcast_pending(&self.pending, &self.publisher).await?;
+        Ok(())
+    }
+}
+
+impl BroadcastRedisStorage {
+    pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
+        BroadcastRedisStorageBuilder::new(redis_url).build().await
+    }
+
+    fn value_of(&self, counter: &Counter, now: SystemTime) -> (Vec<u8>, Arc<AtomicExpiringValue>) {
+        let key = key_for_counter(counter);
+        let value = self.local.get_with_by_ref(&key, || {
+            Arc::new(AtomicExpiringValue::new(0, now + counter.window()))
+        });
+        (key, value)
+    }
+}
+
+// What an instance added to, and refunded from, a counter since its last
+// broadcast
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct CounterDelta {
+    key: Vec<u8>,
+    // in seconds
+    window: u64,
+    // milliseconds since the epoch
+    expires_at: u64,
+    added: u64,
+    refunded: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Deltas {
+    // The instance that published them, for it to skip its own
+    origin: String,
+    deltas: Vec<CounterDelta>,
+}
+
+#[derive(Clone)]
+struct Publisher {
+    redis_conn: ConnectionManager,
+    channel: String,
+    origin: String,
+}
+
+fn pending_delta<'a>(
+    pending: &'a mut HashMap<Vec<u8>, CounterDelta>,
+    key: Vec<u8>,
+    counter: &Counter,
+    value: &AtomicExpiringValue,
+) -> &'a mut CounterDelta {
+    let delta = pending.entry(key).or_insert_with_key(|key| CounterDelta {
+        key: key.clone(),
+        window: counter.window().as_secs(),
+        ..Default::default()
+    });
+    delta.expires_at = millis_since_epoch(value.expires_at());
+    delta
+}
+
+async fn broadcast_pending(
+    pending: &Mutex<HashMap<Vec<u8>, CounterDelta>>,
+    publisher: &Publisher,
+) -> Result<(), RedisError> {
+    let deltas: Vec<CounterDelta> = mem::take(&mut *pending.lock().unwrap())
+        .into_values()
+        .collect();
+    if deltas.is_empty() {
+        return Ok(());
+    }
+
+    let message = Deltas {
+        origin: publisher.origin.clone(),
+        deltas,
+    };
+    let result: Result<(), RedisError> = redis::cmd("PUBLISH")
+        .arg(&publisher.channel)
+        .arg(postcard::to_stdvec(&message).unwrap())
+        .query_async(&mut publisher.redis_conn.clone())
+        .instrument(info_span!("datastore"))
+        .await;
+    if result.is_err() {
+        // Keep them around for the next broadcast, along with the ones made
+        // since, unless their window ended already
+        let now = millis_since_epoch(SystemTime::now());
+        let mut pending = pending.lock().unwrap();
+        for delta in message.deltas.into_iter().filter(|d| d.expires_at > now) {
+            match pending.get_mut(&delta.key) {
+                Some(latest) if latest.expires_at == delta.expires_at => {
+                    latest.added = latest.added.saturating_add(delta.added);
+                    latest.refunded = latest.refunded.saturating_add(delta.refunded);
+                }
+                Some(_) => {}
+                None => {
+                    pending.insert(delta.key.clone(), delta);
+                }
+            }
+        }
+    }
+    result
+}
+
+// Applies the deltas published by the other instances, until the storage is
+// dropped
+async fn apply_from_peers(
+    client: Client,
+    channel: String,
+    origin: String,
+    local: Weak<Cache<Vec<u8>, Arc<AtomicExpiringValue>>>,
+) {
+    while local.strong_count() > 0 {
+        if let Err(err) = subscribe_to_deltas(&client, &channel, &origin, &local).await {
+            warn!("Error receiving counter deltas, will retry: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn subscribe_to_deltas(
+    client: &Client,
+    channel: &str,
+    origin: &str,
+    local: &Weak<Cache<Vec<u8>, Arc<AtomicExpiringValue>>>,
+) -> Result<(), RedisError> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        let Some(local) = local.upgrade() else {
+            return Ok(());
+        };
+        let message: Deltas = match postcard::from_bytes(msg.get_payload_bytes()) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Ignoring malformed counter deltas: {}", err);
+                continue;
+            }
+        };
+        if message.origin == origin {
+            continue;
+        }
+        let now = SystemTime::now();
+        for delta in message.deltas {
+            apply_delta(&local, delta, now);
+        }
+    }
+    Ok(())
+}
+
+fn apply_delta(
+    local: &Cache<Vec<u8>, Arc<AtomicExpiringValue>>,
+    delta: CounterDelta,
+    now: SystemTime,
+) {
+    let expires_at = UNIX_EPOCH + Duration::from_millis(delta.expires_at);
+    if expires_at <= now {
+        return;
+    }
+    let value = local.get_with(delta.key, || {
+        Arc::new(AtomicExpiringValue::new(0, expires_at))
+    });
+    value.update(delta.added, Duration::from_secs(delta.window), now);
+    value.refund(delta.refunded, now);
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Distinguishes this instance's deltas from the others', even across restarts
+fn instance_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:x}-{:x}", std::process::id(), nanos)
+}
+
+pub struct BroadcastRedisStorageBuilder {
+    redis_url: String,
+    channel: String,
+    broadcast_period: Duration,
+    max_cached_counters: usize,
+    connection_config: RedisConnectionConfig,
+}
+
+impl BroadcastRedisStorageBuilder {
+    pub fn new(redis_url: &str) -> Self {
+        Self {
+            redis_url: redis_url.to_string(),
+            channel: DEFAULT_BROADCAST_CHANNEL.to_string(),
+            broadcast_period: Duration::from_millis(DEFAULT_BROADCAST_PERIOD_MS),
+            max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
+            connection_config: RedisConnectionConfig::default(),
+        }
+    }
+
+    /// Pub/sub channel the deltas get published on, shared by all the
+    /// instances enforcing the same limits
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channel = channel.to_string();
+        self
+    }
+
+    /// How often the deltas get published. The shorter, the more accurate
+    /// the enforcement, at the cost of more messages going through Redis.
+    pub fn broadcast_period(mut self, broadcast_period: Duration) -> Self {
+        self.broadcast_period = broadcast_period;
+        self
+    }
+
+    pub fn max_cached_counters(mut self, max_cached_counters: usize) -> Self {
+        self.max_cached_counters = max_cached_counters;
+        self
+    }
+
+    pub fn connection_config(mut self, connection_config: RedisConnectionConfig) -> Self {
+        self.connection_config = connection_config;
+        self
+    }
+
+    pub async fn build(self) -> Result<BroadcastRedisStorage, RedisError> {
+        let client = self.connection_config.client(&self.redis_url)?;
+        let redis_conn = ConnectionManager::new_with_config(
+            client.clone(),
+            self.connection_config.connection_manager_config(),
+        )
+        .await?;
+        let publisher = Publisher {
+            redis_conn,
+            channel: self.channel,
+            origin: instance_id(),
+        };
+        let local = Arc::new(Cache::new(self.max_cached_counters as u64));
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+
+        {
+            let pending = Arc::downgrade(&pending);
+            let publisher = publisher.clone();
+            let mut interval = tokio::time::interval(self.broadcast_period);
+            tokio::spawn(async move {
+                loop {
+                    interval.tick().await;
+                    // Stop once the storage is gone
+                    let Some(pending) = pending.upgrade() else {
+                        break;
+                    };
+                    if let Err(err) = broadcast_pending(&pending, &publisher).await {
+                        warn!("Error publishing counter deltas, will retry: {}", err);
+                    }
+                }
+            });
+        }
+
+        tokio::spawn(apply_from_peers(
+            client,
+            publisher.channel.clone(),
+            publisher.origin.clone(),
+            Arc::downgrade(&local),
+        ));
+
+        Ok(BroadcastRedisStorage {
+            local,
+            pending,
+            publisher,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, millis_since_epoch, CounterDelta};
+    use moka::sync::Cache;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn applies_the_deltas_of_peers_within_their_window() {
+        let local = Cache::new(10);
+        let now = SystemTime::now();
+        let delta = |key: &str, expires_at: SystemTime, added, refunded| CounterDelta {
+            key: key.as_bytes().to_vec(),
+            window: 60,
+            expires_at: millis_since_epoch(expires_at),
+            added,
+            refunded,
+        };
+
+        apply_delta(&local, delta("a", now + Duration::from_secs(30), 5, 0), now);
+        apply_delta(&local, delta("a", now + Duration::from_secs(30), 2, 1), now);
+        // The window it was published for ended already
+        apply_delta(&local, delta("b", now - Duration::from_secs(1), 5, 0), now);
+
+        let value = local.get(b"a".as_slice()).expect("must have a value");
+        assert_eq!(value.value_at(now), 6);
+        assert!(value.ttl() <= Duration::from_secs(30));
+        assert!(local.get(b"b".as_slice()).is_none());
+    }
+}
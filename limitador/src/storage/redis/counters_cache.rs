@@ -1,3 +1,4 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
 use crate::storage::atomic_expiring_value::AtomicExpiringValue;
 use crate::storage::redis::DEFAULT_MAX_CACHED_COUNTERS;
@@ -7,38 +8,51 @@ use metrics::{counter, gauge, histogram};
 use moka::notification::RemovalCause;
 use moka::sync::Cache;
 use std::collections::HashMap;
+use std::fmt;
 use std::future::Future;
 use std::ops::Not;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::select;
 use tokio::sync::{Notify, Semaphore};
 use tracing::info;
 
-#[derive(Debug)]
 pub struct CachedCounterValue {
     value: AtomicExpiringValue,
     initial_value: AtomicU64,
     from_authority: AtomicBool,
+    // milliseconds since the epoch of the last time the value was read from
+    // the authority, 0 if it never was
+    synced_at: AtomicU64,
+    // The one of the cache the value is in
+    clock: Arc<dyn Clock>,
 }
 
 impl CachedCounterValue {
-    pub fn from_authority(counter: &Counter, value: u64) -> Self {
-        let now = SystemTime::now();
+    pub fn from_authority(counter: &Counter, value: u64, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now();
         Self {
             value: AtomicExpiringValue::new(value, now + counter.window()),
             initial_value: AtomicU64::new(value),
             from_authority: AtomicBool::new(true),
+            synced_at: AtomicU64::new(millis_since_epoch(now)),
+            clock,
         }
     }
 
-    pub fn load_from_authority_asap(counter: &Counter, temp_value: u64) -> Self {
-        let now = SystemTime::now();
+    pub fn load_from_authority_asap(
+        counter: &Counter,
+        temp_value: u64,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
         Self {
             value: AtomicExpiringValue::new(temp_value, now + counter.window()),
             initial_value: AtomicU64::new(0),
             from_authority: AtomicBool::new(false),
+            synced_at: AtomicU64::new(0),
+            clock,
         }
     }
 
@@ -48,14 +62,46 @@ impl CachedCounterValue {
         if new_val > max_value {
             histogram!("counter_overshoot").record((new_val - max_value) as f64);
         }
-        self.initial_value.fetch_add(delta, Ordering::SeqCst);
+        self.add_to_initial_value(delta);
+        self.synced_at
+            .store(millis_since_epoch(self.clock.now()), Ordering::Release);
         self.from_authority.store(true, Ordering::Release);
     }
 
+    // Catches up with the value the authority holds, as last written by
+    // another instance, when it is ahead of the one we know of
+    pub fn refresh_from_authority(&self, value: u64, expire_at: SystemTime) {
+        let known = self.initial_value.load(Ordering::SeqCst);
+        if let Some(delta) = value.checked_sub(known).filter(|delta| *delta > 0) {
+            self.value.add_and_set_expiry(delta, expire_at);
+            self.add_to_initial_value(delta);
+            self.synced_at
+                .store(millis_since_epoch(self.clock.now()), Ordering::Release);
+        }
+    }
+
+    // Takes the `delta`, refunded to the authority, back from the value, as
+    // well as from the one known of the authority, leaving the pending writes
+    // be. Whatever couldn't be taken from the latter is from the pending writes
+    pub fn refund(&self, delta: u64) {
+        self.value.refund(delta, self.clock.now());
+        let _ = self
+            .initial_value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_sub(delta))
+            });
+    }
+
+    fn add_to_initial_value(&self, delta: u64) {
+        let _ = self
+            .initial_value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some(value.saturating_add(delta))
+            });
+    }
+
     pub fn delta(&self, counter: &Counter, delta: u64) -> u64 {
-        let value = self
-            .value
-            .update(delta, counter.window(), SystemTime::now());
+        let value = self.value.update(delta, counter.window(), self.clock.now());
         if value == delta {
             // new window, invalidate initial value
             // which happens _after_ the self.value was reset, see `pending_writes`
@@ -70,7 +116,7 @@ impl CachedCounterValue {
 
     pub fn pending_writes_and_value(&self) -> Result<(u64, u64), ()> {
         let start = self.initial_value.load(Ordering::SeqCst);
-        let value = self.value.value_at(SystemTime::now());
+        let value = self.value.value_at(self.clock.now());
         let offset = if start == 0 {
             value
         } else {
@@ -99,7 +145,7 @@ impl CachedCounterValue {
 
     fn no_pending_writes(&self) -> bool {
         let start = self.initial_value.load(Ordering::SeqCst);
-        let value = self.value.value_at(SystemTime::now());
+        let value = self.value.value_at(self.clock.now());
         value - start == 0
     }
 
@@ -120,11 +166,11 @@ impl CachedCounterValue {
     }
 
     pub fn hits(&self, _: &Counter) -> u64 {
-        self.value.value_at(SystemTime::now())
+        self.value.value_at(self.clock.now())
     }
 
     pub fn remaining(&self, counter: &Counter) -> u64 {
-        counter.max_value() - self.hits(counter)
+        counter.max_value().saturating_sub(self.hits(counter))
     }
 
     pub fn is_limited(&self, counter: &Counter, delta: u64) -> bool {
@@ -132,18 +178,43 @@ impl CachedCounterValue {
     }
 
     pub fn ttl(&self) -> Duration {
-        self.value.ttl()
+        self.value.ttl_at(self.clock.now())
     }
 
     pub fn requires_fast_flush(&self, within: &Duration) -> bool {
-        self.from_authority.load(Ordering::Acquire).not() || &self.value.ttl() <= within
+        self.from_authority.load(Ordering::Acquire).not() || &self.ttl() <= within
+    }
+
+    pub fn is_stale(&self, max_staleness: &Duration) -> bool {
+        let synced_at = self.synced_at.load(Ordering::Acquire);
+        let age = millis_since_epoch(self.clock.now()).saturating_sub(synced_at);
+        age > max_staleness.as_millis() as u64
+    }
+}
+
+impl fmt::Debug for CachedCounterValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedCounterValue")
+            .field("value", &self.value)
+            .field("initial_value", &self.initial_value)
+            .field("from_authority", &self.from_authority)
+            .field("synced_at", &self.synced_at)
+            .finish_non_exhaustive()
     }
 }
 
+fn millis_since_epoch(when: SystemTime) -> u64 {
+    when.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 pub struct Batcher {
     updates: DashMap<Counter, Arc<CachedCounterValue>>,
     notifier: Notify,
-    interval: Duration,
+    // in microseconds, as it can be adapted while flushing
+    interval: AtomicU64,
+    max_staleness: Option<Duration>,
     priority_flush: AtomicBool,
     limiter: Semaphore,
 }
@@ -153,14 +224,15 @@ impl Batcher {
         Self {
             updates: Default::default(),
             notifier: Default::default(),
-            interval: period,
+            interval: AtomicU64::new(period.as_micros() as u64),
+            max_staleness: None,
             priority_flush: AtomicBool::new(false),
             limiter: Semaphore::new(max_cached_counters),
         }
     }
 
     pub async fn add(&self, counter: Counter, value: Arc<CachedCounterValue>) {
-        let priority = value.requires_fast_flush(&self.interval);
+        let priority = self.requires_fast_flush(&value);
         match self.updates.entry(counter.clone()) {
             Entry::Occupied(needs_merge) => {
                 let arc = needs_merge.get();
@@ -192,7 +264,7 @@ impl Batcher {
                 batch.extend(
                     self.updates
                         .iter()
-                        .filter(|entry| entry.value().requires_fast_flush(&self.interval))
+                        .filter(|entry| self.requires_fast_flush(entry.value()))
                         .take(max)
                         .map(|e| e.key().clone()),
                 );
@@ -231,12 +303,42 @@ impl Batcher {
                         info!("Priority flush!");
                         true
                     },
-                    _ = tokio::time::sleep(self.interval) => true,
+                    _ = tokio::time::sleep(self.interval()) => true,
                 }
             }
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// How long a batch waits to fill up, before being consumed anyways
+    pub fn interval(&self) -> Duration {
+        Duration::from_micros(self.interval.load(Ordering::Acquire))
+    }
+
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval
+            .store(interval.as_micros() as u64, Ordering::Release);
+    }
+
+    /// Has the next batch consumed right away, without waiting for the period
+    /// to elapse or for the batch to fill up
+    pub fn flush_soon(&self) {
+        self.priority_flush.store(true, Ordering::Release);
+        self.notifier.notify_one();
+    }
+
+    // Values that are about to expire, or were read from the authority longer
+    // ago than tolerated, get flushed first, which also refreshes them
+    fn requires_fast_flush(&self, value: &CachedCounterValue) -> bool {
+        value.requires_fast_flush(&self.interval())
+            || self
+                .max_staleness
+                .is_some_and(|max_staleness| value.is_stale(&max_staleness))
+    }
+
     fn batch_ready(&self, size: usize) -> bool {
         self.updates.len() >= size
             || self
@@ -255,6 +357,7 @@ impl Default for Batcher {
 pub struct CountersCache {
     cache: Cache<Counter, Arc<CachedCounterValue>>,
     batcher: Batcher,
+    clock: Arc<dyn Clock>,
 }
 
 impl CountersCache {
@@ -275,6 +378,10 @@ impl CountersCache {
         &self.batcher
     }
 
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
     pub fn return_pending_writes(
         &self,
         counter: &Counter,
@@ -288,7 +395,11 @@ impl CountersCache {
                     entry.value().clone()
                 } else {
                     miss = true;
-                    let value = Arc::new(CachedCounterValue::from_authority(counter, value));
+                    let value = Arc::new(CachedCounterValue::from_authority(
+                        counter,
+                        value,
+                        Arc::clone(&self.clock),
+                    ));
                     value.delta(counter, writes);
                     value
                 }
@@ -307,7 +418,7 @@ impl CountersCache {
         remote_deltas: u64,
         expiry: SystemTime,
     ) -> Arc<CachedCounterValue> {
-        if expiry > SystemTime::now() {
+        if expiry > self.clock.now() {
             let mut from_cache = true;
             let cached = self.cache.get_with(counter.clone(), || {
                 from_cache = false;
@@ -317,7 +428,11 @@ impl CountersCache {
                     cached_value.add_from_authority(remote_deltas, expiry, counter.max_value());
                     cached_value.clone()
                 } else {
-                    Arc::new(CachedCounterValue::from_authority(&counter, redis_val))
+                    Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        redis_val,
+                        Arc::clone(&self.clock),
+                    ))
                 }
             });
             if from_cache {
@@ -326,17 +441,49 @@ impl CountersCache {
             return cached;
         }
         Arc::new(CachedCounterValue::load_from_authority_asap(
-            &counter, redis_val,
+            &counter,
+            redis_val,
+            Arc::clone(&self.clock),
         ))
     }
 
+    /// Refreshes the cached value of the `counter` with the one another
+    /// instance wrote to Redis. Values with pending writes are left alone, as
+    /// flushing them brings the remote writes in anyway.
+    pub fn refresh(&self, counter: &Counter, redis_val: u64, expiry: SystemTime) {
+        if expiry <= self.clock.now() || self.batcher.updates.contains_key(counter) {
+            return;
+        }
+        if let Some(cached) = self.cache.get(counter) {
+            cached.refresh_from_authority(redis_val, expiry);
+        }
+    }
+
+    /// Takes the `delta`, refunded to Redis, back from the cached value of the
+    /// `counter`, if any
+    pub fn refund(&self, counter: &Counter, delta: u64) {
+        if let Some(cached) = self.get(counter) {
+            cached.refund(counter.delta(delta));
+        }
+    }
+
+    /// Forgets about the `counter`, along with its writes not flushed yet
+    pub fn remove(&self, counter: &Counter) {
+        self.batcher.updates.remove(counter);
+        self.cache.invalidate(counter);
+    }
+
     pub async fn increase_by(&self, counter: &Counter, delta: u64) {
         let val = self.cache.get_with_by_ref(counter, || {
             gauge!("cache_size").increment(1);
             if let Some(entry) = self.batcher.updates.get(counter) {
                 entry.value().clone()
             } else {
-                Arc::new(CachedCounterValue::load_from_authority_asap(counter, 0))
+                Arc::new(CachedCounterValue::load_from_authority_asap(
+                    counter,
+                    0,
+                    Arc::clone(&self.clock),
+                ))
             }
         });
         val.delta(counter, delta);
@@ -346,12 +493,16 @@ impl CountersCache {
 
 pub struct CountersCacheBuilder {
     max_cached_counters: usize,
+    max_staleness: Option<Duration>,
+    clock: Arc<dyn Clock>,
 }
 
 impl CountersCacheBuilder {
     pub fn new() -> Self {
         Self {
             max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
+            max_staleness: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
@@ -360,6 +511,17 @@ impl CountersCacheBuilder {
         self
     }
 
+    pub fn max_staleness(mut self, max_staleness: Option<Duration>) -> Self {
+        self.max_staleness = max_staleness;
+        self
+    }
+
+    /// The clock the windows of the cached counters are measured against
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     fn eviction_listener(
         _key: Arc<Counter>,
         value: Arc<CachedCounterValue>,
@@ -372,12 +534,15 @@ impl CountersCacheBuilder {
     }
 
     pub fn build(&self, period: Duration) -> CountersCache {
+        let mut batcher = Batcher::new(period, self.max_cached_counters);
+        batcher.max_staleness = self.max_staleness;
         CountersCache {
             cache: Cache::builder()
                 .max_capacity(self.max_cached_counters as u64)
                 .eviction_listener(Self::eviction_listener)
                 .build(),
-            batcher: Batcher::new(period, self.max_cached_counters),
+            batcher,
+            clock: Arc::clone(&self.clock),
         }
     }
 }
@@ -395,13 +560,15 @@ mod tests {
         use std::ops::{Add, Not};
         use std::time::{Duration, SystemTime};
 
+        use crate::clock::{ManualClock, SystemClock};
         use crate::storage::redis::counters_cache::tests::test_counter;
         use crate::storage::redis::counters_cache::CachedCounterValue;
+        use std::sync::Arc;
 
         #[test]
         fn records_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             assert_eq!(value.pending_writes(), Ok(0));
             value.delta(&counter, 5);
             assert_eq!(value.pending_writes(), Ok(5));
@@ -410,7 +577,7 @@ mod tests {
         #[test]
         fn consumes_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, 5);
             assert_eq!(value.pending_writes(), Ok(5));
             assert_eq!(value.pending_writes(), Ok(0));
@@ -419,7 +586,7 @@ mod tests {
         #[test]
         fn no_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, 5);
             assert!(value.no_pending_writes().not());
             assert!(value.pending_writes().is_ok());
@@ -429,7 +596,7 @@ mod tests {
         #[test]
         fn adding_from_auth_not_affecting_pending_writes() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, 5);
             assert!(value.no_pending_writes().not());
             value.add_from_authority(
@@ -444,30 +611,46 @@ mod tests {
         #[test]
         fn from_authority_no_need_to_flush() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             assert!(value.requires_fast_flush(&Duration::from_secs(30)).not());
         }
 
         #[test]
         fn from_authority_needs_to_flush_within_ttl() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             assert!(value.requires_fast_flush(&Duration::from_secs(90)));
         }
 
         #[test]
         fn fake_needs_to_flush_within_ttl() {
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::load_from_authority_asap(&counter, 0);
+            let value =
+                CachedCounterValue::load_from_authority_asap(&counter, 0, Arc::new(SystemClock));
             assert!(value.requires_fast_flush(&Duration::from_secs(30)));
         }
 
+        #[test]
+        fn from_authority_is_fresh() {
+            let counter = test_counter(10, None);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
+            assert!(value.is_stale(&Duration::from_secs(30)).not());
+        }
+
+        #[test]
+        fn fake_is_stale() {
+            let counter = test_counter(10, None);
+            let value =
+                CachedCounterValue::load_from_authority_asap(&counter, 0, Arc::new(SystemClock));
+            assert!(value.is_stale(&Duration::from_secs(30)));
+        }
+
         #[test]
         fn delegates_to_underlying_value() {
             let hits = 4;
 
             let counter = test_counter(10, None);
-            let value = CachedCounterValue::from_authority(&counter, 0);
+            let value = CachedCounterValue::from_authority(&counter, 0, Arc::new(SystemClock));
             value.delta(&counter, hits);
             assert!(value.ttl() > Duration::from_millis(59999));
             assert_eq!(value.hits(&counter), hits);
@@ -477,12 +660,24 @@ mod tests {
             assert!(value.is_limited(&counter, remaining).not());
             assert!(value.is_limited(&counter, remaining + 1));
         }
+
+        #[test]
+        fn expires_as_of_its_clock() {
+            let clock = Arc::new(ManualClock::new(SystemTime::now()));
+            let counter = test_counter(10, None);
+            let value = CachedCounterValue::from_authority(&counter, 0, clock.clone());
+            value.delta(&counter, 5);
+            assert_eq!(value.hits(&counter), 5);
+            clock.advance(counter.window());
+            assert_eq!(value.hits(&counter), 0);
+        }
     }
 
     mod batcher {
         use std::sync::Arc;
         use std::time::{Duration, SystemTime};
 
+        use crate::clock::SystemClock;
         use crate::storage::redis::counters_cache::tests::test_counter;
         use crate::storage::redis::counters_cache::{Batcher, CachedCounterValue};
         use crate::storage::redis::DEFAULT_MAX_CACHED_COUNTERS;
@@ -512,7 +707,11 @@ mod tests {
                 tokio::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(40)).await;
                     let counter = test_counter(6, None);
-                    let arc = Arc::new(CachedCounterValue::from_authority(&counter, 0));
+                    let arc = Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
                     batcher.add(counter, arc).await;
                 });
             }
@@ -539,7 +738,11 @@ mod tests {
                 tokio::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(40)).await;
                     let counter = test_counter(6, None);
-                    let arc = Arc::new(CachedCounterValue::from_authority(&counter, 0));
+                    let arc = Arc::new(CachedCounterValue::from_authority(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
                     batcher.add(counter, arc).await;
                 });
             }
@@ -562,7 +765,11 @@ mod tests {
             let start = SystemTime::now();
             {
                 let counter = test_counter(6, None);
-                let arc = Arc::new(CachedCounterValue::from_authority(&counter, 0));
+                let arc = Arc::new(CachedCounterValue::from_authority(
+                    &counter,
+                    0,
+                    Arc::new(SystemClock),
+                ));
                 batcher.add(counter, arc).await;
             }
             batcher
@@ -588,7 +795,11 @@ mod tests {
                 tokio::spawn(async move {
                     tokio::time::sleep(Duration::from_millis(40)).await;
                     let counter = test_counter(6, None);
-                    let arc = Arc::new(CachedCounterValue::load_from_authority_asap(&counter, 0));
+                    let arc = Arc::new(CachedCounterValue::load_from_authority_asap(
+                        &counter,
+                        0,
+                        Arc::new(SystemClock),
+                    ));
                     batcher.add(counter, arc).await;
                 });
             }
@@ -670,6 +881,40 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn refresh_only_applies_without_pending_writes() {
+        let counter = test_counter(10, None);
+        let expiry = SystemTime::now().add(Duration::from_secs(1));
+
+        let cache = CountersCacheBuilder::new().build(Duration::default());
+        cache.apply_remote_delta(counter.clone(), 2, 0, expiry);
+        cache.refresh(&counter, 5, expiry);
+        assert_eq!(cache.get(&counter).map(|e| e.hits(&counter)).unwrap(), 5);
+
+        // Stale updates are ignored
+        cache.refresh(&counter, 3, expiry);
+        assert_eq!(cache.get(&counter).map(|e| e.hits(&counter)).unwrap(), 5);
+
+        cache.increase_by(&counter, 1).await;
+        cache.refresh(&counter, 8, expiry);
+        assert_eq!(cache.get(&counter).map(|e| e.hits(&counter)).unwrap(), 6);
+    }
+
+    #[tokio::test]
+    async fn refunds_keep_pending_writes() {
+        let counter = test_counter(10, None);
+        let expiry = SystemTime::now().add(Duration::from_secs(1));
+
+        let cache = CountersCacheBuilder::new().build(Duration::default());
+        cache.apply_remote_delta(counter.clone(), 5, 0, expiry);
+        cache.increase_by(&counter, 2).await;
+        cache.refund(&counter, 3);
+
+        let cached = cache.get(&counter).unwrap();
+        assert_eq!(cached.hits(&counter), 4);
+        assert_eq!(cached.pending_writes(), Ok(2));
+    }
+
     fn test_counter(max_val: u64, other_values: Option<HashMap<String, String>>) -> Counter {
         let mut values = HashMap::new();
         values.insert("app_id".to_string(), "1".to_string());
@@ -1,23 +1,36 @@
 use ::redis::RedisError;
 use std::time::Duration;
 
+mod broadcast;
+mod config;
 mod counters_cache;
+mod pool;
 mod redis_async;
 mod redis_cached;
 mod redis_sync;
 mod scripts;
+mod sentinel;
+mod write_behind;
 
 pub const DEFAULT_FLUSHING_PERIOD_SEC: u64 = 1;
 pub const DEFAULT_BATCH_SIZE: usize = 100;
 pub const DEFAULT_MAX_CACHED_COUNTERS: usize = 10000;
 pub const DEFAULT_RESPONSE_TIMEOUT_MS: u64 = 350;
+pub const DEFAULT_BROADCAST_PERIOD_MS: u64 = 100;
+pub const DEFAULT_BROADCAST_CHANNEL: &str = "limitador:counter_deltas";
 
 use crate::counter::Counter;
-use crate::storage::{Authorization, StorageErr};
+pub use crate::storage::keys::KeyCodec;
+use crate::storage::{invalid_cursor, parse_cursor, Authorization, StorageErr};
+pub use broadcast::BroadcastRedisStorage;
+pub use broadcast::BroadcastRedisStorageBuilder;
+pub use config::RedisConnectionConfig;
 pub use redis_async::AsyncRedisStorage;
 pub use redis_cached::CachedRedisStorage;
 pub use redis_cached::CachedRedisStorageBuilder;
 pub use redis_sync::RedisStorage;
+pub use write_behind::WriteBehindRedisStorage;
+pub use write_behind::WriteBehindRedisStorageBuilder;
 
 impl From<RedisError> for StorageErr {
     fn from(e: RedisError) -> Self {
@@ -49,9 +62,9 @@ pub fn is_limited(
     let mut first_limited = None;
     for (i, counter) in counters.iter_mut().enumerate() {
         // remaining  = max - (curr_val + delta)
-        let remaining = counter
-            .max_value()
-            .checked_sub((counter_vals[i].unwrap_or(0) as u64) + delta);
+        let remaining = counter.max_value().checked_sub(
+            (counter_vals[i].unwrap_or(0) as u64).saturating_add(counter.delta(delta)),
+        );
         counter.set_remaining(remaining.unwrap_or_default());
         let expires_in = counter_ttls_msecs[i]
             .map(|x| {
@@ -65,10 +78,35 @@ pub fn is_limited(
 
         counter.set_expires_in(expires_in);
         if first_limited.is_none() && remaining.is_none() {
-            first_limited = Some(Authorization::Limited(
-                counter.limit().name().map(|n| n.to_owned()),
-            ))
+            first_limited = Some(Authorization::limited_by(counter))
         }
     }
     first_limited
 }
+
+// The horizon of an idempotency token, as the PX of the SET recording it, which
+// has to be positive
+fn horizon_millis(horizon: Duration) -> u64 {
+    u64::try_from(horizon.as_millis())
+        .unwrap_or(u64::MAX)
+        .max(1)
+}
+
+// A delta as the argument of an INCRBY, which Redis only takes when it fits an
+// i64, the scripts then saturating the counters at that
+fn incr_delta(delta: u64) -> u64 {
+    delta.min(i64::MAX as u64)
+}
+
+// The index of the limit to resume scanning the counters of, and the SSCAN
+// cursor to resume from
+fn parse_scan_cursor(cursor: Option<&str>) -> Result<(usize, u64), StorageErr> {
+    let (index, position) = parse_cursor(cursor)?;
+    if position.is_empty() {
+        return Ok((index, 0));
+    }
+    match position.parse() {
+        Ok(scan_cursor) => Ok((index, scan_cursor)),
+        Err(_) => Err(invalid_cursor(cursor.unwrap_or_default())),
+    }
+}
@@ -0,0 +1,106 @@
+use metrics::{gauge, histogram};
+use redis::aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig};
+use redis::{Client, Cmd, ErrorKind, Pipeline, RedisError, RedisResult, Value};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// A fixed set of connections to a single Redis server. Every command checks
+// one out for its own use, waiting for one to be returned when they are all
+// busy, so that concurrent requests don't queue up behind each other on the
+// same connection.
+pub(super) struct ConnectionPool {
+    idle: Mutex<Vec<ConnectionManager>>,
+    permits: Semaphore,
+    db: i64,
+}
+
+impl ConnectionPool {
+    pub async fn new(
+        client: Client,
+        config: ConnectionManagerConfig,
+        size: usize,
+    ) -> RedisResult<Self> {
+        if size == 0 {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "The connection pool needs at least one connection",
+            )));
+        }
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            idle.push(ConnectionManager::new_with_config(client.clone(), config.clone()).await?);
+        }
+        Ok(Self {
+            idle: Mutex::new(idle),
+            permits: Semaphore::new(size),
+            db: client.get_connection_info().redis.db,
+        })
+    }
+
+    pub async fn req_packed_command(&self, cmd: &Cmd) -> RedisResult<Value> {
+        self.checkout().await.conn().req_packed_command(cmd).await
+    }
+
+    pub async fn req_packed_commands(
+        &self,
+        cmd: &Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        self.checkout()
+            .await
+            .conn()
+            .req_packed_commands(cmd, offset, count)
+            .await
+    }
+
+    pub fn get_db(&self) -> i64 {
+        self.db
+    }
+
+    async fn checkout(&self) -> PooledConnection<'_> {
+        let start = Instant::now();
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the pool's semaphore is never closed");
+        histogram!("datastore_pool_wait_time").record(start.elapsed().as_secs_f64());
+        gauge!("datastore_pool_connections_in_use").increment(1);
+        let conn = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit guarantees an idle connection");
+        PooledConnection {
+            pool: self,
+            conn: Some(conn),
+            _permit: permit,
+        }
+    }
+}
+
+// Returns the connection to the pool when dropped, even if the command using it
+// got cancelled
+struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    conn: Option<ConnectionManager>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledConnection<'_> {
+    fn conn(&mut self) -> &mut ConnectionManager {
+        self.conn.as_mut().expect("only taken when dropped")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+        }
+        gauge!("datastore_pool_connections_in_use").decrement(1);
+    }
+}
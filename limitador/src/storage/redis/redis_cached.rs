@@ -1,5 +1,6 @@
+use crate::clock::{Clock, SystemClock};
 use crate::counter::Counter;
-use crate::limit::Limit;
+use crate::limit::{Limit, Namespace};
 use crate::storage::keys::*;
 use crate::storage::redis::counters_cache::{
     CachedCounterValue, CountersCache, CountersCacheBuilder,
@@ -7,27 +8,28 @@ use crate::storage::redis::counters_cache::{
 use crate::storage::redis::redis_async::AsyncRedisStorage;
 use crate::storage::redis::scripts::BATCH_UPDATE_COUNTERS;
 use crate::storage::redis::{
-    DEFAULT_BATCH_SIZE, DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS,
-    DEFAULT_RESPONSE_TIMEOUT_MS,
+    incr_delta, RedisConnectionConfig, DEFAULT_BATCH_SIZE, DEFAULT_FLUSHING_PERIOD_SEC,
+    DEFAULT_MAX_CACHED_COUNTERS, DEFAULT_RESPONSE_TIMEOUT_MS,
 };
-use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use crate::storage::{AsyncCounterStorage, Authorization, CounterFilter, CountersPage, StorageErr};
 use async_trait::async_trait;
 use metrics::gauge;
 use redis::aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig};
-use redis::{ConnectionInfo, RedisError};
+use redis::{Client, RedisError};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_stream::StreamExt;
 use tracing::{error, info, info_span, warn, Instrument};
 
 // This is just a first version.
 //
 // The idea is to improve throughput and latencies by caching the limits and
 // counters in memory to reduce the number of accesses to Redis.
-// For now, only the "check_and_update" function uses caching, the rest of
-// functions simply delegate the work to another storage implementation.
+// For now, only the "check_and_update" and "peek" functions use caching, the
+// rest of functions simply delegate the work to another storage implementation.
 //
 // There might be several instances of Limitador accessing the same Redis server
 // at the same time. This means that cached values might not reflect updates
@@ -36,10 +38,19 @@ use tracing::{error, info, info_span, warn, Instrument};
 // rate-limit accuracy. We can go over limits, but the amount can be configured
 // by tuning the constants below.
 //
+// To shrink the window during which cached values are stale, the instances can
+// publish the values they got from Redis when flushing on a pub/sub channel,
+// for the others to refresh their cached values with.
+//
 // Future improvements:
 // - Introduce a mechanism to avoid going to Redis to fetch the same counter
 // multiple times when it is not cached.
 
+const FLUSH_POLLING_PERIOD: Duration = Duration::from_millis(10);
+
+// The share of the recent flushes that can fail, before the flushing backs off
+const MAX_FLUSH_ERROR_RATE: f64 = 0.1;
+
 pub struct CachedRedisStorage {
     cached_counters: Arc<CountersCache>,
     async_redis_storage: AsyncRedisStorage,
@@ -61,6 +72,34 @@ impl AsyncCounterStorage for CachedRedisStorage {
             .await
     }
 
+    #[tracing::instrument(skip_all)]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.async_redis_storage
+            .refund_counter(counter, delta)
+            .await?;
+        self.cached_counters.refund(counter, delta);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        self.async_redis_storage
+            .claim_token(namespace, token, horizon)
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        self.async_redis_storage
+            .release_token(namespace, token)
+            .await
+    }
+
     // Notice that this method does not guarantee 100% accuracy when applying the
     // limits. In order to do so, we'd need to run this whole function
     // atomically, but that'd be too slow.
@@ -77,11 +116,11 @@ impl AsyncCounterStorage for CachedRedisStorage {
 
         // Check cached counters
         for counter in counters.iter_mut() {
+            let delta = counter.delta(delta);
             match self.cached_counters.get(counter) {
                 Some(val) => {
                     if first_limited.is_none() && val.is_limited(counter, delta) {
-                        let a =
-                            Authorization::Limited(counter.limit().name().map(|n| n.to_owned()));
+                        let a = Authorization::limited_by(counter);
                         if !load_counters {
                             return Ok(a);
                         }
@@ -105,15 +144,18 @@ impl AsyncCounterStorage for CachedRedisStorage {
         // Fetch non-cached counters, cache them, and check them
         if !not_cached.is_empty() {
             for counter in not_cached.iter_mut() {
-                let fake = CachedCounterValue::load_from_authority_asap(counter, 0);
+                let delta = counter.delta(delta);
+                let fake = CachedCounterValue::load_from_authority_asap(
+                    counter,
+                    0,
+                    Arc::clone(self.cached_counters.clock()),
+                );
                 let remaining = fake.remaining(counter);
                 if first_limited.is_none() && remaining == 0 {
-                    first_limited = Some(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
+                    first_limited = Some(Authorization::limited_by(counter));
                 }
                 if load_counters {
-                    counter.set_remaining(remaining - delta);
+                    counter.set_remaining(remaining.saturating_sub(delta));
                     counter.set_expires_in(fake.ttl()); // todo: this is a plain lie!
                 }
             }
@@ -125,12 +167,56 @@ impl AsyncCounterStorage for CachedRedisStorage {
 
         // Update cached values
         for counter in counters.iter() {
-            self.cached_counters.increase_by(counter, delta).await;
+            self.cached_counters
+                .increase_by(counter, counter.delta(delta))
+                .await;
         }
 
         Ok(Authorization::Ok)
     }
 
+    #[tracing::instrument(skip_all)]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        let mut first_limited = None;
+        let mut not_cached = vec![];
+
+        for (i, counter) in counters.iter_mut().enumerate() {
+            let delta = counter.delta(delta);
+            match self.cached_counters.get(counter) {
+                Some(val) => {
+                    counter.set_remaining(
+                        val.remaining(counter)
+                            .checked_sub(delta)
+                            .unwrap_or_default(),
+                    );
+                    counter.set_expires_in(val.ttl());
+                    if first_limited.is_none() && val.is_limited(counter, delta) {
+                        first_limited = Some(Authorization::limited_by(counter));
+                    }
+                }
+                None => not_cached.push(i),
+            }
+        }
+
+        if !not_cached.is_empty() {
+            let mut uncached: Vec<Counter> =
+                not_cached.iter().map(|&i| counters[i].clone()).collect();
+            let authorization = self.async_redis_storage.peek(&mut uncached, delta).await?;
+            if first_limited.is_none() && matches!(authorization, Authorization::Limited(_)) {
+                first_limited = Some(authorization);
+            }
+            for (i, counter) in not_cached.into_iter().zip(uncached) {
+                counters[i] = counter;
+            }
+        }
+
+        Ok(first_limited.unwrap_or(Authorization::Ok))
+    }
+
     #[tracing::instrument(skip_all)]
     async fn get_counters(
         &self,
@@ -139,6 +225,25 @@ impl AsyncCounterStorage for CachedRedisStorage {
         self.async_redis_storage.get_counters(limits).await
     }
 
+    #[tracing::instrument(skip_all)]
+    async fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        self.async_redis_storage
+            .get_counters_page(limits, filter, cursor, page_size)
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.cached_counters.remove(counter);
+        self.async_redis_storage.delete_counter(counter).await
+    }
+
     #[tracing::instrument(skip_all)]
     async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
         self.async_redis_storage.delete_counters(limits).await
@@ -148,6 +253,18 @@ impl AsyncCounterStorage for CachedRedisStorage {
     async fn clear(&self) -> Result<(), StorageErr> {
         self.async_redis_storage.clear().await
     }
+
+    // The flushing task does the actual writes, and keeps on retrying while
+    // Redis is unreachable, so callers should bound this with a timeout
+    #[tracing::instrument(skip_all)]
+    async fn flush(&self) -> Result<(), StorageErr> {
+        let batcher = self.cached_counters.batcher();
+        while !batcher.is_empty() {
+            batcher.flush_soon();
+            tokio::time::sleep(FLUSH_POLLING_PERIOD).await;
+        }
+        Ok(())
+    }
 }
 
 impl CachedRedisStorage {
@@ -157,31 +274,47 @@ impl CachedRedisStorage {
             DEFAULT_BATCH_SIZE,
             Duration::from_secs(DEFAULT_FLUSHING_PERIOD_SEC),
             DEFAULT_MAX_CACHED_COUNTERS,
+            None,
             Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS),
+            &RedisConnectionConfig::default(),
+            None,
+            None,
+            Arc::new(SystemClock),
         )
         .await
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn new_with_options(
         redis_url: &str,
         batch_size: usize,
         flushing_period: Duration,
         max_cached_counters: usize,
+        max_staleness: Option<Duration>,
         response_timeout: Duration,
+        config: &RedisConnectionConfig,
+        updates_channel: Option<String>,
+        max_flushing_period: Option<Duration>,
+        clock: Arc<dyn Clock>,
     ) -> Result<Self, RedisError> {
-        let info = ConnectionInfo::from_str(redis_url)?;
+        let client = config.client(redis_url)?;
         let redis_conn_manager = ConnectionManager::new_with_config(
-            redis::Client::open(info)
-                .expect("This couldn't fail in the past, yet now it did somehow!"),
+            client.clone(),
             ConnectionManagerConfig::default()
-                .set_connection_timeout((response_timeout * 3) + Duration::from_millis(50))
-                .set_response_timeout(response_timeout)
+                .set_connection_timeout(
+                    config
+                        .connection_timeout
+                        .unwrap_or((response_timeout * 3) + Duration::from_millis(50)),
+                )
+                .set_response_timeout(config.response_timeout.unwrap_or(response_timeout))
                 .set_number_of_retries(1),
         )
         .await?;
 
         let cached_counters = CountersCacheBuilder::new()
             .max_cached_counters(max_cached_counters)
+            .max_staleness(max_staleness)
+            .clock(clock)
             .build(flushing_period);
 
         let counters_cache = Arc::new(cached_counters);
@@ -189,23 +322,44 @@ impl CachedRedisStorage {
         let async_redis_storage =
             AsyncRedisStorage::new_with_conn_manager(redis_conn_manager.clone()).await?;
 
+        record_flushing_pace(flushing_period, batch_size);
+        // Flushes taking more than half the time Redis has to respond are slow
+        let slow_flush = config.response_timeout.unwrap_or(response_timeout) / 2;
+        let mut pacer = max_flushing_period
+            .map(|max_period| FlushPacer::new(flushing_period, max_period, batch_size, slow_flush));
+
         {
             let counters_cache_clone = counters_cache.clone();
             let conn = redis_conn_manager.clone();
             let p = Arc::clone(&partitioned);
+            let channel = updates_channel.clone();
             tokio::spawn(async move {
                 loop {
-                    flush_batcher_and_update_counters(
+                    let flushed = flush_batcher_and_update_counters(
                         conn.clone(),
                         counters_cache_clone.clone(),
                         p.clone(),
-                        batch_size,
+                        pacer.as_ref().map_or(batch_size, |pacer| pacer.batch_size),
+                        channel.as_deref(),
                     )
                     .await;
+                    if let (Some(pacer), Some(flushed)) = (pacer.as_mut(), flushed) {
+                        pacer.observe(flushed);
+                        counters_cache_clone.batcher().set_interval(pacer.period);
+                        record_flushing_pace(pacer.period, pacer.batch_size);
+                    }
                 }
             });
         }
 
+        if let Some(channel) = updates_channel {
+            tokio::spawn(refresh_from_updates(
+                client,
+                channel,
+                Arc::downgrade(&counters_cache),
+            ));
+        }
+
         async_redis_storage
             .load_script(BATCH_UPDATE_COUNTERS)
             .await?;
@@ -217,6 +371,64 @@ impl CachedRedisStorage {
     }
 }
 
+fn record_flushing_pace(period: Duration, batch_size: usize) {
+    gauge!("batcher_flushing_period").set(period.as_secs_f64());
+    gauge!("batcher_flushing_batch_size").set(batch_size as f64);
+}
+
+/// How a flush to Redis went
+struct Flush {
+    rtt: Duration,
+    failed: bool,
+}
+
+/// Adapts the flushing to how Redis copes: while flushes are slow or failing,
+/// the period doubles, up to its maximum, and the batches halve, tightening
+/// back to the configured ones once Redis is healthy again
+struct FlushPacer {
+    period: Duration,
+    batch_size: usize,
+    min_period: Duration,
+    max_period: Duration,
+    max_batch_size: usize,
+    slow: Duration,
+    // Moving averages of the round-trips and failures of the recent flushes
+    rtt: Option<Duration>,
+    error_rate: f64,
+}
+
+impl FlushPacer {
+    fn new(period: Duration, max_period: Duration, batch_size: usize, slow: Duration) -> Self {
+        Self {
+            period,
+            batch_size,
+            min_period: period,
+            max_period: max_period.max(period),
+            max_batch_size: batch_size,
+            slow,
+            rtt: None,
+            error_rate: 0.0,
+        }
+    }
+
+    fn observe(&mut self, flush: Flush) {
+        let rtt = match self.rtt {
+            Some(rtt) => (rtt * 3 + flush.rtt) / 4,
+            None => flush.rtt,
+        };
+        self.rtt = Some(rtt);
+        self.error_rate = self.error_rate * 0.75 + if flush.failed { 0.25 } else { 0.0 };
+
+        if rtt > self.slow || self.error_rate > MAX_FLUSH_ERROR_RATE {
+            self.period = (self.period * 2).min(self.max_period);
+            self.batch_size = (self.batch_size / 2).max(1);
+        } else {
+            self.period = (self.period / 2).max(self.min_period);
+            self.batch_size = (self.batch_size * 2).min(self.max_batch_size);
+        }
+    }
+}
+
 fn flip_partitioned(storage: &AtomicBool, partition: bool) -> bool {
     let we_flipped = storage
         .compare_exchange(!partition, partition, Ordering::Release, Ordering::Acquire)
@@ -233,12 +445,92 @@ fn flip_partitioned(storage: &AtomicBool, partition: bool) -> bool {
     we_flipped
 }
 
+// The values of the counters an instance got from Redis when flushing
+#[derive(Serialize, Deserialize)]
+struct CounterUpdate {
+    key: Vec<u8>,
+    value: u64,
+    // milliseconds since the epoch
+    expires_at: u64,
+}
+
+async fn publish_updates<C: ConnectionLike>(
+    redis_conn: &mut C,
+    channel: &str,
+    updated_counters: &[(Counter, u64, u64, SystemTime)],
+) -> Result<(), RedisError> {
+    if updated_counters.is_empty() {
+        return Ok(());
+    }
+    let updates: Vec<CounterUpdate> = updated_counters
+        .iter()
+        .map(|(counter, value, _, expires_at)| CounterUpdate {
+            key: key_for_counter(counter),
+            value: *value,
+            expires_at: expires_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+        })
+        .collect();
+    redis::cmd("PUBLISH")
+        .arg(channel)
+        .arg(postcard::to_stdvec(&updates).unwrap())
+        .query_async(redis_conn)
+        .instrument(info_span!("datastore"))
+        .await
+}
+
+// Refreshes the cached counters with the updates published by the other
+// instances, until the storage is dropped
+async fn refresh_from_updates(client: Client, channel: String, cache: Weak<CountersCache>) {
+    while cache.strong_count() > 0 {
+        if let Err(err) = subscribe_to_updates(&client, &channel, &cache).await {
+            warn!("Error receiving counter updates, will retry: {}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn subscribe_to_updates(
+    client: &Client,
+    channel: &str,
+    cache: &Weak<CountersCache>,
+) -> Result<(), RedisError> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        let Some(cache) = cache.upgrade() else {
+            return Ok(());
+        };
+        let updates: Vec<CounterUpdate> = match postcard::from_bytes(msg.get_payload_bytes()) {
+            Ok(updates) => updates,
+            Err(err) => {
+                warn!("Ignoring malformed counter updates: {}", err);
+                continue;
+            }
+        };
+        for update in updates {
+            let counter = partial_counter_from_counter_key(&update.key);
+            let expiry = UNIX_EPOCH + Duration::from_millis(update.expires_at);
+            cache.refresh(&counter, update.value, expiry);
+        }
+    }
+    Ok(())
+}
+
 pub struct CachedRedisStorageBuilder {
     redis_url: String,
     batch_size: usize,
     flushing_period: Duration,
     max_cached_counters: usize,
+    max_staleness: Option<Duration>,
     response_timeout: Duration,
+    connection_config: RedisConnectionConfig,
+    updates_channel: Option<String>,
+    max_flushing_period: Option<Duration>,
+    clock: Arc<dyn Clock>,
 }
 
 impl CachedRedisStorageBuilder {
@@ -248,7 +540,12 @@ impl CachedRedisStorageBuilder {
             batch_size: DEFAULT_BATCH_SIZE,
             flushing_period: Duration::from_secs(DEFAULT_FLUSHING_PERIOD_SEC),
             max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
+            max_staleness: None,
             response_timeout: Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS),
+            connection_config: RedisConnectionConfig::default(),
+            updates_channel: None,
+            max_flushing_period: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
@@ -267,18 +564,60 @@ impl CachedRedisStorageBuilder {
         self
     }
 
+    /// How long a counter value read from Redis can be relied upon, before
+    /// it gets refreshed ahead of the next flush. When not set, values only get
+    /// refreshed when flushed every `flushing_period`.
+    pub fn max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
     pub fn response_timeout(mut self, response_timeout: Duration) -> Self {
         self.response_timeout = response_timeout;
         self
     }
 
+    pub fn connection_config(mut self, connection_config: RedisConnectionConfig) -> Self {
+        self.connection_config = connection_config;
+        self
+    }
+
+    /// Pub/sub channel to publish the values read from Redis on, when
+    /// flushing, and to refresh the cached counters from, with the values the
+    /// other instances publish on it
+    pub fn updates_channel(mut self, channel: &str) -> Self {
+        self.updates_channel = Some(channel.to_string());
+        self
+    }
+
+    /// Has the flushing adapt to how Redis copes: backing off, up to flushing
+    /// every `max_flushing_period` in smaller batches, while it responds
+    /// slowly or errs, and back to the configured `flushing_period` and
+    /// `batch_size` once healthy again
+    pub fn adaptive_flushing(mut self, max_flushing_period: Duration) -> Self {
+        self.max_flushing_period = Some(max_flushing_period);
+        self
+    }
+
+    /// The clock the windows of the cached counters are measured against,
+    /// rather than the one of the system. Redis expires them on its own.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub async fn build(self) -> Result<CachedRedisStorage, RedisError> {
         CachedRedisStorage::new_with_options(
             &self.redis_url,
             self.batch_size,
             self.flushing_period,
             self.max_cached_counters,
+            self.max_staleness,
             self.response_timeout,
+            &self.connection_config,
+            self.updates_channel,
+            self.max_flushing_period,
+            self.clock,
         )
         .await
     }
@@ -306,7 +645,7 @@ async fn update_counters<C: ConnectionLike>(
                 script_invocation.key(key_for_counter(&counter));
                 script_invocation.key(key_for_counters_of_limit(counter.limit()));
                 script_invocation.arg(counter.window().as_secs());
-                script_invocation.arg(delta);
+                script_invocation.arg(incr_delta(delta));
                 // We need to store the counter in the actual order we are sending it to the script
                 res.push((counter, last_value_from_redis, delta, UNIX_EPOCH));
             }
@@ -350,16 +689,32 @@ async fn flush_batcher_and_update_counters<C: ConnectionLike>(
     cached_counters: Arc<CountersCache>,
     partitioned: Arc<AtomicBool>,
     batch_size: usize,
-) {
-    let updated_counters = cached_counters
-        .batcher()
-        .consume(batch_size, |counters| {
-            if !counters.is_empty() && !partitioned.load(Ordering::Acquire) {
-                info!("Flushing {} counter updates", counters.len());
-            }
-            update_counters(&mut redis_conn, counters)
-        })
-        .await
+    updates_channel: Option<&str>,
+) -> Option<Flush> {
+    let mut rtt = None;
+    let result = {
+        let conn = &mut redis_conn;
+        let rtt = &mut rtt;
+        cached_counters
+            .batcher()
+            .consume(batch_size, |counters| {
+                let flushing = !counters.is_empty();
+                if flushing && !partitioned.load(Ordering::Acquire) {
+                    info!("Flushing {} counter updates", counters.len());
+                }
+                async move {
+                    let started = Instant::now();
+                    let result = update_counters(conn, counters).await;
+                    if flushing {
+                        *rtt = Some(started.elapsed());
+                    }
+                    result
+                }
+            })
+            .await
+    };
+    let failed = result.is_err();
+    let updated_counters = result
         .map(|result| {
             flip_partitioned(&partitioned, false);
             result
@@ -392,20 +747,31 @@ async fn flush_batcher_and_update_counters<C: ConnectionLike>(
         })
         .expect("Unrecoverable Redis error!");
 
+    if let Some(channel) = updates_channel {
+        if let Err(err) = publish_updates(&mut redis_conn, channel, &updated_counters).await {
+            warn!("Error publishing counter updates: {}", err);
+        }
+    }
+
     for (counter, new_value, remote_deltas, ttl) in updated_counters {
         cached_counters.apply_remote_delta(counter, new_value, remote_deltas, ttl);
     }
+
+    rtt.map(|rtt| Flush { rtt, failed })
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::clock::SystemClock;
     use crate::counter::Counter;
     use crate::limit::Limit;
     use crate::storage::keys::{key_for_counter, key_for_counters_of_limit};
     use crate::storage::redis::counters_cache::{
         CachedCounterValue, CountersCache, CountersCacheBuilder,
     };
-    use crate::storage::redis::redis_cached::{flush_batcher_and_update_counters, update_counters};
+    use crate::storage::redis::redis_cached::{
+        flush_batcher_and_update_counters, update_counters, Flush, FlushPacer,
+    };
     use crate::storage::redis::CachedRedisStorage;
     use redis::{Cmd, ErrorKind, RedisError, Value};
     use redis_test::{MockCmd, MockRedisConnection};
@@ -416,6 +782,43 @@ mod tests {
     use std::sync::Arc;
     use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+    #[test]
+    fn flushing_backs_off_while_redis_is_degraded() {
+        let period = Duration::from_millis(100);
+        let mut pacer = FlushPacer::new(
+            period,
+            Duration::from_millis(1_000),
+            100,
+            Duration::from_millis(50),
+        );
+        let flush = |millis, failed| Flush {
+            rtt: Duration::from_millis(millis),
+            failed,
+        };
+
+        pacer.observe(flush(5, false));
+        assert_eq!((pacer.period, pacer.batch_size), (period, 100));
+
+        pacer.observe(flush(5, true));
+        assert_eq!(
+            (pacer.period, pacer.batch_size),
+            (Duration::from_millis(200), 50)
+        );
+        for _ in 0..10 {
+            pacer.observe(flush(500, false));
+        }
+        assert_eq!(
+            (pacer.period, pacer.batch_size),
+            (Duration::from_millis(1_000), 1)
+        );
+
+        // Tightens back, once the round-trips and failures are low again
+        for _ in 0..20 {
+            pacer.observe(flush(5, false));
+        }
+        assert_eq!((pacer.period, pacer.batch_size), (period, 100));
+    }
+
     #[tokio::test]
     async fn errs_on_bad_url() {
         let result = CachedRedisStorage::new("cassandra://127.0.0.1:6379").await;
@@ -457,6 +860,7 @@ mod tests {
         let arc = Arc::new(CachedCounterValue::from_authority(
             &counter,
             INITIAL_VALUE_FROM_REDIS,
+            Arc::new(SystemClock),
         ));
         arc.delta(&counter, LOCAL_INCREMENTS);
         counters_and_deltas.insert(counter.clone(), arc);
@@ -547,7 +951,11 @@ mod tests {
             .batcher()
             .add(
                 counter.clone(),
-                Arc::new(CachedCounterValue::load_from_authority_asap(&counter, 2)),
+                Arc::new(CachedCounterValue::load_from_authority_asap(
+                    &counter,
+                    2,
+                    Arc::new(SystemClock),
+                )),
             )
             .await;
 
@@ -558,8 +966,14 @@ mod tests {
             assert_eq!(c.hits(&counter), 2);
         }
 
-        flush_batcher_and_update_counters(mock_client, cached_counters.clone(), partitioned, 100)
-            .await;
+        flush_batcher_and_update_counters(
+            mock_client,
+            cached_counters.clone(),
+            partitioned,
+            100,
+            None,
+        )
+        .await;
 
         let c = cached_counters.get(&counter).unwrap();
         assert_eq!(c.hits(&counter), 8);
@@ -597,7 +1011,11 @@ mod tests {
         )]);
 
         let cache = CountersCacheBuilder::new().build(Duration::from_millis(10));
-        let value = Arc::new(CachedCounterValue::from_authority(&counter, 2));
+        let value = Arc::new(CachedCounterValue::from_authority(
+            &counter,
+            2,
+            Arc::new(SystemClock),
+        ));
         value.delta(&counter, 3);
         cache.batcher().add(counter.clone(), value).await;
 
@@ -608,8 +1026,14 @@ mod tests {
             assert_eq!(c.hits(&counter), 5);
         }
 
-        flush_batcher_and_update_counters(mock_client, cached_counters.clone(), partitioned, 100)
-            .await;
+        flush_batcher_and_update_counters(
+            mock_client,
+            cached_counters.clone(),
+            partitioned,
+            100,
+            None,
+        )
+        .await;
 
         let c = cached_counters.get(&counter).unwrap();
         assert_eq!(c.hits(&counter), 5);
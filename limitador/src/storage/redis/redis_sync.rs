@@ -1,12 +1,16 @@
 extern crate redis;
 
-use self::redis::{Commands, ConnectionInfo, ConnectionLike, IntoConnectionInfo, RedisError};
+use self::redis::{
+    Commands, ConnectionInfo, ConnectionLike, ErrorKind, IntoConnectionInfo, RedisError,
+};
 use crate::counter::Counter;
-use crate::limit::Limit;
+use crate::limit::{Limit, Namespace};
 use crate::storage::keys::*;
-use crate::storage::redis::is_limited;
-use crate::storage::redis::scripts::{SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS};
-use crate::storage::{Authorization, CounterStorage, StorageErr};
+use crate::storage::redis::scripts::{
+    SCRIPT_REFUND_COUNTER, SCRIPT_UPDATE_COUNTER, VALUES_AND_TTLS,
+};
+use crate::storage::redis::{horizon_millis, incr_delta, is_limited, parse_scan_cursor};
+use crate::storage::{Authorization, CounterFilter, CounterStorage, CountersPage, StorageErr};
 use r2d2::{ManageConnection, Pool};
 use std::collections::HashSet;
 use std::ops::Deref;
@@ -30,8 +34,14 @@ impl CounterStorage for RedisStorage {
         let mut con = self.conn_pool.get()?;
 
         match con.get::<Vec<u8>, Option<i64>>(key_for_counter(counter))? {
-            Some(val) => Ok(u64::try_from(val).unwrap_or(0) + delta <= counter.max_value()),
-            None => Ok(counter.max_value().checked_sub(delta).is_some()),
+            Some(val) => Ok(u64::try_from(val)
+                .unwrap_or(0)
+                .saturating_add(counter.delta(delta))
+                <= counter.max_value()),
+            None => Ok(counter
+                .max_value()
+                .checked_sub(counter.delta(delta))
+                .is_some()),
         }
     }
 
@@ -48,12 +58,49 @@ impl CounterStorage for RedisStorage {
             .key(key_for_counter(counter))
             .key(key_for_counters_of_limit(counter.limit()))
             .arg(counter.window().as_secs())
-            .arg(delta)
+            .arg(incr_delta(counter.delta(delta)))
             .invoke::<()>(&mut *con)?;
 
         Ok(())
     }
 
+    #[tracing::instrument(skip_all)]
+    fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let mut con = self.conn_pool.get()?;
+
+        redis::Script::new(SCRIPT_REFUND_COUNTER)
+            .key(key_for_counter(counter))
+            .arg(incr_delta(counter.delta(delta)))
+            .invoke::<()>(&mut *con)?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        let claimed = redis::cmd("SET")
+            .arg(key_for_token(namespace, token))
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(horizon_millis(horizon))
+            .query::<Option<String>>(&mut *con)?;
+        Ok(claimed.is_some())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        con.del::<_, ()>(key_for_token(namespace, token))?;
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     fn check_and_update(
         &self,
@@ -82,26 +129,38 @@ impl CounterStorage for RedisStorage {
 
             for (i, counter) in counters.iter().enumerate() {
                 // remaining  = max - (curr_val + delta)
-                let remaining = counter
-                    .max_value()
-                    .checked_sub(u64::try_from(counter_vals[i].unwrap_or(0)).unwrap_or(0) + delta);
+                let remaining = counter.max_value().checked_sub(
+                    u64::try_from(counter_vals[i].unwrap_or(0))
+                        .unwrap_or(0)
+                        .saturating_add(counter.delta(delta)),
+                );
                 if remaining.is_none() {
-                    return Ok(Authorization::Limited(
-                        counter.limit().name().map(|n| n.to_owned()),
-                    ));
+                    return Ok(Authorization::limited_by(counter));
                 }
             }
         }
 
-        // TODO: this can be optimized by using pipelines with multiple updates
-        for (counter_idx, key) in counter_keys.into_iter().enumerate() {
-            let counter = &counters[counter_idx];
-            redis::Script::new(SCRIPT_UPDATE_COUNTER)
-                .key(key)
-                .key(key_for_counters_of_limit(counter.limit()))
-                .arg(counter.window().as_secs())
-                .arg(delta)
-                .invoke::<()>(&mut *con)?;
+        // All the updates in a single round trip
+        let script = redis::Script::new(SCRIPT_UPDATE_COUNTER);
+        let mut pipeline = redis::pipe();
+        for (counter, key) in counters.iter().zip(counter_keys) {
+            pipeline
+                .invoke_script(
+                    script
+                        .key(key)
+                        .key(key_for_counters_of_limit(counter.limit()))
+                        .arg(counter.window().as_secs())
+                        .arg(incr_delta(counter.delta(delta))),
+                )
+                .ignore();
+        }
+        if let Err(err) = pipeline.query::<()>(&mut *con) {
+            if err.kind() == ErrorKind::NoScriptError {
+                script.prepare_invoke().load(&mut *con)?;
+                pipeline.query::<()>(&mut *con)?;
+            } else {
+                Err(err)?;
+            }
         }
 
         Ok(Authorization::Ok)
@@ -130,7 +189,7 @@ impl CounterStorage for RedisStorage {
                 // unnecessarily.
                 if let Some(val) = con.get::<Vec<u8>, Option<i64>>(counter_key.clone())? {
                     counter.set_remaining(
-                        limit
+                        counter
                             .max_value()
                             .saturating_sub(u64::try_from(val).unwrap_or(0)),
                     );
@@ -145,6 +204,73 @@ impl CounterStorage for RedisStorage {
         Ok(res)
     }
 
+    // Scans the set of counters of each limit in turn, so that only a batch
+    // of them is loaded at a time
+    #[tracing::instrument(skip_all)]
+    fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let mut limits: Vec<&Arc<Limit>> = limits.iter().collect();
+        limits.sort_by_cached_key(|limit| key_for_counters_of_limit(limit));
+        let (mut index, mut scan_cursor) = parse_scan_cursor(cursor)?;
+
+        let mut con = self.conn_pool.get()?;
+        let mut page = CountersPage::default();
+        while index < limits.len() && page.counters.len() < page_size {
+            let limit = limits[index];
+            let (next, counter_keys): (u64, Vec<Vec<u8>>) = redis::cmd("SSCAN")
+                .arg(key_for_counters_of_limit(limit))
+                .arg(scan_cursor)
+                .arg("COUNT")
+                .arg(page_size)
+                .query(&mut *con)?;
+
+            for counter_key in counter_keys {
+                let mut counter: Counter =
+                    counter_from_counter_key(&counter_key, Arc::clone(limit));
+                if !filter.matches_variables(&counter) {
+                    continue;
+                }
+                if let Some(val) = con.get::<Vec<u8>, Option<i64>>(counter_key.clone())? {
+                    counter.set_remaining(
+                        counter
+                            .max_value()
+                            .saturating_sub(u64::try_from(val).unwrap_or(0)),
+                    );
+                    let ttl = con.ttl(&counter_key)?;
+                    counter.set_expires_in(Duration::from_secs(ttl));
+                    if filter.matches(&counter) {
+                        page.counters.push(counter);
+                    }
+                }
+            }
+
+            if next == 0 {
+                index += 1;
+            }
+            scan_cursor = next;
+        }
+        if index < limits.len() {
+            page.next_cursor = Some(format!("{index}:{scan_cursor}"));
+        }
+        Ok(page)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        let counter_key = key_for_counter(counter);
+
+        con.del::<_, ()>(&counter_key)?;
+        con.srem::<_, _, ()>(key_for_counters_of_limit(counter.limit()), counter_key)?;
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
         let mut con = self.conn_pool.get()?;
@@ -168,6 +294,13 @@ impl CounterStorage for RedisStorage {
         redis::cmd("FLUSHDB").exec(&mut *con)?;
         Ok(())
     }
+
+    #[tracing::instrument(skip_all)]
+    fn ping(&self) -> Result<(), StorageErr> {
+        let mut con = self.conn_pool.get()?;
+        redis::cmd("PING").exec(&mut *con)?;
+        Ok(())
+    }
 }
 
 impl RedisStorage {
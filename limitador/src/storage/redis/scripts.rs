@@ -7,17 +7,51 @@
 // because the counter key could expire between the "set" and the "incrby"
 // calls.
 
+// Defines `incrby`, which saturates the counter at the largest integer Redis
+// holds, rather than failing, when incrementing it would overflow. The deltas
+// themselves are never larger than that.
+macro_rules! saturating_incrby {
+    () => {
+        "
+    local function incrby(key, delta)
+        local c = redis.pcall('incrby', key, delta)
+        if type(c) == 'table' and c.err then
+            if not string.find(c.err, 'overflow') then
+                error(c)
+            end
+            redis.call('set', key, '9223372036854775807', 'KEEPTTL')
+            return '9223372036854775807'
+        end
+        return c
+    end"
+    };
+}
+
 // KEYS[1]: counter key
 // KEYS[2]: key that contains the counters that belong to the limit
 // ARGV[1]: counter TTL
 // ARGV[2]: delta
-pub const SCRIPT_UPDATE_COUNTER: &str = "
-    local c = redis.call('incrby', KEYS[1], ARGV[2])
+pub const SCRIPT_UPDATE_COUNTER: &str = concat!(
+    saturating_incrby!(),
+    "
+    local c = incrby(KEYS[1], ARGV[2])
     if c == tonumber(ARGV[2]) then
       redis.call('expire', KEYS[1], ARGV[1])
       redis.call('sadd', KEYS[2], KEYS[1])
     end
-    return c";
+    return c"
+);
+
+// KEYS[1]: counter key
+// ARGV[1]: delta
+// Decrements the counter, without going below 0. A counter that doesn't exist,
+// e.g. because its window expired, is left as is.
+pub const SCRIPT_REFUND_COUNTER: &str = "
+    local c = tonumber(redis.call('get', KEYS[1]))
+    if not c then
+      return 0
+    end
+    return redis.call('decrby', KEYS[1], math.min(c, tonumber(ARGV[1])))";
 
 // KEY[i]: Counter key
 // KEY[i+1]: Limit key
@@ -25,7 +59,9 @@ pub const SCRIPT_UPDATE_COUNTER: &str = "
 // ARGV[i+1]: Deltas
 // This function returns a list with the values and TTLs for the updated counter_keys,
 // the first position the counter value and the second the TTL
-pub const BATCH_UPDATE_COUNTERS: &str = "
+pub const BATCH_UPDATE_COUNTERS: &str = concat!(
+    saturating_incrby!(),
+    "
     local res = {}
     for i = 1, #KEYS, 2 do
         local counter_key = KEYS[i]
@@ -33,7 +69,7 @@ pub const BATCH_UPDATE_COUNTERS: &str = "
         local ttl = ARGV[i]
         local delta = ARGV[i+1]
 
-        local c = redis.call('incrby', counter_key, delta)
+        local c = incrby(counter_key, delta)
         table.insert(res, c)
         if c == tonumber(delta) then
             redis.call('expire', counter_key, ttl)
@@ -42,7 +78,74 @@ pub const BATCH_UPDATE_COUNTERS: &str = "
         table.insert(res, redis.call('pexpiretime', counter_key))
     end
     return res
-";
+"
+);
+
+// KEYS[i]: Counter key
+// KEYS[i+1]: Limit key
+// ARGV[j]: Max value of the counter
+// ARGV[j+1]: TTL
+// ARGV[j+2]: Delta
+// Checks all the counters, and only increments them if none would go over its
+// max value, all atomically. The first position of the list returned contains
+// the (1-based) position of the first counter that is over its limit, 0 if
+// none is. It is followed by the value, before the update, and TTL (in ms) of
+// each counter, as returned by VALUES_AND_TTLS.
+pub const CHECK_AND_UPDATE: &str = concat!(
+    saturating_incrby!(),
+    "
+    local res = {0}
+    local n = #KEYS / 2
+    for i = 1, n do
+        local value = tonumber(redis.call('get', KEYS[i*2-1]) or '0')
+        table.insert(res, value)
+        table.insert(res, redis.call('pttl', KEYS[i*2-1]))
+        if res[1] == 0 and value + tonumber(ARGV[i*3]) > tonumber(ARGV[i*3-2]) then
+            res[1] = i
+        end
+    end
+    if res[1] == 0 then
+        for i = 1, n do
+            local counter_key = KEYS[i*2-1]
+            local delta = ARGV[i*3]
+            local c = incrby(counter_key, delta)
+            if c == tonumber(delta) then
+                redis.call('expire', counter_key, ARGV[i*3-1])
+                redis.call('sadd', KEYS[i*2], counter_key)
+            end
+        end
+    end
+    return res
+"
+);
+
+// KEYS[i*4-3]: Previous key of the counter
+// KEYS[i*4-2]: Counter key
+// KEYS[i*4-1]: Previous key of the limit
+// KEYS[i*4]: Limit key
+// Moves each counter found under its previous key to its current one, TTL
+// included, adding to the value already there, if any, e.g. when written by
+// another instance using the current keys already.
+pub const MIGRATE_COUNTERS: &str = concat!(
+    saturating_incrby!(),
+    "
+    for i = 1, #KEYS / 4 do
+        local previous = KEYS[i*4-3]
+        local value = redis.call('get', previous)
+        if value then
+            local counter_key = KEYS[i*4-2]
+            if redis.call('exists', counter_key) == 1 then
+                incrby(counter_key, value)
+                redis.call('del', previous)
+            else
+                redis.call('rename', previous, counter_key)
+            end
+            redis.call('sadd', KEYS[i*4], counter_key)
+        end
+        redis.call('srem', KEYS[i*4-1], previous)
+    end
+"
+);
 
 // KEYS: the function returns the value and TTL (in ms) for these keys
 // The first position of the list returned contains the value of KEYS[1], the
@@ -0,0 +1,152 @@
+use crate::storage::redis::RedisConnectionConfig;
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType};
+use redis::{
+    AsyncConnectionConfig, Cmd, ConnectionInfo, ErrorKind, Pipeline, RedisError, RedisResult,
+    TlsMode, Value,
+};
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// A connection to the current master of a Redis deployment monitored by
+// Sentinel. When the master becomes unreachable, or turns into a replica after
+// a failover, the connection is dropped and the new master is looked up with
+// the sentinels on the next command, backing off exponentially while that
+// fails. All these failures surface as connection errors, i.e. transient ones.
+pub(super) struct SentinelConnection {
+    client: tokio::sync::Mutex<SentinelClient>,
+    config: AsyncConnectionConfig,
+    state: Mutex<State>,
+}
+
+struct State {
+    conn: Option<MultiplexedConnection>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl SentinelConnection {
+    pub async fn new(
+        sentinels: Vec<String>,
+        master_name: String,
+        tls: bool,
+        config: &RedisConnectionConfig,
+    ) -> RedisResult<Self> {
+        // The sentinels only hand out clients verifying the master with the
+        // certificates of the system
+        if config.ca_cert.is_some() || config.client_cert.is_some() || config.client_key.is_some() {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "Custom CA and client certificates aren't supported with Sentinel",
+            )));
+        }
+        // Credentials of the sentinels are used for the master too
+        let mut redis_connection_info = match sentinels.first() {
+            Some(sentinel) => ConnectionInfo::from_str(sentinel)?.redis,
+            None => Default::default(),
+        };
+        config.apply_credentials(&mut redis_connection_info);
+        let client = SentinelClient::build(
+            sentinels,
+            master_name,
+            Some(SentinelNodeConnectionInfo {
+                tls_mode: tls.then_some(TlsMode::Secure),
+                redis_connection_info: Some(redis_connection_info),
+            }),
+            SentinelServerType::Master,
+        )?;
+        let conn = Self {
+            client: tokio::sync::Mutex::new(client),
+            config: config.async_connection_config(),
+            state: Mutex::new(State {
+                conn: None,
+                retry_at: None,
+                backoff: MIN_BACKOFF,
+            }),
+        };
+        conn.connection().await?;
+        Ok(conn)
+    }
+
+    pub async fn req_packed_command(&self, cmd: &Cmd) -> RedisResult<Value> {
+        let mut conn = self.connection().await?;
+        conn.req_packed_command(cmd)
+            .await
+            .map_err(|err| self.on_error(err))
+    }
+
+    pub async fn req_packed_commands(
+        &self,
+        cmd: &Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let mut conn = self.connection().await?;
+        conn.req_packed_commands(cmd, offset, count)
+            .await
+            .map_err(|err| self.on_error(err))
+    }
+
+    async fn connection(&self) -> RedisResult<MultiplexedConnection> {
+        if let Some(conn) = self.current()? {
+            return Ok(conn);
+        }
+
+        let mut client = self.client.lock().await;
+        // Some other task might have reconnected while we waited for the client
+        if let Some(conn) = self.current()? {
+            return Ok(conn);
+        }
+
+        let result = client.get_async_connection_with_config(&self.config).await;
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(conn) => {
+                state.conn = Some(conn.clone());
+                state.retry_at = None;
+                state.backoff = MIN_BACKOFF;
+                Ok(conn)
+            }
+            Err(err) => {
+                state.retry_at = Some(Instant::now() + state.backoff);
+                state.backoff = (state.backoff * 2).min(MAX_BACKOFF);
+                Err(transient(err))
+            }
+        }
+    }
+
+    fn current(&self) -> RedisResult<Option<MultiplexedConnection>> {
+        let state = self.state.lock().unwrap();
+        match (&state.conn, state.retry_at) {
+            (Some(conn), _) => Ok(Some(conn.clone())),
+            (None, Some(retry_at)) if Instant::now() < retry_at => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "no Redis master available, backing off",
+            )
+            .into()),
+            (None, _) => Ok(None),
+        }
+    }
+
+    fn on_error(&self, err: RedisError) -> RedisError {
+        if err.is_unrecoverable_error() || err.kind() == ErrorKind::ReadOnly {
+            self.state.lock().unwrap().conn = None;
+            transient(err)
+        } else {
+            err
+        }
+    }
+}
+
+fn transient(err: RedisError) -> RedisError {
+    if err.is_connection_dropped() || err.is_connection_refusal() || err.is_timeout() {
+        err
+    } else {
+        io::Error::new(io::ErrorKind::ConnectionReset, err.to_string()).into()
+    }
+}
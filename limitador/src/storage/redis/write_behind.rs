@@ -0,0 +1,361 @@
+use crate::counter::Counter;
+use crate::limit::{Limit, Namespace};
+use crate::storage::atomic_expiring_value::AtomicExpiringValue;
+use crate::storage::redis::redis_async::AsyncRedisStorage;
+use crate::storage::redis::{
+    RedisConnectionConfig, DEFAULT_FLUSHING_PERIOD_SEC, DEFAULT_MAX_CACHED_COUNTERS,
+};
+use crate::storage::{peek_counters, AsyncCounterStorage, Authorization, StorageErr};
+use async_trait::async_trait;
+use moka::sync::Cache;
+use redis::RedisError;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{error, warn};
+
+// Decisions are made from the counters held in memory alone, as the in memory
+// storage does, which makes them authoritative. The updates are then mirrored
+// to Redis in the background, every `flushing_period`, so that the counters
+// survive restarts: a counter that isn't known locally gets its value and TTL
+// from Redis first, i.e. the local state gets hydrated from Redis as counters
+// get used.
+//
+// When multiple Limitador instances share the same Redis, each only sees the
+// updates the others made to a counter up until it loaded it. This storage is
+// meant for a single instance, or instances that don't share counters.
+pub struct WriteBehindRedisStorage {
+    local: Cache<Counter, Arc<AtomicExpiringValue>>,
+    pending: Arc<Mutex<HashMap<Counter, u64>>>,
+    async_redis_storage: AsyncRedisStorage,
+}
+
+#[async_trait]
+impl AsyncCounterStorage for WriteBehindRedisStorage {
+    #[tracing::instrument(skip_all)]
+    async fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let value = self.load(&[counter.clone()]).await?.remove(0);
+        Ok(counter.max_value() >= value.value().saturating_add(counter.delta(delta)))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let value = self.load(&[counter.clone()]).await?.remove(0);
+        let delta = counter.delta(delta);
+        value.update(delta, counter.window(), SystemTime::now());
+        let mut pending = self.pending.lock().unwrap();
+        let pending = pending.entry(counter.clone()).or_default();
+        *pending = pending.saturating_add(delta);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let delta = counter.delta(delta);
+        if let Some(value) = self.local.get(counter) {
+            value.refund(delta, SystemTime::now());
+        }
+        // What wasn't flushed yet is just dropped, Redis is refunded the rest
+        let unflushed = match self.pending.lock().unwrap().get_mut(counter) {
+            Some(pending) => {
+                let unflushed = delta.min(*pending);
+                *pending -= unflushed;
+                unflushed
+            }
+            None => 0,
+        };
+        if delta > unflushed {
+            self.async_redis_storage
+                .take_from_counter(counter, delta - unflushed)
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn claim_token(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+    ) -> Result<bool, StorageErr> {
+        self.async_redis_storage
+            .claim_token(namespace, token, horizon)
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn release_token(&self, namespace: &Namespace, token: &str) -> Result<(), StorageErr> {
+        self.async_redis_storage
+            .release_token(namespace, token)
+            .await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let values = self.load(counters).await?;
+        let now = SystemTime::now();
+
+        let mut first_limited = None;
+        for (counter, value) in counters.iter_mut().zip(&values) {
+            let delta = counter.delta(delta);
+            let remaining = counter
+                .max_value()
+                .checked_sub(value.value_at(now).saturating_add(delta));
+            if load_counters {
+                counter.set_remaining(remaining.unwrap_or_default());
+                counter.set_expires_in(value.ttl());
+            }
+            if first_limited.is_none() && remaining.is_none() {
+                first_limited = Some(Authorization::limited_by(counter));
+                if !load_counters {
+                    break;
+                }
+            }
+        }
+        if let Some(limited) = first_limited {
+            return Ok(limited);
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        for (counter, value) in counters.iter().zip(&values) {
+            let delta = counter.delta(delta);
+            value.update(delta, counter.window(), now);
+            let pending = pending.entry(counter.clone()).or_default();
+            *pending = pending.saturating_add(delta);
+        }
+
+        Ok(Authorization::Ok)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn peek(
+        &self,
+        counters: &mut [Counter],
+        delta: u64,
+    ) -> Result<Authorization, StorageErr> {
+        let now = SystemTime::now();
+        let values_and_ttls: Vec<(u64, Duration)> = self
+            .load(counters)
+            .await?
+            .iter()
+            .zip(counters.iter())
+            .map(|(value, counter)| match value.ttl() {
+                ttl if ttl.is_zero() => (0, counter.window()),
+                ttl => (value.value_at(now), ttl),
+            })
+            .collect();
+        Ok(peek_counters(counters, values_and_ttls, delta))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_counters(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+    ) -> Result<HashSet<Counter>, StorageErr> {
+        // Redis knows of the counters not loaded yet, but ours are more recent
+        let mut res = self.async_redis_storage.get_counters(limits).await?;
+        let now = SystemTime::now();
+        for (counter, value) in self.local.iter() {
+            if !limits.contains(counter.limit()) {
+                continue;
+            }
+            let ttl = value.ttl();
+            if ttl > Duration::ZERO {
+                let mut counter = counter.deref().clone();
+                counter.set_remaining(counter.max_value().saturating_sub(value.value_at(now)));
+                counter.set_expires_in(ttl);
+                res.replace(counter);
+            }
+        }
+        Ok(res)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        self.local.invalidate(counter);
+        self.pending.lock().unwrap().remove(counter);
+        self.async_redis_storage.delete_counter(counter).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        for (counter, _) in self.local.iter() {
+            if limits.contains(counter.limit()) {
+                self.local.invalidate(counter.deref());
+            }
+        }
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|counter, _| !limits.contains(counter.limit()));
+        self.async_redis_storage.delete_counters(limits).await
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.local.invalidate_all();
+        self.pending.lock().unwrap().clear();
+        self.async_redis_storage.clear().await
+    }
+
+    // Mirrors the updates not yet written to Redis right away
+    #[tracing::instrument(skip_all)]
+    async fn flush(&self) -> Result<(), StorageErr> {
+        flush_pending(&self.pending, &self.async_redis_storage).await
+    }
+}
+
+impl WriteBehindRedisStorage {
+    pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
+        WriteBehindRedisStorageBuilder::new(redis_url).build().await
+    }
+
+    // The local values of the `counters`, loading the ones we don't know of
+    // from Redis
+    async fn load(
+        &self,
+        counters: &[Counter],
+    ) -> Result<Vec<Arc<AtomicExpiringValue>>, StorageErr> {
+        let mut values: Vec<Option<Arc<AtomicExpiringValue>>> =
+            counters.iter().map(|c| self.local.get(c)).collect();
+        let missing: Vec<&Counter> = counters
+            .iter()
+            .zip(&values)
+            .filter(|(_, value)| value.is_none())
+            .map(|(counter, _)| counter)
+            .collect();
+        if missing.is_empty() {
+            return Ok(values.into_iter().flatten().collect());
+        }
+
+        let remote = self.async_redis_storage.values_and_ttls(&missing).await?;
+        let now = SystemTime::now();
+        let loaded: Vec<Arc<AtomicExpiringValue>> = {
+            // Updates that were not flushed yet, e.g. if the counter got evicted
+            let pending = self.pending.lock().unwrap();
+            missing
+                .into_iter()
+                .zip(remote)
+                .map(|(counter, (value, ttl))| {
+                    let unflushed = pending.get(counter).copied().unwrap_or_default();
+                    let (value, expiry) = match ttl {
+                        Some(ttl) if value > 0 => (value.saturating_add(unflushed), now + ttl),
+                        _ => (unflushed, now + counter.window()),
+                    };
+                    // Another request might have loaded it concurrently
+                    self.local.get_with_by_ref(counter, || {
+                        Arc::new(AtomicExpiringValue::new(value, expiry))
+                    })
+                })
+                .collect()
+        };
+
+        let mut loaded = loaded.into_iter();
+        for value in values.iter_mut().filter(|value| value.is_none()) {
+            *value = loaded.next();
+        }
+        Ok(values.into_iter().flatten().collect())
+    }
+}
+
+async fn flush_pending(
+    pending: &Mutex<HashMap<Counter, u64>>,
+    async_redis_storage: &AsyncRedisStorage,
+) -> Result<(), StorageErr> {
+    let updates = mem::take(&mut *pending.lock().unwrap());
+    if updates.is_empty() {
+        return Ok(());
+    }
+
+    let deltas: Vec<(&Counter, u64)> = updates
+        .iter()
+        .map(|(counter, delta)| (counter, *delta))
+        .collect();
+    let result = async_redis_storage.add_to_counters(&deltas).await;
+    if let Err(err) = &result {
+        if err.is_transient() {
+            // Keep them around for the next flush
+            let mut pending = pending.lock().unwrap();
+            for (counter, delta) in updates {
+                let pending = pending.entry(counter).or_default();
+                *pending = pending.saturating_add(delta);
+            }
+        }
+    }
+    result
+}
+
+pub struct WriteBehindRedisStorageBuilder {
+    redis_url: String,
+    flushing_period: Duration,
+    max_cached_counters: usize,
+    connection_config: RedisConnectionConfig,
+}
+
+impl WriteBehindRedisStorageBuilder {
+    pub fn new(redis_url: &str) -> Self {
+        Self {
+            redis_url: redis_url.to_string(),
+            flushing_period: Duration::from_secs(DEFAULT_FLUSHING_PERIOD_SEC),
+            max_cached_counters: DEFAULT_MAX_CACHED_COUNTERS,
+            connection_config: RedisConnectionConfig::default(),
+        }
+    }
+
+    pub fn flushing_period(mut self, flushing_period: Duration) -> Self {
+        self.flushing_period = flushing_period;
+        self
+    }
+
+    pub fn max_cached_counters(mut self, max_cached_counters: usize) -> Self {
+        self.max_cached_counters = max_cached_counters;
+        self
+    }
+
+    pub fn connection_config(mut self, connection_config: RedisConnectionConfig) -> Self {
+        self.connection_config = connection_config;
+        self
+    }
+
+    pub async fn build(self) -> Result<WriteBehindRedisStorage, RedisError> {
+        let async_redis_storage =
+            AsyncRedisStorage::new_with_config(&self.redis_url, &self.connection_config).await?;
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+
+        {
+            let pending = Arc::downgrade(&pending);
+            let async_redis_storage = async_redis_storage.clone();
+            let mut interval = tokio::time::interval(self.flushing_period);
+            tokio::spawn(async move {
+                loop {
+                    interval.tick().await;
+                    // Stop once the storage is gone
+                    let Some(pending) = pending.upgrade() else {
+                        break;
+                    };
+                    if let Err(err) = flush_pending(&pending, &async_redis_storage).await {
+                        if err.is_transient() {
+                            warn!("Error flushing counter updates, will retry: {}", err);
+                        } else {
+                            error!("Error flushing counter updates: {}", err);
+                        }
+                    }
+                }
+            });
+        }
+
+        Ok(WriteBehindRedisStorage {
+            local: Cache::new(self.max_cached_counters as u64),
+            pending,
+            async_redis_storage,
+        })
+    }
+}
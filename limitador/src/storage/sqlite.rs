@@ -0,0 +1,523 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::keys::bin::{key_for_counter, partial_counter_from_counter_key};
+use crate::storage::{
+    from_hex, invalid_cursor, parse_cursor, to_hex, Authorization, CounterFilter, CounterStorage,
+    CountersPage, StorageErr,
+};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, TransactionBehavior};
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug_span;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS counters (
+    key BLOB PRIMARY KEY,
+    namespace TEXT NOT NULL,
+    value INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+) WITHOUT ROWID;
+CREATE INDEX IF NOT EXISTS counters_namespace ON counters (namespace);
+";
+
+// Adds to the counter, starting a new window if the current one has expired,
+// saturating at the largest integer SQLite holds
+const UPSERT: &str = "
+INSERT INTO counters (key, namespace, value, expires_at) VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (key) DO UPDATE SET
+    value = CASE WHEN expires_at <= ?5 THEN excluded.value
+        ELSE MIN(value, 9223372036854775807 - excluded.value) + excluded.value END,
+    expires_at = CASE WHEN expires_at <= ?5 THEN excluded.expires_at ELSE expires_at END
+";
+
+// Takes from the counter, without going below 0, unless it has expired
+const REFUND: &str = "
+UPDATE counters SET value = MAX(value - ?2, 0) WHERE key = ?1 AND expires_at > ?3
+";
+
+/// Counters stored in an embedded SQLite database, for single node
+/// deployments that want their counters to survive restarts. The database is
+/// in WAL mode, and all operations on it are serialized.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl CounterStorage for SqliteStorage {
+    #[tracing::instrument(skip_all)]
+    fn is_within_limits(&self, counter: &Counter, delta: u64) -> Result<bool, StorageErr> {
+        let conn = self.conn.lock().unwrap();
+        let (value, _) = Self::value_of(&conn, &key_for_counter(counter), counter, now_ms())?;
+        Ok(counter.max_value() >= value.saturating_add(counter.delta(delta)))
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn add_counter(&self, _limit: &Limit) -> Result<(), StorageErr> {
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let conn = self.conn.lock().unwrap();
+        Self::add(&conn, &key_for_counter(counter), counter, delta, now_ms())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn refund_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        let conn = self.conn.lock().unwrap();
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        conn.prepare_cached(REFUND)?.execute(params![
+            key_for_counter(counter),
+            counter.delta(delta).min(i64::MAX as u64),
+            now_ms()
+        ])?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn check_and_update(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        load_counters: bool,
+    ) -> Result<Authorization, StorageErr> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = now_ms();
+        let mut keys: Vec<Vec<u8>> = Vec::with_capacity(counters.len());
+        let mut first_limited = None;
+
+        for counter in &mut *counters {
+            let key = key_for_counter(counter);
+            let delta = counter.delta(delta);
+            let (val, ttl) = Self::value_of(&tx, &key, counter, now)?;
+
+            if load_counters {
+                counter.set_expires_in(ttl);
+                counter.set_remaining(
+                    counter
+                        .max_value()
+                        .checked_sub(val.saturating_add(delta))
+                        .unwrap_or_default(),
+                );
+            }
+
+            if first_limited.is_none() && counter.max_value() < val.saturating_add(delta) {
+                first_limited = Some(Authorization::limited_by(counter));
+                // Keep loading the values of all the counters
+                if !load_counters {
+                    break;
+                }
+            }
+
+            keys.push(key);
+        }
+
+        if let Some(limited) = first_limited {
+            return Ok(limited);
+        }
+
+        for (key, counter) in keys.iter().zip(counters.iter()) {
+            Self::add(&tx, key, counter, delta, now)?;
+        }
+        tx.commit()?;
+
+        Ok(Authorization::Ok)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn get_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
+        let conn = self.conn.lock().unwrap();
+        let now = now_ms();
+        let mut counters = HashSet::default();
+        let namespaces: BTreeSet<&str> = limits.iter().map(|l| l.namespace().as_ref()).collect();
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, value, expires_at FROM counters WHERE namespace = ?1 AND expires_at > ?2",
+        )?;
+        for ns in namespaces {
+            let rows = {
+                let span = debug_span!("datastore");
+                let _entered = span.enter();
+                stmt.query_map(params![ns, now], |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, u64>(1)?,
+                        row.get::<_, u64>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?
+            };
+            for (key, value, expires_at) in rows {
+                let mut counter = partial_counter_from_counter_key(&key);
+                if let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) {
+                    counter.update_to_limit(Arc::clone(limit));
+                    counter.set_expires_in(Duration::from_millis(expires_at - now));
+                    counter.set_remaining(counter.max_value().saturating_sub(value));
+                    counters.insert(counter);
+                }
+            }
+        }
+        Ok(counters)
+    }
+
+    // Pages through the counters of each namespace in turn, in key order, so
+    // that only a batch of them is loaded at a time
+    #[tracing::instrument(skip_all)]
+    fn get_counters_page(
+        &self,
+        limits: &HashSet<Arc<Limit>>,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, StorageErr> {
+        let namespaces: Vec<&str> = limits
+            .iter()
+            .map(|l| l.namespace().as_ref())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let (mut index, position) = parse_cursor(cursor)?;
+        let mut after =
+            from_hex(position).ok_or_else(|| invalid_cursor(cursor.unwrap_or_default()))?;
+
+        let conn = self.conn.lock().unwrap();
+        let now = now_ms();
+        let mut stmt = conn.prepare_cached(
+            "SELECT key, value, expires_at FROM counters
+             WHERE namespace = ?1 AND expires_at > ?2 AND key > ?3 ORDER BY key LIMIT ?4",
+        )?;
+        let mut page = CountersPage::default();
+        while index < namespaces.len() && page.counters.len() < page_size {
+            let rows = {
+                let span = debug_span!("datastore");
+                let _entered = span.enter();
+                stmt.query_map(params![namespaces[index], now, after, page_size], |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, u64>(1)?,
+                        row.get::<_, u64>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?
+            };
+            let mut exhausted = rows.len() < page_size;
+            for (key, value, expires_at) in rows {
+                if page.counters.len() == page_size {
+                    exhausted = false;
+                    break;
+                }
+                let mut counter = partial_counter_from_counter_key(&key);
+                if let Some(limit) = limits.iter().find(|l| l.deref() == counter.limit()) {
+                    counter.update_to_limit(Arc::clone(limit));
+                    counter.set_expires_in(Duration::from_millis(expires_at - now));
+                    counter.set_remaining(counter.max_value().saturating_sub(value));
+                    if filter.matches(&counter) {
+                        page.counters.push(counter);
+                    }
+                }
+                after = key;
+            }
+            if exhausted {
+                index += 1;
+                after = Vec::new();
+            }
+        }
+        if index < namespaces.len() {
+            page.next_cursor = Some(format!("{index}:{}", to_hex(&after)));
+        }
+        Ok(page)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn delete_counter(&self, counter: &Counter) -> Result<(), StorageErr> {
+        let conn = self.conn.lock().unwrap();
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        conn.prepare_cached("DELETE FROM counters WHERE key = ?1")?
+            .execute(params![key_for_counter(counter)])?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn delete_counters(&self, limits: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        let counters = self.get_counters(limits)?;
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("DELETE FROM counters WHERE key = ?1")?;
+        for counter in &counters {
+            let span = debug_span!("datastore");
+            let _entered = span.enter();
+            stmt.execute(params![key_for_counter(counter)])?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn clear(&self) -> Result<(), StorageErr> {
+        let conn = self.conn.lock().unwrap();
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        conn.execute("DELETE FROM counters", [])?;
+        Ok(())
+    }
+}
+
+impl SqliteStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageErr> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        // Whatever expired while we were down
+        conn.execute(
+            "DELETE FROM counters WHERE expires_at <= ?1",
+            params![now_ms()],
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn value_of(
+        conn: &Connection,
+        key: &[u8],
+        counter: &Counter,
+        now: u64,
+    ) -> Result<(u64, Duration), StorageErr> {
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        let entry = conn
+            .prepare_cached("SELECT value, expires_at FROM counters WHERE key = ?1")?
+            .query_row(params![key], |row| {
+                Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?))
+            })
+            .optional()?;
+        Ok(match entry {
+            Some((value, expires_at)) if expires_at > now => {
+                (value, Duration::from_millis(expires_at - now))
+            }
+            _ => (0, counter.window()),
+        })
+    }
+
+    fn add(
+        conn: &Connection,
+        key: &[u8],
+        counter: &Counter,
+        delta: u64,
+        now: u64,
+    ) -> Result<(), StorageErr> {
+        let expires_at = now + counter.window().as_millis() as u64;
+        let span = debug_span!("datastore");
+        let _entered = span.enter();
+        conn.prepare_cached(UPSERT)?.execute(params![
+            key,
+            counter.namespace().as_ref(),
+            counter.delta(delta).min(i64::MAX as u64),
+            expires_at,
+            now
+        ])?;
+        Ok(())
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl From<rusqlite::Error> for StorageErr {
+    fn from(error: rusqlite::Error) -> Self {
+        let transient = matches!(
+            error.sqlite_error_code(),
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        );
+        Self {
+            msg: format!("Underlying storage error: {error}"),
+            source: Some(Box::new(error)),
+            transient,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteStorage;
+    use crate::counter::Counter;
+    use crate::limit::Limit;
+    use crate::storage::{Authorization, CounterFilter, CounterStorage};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn lists_counters_against_their_max_value() {
+        let mut limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        limit
+            .set_override(BTreeMap::from([("app_id".into(), "foo".into())]), 2)
+            .expect("valid override");
+        let limit = Arc::new(limit);
+        let counter = |app_id: &str| {
+            let map = HashMap::from([("app_id".to_string(), app_id.to_string())]);
+            Counter::new(Arc::clone(&limit), &map.into())
+                .unwrap()
+                .expect("must have a counter")
+        };
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage = SqliteStorage::open(tmp.path().join("counters.db")).unwrap();
+        // Past the max value, e.g. reported hits
+        storage.update_counter(&counter("foo"), 3).unwrap();
+        storage.update_counter(&counter("bar"), 3).unwrap();
+
+        let counters = storage.get_counters(&HashSet::from([limit])).unwrap();
+        let remaining = |app_id: &str| {
+            counters
+                .iter()
+                .find(|c| c.set_variables()["app_id"] == app_id)
+                .and_then(|c| c.remaining())
+        };
+        assert_eq!(remaining("foo"), Some(0));
+        assert_eq!(remaining("bar"), Some(7));
+    }
+
+    #[test]
+    fn keeps_counters_across_restarts() {
+        let limit = Limit::new(
+            "test_namespace",
+            1,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let path = tmp.path().join("counters.db");
+        {
+            let storage = SqliteStorage::open(&path).expect("We should have a storage");
+            let result = storage
+                .check_and_update(&mut vec![counter.clone()], 1, false)
+                .unwrap();
+            assert!(matches!(result, Authorization::Ok));
+        }
+
+        {
+            let storage = SqliteStorage::open(&path).expect("We should still have a storage");
+            assert!(
+                !storage.is_within_limits(&counter, 1).unwrap(),
+                "Should be above threshold still!"
+            );
+            let result = storage
+                .check_and_update(&mut vec![counter.clone()], 1, false)
+                .unwrap();
+            assert!(matches!(result, Authorization::Limited(Some(_))));
+        }
+    }
+
+    #[test]
+    fn loads_all_counters_when_limited() {
+        let limited = Limit::new("test_namespace", 1, 60, vec![], vec![]);
+        let other = Limit::new("test_namespace", 10, 120, vec![], vec![]);
+        let counter = |limit: &Limit| {
+            Counter::new(limit.clone(), &Default::default())
+                .unwrap()
+                .expect("must have a counter")
+        };
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage =
+            SqliteStorage::open(tmp.path().join("counters.db")).expect("We should have a storage");
+        storage.update_counter(&counter(&other), 3).unwrap();
+
+        let mut counters = vec![counter(&limited), counter(&other)];
+        let result = storage.check_and_update(&mut counters, 2, true).unwrap();
+        assert!(matches!(result, Authorization::Limited(Some(_))));
+        assert_eq!(counters[0].remaining(), Some(0));
+        assert!(counters[0].expires_in().is_some());
+        assert_eq!(counters[1].remaining(), Some(5));
+        assert!(counters[1].expires_in().is_some());
+
+        // Nothing got updated
+        let mut counters = vec![counter(&limited), counter(&other)];
+        let result = storage.check_and_update(&mut counters, 1, true).unwrap();
+        assert!(matches!(result, Authorization::Ok));
+        assert_eq!(counters[0].remaining(), Some(0));
+        assert_eq!(counters[1].remaining(), Some(6));
+    }
+
+    #[test]
+    fn refunds_without_going_below_zero() {
+        let limit = Limit::new("test_namespace", 2, 60, vec![], vec![]);
+        let counter = Counter::new(limit, &Default::default())
+            .unwrap()
+            .expect("must have a counter");
+
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage =
+            SqliteStorage::open(tmp.path().join("counters.db")).expect("We should have a storage");
+        storage.update_counter(&counter, 2).unwrap();
+        assert!(!storage.is_within_limits(&counter, 1).unwrap());
+
+        storage.refund_counter(&counter, 5).unwrap();
+        assert!(storage.is_within_limits(&counter, 2).unwrap());
+        assert!(!storage.is_within_limits(&counter, 3).unwrap());
+    }
+
+    #[test]
+    fn pages_through_counters() {
+        let limit = Arc::new(Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        ));
+        let tmp = TempDir::new().expect("We should have a dir!");
+        let storage =
+            SqliteStorage::open(tmp.path().join("counters.db")).expect("We should have a storage");
+        for app_id in ["a1", "a2", "a3", "a4", "b1"] {
+            let ctx = HashMap::from([("app_id".to_string(), app_id.to_string())]).into();
+            let counter = Counter::new(Arc::clone(&limit), &ctx)
+                .unwrap()
+                .expect("must have a counter");
+            storage.update_counter(&counter, 1).unwrap();
+        }
+
+        let limits = HashSet::from([limit]);
+        let filter = CounterFilter::default().variable_prefix("app_id", "a");
+        let mut seen = HashSet::new();
+        let mut cursor = None;
+        loop {
+            let page = storage
+                .get_counters_page(&limits, &filter, cursor.as_deref(), 3)
+                .unwrap();
+            assert!(page.counters.len() <= 3);
+            for counter in page.counters {
+                assert!(seen.insert(counter.set_variables()["app_id"].clone()));
+            }
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(
+            seen,
+            HashSet::from(["a1", "a2", "a3", "a4"].map(String::from))
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::counter::Counter;
+use crate::limit::Limit;
+use crate::storage::{AsyncCounterStorage, Authorization, StorageErr};
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Counts the calls it gets, and the hits, failing them while down, or
+// rejecting them for good
+#[derive(Default)]
+pub(super) struct Flaky {
+    pub down: AtomicBool,
+    pub rejecting: AtomicBool,
+    pub calls: AtomicU64,
+    pub hits: AtomicU64,
+}
+
+impl Flaky {
+    fn check(&self) -> Result<(), StorageErr> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if self.down.load(Ordering::SeqCst) {
+            return Err(StorageErr {
+                msg: "down".to_string(),
+                source: None,
+                transient: true,
+            });
+        }
+        if self.rejecting.load(Ordering::SeqCst) {
+            return Err(StorageErr {
+                msg: "rejected".to_string(),
+                source: None,
+                transient: false,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AsyncCounterStorage for Arc<Flaky> {
+    async fn is_within_limits(&self, _: &Counter, _: u64) -> Result<bool, StorageErr> {
+        self.check().map(|_| true)
+    }
+
+    async fn update_counter(&self, counter: &Counter, delta: u64) -> Result<(), StorageErr> {
+        self.check()?;
+        self.hits.fetch_add(counter.delta(delta), Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn check_and_update<'a>(
+        &self,
+        counters: &mut Vec<Counter>,
+        delta: u64,
+        _: bool,
+    ) -> Result<Authorization, StorageErr> {
+        self.check()?;
+        for counter in counters.iter() {
+            self.hits.fetch_add(counter.delta(delta), Ordering::SeqCst);
+        }
+        Ok(Authorization::Ok)
+    }
+
+    async fn get_counters(&self, _: &HashSet<Arc<Limit>>) -> Result<HashSet<Counter>, StorageErr> {
+        self.check().map(|_| HashSet::new())
+    }
+
+    async fn delete_counter(&self, _: &Counter) -> Result<(), StorageErr> {
+        self.check()
+    }
+
+    async fn delete_counters(&self, _: &HashSet<Arc<Limit>>) -> Result<(), StorageErr> {
+        self.check()
+    }
+
+    async fn clear(&self) -> Result<(), StorageErr> {
+        self.check()
+    }
+}
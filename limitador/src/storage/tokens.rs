@@ -0,0 +1,128 @@
+use crate::limit::Namespace;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// How many idempotency tokens the in-memory storages remember, at most
+pub(crate) const MAX_SEEN_TOKENS: usize = 100_000;
+
+type Token = (Namespace, String);
+
+// The idempotency tokens of the requests seen within their horizon, forgetting
+// about the oldest ones first when there are more than `capacity` of them
+pub(crate) struct SeenTokens {
+    capacity: usize,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    expiries: HashMap<Token, SystemTime>,
+    // In the order they got claimed in, including the ones released since
+    claimed: VecDeque<(Token, SystemTime)>,
+}
+
+impl Seen {
+    fn forget_oldest(&mut self) {
+        if let Some((token, expiry)) = self.claimed.pop_front() {
+            if self.expiries.get(&token) == Some(&expiry) {
+                self.expiries.remove(&token);
+            }
+        }
+    }
+}
+
+impl SeenTokens {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// `false` when the `token` got claimed already, less than a `horizon`
+    /// ago, and wasn't released since
+    pub fn claim(
+        &self,
+        namespace: &Namespace,
+        token: &str,
+        horizon: Duration,
+        now: SystemTime,
+    ) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        while seen
+            .claimed
+            .front()
+            .is_some_and(|(_, expiry)| *expiry <= now)
+        {
+            seen.forget_oldest();
+        }
+
+        let token = (namespace.clone(), token.to_owned());
+        if seen
+            .expiries
+            .get(&token)
+            .is_some_and(|expiry| *expiry > now)
+        {
+            return false;
+        }
+        let expiry = now + horizon;
+        seen.expiries.insert(token.clone(), expiry);
+        seen.claimed.push_back((token, expiry));
+        while seen.claimed.len() > self.capacity {
+            seen.forget_oldest();
+        }
+        true
+    }
+
+    pub fn release(&self, namespace: &Namespace, token: &str) {
+        self.seen
+            .lock()
+            .unwrap()
+            .expiries
+            .remove(&(namespace.clone(), token.to_owned()));
+    }
+
+    pub fn clear(&self) {
+        *self.seen.lock().unwrap() = Seen::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeenTokens;
+    use crate::limit::Namespace;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn claims_tokens_once_within_their_horizon() {
+        let tokens = SeenTokens::new(10);
+        let ns = Namespace::from("ns");
+        let horizon = Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        assert!(tokens.claim(&ns, "a", horizon, now));
+        assert!(!tokens.claim(&ns, "a", horizon, now + Duration::from_secs(59)));
+        assert!(tokens.claim(&Namespace::from("other"), "a", horizon, now));
+        assert!(tokens.claim(&ns, "a", horizon, now + horizon));
+
+        assert!(tokens.claim(&ns, "b", horizon, now));
+        tokens.release(&ns, "b");
+        assert!(tokens.claim(&ns, "b", horizon, now));
+    }
+
+    #[test]
+    fn forgets_the_oldest_tokens_past_capacity() {
+        let tokens = SeenTokens::new(2);
+        let ns = Namespace::from("ns");
+        let horizon = Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        assert!(tokens.claim(&ns, "a", horizon, now));
+        assert!(tokens.claim(&ns, "b", horizon, now));
+        assert!(tokens.claim(&ns, "c", horizon, now));
+        assert!(!tokens.claim(&ns, "c", horizon, now));
+        assert!(!tokens.claim(&ns, "b", horizon, now));
+        assert!(tokens.claim(&ns, "a", horizon, now));
+    }
+}
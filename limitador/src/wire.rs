@@ -0,0 +1,365 @@
+//! The stable format limits and counters get exchanged in with other tools,
+//! e.g. to generate limits files or consume dumps of counters, in JSON or
+//! YAML.
+//!
+//! The serde derives of [`limit::Limit`] and [`counter::Counter`] are what the
+//! storages key the counters by, and leave out most of what makes a limit.
+//! The types of this module carry all of it instead, and are versioned: a
+//! document of [`Limits`] or [`Counters`] states the [`Version`] of the format
+//! it's in, which won't change in incompatible ways within a version.
+//!
+//! Version `1` of a limit is:
+//!
+//! ```yaml
+//! id: per-user                    # optional
+//! namespace: my_namespace
+//! max_value: 10
+//! max_value_from: int(descriptors[0].quota)      # optional, CEL
+//! seconds: 60
+//! name: 10 requests per minute    # optional
+//! cost: int(descriptors[0].tokens)               # optional, CEL
+//! schedule:                       # optional
+//!   from: "09:00"
+//!   to: "17:00"
+//!   days: [monday, friday]        # optional, all days when empty
+//!   utc_offset: "+02:00"          # optional, UTC by default
+//! ttl: 3600                       # optional, in seconds
+//! overrides:                      # optional
+//!   - variables: { descriptors[0].user_id: alice }
+//!     max_value: 100
+//! metadata:                       # optional
+//!   team: payments
+//! conditions:                     # optional, CEL predicates, all to be met
+//!   - descriptors[0].method == 'GET'
+//! variables:                      # optional, CEL expressions qualifying the counters
+//!   - descriptors[0].user_id
+//! ```
+//!
+//! The conditions and variables are given as the source of their CEL
+//! expressions, which their syntax tree gets parsed back from, rather than as
+//! the tree itself, tied to the CEL implementation. A counter is its limit,
+//! along with the values its variables qualify it with:
+//!
+//! ```yaml
+//! limit: { namespace: my_namespace, max_value: 10, seconds: 60, variables: [descriptors[0].user_id] }
+//! set_variables: { descriptors[0].user_id: alice }   # optional, none for simple limits
+//! remaining: 7                    # optional
+//! expires_in_seconds: 42          # optional
+//! ```
+
+use crate::counter;
+use crate::limit::{self, InvalidLimit, LimitBuilder, Schedule};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// A version of the format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub enum Version {
+    V1 = 1,
+}
+
+impl Version {
+    /// The version documents get written in
+    pub const CURRENT: Version = Version::V1;
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::CURRENT
+    }
+}
+
+impl TryFrom<u32> for Version {
+    type Error = UnsupportedVersion;
+
+    fn try_from(version: u32) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Version::V1),
+            version => Err(UnsupportedVersion(version)),
+        }
+    }
+}
+
+impl From<Version> for u32 {
+    fn from(version: Version) -> Self {
+        version as u32
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnsupportedVersion(u32);
+
+impl Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unsupported version {} of the format", self.0)
+    }
+}
+
+impl Error for UnsupportedVersion {}
+
+/// A document of limits, e.g. a limits file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    pub version: Version,
+    pub limits: Vec<Limit>,
+}
+
+impl<'a> FromIterator<&'a limit::Limit> for Limits {
+    fn from_iter<T: IntoIterator<Item = &'a limit::Limit>>(limits: T) -> Self {
+        Self {
+            version: Version::CURRENT,
+            limits: limits.into_iter().map(Limit::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<Limits> for Vec<limit::Limit> {
+    type Error = InvalidLimit;
+
+    fn try_from(limits: Limits) -> Result<Self, Self::Error> {
+        limits.limits.into_iter().map(TryInto::try_into).collect()
+    }
+}
+
+/// A document of counters, e.g. a dump of the ones of a storage
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Counters {
+    pub version: Version,
+    pub counters: Vec<Counter>,
+}
+
+impl<'a> FromIterator<&'a counter::Counter> for Counters {
+    fn from_iter<T: IntoIterator<Item = &'a counter::Counter>>(counters: T) -> Self {
+        Self {
+            version: Version::CURRENT,
+            counters: counters.into_iter().map(Counter::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Limit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub namespace: String,
+    pub max_value: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_value_from: Option<String>,
+    pub seconds: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<MaxValueOverride>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variables: Vec<String>,
+}
+
+impl From<&limit::Limit> for Limit {
+    fn from(limit: &limit::Limit) -> Self {
+        Self {
+            id: limit.id().map(String::from),
+            namespace: limit.namespace().as_ref().to_string(),
+            max_value: limit.max_value(),
+            max_value_from: limit.max_value_from().map(String::from),
+            seconds: limit.seconds(),
+            name: limit.name().map(String::from),
+            cost: limit.cost().map(String::from),
+            schedule: limit.schedule().cloned(),
+            ttl: limit.ttl(),
+            overrides: limit
+                .overrides()
+                .iter()
+                .map(|o| MaxValueOverride {
+                    variables: o.variables().clone(),
+                    max_value: o.max_value(),
+                })
+                .collect(),
+            metadata: limit.metadata().clone(),
+            // Sorted, for the same limit to always be written the same
+            conditions: limit
+                .predicates()
+                .map(|predicate| predicate.clone().into())
+                .collect(),
+            variables: limit
+                .variable_expressions()
+                .iter()
+                .map(|variable| variable.source().to_string())
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<Limit> for limit::Limit {
+    type Error = InvalidLimit;
+
+    fn try_from(limit: Limit) -> Result<Self, Self::Error> {
+        let mut builder = LimitBuilder::new(limit.namespace, limit.max_value, limit.seconds);
+        if let Some(id) = limit.id {
+            builder = builder.id(id);
+        }
+        if let Some(name) = limit.name {
+            builder = builder.name(name);
+        }
+        if let Some(max_value_from) = limit.max_value_from {
+            builder = builder.max_value_from(max_value_from);
+        }
+        if let Some(cost) = limit.cost {
+            builder = builder.cost(cost);
+        }
+        if let Some(schedule) = limit.schedule {
+            builder = builder.schedule(schedule);
+        }
+        if let Some(ttl) = limit.ttl {
+            builder = builder.ttl(ttl);
+        }
+        for condition in limit.conditions {
+            builder = builder.condition(condition);
+        }
+        for variable in limit.variables {
+            builder = builder.variable(variable);
+        }
+        for (key, value) in limit.metadata {
+            builder = builder.metadata(key, value);
+        }
+
+        let mut built = builder.build()?;
+        for o in limit.overrides {
+            built.set_override(o.variables, o.max_value)?;
+        }
+        Ok(built)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaxValueOverride {
+    pub variables: BTreeMap<String, String>,
+    pub max_value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Counter {
+    pub limit: Limit,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set_variables: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_seconds: Option<u64>,
+}
+
+impl From<&counter::Counter> for Counter {
+    fn from(counter: &counter::Counter) -> Self {
+        Self {
+            limit: counter.limit().into(),
+            set_variables: counter.set_variables().clone(),
+            remaining: counter.remaining(),
+            expires_in_seconds: counter.expires_in().map(|ttl| ttl.as_secs()),
+        }
+    }
+}
+
+impl TryFrom<Counter> for counter::Counter {
+    type Error = InvalidLimit;
+
+    fn try_from(counter: Counter) -> Result<Self, Self::Error> {
+        let limit = limit::Limit::try_from(counter.limit)?;
+        let mut built = counter::Counter::with_variables(limit, counter.set_variables);
+        if let Some(remaining) = counter.remaining {
+            built.set_remaining(remaining);
+        }
+        if let Some(seconds) = counter.expires_in_seconds {
+            built.set_expires_in(Duration::from_secs(seconds));
+        }
+        Ok(built)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counters, Limits, Version};
+    use crate::counter::Counter;
+    use crate::limit::{Limit, LimitBuilder, Schedule, TimeOfDay};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn round_trips_the_limits_and_counters() {
+        let mut limit = LimitBuilder::new("test_namespace", 10, 60)
+            .id("per-user")
+            .name("Per user")
+            .condition("descriptors[0].method == 'GET'")
+            .condition("descriptors[0].path == '/'")
+            .variable("descriptors[0].user_id")
+            .cost("int(descriptors[0].tokens)")
+            .schedule(Schedule::new(
+                TimeOfDay::new(9, 0).unwrap(),
+                TimeOfDay::new(17, 0).unwrap(),
+            ))
+            .metadata("team", "payments")
+            .build()
+            .unwrap();
+        limit
+            .set_override(
+                BTreeMap::from([("descriptors[0].user_id".to_string(), "alice".to_string())]),
+                100,
+            )
+            .unwrap();
+
+        let json = serde_json::to_string(&Limits::from_iter([&limit])).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"limits":[{"id":"per-user","namespace":"test_namespace","max_value":10,"seconds":60,"name":"Per user","cost":"int(descriptors[0].tokens)","schedule":{"from":"09:00","to":"17:00"},"overrides":[{"variables":{"descriptors[0].user_id":"alice"},"max_value":100}],"metadata":{"team":"payments"},"conditions":["descriptors[0].method == 'GET'","descriptors[0].path == '/'"],"variables":["descriptors[0].user_id"]}]}"#
+        );
+        let limits: Vec<Limit> = serde_json::from_str::<Limits>(&json)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let parsed = &limits[0];
+        assert_eq!(parsed, &limit);
+        assert_eq!(parsed.id(), limit.id());
+        assert_eq!(parsed.name(), limit.name());
+        assert_eq!(parsed.cost(), limit.cost());
+        assert_eq!(parsed.schedule(), limit.schedule());
+        assert_eq!(parsed.overrides(), limit.overrides());
+        assert_eq!(parsed.metadata(), limit.metadata());
+
+        let mut counter = Counter::with_variables(
+            limit,
+            BTreeMap::from([("descriptors[0].user_id".to_string(), "bob".to_string())]),
+        );
+        counter.set_remaining(7);
+        counter.set_expires_in(Duration::from_secs(42));
+        let json = serde_json::to_string(&Counters::from_iter([&counter])).unwrap();
+        let mut counters = serde_json::from_str::<Counters>(&json).unwrap().counters;
+        let parsed = Counter::try_from(counters.remove(0)).unwrap();
+        assert_eq!(parsed, counter);
+        assert_eq!(parsed.remaining(), Some(7));
+        assert_eq!(parsed.expires_in(), Some(Duration::from_secs(42)));
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let limits: Limits = serde_json::from_str(
+            r#"{"version": 1, "limits": [{"namespace": "ns", "max_value": 1, "seconds": 1}]}"#,
+        )
+        .unwrap();
+        assert_eq!(limits.version, Version::V1);
+
+        let err = serde_json::from_str::<Limits>(r#"{"version": 2, "limits": []}"#).unwrap_err();
+        assert!(err.to_string().contains("unsupported version 2"), "{err}");
+    }
+}
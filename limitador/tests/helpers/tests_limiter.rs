@@ -1,7 +1,8 @@
 use limitador::counter::Counter;
 use limitador::errors::LimitadorError;
 use limitador::limit::{Context, Limit, Namespace};
-use limitador::{AsyncRateLimiter, CheckResult, RateLimiter};
+use limitador::storage::{CounterFilter, CountersPage};
+use limitador::{AsyncRateLimiter, CheckRequest, CheckResult, RateLimiter};
 use std::collections::HashSet;
 
 // This exposes a struct that wraps both implementations of the rate limiter,
@@ -44,6 +45,7 @@ impl TestsLimiter {
             LimiterImpl::Blocking(limiter) => limiter.add_limit(limit.clone()),
             LimiterImpl::Async(limiter) => limiter.add_limit(limit.clone()),
         }
+        .expect("conflicting limit id")
     }
 
     pub async fn delete_limit(&self, limit: &Limit) -> Result<(), LimitadorError> {
@@ -118,6 +120,35 @@ impl TestsLimiter {
         }
     }
 
+    pub async fn check_rate_limited_and_update_batch(
+        &self,
+        requests: Vec<CheckRequest<'_>>,
+        load_counters: bool,
+    ) -> Result<Vec<CheckResult>, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.check_rate_limited_and_update_batch(requests, load_counters)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter
+                    .check_rate_limited_and_update_batch(requests, load_counters)
+                    .await
+            }
+        }
+    }
+
+    pub async fn peek(
+        &self,
+        namespace: &str,
+        ctx: &Context<'_>,
+        delta: u64,
+    ) -> Result<CheckResult, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.peek(&namespace.into(), ctx, delta),
+            LimiterImpl::Async(limiter) => limiter.peek(&namespace.into(), ctx, delta).await,
+        }
+    }
+
     pub async fn get_counters(&self, namespace: &str) -> Result<HashSet<Counter>, LimitadorError> {
         match &self.limiter_impl {
             LimiterImpl::Blocking(limiter) => limiter.get_counters(&namespace.into()),
@@ -125,6 +156,50 @@ impl TestsLimiter {
         }
     }
 
+    pub async fn get_counters_page(
+        &self,
+        namespace: &str,
+        filter: &CounterFilter,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<CountersPage, LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => {
+                limiter.get_counters_page(&namespace.into(), filter, cursor, page_size)
+            }
+            LimiterImpl::Async(limiter) => {
+                limiter
+                    .get_counters_page(&namespace.into(), filter, cursor, page_size)
+                    .await
+            }
+        }
+    }
+
+    pub async fn reset_counter(&self, counter: &Counter) -> Result<(), LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.reset_counter(counter),
+            LimiterImpl::Async(limiter) => limiter.reset_counter(counter).await,
+        }
+    }
+
+    pub async fn reset_counters_of_limit(&self, limit: &Limit) -> Result<(), LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.reset_counters_of_limit(limit),
+            LimiterImpl::Async(limiter) => limiter.reset_counters_of_limit(limit).await,
+        }
+    }
+
+    pub async fn replace_limits(
+        &self,
+        namespace: &str,
+        limits: impl IntoIterator<Item = Limit>,
+    ) -> Result<(), LimitadorError> {
+        match &self.limiter_impl {
+            LimiterImpl::Blocking(limiter) => limiter.replace_limits(&namespace.into(), limits),
+            LimiterImpl::Async(limiter) => limiter.replace_limits(&namespace.into(), limits).await,
+        }
+    }
+
     pub async fn configure_with(
         &self,
         limits: impl IntoIterator<Item = Limit>,
@@ -30,6 +30,15 @@ macro_rules! test_with_all_storage_impls {
                 $function(&mut TestsLimiter::new_from_blocking_impl(rate_limiter)).await;
             }
 
+            #[cfg(feature = "sqlite_storage")]
+            #[tokio::test]
+            async fn [<$function _sqlite_storage>]() {
+                let dir = TempDir::new().expect("We should have a dir!");
+                let rate_limiter =
+                    RateLimiter::new_with_storage(Box::new(SqliteStorage::open(dir.path().join("counters.db")).expect("Couldn't open temp db")));
+                $function(&mut TestsLimiter::new_from_blocking_impl(rate_limiter)).await;
+            }
+
             #[cfg(feature = "redis_storage")]
             #[tokio::test]
             #[serial]
@@ -55,6 +64,18 @@ macro_rules! test_with_all_storage_impls {
                 $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
             }
 
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            async fn [<$function _with_async_redis_strict>]() {
+                let storage = AsyncRedisStorage::new("redis://127.0.0.1:6379").await.expect("We need a Redis running locally").strict(true);
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
             #[cfg(feature = "redis_storage")]
             #[tokio::test]
             #[serial]
@@ -69,6 +90,38 @@ macro_rules! test_with_all_storage_impls {
                 );
                 $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
             }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            async fn [<$function _with_async_redis_write_behind>]() {
+                let storage = WriteBehindRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                    .flushing_period(Duration::from_millis(2))
+                    .build()
+                    .await
+                    .expect("We need a Redis running locally");
+                storage.clear().await.unwrap();
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
+
+            #[cfg(feature = "redis_storage")]
+            #[tokio::test]
+            #[serial]
+            async fn [<$function _with_async_redis_broadcast>]() {
+                let storage = BroadcastRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                    .channel(stringify!($function))
+                    .broadcast_period(Duration::from_millis(2))
+                    .build()
+                    .await
+                    .expect("We need a Redis running locally");
+                let rate_limiter = AsyncRateLimiter::new_with_storage(
+                    Box::new(storage)
+                );
+                $function(&mut TestsLimiter::new_from_async_impl(rate_limiter)).await;
+            }
         }
     };
 }
@@ -146,8 +199,10 @@ mod test {
     cfg_if::cfg_if! {
         if #[cfg(feature = "redis_storage")] {
             use limitador::storage::redis::AsyncRedisStorage;
+            use limitador::storage::redis::BroadcastRedisStorageBuilder;
             use limitador::storage::redis::CachedRedisStorageBuilder;
             use limitador::storage::redis::RedisStorage;
+            use limitador::storage::redis::WriteBehindRedisStorageBuilder;
 
             use limitador::AsyncRateLimiter;
             use serial_test::serial;
@@ -157,19 +212,23 @@ mod test {
     }
 
     use self::limitador::counter::Counter;
-    use self::limitador::RateLimiter;
+    use self::limitador::errors::LimitadorError;
+    use self::limitador::{CheckRequest, RateLimiter};
     use crate::helpers::tests_limiter::*;
-    use limitador::limit::Limit;
+    use limitador::limit::{Context, Limit};
     #[cfg(feature = "disk_storage")]
     use limitador::storage::disk::{DiskStorage, OptimizeFor};
     #[cfg(feature = "distributed_storage")]
     use limitador::storage::distributed::CrInMemoryStorage;
     use limitador::storage::in_memory::InMemoryStorage;
+    #[cfg(feature = "sqlite_storage")]
+    use limitador::storage::sqlite::SqliteStorage;
+    use limitador::storage::CounterFilter;
     use std::collections::{HashMap, HashSet};
     use std::future::Future;
     use std::thread::sleep;
     use std::time::Duration;
-    #[cfg(feature = "disk_storage")]
+    #[cfg(any(feature = "disk_storage", feature = "sqlite_storage"))]
     use tempfile::TempDir;
     use tokio::time::error::Elapsed;
 
@@ -191,15 +250,22 @@ mod test {
     test_with_all_storage_impls!(multiple_limits_rate_limited);
     test_with_all_storage_impls!(rate_limited_with_delta_higher_than_one);
     test_with_all_storage_impls!(rate_limited_with_delta_higher_than_max);
+    test_with_all_storage_impls!(check_rate_limited_and_update_rejects_delta_higher_than_max);
     test_with_all_storage_impls!(takes_into_account_only_vars_of_the_limits);
     test_with_all_storage_impls!(is_rate_limited_returns_false_when_no_limits_in_namespace);
     test_with_all_storage_impls!(is_rate_limited_returns_false_when_no_matching_limits);
     test_with_all_storage_impls!(is_rate_limited_applies_limit_if_its_unconditional);
     test_with_all_storage_impls!(check_rate_limited_and_update);
     test_with_all_storage_impls!(check_rate_limited_and_update_load_counters);
+    test_with_all_storage_impls!(check_rate_limited_and_update_reports_limiting_counter);
+    test_with_all_storage_impls!(check_rate_limited_and_update_batch);
+    test_with_all_storage_impls!(peek_does_not_update_counters);
     test_with_all_storage_impls!(check_rate_limited_and_update_returns_true_if_no_limits_apply);
     test_with_all_storage_impls!(check_rate_limited_and_update_applies_limit_if_its_unconditional);
     test_with_all_storage_impls!(get_counters);
+    test_with_all_storage_impls!(reset_counter_only_resets_the_given_counter);
+    test_with_all_storage_impls!(reset_counters_of_limit_keeps_the_limit);
+    test_with_all_storage_impls!(get_counters_page_filters_and_pages);
     test_with_all_storage_impls!(get_counters_returns_empty_when_no_limits_in_namespace);
     test_with_all_storage_impls!(get_counters_returns_empty_when_no_counters_in_namespace);
     test_with_all_storage_impls!(get_counters_does_not_return_expired_ones);
@@ -207,6 +273,7 @@ mod test {
     test_with_all_storage_impls!(configure_with_keeps_the_given_limits_and_counters_if_they_exist);
     test_with_all_storage_impls!(configure_with_deletes_all_except_the_limits_given);
     test_with_all_storage_impls!(configure_with_updates_the_limits);
+    test_with_all_storage_impls!(replace_limits_only_resets_counters_of_changed_limits);
     test_with_all_storage_impls!(add_limit_only_adds_if_not_present);
 
     test_with_distributed_storage_impls!(distributed_rate_limited);
@@ -840,6 +907,48 @@ mod test {
         );
     }
 
+    async fn check_rate_limited_and_update_rejects_delta_higher_than_max(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let max = 10;
+        let namespace = "test_namespace";
+        let limit = Limit::new(
+            namespace,
+            max,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+
+        rate_limiter.add_limit(&limit).await;
+
+        let mut values: HashMap<String, String> = HashMap::new();
+        values.insert("req_method".to_string(), "GET".to_string());
+        values.insert("app_id".to_string(), "test_app_id".to_string());
+        let ctx = values.into();
+
+        for delta in [max + 1, u64::MAX] {
+            match rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx, delta, false)
+                .await
+            {
+                Err(LimitadorError::DeltaExceedsMaxValue(err)) => {
+                    assert_eq!(err.delta(), delta);
+                    assert_eq!(err.max_value(), max);
+                }
+                _ => panic!("a delta of {delta} got checked"),
+            }
+        }
+
+        assert!(
+            !rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx, max, false)
+                .await
+                .unwrap()
+                .limited
+        );
+    }
+
     async fn check_rate_limited_and_update_load_counters(rate_limiter: &mut TestsLimiter) {
         let namespace = "test_namespace";
         let max_hits = 3;
@@ -890,6 +999,259 @@ mod test {
         }
     }
 
+    async fn check_rate_limited_and_update_reports_limiting_counter(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+
+        let mut per_minute = Limit::new(namespace, 1, 60, vec![], vec![]);
+        per_minute.set_name("per_minute".to_string());
+        let mut per_hour = Limit::new(namespace, 10, 3600, vec![], vec![]);
+        per_hour.set_name("per_hour".to_string());
+
+        rate_limiter.add_limit(&per_minute).await;
+        rate_limiter.add_limit(&per_hour).await;
+
+        let ctx = Context::default();
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &ctx, 1, true)
+            .await
+            .unwrap();
+        assert!(!result.limited);
+        assert_eq!(result.statuses.len(), 2);
+        assert!(result.statuses.iter().all(|status| !status.limiting));
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &ctx, 1, true)
+            .await
+            .unwrap();
+        assert!(result.limited);
+        assert_eq!(result.limit_name.as_deref(), Some("per_minute"));
+        assert_eq!(result.statuses.len(), 2);
+        for status in result.statuses.iter() {
+            assert_eq!(
+                status.limiting,
+                status.limit_name.as_deref() == Some("per_minute")
+            );
+        }
+
+        let result = rate_limiter
+            .check_rate_limited_and_update(namespace, &ctx, 1, false)
+            .await
+            .unwrap();
+        assert!(result.limited);
+        assert_eq!(result.statuses.len(), 1);
+        assert!(result.statuses[0].limiting);
+        assert_eq!(result.statuses[0].max_value, 1);
+    }
+
+    async fn check_rate_limited_and_update_batch(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit = Limit::new(
+            namespace,
+            2,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(&limit).await;
+
+        let request = |app_id: &str, delta: u64| {
+            let values = HashMap::from([
+                ("req_method".to_string(), "GET".to_string()),
+                ("app_id".to_string(), app_id.to_string()),
+            ]);
+            CheckRequest {
+                namespace: namespace.into(),
+                ctx: values.into(),
+                delta,
+            }
+        };
+        let no_limits = CheckRequest {
+            namespace: "other_namespace".into(),
+            ctx: Context::default(),
+            delta: 1,
+        };
+
+        let results = rate_limiter
+            .check_rate_limited_and_update_batch(
+                vec![request("app_a", 1), request("app_b", 3), no_limits],
+                true,
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(!results[0].limited);
+        assert_eq!(results[0].counters[0].remaining(), Some(1));
+        assert!(results[1].limited);
+        assert!(!results[2].limited);
+        assert!(results[2].counters.is_empty());
+
+        // Each request only took hits from its own counters
+        let results = rate_limiter
+            .check_rate_limited_and_update_batch(
+                vec![
+                    request("app_a", 1),
+                    request("app_a", 1),
+                    request("app_b", 2),
+                ],
+                false,
+            )
+            .await
+            .unwrap();
+        assert!(!results[0].limited);
+        assert!(results[1].limited);
+        assert!(!results[2].limited);
+    }
+
+    async fn peek_does_not_update_counters(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+        let max_hits = 2;
+
+        let limit = Limit::new(namespace, max_hits, 60, vec![], vec![]);
+        rate_limiter.add_limit(&limit).await;
+
+        let ctx = Context::default();
+
+        for _ in 0..3 {
+            let result = rate_limiter.peek(namespace, &ctx, 1).await.unwrap();
+            assert!(!result.limited);
+            assert_eq!(result.statuses.len(), 1);
+            if let Some(remaining) = result.statuses[0].remaining {
+                assert_eq!(remaining, max_hits - 1);
+            }
+        }
+
+        for _ in 0..max_hits {
+            let result = rate_limiter
+                .check_rate_limited_and_update(namespace, &ctx, 1, false)
+                .await
+                .unwrap();
+            assert!(!result.limited);
+        }
+
+        let result = rate_limiter.peek(namespace, &ctx, 1).await.unwrap();
+        assert!(result.limited);
+        assert!(result.statuses[0].limiting);
+        if let Some(remaining) = result.statuses[0].remaining {
+            assert_eq!(remaining, 0);
+        }
+    }
+
+    async fn reset_counter_only_resets_the_given_counter(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit = Limit::new(
+            namespace,
+            1,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(&limit).await;
+
+        fn ctx_of(app_id: &str) -> Context<'static> {
+            let mut values: HashMap<String, String> = HashMap::new();
+            values.insert("app_id".to_string(), app_id.to_string());
+            values.into()
+        }
+
+        for app_id in ["app_1", "app_2"] {
+            rate_limiter
+                .update_counters(namespace, &ctx_of(app_id), 1)
+                .await
+                .unwrap();
+            assert!(rate_limiter
+                .is_rate_limited(namespace, &ctx_of(app_id), 1)
+                .await
+                .unwrap());
+        }
+
+        let counter =
+            Counter::with_variables(limit, [("app_id".to_string(), "app_1".to_string())].into());
+        rate_limiter.reset_counter(&counter).await.unwrap();
+
+        assert!(!rate_limiter
+            .is_rate_limited(namespace, &ctx_of("app_1"), 1)
+            .await
+            .unwrap());
+        assert!(rate_limiter
+            .is_rate_limited(namespace, &ctx_of("app_2"), 1)
+            .await
+            .unwrap());
+    }
+
+    async fn reset_counters_of_limit_keeps_the_limit(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit = Limit::new(namespace, 1, 60, vec![], vec![]);
+        rate_limiter.add_limit(&limit).await;
+
+        let ctx = Context::default();
+        rate_limiter
+            .update_counters(namespace, &ctx, 1)
+            .await
+            .unwrap();
+        assert!(rate_limiter
+            .is_rate_limited(namespace, &ctx, 1)
+            .await
+            .unwrap());
+
+        rate_limiter.reset_counters_of_limit(&limit).await.unwrap();
+
+        assert!(rate_limiter.get_limits(namespace).await.contains(&limit));
+        assert!(!rate_limiter
+            .is_rate_limited(namespace, &ctx, 1)
+            .await
+            .unwrap());
+
+        // The counters of every value of the variables get reset too
+        let qualified_namespace = "test_qualified_namespace";
+        let qualified = Limit::new(
+            qualified_namespace,
+            1,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(&qualified).await;
+
+        fn ctx_of(app_id: &str) -> Context<'static> {
+            let mut values: HashMap<String, String> = HashMap::new();
+            values.insert("app_id".to_string(), app_id.to_string());
+            values.into()
+        }
+
+        for app_id in ["app_1", "app_2"] {
+            rate_limiter
+                .update_counters(qualified_namespace, &ctx_of(app_id), 1)
+                .await
+                .unwrap();
+            assert!(rate_limiter
+                .is_rate_limited(qualified_namespace, &ctx_of(app_id), 1)
+                .await
+                .unwrap());
+        }
+
+        rate_limiter
+            .reset_counters_of_limit(&qualified)
+            .await
+            .unwrap();
+
+        assert!(rate_limiter
+            .get_limits(qualified_namespace)
+            .await
+            .contains(&qualified));
+        for app_id in ["app_1", "app_2"] {
+            assert!(!rate_limiter
+                .is_rate_limited(qualified_namespace, &ctx_of(app_id), 1)
+                .await
+                .unwrap());
+        }
+    }
+
     async fn check_rate_limited_and_update_returns_true_if_no_limits_apply(
         rate_limiter: &mut TestsLimiter,
     ) {
@@ -1000,6 +1362,67 @@ mod test {
         }
     }
 
+    async fn get_counters_page_filters_and_pages(rate_limiter: &mut TestsLimiter) {
+        let namespace = "test_namespace";
+
+        let limit = Limit::with_id(
+            "test_limit",
+            namespace,
+            10,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        rate_limiter.add_limit(&limit).await;
+
+        for (app_id, hits) in [("a1", 1), ("a2", 1), ("a3", 1), ("b1", 8)] {
+            let mut values = HashMap::new();
+            values.insert("app_id".to_string(), app_id.to_string());
+            let ctx = values.into();
+            rate_limiter
+                .update_counters(namespace, &ctx, hits)
+                .await
+                .unwrap();
+        }
+
+        let rate_limiter = &*rate_limiter;
+        let app_ids = |filter: CounterFilter, page_size: usize| async move {
+            let mut app_ids = HashSet::new();
+            let mut cursor: Option<String> = None;
+            loop {
+                let page = rate_limiter
+                    .get_counters_page(namespace, &filter, cursor.as_deref(), page_size)
+                    .await
+                    .unwrap();
+                for counter in page.counters {
+                    app_ids.insert(counter.set_variables()["app_id"].clone());
+                }
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    return app_ids;
+                }
+            }
+        };
+
+        assert_eq!(
+            app_ids(CounterFilter::default(), 1).await,
+            HashSet::from(["a1", "a2", "a3", "b1"].map(String::from))
+        );
+        assert_eq!(
+            app_ids(CounterFilter::default().variable_prefix("app_id", "a"), 2).await,
+            HashSet::from(["a1", "a2", "a3"].map(String::from))
+        );
+        assert_eq!(
+            app_ids(CounterFilter::default().min_usage_percent(50), 10).await,
+            HashSet::from(["b1".to_string()])
+        );
+        assert!(
+            app_ids(CounterFilter::default().limit_id("other_limit"), 10)
+                .await
+                .is_empty()
+        );
+    }
+
     async fn get_counters_returns_empty_when_no_limits_in_namespace(
         rate_limiter: &mut TestsLimiter,
     ) {
@@ -1204,6 +1627,49 @@ mod test {
         assert_eq!(limits.iter().next().unwrap().max_value(), 20);
     }
 
+    async fn replace_limits_only_resets_counters_of_changed_limits(
+        rate_limiter: &mut TestsLimiter,
+    ) {
+        let namespace = "test_namespace";
+
+        let per_minute = Limit::new(namespace, 2, 60, vec![], vec![]);
+        let per_hour = Limit::new(namespace, 2, 3600, vec![], vec![]);
+        let unconfigured = Limit::new(
+            namespace,
+            2,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        for limit in [&per_minute, &per_hour, &unconfigured] {
+            rate_limiter.add_limit(limit).await;
+        }
+
+        let ctx = Context::default();
+        rate_limiter
+            .update_counters(namespace, &ctx, 1)
+            .await
+            .unwrap();
+
+        // Same definition with a higher max value, and a different window
+        let mut per_minute_update = per_minute.clone();
+        per_minute_update.set_max_value(3);
+        let per_day = Limit::new(namespace, 2, 86400, vec![], vec![]);
+        rate_limiter
+            .replace_limits(namespace, vec![per_minute_update, per_day.clone()])
+            .await
+            .unwrap();
+
+        let limits = rate_limiter.get_limits(namespace).await;
+        assert_eq!(limits.len(), 2);
+        assert!(limits.contains(&per_day));
+        assert_eq!(limits.get(&per_minute).unwrap().max_value(), 3);
+
+        let counters = rate_limiter.get_counters(namespace).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters.iter().next().unwrap().limit(), &per_minute);
+    }
+
     async fn add_limit_only_adds_if_not_present(rate_limiter: &mut TestsLimiter) {
         let namespace = "test_namespace";
 
@@ -1300,4 +1766,208 @@ mod test {
         .await
         .unwrap());
     }
+
+    #[cfg(feature = "distributed_storage")]
+    #[tokio::test]
+    async fn distributed_storage_syncs_the_counters_of_a_joining_peer() {
+        let namespace = "test_namespace".into();
+        let limit = Limit::new(
+            "test_namespace",
+            3,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let values = HashMap::from([
+            ("req_method".to_string(), "GET".to_string()),
+            ("app_id".to_string(), "test_app_id".to_string()),
+        ]);
+        let ctx = values.into();
+
+        let running = RateLimiter::new_with_storage(Box::new(CrInMemoryStorage::new(
+            "j0".to_owned(),
+            10_000,
+            "127.0.0.1:5210".to_owned(),
+            vec![],
+        )));
+        running.add_limit(limit.clone()).unwrap();
+        for _ in 0..3 {
+            let result = running
+                .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                .unwrap();
+            assert!(!result.limited);
+        }
+
+        let joining = CrInMemoryStorage::new(
+            "j1".to_owned(),
+            10_000,
+            "127.0.0.1:5211".to_owned(),
+            vec!["http://127.0.0.1:5210".to_owned()],
+        );
+        assert!(joining.synced(Duration::from_secs(5)).await);
+
+        // the hits the running peer got are all known, before any replication
+        let joined = RateLimiter::new_with_storage(Box::new(joining));
+        joined.add_limit(limit).unwrap();
+        assert!(joined.is_rate_limited(&namespace, &ctx, 1).unwrap());
+    }
+
+    #[cfg(feature = "distributed_storage")]
+    #[tokio::test]
+    async fn distributed_storage_discovers_its_peers_through_dns() {
+        use limitador::storage::distributed::{CrInMemoryStorageBuilder, PeerDiscovery};
+
+        let _running =
+            CrInMemoryStorage::new("d0".to_owned(), 10_000, "127.0.0.1:5220".to_owned(), vec![]);
+        let joining = CrInMemoryStorageBuilder::new("d1".to_owned(), "127.0.0.1:5221".to_owned())
+            .peer_discovery(PeerDiscovery {
+                address: "localhost:5220".to_owned(),
+                period: Duration::from_millis(100),
+            })
+            .build()
+            .unwrap();
+
+        assert!(joining.synced(Duration::from_secs(5)).await);
+    }
+
+    #[cfg(feature = "distributed_storage")]
+    #[tokio::test]
+    async fn distributed_storage_only_admits_the_share_of_the_region() {
+        use limitador::storage::distributed::{
+            ConflictResolution, CrInMemoryStorageBuilder, RegionalQuotas,
+        };
+
+        let namespace = "test_namespace".into();
+        let limit = Limit::new(
+            "test_namespace",
+            4,
+            60,
+            vec![],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let values = HashMap::from([("app_id".to_string(), "test_app_id".to_string())]);
+        let ctx = values.into();
+        let regional = |region: &str| {
+            ConflictResolution::Regional(RegionalQuotas {
+                region: region.to_owned(),
+                shares: HashMap::from([("eu".to_owned(), 0.5), ("us".to_owned(), 0.5)]),
+                rebalancing: None,
+            })
+        };
+
+        let eu = CrInMemoryStorageBuilder::new("r0".to_owned(), "127.0.0.1:5230".to_owned())
+            .conflict_resolution(regional("eu"))
+            .build()
+            .unwrap();
+        let eu = RateLimiter::new_with_storage(Box::new(eu));
+        eu.add_limit(limit.clone()).unwrap();
+        for limited in [false, false, true] {
+            let result = eu
+                .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                .unwrap();
+            assert_eq!(result.limited, limited);
+        }
+
+        let us = CrInMemoryStorageBuilder::new("r1".to_owned(), "127.0.0.1:5231".to_owned())
+            .peer_urls(vec!["http://127.0.0.1:5230".to_owned()])
+            .conflict_resolution(regional("us"))
+            .build()
+            .unwrap();
+        assert!(us.synced(Duration::from_secs(5)).await);
+
+        // the hits of the other region don't count against this one's share
+        let us = RateLimiter::new_with_storage(Box::new(us));
+        us.add_limit(limit).unwrap();
+        for limited in [false, false, true] {
+            let result = us
+                .check_rate_limited_and_update(&namespace, &ctx, 1, false)
+                .unwrap();
+            assert_eq!(result.limited, limited);
+        }
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test]
+    #[serial]
+    async fn write_behind_storage_hydrates_counters_from_redis() {
+        let redis_url = "redis://127.0.0.1:6379";
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let redis = AsyncRedisStorage::new(redis_url)
+            .await
+            .expect("We need a Redis running locally");
+        redis.clear().await.unwrap();
+        redis.update_counter(&counter, 7).await.unwrap();
+
+        let storage = WriteBehindRedisStorageBuilder::new(redis_url)
+            .flushing_period(Duration::from_secs(3600))
+            .build()
+            .await
+            .unwrap();
+        assert!(storage.is_within_limits(&counter, 3).await.unwrap());
+        assert!(!storage.is_within_limits(&counter, 4).await.unwrap());
+
+        storage.update_counter(&counter, 2).await.unwrap();
+        // Not flushed yet, Redis lags behind
+        assert!(redis.is_within_limits(&counter, 3).await.unwrap());
+        storage.flush().await.unwrap();
+        assert!(!redis.is_within_limits(&counter, 2).await.unwrap());
+
+        redis.clear().await.unwrap();
+    }
+
+    #[cfg(feature = "redis_storage")]
+    #[tokio::test]
+    #[serial]
+    async fn broadcast_storage_applies_the_updates_of_peers() {
+        let limit = Limit::new(
+            "test_namespace",
+            10,
+            60,
+            vec!["req_method == 'GET'".try_into().expect("failed parsing!")],
+            vec!["app_id".try_into().expect("failed parsing!")],
+        );
+        let map = HashMap::from([("app_id".to_string(), "foo".to_string())]);
+        let ctx = map.into();
+        let counter = Counter::new(limit, &ctx)
+            .unwrap()
+            .expect("must have a counter");
+
+        let storage = || async {
+            BroadcastRedisStorageBuilder::new("redis://127.0.0.1:6379")
+                .channel("broadcast_storage_applies_the_updates_of_peers")
+                .broadcast_period(Duration::from_secs(3600))
+                .build()
+                .await
+                .expect("We need a Redis running locally")
+        };
+        let (a, b) = (storage().await, storage().await);
+        // Give them time to subscribe
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        a.update_counter(&counter, 7).await.unwrap();
+        // Not published yet
+        assert!(b.is_within_limits(&counter, 10).await.unwrap());
+        a.flush().await.unwrap();
+        assert!(eventually(
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+            || async { !b.is_within_limits(&counter, 4).await.unwrap() }
+        )
+        .await
+        .unwrap());
+        // Its own updates don't get applied twice
+        assert!(a.is_within_limits(&counter, 3).await.unwrap());
+    }
 }